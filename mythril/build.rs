@@ -1,4 +1,6 @@
 use nasm_rs;
+use std::env;
+use std::process::Command;
 
 fn main() {
     // We must _not_ do this build under the test setup, because that
@@ -19,4 +21,15 @@ fn main() {
         .target("x86_64-unknown-none")
         .compile("vm");
     println!("cargo:rustc-link-lib=static=vm");
+
+    // The selftest guest is a flat firmware image (rather than an object
+    // linked into the hypervisor), so it is assembled directly
+    let out_dir = env::var("OUT_DIR").expect("OUT_DIR is not set");
+    let status = Command::new("nasm")
+        .args(&["-f", "bin", "-o"])
+        .arg(format!("{}/selftest_guest.bin", out_dir))
+        .arg("src/selftest_guest.S")
+        .status()
+        .expect("Failed to run nasm");
+    assert!(status.success(), "Failed to assemble the selftest guest");
 }
@@ -0,0 +1,268 @@
+use super::rsdt::SDT;
+use crate::error::{Error, Result};
+use bitflags::bitflags;
+use byteorder::{ByteOrder, NativeEndian};
+use core::fmt;
+use core::ops::Range;
+
+/// See Table 8-1 in the VT-d specification.
+///
+/// Note that these offsets are relative to the end of the
+/// SDT (the end of the Creator Revision at offset 36).
+mod offsets {
+    use super::*;
+    /// The maximum DMA physical addressability (minus one).
+    pub const HOST_ADDRESS_WIDTH: usize = 0;
+    /// DMA Remapping Flags.
+    pub const FLAGS: usize = 1;
+    /// Remapping Structures.
+    pub const REMAPPING_STRUCTS: usize = 12;
+
+    /// The type of a remapping structure.
+    pub const STRUCT_TYPE: Range<usize> = 0..2;
+    /// The length of a remapping structure.
+    pub const STRUCT_LENGTH: Range<usize> = 2..4;
+
+    /// DRHD Flags.
+    pub const DRHD_FLAGS: usize = 4;
+    /// The PCI segment associated with a DRHD.
+    pub const DRHD_SEGMENT: Range<usize> = 6..8;
+    /// The base address of the DRHD register set.
+    pub const DRHD_REGISTER_BASE: Range<usize> = 8..16;
+
+    /// The PCI segment associated with an RMRR.
+    pub const RMRR_SEGMENT: Range<usize> = 6..8;
+    /// The base address of the reserved memory region.
+    pub const RMRR_BASE: Range<usize> = 8..16;
+    /// The (inclusive) limit of the reserved memory region.
+    pub const RMRR_LIMIT: Range<usize> = 16..24;
+}
+
+const STRUCT_TYPE_DRHD: u16 = 0;
+const STRUCT_TYPE_RMRR: u16 = 1;
+
+bitflags! {
+    /// DMA Remapping Flags.
+    ///
+    /// See Table 8-1 in the VT-d specification.
+    pub struct DmarFlags: u8 {
+        /// The platform supports interrupt remapping.
+        const INTR_REMAP = 1 << 0;
+        /// The firmware requests that x2APIC mode is not enabled.
+        const X2APIC_OPT_OUT = 1 << 1;
+        /// The platform supports DMA protection with opt-in devices.
+        const DMA_CTRL_PLATFORM_OPT_IN = 1 << 2;
+    }
+}
+
+bitflags! {
+    /// DMA Remapping Hardware Unit Definition Flags.
+    ///
+    /// See Table 8-2 in the VT-d specification.
+    pub struct DrhdFlags: u8 {
+        /// The unit covers all PCI devices in its segment that are not
+        /// covered by another unit.
+        const INCLUDE_PCI_ALL = 1 << 0;
+    }
+}
+
+/// A DMA remapping structure.
+#[derive(Debug)]
+pub enum RemappingStructure {
+    /// DMA Remapping Hardware Unit Definition.
+    ///
+    /// See `VT-d § 8.3`.
+    HardwareUnit {
+        /// DRHD Flags.
+        flags: DrhdFlags,
+        /// The PCI segment associated with this unit.
+        segment: u16,
+        /// The physical base address of the remapping register set.
+        register_base: u64,
+    },
+    /// Reserved Memory Region Reporting structure.
+    ///
+    /// See `VT-d § 8.4`.
+    ReservedMemory {
+        /// The PCI segment of the devices using this region.
+        segment: u16,
+        /// The base address of the region.
+        base: u64,
+        /// The (inclusive) limit address of the region.
+        limit: u64,
+    },
+    /// A remapping structure that is not used by mythril.
+    Other {
+        /// The type of this structure.
+        structure_type: u16,
+    },
+}
+
+/// DMA Remapping Reporting table.
+///
+/// See `VT-d § 8.1`.
+pub struct DMAR<'a> {
+    /// System Descriptor Table Header for this structure.
+    sdt: &'a SDT<'a>,
+    /// The maximum DMA physical addressability supported by the platform.
+    pub host_address_width: u8,
+    /// DMA Remapping Flags.
+    pub flags: DmarFlags,
+    /// A TLV buffer of the remapping structures.
+    structures: &'a [u8],
+}
+
+impl<'a> DMAR<'a> {
+    /// Create a new DMAR given a SDT.
+    pub fn new(sdt: &'a SDT<'a>) -> Result<DMAR<'a>> {
+        if sdt.table.len() < offsets::REMAPPING_STRUCTS {
+            return Err(Error::InvalidValue(format!(
+                "DMAR is too small: {} bytes",
+                sdt.table.len()
+            )));
+        }
+        let host_address_width =
+            sdt.table[offsets::HOST_ADDRESS_WIDTH].wrapping_add(1);
+        let flags = DmarFlags::from_bits_truncate(sdt.table[offsets::FLAGS]);
+        Ok(DMAR {
+            sdt,
+            host_address_width,
+            flags,
+            structures: &sdt.table[offsets::REMAPPING_STRUCTS..],
+        })
+    }
+
+    /// The DMA remapping structures.
+    pub fn structures<'c, 'd: 'c>(&'d self) -> RemappingStructureIterator<'c> {
+        RemappingStructureIterator {
+            bytes: self.structures,
+        }
+    }
+}
+
+impl<'a> fmt::Debug for DMAR<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.sdt)?;
+        write!(
+            f,
+            " DMAR host_address_width={} flags={:?}",
+            self.host_address_width, self.flags
+        )
+    }
+}
+
+/// Iterator for the remapping structures found in the DMAR.
+pub struct RemappingStructureIterator<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Iterator for RemappingStructureIterator<'a> {
+    type Item = Result<RemappingStructure>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if offsets::STRUCT_LENGTH.end > self.bytes.len() {
+            return None;
+        }
+
+        let ty = NativeEndian::read_u16(&self.bytes[offsets::STRUCT_TYPE]);
+        let len = NativeEndian::read_u16(&self.bytes[offsets::STRUCT_LENGTH])
+            as usize;
+
+        if len < offsets::STRUCT_LENGTH.end || len > self.bytes.len() {
+            let err = Error::InvalidValue(format!(
+                "Invalid length={} for remapping structure type=0x{:x} (buffer len={})",
+                len,
+                ty,
+                self.bytes.len()
+            ));
+            self.bytes = &[];
+            return Some(Err(err));
+        }
+
+        let bytes = &self.bytes[..len];
+        self.bytes = &self.bytes[len..];
+
+        let structure = match ty {
+            STRUCT_TYPE_DRHD if len >= offsets::DRHD_REGISTER_BASE.end => {
+                RemappingStructure::HardwareUnit {
+                    flags: DrhdFlags::from_bits_truncate(
+                        bytes[offsets::DRHD_FLAGS],
+                    ),
+                    segment: NativeEndian::read_u16(
+                        &bytes[offsets::DRHD_SEGMENT],
+                    ),
+                    register_base: NativeEndian::read_u64(
+                        &bytes[offsets::DRHD_REGISTER_BASE],
+                    ),
+                }
+            }
+            STRUCT_TYPE_RMRR if len >= offsets::RMRR_LIMIT.end => {
+                RemappingStructure::ReservedMemory {
+                    segment: NativeEndian::read_u16(
+                        &bytes[offsets::RMRR_SEGMENT],
+                    ),
+                    base: NativeEndian::read_u64(&bytes[offsets::RMRR_BASE]),
+                    limit: NativeEndian::read_u64(&bytes[offsets::RMRR_LIMIT]),
+                }
+            }
+            STRUCT_TYPE_DRHD | STRUCT_TYPE_RMRR => {
+                return Some(Err(Error::InvalidValue(format!(
+                    "Remapping structure type=0x{:x} is too short: len={}",
+                    ty, len
+                ))));
+            }
+            ty => RemappingStructure::Other { structure_type: ty },
+        };
+        Some(Ok(structure))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_dmar_parse() {
+        // sample DMAR ACPI entry (one DRHD and one RMRR)
+        let buf = [
+            0x44, 0x4d, 0x41, 0x52, 0x58, 0x00, 0x00, 0x00, 0x01, 0xec, 0x49,
+            0x4e, 0x54, 0x45, 0x4c, 0x20, 0x45, 0x44, 0x4b, 0x32, 0x20, 0x20,
+            0x20, 0x20, 0x02, 0x00, 0x00, 0x00, 0x20, 0x20, 0x20, 0x20, 0x13,
+            0x00, 0x00, 0x01, 0x26, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x01, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0xd9, 0xfe, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00,
+            0x18, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xc0, 0x7b, 0x00,
+            0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0x7f, 0x00, 0x00, 0x00, 0x00,
+        ];
+
+        let dmar_sdt = unsafe { SDT::new(buf.as_ptr()).unwrap() };
+        let dmar = DMAR::new(&dmar_sdt).unwrap();
+        assert_eq!(dmar.host_address_width, 39);
+        assert_eq!(dmar.flags, DmarFlags::INTR_REMAP);
+
+        let structures = dmar
+            .structures()
+            .collect::<Result<alloc::vec::Vec<_>>>()
+            .unwrap();
+        assert_eq!(structures.len(), 2);
+        match structures[0] {
+            RemappingStructure::HardwareUnit {
+                flags,
+                segment,
+                register_base,
+            } => {
+                assert_eq!(flags, DrhdFlags::INCLUDE_PCI_ALL);
+                assert_eq!(segment, 0);
+                assert_eq!(register_base, 0xfed90000);
+            }
+            ref other => panic!("Unexpected structure: {:?}", other),
+        }
+        match structures[1] {
+            RemappingStructure::ReservedMemory { base, limit, .. } => {
+                assert_eq!(base, 0x7bc00000);
+                assert_eq!(limit, 0x7fffffff);
+            }
+            ref other => panic!("Unexpected structure: {:?}", other),
+        }
+    }
+}
@@ -14,6 +14,8 @@ use core::convert::TryFrom;
 use num_enum::TryFromPrimitive;
 use raw_cpuid::CpuId;
 
+/// Support for the DMA Remapping Reporting table (DMAR).
+pub mod dmar;
 /// Support for the Fixed ACPI Descriptor Table (FADT).
 pub mod fadt;
 /// Support for the High Precision Event Timer (HPET)
@@ -24,6 +26,8 @@ pub mod madt;
 pub mod rsdp;
 /// Support for the Root System Descriptor Table (RSDT).
 pub mod rsdt;
+/// Generation of the ACPI tables of a guest.
+pub mod tables;
 
 mod offsets {
     use core::ops::Range;
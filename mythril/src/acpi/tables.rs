@@ -0,0 +1,676 @@
+use crate::error::{Error, Result};
+use crate::virtdev::acpi::AcpiRuntime;
+use crate::virtdev::acpi_pm::{AcpiPm, ACPI_DISABLE, ACPI_ENABLE, SLP_TYP_S5};
+use crate::virtdev::hpet::{HPET_FREQUENCY, HPET_TIMERS};
+use crate::virtdev::ioapic::IOAPIC_BASE;
+use crate::virtdev::tpm::{TPM_CRB_CONTROL_AREA, TPM_CRB_SIZE};
+use crate::virtdev::Port;
+use alloc::vec::Vec;
+
+/// The fw_cfg file holding the RSDP
+pub const RSDP_FILE: &str = "etc/acpi/rsdp";
+
+/// The fw_cfg file holding every other table
+pub const TABLES_FILE: &str = "etc/acpi/tables";
+
+// The guest physical address of the local APICs
+const LOCAL_APIC_BASE: u32 = 0xfee00000;
+
+// The SCI is wired to IRQ9
+const SCI_IRQ: u8 = 9;
+
+// The header of a System Description Table
+const SDT_HEADER_SIZE: usize = 36;
+const SDT_CHECKSUM: usize = 9;
+
+// The ACPI 1.0 RSDP (which only points at the RSDT)
+const RSDP_SIZE: usize = 20;
+const RSDP_CHECKSUM: usize = 8;
+const RSDP_RSDT_ADDRESS: usize = 16;
+
+const FACS_SIZE: usize = 64;
+const FADT_REVISION: u8 = 3;
+const FADT_SIZE: usize = 244;
+
+// The FADT fields that are set (see `ACPI § 5.2.9`)
+const FADT_FIRMWARE_CTRL: usize = 36;
+const FADT_DSDT: usize = 40;
+const FADT_SCI_INT: usize = 46;
+const FADT_SMI_CMD: usize = 48;
+const FADT_ACPI_ENABLE: usize = 52;
+const FADT_ACPI_DISABLE: usize = 53;
+const FADT_PM1A_EVT_BLK: usize = 56;
+const FADT_PM1A_CNT_BLK: usize = 64;
+const FADT_PM_TMR_BLK: usize = 76;
+const FADT_GPE0_BLK: usize = 80;
+const FADT_PM1_EVT_LEN: usize = 88;
+const FADT_PM1_CNT_LEN: usize = 89;
+const FADT_PM_TMR_LEN: usize = 91;
+const FADT_GPE0_BLK_LEN: usize = 92;
+const FADT_CENTURY: usize = 108;
+const FADT_IAPC_BOOT_ARCH: usize = 109;
+const FADT_FLAGS: usize = 112;
+
+// FADT flags: WBINVD, C1 support, no fixed power or sleep buttons and a
+// 32-bit PM timer
+const FADT_FLAGS_VALUE: u32 =
+    (1 << 0) | (1 << 2) | (1 << 4) | (1 << 5) | (1 << 8);
+
+// IA-PC boot architecture flags: legacy devices and an 8042
+const FADT_IAPC_BOOT_ARCH_VALUE: u16 = (1 << 0) | (1 << 1);
+
+// The CMOS register holding the century
+const RTC_CENTURY: u8 = 0x32;
+
+// MADT structure types and flags
+const MADT_PCAT_COMPAT: u32 = 1 << 0;
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_NMI: u8 = 4;
+const MADT_LOCAL_APIC_ENABLED: u32 = 1 << 0;
+
+// The SCI is active high and level triggered
+const MADT_SCI_FLAGS: u16 = 0b1101;
+
+// The commands of the QEMU linker/loader ('etc/table-loader')
+const LOADER_ALLOCATE: u32 = 1;
+const LOADER_ADD_POINTER: u32 = 2;
+const LOADER_ADD_CHECKSUM: u32 = 3;
+const LOADER_ENTRY_SIZE: usize = 128;
+const LOADER_FILE_NAME_SIZE: usize = 56;
+const LOADER_ZONE_HIGH: u8 = 1;
+const LOADER_ZONE_FSEG: u8 = 2;
+
+// The FACS must be 64 byte aligned, and so is the start of the tables
+const TABLES_ALIGN: u32 = 64;
+const RSDP_ALIGN: u32 = 16;
+
+/// One of the two blobs holding the generated tables
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Blob {
+    Rsdp,
+    Tables,
+}
+
+impl Blob {
+    fn file(&self) -> &'static str {
+        match self {
+            Blob::Rsdp => RSDP_FILE,
+            Blob::Tables => TABLES_FILE,
+        }
+    }
+}
+
+// A 32-bit field of `blob` holding an offset into the tables, which must
+// be relocated to the guest physical address of the tables
+#[derive(Clone, Debug)]
+struct Pointer {
+    blob: Blob,
+    offset: usize,
+}
+
+// The checksum byte at `offset` of `blob` covers `range`
+#[derive(Clone, Debug)]
+struct Checksum {
+    blob: Blob,
+    offset: usize,
+    range: core::ops::Range<usize>,
+}
+
+/// Builds the ACPI tables describing the platform emulated for a guest
+///
+/// The tables are a FACS, FADT, DSDT and MADT, followed by an HPET, MCFG
+/// and TPM2 table if the guest has those devices, and then any additional
+/// tables (e.g., a DMAR table). They are all reached from the RSDT.
+pub struct AcpiTablesBuilder {
+    apic_ids: Vec<u32>,
+    pm_base: Port,
+    hpet: Option<u64>,
+    ecam: Option<u64>,
+    tpm: Option<u64>,
+    extra: Vec<Vec<u8>>,
+}
+
+impl AcpiTablesBuilder {
+    /// Create a builder for a guest with the given (local APIC IDs of its)
+    /// CPUs, and the ACPI PM registers at `pm_base`
+    pub fn new(apic_ids: Vec<u32>, pm_base: Port) -> Self {
+        Self {
+            apic_ids,
+            pm_base,
+            hpet: None,
+            ecam: None,
+            tpm: None,
+            extra: vec![],
+        }
+    }
+
+    /// Describe an HPET with its registers at `base`
+    pub fn set_hpet(&mut self, base: u64) {
+        self.hpet = Some(base);
+    }
+
+    /// Describe the memory-mapped PCI config space (for all 256 buses of
+    /// segment 0) at `base`
+    pub fn set_ecam(&mut self, base: u64) {
+        self.ecam = Some(base);
+    }
+
+    /// Describe a TPM 2.0 with its CRB registers at `base` (see
+    /// `virtdev::tpm`)
+    pub fn set_tpm(&mut self, base: u64) {
+        self.tpm = Some(base);
+    }
+
+    /// Add a complete table (with its checksum already set), which is
+    /// referenced from the RSDT
+    pub fn add_table(&mut self, table: Vec<u8>) {
+        self.extra.push(table);
+    }
+
+    /// Build the tables
+    pub fn build(&self) -> Result<AcpiTables> {
+        let mut tables = AcpiTables {
+            rsdp: vec![0u8; RSDP_SIZE],
+            tables: vec![],
+            pointers: vec![],
+            checksums: vec![],
+        };
+
+        let facs = tables.push(self.facs(), false);
+        let dsdt = tables.push(self.dsdt(), true);
+
+        let mut fadt = self.fadt();
+        fadt[FADT_FIRMWARE_CTRL..FADT_FIRMWARE_CTRL + 4]
+            .copy_from_slice(&(facs as u32).to_le_bytes());
+        fadt[FADT_DSDT..FADT_DSDT + 4]
+            .copy_from_slice(&(dsdt as u32).to_le_bytes());
+        let fadt = tables.push(fadt, true);
+        tables.add_pointer(Blob::Tables, fadt + FADT_FIRMWARE_CTRL);
+        tables.add_pointer(Blob::Tables, fadt + FADT_DSDT);
+
+        let mut entries = vec![fadt, tables.push(self.madt()?, true)];
+        if let Some(base) = self.hpet {
+            entries.push(tables.push(hpet(base), true));
+        }
+        if let Some(base) = self.ecam {
+            entries.push(tables.push(mcfg(base), true));
+        }
+        if let Some(base) = self.tpm {
+            entries.push(tables.push(tpm2(base), true));
+        }
+        for table in self.extra.iter() {
+            entries.push(tables.push(table.clone(), false));
+        }
+
+        let mut rsdt = sdt_header(b"RSDT", 1);
+        for entry in entries.iter() {
+            rsdt.extend_from_slice(&(*entry as u32).to_le_bytes());
+        }
+        let rsdt_offset = tables.push(rsdt, true);
+        for i in 0..entries.len() {
+            tables.add_pointer(
+                Blob::Tables,
+                rsdt_offset + SDT_HEADER_SIZE + i * 4,
+            );
+        }
+
+        tables.rsdp[..8].copy_from_slice(b"RSD PTR ");
+        tables.rsdp[9..15].copy_from_slice(b"MYTHRL");
+        tables.rsdp[RSDP_RSDT_ADDRESS..RSDP_RSDT_ADDRESS + 4]
+            .copy_from_slice(&(rsdt_offset as u32).to_le_bytes());
+        tables.add_pointer(Blob::Rsdp, RSDP_RSDT_ADDRESS);
+        tables.checksums.push(Checksum {
+            blob: Blob::Rsdp,
+            offset: RSDP_CHECKSUM,
+            range: 0..RSDP_SIZE,
+        });
+        Ok(tables)
+    }
+
+    fn facs(&self) -> Vec<u8> {
+        let mut facs = vec![0u8; FACS_SIZE];
+        facs[..4].copy_from_slice(b"FACS");
+        facs[4..8].copy_from_slice(&(FACS_SIZE as u32).to_le_bytes());
+        facs[32] = 2; // Version
+        facs
+    }
+
+    fn dsdt(&self) -> Vec<u8> {
+        let mut dsdt = sdt_header(b"DSDT", 2);
+
+        // Name (_S5, Package () { SLP_TYP_S5, SLP_TYP_S5 })
+        let slp_typ = aml_integer(SLP_TYP_S5);
+        dsdt.push(AML_NAME_OP);
+        dsdt.extend_from_slice(b"_S5_");
+        dsdt.push(AML_PACKAGE_OP);
+        dsdt.push((2 + 2 * slp_typ.len()) as u8); // PkgLength
+        dsdt.push(2); // NumElements
+        dsdt.extend_from_slice(&slp_typ);
+        dsdt.extend_from_slice(&slp_typ);
+
+        if let Some(base) = self.tpm {
+            dsdt.extend_from_slice(&tpm_device(base));
+        }
+        dsdt
+    }
+
+    fn fadt(&self) -> Vec<u8> {
+        let mut fadt = sdt_header(b"FACP", FADT_REVISION);
+        fadt.resize(FADT_SIZE, 0);
+
+        let mut write_u32 = |offset: usize, val: u32| {
+            fadt[offset..offset + 4].copy_from_slice(&val.to_le_bytes())
+        };
+        write_u32(FADT_SMI_CMD, AcpiPm::FADT_SMI_COMMAND as u32);
+        write_u32(FADT_PM1A_EVT_BLK, self.pm_base as u32);
+        write_u32(FADT_PM1A_CNT_BLK, self.pm_base as u32 + 4);
+        write_u32(FADT_PM_TMR_BLK, self.pm_base as u32 + 8);
+        write_u32(FADT_GPE0_BLK, AcpiRuntime::GPE_BLOCK_START as u32);
+        write_u32(FADT_FLAGS, FADT_FLAGS_VALUE);
+
+        fadt[FADT_SCI_INT..FADT_SCI_INT + 2]
+            .copy_from_slice(&(SCI_IRQ as u16).to_le_bytes());
+        fadt[FADT_ACPI_ENABLE] = ACPI_ENABLE;
+        fadt[FADT_ACPI_DISABLE] = ACPI_DISABLE;
+        fadt[FADT_PM1_EVT_LEN] = 4;
+        fadt[FADT_PM1_CNT_LEN] = 2;
+        fadt[FADT_PM_TMR_LEN] = 4;
+        fadt[FADT_GPE0_BLK_LEN] = 4;
+        fadt[FADT_CENTURY] = RTC_CENTURY;
+        fadt[FADT_IAPC_BOOT_ARCH..FADT_IAPC_BOOT_ARCH + 2]
+            .copy_from_slice(&FADT_IAPC_BOOT_ARCH_VALUE.to_le_bytes());
+        fadt
+    }
+
+    fn madt(&self) -> Result<Vec<u8>> {
+        let mut madt = sdt_header(b"APIC", 1);
+        madt.extend_from_slice(&LOCAL_APIC_BASE.to_le_bytes());
+        madt.extend_from_slice(&MADT_PCAT_COMPAT.to_le_bytes());
+
+        for (uid, apic_id) in self.apic_ids.iter().enumerate() {
+            if *apic_id > 0xfe {
+                return Err(Error::InvalidValue(format!(
+                    "Guest APIC ID {} does not fit in the MADT",
+                    apic_id
+                )));
+            }
+            madt.extend_from_slice(&[MADT_LOCAL_APIC, 8]);
+            madt.push(uid as u8);
+            madt.push(*apic_id as u8);
+            madt.extend_from_slice(&MADT_LOCAL_APIC_ENABLED.to_le_bytes());
+        }
+
+        // ISA IRQs are identity mapped to GSIs (see `virtdev::ioapic`)
+        madt.extend_from_slice(&[MADT_IO_APIC, 12, 0, 0]);
+        madt.extend_from_slice(&(IOAPIC_BASE as u32).to_le_bytes());
+        madt.extend_from_slice(&0u32.to_le_bytes()); // GSI base
+
+        madt.extend_from_slice(&[MADT_INTERRUPT_SOURCE_OVERRIDE, 10, 0]);
+        madt.push(SCI_IRQ);
+        madt.extend_from_slice(&(SCI_IRQ as u32).to_le_bytes());
+        madt.extend_from_slice(&MADT_SCI_FLAGS.to_le_bytes());
+
+        // LINT1 of every processor is the NMI
+        madt.extend_from_slice(&[MADT_LOCAL_APIC_NMI, 6, 0xff, 0, 0, 1]);
+        Ok(madt)
+    }
+}
+
+// AML opcodes used by the DSDT
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+const AML_BYTE_PREFIX: u8 = 0x0a;
+const AML_NAME_OP: u8 = 0x08;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_STRING_PREFIX: u8 = 0x0d;
+const AML_BUFFER_OP: u8 = 0x11;
+const AML_EXT_OP_PREFIX: u8 = 0x5b;
+const AML_DEVICE_OP: u8 = 0x82;
+const AML_ROOT_CHAR: u8 = 0x5c;
+const AML_DUAL_NAME_PREFIX: u8 = 0x2e;
+
+// The resource descriptors used in _CRS buffers
+const RESOURCE_MEMORY32_FIXED: u8 = 0x86;
+const RESOURCE_END_TAG: u8 = 0x79;
+
+// The TPM2 table describes a TPM using the CRB start method
+const TPM2_START_METHOD_CRB: u32 = 7;
+
+fn aml_integer(val: u8) -> Vec<u8> {
+    match val {
+        0 => vec![AML_ZERO_OP],
+        1 => vec![AML_ONE_OP],
+        val => vec![AML_BYTE_PREFIX, val],
+    }
+}
+
+// The PkgLength of a package with `len` bytes following the PkgLength
+// (which counts itself)
+fn aml_pkg_length(len: usize) -> Vec<u8> {
+    if len + 1 < 0x40 {
+        vec![(len + 1) as u8]
+    } else {
+        let len = len + 2;
+        vec![0x40 | (len & 0xf) as u8, (len >> 4) as u8]
+    }
+}
+
+// Device (\_SB.TPM0), a TPM 2.0 with its registers at `base`
+fn tpm_device(base: u64) -> Vec<u8> {
+    let mut resources = vec![RESOURCE_MEMORY32_FIXED, 9, 0, 1]; // Read/write
+    resources.extend_from_slice(&(base as u32).to_le_bytes());
+    resources.extend_from_slice(&(TPM_CRB_SIZE as u32).to_le_bytes());
+    resources.extend_from_slice(&[RESOURCE_END_TAG, 0]);
+
+    let mut body = vec![AML_ROOT_CHAR, AML_DUAL_NAME_PREFIX];
+    body.extend_from_slice(b"_SB_TPM0");
+
+    // Name (_HID, "MSFT0101")
+    body.push(AML_NAME_OP);
+    body.extend_from_slice(b"_HID");
+    body.push(AML_STRING_PREFIX);
+    body.extend_from_slice(b"MSFT0101\0");
+
+    // Name (_CRS, ResourceTemplate () { Memory32Fixed (...) })
+    body.push(AML_NAME_OP);
+    body.extend_from_slice(b"_CRS");
+    body.push(AML_BUFFER_OP);
+    body.extend_from_slice(&aml_pkg_length(2 + resources.len()));
+    body.extend_from_slice(&[AML_BYTE_PREFIX, resources.len() as u8]);
+    body.extend_from_slice(&resources);
+
+    let mut device = vec![AML_EXT_OP_PREFIX, AML_DEVICE_OP];
+    device.extend_from_slice(&aml_pkg_length(body.len()));
+    device.extend_from_slice(&body);
+    device
+}
+
+// The header of a table (with the length and checksum left to be set)
+fn sdt_header(signature: &[u8; 4], revision: u8) -> Vec<u8> {
+    let mut table = Vec::with_capacity(SDT_HEADER_SIZE);
+    table.extend_from_slice(signature);
+    table.extend_from_slice(&[0u8; 4]); // Length
+    table.push(revision);
+    table.push(0); // Checksum
+    table.extend_from_slice(b"MYTHRL");
+    table.extend_from_slice(b"MYTHRIL ");
+    table.extend_from_slice(&1u32.to_le_bytes()); // OEM Revision
+    table.extend_from_slice(b"MYTH");
+    table.extend_from_slice(&1u32.to_le_bytes()); // Creator Revision
+    table
+}
+
+fn hpet(base: u64) -> Vec<u8> {
+    let mut hpet = sdt_header(b"HPET", 1);
+
+    // The event timer block ID matches the capabilities register of
+    // `virtdev::hpet::Hpet`: revision 1, a 64-bit counter and legacy
+    // replacement routing, from Intel
+    let block_id: u32 = 0x01
+        | ((HPET_TIMERS as u32 - 1) << 8)
+        | (1 << 13)
+        | (1 << 15)
+        | (0x8086 << 16);
+    hpet.extend_from_slice(&block_id.to_le_bytes());
+    hpet.extend_from_slice(&[0, 64, 0, 0]); // Base address GAS
+    hpet.extend_from_slice(&base.to_le_bytes());
+    hpet.push(0); // HPET number
+
+    // The minimum periodic tick (in main counter ticks), here 10us
+    let min_tick = (HPET_FREQUENCY / 100_000) as u16;
+    hpet.extend_from_slice(&min_tick.to_le_bytes());
+    hpet.push(0); // Page protection
+    hpet
+}
+
+fn mcfg(base: u64) -> Vec<u8> {
+    let mut mcfg = sdt_header(b"MCFG", 1);
+    mcfg.extend_from_slice(&[0u8; 8]);
+    mcfg.extend_from_slice(&base.to_le_bytes());
+    mcfg.extend_from_slice(&0u16.to_le_bytes()); // Segment
+    mcfg.extend_from_slice(&[0, 255]); // Start and end bus
+    mcfg.extend_from_slice(&[0u8; 4]);
+    mcfg
+}
+
+fn tpm2(base: u64) -> Vec<u8> {
+    let mut tpm2 = sdt_header(b"TPM2", 4);
+    tpm2.extend_from_slice(&0u16.to_le_bytes()); // Client platform
+    tpm2.extend_from_slice(&0u16.to_le_bytes());
+    let control_area = base + TPM_CRB_CONTROL_AREA as u64;
+    tpm2.extend_from_slice(&control_area.to_le_bytes());
+    tpm2.extend_from_slice(&TPM2_START_METHOD_CRB.to_le_bytes());
+    tpm2.extend_from_slice(&[0u8; 12]); // Start method parameters
+    tpm2
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    0u8.wrapping_sub(bytes.iter().fold(0u8, |acc, val| acc.wrapping_add(*val)))
+}
+
+/// The generated ACPI tables of a guest
+///
+/// The tables are held in two blobs: the RSDP, which must be placed in
+/// the BIOS area where the guest searches for it, and the other tables.
+/// Pointers between tables are stored as offsets into the second blob,
+/// so the tables can be placed anywhere, either by firmware following the
+/// `loader` commands or directly with `relocate`.
+#[derive(Clone, Debug)]
+pub struct AcpiTables {
+    rsdp: Vec<u8>,
+    tables: Vec<u8>,
+    pointers: Vec<Pointer>,
+    checksums: Vec<Checksum>,
+}
+
+impl AcpiTables {
+    // Append `table` (setting its length if `sdt`), returning its offset
+    fn push(&mut self, mut table: Vec<u8>, sdt: bool) -> usize {
+        let offset = self.tables.len();
+        if sdt {
+            let len = table.len() as u32;
+            table[4..8].copy_from_slice(&len.to_le_bytes());
+            table[SDT_CHECKSUM] = 0;
+            self.checksums.push(Checksum {
+                blob: Blob::Tables,
+                offset: offset + SDT_CHECKSUM,
+                range: offset..offset + table.len(),
+            });
+        }
+        self.tables.extend_from_slice(&table);
+
+        // Keep every table 8 byte aligned
+        let len = (self.tables.len() + 7) & !7;
+        self.tables.resize(len, 0);
+        offset
+    }
+
+    fn add_pointer(&mut self, blob: Blob, offset: usize) {
+        self.pointers.push(Pointer { blob, offset });
+    }
+
+    /// The RSDP (with pointers relative to the start of `tables`)
+    pub fn rsdp(&self) -> &[u8] {
+        &self.rsdp
+    }
+
+    /// Every table other than the RSDP
+    pub fn tables(&self) -> &[u8] {
+        &self.tables
+    }
+
+    /// The QEMU linker/loader commands ('etc/table-loader') with which the
+    /// firmware allocates the tables, relocates their pointers and sets
+    /// their checksums
+    pub fn loader(&self) -> Vec<u8> {
+        let mut loader = vec![];
+        let mut command = |kind: u32, build: &dyn Fn(&mut Vec<u8>)| {
+            let mut entry = Vec::with_capacity(LOADER_ENTRY_SIZE);
+            entry.extend_from_slice(&kind.to_le_bytes());
+            build(&mut entry);
+            entry.resize(LOADER_ENTRY_SIZE, 0);
+            loader.extend_from_slice(&entry);
+        };
+        let file_name = |entry: &mut Vec<u8>, blob: Blob| {
+            let start = entry.len();
+            entry.extend_from_slice(blob.file().as_bytes());
+            entry.resize(start + LOADER_FILE_NAME_SIZE, 0);
+        };
+
+        command(LOADER_ALLOCATE, &|entry| {
+            file_name(entry, Blob::Rsdp);
+            entry.extend_from_slice(&RSDP_ALIGN.to_le_bytes());
+            entry.push(LOADER_ZONE_FSEG);
+        });
+        command(LOADER_ALLOCATE, &|entry| {
+            file_name(entry, Blob::Tables);
+            entry.extend_from_slice(&TABLES_ALIGN.to_le_bytes());
+            entry.push(LOADER_ZONE_HIGH);
+        });
+        for pointer in self.pointers.iter() {
+            command(LOADER_ADD_POINTER, &|entry| {
+                file_name(entry, pointer.blob);
+                file_name(entry, Blob::Tables);
+                entry.extend_from_slice(&(pointer.offset as u32).to_le_bytes());
+                entry.push(4);
+            });
+        }
+        for checksum in self.checksums.iter() {
+            command(LOADER_ADD_CHECKSUM, &|entry| {
+                file_name(entry, checksum.blob);
+                for val in &[
+                    checksum.offset,
+                    checksum.range.start,
+                    checksum.range.len(),
+                ] {
+                    entry.extend_from_slice(&(*val as u32).to_le_bytes());
+                }
+            });
+        }
+        loader
+    }
+
+    /// The RSDP and the other tables, with their pointers relocated for
+    /// the tables placed at the guest physical address `tables_addr`
+    pub fn relocate(&self, tables_addr: u32) -> (Vec<u8>, Vec<u8>) {
+        let mut rsdp = self.rsdp.clone();
+        let mut tables = self.tables.clone();
+        for pointer in self.pointers.iter() {
+            let blob = match pointer.blob {
+                Blob::Rsdp => &mut rsdp,
+                Blob::Tables => &mut tables,
+            };
+            let field = &mut blob[pointer.offset..pointer.offset + 4];
+            let mut val = [0u8; 4];
+            val.copy_from_slice(field);
+            let val = u32::from_le_bytes(val).wrapping_add(tables_addr);
+            field.copy_from_slice(&val.to_le_bytes());
+        }
+        for sum in self.checksums.iter() {
+            let blob = match sum.blob {
+                Blob::Rsdp => &mut rsdp,
+                Blob::Tables => &mut tables,
+            };
+            blob[sum.offset] = 0;
+            blob[sum.offset] = checksum(&blob[sum.range.clone()]);
+        }
+        (rsdp, tables)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::acpi::madt::{Ics, MADT};
+    use crate::acpi::rsdt::SDT;
+
+    fn find_table<'a>(tables: &'a [u8], offset: u32) -> SDT<'a> {
+        unsafe { SDT::new(tables.as_ptr().add(offset as usize)) }.unwrap()
+    }
+
+    #[test]
+    fn test_guest_tables() {
+        let mut builder = AcpiTablesBuilder::new(vec![0, 2], 0xb000);
+        builder.set_hpet(0xfed00000);
+        builder.set_ecam(0xb000_0000);
+        builder.set_tpm(0xfed40000);
+        let tables = builder.build().unwrap();
+
+        // With the tables at address zero, the pointers are offsets into
+        // the relocated tables (and the parsers verify the checksums)
+        let (rsdp, relocated) = tables.relocate(0);
+        assert_eq!(checksum(&rsdp), 0);
+        let rsdt = u32::from_le_bytes([rsdp[16], rsdp[17], rsdp[18], rsdp[19]]);
+
+        let rsdt = find_table(&relocated, rsdt);
+        assert_eq!(&rsdt.signature, b"RSDT");
+        let entries = rsdt
+            .table
+            .chunks(4)
+            .map(|entry| {
+                u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]])
+            })
+            .collect::<Vec<_>>();
+        let signatures = entries
+            .iter()
+            .map(|entry| find_table(&relocated, *entry).signature)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            signatures,
+            vec![*b"FACP", *b"APIC", *b"HPET", *b"MCFG", *b"TPM2"]
+        );
+
+        // The TPM2 table points at the CRB control area
+        let tpm2 = find_table(&relocated, entries[4]);
+        assert_eq!(&tpm2.table[4..12], &0xfed40040u64.to_le_bytes());
+        assert_eq!(&tpm2.table[12..16], &7u32.to_le_bytes());
+
+        // Both CPUs and the I/O APIC are described
+        let madt = find_table(&relocated, entries[1]);
+        let madt = MADT::new(&madt);
+        let apic_ids = madt
+            .structures()
+            .filter_map(|ics| match ics {
+                Ok(Ics::LocalApic { apic_id, .. }) => Some(apic_id),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(apic_ids, vec![0, 2]);
+        assert!(madt
+            .structures()
+            .any(|ics| matches!(ics, Ok(Ics::IoApic { .. }))));
+    }
+
+    #[test]
+    fn test_table_loader() {
+        let tables = AcpiTablesBuilder::new(vec![0], 0xb000).build().unwrap();
+        let loader = tables.loader();
+        assert_eq!(loader.len() % LOADER_ENTRY_SIZE, 0);
+
+        // The tables are allocated before their pointers are relocated,
+        // and the checksums are set last
+        let commands = loader
+            .chunks(LOADER_ENTRY_SIZE)
+            .map(|entry| entry[0] as u32)
+            .collect::<Vec<_>>();
+        assert_eq!(&commands[..2], &[LOADER_ALLOCATE, LOADER_ALLOCATE]);
+        assert_eq!(commands[2], LOADER_ADD_POINTER);
+        assert_eq!(commands.last(), Some(&LOADER_ADD_CHECKSUM));
+        assert_eq!(&loader[4..4 + RSDP_FILE.len()], RSDP_FILE.as_bytes());
+        assert_eq!(loader[4 + LOADER_FILE_NAME_SIZE + 4], LOADER_ZONE_FSEG);
+
+        // The sleep type of S5 matches the PM1 control block
+        let dsdt = tables
+            .tables()
+            .windows(4)
+            .position(|window| window == b"DSDT")
+            .unwrap();
+        let s5 = &tables.tables()[dsdt + SDT_HEADER_SIZE..];
+        assert_eq!(
+            &s5[..10],
+            &[AML_NAME_OP, b'_', b'S', b'5', b'_', 0x12, 4, 2, 0, 0]
+        );
+    }
+}
@@ -322,6 +322,15 @@ impl LocalApic {
         Ok(())
     }
 
+    /// Stop the timer of this local apic, discarding any interrupt it was
+    /// counting down to
+    pub fn stop_timer(&mut self) {
+        unsafe {
+            msr::wrmsr(msr::IA32_X2APIC_LVT_TIMER, 1 << 16); // Mask the timer
+            msr::wrmsr(msr::IA32_X2APIC_INIT_COUNT, 0);
+        }
+    }
+
     /// Configure the timer for this local apic to generate an interrupt with
     /// the requested vector at the requested time. This will clear any outstanding
     /// apic interrupt.
@@ -0,0 +1,104 @@
+//! Low priority background work
+//!
+//! Each core has a queue of background tasks, which only run while the
+//! core would otherwise be idle: while a vCPU polls for wakeup events
+//! after the guest executes HLT (see `VirtualMachineConfig::set_idle_poll`).
+//! Each step of a task does a small, bounded amount of work, so the vCPU
+//! notices wakeup events promptly. Tasks run in turn, one step at a time.
+
+use crate::error::Result;
+use crate::memory;
+use crate::vm::VirtualMachine;
+use crate::{declare_per_core, get_per_core_mut};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::RwLock;
+
+// The number of pages prefaulted in each step of a `PrefaultTask`
+const PREFAULT_BATCH: usize = 16;
+
+// The number of frames zeroed in each step of a `ScrubTask`
+const SCRUB_BATCH: usize = 16;
+
+declare_per_core! {
+    static mut TASKS: Vec<Box<dyn BackgroundTask>> = Vec::new();
+}
+
+/// Work that is done while a core is idle
+pub trait BackgroundTask: Send {
+    /// A short description of the task (for logging)
+    fn name(&self) -> &'static str;
+
+    /// Perform a bounded amount of work, returning `true` once the task is
+    /// complete (and may be removed from the queue)
+    fn step(&mut self) -> Result<bool>;
+}
+
+/// Queue `task` to run on the current core
+pub fn spawn(task: Box<dyn BackgroundTask>) {
+    debug!("Starting background task: {}", task.name());
+    get_per_core_mut!(TASKS).push(task);
+}
+
+/// The number of tasks queued on the current core
+pub fn pending() -> usize {
+    get_per_core_mut!(TASKS).len()
+}
+
+/// Run one step of the next task queued on the current core
+///
+/// Returns false if there are no tasks to run. Tasks that fail are logged
+/// and removed from the queue.
+pub fn run_step() -> bool {
+    let tasks = get_per_core_mut!(TASKS);
+    if tasks.is_empty() {
+        return false;
+    }
+    let mut task = tasks.remove(0);
+    match task.step() {
+        Ok(true) => debug!("Background task complete: {}", task.name()),
+        Ok(false) => tasks.push(task),
+        Err(e) => warn!("Background task {} failed: {:?}", task.name(), e),
+    }
+    true
+}
+
+/// Allocate the remaining lazily allocated memory of a VM, so the guest
+/// does not pay for the allocation when it first touches each page
+pub struct PrefaultTask {
+    vm: Arc<RwLock<VirtualMachine>>,
+}
+
+impl PrefaultTask {
+    pub fn new(vm: Arc<RwLock<VirtualMachine>>) -> Self {
+        Self { vm }
+    }
+}
+
+impl BackgroundTask for PrefaultTask {
+    fn name(&self) -> &'static str {
+        "prefault guest memory"
+    }
+
+    fn step(&mut self) -> Result<bool> {
+        Ok(self.vm.write().guest_space.prefault(PREFAULT_BATCH)? == 0)
+    }
+}
+
+/// Zero the frames released by guests, so they can be reused for guest
+/// memory without zeroing them on the allocation path
+///
+/// Frames may be released at any time, so this task never completes.
+pub struct ScrubTask;
+
+impl BackgroundTask for ScrubTask {
+    fn name(&self) -> &'static str {
+        "scrub released frames"
+    }
+
+    fn step(&mut self) -> Result<bool> {
+        memory::scrub_frames(SCRUB_BATCH);
+        Ok(false)
+    }
+}
@@ -0,0 +1,135 @@
+//! The real-mode interrupt vector table and BIOS data area of a guest
+//!
+//! Firmware normally fills in the IVT and BDA during POST. When a VM boots
+//! without running its firmware (see `BootMethod::Pvh`), bootloaders and
+//! kernels that probe these structures during early boot would otherwise
+//! find zeroes, so a minimal version is written instead: every vector
+//! points at an `IRET`, and the BDA describes the guest's COM ports,
+//! conventional memory and extended BIOS data area.
+
+use crate::virtdev::Port;
+use alloc::vec::Vec;
+
+/// The guest physical address of the interrupt vector table
+pub const IVT_ADDR: u64 = 0x0;
+
+/// The guest physical address of the BIOS data area
+pub const BDA_ADDR: u64 = 0x400;
+
+/// The guest physical address of the extended BIOS data area (the top of
+/// conventional memory)
+pub const EBDA_ADDR: u64 = 0x9fc00;
+
+/// The guest physical address of the handler of all interrupt vectors (an
+/// `IRET`, at the traditional F000:FF53)
+pub const IRET_STUB_ADDR: u64 =
+    ((IRET_STUB_SEGMENT as u64) << 4) + IRET_STUB_OFFSET as u64;
+
+/// The code of the interrupt handler
+pub const IRET_STUB: [u8; 1] = [0xcf];
+
+const IRET_STUB_SEGMENT: u16 = 0xf000;
+const IRET_STUB_OFFSET: u16 = 0xff53;
+const IVT_ENTRIES: usize = 256;
+const BDA_SIZE: usize = 0x100;
+const EBDA_SIZE_KB: u16 = 1;
+
+// The offsets of the BDA fields
+const BDA_COM_PORTS: usize = 0x00;
+const BDA_EBDA_SEGMENT: usize = 0x0e;
+const BDA_EQUIPMENT: usize = 0x10;
+const BDA_MEMORY_SIZE: usize = 0x13;
+const BDA_VIDEO_COLUMNS: usize = 0x4a;
+const BDA_VIDEO_CRTC_BASE: usize = 0x63;
+
+// Equipment word bits
+const EQUIPMENT_FPU: u16 = 1 << 1;
+const EQUIPMENT_VIDEO_80X25_COLOR: u16 = 0b10 << 4;
+const EQUIPMENT_SERIAL_SHIFT: u16 = 9;
+
+/// The legacy BIOS structures of a guest
+pub struct BiosData {
+    ivt: Vec<u8>,
+    bda: Vec<u8>,
+    ebda: Vec<u8>,
+}
+
+impl BiosData {
+    /// Build the IVT, BDA and EBDA of a guest with UARTs at `com_ports`
+    /// (in COM1 to COM4 order, at most four)
+    pub fn new(com_ports: &[Port]) -> Self {
+        let mut ivt = Vec::with_capacity(IVT_ENTRIES * 4);
+        for _ in 0..IVT_ENTRIES {
+            ivt.extend_from_slice(&IRET_STUB_OFFSET.to_le_bytes());
+            ivt.extend_from_slice(&IRET_STUB_SEGMENT.to_le_bytes());
+        }
+
+        let mut bda = vec![0u8; BDA_SIZE];
+        let mut set_u16 = |offset: usize, val: u16| {
+            bda[offset..offset + 2].copy_from_slice(&val.to_le_bytes())
+        };
+        let com_ports = &com_ports[..com_ports.len().min(4)];
+        for (i, port) in com_ports.iter().enumerate() {
+            set_u16(BDA_COM_PORTS + i * 2, *port);
+        }
+        set_u16(BDA_EBDA_SEGMENT, (EBDA_ADDR >> 4) as u16);
+        set_u16(
+            BDA_EQUIPMENT,
+            EQUIPMENT_FPU
+                | EQUIPMENT_VIDEO_80X25_COLOR
+                | (com_ports.len() as u16) << EQUIPMENT_SERIAL_SHIFT,
+        );
+        set_u16(BDA_MEMORY_SIZE, (EBDA_ADDR >> 10) as u16);
+        set_u16(BDA_VIDEO_COLUMNS, 80);
+        set_u16(BDA_VIDEO_CRTC_BASE, 0x3d4);
+
+        // The first byte of the EBDA is its size (in KB)
+        let mut ebda = vec![0u8; (EBDA_SIZE_KB as usize) << 10];
+        ebda[0] = EBDA_SIZE_KB as u8;
+
+        BiosData { ivt, bda, ebda }
+    }
+
+    /// The interrupt vector table (to be placed at `IVT_ADDR`)
+    pub fn ivt(&self) -> &[u8] {
+        &self.ivt
+    }
+
+    /// The BIOS data area (to be placed at `BDA_ADDR`)
+    pub fn bda(&self) -> &[u8] {
+        &self.bda
+    }
+
+    /// The extended BIOS data area (to be placed at `EBDA_ADDR`)
+    pub fn ebda(&self) -> &[u8] {
+        &self.ebda
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+    }
+
+    #[test]
+    fn test_bios_data() {
+        let data = BiosData::new(&[0x3f8, 0x2f8]);
+
+        // Every vector points at F000:FF53
+        assert_eq!(data.ivt().len(), 1024);
+        assert_eq!(read_u16(data.ivt(), 0x21 * 4), 0xff53);
+        assert_eq!(read_u16(data.ivt(), 0x21 * 4 + 2), 0xf000);
+
+        let bda = data.bda();
+        assert_eq!(read_u16(bda, BDA_COM_PORTS), 0x3f8);
+        assert_eq!(read_u16(bda, BDA_COM_PORTS + 2), 0x2f8);
+        assert_eq!(read_u16(bda, BDA_COM_PORTS + 4), 0);
+        assert_eq!(read_u16(bda, BDA_EQUIPMENT) >> EQUIPMENT_SERIAL_SHIFT, 2);
+        assert_eq!(read_u16(bda, BDA_MEMORY_SIZE), 639);
+        assert_eq!(read_u16(bda, BDA_EBDA_SEGMENT), 0x9fc0);
+        assert_eq!(data.ebda()[0], 1);
+    }
+}
@@ -0,0 +1,172 @@
+//! Fault injection for chaos testing
+//!
+//! The `inject` and `latency` management console commands deliberately
+//! disturb a running VM, so the guest's resilience (and the hypervisor's
+//! own error paths) can be exercised on demand. Injections are sent to the
+//! VM as messages, and so are applied by the vCPU that receives the VM's
+//! messages the next time it exits.
+
+use crate::error::Result;
+use crate::vcpu::{InjectedInterruptType, VCpu};
+use crate::{declare_per_core, get_per_core, get_per_core_mut};
+use crate::{time, vm, vmcs};
+use core::fmt;
+use core::time::Duration;
+
+const NMI_VECTOR: u8 = 2;
+const MACHINE_CHECK_VECTOR: u8 = 18;
+
+declare_per_core! {
+    static mut EXIT_LATENCY: Duration = Duration::from_secs(0);
+}
+
+/// A disturbance applied to a running VM
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Injection {
+    /// Deliver a non-maskable interrupt
+    Nmi,
+    /// Deliver a machine check exception (#MC)
+    MachineCheck,
+    /// Deliver an external interrupt with the given vector, regardless of
+    /// whether any device raised it
+    Vector(u8),
+    /// Force the guest into a triple fault
+    TripleFault,
+    /// Delay the handling of every exit by the given time
+    ExitLatency(Duration),
+}
+
+impl fmt::Display for Injection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Injection::Nmi => write!(f, "NMI"),
+            Injection::MachineCheck => write!(f, "machine check"),
+            Injection::Vector(vector) => write!(f, "vector {}", vector),
+            Injection::TripleFault => write!(f, "triple fault"),
+            Injection::ExitLatency(latency) => {
+                write!(f, "{}us exit latency", latency.as_micros())
+            }
+        }
+    }
+}
+
+/// Apply `injection` to the VM of `vcpu`
+///
+/// Events are delivered through the normal injection queue, so they wait
+/// for the guest to be interruptible like any other pending event.
+pub fn apply(vcpu: &mut VCpu, injection: Injection) -> Result<()> {
+    warn!("Chaos: injecting {}", injection);
+    match injection {
+        Injection::Nmi => vcpu.inject_interrupt(
+            NMI_VECTOR,
+            InjectedInterruptType::NonMaskableInterrupt,
+        ),
+        Injection::MachineCheck => vcpu.inject_interrupt(
+            MACHINE_CHECK_VECTOR,
+            InjectedInterruptType::HardwareException,
+        ),
+        Injection::Vector(vector) => vcpu
+            .inject_interrupt(vector, InjectedInterruptType::ExternalInterrupt),
+        Injection::TripleFault => {
+            // With an empty IDT, delivering the NMI raises #GP, delivering
+            // the #GP raises #DF, and delivering the #DF shuts down the
+            // processor (see Section 6.15 of the SDM)
+            vcpu.vmcs.write_field(vmcs::VmcsField::GuestIdtrLimit, 0)?;
+            vcpu.inject_interrupt(
+                NMI_VECTOR,
+                InjectedInterruptType::NonMaskableInterrupt,
+            );
+        }
+        Injection::ExitLatency(latency) => {
+            *get_per_core_mut!(EXIT_LATENCY) = latency;
+        }
+    }
+    Ok(())
+}
+
+/// Delay the current exit by the latency injected on this core (if any)
+pub fn delay_exit() {
+    let latency = *get_per_core!(EXIT_LATENCY);
+    if latency == Duration::from_secs(0) {
+        return;
+    }
+    let deadline = time::now() + latency;
+    while time::now() < deadline {
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+fn parse_injection(args: &[&str]) -> Option<Injection> {
+    match args {
+        ["nmi"] => Some(Injection::Nmi),
+        ["mce"] => Some(Injection::MachineCheck),
+        ["triple-fault"] => Some(Injection::TripleFault),
+        ["vector", vector] => vector.parse::<u8>().ok().map(Injection::Vector),
+        _ => None,
+    }
+}
+
+fn send(
+    out: &mut dyn fmt::Write,
+    vm_id: u32,
+    injection: Injection,
+) -> fmt::Result {
+    match vm::send_vm_msg(vm::VirtualMachineMsg::Inject(injection), vm_id) {
+        Ok(()) => writeln!(
+            out,
+            "Sent {} to {}",
+            injection,
+            vm::vm_name(vm_id).unwrap_or("?")
+        ),
+        Err(e) => writeln!(out, "Failed to send {}: {:?}", injection, e),
+    }
+}
+
+/// The `inject` management console command
+pub fn inject_command(out: &mut dyn fmt::Write, args: &[&str]) -> fmt::Result {
+    let vm_id = args.get(0).and_then(|vm| vm::find_vm(vm));
+    let injection = args.get(1..).and_then(parse_injection);
+    match (vm_id, injection) {
+        (Some(vm_id), Some(injection)) => send(out, vm_id, injection),
+        _ => writeln!(
+            out,
+            "usage: inject <vm> nmi|mce|triple-fault|vector <vector>"
+        ),
+    }
+}
+
+/// The `latency` management console command
+pub fn latency_command(out: &mut dyn fmt::Write, args: &[&str]) -> fmt::Result {
+    let vm_id = args.get(0).and_then(|vm| vm::find_vm(vm));
+    let micros = args.get(1).and_then(|us| us.parse::<u64>().ok());
+    match (vm_id, micros) {
+        (Some(vm_id), Some(micros)) => send(
+            out,
+            vm_id,
+            Injection::ExitLatency(Duration::from_micros(micros)),
+        ),
+        _ => writeln!(out, "usage: latency <vm> <microseconds>"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_injection() {
+        assert_eq!(parse_injection(&["nmi"]), Some(Injection::Nmi));
+        assert_eq!(parse_injection(&["mce"]), Some(Injection::MachineCheck));
+        assert_eq!(
+            parse_injection(&["triple-fault"]),
+            Some(Injection::TripleFault)
+        );
+        assert_eq!(
+            parse_injection(&["vector", "39"]),
+            Some(Injection::Vector(39))
+        );
+        assert_eq!(parse_injection(&["vector", "256"]), None);
+        assert_eq!(parse_injection(&["nmi", "extra"]), None);
+        assert_eq!(parse_injection(&[]), None);
+    }
+}
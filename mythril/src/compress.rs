@@ -0,0 +1,220 @@
+//! LZ4 block compression
+//!
+//! A small implementation of the LZ4 block format (without the frame
+//! format), used to compress guest pages in snapshots and migration
+//! streams. The compressor uses a single-entry hash table, so it favors
+//! speed over ratio, and the output can be decompressed by any LZ4
+//! implementation (e.g., `lz4.block.decompress` in python).
+
+use crate::error::{Error, Result};
+use alloc::vec::Vec;
+
+const MIN_MATCH: usize = 4;
+const HASH_BITS: u32 = 12;
+const MAX_OFFSET: usize = 0xffff;
+
+// The last match must start at least 12 bytes before the end of the
+// block, and the last 5 bytes are always literals
+const MF_LIMIT: usize = 12;
+const LAST_LITERALS: usize = 5;
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+// Lengths of 15 or more are stored in the token nibble as 15, followed by
+// the remainder as a run of 255s and a final byte less than 255
+fn write_length(out: &mut Vec<u8>, mut length: usize) {
+    while length >= 0xff {
+        out.push(0xff);
+        length -= 0xff;
+    }
+    out.push(length as u8);
+}
+
+fn write_sequence(
+    out: &mut Vec<u8>,
+    literals: &[u8],
+    offset: usize,
+    len: usize,
+) {
+    let match_len = len - MIN_MATCH;
+    out.push(((literals.len().min(15) as u8) << 4) | match_len.min(15) as u8);
+    if literals.len() >= 15 {
+        write_length(out, literals.len() - 15);
+    }
+    out.extend_from_slice(literals);
+    out.extend_from_slice(&(offset as u16).to_le_bytes());
+    if match_len >= 15 {
+        write_length(out, match_len - 15);
+    }
+}
+
+fn write_last_literals(out: &mut Vec<u8>, literals: &[u8]) {
+    out.push((literals.len().min(15) as u8) << 4);
+    if literals.len() >= 15 {
+        write_length(out, literals.len() - 15);
+    }
+    out.extend_from_slice(literals);
+}
+
+/// Compress `input` as a single LZ4 block, appending it to `out`
+pub fn compress(input: &[u8], out: &mut Vec<u8>) {
+    let mut table = [0u32; 1 << HASH_BITS];
+    let mut anchor = 0;
+    let mut i = 0;
+
+    if input.len() > MF_LIMIT {
+        let limit = input.len() - MF_LIMIT;
+        let match_limit = input.len() - LAST_LITERALS;
+        while i < limit {
+            let sequence = read_u32(input, i);
+            let slot = hash(sequence);
+            let candidate = table[slot] as usize;
+            table[slot] = i as u32;
+
+            if candidate >= i
+                || i - candidate > MAX_OFFSET
+                || read_u32(input, candidate) != sequence
+            {
+                i += 1;
+                continue;
+            }
+
+            let mut len = MIN_MATCH;
+            while i + len < match_limit
+                && input[candidate + len] == input[i + len]
+            {
+                len += 1;
+            }
+            write_sequence(out, &input[anchor..i], i - candidate, len);
+            i += len;
+            anchor = i;
+        }
+    }
+    write_last_literals(out, &input[anchor..]);
+}
+
+fn read_length(input: &[u8], offset: &mut usize) -> Result<usize> {
+    let mut length = 0;
+    loop {
+        let byte = *input.get(*offset).ok_or_else(truncated)?;
+        *offset += 1;
+        length += byte as usize;
+        if byte != 0xff {
+            return Ok(length);
+        }
+    }
+}
+
+fn truncated() -> Error {
+    Error::InvalidValue("Truncated LZ4 block".into())
+}
+
+/// Decompress the LZ4 block `input`, appending the contents to `out`
+///
+/// Fails if the block is malformed or would grow `out` past `limit` bytes.
+pub fn decompress(input: &[u8], out: &mut Vec<u8>, limit: usize) -> Result<()> {
+    let mut i = 0;
+    loop {
+        let token = *input.get(i).ok_or_else(truncated)?;
+        i += 1;
+
+        let mut literals = (token >> 4) as usize;
+        if literals == 15 {
+            literals += read_length(input, &mut i)?;
+        }
+        let end = i.checked_add(literals).ok_or_else(truncated)?;
+        let literals = input.get(i..end).ok_or_else(truncated)?;
+        if out.len() + literals.len() > limit {
+            return Err(Error::InvalidValue(
+                "LZ4 block exceeds the output limit".into(),
+            ));
+        }
+        out.extend_from_slice(literals);
+        i = end;
+
+        // The last sequence has no match
+        if i == input.len() {
+            return Ok(());
+        }
+
+        let offset = input.get(i..i + 2).ok_or_else(truncated)?;
+        let offset = u16::from_le_bytes([offset[0], offset[1]]) as usize;
+        i += 2;
+        if offset == 0 || offset > out.len() {
+            return Err(Error::InvalidValue(format!(
+                "Invalid LZ4 match offset: {}",
+                offset
+            )));
+        }
+
+        let mut len = (token & 0xf) as usize + MIN_MATCH;
+        if token & 0xf == 15 {
+            len += read_length(input, &mut i)?;
+        }
+        if out.len() + len > limit {
+            return Err(Error::InvalidValue(
+                "LZ4 block exceeds the output limit".into(),
+            ));
+        }
+
+        // Matches may overlap the bytes they produce, so copy bytewise
+        let start = out.len() - offset;
+        for j in 0..len {
+            let byte = out[start + j];
+            out.push(byte);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn round_trip(data: &[u8]) -> Vec<u8> {
+        let mut compressed = vec![];
+        compress(data, &mut compressed);
+        let mut out = vec![];
+        decompress(&compressed, &mut out, data.len()).unwrap();
+        assert_eq!(out, data);
+        compressed
+    }
+
+    #[test]
+    fn test_round_trip() {
+        assert_eq!(round_trip(b""), [0]);
+        assert_eq!(round_trip(b"abc"), b"\x30abc");
+        round_trip(b"0123456789abcdef0123456789abcdef");
+
+        let text = b"the quick brown fox jumps over the lazy dog. ".repeat(100);
+        assert!(round_trip(&text).len() < 100);
+
+        let page = (0..4096).map(|i| (i * 7 % 251) as u8).collect::<Vec<_>>();
+        round_trip(&page);
+        assert!(round_trip(&[0xaa; 4096]).len() < 32);
+    }
+
+    #[test]
+    fn test_decompress() {
+        // Two literals, then a 6 byte match at offset 2, then a literal
+        let mut out = vec![];
+        decompress(b"\x22ab\x02\x00\x10c", &mut out, 16).unwrap();
+        assert_eq!(out, b"ababababc");
+    }
+
+    #[test]
+    fn test_decompress_invalid() {
+        let mut out = vec![];
+        assert!(decompress(b"", &mut out, 16).is_err());
+        assert!(decompress(b"\x30ab", &mut out, 16).is_err());
+        assert!(decompress(b"\x10a\x05\x00\x00", &mut out, 16).is_err());
+        assert!(decompress(b"\x10a\x01\x00\x0f\xff", &mut out, 64).is_err());
+    }
+}
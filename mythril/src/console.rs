@@ -0,0 +1,316 @@
+//! The hypervisor management console
+//!
+//! The management console shares the physical serial port with the guest
+//! that currently owns it. Typing `Ctrl-a m` switches the serial input to
+//! the console, which accepts simple line-based commands (see `help`).
+//! The `exit` command returns the input to the guest.
+
+use crate::logger;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+
+const CTRL_A: u8 = 0x01;
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+
+const PROMPT: &str = "mythril> ";
+const MAX_LINE_LENGTH: usize = 128;
+
+/// The handler for a console command
+///
+/// The handler receives the whitespace separated arguments that followed
+/// the command name, and writes its output to the provided writer.
+pub type CommandHandler = fn(&mut dyn fmt::Write, &[&str]) -> fmt::Result;
+
+/// A management console command
+pub struct Command {
+    /// The name used to invoke the command
+    pub name: &'static str,
+    /// A short description of the command (shown by `help`)
+    pub help: &'static str,
+    /// The function that executes the command
+    pub handler: CommandHandler,
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        help: "List the available commands",
+        handler: help_command,
+    },
+    Command {
+        name: "exit",
+        help: "Return the serial input to the guest",
+        handler: exit_command,
+    },
+    Command {
+        name: "vms",
+        help: "List the VMs by id, name and UUID",
+        handler: crate::vm::vms_command,
+    },
+    Command {
+        name: "health",
+        help: "Report the liveness of each core",
+        handler: crate::health::health_command,
+    },
+    Command {
+        name: "exitlat",
+        help: "Show (or clear) the histograms of exit handling times",
+        handler: crate::health::exitlat_command,
+    },
+    Command {
+        name: "sched",
+        help: "List the core pools and their scheduler policies",
+        handler: crate::sched::sched_command,
+    },
+    Command {
+        name: "migrate",
+        help: "List the moved vCPUs and idle cores, or move a vCPU",
+        handler: crate::migrate::migrate_command,
+    },
+    Command {
+        name: "offline",
+        help: "Park a core (after moving its vCPU), or hand it back",
+        handler: crate::offline::offline_command,
+    },
+    Command {
+        name: "hostmem",
+        help: "Show the uses of host physical memory",
+        handler: crate::hostmem::hostmem_command,
+    },
+    Command {
+        name: "dmesg",
+        help: "Show the most recent hypervisor log output",
+        handler: crate::logger::dmesg_command,
+    },
+    Command {
+        name: "watch",
+        help: "List watches, or watch a VMCS field or guest memory byte",
+        handler: crate::watch::watch_command,
+    },
+    Command {
+        name: "unwatch",
+        help: "Remove a watch",
+        handler: crate::watch::unwatch_command,
+    },
+    Command {
+        name: "continue",
+        help:
+            "Resume the vCPUs paused by a watch or at their first instruction",
+        handler: crate::watch::continue_command,
+    },
+    Command {
+        name: "coredump",
+        help: "Write the memory and registers of a paused VM as an ELF core",
+        handler: crate::coredump::coredump_command,
+    },
+    Command {
+        name: "snapshot",
+        help: "Write the memory of a paused VM as a compressed page stream",
+        handler: crate::snapshot::snapshot_command,
+    },
+    Command {
+        name: "snapkey",
+        help: "Set or clear the key that seals a VM's snapshots",
+        handler: crate::seal::snapkey_command,
+    },
+    Command {
+        name: "memscan",
+        help: "Search the memory of a paused VM for byte patterns",
+        handler: crate::memscan::memscan_command,
+    },
+    Command {
+        name: "balloon",
+        help: "Set the size of a VM's memory balloon (in MB)",
+        handler: crate::virtdev::virtio::balloon::balloon_command,
+    },
+    Command {
+        name: "uartstats",
+        help: "Report the UART output counters and rate limit of a VM",
+        handler: crate::ratelimit::uartstats_command,
+    },
+    Command {
+        name: "consoleroute",
+        help: "List, add or remove the routes mirroring VM console output",
+        handler: crate::consoleroute::consoleroute_command,
+    },
+    Command {
+        name: "rng",
+        help: "Show the state of the entropy sources",
+        handler: crate::rng::rng_command,
+    },
+    Command {
+        name: "statsdump",
+        help: "Dump the counters of a VM in the binary stats format (as hex)",
+        handler: crate::stats::statsdump_command,
+    },
+    Command {
+        name: "devtrace",
+        help: "List traced devices, or show (or clear) a device's events",
+        handler: crate::virtdev::trace::devtrace_command,
+    },
+    Command {
+        name: "selftest",
+        help: "Report the results of the selftest VM",
+        handler: crate::selftest::selftest_command,
+    },
+    Command {
+        name: "measurements",
+        help: "Report the PCR values of a VM with measured boot enabled",
+        handler: crate::measure::measurements_command,
+    },
+    Command {
+        name: "cpudiff",
+        help: "Report the CPUID leaves and MSRs a VM sees unlike the host",
+        handler: crate::emulate::cpudiff_command,
+    },
+    Command {
+        name: "inject",
+        help: "Inject an NMI, #MC, vector or triple fault into a VM",
+        handler: crate::chaos::inject_command,
+    },
+    Command {
+        name: "latency",
+        help: "Delay every exit of a VM (for chaos testing)",
+        handler: crate::chaos::latency_command,
+    },
+];
+
+struct ManagementConsole {
+    active: bool,
+    escape_pending: bool,
+    line: [u8; MAX_LINE_LENGTH],
+    line_len: usize,
+}
+
+static CONSOLE: Mutex<ManagementConsole> = Mutex::new(ManagementConsole {
+    active: false,
+    escape_pending: false,
+    line: [0; MAX_LINE_LENGTH],
+    line_len: 0,
+});
+
+/// Writes command output to the physical console
+pub struct ConsoleWriter;
+
+impl fmt::Write for ConsoleWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // Serial terminals expect carriage returns before newlines
+        for (i, part) in s.split('\n').enumerate() {
+            if i > 0 {
+                logger::write_console("\r\n");
+            }
+            logger::write_console(part);
+        }
+        Ok(())
+    }
+}
+
+fn help_command(out: &mut dyn fmt::Write, _args: &[&str]) -> fmt::Result {
+    for command in COMMANDS {
+        writeln!(out, "  {:<12} {}", command.name, command.help)?;
+    }
+    Ok(())
+}
+
+fn exit_command(_out: &mut dyn fmt::Write, _args: &[&str]) -> fmt::Result {
+    CONSOLE.lock().active = false;
+    Ok(())
+}
+
+/// Returns true if the management console currently owns the serial input
+pub fn is_active() -> bool {
+    CONSOLE.lock().active
+}
+
+/// Execute a single command line, writing the output to `out`
+pub fn execute(out: &mut dyn fmt::Write, line: &str) -> fmt::Result {
+    let words = line.split_whitespace().collect::<Vec<_>>();
+    let (name, args) = match words.split_first() {
+        Some((name, args)) => (*name, args),
+        None => return Ok(()),
+    };
+    match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => (command.handler)(out, args),
+        None => writeln!(out, "Unknown command '{}' (try 'help')", name),
+    }
+}
+
+/// Offer a byte received on the physical serial port to the console
+///
+/// Returns true if the byte was consumed by the console (and so must not
+/// be delivered to the guest).
+pub fn handle_key(key: u8) -> bool {
+    let mut console = CONSOLE.lock();
+    if !console.active {
+        let open = console.escape_pending && key == b'm';
+        console.escape_pending = key == CTRL_A;
+        if open {
+            console.active = true;
+            console.line_len = 0;
+            drop(console);
+            logger::write_console("\r\nmythril management console\r\n");
+            logger::write_console(PROMPT);
+        }
+        return open;
+    }
+
+    match key {
+        b'\r' | b'\n' => {
+            let len = console.line_len;
+            let line = console.line;
+            console.line_len = 0;
+            drop(console);
+
+            logger::write_console("\r\n");
+            let line = core::str::from_utf8(&line[..len]).unwrap_or("");
+            let _ = execute(&mut ConsoleWriter, line);
+            if is_active() {
+                logger::write_console(PROMPT);
+            }
+        }
+        BACKSPACE | DELETE => {
+            if console.line_len > 0 {
+                console.line_len -= 1;
+                drop(console);
+                logger::write_console("\x08 \x08");
+            }
+        }
+        key if key.is_ascii_graphic() || key == b' ' => {
+            if console.line_len < MAX_LINE_LENGTH {
+                let len = console.line_len;
+                console.line[len] = key;
+                console.line_len += 1;
+                drop(console);
+                let echo = [key];
+                logger::write_console(
+                    core::str::from_utf8(&echo).unwrap_or(""),
+                );
+            }
+        }
+        _ => (),
+    }
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::string::String;
+
+    #[test]
+    fn test_execute() {
+        let mut out = String::new();
+        execute(&mut out, "  help  ").unwrap();
+        assert!(out.lines().any(|line| line.trim().starts_with("health")));
+
+        let mut out = String::new();
+        execute(&mut out, "bogus arg").unwrap();
+        assert_eq!(out, "Unknown command 'bogus' (try 'help')\n");
+
+        let mut out = String::new();
+        execute(&mut out, "").unwrap();
+        assert!(out.is_empty());
+    }
+}
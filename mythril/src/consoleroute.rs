@@ -0,0 +1,431 @@
+//! Routing of guest console output
+//!
+//! The physical serial port has a single owner at a time, which receives
+//! its input (see `VirtualMachineMsg::GrantConsole`) and whose output is
+//! written to it unchanged. In addition, the output of any VM can be
+//! mirrored to any number of destinations by a `ConsoleRoute`:
+//!
+//!  - The physical serial port, where each line is prefixed with the name
+//!    of the VM
+//!  - The channel of a management VM, a bounded buffer of (prefixed) lines
+//!    that it reads with the `HC_MYTHRIL_CONSOLE_READ` hypercall. When the
+//!    buffer is full, the oldest lines are discarded.
+//!
+//! Mirrored output is routed by line, so each route has a `ConsoleFilter`
+//! that selects the lines it carries. Lines longer than `MAX_LINE_LEN` are
+//! split. Routes are set up with `VirtualMachineConfig::add_console_route`,
+//! or at runtime with the `consoleroute` management console command.
+
+use crate::logger;
+use crate::vm;
+use alloc::collections::vec_deque::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+
+/// The longest line that is routed at once
+pub const MAX_LINE_LEN: usize = 256;
+
+/// The size of the buffer of each management VM channel (in bytes)
+pub const CHANNEL_CAPACITY: usize = 16 * 1024;
+
+/// Where a route mirrors console output to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConsoleDestination {
+    /// The physical serial port
+    Serial,
+
+    /// The channel of the management VM with the given id
+    Channel(u32),
+}
+
+/// The lines carried by a route
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConsoleFilter {
+    All,
+
+    /// Only the lines containing the given text
+    Contains(String),
+
+    /// Only the lines that do not contain the given text
+    Excludes(String),
+}
+
+impl ConsoleFilter {
+    pub fn accepts(&self, line: &[u8]) -> bool {
+        let contains = |text: &str| {
+            let text = text.as_bytes();
+            text.is_empty() || line.windows(text.len()).any(|w| w == text)
+        };
+        match self {
+            ConsoleFilter::All => true,
+            ConsoleFilter::Contains(text) => contains(text),
+            ConsoleFilter::Excludes(text) => !contains(text),
+        }
+    }
+}
+
+impl fmt::Display for ConsoleFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConsoleFilter::All => write!(f, "all"),
+            ConsoleFilter::Contains(text) => write!(f, "+{}", text),
+            ConsoleFilter::Excludes(text) => write!(f, "-{}", text),
+        }
+    }
+}
+
+/// Mirrors the console output of the VM `source` to `destination`
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConsoleRoute {
+    pub source: u32,
+    pub destination: ConsoleDestination,
+    pub filter: ConsoleFilter,
+}
+
+struct Channel {
+    vm: u32,
+    data: VecDeque<u8>,
+    dropped: u64,
+}
+
+/// The console routes, and the state of the lines being routed
+pub struct ConsoleRouter {
+    routes: Vec<ConsoleRoute>,
+    // The incomplete line of output of each source VM
+    lines: Vec<(u32, Vec<u8>)>,
+    channels: Vec<Channel>,
+}
+
+impl ConsoleRouter {
+    pub const fn new() -> Self {
+        Self {
+            routes: Vec::new(),
+            lines: Vec::new(),
+            channels: Vec::new(),
+        }
+    }
+
+    pub fn routes(&self) -> &[ConsoleRoute] {
+        &self.routes
+    }
+
+    /// Add `route`, replacing any route with the same source and
+    /// destination
+    pub fn add_route(&mut self, route: ConsoleRoute) {
+        self.remove_route(route.source, route.destination);
+        if let ConsoleDestination::Channel(vm) = route.destination {
+            if !self.channels.iter().any(|channel| channel.vm == vm) {
+                self.channels.push(Channel {
+                    vm,
+                    data: VecDeque::new(),
+                    dropped: 0,
+                });
+            }
+        }
+        self.routes.push(route);
+    }
+
+    /// Remove the route from `source` to `destination`, returning false if
+    /// there is none. The channel of a management VM is kept, so it can
+    /// still read the output already routed to it.
+    pub fn remove_route(
+        &mut self,
+        source: u32,
+        destination: ConsoleDestination,
+    ) -> bool {
+        let count = self.routes.len();
+        self.routes.retain(|route| {
+            route.source != source || route.destination != destination
+        });
+        self.routes.len() != count
+    }
+
+    /// Route a byte of console output of the VM `source` (named `name`),
+    /// returning the lines to mirror to the physical serial port. If the
+    /// VM `owns_serial`, its output is already written to the serial port,
+    /// so it is not mirrored there.
+    pub fn output(
+        &mut self,
+        source: u32,
+        name: &str,
+        byte: u8,
+        owns_serial: bool,
+    ) -> Vec<String> {
+        if !self.routes.iter().any(|route| route.source == source) {
+            return vec![];
+        }
+        let index = match self.lines.iter().position(|(vm, _)| *vm == source) {
+            Some(index) => index,
+            None => {
+                self.lines.push((source, vec![]));
+                self.lines.len() - 1
+            }
+        };
+        let line = &mut self.lines[index].1;
+        match byte {
+            b'\r' => return vec![],
+            b'\n' => (),
+            byte => {
+                line.push(byte);
+                if line.len() < MAX_LINE_LEN {
+                    return vec![];
+                }
+            }
+        }
+
+        let line = core::mem::replace(line, vec![]);
+        let text = format!("[{}] {}", name, String::from_utf8_lossy(&line));
+        let mut serial = vec![];
+        for route in self.routes.iter() {
+            if route.source != source || !route.filter.accepts(&line) {
+                continue;
+            }
+            match route.destination {
+                ConsoleDestination::Serial if !owns_serial => {
+                    serial.push(text.clone())
+                }
+                ConsoleDestination::Serial => (),
+                ConsoleDestination::Channel(vm) => {
+                    let channel = self
+                        .channels
+                        .iter_mut()
+                        .find(|channel| channel.vm == vm);
+                    if let Some(channel) = channel {
+                        channel.push(text.as_bytes());
+                    }
+                }
+            }
+        }
+        serial
+    }
+
+    /// Move the output routed to the channel of the management VM `vm` to
+    /// `out`, returning the number of bytes
+    pub fn read_channel(&mut self, vm: u32, out: &mut [u8]) -> usize {
+        let channel = match self.channels.iter_mut().find(|c| c.vm == vm) {
+            Some(channel) => channel,
+            None => return 0,
+        };
+        let len = core::cmp::min(out.len(), channel.data.len());
+        for (byte, val) in out.iter_mut().zip(channel.data.drain(..len)) {
+            *byte = val;
+        }
+        len
+    }
+}
+
+impl Channel {
+    fn push(&mut self, line: &[u8]) {
+        // Whole lines are discarded, so the reader never sees a partial one
+        let len = line.len() + 1;
+        while self.data.len() + len > CHANNEL_CAPACITY {
+            match self.data.iter().position(|byte| *byte == b'\n') {
+                Some(end) => drop(self.data.drain(..=end)),
+                None => return,
+            }
+            self.dropped += 1;
+        }
+        self.data.extend(line.iter());
+        self.data.push_back(b'\n');
+    }
+}
+
+static ROUTER: Mutex<ConsoleRouter> = Mutex::new(ConsoleRouter::new());
+
+/// Add `route` to the console routes
+pub fn add_route(route: ConsoleRoute) {
+    ROUTER.lock().add_route(route);
+}
+
+/// Handle a byte of console output of the VM `source` (named `name`)
+pub fn guest_output(source: u32, name: &str, byte: u8, owns_serial: bool) {
+    //TODO: This should be a write to the physical serial device
+    if owns_serial {
+        let s = String::from_utf8_lossy(&[byte]).into_owned();
+        logger::write_console(&s);
+    }
+    let lines = ROUTER.lock().output(source, name, byte, owns_serial);
+    for line in lines {
+        logger::write_console(&line);
+        logger::write_console("\r\n");
+    }
+}
+
+/// Move the console output routed to the management VM `vm` to `out`,
+/// returning the number of bytes
+pub fn read_channel(vm: u32, out: &mut [u8]) -> usize {
+    ROUTER.lock().read_channel(vm, out)
+}
+
+fn parse_destination(arg: &str) -> Option<ConsoleDestination> {
+    if arg == "serial" {
+        Some(ConsoleDestination::Serial)
+    } else {
+        vm::find_vm(arg).map(ConsoleDestination::Channel)
+    }
+}
+
+fn parse_filter(arg: Option<&&str>) -> Option<ConsoleFilter> {
+    match arg {
+        None => Some(ConsoleFilter::All),
+        Some(arg) if arg.len() > 1 && arg.starts_with('+') => {
+            Some(ConsoleFilter::Contains(arg[1..].into()))
+        }
+        Some(arg) if arg.len() > 1 && arg.starts_with('-') => {
+            Some(ConsoleFilter::Excludes(arg[1..].into()))
+        }
+        Some(_) => None,
+    }
+}
+
+/// The `consoleroute` management console command
+pub fn consoleroute_command(
+    out: &mut dyn fmt::Write,
+    args: &[&str],
+) -> fmt::Result {
+    let usage = "usage: consoleroute [add <vm> serial|<vm> [+text|-text]] \
+                 [del <vm> serial|<vm>]";
+    let source = args.get(1).and_then(|vm| vm::find_vm(vm));
+    let destination = args.get(2).and_then(|dest| parse_destination(dest));
+    match (args.get(0), source, destination) {
+        (None, _, _) => {
+            let router = ROUTER.lock();
+            for route in router.routes() {
+                let destination = match route.destination {
+                    ConsoleDestination::Serial => "serial".into(),
+                    ConsoleDestination::Channel(vm) => {
+                        String::from(vm::vm_name(vm).unwrap_or("?"))
+                    }
+                };
+                writeln!(
+                    out,
+                    "{} -> {} ({})",
+                    vm::vm_name(route.source).unwrap_or("?"),
+                    destination,
+                    route.filter
+                )?;
+            }
+            for channel in router.channels.iter() {
+                writeln!(
+                    out,
+                    "channel {}: {} bytes buffered, {} lines dropped",
+                    vm::vm_name(channel.vm).unwrap_or("?"),
+                    channel.data.len(),
+                    channel.dropped
+                )?;
+            }
+            Ok(())
+        }
+        (Some(&"add"), Some(source), Some(destination)) if args.len() <= 4 => {
+            let filter = match parse_filter(args.get(3)) {
+                Some(filter) => filter,
+                None => return writeln!(out, "{}", usage),
+            };
+            add_route(ConsoleRoute {
+                source,
+                destination,
+                filter,
+            });
+            writeln!(out, "Added console route")
+        }
+        (Some(&"del"), Some(source), Some(destination)) if args.len() == 3 => {
+            if ROUTER.lock().remove_route(source, destination) {
+                writeln!(out, "Removed console route")
+            } else {
+                writeln!(out, "No such console route")
+            }
+        }
+        _ => writeln!(out, "{}", usage),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write(
+        router: &mut ConsoleRouter,
+        text: &str,
+        owns: bool,
+    ) -> Vec<String> {
+        text.bytes()
+            .flat_map(|byte| router.output(1, "web", byte, owns))
+            .collect()
+    }
+
+    #[test]
+    fn test_console_filter() {
+        assert!(ConsoleFilter::All.accepts(b"anything"));
+        assert!(ConsoleFilter::Contains("ERR".into()).accepts(b"an ERROR"));
+        assert!(!ConsoleFilter::Contains("ERR".into()).accepts(b"ok"));
+        assert!(!ConsoleFilter::Excludes("audit".into()).accepts(b"audit: x"));
+        assert_eq!(format!("{}", ConsoleFilter::Excludes("x".into())), "-x");
+    }
+
+    #[test]
+    fn test_console_routes() {
+        let mut router = ConsoleRouter::new();
+        assert_eq!(
+            write(&mut router, "unrouted\n", false),
+            Vec::<String>::new()
+        );
+
+        router.add_route(ConsoleRoute {
+            source: 1,
+            destination: ConsoleDestination::Serial,
+            filter: ConsoleFilter::Contains("panic".into()),
+        });
+        router.add_route(ConsoleRoute {
+            source: 1,
+            destination: ConsoleDestination::Channel(3),
+            filter: ConsoleFilter::All,
+        });
+        assert_eq!(
+            write(&mut router, "booting\r\nkernel panic\r\n", false),
+            vec![String::from("[web] kernel panic")]
+        );
+
+        // The owner of the serial port is not mirrored to it
+        assert!(write(&mut router, "panic again\n", true).is_empty());
+
+        let mut out = [0u8; 64];
+        let len = router.read_channel(3, &mut out);
+        assert_eq!(
+            &out[..len],
+            &b"[web] booting\n[web] kernel panic\n[web] panic again\n"[..]
+        );
+        assert_eq!(router.read_channel(3, &mut out), 0);
+        assert_eq!(router.read_channel(4, &mut out), 0);
+
+        // Replacing and removing routes
+        router.add_route(ConsoleRoute {
+            source: 1,
+            destination: ConsoleDestination::Serial,
+            filter: ConsoleFilter::All,
+        });
+        assert_eq!(router.routes().len(), 2);
+        assert!(router.remove_route(1, ConsoleDestination::Serial));
+        assert!(!router.remove_route(1, ConsoleDestination::Serial));
+        assert!(write(&mut router, "quiet\n", false).is_empty());
+    }
+
+    #[test]
+    fn test_console_channel_overflow() {
+        let mut router = ConsoleRouter::new();
+        router.add_route(ConsoleRoute {
+            source: 1,
+            destination: ConsoleDestination::Channel(2),
+            filter: ConsoleFilter::All,
+        });
+
+        // Long lines are split
+        let long = "x".repeat(MAX_LINE_LEN * 100);
+        write(&mut router, &long, false);
+        let channel = &router.channels[0];
+        assert!(channel.data.len() <= CHANNEL_CAPACITY);
+        assert!(channel.dropped > 0);
+        assert_eq!(channel.data.front(), Some(&b'['));
+    }
+}
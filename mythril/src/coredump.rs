@@ -0,0 +1,522 @@
+//! Guest core dumps
+//!
+//! The `coredump` management console command writes the memory and
+//! registers of a paused VM (see `watch`) as an ELF core file, in the same
+//! layout as the physical-memory dumps produced by QEMU's
+//! `dump-guest-memory`, so it can be opened by `crash` or gdb along with
+//! the guest's vmlinux. The management console is the only transport, so
+//! the file is written base64 encoded between BEGIN and END markers.
+//! Runs of zero pages are described without any file contents, which
+//! keeps dumps of mostly idle guests small.
+
+use crate::memory::{GuestPhysAddr, HostPhysFrame};
+use crate::migrate;
+use crate::percore::CoreId;
+use crate::vcpu::VCpu;
+use crate::vm;
+use crate::vmcs::VmcsField;
+use crate::vmexit::GuestCpuState;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+const ET_CORE: u16 = 4;
+const EM_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_RWX: u32 = 7;
+
+const NT_PRSTATUS: u32 = 1;
+const NOTE_NAME: &[u8; 8] = b"CORE\0\0\0\0";
+
+// The size of `struct elf_prstatus` on x86_64, and the offset of the
+// registers (`pr_reg`) within it
+const PRSTATUS_SIZE: usize = 336;
+const PRSTATUS_PID_OFFSET: usize = 32;
+const PRSTATUS_REGS_OFFSET: usize = 112;
+
+/// The size of the note describing each vCPU
+pub const PRSTATUS_NOTE_SIZE: usize = 12 + 8 + PRSTATUS_SIZE;
+
+/// The registers of a vCPU, as recorded when it pauses
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VCpuRegisters {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub rsp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub cs: u64,
+    pub ss: u64,
+    pub ds: u64,
+    pub es: u64,
+    pub fs: u64,
+    pub gs: u64,
+    pub fs_base: u64,
+    pub gs_base: u64,
+    /// The guest TSC (which is not written to core dumps)
+    pub tsc: u64,
+}
+
+impl VCpuRegisters {
+    /// Capture the registers of `vcpu` at the current exit
+    pub fn capture(vcpu: &VCpu, guest_cpu: &GuestCpuState) -> Self {
+        let field = |field| vcpu.vmcs.read_field(field).unwrap_or(0);
+        Self {
+            rax: guest_cpu.rax,
+            rbx: guest_cpu.rbx,
+            rcx: guest_cpu.rcx,
+            rdx: guest_cpu.rdx,
+            rsi: guest_cpu.rsi,
+            rdi: guest_cpu.rdi,
+            rbp: guest_cpu.rbp,
+            rsp: field(VmcsField::GuestRsp),
+            r8: guest_cpu.r8,
+            r9: guest_cpu.r9,
+            r10: guest_cpu.r10,
+            r11: guest_cpu.r11,
+            r12: guest_cpu.r12,
+            r13: guest_cpu.r13,
+            r14: guest_cpu.r14,
+            r15: guest_cpu.r15,
+            rip: field(VmcsField::GuestRip),
+            rflags: field(VmcsField::GuestRflags),
+            cs: field(VmcsField::GuestCsSelector),
+            ss: field(VmcsField::GuestSsSelector),
+            ds: field(VmcsField::GuestDsSelector),
+            es: field(VmcsField::GuestEsSelector),
+            fs: field(VmcsField::GuestFsSelector),
+            gs: field(VmcsField::GuestGsSelector),
+            fs_base: field(VmcsField::GuestFsBase),
+            gs_base: field(VmcsField::GuestGsBase),
+            tsc: unsafe { x86::time::rdtsc() }
+                .wrapping_add(field(VmcsField::TscOffset)),
+        }
+    }
+
+    // The registers in the order of `struct user_regs_struct`
+    fn user_regs(&self) -> [u64; 27] {
+        [
+            self.r15,
+            self.r14,
+            self.r13,
+            self.r12,
+            self.rbp,
+            self.rbx,
+            self.r11,
+            self.r10,
+            self.r9,
+            self.r8,
+            self.rax,
+            self.rcx,
+            self.rdx,
+            self.rsi,
+            self.rdi,
+            self.rax, // orig_rax
+            self.rip,
+            self.cs,
+            self.rflags,
+            self.rsp,
+            self.ss,
+            self.fs_base,
+            self.gs_base,
+            self.ds,
+            self.es,
+            self.fs,
+            self.gs,
+        ]
+    }
+}
+
+// The VM, id and registers of each paused vCPU (a vCPU is named by the
+// core it was created for, wherever it runs)
+static PAUSED: Mutex<Vec<(u32, CoreId, VCpuRegisters)>> =
+    Mutex::new(Vec::new());
+
+/// Record that the vCPU on the current core has paused with `registers`
+pub fn vcpu_paused(vm_id: u32, registers: VCpuRegisters) {
+    PAUSED
+        .lock()
+        .push((vm_id, migrate::current_vcpu(), registers));
+}
+
+/// Record that the vCPU on the current core has resumed
+pub fn vcpu_resumed(vm_id: u32) {
+    let core = migrate::current_vcpu();
+    PAUSED
+        .lock()
+        .retain(|(id, paused_core, _)| (*id, *paused_core) != (vm_id, core));
+}
+
+/// The core and registers of each paused vCPU of the given VM
+pub fn paused_vcpus(vm_id: u32) -> Vec<(CoreId, VCpuRegisters)> {
+    PAUSED
+        .lock()
+        .iter()
+        .filter(|(id, _, _)| *id == vm_id)
+        .map(|(_, core, registers)| (*core, *registers))
+        .collect()
+}
+
+/// A contiguous range of guest physical memory in the dump
+#[derive(Clone, Debug, PartialEq)]
+pub struct Region {
+    pub start: GuestPhysAddr,
+    /// The host frames backing the region (empty if it is all zero)
+    pub frames: Vec<HostPhysFrame>,
+    /// The number of pages in the region
+    pub pages: usize,
+}
+
+impl Region {
+    fn size(&self) -> u64 {
+        (self.pages * HostPhysFrame::SIZE) as u64
+    }
+
+    fn file_size(&self) -> u64 {
+        (self.frames.len() * HostPhysFrame::SIZE) as u64
+    }
+}
+
+/// Group `frames` (in increasing address order) into contiguous regions,
+/// separating the pages for which `is_zero` returns true
+pub fn regions(
+    frames: impl Iterator<Item = (GuestPhysAddr, HostPhysFrame)>,
+    is_zero: impl Fn(&HostPhysFrame) -> bool,
+) -> Vec<Region> {
+    let mut regions: Vec<Region> = vec![];
+    for (addr, frame) in frames {
+        let zero = is_zero(&frame);
+        if let Some(last) = regions.last_mut() {
+            let end = last.start.as_u64() + last.size();
+            if end == addr.as_u64() && last.frames.is_empty() == zero {
+                last.pages += 1;
+                if !zero {
+                    last.frames.push(frame);
+                }
+                continue;
+            }
+        }
+        regions.push(Region {
+            start: addr,
+            frames: if zero { vec![] } else { vec![frame] },
+            pages: 1,
+        });
+    }
+    regions
+}
+
+fn put_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(buf: &mut [u8], offset: usize, value: u64) {
+    buf[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+fn elf_header(program_headers: u16) -> [u8; ELF_HEADER_SIZE] {
+    let mut header = [0u8; ELF_HEADER_SIZE];
+    // Magic, 64-bit, little endian, version 1
+    header[..7].copy_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1]);
+    put_u16(&mut header, 16, ET_CORE);
+    put_u16(&mut header, 18, EM_X86_64);
+    put_u32(&mut header, 20, 1);
+    put_u64(&mut header, 32, ELF_HEADER_SIZE as u64); // e_phoff
+    put_u16(&mut header, 52, ELF_HEADER_SIZE as u16);
+    put_u16(&mut header, 54, PROGRAM_HEADER_SIZE as u16);
+    put_u16(&mut header, 56, program_headers);
+    header
+}
+
+fn program_header(
+    kind: u32,
+    offset: u64,
+    paddr: u64,
+    file_size: u64,
+    mem_size: u64,
+) -> [u8; PROGRAM_HEADER_SIZE] {
+    let mut header = [0u8; PROGRAM_HEADER_SIZE];
+    put_u32(&mut header, 0, kind);
+    put_u32(&mut header, 4, PF_RWX);
+    put_u64(&mut header, 8, offset);
+    put_u64(&mut header, 24, paddr);
+    put_u64(&mut header, 32, file_size);
+    put_u64(&mut header, 40, mem_size);
+    header
+}
+
+fn prstatus_note(
+    pid: u32,
+    registers: &VCpuRegisters,
+) -> [u8; PRSTATUS_NOTE_SIZE] {
+    let mut note = [0u8; PRSTATUS_NOTE_SIZE];
+    put_u32(&mut note, 0, 5); // "CORE" and its terminator
+    put_u32(&mut note, 4, PRSTATUS_SIZE as u32);
+    put_u32(&mut note, 8, NT_PRSTATUS);
+    note[12..20].copy_from_slice(NOTE_NAME);
+
+    let status = &mut note[20..];
+    put_u32(status, PRSTATUS_PID_OFFSET, pid);
+    for (i, reg) in registers.user_regs().iter().enumerate() {
+        put_u64(status, PRSTATUS_REGS_OFFSET + i * 8, *reg);
+    }
+    note
+}
+
+/// The ELF header, program headers and notes of a core file containing
+/// the given vCPUs and memory regions (i.e., everything before the memory
+/// contents)
+pub fn core_headers(vcpus: &[VCpuRegisters], regions: &[Region]) -> Vec<u8> {
+    let headers = 1 + regions.len();
+    let notes_offset = ELF_HEADER_SIZE + headers * PROGRAM_HEADER_SIZE;
+    let notes_size = vcpus.len() * PRSTATUS_NOTE_SIZE;
+
+    let mut data = vec![];
+    data.extend_from_slice(&elf_header(headers as u16));
+    data.extend_from_slice(&program_header(
+        PT_NOTE,
+        notes_offset as u64,
+        0,
+        notes_size as u64,
+        0,
+    ));
+    let mut offset = (notes_offset + notes_size) as u64;
+    for region in regions {
+        data.extend_from_slice(&program_header(
+            PT_LOAD,
+            offset,
+            region.start.as_u64(),
+            region.file_size(),
+            region.size(),
+        ));
+        offset += region.file_size();
+    }
+
+    // crash and gdb identify the vCPUs by the (one-based) "pid"
+    for (i, registers) in vcpus.iter().enumerate() {
+        data.extend_from_slice(&prstatus_note(i as u32 + 1, registers));
+    }
+    data
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const BASE64_LINE_LENGTH: usize = 76;
+
+/// Writes bytes as base64 encoded lines
+pub struct Base64Writer<'a> {
+    out: &'a mut dyn fmt::Write,
+    pending: [u8; 3],
+    pending_len: usize,
+    line_len: usize,
+}
+
+impl<'a> Base64Writer<'a> {
+    pub fn new(out: &'a mut dyn fmt::Write) -> Self {
+        Self {
+            out,
+            pending: [0; 3],
+            pending_len: 0,
+            line_len: 0,
+        }
+    }
+
+    fn emit(&mut self, chars: &[u8]) -> fmt::Result {
+        for c in chars {
+            if self.line_len == BASE64_LINE_LENGTH {
+                self.out.write_char('\n')?;
+                self.line_len = 0;
+            }
+            self.out.write_char(*c as char)?;
+            self.line_len += 1;
+        }
+        Ok(())
+    }
+
+    fn encode(&mut self, len: usize) -> fmt::Result {
+        let [a, b, c] = self.pending;
+        let group = (a as u32) << 16 | (b as u32) << 8 | c as u32;
+        let mut chars = [b'='; 4];
+        for (i, ch) in chars.iter_mut().enumerate().take(len + 1) {
+            *ch = BASE64_ALPHABET[(group >> (18 - 6 * i)) as usize & 0x3f];
+        }
+        self.pending = [0; 3];
+        self.pending_len = 0;
+        self.emit(&chars)
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> fmt::Result {
+        for byte in data {
+            self.pending[self.pending_len] = *byte;
+            self.pending_len += 1;
+            if self.pending_len == 3 {
+                self.encode(3)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write any remaining bytes (with padding) and end the last line
+    pub fn finish(mut self) -> fmt::Result {
+        if self.pending_len > 0 {
+            let len = self.pending_len;
+            self.encode(len)?;
+        }
+        if self.line_len > 0 {
+            self.out.write_char('\n')?;
+        }
+        Ok(())
+    }
+}
+
+/// The `coredump` management console command
+pub fn coredump_command(
+    out: &mut dyn fmt::Write,
+    args: &[&str],
+) -> fmt::Result {
+    let vm_id = match args.get(0).and_then(|vm| vm::find_vm(vm)) {
+        Some(vm_id) => vm_id,
+        None => return writeln!(out, "usage: coredump <vm>"),
+    };
+
+    let paused = paused_vcpus(vm_id);
+    let core = match paused.first() {
+        Some((core, _)) => *core,
+        None => {
+            return writeln!(
+                out,
+                "VM {} is not paused (pause it with a watch first)",
+                vm::vm_name(vm_id).unwrap_or("?")
+            )
+        }
+    };
+    let vcpus = paused
+        .iter()
+        .map(|(_, registers)| *registers)
+        .collect::<Vec<_>>();
+
+    // The VM is paused, so its memory is not being modified
+    let vm = match unsafe { vm::get_vm_for_core_id(core) } {
+        Some(vm) => vm,
+        None => return writeln!(out, "No VM {}", vm_id),
+    };
+    let vm = vm.read();
+    let regions = regions(
+        vm.guest_space
+            .mappings()
+            .into_iter()
+            .map(|(addr, frame, _)| (addr, frame)),
+        |frame| unsafe { frame.as_array() }.iter().all(|byte| *byte == 0),
+    );
+
+    writeln!(out, "-----BEGIN MYTHRIL CORE-----")?;
+    let mut encoder = Base64Writer::new(out);
+    encoder.write(&core_headers(&vcpus, &regions))?;
+    for region in regions.iter() {
+        for frame in region.frames.iter() {
+            encoder.write(unsafe { frame.as_array() })?;
+        }
+    }
+    encoder.finish()?;
+    writeln!(out, "-----END MYTHRIL CORE-----")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::HostPhysAddr;
+    use alloc::string::String;
+
+    fn base64(data: &[u8]) -> String {
+        let mut out = String::new();
+        let mut encoder = Base64Writer::new(&mut out);
+        encoder.write(data).unwrap();
+        encoder.finish().unwrap();
+        out
+    }
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==\n");
+        assert_eq!(base64(b"fo"), "Zm8=\n");
+        assert_eq!(base64(b"foo"), "Zm9v\n");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy\n");
+
+        let lines = base64(&[0u8; 120]);
+        assert_eq!(lines.lines().count(), 3);
+        assert_eq!(lines.lines().next().unwrap().len(), BASE64_LINE_LENGTH);
+    }
+
+    #[test]
+    fn test_regions() {
+        let frame = |addr| {
+            HostPhysFrame::from_start_address(HostPhysAddr::new(addr)).unwrap()
+        };
+        let frames = vec![
+            (GuestPhysAddr::new(0x0), frame(0x10000)),
+            (GuestPhysAddr::new(0x1000), frame(0x20000)),
+            (GuestPhysAddr::new(0x2000), frame(0x30000)),
+            (GuestPhysAddr::new(0x5000), frame(0x40000)),
+        ];
+        let regions = regions(frames.into_iter(), |frame| {
+            frame.start_address().as_u64() == 0x20000
+        });
+        assert_eq!(regions.len(), 4);
+        assert_eq!(regions[0].frames, vec![frame(0x10000)]);
+        assert_eq!((regions[1].pages, regions[1].file_size()), (1, 0));
+        assert_eq!(regions[2].start, GuestPhysAddr::new(0x2000));
+        assert_eq!(regions[3].start, GuestPhysAddr::new(0x5000));
+    }
+
+    #[test]
+    fn test_core_headers() {
+        let registers = VCpuRegisters {
+            rip: 0xffffffff81000000,
+            ..Default::default()
+        };
+        let regions = [Region {
+            start: GuestPhysAddr::new(0x100000),
+            frames: vec![],
+            pages: 2,
+        }];
+        let data = core_headers(&[registers], &regions);
+        assert_eq!(
+            data.len(),
+            ELF_HEADER_SIZE + 2 * PROGRAM_HEADER_SIZE + PRSTATUS_NOTE_SIZE
+        );
+        assert_eq!(&data[..4], b"\x7fELF");
+        assert_eq!(data[56], 2); // e_phnum
+
+        let load = &data[ELF_HEADER_SIZE + PROGRAM_HEADER_SIZE..];
+        assert_eq!(load[0], PT_LOAD as u8);
+        assert_eq!(&load[24..32], &0x100000u64.to_le_bytes());
+        assert_eq!(&load[32..40], &0u64.to_le_bytes());
+        assert_eq!(&load[40..48], &0x2000u64.to_le_bytes());
+
+        let note = &data[data.len() - PRSTATUS_NOTE_SIZE..];
+        assert_eq!(&note[12..16], b"CORE");
+        let rip = 20 + PRSTATUS_REGS_OFFSET + 16 * 8;
+        assert_eq!(&note[rip..rip + 8], &registers.rip.to_le_bytes());
+    }
+}
@@ -0,0 +1,232 @@
+//! Panic propagation between cores
+//!
+//! When a core panics, it claims the crash (so only the first panicking
+//! core reports it) and sends the cross-call IPI (`IPC_VECTOR`) to every
+//! other core. A core receiving the IPI checks for a crash before handling
+//! its messages and parks: it flushes the log, records where its guest was
+//! stopped and halts with interrupts disabled, leaving the guest frozen.
+//! The panicking core waits briefly for the other cores to park, then
+//! prints a report of the state of every core.
+
+use crate::apic;
+use crate::health;
+use crate::interrupt;
+use crate::lock::ro_after_init::RoAfterInit;
+use crate::logger;
+use crate::percore::{self, CoreId};
+use crate::time;
+use alloc::collections::BTreeMap;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use core::time::Duration;
+
+/// The time the panicking core waits for the other cores to park
+const PARK_TIMEOUT: Duration = Duration::from_secs(1);
+
+// The number of polls to wait for the other cores to park if the system
+// time source is not yet available
+const PARK_TIMEOUT_POLLS: u64 = 100_000_000;
+
+const NO_CORE: u32 = u32::MAX;
+
+// The core that is reporting the crash (if any)
+static CRASHING_CORE: AtomicU32 = AtomicU32::new(NO_CORE);
+
+/// The state a core recorded when it parked
+#[derive(Default)]
+struct ParkRecord {
+    parked: AtomicBool,
+    has_guest: AtomicBool,
+    guest_rip: AtomicU64,
+}
+
+static RECORDS: RoAfterInit<BTreeMap<CoreId, ParkRecord>> =
+    RoAfterInit::uninitialized();
+
+/// Create the park records for the given cores
+pub unsafe fn init(cores: impl Iterator<Item = CoreId>) {
+    RoAfterInit::init(
+        &RECORDS,
+        cores.map(|core| (core, ParkRecord::default())).collect(),
+    );
+}
+
+/// Returns true if a core has panicked
+pub fn is_crashing() -> bool {
+    CRASHING_CORE.load(Ordering::SeqCst) != NO_CORE
+}
+
+/// Claim the crash for the current core
+///
+/// Returns false if another core (or this core, in a nested panic) has
+/// already claimed it.
+pub fn claim() -> bool {
+    CRASHING_CORE
+        .compare_exchange(
+            NO_CORE,
+            percore::read_core_id().raw,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        )
+        .is_ok()
+}
+
+/// Ask every other core to park
+pub fn broadcast() {
+    if !RoAfterInit::is_initialized(&RECORDS) {
+        return;
+    }
+    let current = percore::read_core_id();
+    for core in RECORDS.keys().filter(|core| **core != current) {
+        unsafe {
+            apic::get_local_apic_mut().send_ipi(
+                core.raw.into(),
+                apic::DstShorthand::NoShorthand,
+                apic::TriggerMode::Edge,
+                apic::Level::Assert,
+                apic::DstMode::Physical,
+                apic::DeliveryMode::Fixed,
+                interrupt::IPC_VECTOR,
+            );
+        }
+    }
+}
+
+/// Park the current core (with its guest stopped at `guest_rip`, if it
+/// has one), because another core has panicked
+pub fn park(guest_rip: Option<u64>) -> ! {
+    log::logger().flush();
+    if RoAfterInit::is_initialized(&RECORDS) {
+        if let Some(record) = RECORDS.get(&percore::read_core_id()) {
+            if let Some(rip) = guest_rip {
+                record.guest_rip.store(rip, Ordering::SeqCst);
+                record.has_guest.store(true, Ordering::SeqCst);
+            }
+            record.parked.store(true, Ordering::SeqCst);
+        }
+    }
+    halt()
+}
+
+/// Halt the current core for good, with interrupts disabled
+pub fn halt() -> ! {
+    loop {
+        unsafe {
+            interrupt::disable_interrupts();
+            llvm_asm!("hlt" :::: "volatile");
+        }
+    }
+}
+
+/// The state of a core when the crash was reported
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CoreState {
+    /// The core that panicked
+    Panicked,
+    /// The core parked, with its guest stopped at the given address
+    Parked(Option<u64>),
+    /// The core did not park (it may be wedged with interrupts disabled)
+    NotResponding,
+}
+
+impl fmt::Display for CoreState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CoreState::Panicked => write!(f, "PANICKED"),
+            CoreState::Parked(Some(rip)) => {
+                write!(f, "parked (guest rip=0x{:x})", rip)
+            }
+            CoreState::Parked(None) => write!(f, "parked (no guest)"),
+            CoreState::NotResponding => write!(f, "NOT RESPONDING"),
+        }
+    }
+}
+
+fn state(core: CoreId, record: &ParkRecord) -> CoreState {
+    if core.raw == CRASHING_CORE.load(Ordering::SeqCst) {
+        CoreState::Panicked
+    } else if record.parked.load(Ordering::SeqCst) {
+        let rip = if record.has_guest.load(Ordering::SeqCst) {
+            Some(record.guest_rip.load(Ordering::SeqCst))
+        } else {
+            None
+        };
+        CoreState::Parked(rip)
+    } else {
+        CoreState::NotResponding
+    }
+}
+
+fn all_parked() -> bool {
+    let current = percore::read_core_id();
+    RECORDS.iter().all(|(core, record)| {
+        *core == current || record.parked.load(Ordering::SeqCst)
+    })
+}
+
+fn wait_for_cores() {
+    if time::is_global_time_ready() {
+        let deadline = time::now() + PARK_TIMEOUT;
+        while !all_parked() && time::now() < deadline {
+            core::sync::atomic::spin_loop_hint();
+        }
+    } else {
+        for _ in 0..PARK_TIMEOUT_POLLS {
+            if all_parked() {
+                break;
+            }
+            core::sync::atomic::spin_loop_hint();
+        }
+    }
+}
+
+/// Wait for the other cores to park, then report the state of every core
+/// and halt
+pub fn report() -> ! {
+    if !RoAfterInit::is_initialized(&RECORDS) {
+        halt();
+    }
+    wait_for_cores();
+
+    // The other cores are stopped, so a log lock held by one of them will
+    // never be released
+    unsafe {
+        logger::force_unlock();
+    }
+    error!("Crash report ({} cores):", RECORDS.len());
+    for (core, record) in RECORDS.iter() {
+        match health::last_exit(*core) {
+            Some(reason) => error!(
+                "  core {:>3}: {} last_exit={}",
+                core.raw,
+                state(*core, record),
+                reason
+            ),
+            None => error!("  core {:>3}: {}", core.raw, state(*core, record)),
+        }
+    }
+    halt()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_core_state() {
+        assert_eq!(format!("{}", CoreState::Panicked), "PANICKED");
+        assert_eq!(
+            format!("{}", CoreState::Parked(Some(0xfff0))),
+            "parked (guest rip=0xfff0)"
+        );
+        assert_eq!(format!("{}", CoreState::Parked(None)), "parked (no guest)");
+
+        let record = ParkRecord::default();
+        let core = CoreId::from(NO_CORE - 1);
+        assert_eq!(state(core, &record), CoreState::NotResponding);
+        record.guest_rip.store(0x1000, Ordering::SeqCst);
+        record.has_guest.store(true, Ordering::SeqCst);
+        record.parked.store(true, Ordering::SeqCst);
+        assert_eq!(state(core, &record), CoreState::Parked(Some(0x1000)));
+    }
+}
@@ -1,5 +1,152 @@
+use crate::emulate::msr::{update_long_mode, EFER_LMA};
 use crate::error::{Error, Result};
+use crate::memory::{GuestFault, GuestPhysAddr};
 use crate::{vcpu, vmcs, vmexit, vmx};
+use x86::msr;
+
+const CR0_PE: u64 = 1 << 0;
+const CR0_TS: u64 = 1 << 3;
+const CR0_NW: u64 = 1 << 29;
+const CR0_CD: u64 = 1 << 30;
+const CR0_PG: u64 = 1 << 31;
+const CR4_PSE: u64 = 1 << 4;
+const CR4_PAE: u64 = 1 << 5;
+const CR4_PGE: u64 = 1 << 7;
+const CR4_LA57: u64 = 1 << 12;
+const CR4_SMEP: u64 = 1 << 20;
+
+// The bits of a present PDPTE that must be zero (below MAXPHYADDR)
+const PDPTE_RESERVED: u64 = 0x1e6;
+
+const PDPTR_FIELDS: [vmcs::VmcsField; 4] = [
+    vmcs::VmcsField::GuestPdptr0,
+    vmcs::VmcsField::GuestPdptr1,
+    vmcs::VmcsField::GuestPdptr2,
+    vmcs::VmcsField::GuestPdptr3,
+];
+
+// Returns true if the given state selects PAE paging (without long mode)
+fn is_pae_paging(cr0: u64, cr4: u64, efer: u64) -> bool {
+    cr0 & CR0_PG != 0 && cr4 & CR4_PAE != 0 && efer & EFER_LMA == 0
+}
+
+// The value of a control register as seen by the guest: the bits owned by
+// the host (set in the guest/host mask) are read from the read shadow
+fn guest_visible_value(actual: u64, shadow: u64, mask: u64) -> u64 {
+    (actual & !mask) | (shadow & mask)
+}
+
+// Apply the bits that are fixed in VMX operation (see Appendix A.7 and
+// A.8 of the SDM) to a value written by the guest
+fn apply_fixed_bits(value: u64, fixed0: u64, fixed1: u64) -> u64 {
+    (value | fixed0) & fixed1
+}
+
+/// The value of CR0 as seen by the guest
+pub fn guest_cr0(vcpu: &vcpu::VCpu) -> Result<u64> {
+    Ok(guest_visible_value(
+        vcpu.vmcs.read_field(vmcs::VmcsField::GuestCr0)?,
+        vcpu.vmcs.read_field(vmcs::VmcsField::Cr0ReadShadow)?,
+        vcpu.vmcs.read_field(vmcs::VmcsField::Cr0GuestHostMask)?,
+    ))
+}
+
+/// The value of CR4 as seen by the guest
+pub fn guest_cr4(vcpu: &vcpu::VCpu) -> Result<u64> {
+    Ok(guest_visible_value(
+        vcpu.vmcs.read_field(vmcs::VmcsField::GuestCr4)?,
+        vcpu.vmcs.read_field(vmcs::VmcsField::Cr4ReadShadow)?,
+        vcpu.vmcs.read_field(vmcs::VmcsField::Cr4GuestHostMask)?,
+    ))
+}
+
+// Set the guest's CR0 to `value`, while keeping the bits required by VMX
+// operation set in the real CR0. The guest continues to read `value`.
+fn write_cr0(vcpu: &mut vcpu::VCpu, value: u64) -> Result<()> {
+    let (fixed0, fixed1) = unsafe {
+        (
+            msr::rdmsr(msr::IA32_VMX_CR0_FIXED0),
+            msr::rdmsr(msr::IA32_VMX_CR0_FIXED1),
+        )
+    };
+    // Unrestricted guests may clear PE and PG
+    let fixed0 = fixed0 & !(CR0_PE | CR0_PG);
+    vcpu.vmcs.write_field(
+        vmcs::VmcsField::GuestCr0,
+        apply_fixed_bits(value, fixed0, fixed1),
+    )?;
+    vcpu.vmcs.write_field(vmcs::VmcsField::Cr0ReadShadow, value)
+}
+
+// Set the guest's CR4 to `value` (see `write_cr0`)
+fn write_cr4(vcpu: &mut vcpu::VCpu, value: u64) -> Result<()> {
+    let (fixed0, fixed1) = unsafe {
+        (
+            msr::rdmsr(msr::IA32_VMX_CR4_FIXED0),
+            msr::rdmsr(msr::IA32_VMX_CR4_FIXED1),
+        )
+    };
+    vcpu.vmcs.write_field(
+        vmcs::VmcsField::GuestCr4,
+        apply_fixed_bits(value, fixed0, fixed1),
+    )?;
+    vcpu.vmcs.write_field(vmcs::VmcsField::Cr4ReadShadow, value)
+}
+
+// Returns true if `pdpte` may be loaded by a processor with the given
+// physical address width (see Section 4.4.1 of the SDM)
+fn is_valid_pdpte(pdpte: u64, max_phys_addr: u32) -> bool {
+    if pdpte & 1 == 0 {
+        return true;
+    }
+    let reserved = PDPTE_RESERVED | (!0u64 << max_phys_addr);
+    pdpte & reserved == 0
+}
+
+/// Load the PDPTEs referenced by `cr3` into the guest PDPTR fields
+///
+/// With EPT, the processor uses the PDPTEs in the VMCS (rather than
+/// reading them from guest memory) on VM entry to a PAE paging guest,
+/// so they must be loaded whenever an emulated control register write
+/// would load them on real hardware. Invalid PDPTEs cause a #GP.
+fn load_pdptes(vcpu: &mut vcpu::VCpu, cr3: u64) -> Result<()> {
+    // The PDPT is 32-byte aligned, so it never crosses a page
+    let pdpt = cr3 & 0xffffffe0;
+    let mut pdptes = [0u64; 4];
+    {
+        let vm = vcpu.vm.read();
+        let frame = vm
+            .guest_space
+            .find_host_frame(GuestPhysAddr::new(pdpt & !0xfff))
+            .map_err(|_| GuestFault::GeneralProtection(0))?;
+        let array = unsafe { frame.as_array() };
+        let offset = (pdpt & 0xfff) as usize;
+        for (i, pdpte) in pdptes.iter_mut().enumerate() {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&array[offset + i * 8..offset + (i + 1) * 8]);
+            *pdpte = u64::from_le_bytes(bytes);
+        }
+    }
+
+    // Processors that do not report their physical address width support
+    // 36 bits
+    let max_phys_addr =
+        match vcpu.vm.read().cpuid.lookup(0x80000008, 0, 0).eax & 0xff {
+            width @ 32..=52 => width,
+            _ => 36,
+        };
+    if pdptes
+        .iter()
+        .any(|pdpte| !is_valid_pdpte(*pdpte, max_phys_addr))
+    {
+        return Err(GuestFault::GeneralProtection(0).into());
+    }
+
+    for (field, pdpte) in PDPTR_FIELDS.iter().zip(pdptes.iter()) {
+        vcpu.vmcs.write_field(*field, *pdpte)?;
+    }
+    Ok(())
+}
 
 pub fn emulate_access(
     vcpu: &mut vcpu::VCpu,
@@ -9,17 +156,65 @@ pub fn emulate_access(
     match info.cr_num {
         0 => match info.access_type {
             vmexit::CrAccessType::Clts => {
-                let cr0 = vcpu.vmcs.read_field(vmcs::VmcsField::GuestCr0)?;
-                vcpu.vmcs
-                    .write_field(vmcs::VmcsField::GuestCr0, cr0 & !0b1000)?;
+                let cr0 = guest_cr0(vcpu)?;
+                write_cr0(vcpu, cr0 & !CR0_TS)?;
             }
             vmexit::CrAccessType::MovToCr => {
                 let reg = info.register.unwrap();
                 let val = reg.read(&vcpu.vmcs, guest_cpu)?;
-                vcpu.vmcs.write_field(vmcs::VmcsField::GuestCr0, val)?;
+                let old = guest_cr0(vcpu)?;
+                let cr4 = guest_cr4(vcpu)?;
+                update_long_mode(vcpu, old, val, cr4)?;
+
+                // Changes to PG, CD or NW load the PDPTEs in PAE mode
+                let efer =
+                    vcpu.vmcs.read_field(vmcs::VmcsField::GuestIa32Efer)?;
+                if (old ^ val) & (CR0_PG | CR0_CD | CR0_NW) != 0
+                    && is_pae_paging(val, cr4, efer)
+                {
+                    let cr3 =
+                        vcpu.vmcs.read_field(vmcs::VmcsField::GuestCr3)?;
+                    load_pdptes(vcpu, cr3)?;
+                }
+                write_cr0(vcpu, val)?;
             }
             op => panic!("Unsupported MovToCr cr0 operation: {:?}", op),
         },
+        4 => match info.access_type {
+            vmexit::CrAccessType::MovToCr => {
+                let reg = info.register.unwrap();
+                let val = reg.read(&vcpu.vmcs, guest_cpu)?;
+                let old = guest_cr4(vcpu)?;
+                let cr0 = guest_cr0(vcpu)?;
+                let efer =
+                    vcpu.vmcs.read_field(vmcs::VmcsField::GuestIa32Efer)?;
+
+                // PAE cannot be disabled in long mode
+                if efer & EFER_LMA != 0 && val & CR4_PAE == 0 {
+                    return Err(GuestFault::GeneralProtection(0).into());
+                }
+
+                // LA57 is only writable outside of long mode, and only if
+                // 5-level paging is reported to the guest
+                if (old ^ val) & CR4_LA57 != 0
+                    && (efer & EFER_LMA != 0
+                        || !vcpu.vm.read().cpuid.la57_supported())
+                {
+                    return Err(GuestFault::GeneralProtection(0).into());
+                }
+
+                // Changes to the paging mode bits load the PDPTEs in PAE mode
+                if (old ^ val) & (CR4_PSE | CR4_PAE | CR4_PGE | CR4_SMEP) != 0
+                    && is_pae_paging(cr0, val, efer)
+                {
+                    let cr3 =
+                        vcpu.vmcs.read_field(vmcs::VmcsField::GuestCr3)?;
+                    load_pdptes(vcpu, cr3)?;
+                }
+                write_cr4(vcpu, val)?;
+            }
+            op => panic!("Unsupported cr4 operation: {:?}", op),
+        },
         3 => match info.access_type {
             vmexit::CrAccessType::MovToCr => {
                 let reg = info.register.unwrap();
@@ -50,6 +245,14 @@ pub fn emulate_access(
                     val &= !(1 << 63);
                 }
 
+                let cr0 = guest_cr0(vcpu)?;
+                let cr4 = guest_cr4(vcpu)?;
+                let efer =
+                    vcpu.vmcs.read_field(vmcs::VmcsField::GuestIa32Efer)?;
+                if is_pae_paging(cr0, cr4, efer) {
+                    load_pdptes(vcpu, val)?;
+                }
+
                 vcpu.vmcs.write_field(vmcs::VmcsField::GuestCr3, val)?;
             }
             vmexit::CrAccessType::MovFromCr => {
@@ -67,3 +270,45 @@ pub fn emulate_access(
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::emulate::msr::EFER_LME;
+
+    #[test]
+    fn test_pdpte_validation() {
+        // Not present entries are never checked
+        assert!(is_valid_pdpte(0xffff_ffff_ffff_fffe, 39));
+        assert!(is_valid_pdpte(0x1234_5001, 39));
+        // Bits 1, 2 and 5-8 are reserved
+        assert!(!is_valid_pdpte(0x1234_5003, 39));
+        assert!(!is_valid_pdpte(0x1234_5101, 39));
+        // As are the bits above MAXPHYADDR (including XD)
+        assert!(!is_valid_pdpte(0x80_0000_0001, 39));
+        assert!(!is_valid_pdpte(0x8000_0000_0000_0001, 52));
+    }
+
+    #[test]
+    fn test_guest_visible_value() {
+        // CR0.NE is owned by the host, but the guest cleared it
+        let mask = (1 << 5) | CR0_PG;
+        let actual = apply_fixed_bits(0x11, 1 << 5, !0);
+        assert_eq!(actual, 0x31);
+        assert_eq!(guest_visible_value(actual, 0x11, mask), 0x11);
+
+        // The guest owns CR0.TS
+        assert_eq!(guest_visible_value(0x39, 0x11, mask), 0x19);
+
+        // Fixed1 bits are cleared in the real value
+        assert_eq!(apply_fixed_bits(0x3, 0, !0x2), 0x1);
+    }
+
+    #[test]
+    fn test_is_pae_paging() {
+        assert!(is_pae_paging(CR0_PG | 1, CR4_PAE, 0));
+        assert!(!is_pae_paging(CR0_PG | 1, 0, 0));
+        assert!(!is_pae_paging(1, CR4_PAE, 0));
+        assert!(!is_pae_paging(CR0_PG | 1, CR4_PAE, EFER_LME | EFER_LMA));
+    }
+}
@@ -1,30 +1,433 @@
 use crate::error::Result;
 use crate::{vcpu, vmexit};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use raw_cpuid::CpuIdResult;
+
+const EXTENDED_LEAF_BASE: u32 = 0x80000000;
+
+// The maximum number of subleaves recorded for any leaf
+const MAX_SUBLEAVES: u32 = 64;
+
+// The TSC/core crystal clock leaf and the processor frequency leaf
+const TSC_LEAF: u32 = 0x15;
+const FREQUENCY_LEAF: u32 = 0x16;
+
+// The address width leaf, and the 5-level paging flag (in leaf 7 ecx)
+const LINEAR_ADDRESS_LEAF: u32 = 0x80000008;
+const LA57_FEATURE: u32 = 1 << 16;
+
+/// The frequencies (in Hz) of the clocks reported to a guest
+///
+/// These are reported in CPUID leaves 0x15 and 0x16, so the guest does
+/// not need to calibrate its clocks (and the result is consistent with
+/// the rate of the virtual APIC timer).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GuestClocks {
+    /// The frequency of the TSC
+    pub tsc: u64,
+    /// The frequency of the local APIC timer (before division)
+    pub apic_timer: u64,
+}
+
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        let r = a % b;
+        a = b;
+        b = r;
+    }
+    a
+}
+
+impl GuestClocks {
+    // The leaf 0x15 values: the TSC/crystal ratio (eax is the denominator,
+    // ebx the numerator) and the crystal frequency in ecx
+    fn tsc_leaf(&self) -> CpuIdResult {
+        let divisor = core::cmp::max(gcd(self.tsc, self.apic_timer), 1);
+        let (mut numerator, mut denominator) =
+            (self.tsc / divisor, self.apic_timer / divisor);
+        while numerator > u32::MAX as u64 || denominator > u32::MAX as u64 {
+            numerator >>= 1;
+            denominator >>= 1;
+        }
+        CpuIdResult {
+            eax: denominator as u32,
+            ebx: numerator as u32,
+            ecx: core::cmp::min(self.apic_timer, u32::MAX as u64) as u32,
+            edx: 0,
+        }
+    }
+
+    // The leaf 0x16 values: the base and maximum frequencies and the bus
+    // (reference) frequency, in MHz
+    fn frequency_leaf(&self) -> CpuIdResult {
+        let tsc_mhz = core::cmp::min(self.tsc / 1_000_000, 0xffff) as u32;
+        CpuIdResult {
+            eax: tsc_mhz,
+            ebx: tsc_mhz,
+            ecx: core::cmp::min(self.apic_timer / 1_000_000, 0xffff) as u32,
+            edx: 0,
+        }
+    }
+}
+
+/// The CPUID features exposed to a guest
+///
+/// By default, features that mythril does not yet virtualize are hidden.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CpuidPolicy {
+    /// Report support for MTRRs
+    pub mtrr: bool,
+    /// Report support for XSAVE
+    pub xsave: bool,
+    /// Report that the guest is running under a hypervisor
+    pub hypervisor: bool,
+    /// Report support for 5-level paging (if the host supports it)
+    pub la57: bool,
+}
+
+impl CpuidPolicy {
+    fn apply(&self, leaf: u32, subleaf: u32, res: &mut CpuIdResult) {
+        if leaf == 7 && subleaf == 0 && !self.la57 {
+            res.ecx &= !LA57_FEATURE;
+        }
+        // Without 5-level paging, linear addresses are at most 48 bits
+        if leaf == LINEAR_ADDRESS_LEAF && !self.la57 {
+            let width = core::cmp::min((res.eax >> 8) & 0xff, 48);
+            res.eax = (res.eax & !0xff00) | (width << 8);
+        }
+        if leaf == 1 {
+            if !self.mtrr {
+                res.edx &= !(1 << 12);
+            }
+            if !self.xsave {
+                res.ecx &= !(1 << 26);
+            }
+            if !self.hypervisor {
+                res.ecx &= !(1 << 31);
+            }
+        }
+    }
+}
+
+/// A CPUID register that a guest sees differently from the host
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CpuidDivergence {
+    pub leaf: u32,
+    pub subleaf: u32,
+    pub register: &'static str,
+    pub host: u32,
+    pub guest: u32,
+}
+
+/// The complete set of CPUID leaves reported to a guest
+///
+/// The table is computed once (per policy) so that emulating CPUID is
+/// only a lookup, rather than executing CPUID on the host for every exit.
+pub struct CpuidTable {
+    policy: CpuidPolicy,
+    clocks: GuestClocks,
+    max_basic_leaf: u32,
+    max_extended_leaf: u32,
+    entries: BTreeMap<(u32, u32), CpuIdResult>,
+}
+
+impl CpuidTable {
+    /// Build the table from the CPUID leaves of the current core
+    pub fn new(policy: CpuidPolicy, clocks: GuestClocks) -> Self {
+        Self::from_source(policy, clocks, raw_cpuid::native_cpuid::cpuid_count)
+    }
+
+    fn from_source(
+        policy: CpuidPolicy,
+        clocks: GuestClocks,
+        cpuid: impl Fn(u32, u32) -> CpuIdResult,
+    ) -> Self {
+        let host_max_basic_leaf = cpuid(0, 0).eax;
+        let max_extended_leaf = cpuid(EXTENDED_LEAF_BASE, 0).eax;
+
+        let mut entries = BTreeMap::new();
+        let leaves = (0..=host_max_basic_leaf)
+            .chain(EXTENDED_LEAF_BASE..=max_extended_leaf);
+        for leaf in leaves {
+            for subleaf in 0..Self::subleaf_count(leaf, &cpuid) {
+                let mut res = cpuid(leaf, subleaf);
+                policy.apply(leaf, subleaf, &mut res);
+                entries.insert((leaf, subleaf), res);
+            }
+        }
+
+        // The clock leaves are always reported (even if the host does not
+        // support them), as they describe the virtual clocks.
+        let max_basic_leaf =
+            core::cmp::max(host_max_basic_leaf, FREQUENCY_LEAF);
+        if let Some(res) = entries.get_mut(&(0, 0)) {
+            res.eax = max_basic_leaf;
+        }
+        entries.insert((TSC_LEAF, 0), clocks.tsc_leaf());
+        entries.insert((FREQUENCY_LEAF, 0), clocks.frequency_leaf());
+
+        Self {
+            policy,
+            clocks,
+            max_basic_leaf,
+            max_extended_leaf,
+            entries,
+        }
+    }
+
+    // Returns true if the result of `leaf` depends on the subleaf (ecx)
+    fn is_indexed(leaf: u32) -> bool {
+        match leaf {
+            0x4 | 0x7 | 0xb | 0xd | 0xf | 0x10 | 0x12 | 0x14 | 0x17 | 0x18
+            | 0x1f => true,
+            _ => false,
+        }
+    }
+
+    // The number of subleaves to record for `leaf`
+    fn subleaf_count(
+        leaf: u32,
+        cpuid: &impl Fn(u32, u32) -> CpuIdResult,
+    ) -> u32 {
+        let count = match leaf {
+            // Deterministic cache parameters (terminated by a null cache type)
+            0x4 => (0..MAX_SUBLEAVES)
+                .find(|subleaf| cpuid(leaf, *subleaf).eax & 0x1f == 0)
+                .map(|subleaf| subleaf + 1)
+                .unwrap_or(MAX_SUBLEAVES),
+            // Leaves that report the maximum subleaf in eax of subleaf 0
+            0x7 | 0x14 | 0x17 | 0x18 => cpuid(leaf, 0).eax.saturating_add(1),
+            // Topology enumeration (terminated by an invalid level type)
+            0xb | 0x1f => (0..MAX_SUBLEAVES)
+                .find(|subleaf| (cpuid(leaf, *subleaf).ecx >> 8) & 0xff == 0)
+                .map(|subleaf| subleaf + 1)
+                .unwrap_or(MAX_SUBLEAVES),
+            0xd => MAX_SUBLEAVES,
+            0xf | 0x10 | 0x12 => 16,
+            _ => 1,
+        };
+        core::cmp::min(count, MAX_SUBLEAVES)
+    }
+
+    /// The policy used to build this table
+    pub fn policy(&self) -> &CpuidPolicy {
+        &self.policy
+    }
+
+    /// Returns true if 5-level paging is reported to the guest
+    pub fn la57_supported(&self) -> bool {
+        self.lookup(7, 0, 0).ecx & LA57_FEATURE != 0
+    }
+
+    /// The clock frequencies reported by this table
+    pub fn clocks(&self) -> GuestClocks {
+        self.clocks
+    }
+
+    /// The registers of the leaves in this table that differ from the
+    /// CPUID leaves of the current core
+    ///
+    /// The per-core fields (e.g., the APIC ID) are not patched in to the
+    /// table, so only differences due to the policy and the virtual clocks
+    /// are reported.
+    pub fn host_divergence(&self) -> Vec<CpuidDivergence> {
+        self.divergence(raw_cpuid::native_cpuid::cpuid_count)
+    }
+
+    fn divergence(
+        &self,
+        cpuid: impl Fn(u32, u32) -> CpuIdResult,
+    ) -> Vec<CpuidDivergence> {
+        let mut divergence = vec![];
+        for ((leaf, subleaf), guest) in self.entries.iter() {
+            let host = cpuid(*leaf, *subleaf);
+            let registers = [
+                ("eax", host.eax, guest.eax),
+                ("ebx", host.ebx, guest.ebx),
+                ("ecx", host.ecx, guest.ecx),
+                ("edx", host.edx, guest.edx),
+            ];
+            divergence.extend(
+                registers
+                    .iter()
+                    .filter(|(_, host, guest)| host != guest)
+                    .map(|(register, host, guest)| CpuidDivergence {
+                        leaf: *leaf,
+                        subleaf: *subleaf,
+                        register,
+                        host: *host,
+                        guest: *guest,
+                    }),
+            );
+        }
+        divergence
+    }
+
+    /// The values reported for `leaf` and `subleaf` on the core with the
+    /// given (x2)APIC ID.
+    pub fn lookup(&self, leaf: u32, subleaf: u32, apic_id: u32) -> CpuIdResult {
+        // Like Intel processors, invalid leaves report the highest basic leaf
+        let leaf = if (leaf > self.max_basic_leaf && leaf < EXTENDED_LEAF_BASE)
+            || leaf > self.max_extended_leaf
+        {
+            self.max_basic_leaf
+        } else {
+            leaf
+        };
+
+        let key = if Self::is_indexed(leaf) {
+            (leaf, subleaf)
+        } else {
+            (leaf, 0)
+        };
+        let mut res = self.entries.get(&key).copied().unwrap_or(CpuIdResult {
+            eax: 0,
+            ebx: 0,
+            ecx: 0,
+            edx: 0,
+        });
+
+        // The table is shared by all cores, so patch in the core's own ID
+        match leaf {
+            0x1 => {
+                res.ebx = (res.ebx & 0x00ffffff) | ((apic_id & 0xff) << 24);
+            }
+            0xb | 0x1f => {
+                res.ecx = (res.ecx & !0xff) | (subleaf & 0xff);
+                res.edx = apic_id;
+            }
+            _ => (),
+        }
+        res
+    }
+}
 
 pub fn emulate_cpuid(
-    _vcpu: &mut vcpu::VCpu,
+    vcpu: &mut vcpu::VCpu,
     guest_cpu: &mut vmexit::GuestCpuState,
 ) -> Result<()> {
-    //FIXME: for now just use the actual cpuid
-    let mut res = raw_cpuid::native_cpuid::cpuid_count(
+    // The table cached by the vCPU is used, so no lock is taken
+    let res = vcpu.cpuid().lookup(
         guest_cpu.rax as u32,
         guest_cpu.rcx as u32,
+        vcpu.id().raw,
     );
 
-    if guest_cpu.rax as u32 == 1 {
-        // Disable MTRR
-        res.edx &= !(1 << 12);
+    // CPUID clears the upper halves of the registers in 64-bit mode
+    let dword = vmexit::OperandWidth::Dword;
+    guest_cpu.write_gpr(vmexit::Gpr::Rax, res.eax as u64, dword);
+    guest_cpu.write_gpr(vmexit::Gpr::Rbx, res.ebx as u64, dword);
+    guest_cpu.write_gpr(vmexit::Gpr::Rcx, res.ecx as u64, dword);
+    guest_cpu.write_gpr(vmexit::Gpr::Rdx, res.edx as u64, dword);
+    Ok(())
+}
 
-        // Disable XSAVE
-        res.ecx &= !(1 << 26);
+#[cfg(test)]
+mod test {
+    use super::*;
 
-        // Hide hypervisor feature
-        res.ecx &= !(1 << 31);
+    fn fake_cpuid(leaf: u32, subleaf: u32) -> CpuIdResult {
+        let (eax, ebx, ecx, edx) = match (leaf, subleaf) {
+            (0x0, _) => (0xb, 0x756e6547, 0x6c65746e, 0x49656e69),
+            (0x1, _) => (0x906ea, 0x0a100800, 0xfffa3203, 0x178bfbff),
+            (0xb, 0) => (1, 2, 0x100, 0),
+            (0xb, 1) => (4, 8, 0x201, 0),
+            (0x7, 0) => (0, 0, LA57_FEATURE | 1, 0),
+            (0xb, n) => (0, 0, n, 0),
+            (0x80000000, _) => (0x80000008, 0, 0, 0),
+            (0x80000001, _) => (0, 0, 0x121, 0x2c100800),
+            (0x80000008, _) => (0x3927, 0, 0, 0),
+            _ => (0, 0, 0, 0),
+        };
+        CpuIdResult { eax, ebx, ecx, edx }
     }
 
-    guest_cpu.rax = res.eax as u64 | (guest_cpu.rax & 0xffffffff00000000);
-    guest_cpu.rbx = res.ebx as u64 | (guest_cpu.rbx & 0xffffffff00000000);
-    guest_cpu.rcx = res.ecx as u64 | (guest_cpu.rcx & 0xffffffff00000000);
-    guest_cpu.rdx = res.edx as u64 | (guest_cpu.rdx & 0xffffffff00000000);
-    Ok(())
+    #[test]
+    fn test_cpuid_table() {
+        let clocks = GuestClocks {
+            tsc: 2_400_000_000,
+            apic_timer: 25_000_000,
+        };
+        let table =
+            CpuidTable::from_source(CpuidPolicy::default(), clocks, fake_cpuid);
+
+        let res = table.lookup(0x1, 0, 5);
+        assert_eq!(res.ecx, 0xfffa3203 & !(1 << 26) & !(1 << 31));
+        assert_eq!(res.edx, 0x178bfbff & !(1 << 12));
+        assert_eq!(res.ebx >> 24, 5);
+
+        // Topology leaves report the current core and requested subleaf
+        let res = table.lookup(0xb, 1, 5);
+        assert_eq!((res.eax, res.ecx, res.edx), (4, 0x201, 5));
+        let res = table.lookup(0xb, 7, 5);
+        assert_eq!((res.eax, res.ecx, res.edx), (0, 7, 5));
+
+        // The clock leaves are added to the basic leaves
+        assert_eq!(table.lookup(0x0, 0, 0).eax, 0x16);
+        let res = table.lookup(0x15, 0, 0);
+        assert_eq!((res.eax, res.ebx, res.ecx), (1, 96, 25_000_000));
+        let res = table.lookup(0x16, 0, 0);
+        assert_eq!((res.eax, res.ebx, res.ecx), (2400, 2400, 25));
+
+        // Invalid leaves report the highest basic leaf
+        assert_eq!(table.lookup(0x40000000, 0, 0).ecx, 25);
+        assert_eq!(table.lookup(0x80000001, 0, 0).edx, 0x2c100800);
+        assert_eq!(table.lookup(0x80000009, 0, 0).ecx, 25);
+    }
+
+    #[test]
+    fn test_host_divergence() {
+        let clocks = GuestClocks {
+            tsc: 2_400_000_000,
+            apic_timer: 25_000_000,
+        };
+        let table =
+            CpuidTable::from_source(CpuidPolicy::default(), clocks, fake_cpuid);
+        let divergence = table.divergence(fake_cpuid);
+        assert_eq!(
+            divergence[..2],
+            [
+                CpuidDivergence {
+                    leaf: 0,
+                    subleaf: 0,
+                    register: "eax",
+                    host: 0xb,
+                    guest: 0x16,
+                },
+                CpuidDivergence {
+                    leaf: 1,
+                    subleaf: 0,
+                    register: "ecx",
+                    host: 0xfffa3203,
+                    guest: 0xfffa3203 & !(1 << 26) & !(1 << 31),
+                },
+            ]
+        );
+        assert!(divergence
+            .iter()
+            .any(|div| (div.leaf, div.register) == (0x15, "ebx")));
+        assert!(!divergence.iter().any(|div| div.leaf == 0xb));
+    }
+
+    #[test]
+    fn test_la57_policy() {
+        let clocks = GuestClocks {
+            tsc: 2_400_000_000,
+            apic_timer: 25_000_000,
+        };
+        let table =
+            CpuidTable::from_source(CpuidPolicy::default(), clocks, fake_cpuid);
+        assert!(!table.la57_supported());
+        assert_eq!(table.lookup(0x7, 0, 0).ecx, 1);
+        assert_eq!(table.lookup(0x80000008, 0, 0).eax, 0x3027);
+
+        let policy = CpuidPolicy {
+            la57: true,
+            ..CpuidPolicy::default()
+        };
+        let table = CpuidTable::from_source(policy, clocks, fake_cpuid);
+        assert!(table.la57_supported());
+        assert_eq!(table.lookup(0x80000008, 0, 0).eax, 0x3927);
+    }
 }
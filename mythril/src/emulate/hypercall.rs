@@ -0,0 +1,370 @@
+//! Paravirtual hypercalls
+//!
+//! Guests invoke a hypercall by executing VMCALL with the hypercall number
+//! in `rax` and the arguments in `rbx`, `rcx`, `rdx` and `rsi`. The result
+//! is returned in `rax`. The numbering and calling convention follow KVM, so
+//! existing paravirtual spinlock support in guests can be used unchanged.
+//! Mythril's own hypercalls are numbered from `0x4d59_0000` (like its
+//! synthetic MSRs), clear of the KVM numbers.
+
+use crate::error::Result;
+use crate::evtchn::{self, EventSource};
+use crate::memory::{self, GuestVirtAddr};
+use crate::{
+    consoleroute, migrate, percore, stats, time, vcpu, vm, vmcs, vmexit,
+};
+use alloc::string::String;
+
+/// Wake the vCPU with the APIC ID in `rcx` (`rbx` holds flags, which must
+/// be zero). Used by a spinlock holder to wake a halted waiter.
+pub const HC_KICK_CPU: u64 = 5;
+
+/// Yield to the vCPU with the APIC ID in `rbx` (typically the holder of the
+/// lock that the caller is spinning on). Every vCPU owns its core, so there
+/// is nothing to yield to, and this fails with `HC_ENOSYS` (the guest then
+/// keeps spinning).
+pub const HC_SCHED_YIELD: u64 = 11;
+
+/// Write the message of `rdx` bytes at the guest virtual address `rcx` to
+/// the hypervisor log, tagged with the (up to 8) ASCII characters packed
+/// into `rbx`. Messages are rate limited per VM (see
+/// `VirtualMachineConfig::set_guest_log_rate_limit`).
+pub const HC_MYTHRIL_LOG: u64 = 0x4d59_0000;
+
+/// Return the UUID of the VM (see `identity::Uuid::halves`) in `rbx` and
+/// `rcx`
+pub const HC_MYTHRIL_VM_UUID: u64 = 0x4d59_0001;
+
+/// Copy the name of the VM (without a NULL terminator) to the buffer of
+/// `rcx` bytes at the guest virtual address `rbx`, returning the length of
+/// the name. The name is truncated if the buffer is too small.
+pub const HC_MYTHRIL_VM_NAME: u64 = 0x4d59_0002;
+
+/// Use the page at the guest physical address `rbx` for the pending and
+/// mask bitmaps of the event channels, and deliver their notifications with
+/// vector `rcx` (see `evtchn`)
+pub const HC_MYTHRIL_EVTCHN_SETUP: u64 = 0x4d59_0003;
+
+/// Bind a channel to the event source of kind `rbx` (0 for a device, 1 for
+/// a VM) and id `rcx`, returning the channel
+pub const HC_MYTHRIL_EVTCHN_BIND: u64 = 0x4d59_0004;
+
+/// Unbind the event channel `rbx`
+pub const HC_MYTHRIL_EVTCHN_CLOSE: u64 = 0x4d59_0005;
+
+/// Mask (if `rcx` is non-zero) or unmask the event channel `rbx`.
+/// Unmasking a pending channel delivers its notification.
+pub const HC_MYTHRIL_EVTCHN_MASK: u64 = 0x4d59_0006;
+
+/// Signal the VM bound to the event channel `rbx`, on the channel it has
+/// bound to the caller
+pub const HC_MYTHRIL_EVTCHN_SEND: u64 = 0x4d59_0007;
+
+/// Copy the statistics of the VM (in the format of `stats::encode`) to the
+/// buffer of `rcx` bytes at the guest virtual address `rbx`, returning the
+/// length of the export. Nothing is copied if the buffer is too small.
+pub const HC_MYTHRIL_STATS: u64 = 0x4d59_0008;
+
+/// Move up to `rcx` bytes of the console output routed to the VM (see
+/// `consoleroute`) to the buffer at the guest virtual address `rbx`,
+/// returning the number of bytes
+pub const HC_MYTHRIL_CONSOLE_READ: u64 = 0x4d59_0009;
+
+/// The maximum length of a message logged with `HC_MYTHRIL_LOG`
+pub const MAX_LOG_MESSAGE: u64 = 256;
+
+// Hypercall return values
+const HC_SUCCESS: u64 = 0;
+const HC_EINVAL: u64 = -22i64 as u64;
+const HC_EPERM: u64 = -1i64 as u64;
+const HC_EAGAIN: u64 = -11i64 as u64;
+const HC_ENOSYS: u64 = -1000i64 as u64;
+
+// Returns the core running the vCPU with the given APIC ID, if it belongs
+// to the same VM as the caller.
+fn sibling_core(vcpu: &vcpu::VCpu, apic_id: u64) -> Option<percore::CoreId> {
+    let core_id = percore::CoreId::from(apic_id as u32);
+    if core_id == migrate::current_vcpu() {
+        return None;
+    }
+    let vm = vcpu.vm.read();
+    if vm.config.cpus().contains(&core_id) {
+        Some(core_id)
+    } else {
+        None
+    }
+}
+
+fn kick_cpu(vcpu: &vcpu::VCpu, flags: u64, apic_id: u64) -> Result<u64> {
+    if flags != 0 {
+        return Ok(HC_EINVAL);
+    }
+    match sibling_core(vcpu, apic_id) {
+        Some(core_id) => {
+            vm::send_vm_msg_core(vm::VirtualMachineMsg::Kick, core_id)?;
+            Ok(HC_SUCCESS)
+        }
+        None => Ok(HC_EINVAL),
+    }
+}
+
+// The printable form of a log tag or message
+fn printable(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| match byte {
+            0x20..=0x7e => *byte as char,
+            b'\t' => ' ',
+            _ => '?',
+        })
+        .collect()
+}
+
+fn decode_tag(tag: u64) -> String {
+    let bytes = tag.to_le_bytes();
+    let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    printable(&bytes[..len])
+}
+
+fn guest_log(vcpu: &vcpu::VCpu, tag: u64, addr: u64, len: u64) -> Result<u64> {
+    if len > MAX_LOG_MESSAGE {
+        return Ok(HC_EINVAL);
+    }
+    let vm = vcpu.vm.read();
+    let suppressed = match vm.guest_log.lock().admit(time::uptime()) {
+        Some(suppressed) => suppressed,
+        None => return Ok(HC_EAGAIN),
+    };
+
+    let view =
+        memory::GuestAddressSpaceView::from_vmcs(&vcpu.vmcs, &vm.guest_space)?;
+    let message = view.read_bytes(
+        GuestVirtAddr::new(addr, &vcpu.vmcs)?,
+        len as usize,
+        memory::GuestAccess::Read(memory::PrivilegeLevel(0)),
+    )?;
+
+    if suppressed > 0 {
+        info!("guest {}: {} messages suppressed", vm.name, suppressed);
+    }
+    // Messages are logged one per line, so drop any trailing line ending
+    let end = message
+        .iter()
+        .rposition(|byte| !b"\r\n\0".contains(byte))
+        .map_or(0, |pos| pos + 1);
+    info!(
+        "guest {} [{}]: {}",
+        vm.name,
+        decode_tag(tag),
+        printable(&message[..end])
+    );
+    Ok(HC_SUCCESS)
+}
+
+fn vm_uuid(vcpu: &vcpu::VCpu, guest_cpu: &mut vmexit::GuestCpuState) -> u64 {
+    let (high, low) = vcpu.vm.read().uuid.halves();
+    guest_cpu.rbx = high;
+    guest_cpu.rcx = low;
+    HC_SUCCESS
+}
+
+fn vm_name(vcpu: &vcpu::VCpu, addr: u64, len: u64) -> Result<u64> {
+    let mut vm = vcpu.vm.write();
+    let name = vm.name.clone().into_bytes();
+    let copied = core::cmp::min(name.len(), len as usize);
+    if copied > 0 {
+        let mut view = memory::GuestAddressSpaceViewMut::from_vmcs(
+            &vcpu.vmcs,
+            &mut vm.guest_space,
+        )?;
+        view.write_bytes(
+            GuestVirtAddr::new(addr, &vcpu.vmcs)?,
+            &name[..copied],
+            memory::GuestAccess::Write(memory::PrivilegeLevel(0)),
+        )?;
+    }
+    Ok(name.len() as u64)
+}
+
+fn vm_stats(vcpu: &vcpu::VCpu, addr: u64, len: u64) -> Result<u64> {
+    let mut vm = vcpu.vm.write();
+    let export = stats::export_vm(&vm);
+    if export.len() as u64 <= len {
+        let mut view = memory::GuestAddressSpaceViewMut::from_vmcs(
+            &vcpu.vmcs,
+            &mut vm.guest_space,
+        )?;
+        view.write_bytes(
+            GuestVirtAddr::new(addr, &vcpu.vmcs)?,
+            &export,
+            memory::GuestAccess::Write(memory::PrivilegeLevel(0)),
+        )?;
+    }
+    Ok(export.len() as u64)
+}
+
+fn console_read(vcpu: &vcpu::VCpu, addr: u64, len: u64) -> Result<u64> {
+    let mut vm = vcpu.vm.write();
+    let mut data =
+        vec![0u8; len.min(consoleroute::CHANNEL_CAPACITY as u64) as usize];
+    let len = consoleroute::read_channel(vm.id, &mut data);
+    if len > 0 {
+        let mut view = memory::GuestAddressSpaceViewMut::from_vmcs(
+            &vcpu.vmcs,
+            &mut vm.guest_space,
+        )?;
+        view.write_bytes(
+            GuestVirtAddr::new(addr, &vcpu.vmcs)?,
+            &data[..len],
+            memory::GuestAccess::Write(memory::PrivilegeLevel(0)),
+        )?;
+    }
+    Ok(len as u64)
+}
+
+// The event channel in a hypercall argument
+fn channel_arg(raw: u64) -> Option<evtchn::Channel> {
+    if raw < evtchn::MAX_EVENT_CHANNELS as u64 {
+        Some(raw as evtchn::Channel)
+    } else {
+        None
+    }
+}
+
+fn evtchn_setup(vcpu: &vcpu::VCpu, page: u64, vector: u64) -> Result<u64> {
+    if vector > u8::MAX as u64 {
+        return Ok(HC_EINVAL);
+    }
+    let vm = vcpu.vm.read();
+    let mut channels = vm.event_channels.lock();
+    match channels.setup(&vm.guest_space, page, vector as u8) {
+        Ok(()) => Ok(HC_SUCCESS),
+        Err(_) => Ok(HC_EINVAL),
+    }
+}
+
+fn evtchn_bind(vcpu: &vcpu::VCpu, kind: u64, id: u64) -> Result<u64> {
+    let source = match EventSource::from_raw(kind, id) {
+        Ok(source) => source,
+        Err(_) => return Ok(HC_EINVAL),
+    };
+    let vm = vcpu.vm.read();
+    let mut channels = vm.event_channels.lock();
+    match channels.bind(source) {
+        Ok(channel) => Ok(channel as u64),
+        Err(_) => Ok(HC_EINVAL),
+    }
+}
+
+fn evtchn_close(vcpu: &vcpu::VCpu, channel: u64) -> Result<u64> {
+    let channel = match channel_arg(channel) {
+        Some(channel) => channel,
+        None => return Ok(HC_EINVAL),
+    };
+    let vm = vcpu.vm.read();
+    let mut channels = vm.event_channels.lock();
+    match channels.close(channel) {
+        Ok(()) => Ok(HC_SUCCESS),
+        Err(_) => Ok(HC_EINVAL),
+    }
+}
+
+fn evtchn_mask(
+    vcpu: &mut vcpu::VCpu,
+    channel: u64,
+    masked: u64,
+) -> Result<u64> {
+    let channel = match channel_arg(channel) {
+        Some(channel) => channel,
+        None => return Ok(HC_EINVAL),
+    };
+    let result = {
+        let vm = vcpu.vm.read();
+        let mut channels = vm.event_channels.lock();
+        channels.set_masked(&vm.guest_space, channel, masked != 0)
+    };
+    match result {
+        Ok(Some(vector)) => {
+            vcpu.inject_interrupt(
+                vector,
+                vcpu::InjectedInterruptType::ExternalInterrupt,
+            );
+            Ok(HC_SUCCESS)
+        }
+        Ok(None) => Ok(HC_SUCCESS),
+        Err(_) => Ok(HC_EINVAL),
+    }
+}
+
+fn evtchn_send(vcpu: &vcpu::VCpu, channel: u64) -> Result<u64> {
+    let channel = match channel_arg(channel) {
+        Some(channel) => channel,
+        None => return Ok(HC_EINVAL),
+    };
+    let (vm_id, source) = {
+        let vm = vcpu.vm.read();
+        let source = vm.event_channels.lock().source(channel);
+        (vm.id, source)
+    };
+    match source {
+        Some(EventSource::Vm(remote)) => {
+            match evtchn::signal(remote, EventSource::Vm(vm_id)) {
+                Ok(()) => Ok(HC_SUCCESS),
+                Err(_) => Ok(HC_EAGAIN),
+            }
+        }
+        _ => Ok(HC_EINVAL),
+    }
+}
+
+pub fn emulate_vmcall(
+    vcpu: &mut vcpu::VCpu,
+    guest_cpu: &mut vmexit::GuestCpuState,
+) -> Result<()> {
+    // Hypercalls are only permitted from ring 0 (the DPL of SS is the CPL)
+    let ss_access = vcpu.vmcs.read_field(vmcs::VmcsField::GuestSsArBytes)?;
+    if (ss_access >> 5) & 0b11 != 0 {
+        guest_cpu.rax = HC_EPERM;
+        return Ok(());
+    }
+
+    guest_cpu.rax = match guest_cpu.rax {
+        HC_KICK_CPU => kick_cpu(vcpu, guest_cpu.rbx, guest_cpu.rcx)?,
+        HC_SCHED_YIELD => HC_ENOSYS,
+        HC_MYTHRIL_LOG => {
+            guest_log(vcpu, guest_cpu.rbx, guest_cpu.rcx, guest_cpu.rdx)?
+        }
+        HC_MYTHRIL_VM_UUID => vm_uuid(vcpu, guest_cpu),
+        HC_MYTHRIL_VM_NAME => vm_name(vcpu, guest_cpu.rbx, guest_cpu.rcx)?,
+        HC_MYTHRIL_EVTCHN_SETUP => {
+            evtchn_setup(vcpu, guest_cpu.rbx, guest_cpu.rcx)?
+        }
+        HC_MYTHRIL_EVTCHN_BIND => {
+            evtchn_bind(vcpu, guest_cpu.rbx, guest_cpu.rcx)?
+        }
+        HC_MYTHRIL_EVTCHN_CLOSE => evtchn_close(vcpu, guest_cpu.rbx)?,
+        HC_MYTHRIL_EVTCHN_MASK => {
+            evtchn_mask(vcpu, guest_cpu.rbx, guest_cpu.rcx)?
+        }
+        HC_MYTHRIL_EVTCHN_SEND => evtchn_send(vcpu, guest_cpu.rbx)?,
+        HC_MYTHRIL_STATS => vm_stats(vcpu, guest_cpu.rbx, guest_cpu.rcx)?,
+        HC_MYTHRIL_CONSOLE_READ => {
+            console_read(vcpu, guest_cpu.rbx, guest_cpu.rcx)?
+        }
+        _ => HC_ENOSYS,
+    };
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_log_formatting() {
+        assert_eq!(decode_tag(u64::from_le_bytes(*b"initrd\0\0")), "initrd");
+        assert_eq!(decode_tag(u64::from_le_bytes(*b"12345678")), "12345678");
+        assert_eq!(decode_tag(0), "");
+        assert_eq!(printable(b"ok\tdone\x1b[0m\n"), "ok done?[0m?");
+    }
+}
@@ -0,0 +1,119 @@
+//! Emulation of the TLB invalidation instructions
+//!
+//! INVLPG (and INVPCID, when it is enabled) only cause a VM exit if the VM
+//! requested INVLPG exiting (see `VirtualMachineConfig::set_invlpg_exiting`),
+//! for example to observe the guest's paging changes. Guest translations are
+//! tagged with the VPID of the vCPU, so each instruction is emulated with an
+//! INVVPID that invalidates at least the same translations.
+//!
+//! Mythril does not keep its own cache of guest translations (guest linear
+//! addresses are walked on each access, see
+//! `GuestAddressSpace::translate_linear_address`), so there are no software
+//! translations to invalidate.
+
+use crate::error::Result;
+use crate::memory::{self, GuestFault, GuestVirtAddr};
+use crate::{vcpu, vmcs, vmexit, vmx};
+
+// The INVPCID invalidation types
+const INVPCID_INDIVIDUAL_ADDRESS: u64 = 0;
+const INVPCID_SINGLE_CONTEXT: u64 = 1;
+const INVPCID_ALL_CONTEXT: u64 = 2;
+const INVPCID_ALL_CONTEXT_RETAIN_GLOBAL: u64 = 3;
+
+const CR4_PCIDE: u64 = 1 << 17;
+
+fn current_vpid(vcpu: &vcpu::VCpu) -> Result<u16> {
+    Ok(vcpu.vmcs.read_field(vmcs::VmcsField::VirtualProcessorId)? as u16)
+}
+
+fn invalidate_address(
+    vcpu: &mut vcpu::VCpu,
+    addr: GuestVirtAddr,
+) -> Result<()> {
+    let vpid = current_vpid(vcpu)?;
+
+    // INVVPID checks the address against the linear address width of the
+    // host, so the wider addresses of a 5-level paging guest can only be
+    // invalidated with the rest of the context on a 4-level paging host
+    if !memory::is_host_canonical(addr.as_u64()) {
+        return vcpu.vmcs.vmx.invvpid(vmx::InvVpidMode::SingleContext(vpid));
+    }
+    vcpu.vmcs
+        .vmx
+        .invvpid(vmx::InvVpidMode::IndividualAddress(vpid, addr))
+}
+
+pub fn emulate_invlpg(vcpu: &mut vcpu::VCpu) -> Result<()> {
+    let addr = vcpu.vmcs.read_field(vmcs::VmcsField::ExitQualification)?;
+    let addr = GuestVirtAddr::new(addr, &vcpu.vmcs)?;
+
+    // INVLPG of a non-canonical address is a no-op (INVVPID would fail)
+    if !addr.is_canonical() {
+        return Ok(());
+    }
+    invalidate_address(vcpu, addr)
+}
+
+pub fn emulate_invpcid(
+    vcpu: &mut vcpu::VCpu,
+    guest_cpu: &mut vmexit::GuestCpuState,
+    info: vmexit::InvpcidInformation,
+) -> Result<()> {
+    let kind = info.type_register.read(&vcpu.vmcs, guest_cpu)?;
+    let addr = info.descriptor_address(&vcpu.vmcs, guest_cpu)?;
+
+    let descriptor = {
+        let vm = vcpu.vm.read();
+        let view = memory::GuestAddressSpaceView::from_vmcs(
+            &vcpu.vmcs,
+            &vm.guest_space,
+        )?;
+        view.read_bytes(
+            GuestVirtAddr::new(addr, &vcpu.vmcs)?,
+            16,
+            memory::GuestAccess::Read(memory::PrivilegeLevel(0)),
+        )?
+    };
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&descriptor[..8]);
+    let pcid = u64::from_le_bytes(bytes);
+    bytes.copy_from_slice(&descriptor[8..]);
+    let linear_addr =
+        GuestVirtAddr::new(u64::from_le_bytes(bytes), &vcpu.vmcs)?;
+
+    // Bits 63:12 of the descriptor are reserved (see "INVPCID" in Volume 2)
+    if pcid > 0xfff {
+        return Err(GuestFault::GeneralProtection(0).into());
+    }
+    let cr4 = vcpu.vmcs.read_field(vmcs::VmcsField::Cr4ReadShadow)?;
+    let pcid_enabled = cr4 & CR4_PCIDE != 0;
+
+    let vpid = current_vpid(vcpu)?;
+    match kind {
+        INVPCID_INDIVIDUAL_ADDRESS => {
+            if !linear_addr.is_canonical() || (pcid != 0 && !pcid_enabled) {
+                return Err(GuestFault::GeneralProtection(0).into());
+            }
+            invalidate_address(vcpu, linear_addr)
+        }
+        // Translations are not tagged with the PCID by the VPID, so all
+        // non-global translations of the vCPU are invalidated
+        INVPCID_SINGLE_CONTEXT => {
+            if pcid != 0 && !pcid_enabled {
+                return Err(GuestFault::GeneralProtection(0).into());
+            }
+            vcpu.vmcs
+                .vmx
+                .invvpid(vmx::InvVpidMode::SingleContextRetainGlobal(vpid))
+        }
+        INVPCID_ALL_CONTEXT => {
+            vcpu.vmcs.vmx.invvpid(vmx::InvVpidMode::SingleContext(vpid))
+        }
+        INVPCID_ALL_CONTEXT_RETAIN_GLOBAL => vcpu
+            .vmcs
+            .vmx
+            .invvpid(vmx::InvVpidMode::SingleContextRetainGlobal(vpid)),
+        _ => Err(GuestFault::GeneralProtection(0).into()),
+    }
+}
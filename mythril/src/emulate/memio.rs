@@ -1,33 +1,98 @@
+use crate::emulate::msr;
 use crate::error::{Error, Result};
 use crate::memory;
 use crate::virtdev::{
     DeviceEvent, MemReadRequest, MemWriteRequest, ResponseEventArray,
 };
+use crate::vmexit::{Gpr, OperandWidth};
 use crate::{vcpu, vmcs, vmexit};
 use arrayvec::ArrayVec;
 use iced_x86;
 
-macro_rules! read_register {
-    ($out:ident, $value:expr, $type:ty) => {{
-        let data = ($value as $type).to_be_bytes();
-        $out.try_extend_from_slice(&data).map_err(|_| {
-            Error::InvalidValue("Invalid length with reading register".into())
-        })?;
-    }};
-}
-
-macro_rules! write_register {
-    ($vm:ident, $vcpu:ident, $addr:expr, $responses:ident, $value:expr, $type:ty, $mask:expr) => {{
-        let mut buff = <$type>::default().to_be_bytes();
-        let request = MemReadRequest::new(&mut buff[..]);
-        $vm.dispatch_event(
-            $addr,
-            DeviceEvent::MemRead($addr, request),
-            $vcpu,
-            $responses,
-        )?;
-        $value = ($value & $mask) | <$type>::from_be_bytes(buff) as u64;
-    }};
+// The register and width of a register operand
+//
+// TODO: we should probably support the AH style registers
+fn register_operand(
+    register: iced_x86::Register,
+) -> Option<(Gpr, OperandWidth)> {
+    use iced_x86::Register;
+    Some(match register {
+        Register::AL => (Gpr::Rax, OperandWidth::Byte),
+        Register::AX => (Gpr::Rax, OperandWidth::Word),
+        Register::EAX => (Gpr::Rax, OperandWidth::Dword),
+        Register::RAX => (Gpr::Rax, OperandWidth::Qword),
+
+        Register::BL => (Gpr::Rbx, OperandWidth::Byte),
+        Register::BX => (Gpr::Rbx, OperandWidth::Word),
+        Register::EBX => (Gpr::Rbx, OperandWidth::Dword),
+        Register::RBX => (Gpr::Rbx, OperandWidth::Qword),
+
+        Register::CL => (Gpr::Rcx, OperandWidth::Byte),
+        Register::CX => (Gpr::Rcx, OperandWidth::Word),
+        Register::ECX => (Gpr::Rcx, OperandWidth::Dword),
+        Register::RCX => (Gpr::Rcx, OperandWidth::Qword),
+
+        Register::DL => (Gpr::Rdx, OperandWidth::Byte),
+        Register::DX => (Gpr::Rdx, OperandWidth::Word),
+        Register::EDX => (Gpr::Rdx, OperandWidth::Dword),
+        Register::RDX => (Gpr::Rdx, OperandWidth::Qword),
+
+        Register::R8L => (Gpr::R8, OperandWidth::Byte),
+        Register::R8W => (Gpr::R8, OperandWidth::Word),
+        Register::R8D => (Gpr::R8, OperandWidth::Dword),
+        Register::R8 => (Gpr::R8, OperandWidth::Qword),
+
+        Register::R9L => (Gpr::R9, OperandWidth::Byte),
+        Register::R9W => (Gpr::R9, OperandWidth::Word),
+        Register::R9D => (Gpr::R9, OperandWidth::Dword),
+        Register::R9 => (Gpr::R9, OperandWidth::Qword),
+
+        Register::R10L => (Gpr::R10, OperandWidth::Byte),
+        Register::R10W => (Gpr::R10, OperandWidth::Word),
+        Register::R10D => (Gpr::R10, OperandWidth::Dword),
+        Register::R10 => (Gpr::R10, OperandWidth::Qword),
+
+        Register::R11L => (Gpr::R11, OperandWidth::Byte),
+        Register::R11W => (Gpr::R11, OperandWidth::Word),
+        Register::R11D => (Gpr::R11, OperandWidth::Dword),
+        Register::R11 => (Gpr::R11, OperandWidth::Qword),
+
+        Register::R12L => (Gpr::R12, OperandWidth::Byte),
+        Register::R12W => (Gpr::R12, OperandWidth::Word),
+        Register::R12D => (Gpr::R12, OperandWidth::Dword),
+        Register::R12 => (Gpr::R12, OperandWidth::Qword),
+
+        Register::R13L => (Gpr::R13, OperandWidth::Byte),
+        Register::R13W => (Gpr::R13, OperandWidth::Word),
+        Register::R13D => (Gpr::R13, OperandWidth::Dword),
+        Register::R13 => (Gpr::R13, OperandWidth::Qword),
+
+        Register::R14L => (Gpr::R14, OperandWidth::Byte),
+        Register::R14W => (Gpr::R14, OperandWidth::Word),
+        Register::R14D => (Gpr::R14, OperandWidth::Dword),
+        Register::R14 => (Gpr::R14, OperandWidth::Qword),
+
+        Register::R15L => (Gpr::R15, OperandWidth::Byte),
+        Register::R15W => (Gpr::R15, OperandWidth::Word),
+        Register::R15D => (Gpr::R15, OperandWidth::Dword),
+        Register::R15 => (Gpr::R15, OperandWidth::Qword),
+
+        Register::DIL => (Gpr::Rdi, OperandWidth::Byte),
+        Register::DI => (Gpr::Rdi, OperandWidth::Word),
+        Register::EDI => (Gpr::Rdi, OperandWidth::Dword),
+        Register::RDI => (Gpr::Rdi, OperandWidth::Qword),
+
+        Register::SIL => (Gpr::Rsi, OperandWidth::Byte),
+        Register::SI => (Gpr::Rsi, OperandWidth::Word),
+        Register::ESI => (Gpr::Rsi, OperandWidth::Dword),
+        Register::RSI => (Gpr::Rsi, OperandWidth::Qword),
+
+        Register::BPL => (Gpr::Rbp, OperandWidth::Byte),
+        Register::BP => (Gpr::Rbp, OperandWidth::Word),
+        Register::EBP => (Gpr::Rbp, OperandWidth::Dword),
+        Register::RBP => (Gpr::Rbp, OperandWidth::Qword),
+        _ => return None,
+    })
 }
 
 fn read_register_value(
@@ -35,112 +100,32 @@ fn read_register_value(
     vmcs: &vmcs::ActiveVmcs,
     guest_cpu: &mut vmexit::GuestCpuState,
 ) -> Result<ArrayVec<[u8; 8]>> {
-    let mut res = ArrayVec::new();
-
-    // TODO: we should probably support the AH style registers
-    match register {
-        iced_x86::Register::AL => read_register!(res, guest_cpu.rax, u8),
-        iced_x86::Register::AX => read_register!(res, guest_cpu.rax, u16),
-        iced_x86::Register::EAX => read_register!(res, guest_cpu.rax, u32),
-        iced_x86::Register::RAX => read_register!(res, guest_cpu.rax, u64),
-
-        iced_x86::Register::BL => read_register!(res, guest_cpu.rbx, u8),
-        iced_x86::Register::BX => read_register!(res, guest_cpu.rbx, u16),
-        iced_x86::Register::EBX => read_register!(res, guest_cpu.rbx, u32),
-        iced_x86::Register::RBX => read_register!(res, guest_cpu.rbx, u64),
-
-        iced_x86::Register::CL => read_register!(res, guest_cpu.rcx, u8),
-        iced_x86::Register::CX => read_register!(res, guest_cpu.rcx, u16),
-        iced_x86::Register::ECX => read_register!(res, guest_cpu.rcx, u32),
-        iced_x86::Register::RCX => read_register!(res, guest_cpu.rcx, u64),
-
-        iced_x86::Register::DL => read_register!(res, guest_cpu.rdx, u8),
-        iced_x86::Register::DX => read_register!(res, guest_cpu.rdx, u16),
-        iced_x86::Register::EDX => read_register!(res, guest_cpu.rdx, u32),
-        iced_x86::Register::RDX => read_register!(res, guest_cpu.rdx, u64),
-
-        iced_x86::Register::R8L => read_register!(res, guest_cpu.r8, u8),
-        iced_x86::Register::R8W => read_register!(res, guest_cpu.r8, u16),
-        iced_x86::Register::R8D => read_register!(res, guest_cpu.r8, u32),
-        iced_x86::Register::R8 => read_register!(res, guest_cpu.r8, u64),
-
-        iced_x86::Register::R9L => read_register!(res, guest_cpu.r9, u8),
-        iced_x86::Register::R9W => read_register!(res, guest_cpu.r9, u16),
-        iced_x86::Register::R9D => read_register!(res, guest_cpu.r9, u32),
-        iced_x86::Register::R9 => read_register!(res, guest_cpu.r9, u64),
-
-        iced_x86::Register::R10L => read_register!(res, guest_cpu.r10, u8),
-        iced_x86::Register::R10W => read_register!(res, guest_cpu.r10, u16),
-        iced_x86::Register::R10D => read_register!(res, guest_cpu.r10, u32),
-        iced_x86::Register::R10 => read_register!(res, guest_cpu.r10, u64),
-
-        iced_x86::Register::R11L => read_register!(res, guest_cpu.r11, u8),
-        iced_x86::Register::R11W => read_register!(res, guest_cpu.r11, u16),
-        iced_x86::Register::R11D => read_register!(res, guest_cpu.r11, u32),
-        iced_x86::Register::R11 => read_register!(res, guest_cpu.r11, u64),
-
-        iced_x86::Register::R12L => read_register!(res, guest_cpu.r12, u8),
-        iced_x86::Register::R12W => read_register!(res, guest_cpu.r12, u16),
-        iced_x86::Register::R12D => read_register!(res, guest_cpu.r12, u32),
-        iced_x86::Register::R12 => read_register!(res, guest_cpu.r12, u64),
-
-        iced_x86::Register::R13L => read_register!(res, guest_cpu.r13, u8),
-        iced_x86::Register::R13W => read_register!(res, guest_cpu.r13, u16),
-        iced_x86::Register::R13D => read_register!(res, guest_cpu.r13, u32),
-        iced_x86::Register::R13 => read_register!(res, guest_cpu.r13, u64),
-
-        iced_x86::Register::R14L => read_register!(res, guest_cpu.r14, u8),
-        iced_x86::Register::R14W => read_register!(res, guest_cpu.r14, u16),
-        iced_x86::Register::R14D => read_register!(res, guest_cpu.r14, u32),
-        iced_x86::Register::R14 => read_register!(res, guest_cpu.r14, u64),
-
-        iced_x86::Register::R15L => read_register!(res, guest_cpu.r15, u8),
-        iced_x86::Register::R15W => read_register!(res, guest_cpu.r15, u16),
-        iced_x86::Register::R15D => read_register!(res, guest_cpu.r15, u32),
-        iced_x86::Register::R15 => read_register!(res, guest_cpu.r15, u64),
-
-        iced_x86::Register::DIL => read_register!(res, guest_cpu.rdi, u8),
-        iced_x86::Register::DI => read_register!(res, guest_cpu.rdi, u16),
-        iced_x86::Register::EDI => read_register!(res, guest_cpu.rdi, u32),
-        iced_x86::Register::RDI => read_register!(res, guest_cpu.rdi, u64),
-
-        iced_x86::Register::SIL => read_register!(res, guest_cpu.rsi, u8),
-        iced_x86::Register::SI => read_register!(res, guest_cpu.rsi, u16),
-        iced_x86::Register::ESI => read_register!(res, guest_cpu.rsi, u32),
-        iced_x86::Register::RSI => read_register!(res, guest_cpu.rsi, u64),
-
-        iced_x86::Register::SPL => {
-            read_register!(res, vmcs.read_field(vmcs::VmcsField::GuestRsp)?, u8)
-        }
-        iced_x86::Register::SP => read_register!(
-            res,
-            vmcs.read_field(vmcs::VmcsField::GuestRsp)?,
-            u16
-        ),
-        iced_x86::Register::ESP => read_register!(
-            res,
-            vmcs.read_field(vmcs::VmcsField::GuestRsp)?,
-            u32
-        ),
-        iced_x86::Register::RSP => read_register!(
-            res,
-            vmcs.read_field(vmcs::VmcsField::GuestRsp)?,
-            u64
-        ),
-
-        iced_x86::Register::BPL => read_register!(res, guest_cpu.rbp, u8),
-        iced_x86::Register::BP => read_register!(res, guest_cpu.rbp, u16),
-        iced_x86::Register::EBP => read_register!(res, guest_cpu.rbp, u32),
-        iced_x86::Register::RBP => read_register!(res, guest_cpu.rbp, u64),
-
-        _ => {
-            return Err(Error::InvalidValue(format!(
-                "Invalid register '{:?}'",
-                register
-            )))
+    // RSP is held in the VMCS
+    let rsp_width = match register {
+        iced_x86::Register::SPL => Some(OperandWidth::Byte),
+        iced_x86::Register::SP => Some(OperandWidth::Word),
+        iced_x86::Register::ESP => Some(OperandWidth::Dword),
+        iced_x86::Register::RSP => Some(OperandWidth::Qword),
+        _ => None,
+    };
+    let (value, width) = match rsp_width {
+        Some(width) => (vmcs.read_field(vmcs::VmcsField::GuestRsp)?, width),
+        None => {
+            let (gpr, width) = register_operand(register).ok_or_else(|| {
+                Error::InvalidValue(format!(
+                    "Invalid register '{:?}'",
+                    register
+                ))
+            })?;
+            (guest_cpu.read_gpr(gpr, width), width)
         }
-    }
+    };
 
+    let mut res = ArrayVec::new();
+    res.try_extend_from_slice(&value.to_be_bytes()[8 - width.bytes()..])
+        .map_err(|_| {
+            Error::InvalidValue("Invalid length with reading register".into())
+        })?;
     Ok(res)
 }
 
@@ -197,537 +182,30 @@ fn do_mmio_read(
     responses: &mut ResponseEventArray,
     instr: iced_x86::Instruction,
 ) -> Result<()> {
-    let mut vm = vcpu.vm.write();
-    match instr.op0_kind() {
-        iced_x86::OpKind::Register => match instr.op_register(0) {
-            iced_x86::Register::AL => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.rax,
-                u8,
-                !0xff
-            ),
-            iced_x86::Register::AX => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.rax,
-                u16,
-                !0xffff
-            ),
-            iced_x86::Register::EAX => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.rax,
-                u32,
-                !0xffffffff
-            ),
-            iced_x86::Register::RAX => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.rax,
-                u64,
-                0x00
-            ),
-
-            iced_x86::Register::BL => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.rbx,
-                u8,
-                !0xff
-            ),
-            iced_x86::Register::BX => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.rbx,
-                u16,
-                !0xffff
-            ),
-            iced_x86::Register::EBX => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.rbx,
-                u32,
-                !0xffffffff
-            ),
-            iced_x86::Register::RBX => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.rbx,
-                u64,
-                0x00
-            ),
-
-            iced_x86::Register::CL => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.rcx,
-                u8,
-                !0xff
-            ),
-            iced_x86::Register::CX => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.rcx,
-                u16,
-                !0xffff
-            ),
-            iced_x86::Register::ECX => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.rcx,
-                u32,
-                !0xffffffff
-            ),
-            iced_x86::Register::RCX => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.rdx,
-                u64,
-                0x00
-            ),
-
-            iced_x86::Register::DL => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.rdx,
-                u8,
-                !0xff
-            ),
-            iced_x86::Register::DX => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.rdx,
-                u16,
-                !0xffff
-            ),
-            iced_x86::Register::EDX => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.rdx,
-                u32,
-                !0xffffffff
-            ),
-            iced_x86::Register::RDX => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.rdx,
-                u64,
-                0x00
-            ),
-
-            iced_x86::Register::R8L => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.r8,
-                u8,
-                !0xff
-            ),
-            iced_x86::Register::R8W => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.r8,
-                u16,
-                !0xffff
-            ),
-            iced_x86::Register::R8D => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.r8,
-                u32,
-                !0xffffffff
-            ),
-            iced_x86::Register::R8 => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.r8,
-                u64,
-                0x00
-            ),
-
-            iced_x86::Register::R9L => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.r9,
-                u8,
-                !0xff
-            ),
-            iced_x86::Register::R9W => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.r9,
-                u16,
-                !0xffff
-            ),
-            iced_x86::Register::R9D => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.r9,
-                u32,
-                !0xffffffff
-            ),
-            iced_x86::Register::R9 => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.r9,
-                u64,
-                0x00
-            ),
-
-            iced_x86::Register::R10L => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.r10,
-                u8,
-                !0xff
-            ),
-            iced_x86::Register::R10W => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.r10,
-                u16,
-                !0xffff
-            ),
-            iced_x86::Register::R10D => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.r10,
-                u32,
-                !0xffffffff
-            ),
-            iced_x86::Register::R10 => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.r10,
-                u64,
-                0x00
-            ),
-
-            iced_x86::Register::R11L => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.r11,
-                u8,
-                !0xff
-            ),
-            iced_x86::Register::R11W => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.r11,
-                u16,
-                !0xffff
-            ),
-            iced_x86::Register::R11D => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.r11,
-                u32,
-                !0xffffffff
-            ),
-            iced_x86::Register::R11 => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.r11,
-                u64,
-                0x00
-            ),
-
-            iced_x86::Register::R12L => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.r12,
-                u8,
-                !0xff
-            ),
-            iced_x86::Register::R12W => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.r12,
-                u16,
-                !0xffff
-            ),
-            iced_x86::Register::R12D => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.r12,
-                u32,
-                !0xffffffff
-            ),
-            iced_x86::Register::R12 => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.r12,
-                u64,
-                0x00
-            ),
-
-            iced_x86::Register::R13L => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.r13,
-                u8,
-                !0xff
-            ),
-            iced_x86::Register::R13W => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.r13,
-                u16,
-                !0xffff
-            ),
-            iced_x86::Register::R13D => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.r13,
-                u32,
-                !0xffffffff
-            ),
-            iced_x86::Register::R13 => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.r13,
-                u64,
-                0x00
-            ),
-
-            iced_x86::Register::R14L => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.r14,
-                u8,
-                !0xff
-            ),
-            iced_x86::Register::R14W => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.r14,
-                u16,
-                !0xffff
-            ),
-            iced_x86::Register::R14D => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.r14,
-                u32,
-                !0xffffffff
-            ),
-            iced_x86::Register::R14 => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.r14,
-                u64,
-                0x00
-            ),
-
-            iced_x86::Register::R15L => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.r15,
-                u8,
-                !0xff
-            ),
-            iced_x86::Register::R15W => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.r15,
-                u16,
-                !0xffff
-            ),
-            iced_x86::Register::R15D => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.r15,
-                u32,
-                !0xffffffff
-            ),
-            iced_x86::Register::R15 => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.r15,
-                u64,
-                0x00
-            ),
-
-            iced_x86::Register::DIL => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.rdi,
-                u8,
-                !0xff
-            ),
-            iced_x86::Register::DI => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.rdi,
-                u16,
-                !0xffff
-            ),
-            iced_x86::Register::EDI => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.rdi,
-                u32,
-                !0xffffffff
-            ),
-            iced_x86::Register::RDI => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.rdi,
-                u64,
-                0x00
-            ),
-
-            iced_x86::Register::SIL => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.rsi,
-                u8,
-                !0xff
-            ),
-            iced_x86::Register::SI => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.rsi,
-                u16,
-                !0xffff
-            ),
-            iced_x86::Register::ESI => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.rsi,
-                u32,
-                !0xffffffff
-            ),
-            iced_x86::Register::RSI => write_register!(
-                vm,
-                vcpu,
-                addr,
-                responses,
-                guest_cpu.rsi,
-                u64,
-                0x00
-            ),
-
-            register => {
-                return Err(Error::InvalidValue(format!(
+    let (gpr, width) = match instr.op0_kind() {
+        iced_x86::OpKind::Register => {
+            let register = instr.op_register(0);
+            register_operand(register).ok_or_else(|| {
+                Error::InvalidValue(format!(
                     "mmio read into invalid register '{:?}'",
                     register
-                )))
-            }
-        },
+                ))
+            })?
+        }
         _ => return Err(Error::NotSupported),
     };
 
+    let mut buff = [0u8; 8];
+    let data = &mut buff[8 - width.bytes()..];
+    let request = MemReadRequest::new(data);
+    let mut vm = vcpu.vm.write();
+    vm.dispatch_event(
+        addr,
+        DeviceEvent::MemRead(addr, request),
+        vcpu,
+        responses,
+    )?;
+    guest_cpu.write_gpr(gpr, u64::from_be_bytes(buff), width);
     Ok(())
 }
 
@@ -758,7 +236,7 @@ pub fn handle_ept_violation(
 
     let efer = vcpu.vmcs.read_field(vmcs::VmcsField::GuestIa32Efer)?;
     // TODO: 16bit support
-    let mode = if efer & 0x00000100 != 0 { 64 } else { 32 };
+    let mode = if efer & msr::EFER_LMA != 0 { 64 } else { 32 };
 
     let mut decoder =
         iced_x86::Decoder::new(mode, &bytes, iced_x86::DecoderOptions::NONE);
@@ -781,10 +259,11 @@ pub fn handle_ept_violation(
     {
         do_mmio_read(addr, vcpu, guest_cpu, responses, instr)?;
     } else {
+        let vm = vcpu.vm.read();
         return Err(Error::InvalidValue(format!(
-            "Unsupported mmio instruction: {:?} (rip=0x{:x}, bytes={:?})",
+            "Unsupported mmio instruction: {:?} (rip={}, bytes={:?})",
             instr.code(),
-            ip,
+            vm.symbols.describe(ip),
             bytes,
         )));
     }
@@ -1,4 +1,55 @@
+use crate::vm;
+use core::fmt;
+
 pub mod controlreg;
 pub mod cpuid;
+pub mod hypercall;
+pub mod invalidate;
 pub mod memio;
+pub mod msr;
 pub mod portio;
+pub mod random;
+
+/// The `cpudiff` management console command
+///
+/// Reports the CPUID registers and MSRs that a VM sees differently from
+/// the host (because of its policies, or because they are emulated), to
+/// help debug guests that only fail when run under the hypervisor.
+pub fn cpudiff_command(out: &mut dyn fmt::Write, args: &[&str]) -> fmt::Result {
+    let vm_id = match args.get(0).and_then(|vm| vm::find_vm(vm)) {
+        Some(vm_id) => vm_id,
+        None => return writeln!(out, "usage: cpudiff <vm>"),
+    };
+    let vm = match unsafe { vm::get_vm(vm_id) } {
+        Some(vm) => vm,
+        None => return writeln!(out, "No VM {}", vm_id),
+    };
+    let vm = vm.read();
+
+    writeln!(out, "CPUID (leaf.subleaf register: host -> guest):")?;
+    for div in vm.cpuid.host_divergence() {
+        writeln!(
+            out,
+            "  0x{:x}.{} {}: 0x{:08x} -> 0x{:08x}",
+            div.leaf, div.subleaf, div.register, div.host, div.guest
+        )?;
+    }
+
+    writeln!(out, "MSRs:")?;
+    let divergence =
+        msr::divergence(vm.config.debugctl_policy(), vm.config.overhead_msrs());
+    for div in divergence {
+        if div.msrs.start() == div.msrs.end() {
+            writeln!(out, "  0x{:x}: {}", div.msrs.start(), div.description)?;
+        } else {
+            writeln!(
+                out,
+                "  0x{:x}-0x{:x}: {}",
+                div.msrs.start(),
+                div.msrs.end(),
+                div.description
+            )?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,372 @@
+//! Emulation of guest accesses to intercepted MSRs
+
+use crate::error::{Error, Result};
+use crate::memory::{GuestFault, Raw4kPage};
+use crate::{health, vcpu, vmcs, vmexit};
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+use x86::msr;
+
+/// System call extensions
+pub const EFER_SCE: u64 = 1 << 0;
+/// Long mode enable
+pub const EFER_LME: u64 = 1 << 8;
+/// Long mode active (read-only, set when paging is enabled with LME)
+pub const EFER_LMA: u64 = 1 << 10;
+/// No-execute enable
+pub const EFER_NXE: u64 = 1 << 11;
+
+const CR0_PG: u64 = 1 << 31;
+
+/// Record the most recent branches in the LBR stack
+pub const DEBUGCTL_LBR: u64 = 1 << 0;
+/// Single-step on branches (when RFLAGS.TF is set)
+pub const DEBUGCTL_BTF: u64 = 1 << 1;
+/// Freeze the LBR stack on a performance monitoring interrupt
+pub const DEBUGCTL_FREEZE_LBRS_ON_PMI: u64 = 1 << 11;
+
+// The DEBUGCTL bits a guest may set. The remaining features (branch trace
+// messages and stores, and the performance monitoring controls) depend on
+// facilities that are not virtualized, so setting them raises #GP.
+const DEBUGCTL_GUEST_BITS: u64 =
+    DEBUGCTL_LBR | DEBUGCTL_BTF | DEBUGCTL_FREEZE_LBRS_ON_PMI;
+
+const MSR_LBR_SELECT: u32 = 0x1c8;
+const MSR_LASTBRANCH_TOS: u32 = 0x1c9;
+const MSR_LER_FROM_LIP: u32 = 0x1dd;
+const MSR_LER_TO_LIP: u32 = 0x1de;
+
+/// A read-only synthetic MSR reporting the time (in nanoseconds) the vCPU
+/// has spent in the hypervisor handling exits (see `health`)
+pub const MSR_MYTHRIL_EXIT_TIME: u32 = 0x4d59_0000;
+
+/// A read-only synthetic MSR reporting the time (in nanoseconds) the vCPU
+/// has spent running the guest
+pub const MSR_MYTHRIL_GUEST_TIME: u32 = 0x4d59_0001;
+
+/// How a guest's IA32_DEBUGCTL and last branch records are virtualized
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DebugCtlPolicy {
+    /// Branch tracing is hidden from the guest. The LBR and BTF bits of
+    /// DEBUGCTL are ignored (and read as zero), and the LBR MSRs read as
+    /// zero and ignore writes.
+    Masked,
+    /// The guest DEBUGCTL is loaded on every VM entry and the LBR MSRs are
+    /// accessed directly. DEBUGCTL is cleared on VM exit, so the LBR stack
+    /// holds only guest branches. It is not saved when another guest runs
+    /// on the same core.
+    Passthrough,
+}
+
+impl Default for DebugCtlPolicy {
+    fn default() -> Self {
+        DebugCtlPolicy::Masked
+    }
+}
+
+// The MSRs of the LBR stack. The depth of the stack (and which of the
+// ranges exist) varies by microarchitecture, so this covers all of them.
+fn is_lbr_msr(msr: u32) -> bool {
+    match msr {
+        MSR_LBR_SELECT | MSR_LASTBRANCH_TOS => true,
+        MSR_LER_FROM_LIP | MSR_LER_TO_LIP => true,
+        // The FROM_IP and TO_IP stacks of Core 2 and Atom processors
+        0x40..=0x47 | 0x60..=0x67 => true,
+        // The FROM_IP, TO_IP and LBR_INFO stacks of later processors
+        0x680..=0x69f | 0x6c0..=0x6df | 0xdc0..=0xddf => true,
+        _ => false,
+    }
+}
+
+// The offset and bit of `msr` in the read half of an MSR bitmap (see
+// Section 24.6.9 of the SDM). The write bitmaps follow 2048 bytes later.
+fn bitmap_position(msr: u32) -> Option<(usize, u8)> {
+    let (base, index) = match msr {
+        0..=0x1fff => (0, msr),
+        0xc0000000..=0xc0001fff => (1024, msr - 0xc0000000),
+        _ => return None,
+    };
+    Some((base + index as usize / 8, 1 << (index % 8)))
+}
+
+/// Cause guest reads of `msr` to exit
+pub fn intercept_read(bitmap: &mut Raw4kPage, msr: u32) {
+    if let Some((offset, bit)) = bitmap_position(msr) {
+        bitmap.0[offset] |= bit;
+    }
+}
+
+/// Cause guest writes to `msr` to exit
+pub fn intercept_write(bitmap: &mut Raw4kPage, msr: u32) {
+    if let Some((offset, bit)) = bitmap_position(msr) {
+        bitmap.0[offset + 2048] |= bit;
+    }
+}
+
+/// Set the MSR intercepts required by `policy`
+pub fn intercept_debugctl(bitmap: &mut Raw4kPage, policy: DebugCtlPolicy) {
+    // Guest reads of DEBUGCTL return the value loaded on VM entry, so
+    // only writes must be validated
+    intercept_write(bitmap, msr::IA32_DEBUGCTL);
+
+    if policy == DebugCtlPolicy::Masked {
+        for msr in (0x40..0x68)
+            .chain(0x1c8..0x1df)
+            .chain(0x680..0x6e0)
+            .chain(0xdc0..0xde0)
+            .filter(|msr| is_lbr_msr(*msr))
+        {
+            intercept_read(bitmap, msr);
+            intercept_write(bitmap, msr);
+        }
+    }
+}
+
+// Emulate a write to IA32_DEBUGCTL (see Section 17.4.1 of the SDM)
+fn write_debugctl(vcpu: &mut vcpu::VCpu, value: u64) -> Result<()> {
+    if value & !DEBUGCTL_GUEST_BITS != 0 {
+        return Err(GuestFault::GeneralProtection(0).into());
+    }
+    let value = match vcpu.vm.read().config.debugctl_policy() {
+        DebugCtlPolicy::Masked => 0,
+        DebugCtlPolicy::Passthrough => value,
+    };
+    vcpu.vmcs
+        .write_field(vmcs::VmcsField::GuestIa32Debugctl, value)
+}
+
+// Emulate a write to IA32_EFER (see Section 2.2.1 and 9.8.5 of the SDM)
+fn write_efer(vcpu: &mut vcpu::VCpu, value: u64) -> Result<()> {
+    if value & !(EFER_SCE | EFER_LME | EFER_LMA | EFER_NXE) != 0 {
+        return Err(GuestFault::GeneralProtection(0).into());
+    }
+
+    let efer = vcpu.vmcs.read_field(vmcs::VmcsField::GuestIa32Efer)?;
+    let cr0 = vcpu.vmcs.read_field(vmcs::VmcsField::GuestCr0)?;
+
+    // Long mode cannot be enabled or disabled while paging is enabled
+    if cr0 & CR0_PG != 0 && (efer ^ value) & EFER_LME != 0 {
+        return Err(GuestFault::GeneralProtection(0).into());
+    }
+
+    // LMA is maintained by the processor (see `update_long_mode`)
+    let efer = (value & !EFER_LMA) | (efer & EFER_LMA);
+    vcpu.vmcs.write_field(vmcs::VmcsField::GuestIa32Efer, efer)
+}
+
+/// Activate or deactivate long mode as the guest changes CR0.PG
+///
+/// When paging is enabled with EFER.LME set, the processor sets EFER.LMA
+/// and the guest enters IA-32e mode, so the "IA-32e mode guest" VM-entry
+/// control must be updated to match.
+pub fn update_long_mode(
+    vcpu: &mut vcpu::VCpu,
+    old_cr0: u64,
+    new_cr0: u64,
+    cr4: u64,
+) -> Result<()> {
+    if (old_cr0 ^ new_cr0) & CR0_PG == 0 {
+        return Ok(());
+    }
+
+    let efer = vcpu.vmcs.read_field(vmcs::VmcsField::GuestIa32Efer)?;
+    if efer & EFER_LME == 0 {
+        return Ok(());
+    }
+
+    let entry = vcpu.vmcs.read_field(vmcs::VmcsField::VmEntryControls)?;
+    let ia32e = vmcs::VmEntryCtrlFlags::IA32E_MODE.bits();
+    let (efer, entry) = if new_cr0 & CR0_PG != 0 {
+        // Long mode requires PAE paging
+        if cr4 & (1 << 5) == 0 {
+            return Err(GuestFault::GeneralProtection(0).into());
+        }
+        (efer | EFER_LMA, entry | ia32e)
+    } else {
+        (efer & !EFER_LMA, entry & !ia32e)
+    };
+
+    vcpu.vmcs
+        .write_field(vmcs::VmcsField::GuestIa32Efer, efer)?;
+    vcpu.vmcs
+        .write_field(vmcs::VmcsField::VmEntryControls, entry)
+}
+
+// Read the synthetic exit accounting MSRs. These do not exist (and so
+// raise #GP) unless they are enabled for the VM.
+fn read_accounting_msr(vcpu: &vcpu::VCpu, msr: u32) -> Result<u64> {
+    if !vcpu.vm.read().config.overhead_msrs() {
+        return Err(GuestFault::GeneralProtection(0).into());
+    }
+    let accounting =
+        health::current_accounting().ok_or(GuestFault::GeneralProtection(0))?;
+    let time = if msr == MSR_MYTHRIL_EXIT_TIME {
+        accounting.exit_time
+    } else {
+        accounting.guest_time
+    };
+    Ok(time.as_nanos() as u64)
+}
+
+/// MSRs that a guest sees differently from the host
+#[derive(Clone, Debug, PartialEq)]
+pub struct MsrDivergence {
+    pub msrs: RangeInclusive<u32>,
+    pub description: &'static str,
+}
+
+/// The MSRs a guest sees differently from the host, given the policies of
+/// its VM
+pub fn divergence(
+    debugctl_policy: DebugCtlPolicy,
+    overhead_msrs: bool,
+) -> Vec<MsrDivergence> {
+    let diverges = |msrs, description| MsrDivergence { msrs, description };
+    let mut divergence = vec![
+        diverges(
+            msr::IA32_APIC_BASE..=msr::IA32_APIC_BASE,
+            "the x2APIC enable bit reads as clear",
+        ),
+        diverges(
+            msr::IA32_EFER..=msr::IA32_EFER,
+            "writes of bits other than SCE, LME, LMA and NXE raise #GP",
+        ),
+        diverges(
+            msr::IA32_DEBUGCTL..=msr::IA32_DEBUGCTL,
+            match debugctl_policy {
+                DebugCtlPolicy::Masked => {
+                    "reads as zero, and only LBR, BTF and FREEZE_LBRS_ON_PMI \
+                     may be written"
+                }
+                DebugCtlPolicy::Passthrough => {
+                    "only LBR, BTF and FREEZE_LBRS_ON_PMI may be written, \
+                     and it is cleared while the hypervisor runs"
+                }
+            },
+        ),
+    ];
+    if debugctl_policy == DebugCtlPolicy::Masked {
+        for msrs in [
+            0x40..=0x47,
+            0x60..=0x67,
+            MSR_LBR_SELECT..=MSR_LASTBRANCH_TOS,
+            MSR_LER_FROM_LIP..=MSR_LER_TO_LIP,
+            0x680..=0x69f,
+            0x6c0..=0x6df,
+            0xdc0..=0xddf,
+        ]
+        .iter()
+        {
+            divergence.push(diverges(
+                msrs.clone(),
+                "last branch records read as zero and ignore writes",
+            ));
+        }
+    }
+    if overhead_msrs {
+        divergence.push(diverges(
+            MSR_MYTHRIL_EXIT_TIME..=MSR_MYTHRIL_GUEST_TIME,
+            "synthetic exit accounting MSRs (read-only)",
+        ));
+    }
+    // MSRs outside of the bitmap ranges always exit, and only the
+    // synthetic MSRs are emulated
+    for msrs in [
+        0x2000..=MSR_MYTHRIL_EXIT_TIME - 1,
+        MSR_MYTHRIL_GUEST_TIME + 1..=0xbfff_ffff,
+        0xc000_2000..=0xffff_ffff,
+    ]
+    .iter()
+    {
+        divergence.push(diverges(
+            msrs.clone(),
+            "accesses exit, and are not supported by the hypervisor",
+        ));
+    }
+    divergence
+}
+
+pub fn emulate_rdmsr(
+    vcpu: &mut vcpu::VCpu,
+    guest_cpu: &mut vmexit::GuestCpuState,
+) -> Result<()> {
+    let value = match guest_cpu.rcx as u32 {
+        msr if is_lbr_msr(msr) => 0,
+        msr @ MSR_MYTHRIL_EXIT_TIME | msr @ MSR_MYTHRIL_GUEST_TIME => {
+            read_accounting_msr(vcpu, msr)?
+        }
+        msr => {
+            return Err(Error::InvalidValue(format!(
+                "Unsupported read of MSR 0x{:x}",
+                msr
+            )))
+        }
+    };
+    guest_cpu.write_msr_result(value);
+    Ok(())
+}
+
+pub fn emulate_wrmsr(
+    vcpu: &mut vcpu::VCpu,
+    guest_cpu: &mut vmexit::GuestCpuState,
+) -> Result<()> {
+    let value = (guest_cpu.rdx << 32) | (guest_cpu.rax & 0xffffffff);
+    match guest_cpu.rcx as u32 {
+        msr::IA32_EFER => write_efer(vcpu, value),
+        msr::IA32_DEBUGCTL => write_debugctl(vcpu, value),
+        msr if is_lbr_msr(msr) => Ok(()),
+        MSR_MYTHRIL_EXIT_TIME | MSR_MYTHRIL_GUEST_TIME => {
+            Err(GuestFault::GeneralProtection(0).into())
+        }
+        msr => Err(Error::InvalidValue(format!(
+            "Unsupported write of 0x{:x} to MSR 0x{:x}",
+            value, msr
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_intercepts() {
+        let mut bitmap = Raw4kPage::default();
+        intercept_write(&mut bitmap, msr::IA32_EFER);
+        assert_eq!(bitmap.0[3072 + 0x10], 1 << 0);
+        intercept_read(&mut bitmap, msr::IA32_APIC_BASE);
+        assert_eq!(bitmap.0[3], 1 << 3);
+
+        // MSRs outside of the bitmap ranges always exit
+        intercept_read(&mut bitmap, 0x4000_0000);
+        assert_eq!(bitmap.0.iter().filter(|b| **b != 0).count(), 2);
+    }
+
+    #[test]
+    fn test_divergence() {
+        let masked = divergence(DebugCtlPolicy::Masked, false);
+        let passthrough = divergence(DebugCtlPolicy::Passthrough, true);
+        let covers = |divergence: &[MsrDivergence], msr: u32| {
+            divergence.iter().any(|div| div.msrs.contains(&msr))
+        };
+        assert!(covers(&masked, 0x1c9));
+        assert!(!covers(&passthrough, 0x1c9));
+        assert!(!covers(&masked, MSR_MYTHRIL_EXIT_TIME));
+        assert!(covers(&passthrough, MSR_MYTHRIL_EXIT_TIME));
+        assert!(covers(&masked, 0x4b56_4d00));
+        assert!(!covers(&masked, 0x10));
+    }
+
+    #[test]
+    fn test_intercept_debugctl() {
+        let mut bitmap = Raw4kPage::default();
+        intercept_debugctl(&mut bitmap, DebugCtlPolicy::Passthrough);
+        assert_eq!(bitmap.0.iter().filter(|b| **b != 0).count(), 1);
+
+        let mut bitmap = Raw4kPage::default();
+        intercept_debugctl(&mut bitmap, DebugCtlPolicy::Masked);
+        assert_eq!(bitmap.0[0x1c9 / 8] & (1 << (0x1c9 % 8)), 1 << 1);
+        assert_eq!(bitmap.0[2048 + 0x680 / 8], 0xff);
+        assert_eq!(bitmap.0[0x1da / 8] & (1 << (0x1da % 8)), 0);
+    }
+}
@@ -1,18 +1,76 @@
 use crate::error::Result;
 use crate::memory;
 use crate::virtdev::{
-    DeviceEvent, Port, PortReadRequest, PortWriteRequest, ResponseEventArray,
+    DeviceEvent, Port, PortIoValue, PortReadRequest, PortWriteRequest,
+    ResponseEventArray,
 };
-use crate::{vcpu, vmcs, vmexit};
+use crate::{declare_per_core, get_per_core_mut, vcpu, vmcs, vmexit};
 use core::convert::TryFrom;
 
+/// The size of the buffer used to move string port IO data to or from the
+/// guest. A repeated string instruction moves at most this much per exit,
+/// with its registers updated so the guest executes it again for the rest
+/// (and can take interrupts in between).
+const STRING_IO_BUFFER_SIZE: usize = 1024;
+
+declare_per_core! {
+    static mut STRING_IO_BUFFER: [u8; STRING_IO_BUFFER_SIZE] =
+        [0; STRING_IO_BUFFER_SIZE];
+}
+
+/// The number of iterations performed by a string instruction
+fn string_io_count(
+    guest_cpu: &vmexit::GuestCpuState,
+    exit: &vmexit::IoInstructionInformation,
+) -> u64 {
+    if exit.rep {
+        guest_cpu.rcx
+    } else {
+        1
+    }
+}
+
+/// Verify that the guest buffer used by the next chunk of a string
+/// instruction is valid before performing any port accesses
+fn validate_string_io(
+    vcpu: &vcpu::VCpu,
+    space: &mut memory::GuestAddressSpace,
+    addr: memory::GuestVirtAddr,
+    length: usize,
+    access: memory::GuestAccess,
+) -> Result<()> {
+    let view = memory::GuestAddressSpaceViewMut::from_vmcs(&vcpu.vmcs, space)?;
+    view.validate_range(addr, length, access)
+}
+
+/// The number of bytes of the bounce buffer to use for the next chunk
+/// when `remaining` iterations are left
+fn string_io_chunk_len(remaining: u64, size: u8) -> usize {
+    let per_chunk = (STRING_IO_BUFFER_SIZE / size as usize) as u64;
+    (core::cmp::min(remaining, per_chunk) * size as u64) as usize
+}
+
+/// Account for a chunk of `len` bytes moved by a string instruction,
+/// returning true if the instruction is complete
+fn string_io_advance(
+    guest_cpu: &mut vmexit::GuestCpuState,
+    exit: &vmexit::IoInstructionInformation,
+    len: usize,
+) -> bool {
+    if !exit.rep {
+        return true;
+    }
+    guest_cpu.rcx -= (len / exit.size as usize) as u64;
+    guest_cpu.rcx == 0
+}
+
 fn emulate_outs(
     vcpu: &mut vcpu::VCpu,
     port: Port,
     guest_cpu: &mut vmexit::GuestCpuState,
     exit: vmexit::IoInstructionInformation,
     responses: &mut ResponseEventArray,
-) -> Result<()> {
+) -> Result<bool> {
     let mut vm = vcpu.vm.write();
 
     let linear_addr =
@@ -23,33 +81,36 @@ fn emulate_outs(
     //        assume that is requires supervisor
     let access = memory::GuestAccess::Read(memory::PrivilegeLevel(0));
 
-    let view = memory::GuestAddressSpaceViewMut::from_vmcs(
-        &vcpu.vmcs,
-        &mut vm.guest_space,
-    )?;
+    let buffer = get_per_core_mut!(STRING_IO_BUFFER);
 
     // FIXME: The direction we read is determined by the DF flag (I think)
     // FIXME: We should probably only be using some of the lower order bits
-    let bytes = view.read_bytes(
-        guest_addr,
-        (guest_cpu.rcx * exit.size as u64) as usize,
-        access,
-    )?;
+    let remaining = string_io_count(guest_cpu, &exit);
+    if remaining == 0 {
+        return Ok(true);
+    }
+    let len = string_io_chunk_len(remaining, exit.size);
+    validate_string_io(vcpu, &mut vm.guest_space, guest_addr, len, access)?;
 
-    // FIXME: Actually test for REP
-    for chunk in bytes.chunks_exact(exit.size as usize) {
-        let request = PortWriteRequest::try_from(chunk)?;
-        vm.dispatch_event(
-            port,
-            DeviceEvent::PortWrite(port, request),
-            vcpu,
-            responses,
+    let bytes = &mut buffer[..len];
+    {
+        let view = memory::GuestAddressSpaceViewMut::from_vmcs(
+            &vcpu.vmcs,
+            &mut vm.guest_space,
         )?;
+        view.read_bytes_into(guest_addr, bytes, access)?;
     }
 
-    guest_cpu.rsi += bytes.len() as u64;
-    guest_cpu.rcx = 0;
-    Ok(())
+    vm.dispatch_port_write_bulk(
+        port,
+        bytes,
+        exit.size as usize,
+        vcpu,
+        responses,
+    )?;
+
+    guest_cpu.rsi += len as u64;
+    Ok(string_io_advance(guest_cpu, &exit, len))
 }
 
 fn emulate_ins(
@@ -58,15 +119,26 @@ fn emulate_ins(
     guest_cpu: &mut vmexit::GuestCpuState,
     exit: vmexit::IoInstructionInformation,
     responses: &mut ResponseEventArray,
-) -> Result<()> {
+) -> Result<bool> {
     let mut vm = vcpu.vm.write();
 
     let linear_addr =
         vcpu.vmcs.read_field(vmcs::VmcsField::GuestLinearAddress)?;
     let guest_addr = memory::GuestVirtAddr::new(linear_addr, &vcpu.vmcs)?;
-    let access = memory::GuestAccess::Read(memory::PrivilegeLevel(0));
 
-    let mut bytes = vec![0u8; guest_cpu.rcx as usize];
+    // INS stores to the guest buffer, so it must be writable
+    let access = memory::GuestAccess::Write(memory::PrivilegeLevel(0));
+
+    let buffer = get_per_core_mut!(STRING_IO_BUFFER);
+
+    let remaining = string_io_count(guest_cpu, &exit);
+    if remaining == 0 {
+        return Ok(true);
+    }
+    let len = string_io_chunk_len(remaining, exit.size);
+    validate_string_io(vcpu, &mut vm.guest_space, guest_addr, len, access)?;
+
+    let bytes = &mut buffer[..len];
     for chunk in bytes.chunks_exact_mut(exit.size as usize) {
         let request = PortReadRequest::try_from(chunk)?;
         vm.dispatch_event(
@@ -77,58 +149,65 @@ fn emulate_ins(
         )?;
     }
 
-    let mut view = memory::GuestAddressSpaceViewMut::from_vmcs(
-        &vcpu.vmcs,
-        &mut vm.guest_space,
-    )?;
-    view.write_bytes(guest_addr, &bytes, access)?;
+    {
+        let mut view = memory::GuestAddressSpaceViewMut::from_vmcs(
+            &vcpu.vmcs,
+            &mut vm.guest_space,
+        )?;
+        view.write_bytes(guest_addr, bytes, access)?;
+    }
 
-    guest_cpu.rdi += bytes.len() as u64;
-    guest_cpu.rcx = 0;
-    Ok(())
+    guest_cpu.rdi += len as u64;
+    Ok(string_io_advance(guest_cpu, &exit, len))
 }
 
+/// Emulate an IN, OUT, INS or OUTS instruction
+///
+/// Returns true if the instruction is complete, or false if a repeated
+/// string instruction has iterations left, in which case the guest must
+/// execute it again (without advancing RIP).
 pub fn emulate_portio(
     vcpu: &mut vcpu::VCpu,
     guest_cpu: &mut vmexit::GuestCpuState,
     exit: vmexit::IoInstructionInformation,
     responses: &mut ResponseEventArray,
-) -> Result<()> {
+) -> Result<bool> {
     let (port, input, size, string) =
         (exit.port, exit.input, exit.size, exit.string);
 
-    if !string {
-        let mut vm = vcpu.vm.write();
-        if !input {
-            let arr = (guest_cpu.rax as u32).to_be_bytes();
-            let request =
-                PortWriteRequest::try_from(&arr[4 - size as usize..])?;
-            vm.dispatch_event(
-                port,
-                DeviceEvent::PortWrite(port, request),
-                vcpu,
-                responses,
-            )?;
+    if string {
+        return if !input {
+            emulate_outs(vcpu, port, guest_cpu, exit, responses)
         } else {
-            let mut arr = [0u8; 4];
-            let request =
-                PortReadRequest::try_from(&mut arr[4 - size as usize..])?;
-            vm.dispatch_event(
-                port,
-                DeviceEvent::PortRead(port, request),
-                vcpu,
-                responses,
-            )?;
-            guest_cpu.rax &= (!guest_cpu.rax) << (size * 8);
-            guest_cpu.rax |= u32::from_be_bytes(arr) as u64;
+            emulate_ins(vcpu, port, guest_cpu, exit, responses)
         };
-    } else {
-        if !input {
-            emulate_outs(vcpu, port, guest_cpu, exit, responses)?;
-        } else {
-            emulate_ins(vcpu, port, guest_cpu, exit, responses)?;
-        }
     }
 
-    Ok(())
+    let mut vm = vcpu.vm.write();
+    let width = vmexit::OperandWidth::from_bytes(size as usize)?;
+    if !input {
+        let value = PortIoValue::from_sized(
+            guest_cpu.read_gpr(vmexit::Gpr::Rax, width) as u32,
+            size as usize,
+        )?;
+        let request = PortWriteRequest::try_from(value.as_bytes())?;
+        vm.dispatch_event(
+            port,
+            DeviceEvent::PortWrite(port, request),
+            vcpu,
+            responses,
+        )?;
+    } else {
+        let mut value = PortIoValue::from_sized(0, size as usize)?;
+        let request = PortReadRequest::try_from(value.as_bytes_mut())?;
+        vm.dispatch_event(
+            port,
+            DeviceEvent::PortRead(port, request),
+            vcpu,
+            responses,
+        )?;
+        guest_cpu.write_gpr(vmexit::Gpr::Rax, value.as_u32() as u64, width);
+    };
+
+    Ok(true)
 }
@@ -0,0 +1,91 @@
+//! Emulation of the RDRAND and RDSEED instructions
+//!
+//! Where the processor can intercept them, guest RDRAND and RDSEED are
+//! served from a DRBG of each core, which is seeded (and periodically
+//! reseeded) from the hypervisor's generator (see `rng`). A guest kernel
+//! randomizing its own layout (e.g., Linux KASLR) then draws on the
+//! hypervisor's entropy, rather than on the hardware generator alone.
+//!
+//! The instructions always succeed, so RDSEED returns DRBG output rather
+//! than full entropy.
+
+use crate::error::Result;
+use crate::rng::{self, HmacDrbg, RESEED_INTERVAL};
+use crate::{declare_per_core, get_per_core_mut};
+use crate::{vcpu, vmcs, vmexit};
+
+const SEED_SIZE: usize = 48;
+
+// The arithmetic flags (CF, PF, AF, ZF, SF and OF)
+const RFLAGS_CF: u64 = 1 << 0;
+const RFLAGS_ARITHMETIC: u64 =
+    RFLAGS_CF | 1 << 2 | 1 << 4 | 1 << 6 | 1 << 7 | 1 << 11;
+
+declare_per_core! {
+    static mut GUEST_DRBG: Option<HmacDrbg> = None;
+}
+
+fn generate(out: &mut [u8]) -> Result<()> {
+    let mut seed = [0u8; SEED_SIZE];
+    let slot = get_per_core_mut!(GUEST_DRBG);
+    if slot.is_none() {
+        rng::fill_bytes(&mut seed)?;
+        *slot = Some(HmacDrbg::new(&seed));
+    }
+    if let Some(drbg) = slot.as_mut() {
+        if drbg.requests() >= RESEED_INTERVAL {
+            rng::fill_bytes(&mut seed)?;
+            drbg.reseed(&seed);
+        }
+        drbg.generate(out)?;
+    }
+    Ok(())
+}
+
+// The value of `register` after the low `width` bytes are replaced with
+// `value`
+fn merge(register: u64, value: u64, width: vmexit::OperandWidth) -> u64 {
+    match width {
+        vmexit::OperandWidth::Qword => value,
+        vmexit::OperandWidth::Dword => value & 0xffff_ffff,
+        _ => (register & !0xffff) | (value & 0xffff),
+    }
+}
+
+/// Emulate an RDRAND or RDSEED, whose destination is described by `info`
+pub fn emulate_random(
+    vcpu: &mut vcpu::VCpu,
+    guest_cpu: &mut vmexit::GuestCpuState,
+    info: vmexit::RandomInformation,
+) -> Result<()> {
+    let mut bytes = [0u8; 8];
+    generate(&mut bytes)?;
+
+    let old = info.register.read(&vcpu.vmcs, guest_cpu)?;
+    let value = merge(old, u64::from_le_bytes(bytes), info.width);
+    info.register.write(value, &mut vcpu.vmcs, guest_cpu)?;
+
+    // A valid value is reported with CF set (and the other flags clear)
+    let rflags = vcpu.vmcs.read_field(vmcs::VmcsField::GuestRflags)?;
+    vcpu.vmcs.write_field(
+        vmcs::VmcsField::GuestRflags,
+        (rflags & !RFLAGS_ARITHMETIC) | RFLAGS_CF,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_merge_width() {
+        let register = 0x1122_3344_5566_7788;
+        let value = 0xaabb_ccdd_eeff_0011;
+        let width = vmexit::OperandWidth::Word;
+        assert_eq!(merge(register, value, width), 0x1122_3344_5566_0011);
+        let width = vmexit::OperandWidth::Dword;
+        assert_eq!(merge(register, value, width), 0xeeff_0011);
+        let width = vmexit::OperandWidth::Qword;
+        assert_eq!(merge(register, value, width), value);
+    }
+}
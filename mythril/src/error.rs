@@ -1,12 +1,15 @@
+use crate::crash;
+use crate::memory::GuestFault;
 use crate::vmcs;
 use alloc::string::String;
 use core::convert::TryFrom;
+use core::fmt;
 use num_enum::{TryFromPrimitive, TryFromPrimitiveError};
 use x86::bits64::rflags;
 use x86::bits64::rflags::RFlags;
 
 // See Section 30.4
-#[derive(Debug, PartialEq, TryFromPrimitive)]
+#[derive(Clone, Copy, Debug, PartialEq, TryFromPrimitive)]
 #[repr(u64)]
 pub enum VmInstructionError {
     // Use to represent any error that is not in the current spec
@@ -42,11 +45,90 @@ pub enum VmInstructionError {
     InvalidOperandToInveptInvvpid = 28,
 }
 
+impl VmInstructionError {
+    /// The description of this error from the SDM (Table 30-1)
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::UnknownError => "unknown VM-instruction error",
+            Self::VmCallInRoot => "VMCALL executed in VMX root operation",
+            Self::VmClearInvalidAddress => {
+                "VMCLEAR with invalid physical address"
+            }
+            Self::VmClearWithVmxOnPtr => "VMCLEAR with VMXON pointer",
+            Self::VmLaunchNonClear => "VMLAUNCH with non-clear VMCS",
+            Self::VmResumeNonLaunched => "VMRESUME with non-launched VMCS",
+            Self::VmResumeAfterVmxOff => "VMRESUME after VMXOFF",
+            Self::VmEntryWithInvalidCtrlFields => {
+                "VM entry with invalid control field(s)"
+            }
+            Self::VmEntryWithInvalidHostFields => {
+                "VM entry with invalid host-state field(s)"
+            }
+            Self::VmPtrLdWithInvalidPhysAddr => {
+                "VMPTRLD with invalid physical address"
+            }
+            Self::VmPtrLdWithVmxOnPtr => "VMPTRLD with VMXON pointer",
+            Self::VmPtrLdWithWrongVmcsRevision => {
+                "VMPTRLD with incorrect VMCS revision identifier"
+            }
+            Self::VmReadWriteToUnsupportedField => {
+                "VMREAD/VMWRITE from/to unsupported VMCS component"
+            }
+            Self::VmWriteToReadOnly => "VMWRITE to read-only VMCS component",
+            Self::VmxOnInRootMode => "VMXON executed in VMX root operation",
+            Self::VmEntryWithInvalidExecVmcsPtr => {
+                "VM entry with invalid executive-VMCS pointer"
+            }
+            Self::VmEntryWithNonLaunchExecVmcs => {
+                "VM entry with non-launched executive VMCS"
+            }
+            Self::VmEntryWithExecVmcsPtr => {
+                "VM entry with executive-VMCS pointer not VMXON pointer"
+            }
+            Self::VmCallWithNonClearVmcs => {
+                "VMCALL with non-clear VMCS (when attempting to activate \
+                 the dual-monitor treatment of SMIs and SMM)"
+            }
+            Self::VmCallWithInvalidVmExitFields => {
+                "VMCALL with invalid VM-exit control fields"
+            }
+            Self::VmCallWithIncorrectMsegRev => {
+                "VMCALL with incorrect MSEG revision identifier"
+            }
+            Self::VmxOffUnderDualMonitor => {
+                "VMXOFF under dual-monitor treatment of SMIs and SMM"
+            }
+            Self::VmCallWithInvalidSmmFeatures => {
+                "VMCALL with invalid SMM-monitor features"
+            }
+            Self::VmEntryWithInvalidVmExecFields => {
+                "VM entry with invalid VM-execution control fields in \
+                 executive VMCS"
+            }
+            Self::VmEntryWithEventsBlockedMovSs => {
+                "VM entry with events blocked by MOV SS"
+            }
+            Self::InvalidOperandToInveptInvvpid => {
+                "invalid operand to INVEPT/INVVPID"
+            }
+        }
+    }
+}
+
+impl fmt::Display for VmInstructionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (error {})", self.description(), *self as u64)
+    }
+}
+
 pub fn check_vm_insruction(rflags: u64, error: String) -> Result<()> {
     let rflags = rflags::RFlags::from_bits_truncate(rflags);
 
     if rflags.contains(RFlags::FLAGS_CF) {
-        Err(Error::VmFailInvalid(error))
+        Err(Error::VmFailInvalid(format!(
+            "{}: VMfailInvalid (there is no current VMCS)",
+            error
+        )))
     } else if rflags.contains(RFlags::FLAGS_ZF) {
         let errno = unsafe {
             let value: u64;
@@ -59,6 +141,11 @@ pub fn check_vm_insruction(rflags: u64, error: String) -> Result<()> {
         };
         let vm_error = VmInstructionError::try_from(errno)
             .unwrap_or(VmInstructionError::UnknownError);
+        let error = if vm_error == VmInstructionError::UnknownError {
+            format!("{}: unknown VM-instruction error {}", error, errno)
+        } else {
+            format!("{}: {}", error, vm_error)
+        };
 
         Err(Error::VmFailValid((vm_error, error)))
     } else {
@@ -83,6 +170,9 @@ pub enum Error {
     InvalidDevice(String),
     NotImplemented(String),
     DeviceError(String),
+    /// The guest performed an operation that must be reflected back to it
+    /// as an exception, rather than treated as a hypervisor failure
+    GuestFault(GuestFault),
 }
 
 impl<T: TryFromPrimitive> From<TryFromPrimitiveError<T>> for Error {
@@ -100,6 +190,8 @@ fn eh_personality() {}
 #[panic_handler]
 #[cfg(not(test))]
 fn panic_handler(info: &core::panic::PanicInfo) -> ! {
+    // Only the first core to panic reports the crash
+    let reporting = crash::claim();
     if let Some(location) = info.location() {
         error!(
             "Panic in {} at ({}, {}):",
@@ -112,12 +204,11 @@ fn panic_handler(info: &core::panic::PanicInfo) -> ! {
         }
     }
 
-    loop {
-        unsafe {
-            // Try to at least keep CPU from running at 100%
-            llvm_asm!("hlt" :::: "volatile");
-        }
+    if !reporting {
+        crash::park(None);
     }
+    crash::broadcast();
+    crash::report()
 }
 
 #[alloc_error_handler]
@@ -128,3 +219,18 @@ fn out_of_memory(layout: ::core::alloc::Layout) -> ! {
         layout
     );
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_vm_instruction_error_display() {
+        let error = VmInstructionError::try_from(7).unwrap();
+        assert_eq!(error, VmInstructionError::VmEntryWithInvalidCtrlFields);
+        assert_eq!(
+            format!("{}", error),
+            "VM entry with invalid control field(s) (error 7)"
+        );
+    }
+}
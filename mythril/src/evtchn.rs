@@ -0,0 +1,295 @@
+//! Lightweight notifications between the hypervisor and guests
+//!
+//! Event channels let paravirtual devices (and other VMs) signal a guest
+//! without emulating a full interrupt controller. The guest registers a
+//! page of its memory holding a bitmap of pending channels and a bitmap of
+//! masked channels (see `HC_MYTHRIL_EVTCHN_SETUP`), along with the single
+//! 'upcall' vector used for all channels. It then binds a channel to each
+//! source of notifications it is interested in.
+//!
+//! Signaling a channel sets its pending bit, and injects the upcall vector
+//! if the bit was clear and the channel is not masked. The guest's handler
+//! scans for pending, unmasked channels and clears their bits (atomically,
+//! as the hypervisor may set bits at any time).
+//!
+//! Notifications for a VM are always delivered by its own core: other
+//! cores (and other VMs) send a `VirtualMachineMsg::EventChannel` message,
+//! while devices of the VM return a `DeviceEventResponse::EventChannel`.
+
+use crate::error::{Error, Result};
+use crate::memory::{GuestAddressSpace, GuestPhysAddr, HostPhysFrame};
+use crate::vm;
+use alloc::collections::btree_map::BTreeMap;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// The number of event channels of each VM
+pub const MAX_EVENT_CHANNELS: u16 = 256;
+
+/// The offset of the pending bitmap in the shared page
+pub const PENDING_OFFSET: usize = 0;
+
+/// The offset of the mask bitmap in the shared page
+pub const MASK_OFFSET: usize = MAX_EVENT_CHANNELS as usize / 8;
+
+// The first vector that is not reserved for exceptions
+const MIN_UPCALL_VECTOR: u8 = 32;
+
+/// An event channel (channel 0 is never bound, so it can be used by guests
+/// as an invalid channel)
+pub type Channel = u16;
+
+/// The origin of the notifications on an event channel
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventSource {
+    /// A paravirtual device of the hypervisor, by its device number
+    Device(u32),
+
+    /// Another VM, by its id
+    Vm(u32),
+}
+
+impl EventSource {
+    /// The source with the kind and id given to `HC_MYTHRIL_EVTCHN_BIND`
+    pub fn from_raw(kind: u64, id: u64) -> Result<Self> {
+        let id = if id <= u32::MAX as u64 {
+            id as u32
+        } else {
+            return Err(Error::InvalidValue(format!(
+                "Invalid event source id: {}",
+                id
+            )));
+        };
+        match kind {
+            0 => Ok(EventSource::Device(id)),
+            1 => Ok(EventSource::Vm(id)),
+            _ => Err(Error::InvalidValue(format!(
+                "Invalid event source kind: {}",
+                kind
+            ))),
+        }
+    }
+}
+
+/// The event channels of a VM
+#[derive(Debug, Default)]
+pub struct EventChannels {
+    page: Option<GuestPhysAddr>,
+    vector: u8,
+    bindings: BTreeMap<Channel, EventSource>,
+}
+
+impl EventChannels {
+    /// Use the bitmaps in the guest page at `page`, and deliver
+    /// notifications with `vector`
+    pub fn setup(
+        &mut self,
+        space: &GuestAddressSpace,
+        page: u64,
+        vector: u8,
+    ) -> Result<()> {
+        if page % HostPhysFrame::SIZE as u64 != 0 {
+            return Err(Error::InvalidValue(format!(
+                "Event channel page 0x{:x} is not page aligned",
+                page
+            )));
+        }
+        if vector < MIN_UPCALL_VECTOR {
+            return Err(Error::InvalidValue(format!(
+                "Invalid event channel vector: {}",
+                vector
+            )));
+        }
+        let page = GuestPhysAddr::new(page);
+        space.find_host_frame(page)?;
+        self.page = Some(page);
+        self.vector = vector;
+        Ok(())
+    }
+
+    /// Bind the lowest free channel to `source`
+    pub fn bind(&mut self, source: EventSource) -> Result<Channel> {
+        if self.channel(source).is_some() {
+            return Err(Error::InvalidValue(format!(
+                "Event source {:?} is already bound",
+                source
+            )));
+        }
+        let channel = (1..MAX_EVENT_CHANNELS)
+            .find(|channel| !self.bindings.contains_key(channel))
+            .ok_or_else(|| {
+                Error::InvalidValue("No free event channels".into())
+            })?;
+        self.bindings.insert(channel, source);
+        Ok(channel)
+    }
+
+    /// Unbind `channel`
+    pub fn close(&mut self, channel: Channel) -> Result<()> {
+        self.bindings
+            .remove(&channel)
+            .map(|_| ())
+            .ok_or_else(|| Error::NotFound)
+    }
+
+    /// The channel bound to `source`
+    pub fn channel(&self, source: EventSource) -> Option<Channel> {
+        self.bindings
+            .iter()
+            .find(|(_, bound)| **bound == source)
+            .map(|(channel, _)| *channel)
+    }
+
+    /// The source bound to `channel`
+    pub fn source(&self, channel: Channel) -> Option<EventSource> {
+        self.bindings.get(&channel).copied()
+    }
+
+    // The word of the bitmap at `offset` holding the bit of `channel`
+    fn bitmap_word<'a>(
+        &self,
+        space: &'a GuestAddressSpace,
+        offset: usize,
+        channel: Channel,
+    ) -> Result<Option<(&'a AtomicU64, u64)>> {
+        let page = match self.page {
+            Some(page) => page,
+            None => return Ok(None),
+        };
+        let frame = space.find_host_frame(page)?;
+        let index = offset / 8 + channel as usize / 64;
+        // The page is aligned, and the guest may be updating the bitmaps
+        // concurrently, so they are accessed atomically
+        let word = unsafe {
+            &*(frame.start_address().as_u64() as *const AtomicU64).add(index)
+        };
+        Ok(Some((word, 1 << (channel % 64))))
+    }
+
+    fn upcall_if_unmasked(
+        &self,
+        space: &GuestAddressSpace,
+        channel: Channel,
+    ) -> Result<Option<u8>> {
+        match self.bitmap_word(space, MASK_OFFSET, channel)? {
+            Some((mask, bit)) if mask.load(Ordering::SeqCst) & bit == 0 => {
+                Ok(Some(self.vector))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Mark the channel bound to `source` as pending, returning the vector
+    /// to inject in to the guest (if any)
+    pub fn notify(
+        &mut self,
+        space: &GuestAddressSpace,
+        source: EventSource,
+    ) -> Result<Option<u8>> {
+        let channel = match self.channel(source) {
+            Some(channel) => channel,
+            None => return Ok(None),
+        };
+        let (pending, bit) =
+            match self.bitmap_word(space, PENDING_OFFSET, channel)? {
+                Some(word) => word,
+                None => return Ok(None),
+            };
+        if pending.fetch_or(bit, Ordering::SeqCst) & bit != 0 {
+            // The guest has not yet handled the previous notification
+            return Ok(None);
+        }
+        self.upcall_if_unmasked(space, channel)
+    }
+
+    /// Mask or unmask `channel`, returning the vector to inject in to the
+    /// guest if a notification arrived while it was masked
+    pub fn set_masked(
+        &mut self,
+        space: &GuestAddressSpace,
+        channel: Channel,
+        masked: bool,
+    ) -> Result<Option<u8>> {
+        if self.source(channel).is_none() {
+            return Err(Error::NotFound);
+        }
+        let (mask, bit) = match self.bitmap_word(space, MASK_OFFSET, channel)? {
+            Some(word) => word,
+            None => return Err(Error::NotFound),
+        };
+        if masked {
+            mask.fetch_or(bit, Ordering::SeqCst);
+            return Ok(None);
+        }
+        mask.fetch_and(!bit, Ordering::SeqCst);
+
+        match self.bitmap_word(space, PENDING_OFFSET, channel)? {
+            Some((pending, bit))
+                if pending.load(Ordering::SeqCst) & bit != 0 =>
+            {
+                Ok(Some(self.vector))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Signal the channel bound to `source` in the VM with the given id
+pub fn signal(vm_id: u32, source: EventSource) -> Result<()> {
+    vm::send_vm_msg(vm::VirtualMachineMsg::EventChannel(source), vm_id)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn setup() -> (GuestAddressSpace, EventChannels) {
+        let mut space = GuestAddressSpace::new().unwrap();
+        space
+            .map_new_frame(GuestPhysAddr::new(0x1000), false)
+            .unwrap();
+        let mut channels = EventChannels::default();
+        assert!(channels.setup(&space, 0x1004, 0x40).is_err());
+        assert!(channels.setup(&space, 0x1000, 2).is_err());
+        channels.setup(&space, 0x1000, 0x40).unwrap();
+        (space, channels)
+    }
+
+    #[test]
+    fn test_bind() {
+        let mut channels = EventChannels::default();
+        let device = EventSource::Device(3);
+        assert_eq!(channels.bind(device).unwrap(), 1);
+        assert!(channels.bind(device).is_err());
+        assert_eq!(channels.bind(EventSource::Vm(3)).unwrap(), 2);
+        channels.close(1).unwrap();
+        assert!(channels.close(1).is_err());
+        assert_eq!(channels.bind(EventSource::Vm(4)).unwrap(), 1);
+        assert_eq!(channels.source(1), Some(EventSource::Vm(4)));
+        assert!(EventSource::from_raw(2, 0).is_err());
+    }
+
+    #[test]
+    fn test_notify() {
+        let (space, mut channels) = setup();
+        let source = EventSource::Device(0);
+        assert_eq!(channels.notify(&space, source).unwrap(), None);
+
+        let channel = channels.bind(source).unwrap();
+        assert_eq!(channels.notify(&space, source).unwrap(), Some(0x40));
+        // Notifications coalesce until the guest clears the pending bit
+        assert_eq!(channels.notify(&space, source).unwrap(), None);
+
+        let (pending, bit) = channels
+            .bitmap_word(&space, PENDING_OFFSET, channel)
+            .unwrap()
+            .unwrap();
+        pending.fetch_and(!bit, Ordering::SeqCst);
+
+        assert_eq!(channels.set_masked(&space, channel, true).unwrap(), None);
+        assert_eq!(channels.notify(&space, source).unwrap(), None);
+        assert_eq!(
+            channels.set_masked(&space, channel, false).unwrap(),
+            Some(0x40)
+        );
+    }
+}
@@ -0,0 +1,193 @@
+//! Fail-closed handling of selected VM exits
+//!
+//! By default, every VM exit is handled (usually by emulating the
+//! instruction that caused it). An `ExitPolicy` lets a VM configuration
+//! override this for selected exits before the regular handlers run: an
+//! exit may instead be ignored (the instruction is skipped, and reads
+//! return zero) or kill the guest (see `lifecycle::StopReason::Killed`).
+//!
+//! This is intended for high-assurance deployments, where a guest that
+//! attempts something unexpected (e.g., any VMX instruction) should be
+//! stopped rather than have the attempt emulated.
+
+use crate::virtdev::Port;
+use crate::vmexit::ExitInformation;
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+
+/// What is done with an exit
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExitAction {
+    /// The exit is passed to the regular handlers
+    Handle,
+
+    /// The instruction is skipped without being emulated
+    Ignore,
+
+    /// The guest is stopped
+    Kill,
+}
+
+/// The exits an `ExitRule` applies to
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExitClass {
+    /// Any VMX instruction other than VMCALL (VMXON, VMPTRLD, INVEPT, ...)
+    VmxInstruction,
+
+    /// VMCALL (i.e., a hypercall)
+    Hypercall,
+
+    /// CPUID
+    Cpuid,
+
+    /// RDMSR of an MSR in the range
+    RdMsr(RangeInclusive<u32>),
+
+    /// WRMSR of an MSR in the range
+    WrMsr(RangeInclusive<u32>),
+
+    /// Port IO to a port in the range
+    PortIo(RangeInclusive<Port>),
+
+    /// Accesses to the control registers
+    CrAccess,
+
+    /// Accesses to the debug registers
+    DrAccess,
+
+    /// INVD and WBINVD
+    CacheInvalidation,
+}
+
+impl ExitClass {
+    /// Returns true if the exit described by `info` is in this class. The
+    /// `msr` is the MSR index of a RDMSR or WRMSR exit (from `rcx`).
+    pub fn matches(&self, info: &ExitInformation, msr: u32) -> bool {
+        match (self, info) {
+            (ExitClass::VmxInstruction, ExitInformation::VmClear)
+            | (ExitClass::VmxInstruction, ExitInformation::VmLaunch)
+            | (ExitClass::VmxInstruction, ExitInformation::VmPtrLd)
+            | (ExitClass::VmxInstruction, ExitInformation::VmPtrRst)
+            | (ExitClass::VmxInstruction, ExitInformation::VmRead)
+            | (ExitClass::VmxInstruction, ExitInformation::VmResume)
+            | (ExitClass::VmxInstruction, ExitInformation::VmWrite)
+            | (ExitClass::VmxInstruction, ExitInformation::VmxOff)
+            | (ExitClass::VmxInstruction, ExitInformation::VmxOn)
+            | (ExitClass::VmxInstruction, ExitInformation::InvEpt)
+            | (ExitClass::VmxInstruction, ExitInformation::Invvpid)
+            | (ExitClass::VmxInstruction, ExitInformation::VmFunc) => true,
+            (ExitClass::Hypercall, ExitInformation::VmCall) => true,
+            (ExitClass::Cpuid, ExitInformation::CpuId) => true,
+            (ExitClass::RdMsr(msrs), ExitInformation::RdMsr) => {
+                msrs.contains(&msr)
+            }
+            (ExitClass::WrMsr(msrs), ExitInformation::WrMsr) => {
+                msrs.contains(&msr)
+            }
+            (ExitClass::PortIo(ports), ExitInformation::IoInstruction(io)) => {
+                ports.contains(&io.port)
+            }
+            (ExitClass::CrAccess, ExitInformation::CrAccess(_)) => true,
+            (ExitClass::DrAccess, ExitInformation::MovDr) => true,
+            (ExitClass::CacheInvalidation, ExitInformation::Invd)
+            | (ExitClass::CacheInvalidation, ExitInformation::Wbinvd) => true,
+            _ => false,
+        }
+    }
+}
+
+/// An action to take for a class of exits
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExitRule {
+    pub class: ExitClass,
+    pub action: ExitAction,
+}
+
+/// The rules for the exits of a VM, evaluated in order
+///
+/// The first rule matching an exit decides its action. Exits that match
+/// no rule are handled.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExitPolicy {
+    rules: Vec<ExitRule>,
+}
+
+impl ExitPolicy {
+    /// Add a rule, which applies to the exits that are not matched by an
+    /// earlier rule
+    pub fn add_rule(&mut self, class: ExitClass, action: ExitAction) {
+        self.rules.push(ExitRule { class, action });
+    }
+
+    /// The rules of this policy
+    pub fn rules(&self) -> &[ExitRule] {
+        &self.rules
+    }
+
+    /// Returns true if every exit is handled
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The action for the exit described by `info` (where `msr` is the MSR
+    /// index of a RDMSR or WRMSR exit)
+    pub fn evaluate(&self, info: &ExitInformation, msr: u32) -> ExitAction {
+        self.rules
+            .iter()
+            .find(|rule| rule.class.matches(info, msr))
+            .map_or(ExitAction::Handle, |rule| rule.action)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::vmexit::IoInstructionInformation;
+
+    #[test]
+    fn test_exit_policy() {
+        let mut policy = ExitPolicy::default();
+        assert_eq!(
+            policy.evaluate(&ExitInformation::VmxOn, 0),
+            ExitAction::Handle
+        );
+
+        policy.add_rule(ExitClass::RdMsr(0x10..=0x10), ExitAction::Ignore);
+        policy.add_rule(ExitClass::VmxInstruction, ExitAction::Kill);
+        policy.add_rule(ExitClass::RdMsr(0..=0xffff_ffff), ExitAction::Kill);
+        assert_eq!(
+            policy.evaluate(&ExitInformation::VmxOn, 0),
+            ExitAction::Kill
+        );
+        assert_eq!(
+            policy.evaluate(&ExitInformation::VmCall, 0),
+            ExitAction::Handle
+        );
+        assert_eq!(
+            policy.evaluate(&ExitInformation::RdMsr, 0x10),
+            ExitAction::Ignore
+        );
+        assert_eq!(
+            policy.evaluate(&ExitInformation::RdMsr, 0x1b),
+            ExitAction::Kill
+        );
+        assert_eq!(
+            policy.evaluate(&ExitInformation::WrMsr, 0x1b),
+            ExitAction::Handle
+        );
+    }
+
+    #[test]
+    fn test_port_io_class() {
+        let io = ExitInformation::IoInstruction(IoInstructionInformation {
+            size: 1,
+            input: true,
+            string: false,
+            rep: false,
+            immediate: false,
+            port: 0x70,
+        });
+        assert!(ExitClass::PortIo(0x70..=0x71).matches(&io, 0));
+        assert!(!ExitClass::PortIo(0x60..=0x64).matches(&io, 0));
+    }
+}
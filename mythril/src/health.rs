@@ -0,0 +1,516 @@
+//! Per-core health reporting
+//!
+//! Each core publishes a heartbeat, the last VMEXIT it handled and the depth
+//! of its queues into a shared table as it handles exits, along with the
+//! time it has spent handling exits and running the guest. Cores that
+//! support the VMX-preemption timer are forced to exit at least every
+//! `HEARTBEAT_PERIOD`, so a core that stops publishing is wedged (rather
+//! than running an idle guest). The table is read by the `health` command
+//! of the management console.
+//!
+//! Each core also keeps a histogram of the time it takes to handle exits,
+//! and a second one for the exits completed by the fast path (see
+//! `VCpu::handle_fast_vmexit`), shown by the `exitlat` command.
+
+use crate::lock::ro_after_init::RoAfterInit;
+use crate::percore::{self, CoreId};
+use crate::time;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+
+/// The maximum interval between heartbeats of a healthy core
+pub const HEARTBEAT_PERIOD: Duration = Duration::from_millis(100);
+
+/// A core that has not published a heartbeat for this long is wedged
+const WEDGED_THRESHOLD: Duration = Duration::from_secs(1);
+
+/// A core that spends this long handling a single exit (during which it
+/// holds the locks of its VM) is reported as slow
+const SLOW_EXIT_THRESHOLD: Duration = Duration::from_millis(10);
+
+/// The number of basic exit reasons counted separately (higher reasons are
+/// only counted in the total)
+pub const EXIT_REASONS: usize = 80;
+
+/// The number of exits with each basic reason
+pub struct ExitReasonCounts(Vec<AtomicU64>);
+
+impl Default for ExitReasonCounts {
+    fn default() -> Self {
+        Self((0..EXIT_REASONS).map(|_| AtomicU64::new(0)).collect())
+    }
+}
+
+impl ExitReasonCounts {
+    /// The reasons that caused exits, and the number of each
+    pub fn snapshot(&self) -> Vec<(u64, u64)> {
+        self.0
+            .iter()
+            .enumerate()
+            .map(|(reason, count)| {
+                (reason as u64, count.load(Ordering::Relaxed))
+            })
+            .filter(|(_, count)| *count != 0)
+            .collect()
+    }
+}
+
+/// The number of buckets in an exit latency histogram
+pub const LATENCY_BUCKETS: usize = 16;
+
+// The upper bound of the first latency bucket is 2^LATENCY_SHIFT ns (256ns)
+const LATENCY_SHIFT: u32 = 8;
+
+/// A histogram of exit latencies
+///
+/// Bucket `i` counts the exits that took at least `bucket_floor(i)`, with
+/// each bucket twice as wide as the last. The last bucket also counts all
+/// longer exits.
+#[derive(Default)]
+pub struct ExitLatencies([AtomicU64; LATENCY_BUCKETS]);
+
+impl ExitLatencies {
+    /// The bucket that counts exits taking `latency`
+    pub fn bucket(latency: Duration) -> usize {
+        let nanos = latency.as_nanos() as u64 >> LATENCY_SHIFT;
+        let bucket = (64 - nanos.leading_zeros()) as usize;
+        bucket.min(LATENCY_BUCKETS - 1)
+    }
+
+    /// The shortest latency counted by `bucket`
+    pub fn bucket_floor(bucket: usize) -> Duration {
+        if bucket == 0 {
+            Duration::from_nanos(0)
+        } else {
+            Duration::from_nanos(1 << (bucket as u32 - 1 + LATENCY_SHIFT))
+        }
+    }
+
+    fn record(&self, latency: Duration) {
+        self.0[Self::bucket(latency)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The number of exits counted by each bucket
+    pub fn snapshot(&self) -> [u64; LATENCY_BUCKETS] {
+        let mut counts = [0; LATENCY_BUCKETS];
+        for (count, bucket) in counts.iter_mut().zip(self.0.iter()) {
+            *count = bucket.load(Ordering::Relaxed);
+        }
+        counts
+    }
+
+    /// Start counting from zero again
+    pub fn clear(&self) {
+        for bucket in self.0.iter() {
+            bucket.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The health information published by a single core
+#[derive(Default)]
+pub struct CoreHealth {
+    /// The time (in `Instant` ticks) of the last heartbeat
+    heartbeat: AtomicU64,
+    /// Whether this core is forced to exit every `HEARTBEAT_PERIOD`
+    periodic: AtomicBool,
+    /// The number of exits handled
+    exits: AtomicU64,
+    /// The basic reason of the most recent exit
+    last_exit: AtomicU64,
+    /// The time the current exit started (or 0 if running the guest)
+    exit_started: AtomicU64,
+    /// The number of interrupts waiting to be injected
+    pending_interrupts: AtomicU64,
+    /// The number of messages waiting to be processed
+    pending_messages: AtomicU64,
+    /// The time of the most recent VM entry (or 0 before the first)
+    entered: AtomicU64,
+    /// The total time (in ticks) spent handling exits
+    exit_ticks: AtomicU64,
+    /// The total time (in ticks) spent running the guest
+    guest_ticks: AtomicU64,
+    /// The number of exits with each basic reason
+    exit_reasons: ExitReasonCounts,
+    /// The number of events injected in to the guest
+    injections: AtomicU64,
+    /// The time taken to handle each exit
+    exit_latencies: ExitLatencies,
+    /// The time taken to handle each exit completed by the fast path
+    fast_exit_latencies: ExitLatencies,
+}
+
+/// A snapshot of the counters of a core (see `core_counters`)
+#[derive(Clone, Debug, PartialEq)]
+pub struct CoreCounters {
+    pub exits: u64,
+    /// The basic reasons that caused exits, and the number of each
+    pub exit_reasons: Vec<(u64, u64)>,
+    pub injections: u64,
+    pub pending_interrupts: u64,
+    pub accounting: ExitAccounting,
+}
+
+/// The time a core has spent handling exits and running its guest
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ExitAccounting {
+    /// The time spent handling exits (the guest's steal time)
+    pub exit_time: Duration,
+    /// The time spent running the guest
+    pub guest_time: Duration,
+}
+
+impl ExitAccounting {
+    /// The fraction of the total time spent handling exits, in tenths of
+    /// a percent
+    pub fn overhead_permille(&self) -> u64 {
+        let total = self.exit_time + self.guest_time;
+        if total.as_nanos() == 0 {
+            return 0;
+        }
+        (self.exit_time.as_nanos() * 1000 / total.as_nanos()) as u64
+    }
+}
+
+/// The assessed state of a core
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CoreStatus {
+    /// The core is publishing heartbeats
+    Healthy,
+    /// The core has been handling a single exit for the given time
+    SlowExit(Duration),
+    /// The core has not published a heartbeat for the given time
+    Wedged(Duration),
+    /// The core has not exited for the given time, but it does not exit
+    /// periodically, so it may just be running an idle guest
+    Quiet(Duration),
+}
+
+static HEALTH: RoAfterInit<BTreeMap<CoreId, CoreHealth>> =
+    RoAfterInit::uninitialized();
+
+/// Create the health table for the given cores
+pub unsafe fn init(cores: impl Iterator<Item = CoreId>) {
+    RoAfterInit::init(
+        &HEALTH,
+        cores.map(|core| (core, CoreHealth::default())).collect(),
+    );
+}
+
+fn current() -> Option<&'static CoreHealth> {
+    if !RoAfterInit::is_initialized(&HEALTH) {
+        return None;
+    }
+    HEALTH.get(&percore::read_core_id())
+}
+
+/// Record that the current core has no vCPU to run (see `migrate`), so
+/// the heartbeats it no longer publishes are not taken for a wedged core
+pub fn idle() {
+    if let Some(health) = current() {
+        health.exit_started.store(0, Ordering::Relaxed);
+        health.periodic.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Record whether the current core is forced to exit periodically
+pub fn set_periodic(periodic: bool) {
+    if let Some(health) = current() {
+        health.periodic.store(periodic, Ordering::Relaxed);
+    }
+}
+
+/// Publish the start of an exit with the given basic reason
+pub fn exit_started(reason: u64) {
+    if let Some(health) = current() {
+        let now = time::now().0;
+        let entered = health.entered.load(Ordering::Relaxed);
+        if entered != 0 {
+            health
+                .guest_ticks
+                .fetch_add(now.saturating_sub(entered), Ordering::Relaxed);
+        }
+        health.heartbeat.store(now, Ordering::Relaxed);
+        health.exit_started.store(now, Ordering::Relaxed);
+        health.last_exit.store(reason, Ordering::Relaxed);
+        health.exits.fetch_add(1, Ordering::Relaxed);
+        if let Some(count) = health.exit_reasons.0.get(reason as usize) {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Count an event injected in to the guest on the next VM entry
+pub fn event_injected() {
+    if let Some(health) = current() {
+        health.injections.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Publish the end of an exit and the depth of the core's queues
+pub fn exit_finished(pending_interrupts: usize, pending_messages: usize) {
+    if let Some(health) = current() {
+        health.finish_exit(pending_interrupts);
+        health
+            .pending_messages
+            .store(pending_messages as u64, Ordering::Relaxed);
+    }
+}
+
+/// Publish the end of an exit completed by the fast path
+///
+/// No messages are received by the fast path, so the depth of the message
+/// queue is left as it was published by the last full exit.
+pub fn fast_exit_finished(pending_interrupts: usize) {
+    if let Some(health) = current() {
+        if let Some(latency) = health.finish_exit(pending_interrupts) {
+            health.fast_exit_latencies.record(latency);
+        }
+    }
+}
+
+/// The basic reason of the most recent exit handled by `core`
+pub fn last_exit(core: CoreId) -> Option<u64> {
+    if !RoAfterInit::is_initialized(&HEALTH) {
+        return None;
+    }
+    HEALTH
+        .get(&core)
+        .map(|health| health.last_exit.load(Ordering::Relaxed))
+}
+
+/// The counters of `core` (if health reporting is initialized)
+pub fn core_counters(core: CoreId) -> Option<CoreCounters> {
+    if !RoAfterInit::is_initialized(&HEALTH) {
+        return None;
+    }
+    HEALTH.get(&core).map(|health| health.counters())
+}
+
+/// The exit accounting of the current core (if health reporting is
+/// initialized)
+pub fn current_accounting() -> Option<ExitAccounting> {
+    current().map(|health| health.accounting())
+}
+
+impl CoreHealth {
+    // Publish the end of the current exit, returning the time it took
+    fn finish_exit(&self, pending_interrupts: usize) -> Option<Duration> {
+        let now = time::now().0;
+        let started = self.exit_started.load(Ordering::Relaxed);
+        let latency = if started != 0 {
+            self.exit_ticks
+                .fetch_add(now.saturating_sub(started), Ordering::Relaxed);
+            let latency = time::Instant(now) - time::Instant(started);
+            self.exit_latencies.record(latency);
+            Some(latency)
+        } else {
+            None
+        };
+        self.heartbeat.store(now, Ordering::Relaxed);
+        self.exit_started.store(0, Ordering::Relaxed);
+        self.entered.store(now, Ordering::Relaxed);
+        self.pending_interrupts
+            .store(pending_interrupts as u64, Ordering::Relaxed);
+        latency
+    }
+
+    /// The time this core has spent handling exits and running the guest
+    pub fn accounting(&self) -> ExitAccounting {
+        let ticks = |counter: &AtomicU64| {
+            time::Instant(counter.load(Ordering::Relaxed)) - time::Instant(0)
+        };
+        ExitAccounting {
+            exit_time: ticks(&self.exit_ticks),
+            guest_time: ticks(&self.guest_ticks),
+        }
+    }
+
+    /// A snapshot of the counters of this core
+    pub fn counters(&self) -> CoreCounters {
+        CoreCounters {
+            exits: self.exits.load(Ordering::Relaxed),
+            exit_reasons: self.exit_reasons.snapshot(),
+            injections: self.injections.load(Ordering::Relaxed),
+            pending_interrupts: self.pending_interrupts.load(Ordering::Relaxed),
+            accounting: self.accounting(),
+        }
+    }
+
+    /// Assess the state of this core at `now`
+    pub fn status(&self, now: time::Instant) -> CoreStatus {
+        let started = self.exit_started.load(Ordering::Relaxed);
+        if started != 0 {
+            let elapsed = now - time::Instant(started);
+            if elapsed > WEDGED_THRESHOLD {
+                return CoreStatus::Wedged(elapsed);
+            } else if elapsed > SLOW_EXIT_THRESHOLD {
+                return CoreStatus::SlowExit(elapsed);
+            }
+            return CoreStatus::Healthy;
+        }
+
+        let heartbeat = self.heartbeat.load(Ordering::Relaxed);
+        let elapsed = now - time::Instant(heartbeat);
+        if heartbeat == 0 || elapsed <= WEDGED_THRESHOLD {
+            CoreStatus::Healthy
+        } else if self.periodic.load(Ordering::Relaxed) {
+            CoreStatus::Wedged(elapsed)
+        } else {
+            CoreStatus::Quiet(elapsed)
+        }
+    }
+}
+
+impl fmt::Display for CoreStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CoreStatus::Healthy => write!(f, "ok"),
+            CoreStatus::SlowExit(time) => write!(
+                f,
+                "SLOW: handling one exit for {}ms (lock held too long?)",
+                time.as_millis()
+            ),
+            CoreStatus::Wedged(time) => {
+                write!(f, "WEDGED: no heartbeat for {}ms", time.as_millis())
+            }
+            CoreStatus::Quiet(time) => {
+                write!(f, "quiet: no exits for {}ms", time.as_millis())
+            }
+        }
+    }
+}
+
+/// The `health` management console command
+pub fn health_command(out: &mut dyn fmt::Write, _args: &[&str]) -> fmt::Result {
+    if !RoAfterInit::is_initialized(&HEALTH) {
+        return writeln!(out, "Health reporting is not initialized");
+    }
+    let now = time::now();
+    for (core, health) in HEALTH.iter() {
+        let accounting = health.accounting();
+        let overhead = accounting.overhead_permille();
+        writeln!(
+            out,
+            "core {:>3}: exits={} last_exit={} pending_irqs={} pending_msgs={} exit_time={}ms overhead={}.{}% {}",
+            core.raw,
+            health.exits.load(Ordering::Relaxed),
+            health.last_exit.load(Ordering::Relaxed),
+            health.pending_interrupts.load(Ordering::Relaxed),
+            health.pending_messages.load(Ordering::Relaxed),
+            accounting.exit_time.as_millis(),
+            overhead / 10,
+            overhead % 10,
+            health.status(now)
+        )?;
+    }
+    Ok(())
+}
+
+/// The `exitlat` management console command
+pub fn exitlat_command(out: &mut dyn fmt::Write, args: &[&str]) -> fmt::Result {
+    if !RoAfterInit::is_initialized(&HEALTH) {
+        return writeln!(out, "Health reporting is not initialized");
+    }
+    match args.get(0) {
+        Some(&"clear") => {
+            for health in HEALTH.values() {
+                health.exit_latencies.clear();
+                health.fast_exit_latencies.clear();
+            }
+            return writeln!(out, "Cleared the exit latency histograms");
+        }
+        Some(_) => return writeln!(out, "usage: exitlat [clear]"),
+        None => (),
+    }
+
+    writeln!(
+        out,
+        "PCIDs are {} on this core",
+        if crate::pcid::enabled() {
+            "enabled"
+        } else {
+            "disabled"
+        }
+    )?;
+    for (core, health) in HEALTH.iter() {
+        let histograms = [
+            ("", &health.exit_latencies),
+            (" (fast path)", &health.fast_exit_latencies),
+        ];
+        for (path, latencies) in histograms.iter() {
+            let counts = latencies.snapshot();
+            if counts.iter().all(|count| *count == 0) {
+                continue;
+            }
+            writeln!(out, "core {}{}:", core.raw, path)?;
+            for (bucket, count) in counts.iter().enumerate() {
+                if *count != 0 {
+                    writeln!(
+                        out,
+                        "  >= {:>8}ns: {}",
+                        ExitLatencies::bucket_floor(bucket).as_nanos(),
+                        count
+                    )?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_overhead() {
+        let accounting = ExitAccounting {
+            exit_time: Duration::from_micros(25),
+            guest_time: Duration::from_micros(975),
+        };
+        assert_eq!(accounting.overhead_permille(), 25);
+
+        let idle = ExitAccounting {
+            exit_time: Duration::from_secs(0),
+            guest_time: Duration::from_secs(0),
+        };
+        assert_eq!(idle.overhead_permille(), 0);
+    }
+
+    #[test]
+    fn test_exit_reason_counts() {
+        let counts = ExitReasonCounts::default();
+        assert!(counts.snapshot().is_empty());
+        counts.0[10].store(2, Ordering::Relaxed);
+        counts.0[48].store(1, Ordering::Relaxed);
+        assert_eq!(counts.snapshot(), vec![(10, 2), (48, 1)]);
+    }
+
+    #[test]
+    fn test_exit_latencies() {
+        let bucket = |nanos| ExitLatencies::bucket(Duration::from_nanos(nanos));
+        assert_eq!(bucket(0), 0);
+        assert_eq!(bucket(255), 0);
+        assert_eq!(bucket(256), 1);
+        assert_eq!(bucket(1000), 2);
+        assert_eq!(bucket(1024), 3);
+        assert_eq!(bucket(u64::MAX), LATENCY_BUCKETS - 1);
+        for i in 0..LATENCY_BUCKETS {
+            assert_eq!(
+                ExitLatencies::bucket(ExitLatencies::bucket_floor(i)),
+                i
+            );
+        }
+
+        let latencies = ExitLatencies::default();
+        latencies.record(Duration::from_micros(3));
+        latencies.record(Duration::from_micros(3));
+        assert_eq!(latencies.snapshot()[4], 2);
+        latencies.clear();
+        assert_eq!(latencies.snapshot(), [0; LATENCY_BUCKETS]);
+    }
+}
@@ -0,0 +1,457 @@
+//! The registry of host physical memory
+//!
+//! The map is built from the memory map of the boot environment before the
+//! global allocator exists: the hypervisor image, the boot modules and the
+//! boot information are reserved first, and the heap is then placed in the
+//! largest remaining usable range. Everything else that needs a fixed
+//! physical range registers it here, so an overlap with an existing use is
+//! an error at the point it is introduced rather than a memory corruption
+//! later on:
+//!
+//!  - Pages the hardware accesses by physical address (stacks, VMCS
+//!    regions, virtual APIC pages and DMA buffers) are allocated from the
+//!    heap, and are tracked as allocations within it.
+//!  - Device registers (e.g., of a remapping unit) and the BARs of devices
+//!    passed through to guests must not overlap usable memory.
+//!
+//! The `hostmem` console command prints the map.
+
+use crate::error::{Error, Result};
+use crate::memory::Raw4kPage;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use arrayvec::ArrayVec;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use spin::Mutex;
+
+/// The maximum number of usable ranges in the boot memory map
+pub const MAX_USABLE_RANGES: usize = 32;
+
+/// The maximum number of regions reserved outside the heap
+pub const MAX_HOST_REGIONS: usize = 64;
+
+/// What a range of host physical memory is used for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HostRegionKind {
+    /// The information passed by the boot loader
+    BootInfo,
+
+    /// The code and data of the hypervisor
+    HypervisorImage,
+
+    /// A module loaded by the boot loader
+    BootModule,
+
+    /// The range used by the global allocator
+    Heap,
+
+    /// The stack of the core with the given APIC ID
+    Stack(u32),
+
+    /// A VMCS region
+    Vmcs,
+
+    /// A virtual APIC page
+    ApicPage,
+
+    /// A buffer accessed by a device (e.g., a remapping table)
+    DmaBuffer,
+
+    /// The registers of a host device
+    DeviceRegisters,
+
+    /// A BAR of a host device passed through to a guest
+    PassthroughBar,
+}
+
+// Where a region of each kind must be placed
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Placement {
+    // Anywhere outside the other reservations
+    Reserved,
+
+    // Within a single usable range
+    Usable,
+
+    // Within the heap, and outside the other allocations
+    Heap,
+
+    // Outside usable memory
+    Mmio,
+}
+
+impl HostRegionKind {
+    fn placement(&self) -> Placement {
+        match self {
+            HostRegionKind::BootInfo
+            | HostRegionKind::HypervisorImage
+            | HostRegionKind::BootModule => Placement::Reserved,
+            HostRegionKind::Heap => Placement::Usable,
+            HostRegionKind::Stack(_)
+            | HostRegionKind::Vmcs
+            | HostRegionKind::ApicPage
+            | HostRegionKind::DmaBuffer => Placement::Heap,
+            HostRegionKind::DeviceRegisters
+            | HostRegionKind::PassthroughBar => Placement::Mmio,
+        }
+    }
+}
+
+/// A range of host physical memory, from `start` up to (but excluding)
+/// `end`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HostRegion {
+    pub start: u64,
+    pub end: u64,
+    pub kind: HostRegionKind,
+}
+
+impl HostRegion {
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        start < self.end && self.start < end
+    }
+}
+
+/// The uses of host physical memory
+pub struct HostMemoryMap {
+    usable: ArrayVec<[(u64, u64); MAX_USABLE_RANGES]>,
+    regions: ArrayVec<[HostRegion; MAX_HOST_REGIONS]>,
+    allocations: Vec<HostRegion>,
+}
+
+impl HostMemoryMap {
+    /// Create a map with the given usable ranges (as reported by the boot
+    /// environment), which are sorted and merged
+    pub fn new(usable: impl Iterator<Item = (u64, u64)>) -> Result<Self> {
+        let mut ranges = ArrayVec::<[(u64, u64); MAX_USABLE_RANGES]>::new();
+        for range in usable.filter(|(start, end)| start < end) {
+            ranges.try_push(range).map_err(|_| {
+                Error::InvalidValue(format!(
+                    "Too many usable memory ranges (max={})",
+                    MAX_USABLE_RANGES
+                ))
+            })?;
+        }
+        ranges.sort_unstable_by_key(|(start, _)| *start);
+
+        let mut merged = ArrayVec::<[(u64, u64); MAX_USABLE_RANGES]>::new();
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+
+        Ok(Self {
+            usable: merged,
+            regions: ArrayVec::new(),
+            allocations: Vec::new(),
+        })
+    }
+
+    /// The usable ranges of host memory
+    pub fn usable(&self) -> &[(u64, u64)] {
+        &self.usable
+    }
+
+    /// The reserved regions and the allocations within the heap, by start
+    /// address
+    pub fn regions(&self) -> Vec<HostRegion> {
+        let mut regions = self
+            .regions
+            .iter()
+            .chain(self.allocations.iter())
+            .copied()
+            .collect::<Vec<_>>();
+        regions.sort_unstable_by_key(|region| region.start);
+        regions
+    }
+
+    fn is_usable(&self, start: u64, end: u64) -> bool {
+        self.usable
+            .iter()
+            .any(|range| range.0 <= start && end <= range.1)
+    }
+
+    fn overlaps_usable(&self, start: u64, end: u64) -> bool {
+        self.usable
+            .iter()
+            .any(|range| start < range.1 && range.0 < end)
+    }
+
+    /// Record that `start..end` is used for `kind`, failing if that
+    /// conflicts with an existing use
+    pub fn reserve(
+        &mut self,
+        start: u64,
+        end: u64,
+        kind: HostRegionKind,
+    ) -> Result<()> {
+        if start >= end {
+            return Err(Error::InvalidValue(format!(
+                "Empty host region 0x{:x}-0x{:x}",
+                start, end
+            )));
+        }
+
+        let placement = kind.placement();
+        let (existing, placed) = match placement {
+            Placement::Heap => (
+                &self.allocations[..],
+                self.regions.iter().any(|region| {
+                    region.kind == HostRegionKind::Heap
+                        && region.start <= start
+                        && end <= region.end
+                }),
+            ),
+            Placement::Usable => {
+                (&self.regions[..], self.is_usable(start, end))
+            }
+            Placement::Mmio => {
+                (&self.regions[..], !self.overlaps_usable(start, end))
+            }
+            Placement::Reserved => (&self.regions[..], true),
+        };
+        if !placed {
+            return Err(Error::InvalidValue(format!(
+                "Invalid placement of host region 0x{:x}-0x{:x} ({:?})",
+                start, end, kind
+            )));
+        }
+        if let Some(conflict) =
+            existing.iter().find(|region| region.overlaps(start, end))
+        {
+            return Err(Error::InvalidValue(format!(
+                "Host region 0x{:x}-0x{:x} ({:?}) overlaps 0x{:x}-0x{:x} ({:?})",
+                start, end, kind, conflict.start, conflict.end, conflict.kind
+            )));
+        }
+
+        let region = HostRegion { start, end, kind };
+        if placement == Placement::Heap {
+            self.allocations.push(region);
+        } else {
+            self.regions.try_push(region).map_err(|_| {
+                Error::InvalidValue(format!(
+                    "Too many host regions (max={})",
+                    MAX_HOST_REGIONS
+                ))
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Remove the region of `kind` starting at `start`
+    pub fn release(&mut self, start: u64, kind: HostRegionKind) -> Result<()> {
+        let matches =
+            |region: &HostRegion| region.start == start && region.kind == kind;
+        if let Some(index) = self.allocations.iter().position(matches) {
+            self.allocations.swap_remove(index);
+        } else if let Some(index) = self.regions.iter().position(matches) {
+            self.regions.swap_remove(index);
+        } else {
+            return Err(Error::InvalidValue(format!(
+                "No {:?} host region at 0x{:x}",
+                kind, start
+            )));
+        }
+        Ok(())
+    }
+
+    /// The largest part of a usable range that is not reserved
+    pub fn largest_free(&self) -> Option<(u64, u64)> {
+        let mut largest: Option<(u64, u64)> = None;
+        for &(start, end) in self.usable.iter() {
+            let mut cursor = start;
+            loop {
+                // The end of the free range starting at `cursor` is the
+                // first reservation above it (or the end of the range)
+                let next = self
+                    .regions
+                    .iter()
+                    .filter(|region| region.overlaps(cursor, end))
+                    .min_by_key(|region| region.start);
+                let free_end = match next {
+                    Some(region) => region.start.max(cursor),
+                    None => end,
+                };
+                if free_end > cursor
+                    && largest.map_or(true, |l| l.1 - l.0 < free_end - cursor)
+                {
+                    largest = Some((cursor, free_end));
+                }
+                match next {
+                    Some(region) => cursor = region.end,
+                    None => break,
+                }
+            }
+        }
+        largest
+    }
+}
+
+impl fmt::Display for HostMemoryMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (start, end) in self.usable.iter() {
+            writeln!(f, "usable  0x{:016x}-0x{:016x}", start, end)?;
+        }
+        for region in self.regions() {
+            writeln!(
+                f,
+                "{}0x{:016x}-0x{:016x} {:?}",
+                if region.kind.placement() == Placement::Heap {
+                    "  "
+                } else {
+                    ""
+                },
+                region.start,
+                region.end,
+                region.kind
+            )?;
+        }
+        Ok(())
+    }
+}
+
+static HOST_MEMORY: Mutex<Option<HostMemoryMap>> = Mutex::new(None);
+
+/// Make `map` the registry of host memory
+pub fn init(map: HostMemoryMap) {
+    *HOST_MEMORY.lock() = Some(map);
+}
+
+/// Record that `size` bytes at `start` are used for `kind`
+///
+/// Nothing is recorded before the registry is initialized (as in unit
+/// tests).
+pub fn reserve(start: u64, size: u64, kind: HostRegionKind) -> Result<()> {
+    match HOST_MEMORY.lock().as_mut() {
+        Some(map) => map.reserve(start, start + size, kind),
+        None => Ok(()),
+    }
+}
+
+/// Remove the region of `kind` at `start` from the registry
+pub fn release(start: u64, kind: HostRegionKind) -> Result<()> {
+    match HOST_MEMORY.lock().as_mut() {
+        Some(map) => map.release(start, kind),
+        None => Ok(()),
+    }
+}
+
+/// A zeroed page allocated from the heap, which is registered as `kind`
+/// for as long as it lives
+pub struct HostPage {
+    page: Box<Raw4kPage>,
+    kind: HostRegionKind,
+}
+
+impl HostPage {
+    pub fn new(kind: HostRegionKind) -> Result<Self> {
+        let page = Box::new(Raw4kPage::default());
+        reserve(&*page as *const Raw4kPage as u64, 4096, kind)?;
+        Ok(Self { page, kind })
+    }
+
+    /// The host physical address of the page
+    pub fn address(&self) -> u64 {
+        &*self.page as *const Raw4kPage as u64
+    }
+}
+
+impl Deref for HostPage {
+    type Target = Raw4kPage;
+
+    fn deref(&self) -> &Raw4kPage {
+        &self.page
+    }
+}
+
+impl DerefMut for HostPage {
+    fn deref_mut(&mut self) -> &mut Raw4kPage {
+        &mut self.page
+    }
+}
+
+impl Drop for HostPage {
+    fn drop(&mut self) {
+        if let Err(e) = release(self.address(), self.kind) {
+            warn!("Failed to release host page: {:?}", e);
+        }
+    }
+}
+
+/// The `hostmem` management console command
+pub fn hostmem_command(
+    out: &mut dyn fmt::Write,
+    _args: &[&str],
+) -> fmt::Result {
+    match HOST_MEMORY.lock().as_ref() {
+        Some(map) => write!(out, "{}", map),
+        None => writeln!(out, "The host memory map is not initialized"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_map() -> HostMemoryMap {
+        let usable =
+            [(0x100000, 0x800000), (0x0, 0x9fc00), (0x400000, 0x1000000)];
+        let mut map = HostMemoryMap::new(usable.iter().copied()).unwrap();
+        map.reserve(0x0, 0x200000, HostRegionKind::HypervisorImage)
+            .unwrap();
+        map.reserve(0x300000, 0x380000, HostRegionKind::BootModule)
+            .unwrap();
+        map
+    }
+
+    #[test]
+    fn test_host_memory_heap() {
+        let mut map = test_map();
+        assert_eq!(map.usable(), &[(0x0, 0x9fc00), (0x100000, 0x1000000)]);
+        assert_eq!(map.largest_free(), Some((0x380000, 0x1000000)));
+
+        // The heap must be usable memory that is not otherwise reserved
+        assert!(map
+            .reserve(0xa0000, 0x200000, HostRegionKind::Heap)
+            .is_err());
+        assert!(map
+            .reserve(0x200000, 0x380000, HostRegionKind::Heap)
+            .is_err());
+        map.reserve(0x380000, 0x1000000, HostRegionKind::Heap)
+            .unwrap();
+        assert_eq!(map.largest_free(), Some((0x200000, 0x300000)));
+    }
+
+    #[test]
+    fn test_host_memory_allocations() {
+        let mut map = test_map();
+        map.reserve(0x380000, 0x1000000, HostRegionKind::Heap)
+            .unwrap();
+
+        // Allocations are within the heap, and do not overlap
+        map.reserve(0x400000, 0x401000, HostRegionKind::Vmcs)
+            .unwrap();
+        assert!(map
+            .reserve(0x400800, 0x401800, HostRegionKind::ApicPage)
+            .is_err());
+        assert!(map
+            .reserve(0x200000, 0x201000, HostRegionKind::DmaBuffer)
+            .is_err());
+        map.release(0x400000, HostRegionKind::Vmcs).unwrap();
+        assert!(map.release(0x400000, HostRegionKind::Vmcs).is_err());
+        map.reserve(0x400800, 0x401800, HostRegionKind::ApicPage)
+            .unwrap();
+
+        // Device registers are outside usable memory
+        assert!(map
+            .reserve(0xfff000, 0x1001000, HostRegionKind::DeviceRegisters)
+            .is_err());
+        map.reserve(0xfed90000, 0xfed91000, HostRegionKind::DeviceRegisters)
+            .unwrap();
+        assert_eq!(map.regions().len(), 5);
+    }
+}
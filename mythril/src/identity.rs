@@ -0,0 +1,162 @@
+//! The names and UUIDs of virtual machines
+//!
+//! Every VM has a name (by default, `vm<N>` for the VM whose first core is
+//! `N`) and a UUID. Unless one is configured, the UUID is derived from the
+//! name, so it is stable across boots of the same configuration. The UUID
+//! is reported to the guest as its SMBIOS system UUID and through the
+//! `HC_MYTHRIL_VM_UUID` hypercall. Random UUIDs (e.g., for objects that
+//! are not tied to a configuration) can be generated with `Uuid::random`.
+
+use crate::error::{Error, Result};
+use crate::rng;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// The longest name of a VM
+pub const MAX_NAME_LEN: usize = 32;
+
+/// Returns an error if `name` is not a valid VM name
+///
+/// Names are used as management console arguments, so they may only
+/// contain ASCII letters, digits, '-', '_' and '.'. They cannot be a
+/// number, so they are not mistaken for a VM id.
+pub fn validate_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(&b))
+        && name.parse::<u32>().is_err();
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::InvalidValue(format!("Invalid VM name '{}'", name)))
+    }
+}
+
+/// A UUID, stored in its big-endian (RFC 4122) byte order
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Uuid(pub [u8; 16]);
+
+impl Uuid {
+    /// Parse a UUID in the usual hyphenated form
+    /// (e.g., '6e5c9ba2-93f1-4d4a-8e0c-2b7d1f3a9c01')
+    pub fn parse(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidValue(format!("Invalid UUID '{}'", s));
+        let groups = s.split('-').map(|group| group.len()).collect::<Vec<_>>();
+        if groups != [8, 4, 4, 4, 12] {
+            return Err(invalid());
+        }
+
+        let digits = s.bytes().filter(|b| *b != b'-').collect::<Vec<_>>();
+        let mut bytes = [0u8; 16];
+        for (byte, pair) in bytes.iter_mut().zip(digits.chunks(2)) {
+            let pair = core::str::from_utf8(pair).map_err(|_| invalid())?;
+            *byte = u8::from_str_radix(pair, 16).map_err(|_| invalid())?;
+        }
+        Ok(Uuid(bytes))
+    }
+
+    /// A stable UUID for the VM named `name`
+    ///
+    /// The name is hashed (with two rounds of 64-bit FNV-1a) and marked as
+    /// an RFC 4122 variant, version 8 (custom) UUID.
+    pub fn from_name(name: &str) -> Self {
+        const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+        const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+        let hash = |seed: u64| {
+            b"mythril-vm:"
+                .iter()
+                .chain(name.as_bytes())
+                .fold(seed, |hash, b| {
+                    (hash ^ *b as u64).wrapping_mul(FNV_PRIME)
+                })
+        };
+
+        let high = hash(FNV_OFFSET);
+        let low = hash(high);
+        let mut bytes = [0u8; 16];
+        bytes[..8].copy_from_slice(&high.to_be_bytes());
+        bytes[8..].copy_from_slice(&low.to_be_bytes());
+        bytes[6] = (bytes[6] & 0x0f) | 0x80;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        Uuid(bytes)
+    }
+
+    /// A random (version 4) UUID from the hypervisor's generator
+    pub fn random() -> Result<Self> {
+        let mut bytes = [0u8; 16];
+        rng::fill_bytes(&mut bytes)?;
+        Ok(Self::from_random_bytes(bytes))
+    }
+
+    /// Mark 16 random `bytes` as an RFC 4122 variant, version 4 UUID
+    pub fn from_random_bytes(mut bytes: [u8; 16]) -> Self {
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        Uuid(bytes)
+    }
+
+    /// The UUID as two 64-bit halves (each in little-endian byte order),
+    /// as returned by `HC_MYTHRIL_VM_UUID`
+    pub fn halves(&self) -> (u64, u64) {
+        let mut high = [0u8; 8];
+        let mut low = [0u8; 8];
+        high.copy_from_slice(&self.0[..8]);
+        low.copy_from_slice(&self.0[8..]);
+        (u64::from_le_bytes(high), u64::from_le_bytes(low))
+    }
+}
+
+impl fmt::Display for Uuid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if i == 4 || i == 6 || i == 8 || i == 10 {
+                write!(f, "-")?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_uuid_parse() {
+        let text = "6e5c9ba2-93f1-4d4a-8e0c-2b7d1f3a9c01";
+        let uuid = Uuid::parse(text).unwrap();
+        assert_eq!(uuid.0[0], 0x6e);
+        assert_eq!(uuid.0[15], 0x01);
+        assert_eq!(format!("{}", uuid), text);
+        assert_eq!(uuid.halves().0 & 0xff, 0x6e);
+
+        assert!(Uuid::parse("6e5c9ba2-93f1-4d4a-8e0c2b7d1f3a9c01").is_err());
+        assert!(Uuid::parse("6e5c9ba2-93f1-4d4a-8e0c-2b7d1f3a9cxx").is_err());
+    }
+
+    #[test]
+    fn test_uuid_from_name() {
+        let uuid = Uuid::from_name("web");
+        assert_eq!(uuid, Uuid::from_name("web"));
+        assert_ne!(uuid, Uuid::from_name("db"));
+        assert_eq!(uuid.0[6] >> 4, 8);
+        assert_eq!(uuid.0[8] >> 6, 0b10);
+    }
+
+    #[test]
+    fn test_uuid_from_random_bytes() {
+        let uuid = Uuid::from_random_bytes([0xff; 16]);
+        assert_eq!(format!("{}", uuid), "ffffffff-ffff-4fff-bfff-ffffffffffff");
+    }
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name("web-1.prod").is_ok());
+        assert!(validate_name("").is_err());
+        assert!(validate_name("12").is_err());
+        assert!(validate_name("a b").is_err());
+    }
+}
@@ -1,8 +1,12 @@
 pub mod idt;
+pub mod pending;
+pub mod posted;
 
 pub const UART_VECTOR: u8 = 36;
 pub const TIMER_VECTOR: u8 = 48;
 pub const IPC_VECTOR: u8 = 49;
+pub const IOMMU_FAULT_VECTOR: u8 = 50;
+pub const POSTED_INTERRUPT_VECTOR: u8 = 51;
 
 pub unsafe fn enable_interrupts() {
     llvm_asm!("sti" :::: "volatile");
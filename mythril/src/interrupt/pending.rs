@@ -0,0 +1,231 @@
+//! Events waiting to be injected in to a guest
+//!
+//! A `VCpu` queues every event it must deliver (from devices, timers, other
+//! cores or its own exception handling) in `PendingEvents`, and injects at
+//! most one of them on each VM entry. Events are taken in the priority
+//! order of the processor (see Intel SDM Vol. 3A § 6.9): exceptions (and
+//! other events that are not maskable) first, then NMIs, then external
+//! interrupts. Events of the same class are taken in the order they were
+//! queued.
+//!
+//! Events are only merged where the processor would merge them. A second
+//! NMI queued while one is pending is dropped (the processor latches a
+//! single pending NMI), and an external interrupt whose vector is already
+//! pending is absorbed (as by the IRR of a local APIC). Exceptions are
+//! never merged, and keep their error codes.
+
+use crate::vcpu::InjectedInterruptType;
+use alloc::collections::vec_deque::VecDeque;
+
+// The fields of the VM-entry interruption-information field
+const ENTRY_INFO_VALID: u64 = 1 << 31;
+const ENTRY_INFO_ERROR_CODE: u64 = 1 << 11;
+const ENTRY_INFO_TYPE_SHIFT: u64 = 8;
+
+/// An event to inject in to a guest
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PendingEvent {
+    pub vector: u8,
+    pub kind: InjectedInterruptType,
+
+    /// The error code pushed by the guest's handler (for exceptions that
+    /// have one)
+    pub error_code: Option<u32>,
+}
+
+impl PendingEvent {
+    /// An event without an error code
+    pub fn new(vector: u8, kind: InjectedInterruptType) -> Self {
+        Self {
+            vector,
+            kind,
+            error_code: None,
+        }
+    }
+
+    /// A hardware exception, delivered with `error_code` if it has one
+    pub fn exception(vector: u8, error_code: Option<u32>) -> Self {
+        Self {
+            vector,
+            kind: InjectedInterruptType::HardwareException,
+            error_code,
+        }
+    }
+
+    /// Returns true if the guest can hold this event back by clearing
+    /// RFLAGS.IF (or with its task priority)
+    pub fn is_maskable(&self) -> bool {
+        self.kind == InjectedInterruptType::ExternalInterrupt
+    }
+
+    /// The value of the VM-entry interruption-information field that
+    /// injects this event
+    pub fn entry_info(&self) -> u64 {
+        let mut info = ENTRY_INFO_VALID
+            | (self.kind as u64) << ENTRY_INFO_TYPE_SHIFT
+            | self.vector as u64;
+        if self.error_code.is_some() {
+            info |= ENTRY_INFO_ERROR_CODE;
+        }
+        info
+    }
+}
+
+/// The events pending for a `VCpu`, in delivery order
+#[derive(Debug, Default)]
+pub struct PendingEvents {
+    exceptions: VecDeque<PendingEvent>,
+    nmi: Option<PendingEvent>,
+    interrupts: VecDeque<PendingEvent>,
+}
+
+impl PendingEvents {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue `event`, merging it with a pending event only where the
+    /// processor would
+    pub fn push(&mut self, event: PendingEvent) {
+        match event.kind {
+            InjectedInterruptType::NonMaskableInterrupt => {
+                if self.nmi.is_none() {
+                    self.nmi = Some(event);
+                }
+            }
+            InjectedInterruptType::ExternalInterrupt => {
+                if !self.interrupts.iter().any(|i| i.vector == event.vector) {
+                    self.interrupts.push_back(event);
+                }
+            }
+            _ => self.exceptions.push_back(event),
+        }
+    }
+
+    /// The number of pending events
+    pub fn len(&self) -> usize {
+        self.exceptions.len() + self.nmi.iter().count() + self.interrupts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The pending events, in delivery order
+    pub fn iter(&self) -> impl Iterator<Item = &PendingEvent> {
+        self.exceptions
+            .iter()
+            .chain(self.nmi.iter())
+            .chain(self.interrupts.iter())
+    }
+
+    /// The vectors of the pending external interrupts
+    pub fn interrupt_vectors<'a>(&'a self) -> impl Iterator<Item = u8> + 'a {
+        self.interrupts.iter().map(|event| event.vector)
+    }
+
+    /// Take the first event (in delivery order) for which `deliverable`
+    /// returns true
+    ///
+    /// An event that cannot be delivered yet does not hold back those of
+    /// lower priority (e.g., an NMI blocked by a previous NMI does not
+    /// block external interrupts).
+    pub fn take<F>(&mut self, deliverable: F) -> Option<PendingEvent>
+    where
+        F: Fn(&PendingEvent) -> bool,
+    {
+        if let Some(pos) = self.exceptions.iter().position(&deliverable) {
+            return self.exceptions.remove(pos);
+        }
+        if self.nmi.as_ref().map_or(false, &deliverable) {
+            return self.nmi.take();
+        }
+        let pos = self.interrupts.iter().position(&deliverable)?;
+        self.interrupts.remove(pos)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn interrupt(vector: u8) -> PendingEvent {
+        PendingEvent::new(vector, InjectedInterruptType::ExternalInterrupt)
+    }
+
+    fn nmi() -> PendingEvent {
+        PendingEvent::new(2, InjectedInterruptType::NonMaskableInterrupt)
+    }
+
+    #[test]
+    fn test_delivery_order() {
+        let mut events = PendingEvents::new();
+        events.push(interrupt(0x40));
+        events.push(interrupt(0x30));
+        events.push(nmi());
+        events.push(PendingEvent::exception(14, Some(2)));
+        events.push(PendingEvent::exception(18, None));
+        assert_eq!(events.len(), 5);
+
+        let all = |_: &PendingEvent| true;
+        assert_eq!(
+            events.take(all),
+            Some(PendingEvent::exception(14, Some(2)))
+        );
+        assert_eq!(events.take(all), Some(PendingEvent::exception(18, None)));
+        assert_eq!(events.take(all), Some(nmi()));
+        assert_eq!(events.take(all), Some(interrupt(0x40)));
+        assert_eq!(events.take(all), Some(interrupt(0x30)));
+        assert_eq!(events.take(all), None);
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn test_coalescing() {
+        let mut events = PendingEvents::new();
+        events.push(nmi());
+        events.push(nmi());
+        events.push(interrupt(0x30));
+        events.push(interrupt(0x30));
+        events.push(PendingEvent::exception(13, Some(0)));
+        events.push(PendingEvent::exception(13, Some(0)));
+
+        // Both exceptions are kept, and the same vector may be pending as an
+        // exception and as an interrupt
+        events.push(PendingEvent::new(
+            0x30,
+            InjectedInterruptType::HardwareException,
+        ));
+        assert_eq!(events.len(), 5);
+        assert_eq!(events.interrupt_vectors().collect::<Vec<_>>(), [0x30]);
+    }
+
+    #[test]
+    fn test_blocked_events() {
+        let mut events = PendingEvents::new();
+        events.push(nmi());
+        events.push(interrupt(0x30));
+        events.push(interrupt(0x31));
+
+        // A blocked NMI does not hold back a deliverable interrupt
+        let unblocked =
+            |event: &PendingEvent| event.is_maskable() && event.vector != 0x30;
+        assert_eq!(events.take(unblocked), Some(interrupt(0x31)));
+        assert_eq!(events.take(unblocked), None);
+        assert_eq!(
+            events.iter().cloned().collect::<Vec<_>>(),
+            [nmi(), interrupt(0x30)]
+        );
+    }
+
+    #[test]
+    fn test_entry_info() {
+        assert_eq!(interrupt(0x30).entry_info(), 0x80000030);
+        assert_eq!(nmi().entry_info(), 0x80000202);
+        assert_eq!(
+            PendingEvent::exception(14, Some(0)).entry_info(),
+            0x80000b0e
+        );
+    }
+}
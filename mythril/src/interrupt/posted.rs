@@ -0,0 +1,168 @@
+//! Posted-interrupt descriptors
+//!
+//! A posted-interrupt descriptor records interrupts that are pending for a
+//! `VCpu`. Interrupts may be posted by the remapping hardware (for MSIs from
+//! assigned devices) or by software, after which a notification vector is
+//! sent to the core running the `VCpu`. If the core is in guest mode (and
+//! supports posted-interrupt processing), the interrupts are delivered to
+//! the guest without a VMEXIT. Otherwise, the notification arrives as a
+//! normal host interrupt and the pending interrupts are injected.
+//!
+//! See Intel SDM Vol. 3C § 29.6 and VT-d § 9.11.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// Control word fields
+const OUTSTANDING_NOTIFICATION: u64 = 1 << 0;
+const SUPPRESS_NOTIFICATION: u64 = 1 << 1;
+const NOTIFICATION_VECTOR_SHIFT: u64 = 16;
+const NOTIFICATION_DESTINATION_SHIFT: u64 = 32;
+
+/// A posted-interrupt descriptor
+#[repr(C, align(64))]
+pub struct PostedInterruptDescriptor {
+    /// One bit for each of the 256 vectors
+    requests: [AtomicU64; 4],
+    control: AtomicU64,
+    _reserved: [u64; 3],
+}
+
+impl PostedInterruptDescriptor {
+    /// Create a descriptor that notifies the core with the (x2APIC)
+    /// `apic_id` using `vector`
+    pub fn new(vector: u8, apic_id: u32) -> Self {
+        Self {
+            requests: [
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+            ],
+            control: AtomicU64::new(
+                (vector as u64) << NOTIFICATION_VECTOR_SHIFT
+                    | (apic_id as u64) << NOTIFICATION_DESTINATION_SHIFT,
+            ),
+            _reserved: [0; 3],
+        }
+    }
+
+    /// The physical address of this descriptor
+    pub fn address(&self) -> u64 {
+        self as *const _ as u64
+    }
+
+    /// The vector used to notify the destination core
+    pub fn notification_vector(&self) -> u8 {
+        (self.control.load(Ordering::SeqCst) >> NOTIFICATION_VECTOR_SHIFT) as u8
+    }
+
+    /// The (x2APIC) id of the core that is notified
+    pub fn destination(&self) -> u32 {
+        (self.control.load(Ordering::SeqCst) >> NOTIFICATION_DESTINATION_SHIFT)
+            as u32
+    }
+
+    /// Notify the core with the (x2APIC) `apic_id` (e.g., after the `VCpu`
+    /// has moved to it)
+    pub fn set_destination(&self, apic_id: u32) {
+        let mask = !((u32::MAX as u64) << NOTIFICATION_DESTINATION_SHIFT);
+        let mut control = self.control.load(Ordering::SeqCst);
+        loop {
+            let new = control & mask
+                | (apic_id as u64) << NOTIFICATION_DESTINATION_SHIFT;
+            match self.control.compare_exchange(
+                control,
+                new,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return,
+                Err(current) => control = current,
+            }
+        }
+    }
+
+    /// Set whether notifications are suppressed (interrupts are still
+    /// recorded in the descriptor)
+    pub fn set_suppressed(&self, suppressed: bool) {
+        if suppressed {
+            self.control
+                .fetch_or(SUPPRESS_NOTIFICATION, Ordering::SeqCst);
+        } else {
+            self.control
+                .fetch_and(!SUPPRESS_NOTIFICATION, Ordering::SeqCst);
+        }
+    }
+
+    /// Returns true if there are interrupts that have been posted but not
+    /// yet processed
+    pub fn is_outstanding(&self) -> bool {
+        self.control.load(Ordering::SeqCst) & OUTSTANDING_NOTIFICATION != 0
+    }
+
+    /// Record `vector` as pending
+    ///
+    /// Returns true if the caller must send the notification vector to the
+    /// destination core.
+    pub fn post(&self, vector: u8) -> bool {
+        self.requests[vector as usize / 64]
+            .fetch_or(1 << (vector % 64), Ordering::SeqCst);
+
+        let control = self.control.load(Ordering::SeqCst);
+        if control & SUPPRESS_NOTIFICATION != 0 {
+            return false;
+        }
+        let previous = self
+            .control
+            .fetch_or(OUTSTANDING_NOTIFICATION, Ordering::SeqCst);
+        previous & OUTSTANDING_NOTIFICATION == 0
+    }
+
+    /// Remove and return all of the pending vectors (from lowest to highest)
+    pub fn take_pending(&self) -> impl Iterator<Item = u8> {
+        self.control
+            .fetch_and(!OUTSTANDING_NOTIFICATION, Ordering::SeqCst);
+        let mut pending = [0u64; 4];
+        for (word, requests) in pending.iter_mut().zip(self.requests.iter()) {
+            *word = requests.swap(0, Ordering::SeqCst);
+        }
+        (0..=255u8).filter(move |vector| {
+            pending[*vector as usize / 64] & (1 << (vector % 64)) != 0
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn test_post_and_take() {
+        let desc = PostedInterruptDescriptor::new(51, 3);
+        assert_eq!(desc.notification_vector(), 51);
+        assert_eq!(desc.address() % 64, 0);
+        assert_eq!(desc.destination(), 3);
+
+        // Only the first post requires a notification
+        assert!(desc.post(0x41));
+        assert!(!desc.post(0xa0));
+        assert!(desc.is_outstanding());
+
+        let pending = desc.take_pending().collect::<Vec<_>>();
+        assert_eq!(pending, vec![0x41, 0xa0]);
+        assert!(!desc.is_outstanding());
+        assert_eq!(desc.take_pending().count(), 0);
+
+        // Suppressed interrupts are recorded without a notification
+        desc.set_suppressed(true);
+        assert!(!desc.post(0x30));
+        assert_eq!(desc.take_pending().collect::<Vec<_>>(), vec![0x30]);
+
+        // Moving the destination keeps the vector and the other controls
+        desc.set_destination(0x1_0001);
+        assert_eq!(desc.destination(), 0x1_0001);
+        assert_eq!(desc.notification_vector(), 51);
+        assert!(!desc.post(0x31));
+    }
+}
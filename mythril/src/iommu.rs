@@ -0,0 +1,1238 @@
+//! # Support for Intel VT-d DMA remapping hardware
+//!
+//! The remapping units are discovered through the ACPI DMAR table. Each
+//! unit is configured to deliver its fault events to the BSP, where the
+//! recorded faults are decoded, attributed to the VM that owns the
+//! faulting device and (depending on the `FaultPolicy` of the device)
+//! the device is quarantined.
+//!
+//! Devices attached to a VM translate DMA through the VM's guest physical
+//! address space. Where the remapping unit can walk the EPT directly (it
+//! supports 4-level tables and its page walks are cache coherent), the
+//! context entry points at the EPT itself, so the two can never drift apart.
+//! Otherwise a mirror of the EPT is maintained in the second-level format
+//! supported by the unit. In both cases the IOTLB is invalidated whenever a
+//! mapping of the guest address space changes.
+//!
+//! Where supported, interrupt remapping is also enabled so MSIs from
+//! assigned devices can be posted directly to a `VCpu` (see
+//! `interrupt::posted`).
+
+use crate::acpi::dmar::{RemappingStructure, DMAR};
+use crate::error::{Error, Result};
+use crate::hostmem::{self, HostRegionKind};
+use crate::interrupt::posted::PostedInterruptDescriptor;
+use crate::lock::ro_after_init::RoAfterInit;
+use crate::memory::{
+    GuestAddressSpace, GuestPhysAddr, HostPhysAddr, HostPhysFrame,
+    MappingObserver,
+};
+use alloc::boxed::Box;
+use alloc::collections::btree_map::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use arrayvec::ArrayVec;
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::Mutex;
+use x86::io::{inw, outl, outw};
+
+const MAX_IOMMU_COUNT: usize = 16;
+
+// The size of the register block of a remapping unit
+const REGISTER_SIZE: u64 = 0x1000;
+
+/// Remapping unit registers
+///
+/// See `VT-d § 10.4`.
+mod reg {
+    /// Version Register
+    pub const VER: usize = 0x00;
+    /// Capability Register
+    pub const CAP: usize = 0x08;
+    /// Extended Capability Register
+    pub const ECAP: usize = 0x10;
+    /// Global Command Register
+    pub const GCMD: usize = 0x18;
+    /// Global Status Register
+    pub const GSTS: usize = 0x1c;
+    /// Root Table Address Register
+    pub const RTADDR: usize = 0x20;
+    /// Context Command Register
+    pub const CCMD: usize = 0x28;
+    /// Fault Status Register
+    pub const FSTS: usize = 0x34;
+    /// Fault Event Control Register
+    pub const FECTL: usize = 0x38;
+    /// Fault Event Data Register
+    pub const FEDATA: usize = 0x3c;
+    /// Fault Event Address Register
+    pub const FEADDR: usize = 0x40;
+    /// Fault Event Upper Address Register
+    pub const FEUADDR: usize = 0x44;
+    /// Invalidation Queue Tail Register
+    pub const IQT: usize = 0x88;
+    /// Invalidation Queue Address Register
+    pub const IQA: usize = 0x90;
+    /// Interrupt Remapping Table Address Register
+    pub const IRTA: usize = 0xb8;
+}
+
+// Fault status bits (these are write-1-to-clear)
+const FSTS_PRIMARY_FAULT_OVERFLOW: u32 = 1 << 0;
+const FSTS_PRIMARY_PENDING_FAULT: u32 = 1 << 1;
+
+const FECTL_INTERRUPT_MASK: u32 = 1 << 31;
+
+// Global command/status bits
+const GCMD_TRANSLATION_ENABLE: u32 = 1 << 31;
+const GCMD_SET_ROOT_TABLE: u32 = 1 << 30;
+const GCMD_QUEUED_INVALIDATION_ENABLE: u32 = 1 << 26;
+const GCMD_INTERRUPT_REMAPPING_ENABLE: u32 = 1 << 25;
+const GCMD_SET_INTERRUPT_TABLE: u32 = 1 << 24;
+const GCMD_COMPATIBILITY_FORMAT_INTERRUPT: u32 = 1 << 23;
+const GSTS_ONE_SHOT_MASK: u32 = 0x96ffffff;
+
+// Register based invalidation
+const CCMD_INVALIDATE: u64 = 1 << 63;
+const CCMD_GLOBAL: u64 = 0b01 << 61;
+const IOTLB_INVALIDATE: u64 = 1 << 63;
+const IOTLB_GLOBAL: u64 = 0b01 << 60;
+const IOTLB_DOMAIN: u64 = 0b10 << 60;
+
+// The supported adjusted guest address widths (in CAP.SAGAW)
+const SAGAW_39_BIT: u64 = 1 << 1;
+const SAGAW_48_BIT: u64 = 1 << 2;
+
+// CAP.CM: the unit may cache non-present or erroneous entries
+const CAP_CACHING_MODE: u64 = 1 << 7;
+
+// CAP.PI: the unit supports posted interrupts
+const CAP_POSTED_INTERRUPTS: u64 = 1 << 59;
+
+// ECAP.C: page walks snoop the processor caches
+const ECAP_COHERENT: u64 = 1 << 0;
+const ECAP_QUEUED_INVALIDATION: u64 = 1 << 1;
+const ECAP_INTERRUPT_REMAPPING: u64 = 1 << 3;
+
+// Invalidation queue descriptors (the queue holds 256 descriptors)
+const DESCRIPTOR_CONTEXT_CACHE: u64 = 0x1;
+const DESCRIPTOR_IOTLB: u64 = 0x2;
+const DESCRIPTOR_INTERRUPT_ENTRY_CACHE: u64 = 0x4;
+const DESCRIPTOR_WAIT: u64 = 0x5;
+const DESCRIPTOR_GLOBAL: u64 = 0b01 << 4;
+const DESCRIPTOR_DOMAIN: u64 = 0b10 << 4;
+const IOTLB_DRAIN: u64 = 0b11 << 6;
+const WAIT_STATUS_WRITE: u64 = 1 << 5;
+const QUEUE_LENGTH: usize = 256;
+
+// Interrupt remapping table entries (the table holds 256 entries, and uses
+// x2APIC destination IDs)
+const IRTE_POSTED: u64 = 1 << 15;
+const IRTE_VERIFY_SOURCE_ID: u64 = 0b01 << 18;
+const IRTA_SIZE_256: u64 = 7;
+const IRTA_X2APIC_MODE: u64 = 1 << 11;
+const INTERRUPT_TABLE_LENGTH: usize = 256;
+
+// Remappable format MSI address fields
+const MSI_REMAPPABLE_FORMAT: u32 = 1 << 4;
+
+// Root and context entry fields
+const ENTRY_PRESENT: u64 = 1 << 0;
+const CONTEXT_ADDRESS_WIDTH_39_BIT: u64 = 1;
+const CONTEXT_ADDRESS_WIDTH_48_BIT: u64 = 2;
+
+// Second-level paging entry permissions
+const SL_READ: u64 = 1 << 0;
+const SL_WRITE: u64 = 1 << 1;
+const SL_ADDRESS_MASK: u64 = 0x000fffff_fffff000;
+
+// The fields of the upper half of a fault recording register
+const FRCD_FAULT: u64 = 1 << 63;
+const FRCD_TYPE_READ: u64 = 1 << 62;
+
+// The local APIC MSI address window
+const MSI_ADDRESS_BASE: u32 = 0xfee00000;
+
+// The legacy PCI config mechanism (used for the host PCI devices)
+const PCI_CONFIG_ADDRESS: u16 = 0xcf8;
+const PCI_CONFIG_DATA: u16 = 0xcfc;
+const PCI_COMMAND: u8 = 0x04;
+const PCI_COMMAND_BUS_MASTER: u16 = 1 << 2;
+const PCI_COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+static IOMMUS: RoAfterInit<ArrayVec<[Iommu; MAX_IOMMU_COUNT]>> =
+    RoAfterInit::uninitialized();
+
+static DEVICE_ASSIGNMENTS: RoAfterInit<
+    Mutex<BTreeMap<(u16, u16), DeviceAssignment>>,
+> = RoAfterInit::uninitialized();
+
+/// How to respond to DMA remapping faults caused by a device
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FaultPolicy {
+    /// Log the fault and leave the device running
+    Log,
+
+    /// Log the fault and stop the device from performing further DMA or
+    /// raising interrupts
+    Quarantine,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct DeviceAssignment {
+    vmid: u32,
+    policy: FaultPolicy,
+    quarantined: bool,
+}
+
+/// The kind of access that caused a DMA remapping fault
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FaultAccess {
+    /// A DMA read (or atomic operation)
+    Read,
+
+    /// A DMA write
+    Write,
+}
+
+/// A decoded primary fault record
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FaultRecord {
+    /// The PCI segment of the faulting device
+    pub segment: u16,
+
+    /// The requester ID (bus/device/function) of the faulting device
+    pub source_id: u16,
+
+    /// The (page aligned) address of the faulting request
+    pub address: u64,
+
+    /// The fault reason code (see `VT-d § 7.1.3`)
+    pub reason: u8,
+
+    /// The kind of the faulting request
+    pub access: FaultAccess,
+}
+
+impl FaultRecord {
+    /// Decode a raw fault recording register
+    ///
+    /// Returns `None` if the record does not hold a fault.
+    pub fn from_raw(segment: u16, low: u64, high: u64) -> Option<Self> {
+        if high & FRCD_FAULT == 0 {
+            return None;
+        }
+
+        Some(Self {
+            segment: segment,
+            source_id: (high & 0xffff) as u16,
+            address: low & !0xfff,
+            reason: ((high >> 32) & 0xff) as u8,
+            access: if high & FRCD_TYPE_READ != 0 {
+                FaultAccess::Read
+            } else {
+                FaultAccess::Write
+            },
+        })
+    }
+
+    /// A description of the fault reason
+    pub fn reason_str(&self) -> &'static str {
+        match self.reason {
+            0x01 => "root entry not present",
+            0x02 => "context entry not present",
+            0x03 => "invalid context entry",
+            0x04 => "address beyond the guest address width",
+            0x05 => "write to a read-only page",
+            0x06 => "read from a non-readable page",
+            0x07 => "paging entry not readable",
+            0x08 => "root table not readable",
+            0x09 => "context table not readable",
+            0x0a => "reserved field set in root entry",
+            0x0b => "reserved field set in context entry",
+            0x0c => "reserved field set in paging entry",
+            0x0d => "translation blocked by context entry",
+            _ => "unknown fault reason",
+        }
+    }
+}
+
+impl fmt::Display for FaultRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{} {:?} of 0x{:x}: {} (reason=0x{:x})",
+            self.segment,
+            self.source_id >> 8,
+            (self.source_id >> 3) & 0x1f,
+            self.source_id & 0b111,
+            self.access,
+            self.address,
+            self.reason_str(),
+            self.reason
+        )
+    }
+}
+
+/// A page of 512 translation structure entries
+///
+/// The root and context tables use 256 128-bit entries, which are stored as
+/// pairs of 64-bit values.
+#[repr(align(4096))]
+struct TablePage([u64; 512]);
+
+impl TablePage {
+    fn new() -> Box<Self> {
+        Box::new(TablePage([0u64; 512]))
+    }
+
+    fn address(&self) -> u64 {
+        self as *const _ as u64
+    }
+}
+
+// Ensure that an update to a translation structure is visible to a
+// remapping unit that does not snoop the processor caches
+fn flush_entry(entry: *const u64) {
+    unsafe { core::arch::x86_64::_mm_clflush(entry as *const u8) };
+}
+
+/// A second-level page table (in the format used by the remapping units)
+pub struct SecondLevelTable {
+    levels: u8,
+    coherent: bool,
+    root: Box<TablePage>,
+}
+
+impl SecondLevelTable {
+    /// Create an empty table with 3 (39-bit) or 4 (48-bit) levels
+    ///
+    /// If the table is not `coherent`, every modified entry is flushed from
+    /// the processor caches.
+    pub fn new(levels: u8, coherent: bool) -> Result<Self> {
+        if levels != 3 && levels != 4 {
+            return Err(Error::InvalidValue(format!(
+                "Unsupported second-level paging levels: {}",
+                levels
+            )));
+        }
+        Ok(Self {
+            levels: levels,
+            coherent: coherent,
+            root: TablePage::new(),
+        })
+    }
+
+    fn index(addr: u64, level: u8) -> usize {
+        ((addr >> (12 + 9 * (level as u64 - 1))) & 0x1ff) as usize
+    }
+
+    fn check_address(&self, addr: GuestPhysAddr) -> Result<()> {
+        if addr.as_u64() >> (12 + 9 * self.levels as u64) != 0 {
+            return Err(Error::InvalidValue(format!(
+                "Address 0x{:x} is beyond the {} level table",
+                addr.as_u64(),
+                self.levels
+            )));
+        }
+        Ok(())
+    }
+
+    fn set_entry(&self, entry: *mut u64, val: u64) {
+        unsafe { *entry = val };
+        if !self.coherent {
+            flush_entry(entry);
+        }
+    }
+
+    // Find the leaf entry for `addr`, optionally allocating the tables
+    // along the way. Returns a pointer to the entry.
+    fn walk(&mut self, addr: u64, allocate: bool) -> Option<*mut u64> {
+        let mut table = &mut *self.root as *mut TablePage;
+        for level in (2..=self.levels).rev() {
+            let entry = unsafe { &mut (*table).0[Self::index(addr, level)] };
+            if *entry & ENTRY_PRESENT == 0 {
+                if !allocate {
+                    return None;
+                }
+                let next = Box::into_raw(TablePage::new());
+                self.set_entry(entry, next as u64 | SL_READ | SL_WRITE);
+            }
+            table = (*entry & SL_ADDRESS_MASK) as *mut TablePage;
+        }
+        Some(unsafe { &mut (*table).0[Self::index(addr, 1)] as *mut u64 })
+    }
+
+    /// Map `addr` to `frame`
+    pub fn map(
+        &mut self,
+        addr: GuestPhysAddr,
+        frame: HostPhysFrame,
+        readonly: bool,
+    ) -> Result<()> {
+        self.check_address(addr)?;
+        let entry = self.walk(addr.as_u64(), true).unwrap();
+        let permissions = if readonly {
+            SL_READ
+        } else {
+            SL_READ | SL_WRITE
+        };
+        self.set_entry(entry, frame.start_address().as_u64() | permissions);
+        Ok(())
+    }
+
+    /// Remove the mapping for `addr`
+    pub fn unmap(&mut self, addr: GuestPhysAddr) -> Result<()> {
+        self.check_address(addr)?;
+        if let Some(entry) = self.walk(addr.as_u64(), false) {
+            self.set_entry(entry, 0);
+        }
+        Ok(())
+    }
+
+    /// The host frame mapped at `addr` (if any) and whether it is writable
+    pub fn translate(&mut self, addr: GuestPhysAddr) -> Option<(u64, bool)> {
+        let entry = unsafe { *self.walk(addr.as_u64(), false)? };
+        if entry & SL_READ == 0 {
+            return None;
+        }
+        Some((entry & SL_ADDRESS_MASK, entry & SL_WRITE != 0))
+    }
+
+    /// The address of the root of this table
+    pub fn root_address(&self) -> HostPhysAddr {
+        HostPhysAddr::new(self.root.address())
+    }
+}
+
+impl Drop for SecondLevelTable {
+    fn drop(&mut self) {
+        fn free(table: &TablePage, level: u8) {
+            if level == 1 {
+                return;
+            }
+            for entry in table.0.iter() {
+                if entry & ENTRY_PRESENT != 0 {
+                    let next = unsafe {
+                        Box::from_raw(
+                            (entry & SL_ADDRESS_MASK) as *mut TablePage,
+                        )
+                    };
+                    free(&next, level - 1);
+                }
+            }
+        }
+        free(&self.root, self.levels);
+    }
+}
+
+// The second-level translation for the devices attached to one VM. This is
+// registered as an observer of the VM's address space, so the translation
+// is kept in sync with the EPT.
+struct DomainObserver {
+    iommu: &'static Iommu,
+    domain_id: u16,
+
+    // The root of the shared EPT, or None if the translation is mirrored
+    shared_root: Option<HostPhysAddr>,
+    mirror: Option<Mutex<SecondLevelTable>>,
+}
+
+impl DomainObserver {
+    fn new(
+        iommu: &'static Iommu,
+        vmid: u32,
+        space: &GuestAddressSpace,
+    ) -> Result<Self> {
+        let domain_id = (vmid + 1) as u16;
+        if iommu.supports_shared_ept() {
+            return Ok(Self {
+                iommu: iommu,
+                domain_id: domain_id,
+                shared_root: Some(space.root_address()),
+                mirror: None,
+            });
+        }
+
+        let mut mirror = SecondLevelTable::new(
+            iommu.mirror_levels()?,
+            iommu.ecap & ECAP_COHERENT != 0,
+        )?;
+        for (addr, frame, readonly) in space.mappings() {
+            mirror.map(addr, frame, readonly)?;
+        }
+        info!(
+            "IOMMU cannot share the EPT of vm{}, mirroring it instead",
+            vmid
+        );
+        Ok(Self {
+            iommu: iommu,
+            domain_id: domain_id,
+            shared_root: None,
+            mirror: Some(Mutex::new(mirror)),
+        })
+    }
+
+    // The root of the second-level table and its address width (in the
+    // context entry format)
+    fn second_level_root(&self) -> (HostPhysAddr, u64) {
+        match (&self.shared_root, &self.mirror) {
+            (Some(root), _) => (*root, CONTEXT_ADDRESS_WIDTH_48_BIT),
+            (None, Some(mirror)) => {
+                let mirror = mirror.lock();
+                let width = if mirror.levels == 4 {
+                    CONTEXT_ADDRESS_WIDTH_48_BIT
+                } else {
+                    CONTEXT_ADDRESS_WIDTH_39_BIT
+                };
+                (mirror.root_address(), width)
+            }
+            (None, None) => unreachable!(),
+        }
+    }
+
+    fn invalidate(&self) {
+        let mut tables = self.iommu.tables.lock();
+        if tables.translation_enabled {
+            unsafe {
+                self.iommu
+                    .invalidate_iotlb(&mut tables, Some(self.domain_id))
+            };
+        }
+    }
+}
+
+impl MappingObserver for DomainObserver {
+    fn frame_mapped(
+        &self,
+        guest_addr: GuestPhysAddr,
+        host_frame: HostPhysFrame,
+        readonly: bool,
+    ) -> Result<()> {
+        if let Some(ref mirror) = self.mirror {
+            mirror.lock().map(guest_addr, host_frame, readonly)?;
+        }
+
+        // In caching mode, the unit may cache non-present entries
+        if self.iommu.cap & CAP_CACHING_MODE != 0 {
+            self.invalidate();
+        }
+        Ok(())
+    }
+
+    fn frame_unmapped(&self, guest_addr: GuestPhysAddr) -> Result<()> {
+        if let Some(ref mirror) = self.mirror {
+            mirror.lock().unmap(guest_addr)?;
+        }
+        self.invalidate();
+        Ok(())
+    }
+}
+
+// A queue of invalidation requests (used instead of the invalidation
+// registers once interrupt remapping is enabled)
+struct InvalidationQueue {
+    descriptors: Box<TablePage>,
+    tail: usize,
+    status: Box<AtomicU32>,
+}
+
+/// An MSI message (in remappable format) to program into a device
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MsiMessage {
+    pub address: u64,
+    pub data: u32,
+}
+
+impl MsiMessage {
+    /// The remappable format message that uses the interrupt remapping
+    /// table entry `handle`
+    pub fn remappable(handle: u16) -> Self {
+        let address = MSI_ADDRESS_BASE
+            | ((handle as u32) & 0x7fff) << 5
+            | MSI_REMAPPABLE_FORMAT
+            | ((handle as u32) >> 15) << 2;
+        Self {
+            address: address as u64,
+            data: 0,
+        }
+    }
+}
+
+// The translation structures shared by all devices behind a remapping unit
+struct RemappingTables {
+    root: Box<TablePage>,
+    context_tables: BTreeMap<u8, Box<TablePage>>,
+    translation_enabled: bool,
+
+    // The domains (one per VM) of the attached devices
+    domains: BTreeMap<u32, Arc<DomainObserver>>,
+
+    queue: Option<InvalidationQueue>,
+
+    // Each entry is 128 bits (so the page holds 256 entries)
+    interrupt_table: Option<Box<TablePage>>,
+}
+
+/// A DMA remapping hardware unit
+pub struct Iommu {
+    base: *mut u8,
+    segment: u16,
+    cap: u64,
+    ecap: u64,
+    tables: Mutex<RemappingTables>,
+}
+
+// The register block is only accessed through volatile reads and writes,
+// so the Iommu is Send/Sync even though it holds a raw pointer
+unsafe impl Send for Iommu {}
+unsafe impl Sync for Iommu {}
+
+impl Iommu {
+    unsafe fn new(register_base: u64, segment: u16) -> Result<Self> {
+        let mut iommu = Iommu {
+            base: register_base as *mut u8,
+            segment: segment,
+            cap: 0,
+            ecap: 0,
+            tables: Mutex::new(RemappingTables {
+                root: TablePage::new(),
+                context_tables: BTreeMap::new(),
+                translation_enabled: false,
+                domains: BTreeMap::new(),
+                queue: None,
+                interrupt_table: None,
+            }),
+        };
+
+        let version = iommu.read32(reg::VER);
+        if version == 0 || version == 0xffffffff {
+            return Err(Error::NotSupported);
+        }
+        iommu.cap = iommu.read64(reg::CAP);
+        iommu.ecap = iommu.read64(reg::ECAP);
+
+        hostmem::reserve(
+            register_base,
+            REGISTER_SIZE,
+            HostRegionKind::DeviceRegisters,
+        )?;
+        hostmem::reserve(
+            iommu.tables.lock().root.address(),
+            4096,
+            HostRegionKind::DmaBuffer,
+        )?;
+
+        info!(
+            "IOMMU: base=0x{:x} segment={} version={}.{} fault_records={}",
+            register_base,
+            segment,
+            (version >> 4) & 0xf,
+            version & 0xf,
+            iommu.fault_record_count()
+        );
+        Ok(iommu)
+    }
+
+    unsafe fn read32(&self, offset: usize) -> u32 {
+        ptr::read_volatile(self.base.add(offset) as *const u32)
+    }
+
+    unsafe fn write32(&self, offset: usize, val: u32) {
+        ptr::write_volatile(self.base.add(offset) as *mut u32, val)
+    }
+
+    unsafe fn read64(&self, offset: usize) -> u64 {
+        ptr::read_volatile(self.base.add(offset) as *const u64)
+    }
+
+    unsafe fn write64(&self, offset: usize, val: u64) {
+        ptr::write_volatile(self.base.add(offset) as *mut u64, val)
+    }
+
+    fn iotlb_offset(&self) -> usize {
+        ((self.ecap >> 8) & 0x3ff) as usize * 16 + 8
+    }
+
+    /// Returns true if this unit can walk the EPT structures directly
+    fn supports_shared_ept(&self) -> bool {
+        (self.cap >> 8) & SAGAW_48_BIT != 0 && self.ecap & ECAP_COHERENT != 0
+    }
+
+    // The number of levels to use for a mirrored second-level table
+    fn mirror_levels(&self) -> Result<u8> {
+        if (self.cap >> 8) & SAGAW_48_BIT != 0 {
+            Ok(4)
+        } else if (self.cap >> 8) & SAGAW_39_BIT != 0 {
+            Ok(3)
+        } else {
+            Err(Error::NotSupported)
+        }
+    }
+
+    // Issue a global command and wait for the status to reflect it
+    unsafe fn global_command(&self, command: u32) {
+        let status = self.read32(reg::GSTS) & GSTS_ONE_SHOT_MASK;
+        self.write32(reg::GCMD, status | command);
+        while self.read32(reg::GSTS) & command == 0 {
+            core::sync::atomic::spin_loop_hint();
+        }
+    }
+
+    // Submit an invalidation descriptor, and wait for it to complete
+    unsafe fn submit_invalidation(
+        &self,
+        queue: &mut InvalidationQueue,
+        descriptor: (u64, u64),
+    ) {
+        queue.status.store(0, Ordering::SeqCst);
+        let wait = (
+            DESCRIPTOR_WAIT | WAIT_STATUS_WRITE | 1 << 32,
+            &*queue.status as *const _ as u64,
+        );
+        for &(low, high) in [descriptor, wait].iter() {
+            queue.descriptors.0[queue.tail * 2] = low;
+            queue.descriptors.0[queue.tail * 2 + 1] = high;
+            self.flush_entry(&queue.descriptors.0[queue.tail * 2]);
+            queue.tail = (queue.tail + 1) % QUEUE_LENGTH;
+        }
+        self.write64(reg::IQT, (queue.tail as u64) << 4);
+        while queue.status.load(Ordering::SeqCst) == 0 {
+            core::sync::atomic::spin_loop_hint();
+        }
+    }
+
+    unsafe fn invalidate_context_cache(&self, tables: &mut RemappingTables) {
+        if let Some(ref mut queue) = tables.queue {
+            let descriptor = DESCRIPTOR_CONTEXT_CACHE | DESCRIPTOR_GLOBAL;
+            self.submit_invalidation(queue, (descriptor, 0));
+            return;
+        }
+        self.write64(reg::CCMD, CCMD_INVALIDATE | CCMD_GLOBAL);
+        while self.read64(reg::CCMD) & CCMD_INVALIDATE != 0 {
+            core::sync::atomic::spin_loop_hint();
+        }
+    }
+
+    unsafe fn invalidate_iotlb(
+        &self,
+        tables: &mut RemappingTables,
+        domain_id: Option<u16>,
+    ) {
+        if let Some(ref mut queue) = tables.queue {
+            let granularity = match domain_id {
+                Some(domain_id) => DESCRIPTOR_DOMAIN | (domain_id as u64) << 16,
+                None => DESCRIPTOR_GLOBAL,
+            };
+            let descriptor = DESCRIPTOR_IOTLB | IOTLB_DRAIN | granularity;
+            self.submit_invalidation(queue, (descriptor, 0));
+            return;
+        }
+
+        let granularity = match domain_id {
+            Some(domain_id) => IOTLB_DOMAIN | (domain_id as u64) << 32,
+            None => IOTLB_GLOBAL,
+        };
+        let offset = self.iotlb_offset();
+        self.write64(offset, IOTLB_INVALIDATE | granularity);
+        while self.read64(offset) & IOTLB_INVALIDATE != 0 {
+            core::sync::atomic::spin_loop_hint();
+        }
+    }
+
+    unsafe fn invalidate_interrupt_entries(
+        &self,
+        tables: &mut RemappingTables,
+    ) {
+        // Interrupt remapping is only enabled with queued invalidation
+        if let Some(ref mut queue) = tables.queue {
+            let descriptor = DESCRIPTOR_INTERRUPT_ENTRY_CACHE;
+            self.submit_invalidation(queue, (descriptor, 0));
+        }
+    }
+
+    /// Returns true if this unit can post interrupts to a `VCpu`
+    fn supports_posted_interrupts(&self) -> bool {
+        self.cap & CAP_POSTED_INTERRUPTS != 0
+            && self.ecap & ECAP_INTERRUPT_REMAPPING != 0
+            && self.ecap & ECAP_QUEUED_INVALIDATION != 0
+    }
+
+    // Switch to queued invalidation and enable interrupt remapping. Host
+    // interrupts in the compatibility format (e.g., from the IOAPIC) are
+    // still permitted.
+    unsafe fn enable_interrupt_remapping(&self) {
+        let mut tables = self.tables.lock();
+
+        let queue = InvalidationQueue {
+            descriptors: TablePage::new(),
+            tail: 0,
+            status: Box::new(AtomicU32::new(0)),
+        };
+        self.write64(reg::IQT, 0);
+        self.write64(reg::IQA, queue.descriptors.address());
+        self.global_command(GCMD_QUEUED_INVALIDATION_ENABLE);
+        tables.queue = Some(queue);
+
+        let interrupt_table = TablePage::new();
+        self.write64(
+            reg::IRTA,
+            interrupt_table.address() | IRTA_X2APIC_MODE | IRTA_SIZE_256,
+        );
+        self.global_command(GCMD_SET_INTERRUPT_TABLE);
+        tables.interrupt_table = Some(interrupt_table);
+        self.invalidate_interrupt_entries(&mut tables);
+
+        self.global_command(GCMD_COMPATIBILITY_FORMAT_INTERRUPT);
+        self.global_command(GCMD_INTERRUPT_REMAPPING_ENABLE);
+    }
+
+    fn post_interrupt(
+        &self,
+        source_id: u16,
+        vector: u8,
+        descriptor: &PostedInterruptDescriptor,
+    ) -> Result<MsiMessage> {
+        let mut tables = self.tables.lock();
+        let interrupt_table = tables
+            .interrupt_table
+            .as_mut()
+            .ok_or_else(|| Error::NotSupported)?;
+
+        let handle = (0..INTERRUPT_TABLE_LENGTH)
+            .find(|i| interrupt_table.0[i * 2] & ENTRY_PRESENT == 0)
+            .ok_or_else(|| {
+                Error::InvalidValue("No free interrupt remapping entry".into())
+            })?;
+
+        let address = descriptor.address();
+        let low = ENTRY_PRESENT
+            | IRTE_POSTED
+            | (vector as u64) << 16
+            | (address >> 6) << 38;
+        let high =
+            source_id as u64 | IRTE_VERIFY_SOURCE_ID | (address >> 32) << 32;
+        interrupt_table.0[handle * 2 + 1] = high;
+        interrupt_table.0[handle * 2] = low;
+        self.flush_entry(&interrupt_table.0[handle * 2]);
+
+        unsafe { self.invalidate_interrupt_entries(&mut tables) };
+        Ok(MsiMessage::remappable(handle as u16))
+    }
+
+    fn flush_entry(&self, entry: *const u64) {
+        if self.ecap & ECAP_COHERENT == 0 {
+            flush_entry(entry);
+        }
+    }
+
+    fn set_context_entry(
+        &self,
+        tables: &mut RemappingTables,
+        source_id: u16,
+        entry: Option<(u64, u64)>,
+    ) {
+        let bus = (source_id >> 8) as u8;
+        let devfn = (source_id & 0xff) as usize;
+
+        if !tables.context_tables.contains_key(&bus) {
+            let context_table = TablePage::new();
+            tables.root.0[bus as usize * 2] =
+                context_table.address() | ENTRY_PRESENT;
+            self.flush_entry(&tables.root.0[bus as usize * 2]);
+            tables.context_tables.insert(bus, context_table);
+        }
+
+        let context_table = tables.context_tables.get_mut(&bus).unwrap();
+        let (low, high) = entry.unwrap_or((0, 0));
+        context_table.0[devfn * 2 + 1] = high;
+        context_table.0[devfn * 2] = low;
+        self.flush_entry(&context_table.0[devfn * 2]);
+    }
+
+    fn attach(
+        &'static self,
+        source_id: u16,
+        vmid: u32,
+        space: &mut GuestAddressSpace,
+    ) -> Result<()> {
+        let mut tables = self.tables.lock();
+
+        let domain = match tables.domains.get(&vmid) {
+            Some(domain) => domain.clone(),
+            None => {
+                let domain = Arc::new(DomainObserver::new(self, vmid, space)?);
+                space.add_observer(domain.clone());
+                tables.domains.insert(vmid, domain.clone());
+                domain
+            }
+        };
+
+        let (root, address_width) = domain.second_level_root();
+        let low = root.as_u64() | ENTRY_PRESENT;
+        let high = address_width | (domain.domain_id as u64) << 8;
+        self.set_context_entry(&mut tables, source_id, Some((low, high)));
+
+        unsafe {
+            if !tables.translation_enabled {
+                self.write64(reg::RTADDR, tables.root.address());
+                self.global_command(GCMD_SET_ROOT_TABLE);
+                self.invalidate_context_cache(&mut tables);
+                self.invalidate_iotlb(&mut tables, None);
+
+                // From this point, any device behind this unit without a
+                // context entry is blocked from performing DMA
+                self.global_command(GCMD_TRANSLATION_ENABLE);
+                tables.translation_enabled = true;
+            } else {
+                self.invalidate_context_cache(&mut tables);
+                self.invalidate_iotlb(&mut tables, Some(domain.domain_id));
+            }
+        }
+        Ok(())
+    }
+
+    fn detach(&self, source_id: u16) {
+        let mut tables = self.tables.lock();
+        self.set_context_entry(&mut tables, source_id, None);
+        if tables.translation_enabled {
+            unsafe {
+                self.invalidate_context_cache(&mut tables);
+                self.invalidate_iotlb(&mut tables, None);
+            }
+        }
+    }
+
+    fn fault_record_offset(&self) -> usize {
+        ((self.cap >> 24) & 0x3ff) as usize * 16
+    }
+
+    fn fault_record_count(&self) -> usize {
+        ((self.cap >> 40) & 0xff) as usize + 1
+    }
+
+    /// Deliver the fault events of this unit to `vector` on `apic_id`
+    fn enable_fault_events(&self, vector: u8, apic_id: u8) {
+        unsafe {
+            self.write32(reg::FEDATA, vector as u32);
+            self.write32(
+                reg::FEADDR,
+                MSI_ADDRESS_BASE | (apic_id as u32) << 12,
+            );
+            self.write32(reg::FEUADDR, 0);
+
+            // Faults may have been recorded before the event was configured
+            self.take_faults();
+            self.write32(
+                reg::FECTL,
+                self.read32(reg::FECTL) & !FECTL_INTERRUPT_MASK,
+            );
+        }
+    }
+
+    /// Read and clear all of the recorded faults of this unit
+    pub fn take_faults(&self) -> Vec<FaultRecord> {
+        let mut faults = vec![];
+        for i in 0..self.fault_record_count() {
+            let offset = self.fault_record_offset() + i * 16;
+            unsafe {
+                let high = self.read64(offset + 8);
+                let low = self.read64(offset);
+                if let Some(fault) =
+                    FaultRecord::from_raw(self.segment, low, high)
+                {
+                    faults.push(fault);
+
+                    // The F bit is write-1-to-clear
+                    self.write32(offset + 12, (FRCD_FAULT >> 32) as u32);
+                }
+            }
+        }
+
+        unsafe {
+            let status = self.read32(reg::FSTS);
+            if status & FSTS_PRIMARY_FAULT_OVERFLOW != 0 {
+                warn!("IOMMU: fault records overflowed, faults were lost");
+            }
+            self.write32(
+                reg::FSTS,
+                status
+                    & (FSTS_PRIMARY_FAULT_OVERFLOW
+                        | FSTS_PRIMARY_PENDING_FAULT),
+            );
+        }
+        faults
+    }
+}
+
+/// Initialize the remapping units described by `dmar`
+///
+/// Fault events will be delivered to `fault_vector` on the core with the
+/// given `apic_id`. If no DMAR is present, there are no remapping units but
+/// devices may still be assigned to VMs.
+pub unsafe fn init_iommus(
+    dmar: Option<&DMAR>,
+    fault_vector: u8,
+    apic_id: u8,
+) -> Result<()> {
+    let mut iommus = ArrayVec::new();
+    if let Some(dmar) = dmar {
+        for structure in dmar.structures() {
+            match structure {
+                Ok(RemappingStructure::HardwareUnit {
+                    segment,
+                    register_base,
+                    ..
+                }) => match Iommu::new(register_base, segment) {
+                    Ok(iommu) => {
+                        iommu.enable_fault_events(fault_vector, apic_id);
+                        if iommu.supports_posted_interrupts() {
+                            iommu.enable_interrupt_remapping();
+                        }
+                        iommus.try_push(iommu).map_err(|_| {
+                            Error::InvalidValue(format!(
+                                "Too many IOMMUs (max={})",
+                                MAX_IOMMU_COUNT
+                            ))
+                        })?;
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Invalid IOMMU at 0x{:x}: {:?}",
+                            register_base, e
+                        );
+                    }
+                },
+                Ok(_) => (),
+                Err(e) => warn!("Invalid DMAR remapping structure: {:?}", e),
+            }
+        }
+    } else {
+        info!("No DMAR found, DMA remapping is unavailable");
+    }
+
+    RoAfterInit::init(&IOMMUS, iommus);
+    RoAfterInit::init(&DEVICE_ASSIGNMENTS, Mutex::new(BTreeMap::new()));
+    Ok(())
+}
+
+/// Record that the host device `source_id` in `segment` is owned by the VM
+/// `vmid`, so any DMA faults it causes are attributed to (and contained
+/// within) that VM
+pub fn assign_device(
+    segment: u16,
+    source_id: u16,
+    vmid: u32,
+    policy: FaultPolicy,
+) -> Result<()> {
+    let mut assignments = DEVICE_ASSIGNMENTS.lock();
+    if let Some(existing) = assignments.get(&(segment, source_id)) {
+        if existing.quarantined {
+            return Err(Error::InvalidValue(format!(
+                "Device 0x{:x} is quarantined",
+                source_id
+            )));
+        }
+    }
+    assignments.insert(
+        (segment, source_id),
+        DeviceAssignment {
+            vmid: vmid,
+            policy: policy,
+            quarantined: false,
+        },
+    );
+    Ok(())
+}
+
+// The remapping unit responsible for devices in `segment`. This assumes
+// one unit covers the whole segment (DRHD device scopes are not parsed).
+fn find_iommu(segment: u16) -> Option<&'static Iommu> {
+    IOMMUS.iter().find(|iommu| iommu.segment == segment)
+}
+
+/// Attach the host device `source_id` in `segment` to the VM `vmid`
+///
+/// The DMA of the device is translated through the guest physical address
+/// space of the VM, so it can only access the memory of that VM. Note that
+/// once the first device behind a remapping unit is attached, all other
+/// devices behind that unit are blocked from performing DMA until they are
+/// attached to a VM.
+pub fn attach_device(
+    segment: u16,
+    source_id: u16,
+    vmid: u32,
+    policy: FaultPolicy,
+    space: &mut GuestAddressSpace,
+) -> Result<()> {
+    assign_device(segment, source_id, vmid, policy)?;
+    match find_iommu(segment) {
+        Some(iommu) => iommu.attach(source_id, vmid, space),
+        None => {
+            warn!(
+                "No IOMMU for segment {}, device 0x{:x} has unrestricted DMA",
+                segment, source_id
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Detach the host device `source_id` in `segment` from the VM that owns
+/// it, which blocks its DMA
+///
+/// A quarantined device stays quarantined.
+pub fn detach_device(segment: u16, source_id: u16) {
+    let mut assignments = DEVICE_ASSIGNMENTS.lock();
+    if let Some(assignment) = assignments.get(&(segment, source_id)) {
+        if !assignment.quarantined {
+            assignments.remove(&(segment, source_id));
+        }
+    }
+    drop(assignments);
+    if let Some(iommu) = find_iommu(segment) {
+        iommu.detach(source_id);
+    }
+}
+
+/// Post the MSIs from the host device `source_id` in `segment` to
+/// `descriptor` (which must remain valid while the device is assigned)
+///
+/// The interrupts will be delivered to the guest with the given `vector`.
+/// Returns the message that must be programmed in the MSI capability of
+/// the device.
+pub fn post_device_interrupt(
+    segment: u16,
+    source_id: u16,
+    vector: u8,
+    descriptor: &PostedInterruptDescriptor,
+) -> Result<MsiMessage> {
+    match find_iommu(segment) {
+        Some(iommu) => iommu.post_interrupt(source_id, vector, descriptor),
+        None => Err(Error::NotSupported),
+    }
+}
+
+/// Returns true if the given device has been quarantined
+pub fn is_quarantined(segment: u16, source_id: u16) -> bool {
+    DEVICE_ASSIGNMENTS
+        .lock()
+        .get(&(segment, source_id))
+        .map(|assignment| assignment.quarantined)
+        .unwrap_or(false)
+}
+
+// Stop the device from initiating DMA (which includes MSIs) and from
+// asserting its legacy interrupt
+unsafe fn quarantine_device(segment: u16, source_id: u16) -> Result<()> {
+    if segment != 0 {
+        return Err(Error::NotImplemented(format!(
+            "Unable to quarantine device in PCI segment {}",
+            segment
+        )));
+    }
+
+    let address = 0x80000000 | (source_id as u32) << 8 | PCI_COMMAND as u32;
+    outl(PCI_CONFIG_ADDRESS, address);
+    let command = inw(PCI_CONFIG_DATA);
+    outl(PCI_CONFIG_ADDRESS, address);
+    outw(
+        PCI_CONFIG_DATA,
+        (command & !PCI_COMMAND_BUS_MASTER) | PCI_COMMAND_INTX_DISABLE,
+    );
+    Ok(())
+}
+
+/// Handle a fault event from the remapping units
+pub fn handle_fault_event() -> Result<()> {
+    for iommu in IOMMUS.iter() {
+        for fault in iommu.take_faults() {
+            let mut assignments = DEVICE_ASSIGNMENTS.lock();
+            let assignment =
+                match assignments.get_mut(&(fault.segment, fault.source_id)) {
+                    Some(assignment) => assignment,
+                    None => {
+                        error!("IOMMU fault from unassigned device: {}", fault);
+                        continue;
+                    }
+                };
+
+            error!("IOMMU fault (vm={}): {}", assignment.vmid, fault);
+
+            if assignment.policy == FaultPolicy::Quarantine
+                && !assignment.quarantined
+            {
+                unsafe { quarantine_device(fault.segment, fault.source_id)? };
+                if let Some(iommu) = find_iommu(fault.segment) {
+                    iommu.detach(fault.source_id);
+                }
+                assignment.quarantined = true;
+                warn!(
+                    "Quarantined device 0x{:x} (vm={})",
+                    fault.source_id, assignment.vmid
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fault_record_decode() {
+        assert_eq!(FaultRecord::from_raw(0, 0x1234000, 0), None);
+
+        let high = FRCD_FAULT | FRCD_TYPE_READ | 0x06 << 32 | 0x00f8;
+        let fault = FaultRecord::from_raw(0, 0x7f001abc, high).unwrap();
+        assert_eq!(fault.source_id, 0xf8);
+        assert_eq!(fault.address, 0x7f001000);
+        assert_eq!(fault.access, FaultAccess::Read);
+        assert_eq!(fault.reason_str(), "read from a non-readable page");
+        assert_eq!(
+            format!("{}", fault),
+            "0000:00:1f.0 Read of 0x7f001000: read from a non-readable page (reason=0x6)"
+        );
+    }
+
+    #[test]
+    fn test_second_level_table() {
+        assert!(SecondLevelTable::new(5, true).is_err());
+
+        let mut table = SecondLevelTable::new(3, true).unwrap();
+        let frame =
+            HostPhysFrame::from_start_address(HostPhysAddr::new(0x12345000))
+                .unwrap();
+        table
+            .map(GuestPhysAddr::new(0x7f001000), frame, true)
+            .unwrap();
+        assert_eq!(
+            table.translate(GuestPhysAddr::new(0x7f001000)),
+            Some((0x12345000, false))
+        );
+        assert_eq!(table.translate(GuestPhysAddr::new(0x7f002000)), None);
+
+        // A 3 level table only covers 39 bits
+        assert!(table
+            .map(GuestPhysAddr::new(1 << 40), frame, false)
+            .is_err());
+
+        table.unmap(GuestPhysAddr::new(0x7f001000)).unwrap();
+        assert_eq!(table.translate(GuestPhysAddr::new(0x7f001000)), None);
+    }
+
+    #[test]
+    fn test_remappable_msi() {
+        assert_eq!(
+            MsiMessage::remappable(0x8001),
+            MsiMessage {
+                address: 0xfee00034,
+                data: 0
+            }
+        );
+    }
+}
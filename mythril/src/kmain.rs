@@ -2,20 +2,32 @@ use crate::acpi;
 use crate::ap;
 use crate::apic;
 use crate::boot_info::BootInfo;
+use crate::crash;
+use crate::error::{Error, Result};
+use crate::health;
+use crate::hostmem;
 use crate::interrupt;
 use crate::ioapic;
+use crate::iommu;
 use crate::linux;
 use crate::logger;
 use crate::memory;
 use crate::multiboot;
 use crate::multiboot2;
+use crate::nvram;
 use crate::percore;
 use crate::physdev;
+use crate::rng;
+use crate::sched;
+use crate::selftest;
 use crate::time;
 use crate::vcpu;
 use crate::virtdev;
+use crate::virtdev::qemu_fw_cfg::{E820Type, FwCfgSelector, QemuFwCfgBuilder};
 use crate::vm;
 
+use alloc::boxed::Box;
+use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use log::{debug, info};
@@ -31,12 +43,110 @@ extern "C" {
     static IS_MULTIBOOT2_BOOT: u8;
 }
 
+// Populate the fw_cfg device with what the firmware needs to boot the
+// VM using its configured `BootMethod`
+fn setup_boot(
+    config: &vm::VirtualMachineConfig,
+    mem: u64,
+    fw_cfg_builder: &mut QemuFwCfgBuilder,
+    info: &BootInfo,
+) -> Result<()> {
+    // The machine description that SeaBIOS and OVMF read during POST
+    let cpus = config.cpus().len() as u16;
+    fw_cfg_builder.add_u16(FwCfgSelector::NB_CPUS, cpus);
+    fw_cfg_builder.add_u16(FwCfgSelector::MAX_CPUS, cpus);
+    fw_cfg_builder.add_u64(FwCfgSelector::RAM_SIZE, mem << 20);
+
+    fw_cfg_builder.add_bytes(FwCfgSelector::UUID, &config.uuid().0);
+
+    // The firmware places the SMBIOS tables (with the UUID as the system
+    // UUID) in the 0xf0000 segment
+    let smbios = config.smbios_tables()?;
+    fw_cfg_builder.add_smbios_tables(smbios.anchor(), smbios.tables())?;
+
+    //TODO: support memory above 4GB
+    fw_cfg_builder.add_e820_table(&[(0x0, mem << 20, E820Type::Ram)])?;
+
+    if let vm::BootMethod::DirectKernel(image) = config.boot_method() {
+        // The 'linuxboot' file is an option rom that loads the linux kernel
+        // via qemu_fw_cfg
+        fw_cfg_builder
+            .add_file("genroms/linuxboot_dma.bin", linux::LINUXBOOT_DMA_ROM)?;
+
+        linux::load_linux(
+            &image.kernel,
+            image.initramfs.as_ref(),
+            &image.cmdline,
+            mem,
+            fw_cfg_builder,
+            info,
+        )?;
+    }
+
+    // The firmware installs the ACPI tables using the table loader
+    if let Some(tables) = config.acpi_tables() {
+        fw_cfg_builder.add_acpi_tables(
+            tables.rsdp(),
+            tables.tables(),
+            &tables.loader(),
+        )?;
+    }
+
+    // The event log describing the initial PCRs of the vTPM
+    if let Some(measurements) = config.measurements() {
+        fw_cfg_builder
+            .add_file("etc/mythril/event-log", measurements.event_log())?;
+    }
+
+    // Passing the bootorder file selects the default boot device (which is
+    // the linuxboot option rom when booting a kernel directly)
+    if let Some(bootorder) = config.bootorder_file() {
+        fw_cfg_builder.add_file("bootorder", &bootorder)?;
+    }
+
+    Ok(())
+}
+
+// The boot modules holding the disk image of the default VM
+const DISK_MODULE: &str = "disk";
+const READ_ONLY_DISK_MODULE: &str = "disk-ro";
+
+// The boot module holding the disk image attached to the IDE controller
+const IDE_DISK_MODULE: &str = "ide";
+
+// The boot module holding the cpio archive shared with the default VM
+const SHARE_MODULE: &str = "share";
+
+// The boot module holding UEFI firmware (e.g., a split OVMF build) for the
+// default VM, which then keeps its variable store in the VM's nvram
+const UEFI_FIRMWARE_MODULE: &str = "uefi";
+
+// The boot module holding the scheduler pools and the parameters of each
+// VM (see `sched::SchedConfig`)
+const SCHED_MODULE: &str = "sched";
+
+// The legacy IRQs of the virtio devices of the default VM
+const VIRTIO_BLOCK_IRQ: u8 = 11;
+const VIRTIO_9P_IRQ: u8 = 10;
+const VIRTIO_VSOCK_IRQ: u8 = 5;
+const VIRTIO_BALLOON_IRQ: u8 = 7;
+const VIRTIO_RNG_IRQ: u8 = 13;
+
+// The e1000 of the default VM is device 2 on bus 0, and uses the IRQ of
+// the (absent) secondary IDE channel until the guest routes it
+const E1000_BDF: u16 = 0x10;
+const E1000_IRQ: u8 = 15;
+
+// The base port of the ACPI PM registers of the default VM
+const ACPI_PM_BASE: virtdev::Port = 0xb000;
+
 // Temporary helper function to create a vm for a single core
 fn default_vm(
     core: percore::CoreId,
     mem: u64,
     info: &BootInfo,
     add_uart: bool,
+    sched_params: sched::SchedParams,
 ) -> Arc<RwLock<vm::VirtualMachine>> {
     let physical_config = if add_uart == false {
         vm::PhysicalDeviceConfig::default()
@@ -53,42 +163,208 @@ fn default_vm(
     let mut config =
         vm::VirtualMachineConfig::new(vec![core], mem, physical_config);
 
+    config.set_sched_params(sched_params);
+
+    let uefi_firmware = info.find_module(UEFI_FIRMWARE_MODULE);
+    if uefi_firmware.is_some() {
+        config.set_firmware(Some(UEFI_FIRMWARE_MODULE.into()));
+    }
+
+    // A disk image may be provided as a boot module, and is exposed to the
+    // guest as a virtio block device. The 'disk' module is copied, so the
+    // guest may write to it, while a 'disk-ro' module is served in place.
+    let disk_backend: Option<Box<dyn virtdev::virtio::block::BlockBackend>> =
+        if let Some(module) = info.find_module(DISK_MODULE) {
+            Some(Box::new(virtdev::virtio::block::MemoryBackend::new(
+                module.data(),
+            )))
+        } else if let Some(module) = info.find_module(READ_ONLY_DISK_MODULE) {
+            Some(Box::new(virtdev::virtio::block::PassthroughBackend::new(
+                module,
+            )))
+        } else {
+            None
+        };
+    let disk = disk_backend.map(|backend| {
+        virtdev::virtio::VirtioMmio::new(
+            virtdev::virtio::VIRTIO_MMIO_BASE,
+            VIRTIO_BLOCK_IRQ,
+            virtdev::virtio::block::VirtioBlock::new(backend, "mythril-disk"),
+        )
+    });
+
+    // A cpio archive may be provided as a boot module, and is shared with
+    // the guest (read-only) as a virtio 9P filesystem tagged 'mythril'
+    let share = info.find_module(SHARE_MODULE).map(|module| {
+        let fs = virtdev::virtio::p9::Filesystem::from_cpio(module.data())
+            .expect("Failed to read shared filesystem");
+        virtdev::virtio::VirtioMmio::new(
+            virtdev::virtio::VIRTIO_MMIO_BASE
+                + virtdev::virtio::VIRTIO_MMIO_SIZE,
+            VIRTIO_9P_IRQ,
+            virtdev::virtio::p9::Virtio9p::new(fs, "mythril"),
+        )
+    });
+
+    // Guest agents can reach the hypervisor over vsock, where the guest
+    // context ID follows the host (2) by VM
+    let mut vsock_device = virtdev::virtio::vsock::VirtioVsock::new(
+        virtdev::virtio::vsock::HOST_CID + 1 + core.raw as u64,
+    )
+    .expect("Failed to create vsock device");
+    vsock_device
+        .add_service(
+            virtdev::virtio::vsock::AGENT_PORT,
+            Box::new(virtdev::virtio::vsock::AgentService::new(config.name())),
+        )
+        .expect("Failed to add vsock agent service");
+    let vsock = virtdev::virtio::VirtioMmio::new(
+        virtdev::virtio::VIRTIO_MMIO_BASE
+            + 2 * virtdev::virtio::VIRTIO_MMIO_SIZE,
+        VIRTIO_VSOCK_IRQ,
+        vsock_device,
+    );
+
+    // The memory of the VM can be reclaimed with a balloon (see the
+    // 'balloon' console command)
+    let balloon_base = virtdev::virtio::VIRTIO_MMIO_BASE
+        + 3 * virtdev::virtio::VIRTIO_MMIO_SIZE;
+    let balloon = virtdev::virtio::VirtioMmio::new(
+        balloon_base,
+        VIRTIO_BALLOON_IRQ,
+        virtdev::virtio::balloon::VirtioBalloon::new(),
+    );
+    config.set_balloon(memory::GuestPhysAddr::new(balloon_base));
+
+    // The guest can draw entropy from the hypervisor with virtio-rng
+    let entropy = virtdev::virtio::VirtioMmio::new(
+        virtdev::virtio::VIRTIO_MMIO_BASE
+            + 4 * virtdev::virtio::VIRTIO_MMIO_SIZE,
+        VIRTIO_RNG_IRQ,
+        virtdev::virtio::rng::VirtioRng::new()
+            .expect("Failed to create virtio-rng device"),
+    );
+
+    let mut cmdline = String::from(core::concat!(
+        "rodata=0 nopti disableapic acpi=off ",
+        "earlyprintk=serial,0x3f8,115200 ",
+        "console=ttyS0 debug noapic mitigations=off ",
+        "root=/dev/ram0 rdinit=/bin/sh"
+    ));
+    if let Some(disk) = &disk {
+        cmdline.push(' ');
+        cmdline.push_str(&disk.read().linux_cmdline());
+    }
+    if let Some(share) = &share {
+        cmdline.push(' ');
+        cmdline.push_str(&share.read().linux_cmdline());
+    }
+    cmdline.push(' ');
+    cmdline.push_str(&vsock.read().linux_cmdline());
+    cmdline.push(' ');
+    cmdline.push_str(&balloon.read().linux_cmdline());
+    cmdline.push(' ');
+    cmdline.push_str(&entropy.read().linux_cmdline());
+    cmdline.push('\0');
+
+    config.set_boot_method(vm::BootMethod::DirectKernel(vm::KernelImage {
+        kernel: "kernel".into(),
+        initramfs: Some("initramfs".into()),
+        cmdline: cmdline.into_bytes(),
+    }));
+
+    // A previously saved nvram image may be provided as a boot module
+    let nvram_image = info
+        .find_module(format!("nvram{}", core.raw))
+        .map(|module| module.data());
+    let nvram = config.set_nvram(
+        nvram::Nvram::new(Box::new(nvram::MemoryBackend::new(nvram_image)))
+            .expect("Failed to open nvram"),
+    );
+
+    // The boot images are measured into the initial PCRs of a vTPM
+    config
+        .enable_measured_boot(info)
+        .expect("Failed to measure the VM boot");
+    let tpm = virtdev::tpm::TpmCrb::new(
+        virtdev::tpm::TPM_CRB_BASE,
+        nvram.clone(),
+        config.measurements().unwrap().pcrs(),
+    )
+    .expect("Failed to create TPM");
+
+    let rtc_policy = config.rtc_policy();
+    let boot_order = config.boot_order().to_vec();
+    let serial_backends = config.serial_backends();
     let device_map = config.virtual_devices_mut();
+    let acpi_pm = virtdev::acpi_pm::AcpiPm::new(ACPI_PM_BASE);
+    device_map.register_device(acpi_pm.clone()).unwrap();
     device_map
-        .register_device(virtdev::acpi::AcpiRuntime::new(0xb000).unwrap())
+        .register_device(virtdev::acpi::AcpiRuntime::new(acpi_pm, 1).unwrap())
         .unwrap();
     device_map
         .register_device(virtdev::debug::DebugPort::new(0x402))
         .unwrap();
     device_map
-        .register_device(virtdev::com::Uart8250::new(0x3F8))
+        .register_device(virtdev::debug::DebugConsole::new())
         .unwrap();
+    for ((port, irq), backend) in
+        virtdev::uart::COM_PORTS.iter().zip(serial_backends.iter())
+    {
+        device_map
+            .register_device(virtdev::uart::Uart16550::new(
+                *port, *irq, *backend,
+            ))
+            .unwrap();
+    }
     device_map
         .register_device(virtdev::vga::VgaController::new())
         .unwrap();
     device_map
-        .register_device(virtdev::dma::Dma8237::new())
+        .register_device(virtdev::dma8237::Dma8237::new())
         .unwrap();
     device_map
-        .register_device(virtdev::ignore::IgnoredDevice::new())
+        .register_device(virtdev::fdc::FloppyController::new())
         .unwrap();
     device_map
-        .register_device(virtdev::pci::PciRootComplex::new())
+        .register_device(virtdev::ignore::IgnoredDevice::new())
         .unwrap();
+    let pci =
+        virtdev::pci::PciRootComplex::with_ecam(virtdev::pci::PCI_ECAM_BASE);
+    device_map.register_device(pci.clone()).unwrap();
+    let iommu =
+        virtdev::iommu::IntelIommu::new(virtdev::iommu::DEFAULT_REGISTER_BASE);
+    let dmar = iommu.read().dmar_table();
+    device_map.register_device(iommu).unwrap();
+    let pic = virtdev::pic::Pic8259::new();
+    device_map.register_device(pic.clone()).unwrap();
     device_map
-        .register_device(virtdev::pic::Pic8259::new())
+        .register_device(virtdev::ps2::Ps2Controller::new())
         .unwrap();
     device_map
-        .register_device(virtdev::keyboard::Keyboard8042::new())
+        .register_device(virtdev::pit::Pit8254::new())
         .unwrap();
     device_map
-        .register_device(virtdev::pit::Pit8254::new())
+        .register_device(virtdev::pvpanic::PvPanic::new(
+            virtdev::pvpanic::PVPANIC_PORT,
+        ))
         .unwrap();
     device_map
         .register_device(virtdev::pos::ProgrammableOptionSelect::new())
         .unwrap();
     device_map
-        .register_device(virtdev::rtc::CmosRtc::new(mem))
+        .register_device(virtdev::sysctrl::SystemControlPortA::new())
+        .unwrap();
+    device_map
+        .register_device(
+            virtdev::rtc::CmosRtc::new(
+                mem,
+                &boot_order,
+                Some(nvram.clone()),
+                rtc_policy,
+            )
+            .expect("Failed to create CMOS"),
+        )
         .unwrap();
 
     //TODO: this should actually be per-vcpu
@@ -96,39 +372,93 @@ fn default_vm(
         .register_device(virtdev::lapic::LocalApic::new())
         .unwrap();
 
-    let mut fw_cfg_builder = virtdev::qemu_fw_cfg::QemuFwCfgBuilder::new();
-
-    // The 'linuxboot' file is an option rom that loads the linux kernel
-    // via qemu_fw_cfg
-    fw_cfg_builder
-        .add_file("genroms/linuxboot_dma.bin", linux::LINUXBOOT_DMA_ROM)
+    // UEFI firmware finds its variable store directly below the firmware
+    // image at the top of 4GB
+    if let Some(firmware) = uefi_firmware {
+        let base = (4 * 1024 * 1024 * 1024)
+            - firmware.size as u64
+            - virtdev::pflash::Pflash::size() as u64;
+        device_map
+            .register_device(virtdev::pflash::Pflash::new(base, nvram))
+            .unwrap();
+    }
+    let ioapic = virtdev::ioapic::IoApic::new();
+    device_map.register_device(ioapic.clone()).unwrap();
+    let watchdog = virtdev::watchdog::Ib700::new(
+        virtdev::watchdog::WatchdogAction::default(),
+    );
+    device_map.register_device(watchdog.clone()).unwrap();
+    device_map
+        .register_device(virtdev::hpet::Hpet::new())
         .unwrap();
+    device_map.register_device(tpm.clone()).unwrap();
+    if let Some(disk) = disk {
+        device_map.register_device(disk).unwrap();
+    }
 
-    // Passing the bootorder file automatically selects the option rom
-    // as the default boot device
-    fw_cfg_builder
-        .add_file(
-            "bootorder",
-            "/rom@genroms/linuxboot_dma.bin\nHALT".as_bytes(),
-        )
-        .unwrap();
+    // An 'ide' disk image is copied and attached to the primary IDE
+    // channel, for guests without virtio drivers
+    if let Some(module) = info.find_module(IDE_DISK_MODULE) {
+        let backend = virtdev::virtio::block::MemoryBackend::new(module.data());
+        device_map
+            .register_device(virtdev::ide::IdeController::new(Box::new(
+                backend,
+            )))
+            .unwrap();
+    }
+    if let Some(share) = share {
+        device_map.register_device(share).unwrap();
+    }
 
-    linux::load_linux(
-        "kernel",
-        "initramfs",
-        core::concat!(
-            "rodata=0 nopti disableapic acpi=off ",
-            "earlyprintk=serial,0x3f8,115200 ",
-            "console=ttyS0 debug nokaslr noapic mitigations=off ",
-            "root=/dev/ram0 rdinit=/bin/sh\0"
-        )
-        .as_bytes(),
-        mem,
-        &mut fw_cfg_builder,
-        info,
+    // Every VM has an e1000 on the switch that connects them, with a
+    // locally administered address derived from its core
+    let mac = [
+        0x52,
+        0x54,
+        0x00,
+        0x00,
+        (core.raw >> 8) as u8,
+        core.raw as u8,
+    ];
+    let nic = virtdev::e1000::E1000::new(
+        mac,
+        E1000_IRQ,
+        Box::new(virtdev::e1000::SwitchPort::connect(mac)),
     )
-    .unwrap();
-    device_map.register_device(fw_cfg_builder.build()).unwrap();
+    .expect("Failed to create e1000");
+    pci.write().add_device(E1000_BDF, nic.clone()).unwrap();
+    device_map.register_device(nic.clone()).unwrap();
+    device_map.register_device(vsock).unwrap();
+    device_map.register_device(balloon).unwrap();
+    device_map.register_device(entropy).unwrap();
+
+    config.set_pic(pic);
+    config.set_ioapic(ioapic);
+    config.set_watchdog(watchdog);
+    config.set_tpm(tpm);
+    config.set_nic(nic);
+
+    // The guest sees the platform emulated above through generated ACPI
+    // tables, rather than those of the host
+    let apic_ids = config.cpus().iter().map(|core| core.raw).collect();
+    let mut acpi_tables =
+        acpi::tables::AcpiTablesBuilder::new(apic_ids, ACPI_PM_BASE);
+    acpi_tables.set_hpet(virtdev::hpet::HPET_BASE);
+    acpi_tables.set_ecam(virtdev::pci::PCI_ECAM_BASE);
+    acpi_tables.set_tpm(virtdev::tpm::TPM_CRB_BASE);
+    acpi_tables.add_table(dmar);
+    config.set_acpi_tables(
+        acpi_tables.build().expect("Failed to build ACPI tables"),
+    );
+
+    let mut fw_cfg_builder = QemuFwCfgBuilder::new();
+    setup_boot(&config, mem, &mut fw_cfg_builder, info)
+        .expect("Failed to setup VM boot");
+
+    config
+        .virtual_devices_mut()
+        .register_device(fw_cfg_builder.build())
+        .unwrap();
 
     vm::VirtualMachine::new(core.raw, config, info)
         .expect("Failed to create vm")
@@ -185,6 +515,11 @@ unsafe fn kmain(mut boot_info: BootInfo) -> ! {
 
     // Calibrate the global time source
     time::init_global_time().expect("Failed to init global timesource");
+    match physdev::rtc::read_unix_time() {
+        Ok(now) => time::init_wall_clock(now),
+        Err(e) => warn!("Failed to read the host RTC: {:?}", e),
+    }
+    rng::init().expect("Failed to seed the random number generator");
 
     // physdev::keyboard::Ps2Controller::init().expect("Failed to init ps2 controller");
 
@@ -221,23 +556,71 @@ unsafe fn kmain(mut boot_info: BootInfo) -> ! {
     ioapic::map_gsi_vector(4, interrupt::UART_VECTOR, 0)
         .expect("Failed to map com0 gsi");
 
+    let dmar_sdt = rsdt.find_entry(b"DMAR").ok();
+    let dmar = dmar_sdt
+        .as_ref()
+        .map(|sdt| acpi::dmar::DMAR::new(sdt))
+        .transpose()
+        .expect("Invalid DMAR");
+    iommu::init_iommus(
+        dmar.as_ref(),
+        interrupt::IOMMU_FAULT_VECTOR,
+        local_apic.id().raw as u8,
+    )
+    .expect("Failed to initialize IOMMUs");
+
     percore::init_sections(apic_ids.len())
         .expect("Failed to initialize per-core sections");
 
+    // The pools must exist before any vCPU is admitted to its core
+    let sched_config = boot_info
+        .find_module(SCHED_MODULE)
+        .map(|module| {
+            core::str::from_utf8(module.data())
+                .map_err(|_| {
+                    Error::InvalidValue("Invalid scheduler config".into())
+                })
+                .and_then(sched::SchedConfig::parse)
+        })
+        .transpose()
+        .expect("Failed to read the scheduler config")
+        .unwrap_or_default();
+    sched_config
+        .apply()
+        .expect("Failed to create the scheduler pools");
+
     let mut builder = vm::VirtualMachineBuilder::new();
 
+    let run_selftest =
+        boot_info.find_module(selftest::SELFTEST_MODULE).is_some();
     for apic_id in apic_ids.iter() {
-        builder
-            .insert_machine(default_vm(
-                percore::CoreId::from(apic_id.raw),
+        let core = percore::CoreId::from(apic_id.raw);
+        let vm = if run_selftest && apic_id.is_bsp() {
+            selftest::selftest_vm(core, &boot_info)
+                .expect("Failed to create selftest vm")
+        } else {
+            default_vm(
+                core,
                 256,
                 &boot_info,
                 apic_id.is_bsp(),
-            ))
-            .expect("Failed to insert new vm");
+                sched_config.params(core),
+            )
+        };
+        builder.insert_machine(vm).expect("Failed to insert new vm");
     }
 
     vm::init_virtual_machines(builder.finalize());
+    health::init(
+        apic_ids
+            .iter()
+            .map(|apic_id| percore::CoreId::from(apic_id.raw)),
+    );
+    crash::init(
+        apic_ids
+            .iter()
+            .map(|apic_id| percore::CoreId::from(apic_id.raw)),
+    );
 
     debug!("AP_STARTUP address: 0x{:x}", AP_STARTUP_ADDR);
 
@@ -253,6 +636,12 @@ unsafe fn kmain(mut boot_info: BootInfo) -> ! {
         let stack_bottom =
             (stack.as_ptr() as u64 + stack.len() as u64) & 0xFFFFFFFFFFFFFFF0;
 
+        hostmem::reserve(
+            stack.as_ptr() as u64,
+            stack.len() as u64,
+            hostmem::HostRegionKind::Stack(apic_id.raw),
+        )
+        .expect("Failed to reserve AP stack");
         core::mem::forget(stack);
 
         core::ptr::write_volatile(&mut AP_STACK_ADDR as *mut u64, stack_bottom);
@@ -27,28 +27,63 @@ pub mod acpi;
 pub mod ap;
 /// Support for the local APIC.
 pub mod apic;
+pub mod background;
+pub mod biosdata;
 pub mod boot_info;
+pub mod chaos;
+pub mod compress;
+pub mod console;
+pub mod consoleroute;
+pub mod coredump;
+pub mod crash;
 
 pub mod emulate;
 pub mod error;
+pub mod evtchn;
+pub mod exitpolicy;
 pub mod global_alloc;
+pub mod health;
+pub mod hostmem;
+pub mod identity;
 pub mod interrupt;
 pub mod ioapic;
+pub mod iommu;
 pub mod kmain;
+pub mod lifecycle;
 pub mod linux;
 pub mod lock;
 pub mod logger;
+pub mod measure;
 pub mod memory;
+pub mod memscan;
+pub mod migrate;
 pub mod multiboot;
 pub mod multiboot2;
+pub mod nvram;
+pub mod offline;
+pub mod pagewalk;
+pub mod pcid;
 pub mod percore;
 pub mod physdev;
+pub mod pvh;
+pub mod ratelimit;
 pub mod registers;
+pub mod rng;
+pub mod sched;
+pub mod seal;
+pub mod selftest;
+pub mod smbios;
+pub mod snapshot;
+pub mod stats;
+pub mod symbols;
 pub mod time;
+pub mod timekeeping;
 pub mod tsc;
 pub mod vcpu;
 pub mod virtdev;
 pub mod vm;
+pub mod vmcheck;
 pub mod vmcs;
 pub mod vmexit;
 pub mod vmx;
+pub mod watch;
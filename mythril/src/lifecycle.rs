@@ -0,0 +1,211 @@
+//! Stopping and restarting guests
+//!
+//! A guest stops when it triple faults, reports a panic through the
+//! pvpanic device, asks to be shut down, or is killed by a policy of the
+//! hypervisor. What
+//! happens next is decided by the `RestartPolicy` of its VM: the guest is
+//! either left stopped (its core halts, but still serves the management
+//! console) or restarted from the reset vector after a delay.
+//!
+//! The delay doubles with each consecutive restart, up to the maximum of
+//! the policy's `RestartBackoff`. A guest that runs for longer than that
+//! maximum before stopping again starts over from the initial delay.
+//!
+//! Only the vCPU that observed the stop is reset. The firmware is
+//! responsible for reinitializing the emulated devices.
+//!
+//! A guest can also reset itself (through the PS/2 controller, port 0x92
+//! or the PCI reset control register), or be reset by its watchdog. That
+//! is not a stop: every vCPU of the VM is reset at once, whatever its
+//! restart policy.
+
+use core::fmt;
+use core::time::Duration;
+
+/// The delays between consecutive restarts of a guest
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RestartBackoff {
+    /// The delay before the first restart
+    pub initial: Duration,
+
+    /// The longest delay between restarts
+    pub max: Duration,
+}
+
+impl Default for RestartBackoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Whether a stopped guest is restarted
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RestartPolicy {
+    /// The guest is never restarted
+    Never,
+
+    /// The guest is restarted when it crashes (triple faults or panics),
+    /// but not when it is killed
+    OnCrash(RestartBackoff),
+
+    /// The guest is always restarted
+    Always(RestartBackoff),
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy::Never
+    }
+}
+
+/// The reason a guest stopped
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StopReason {
+    /// The guest triple faulted
+    TripleFault,
+
+    /// The guest reported a panic through the pvpanic device
+    GuestPanic,
+
+    /// The guest was killed by a hypervisor policy
+    Killed,
+
+    /// The guest asked to be shut down (e.g., through its vsock agent or an
+    /// ACPI soft-off)
+    Shutdown,
+}
+
+impl StopReason {
+    /// Returns true if the guest stopped because it crashed
+    pub fn is_crash(&self) -> bool {
+        match self {
+            StopReason::TripleFault | StopReason::GuestPanic => true,
+            StopReason::Killed | StopReason::Shutdown => false,
+        }
+    }
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StopReason::TripleFault => write!(f, "triple fault"),
+            StopReason::GuestPanic => write!(f, "guest panic"),
+            StopReason::Killed => write!(f, "killed"),
+            StopReason::Shutdown => write!(f, "shutdown"),
+        }
+    }
+}
+
+/// The restart history of a guest
+///
+/// Times are measured from an arbitrary (but fixed) point, usually the
+/// system start time.
+#[derive(Clone, Debug, Default)]
+pub struct RestartTracker {
+    restarts: u32,
+    resets: u32,
+    started: Duration,
+}
+
+impl RestartTracker {
+    /// The number of times the guest has been restarted
+    pub fn restarts(&self) -> u32 {
+        self.restarts
+    }
+
+    /// The number of times the guest has reset itself
+    pub fn resets(&self) -> u32 {
+        self.resets
+    }
+
+    /// Record a reset of the guest (by itself or its watchdog), returning
+    /// the number of resets so far
+    pub fn guest_reset(&mut self) -> u32 {
+        self.resets += 1;
+        self.resets
+    }
+
+    /// Record that the guest stopped for `reason` at `now`, returning the
+    /// delay before it is restarted (or `None` if it stays stopped)
+    pub fn stopped(
+        &mut self,
+        policy: RestartPolicy,
+        reason: StopReason,
+        now: Duration,
+    ) -> Option<Duration> {
+        let backoff = match policy {
+            RestartPolicy::Never => return None,
+            RestartPolicy::OnCrash(_) if !reason.is_crash() => return None,
+            RestartPolicy::OnCrash(backoff)
+            | RestartPolicy::Always(backoff) => backoff,
+        };
+
+        // The consecutive restarts are counted from the last restart after
+        // which the guest ran for longer than the maximum delay
+        let stable = now
+            .checked_sub(self.started)
+            .map_or(false, |ran| ran > backoff.max);
+        let consecutive = if stable { 0 } else { self.restarts };
+        let delay = backoff
+            .initial
+            .checked_mul(1u32.checked_shl(consecutive).unwrap_or(u32::MAX))
+            .unwrap_or(backoff.max)
+            .min(backoff.max);
+
+        self.restarts = consecutive + 1;
+        self.started = now + delay;
+        Some(delay)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_restart_policy() {
+        let mut tracker = RestartTracker::default();
+        let now = Duration::from_secs(100);
+        assert_eq!(
+            tracker.stopped(RestartPolicy::Never, StopReason::TripleFault, now),
+            None
+        );
+
+        let policy = RestartPolicy::OnCrash(RestartBackoff::default());
+        assert_eq!(tracker.stopped(policy, StopReason::Killed, now), None);
+        assert_eq!(
+            tracker.stopped(policy, StopReason::GuestPanic, now),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(tracker.restarts(), 1);
+
+        // Resets by the guest are not restarts
+        assert_eq!(tracker.guest_reset(), 1);
+        assert_eq!((tracker.restarts(), tracker.resets()), (1, 1));
+    }
+
+    #[test]
+    fn test_restart_backoff() {
+        let mut tracker = RestartTracker::default();
+        let policy = RestartPolicy::Always(RestartBackoff {
+            initial: Duration::from_secs(2),
+            max: Duration::from_secs(10),
+        });
+        let mut now = Duration::from_secs(100);
+        for expected in [2, 4, 8, 10, 10].iter() {
+            let delay = tracker.stopped(policy, StopReason::Killed, now);
+            assert_eq!(delay, Some(Duration::from_secs(*expected)));
+            now += delay.unwrap() + Duration::from_secs(1);
+        }
+
+        // Running for longer than the maximum delay resets the backoff
+        now += Duration::from_secs(11);
+        assert_eq!(
+            tracker.stopped(policy, StopReason::TripleFault, now),
+            Some(Duration::from_secs(2))
+        );
+    }
+}
@@ -23,7 +23,7 @@ bitflags! {
 
 pub fn load_linux(
     kernel_name: impl AsRef<str>,
-    initramfs_name: impl AsRef<str>,
+    initramfs_name: Option<impl AsRef<str>>,
     cmdline: &[u8],
     memory: u64,
     builder: &mut QemuFwCfgBuilder,
@@ -39,15 +39,19 @@ pub fn load_linux(
         })?
         .data()
         .to_vec();
-    let initramfs = info
-        .find_module(initramfs_name.as_ref())
-        .ok_or_else(|| {
-            Error::InvalidValue(format!(
-                "No such initramfs '{}'",
-                initramfs_name.as_ref()
-            ))
-        })?
-        .data();
+    let initramfs = match initramfs_name {
+        Some(name) => Some(
+            info.find_module(name.as_ref())
+                .ok_or_else(|| {
+                    Error::InvalidValue(format!(
+                        "No such initramfs '{}'",
+                        name.as_ref()
+                    ))
+                })?
+                .data(),
+        ),
+        None => None,
+    };
 
     if kernel.len() < 8192 {
         return Err(Error::InvalidValue(format!(
@@ -127,30 +131,36 @@ pub fn load_linux(
         );
     }
 
-    if protocol < 0x200 {
-        return Err(Error::InvalidValue(
-            "Kernel too old for initrd support".into(),
-        ));
-    }
+    if let Some(initramfs) = initramfs {
+        if protocol < 0x200 {
+            return Err(Error::InvalidValue(
+                "Kernel too old for initrd support".into(),
+            ));
+        }
+
+        if initramfs.len() as u32 > initrd_max {
+            return Err(Error::InvalidValue(format!(
+                "Initramfs too large (0x{:x} bytes > max of 0x{:x})",
+                initramfs.len(),
+                initrd_max
+            )));
+        }
+
+        let initrd_addr =
+            ((initrd_max - initramfs.len() as u32) & !4095) as i32;
+        builder.add_i32(FwCfgSelector::INITRD_ADDR, initrd_addr);
+        builder.add_i32(FwCfgSelector::INITRD_SIZE, initramfs.len() as i32);
+        builder.add_bytes(FwCfgSelector::INITRD_DATA, initramfs);
+        LittleEndian::write_i32(&mut kernel[0x218..0x218 + 4], initrd_addr);
+        LittleEndian::write_i32(
+            &mut kernel[0x21c..0x21c + 4],
+            initramfs.len() as i32,
+        );
 
-    if initramfs.len() as u32 > initrd_max {
-        return Err(Error::InvalidValue(format!(
-            "Initramfs too large (0x{:x} bytes > max of 0x{:x})",
-            initramfs.len(),
-            initrd_max
-        )));
+        info!("INITRD_ADDR: 0x{:x}", initrd_addr);
+        info!("INITRD_SIZE: 0x{:x}", initramfs.len());
     }
 
-    let initrd_addr = ((initrd_max - initramfs.len() as u32) & !4095) as i32;
-    builder.add_i32(FwCfgSelector::INITRD_ADDR, initrd_addr);
-    builder.add_i32(FwCfgSelector::INITRD_SIZE, initramfs.len() as i32);
-    builder.add_bytes(FwCfgSelector::INITRD_DATA, initramfs);
-    LittleEndian::write_i32(&mut kernel[0x218..0x218 + 4], initrd_addr);
-    LittleEndian::write_i32(
-        &mut kernel[0x21c..0x21c + 4],
-        initramfs.len() as i32,
-    );
-
     let setup_size = match kernel[0x1f1] {
         // For legacy compat, setup size 0 is really 4 sectors
         0 => 4 + 1,
@@ -181,7 +191,5 @@ pub fn load_linux(
     info!("KERNEL_SIZE: 0x{:x}", kernel_size);
     info!("SETUP_ADDR: 0x{:x}", real_addr);
     info!("SETUP_SIZE: 0x{:x}", setup_size);
-    info!("INITRD_ADDR: 0x{:x}", initrd_addr);
-    info!("INITRD_SIZE: 0x{:x}", initramfs.len());
     Ok(())
 }
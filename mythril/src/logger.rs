@@ -5,8 +5,47 @@ use core::fmt::Write;
 use spin::Mutex;
 
 static LOG_LOCK: Mutex<()> = Mutex::new(());
+static LOG_RING: Mutex<LogRing> = Mutex::new(LogRing::new());
 static mut VGA_WRITER: VgaWriter = VgaWriter::new();
 
+// The size of the ring buffer holding the most recent log output
+const LOG_RING_SIZE: usize = 16 * 1024;
+
+/// The most recent log output, kept so it can be reviewed from the
+/// management console after it has scrolled by
+pub struct LogRing {
+    data: [u8; LOG_RING_SIZE],
+    // The total number of bytes ever written
+    written: usize,
+}
+
+impl LogRing {
+    pub const fn new() -> Self {
+        Self {
+            data: [0; LOG_RING_SIZE],
+            written: 0,
+        }
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.data[self.written % LOG_RING_SIZE] = *byte;
+            self.written += 1;
+        }
+    }
+
+    /// The contents of the ring, oldest first (as two slices, because the
+    /// contents may wrap)
+    pub fn contents(&self) -> (&[u8], &[u8]) {
+        if self.written <= LOG_RING_SIZE {
+            (&self.data[..self.written], &[])
+        } else {
+            let start = self.written % LOG_RING_SIZE;
+            (&self.data[start..], &self.data[..start])
+        }
+    }
+}
+
 const VGA_BASE_ADDR: usize = 0xB8000;
 const VGA_WIDTH: usize = 80;
 const VGA_HEIGHT: usize = 25;
@@ -74,6 +113,15 @@ pub fn write_console(s: impl AsRef<str>) {
     drop(lock)
 }
 
+/// Release the log lock, regardless of which core holds it
+///
+/// This is only safe when the holder will never run again (e.g., it has
+/// been parked by `crash`).
+pub unsafe fn force_unlock() {
+    LOG_LOCK.force_unlock();
+    LOG_RING.force_unlock();
+}
+
 // NOTE: the caller should hold `LOG_LOCK`
 pub unsafe fn raw_write_console(s: impl AsRef<str>) {
     // mirror console output to VGA
@@ -161,9 +209,28 @@ impl log::Log for DirectLogger {
     }
 }
 
+/// The `dmesg` management console command
+pub fn dmesg_command(out: &mut dyn fmt::Write, _args: &[&str]) -> fmt::Result {
+    // Copy the contents out, because writing to the console may log
+    let contents = {
+        let ring = LOG_RING.lock();
+        let (older, newer) = ring.contents();
+        [older, newer].concat()
+    };
+    let contents = alloc::string::String::from_utf8_lossy(&contents);
+
+    // The oldest line is usually partially overwritten
+    let start = match contents.find('\n') {
+        Some(pos) if contents.len() >= LOG_RING_SIZE => pos + 1,
+        _ => 0,
+    };
+    out.write_str(&contents[start..])
+}
+
 struct DirectWriter;
 impl fmt::Write for DirectWriter {
     fn write_str(&mut self, s: &str) -> fmt::Result {
+        LOG_RING.lock().write(s.as_bytes());
         unsafe { raw_write_console(s) };
         Ok(())
     }
@@ -177,3 +244,22 @@ impl fmt::Write for DirectWriter {
         return ret;
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_log_ring() {
+        let mut ring = LogRing::new();
+        ring.write(b"hello");
+        assert_eq!(ring.contents(), (&b"hello"[..], &b""[..]));
+
+        // The oldest bytes are overwritten once the ring wraps
+        ring.write(&[b'x'; LOG_RING_SIZE - 2]);
+        let (older, newer) = ring.contents();
+        assert!(older.starts_with(b"lox"));
+        assert_eq!(older.len() + newer.len(), LOG_RING_SIZE);
+        assert_eq!(newer, b"xxx");
+    }
+}
@@ -0,0 +1,454 @@
+//! Measured launch
+//!
+//! When measured boot is enabled for a VM, the images it boots from are
+//! hashed (with SHA-256) and extended into a bank of PCRs, following the
+//! conventions used by GRUB: the firmware is measured into PCR 0, the
+//! kernel command line into PCR 8 and the kernel and initramfs into PCR 9.
+//! Each measurement is recorded in an event log in the TCG crypto agile
+//! format (see the TCG PC Client Platform Firmware Profile), which is
+//! provided to the guest as the fw_cfg file `etc/mythril/event-log` (a
+//! Linux guest can read it under `/sys/firmware/qemu_fw_cfg`).
+//!
+//! The PCRs are the initial values of the PCRs of the VM's virtual TPM
+//! (see `virtdev::tpm`), where the guest can read them and check them
+//! against the log. The `measurements` console command reports the same
+//! values, so the log can also be checked on the host. The guest's ACPI
+//! tables are installed by the firmware (or placed in memory for a PVH
+//! boot) after the launch, so they are not measured.
+
+use crate::boot_info::BootInfo;
+use crate::error::{Error, Result};
+use crate::vm;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// The number of PCRs in the bank
+pub const PCR_COUNT: usize = 24;
+
+/// The size (in bytes) of a SHA-256 digest
+pub const DIGEST_SIZE: usize = 32;
+
+pub type Digest = [u8; DIGEST_SIZE];
+
+/// The PCR holding the measurement of the firmware
+pub const FIRMWARE_PCR: usize = 0;
+
+/// The PCR holding the measurement of the kernel command line
+pub const CMDLINE_PCR: usize = 8;
+
+/// The PCR holding the measurements of the kernel and initramfs
+pub const KERNEL_PCR: usize = 9;
+
+/// The TPM algorithm identifier of SHA-256
+pub const TPM_ALG_SHA256: u16 = 0x000b;
+
+/// The type of an entry in the event log
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u32)]
+pub enum EventType {
+    /// Firmware code
+    PostCode = 0x1,
+    /// Information that is not extended into any PCR
+    NoAction = 0x3,
+    /// The end of the measurements of the pre-boot environment
+    Separator = 0x4,
+    /// Data loaded by the initial program loader
+    Ipl = 0xd,
+}
+
+const ROUND_CONSTANTS: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1,
+    0x923f82a4, 0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3,
+    0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786,
+    0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147,
+    0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13,
+    0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+    0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a,
+    0x5b9cca4f, 0x682e6ff3, 0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208,
+    0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+const INITIAL_STATE: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c,
+    0x1f83d9ab, 0x5be0cd19,
+];
+
+/// An incremental SHA-256 hash (see FIPS 180-4)
+pub struct Sha256 {
+    state: [u32; 8],
+    block: [u8; 64],
+    block_len: usize,
+    length: u64,
+}
+
+impl Sha256 {
+    pub fn new() -> Self {
+        Self {
+            state: INITIAL_STATE,
+            block: [0; 64],
+            block_len: 0,
+            length: 0,
+        }
+    }
+
+    /// Add `data` to the hashed message
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.length += data.len() as u64;
+        while !data.is_empty() {
+            let count = core::cmp::min(64 - self.block_len, data.len());
+            self.block[self.block_len..self.block_len + count]
+                .copy_from_slice(&data[..count]);
+            self.block_len += count;
+            data = &data[count..];
+            if self.block_len == 64 {
+                compress(&mut self.state, &self.block);
+                self.block_len = 0;
+            }
+        }
+    }
+
+    /// The digest of the message
+    pub fn finish(mut self) -> Digest {
+        let bits = self.length.wrapping_mul(8);
+
+        // Pad with a one bit, then zeros up to the 64-bit message length
+        let mut padding = [0u8; 64];
+        padding[0] = 0x80;
+        let zeros = (119 - self.block_len) % 64;
+        self.update(&padding[..zeros + 1]);
+        let mut length = [0u8; 8];
+        length.copy_from_slice(&bits.to_be_bytes());
+        self.update(&length);
+
+        let mut digest = [0u8; DIGEST_SIZE];
+        for (chunk, word) in digest.chunks_mut(4).zip(self.state.iter()) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+}
+
+fn compress(state: &mut [u32; 8], block: &[u8; 64]) {
+    let mut w = [0u32; 64];
+    for (i, chunk) in block.chunks(4).enumerate() {
+        w[i] = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+    }
+    for i in 16..64 {
+        let s0 = w[i - 15].rotate_right(7)
+            ^ w[i - 15].rotate_right(18)
+            ^ (w[i - 15] >> 3);
+        let s1 = w[i - 2].rotate_right(17)
+            ^ w[i - 2].rotate_right(19)
+            ^ (w[i - 2] >> 10);
+        w[i] = w[i - 16]
+            .wrapping_add(s0)
+            .wrapping_add(w[i - 7])
+            .wrapping_add(s1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+    for i in 0..64 {
+        let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+        let ch = (e & f) ^ (!e & g);
+        let t1 = h
+            .wrapping_add(s1)
+            .wrapping_add(ch)
+            .wrapping_add(ROUND_CONSTANTS[i])
+            .wrapping_add(w[i]);
+        let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+        let maj = (a & b) ^ (a & c) ^ (b & c);
+        let t2 = s0.wrapping_add(maj);
+
+        h = g;
+        g = f;
+        f = e;
+        e = d.wrapping_add(t1);
+        d = c;
+        c = b;
+        b = a;
+        a = t1.wrapping_add(t2);
+    }
+
+    for (word, value) in state.iter_mut().zip([a, b, c, d, e, f, g, h].iter()) {
+        *word = word.wrapping_add(*value);
+    }
+}
+
+/// The SHA-256 digest of `data`
+pub fn sha256(data: &[u8]) -> Digest {
+    let mut hash = Sha256::new();
+    hash.update(data);
+    hash.finish()
+}
+
+/// The PCRs of a VM and the event log describing their values
+pub struct MeasuredBoot {
+    pcrs: [Digest; PCR_COUNT],
+    log: Vec<u8>,
+    events: usize,
+}
+
+impl MeasuredBoot {
+    /// An empty bank of PCRs, and an event log containing only the
+    /// 'Spec ID' header event
+    pub fn new() -> Self {
+        let mut header = vec![];
+        header.extend_from_slice(b"Spec ID Event03\0");
+        header.extend_from_slice(&0u32.to_le_bytes()); // Platform class
+
+        // Version 2.0 errata 0, with 64-bit UINTN
+        header.extend_from_slice(&[0, 2, 0, 2]);
+        header.extend_from_slice(&1u32.to_le_bytes());
+        header.extend_from_slice(&TPM_ALG_SHA256.to_le_bytes());
+        header.extend_from_slice(&(DIGEST_SIZE as u16).to_le_bytes());
+        header.push(0); // No vendor information
+
+        // The header event uses the original (SHA-1 only) event format
+        let mut log = vec![];
+        log.extend_from_slice(&0u32.to_le_bytes());
+        log.extend_from_slice(&(EventType::NoAction as u32).to_le_bytes());
+        log.extend_from_slice(&[0u8; 20]);
+        log.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        log.extend_from_slice(&header);
+
+        Self {
+            pcrs: [[0; DIGEST_SIZE]; PCR_COUNT],
+            log,
+            events: 0,
+        }
+    }
+
+    /// Extend `pcr` with `digest`, and record the extension in the event
+    /// log with the given event type and data
+    pub fn extend(
+        &mut self,
+        pcr: usize,
+        event: EventType,
+        digest: &Digest,
+        event_data: &[u8],
+    ) -> Result<()> {
+        if pcr >= PCR_COUNT {
+            return Err(Error::InvalidValue(format!("Invalid PCR {}", pcr)));
+        }
+
+        let mut hash = Sha256::new();
+        hash.update(&self.pcrs[pcr]);
+        hash.update(digest);
+        self.pcrs[pcr] = hash.finish();
+
+        self.log.extend_from_slice(&(pcr as u32).to_le_bytes());
+        self.log.extend_from_slice(&(event as u32).to_le_bytes());
+        self.log.extend_from_slice(&1u32.to_le_bytes());
+        self.log.extend_from_slice(&TPM_ALG_SHA256.to_le_bytes());
+        self.log.extend_from_slice(digest);
+        self.log
+            .extend_from_slice(&(event_data.len() as u32).to_le_bytes());
+        self.log.extend_from_slice(event_data);
+        self.events += 1;
+        Ok(())
+    }
+
+    /// Measure `data` into `pcr`, recording `description` as the event data
+    pub fn measure(
+        &mut self,
+        pcr: usize,
+        event: EventType,
+        data: &[u8],
+        description: &[u8],
+    ) -> Result<()> {
+        self.extend(pcr, event, &sha256(data), description)
+    }
+
+    /// Mark the end of the pre-boot measurements in PCRs 0 through 7
+    pub fn separate(&mut self) -> Result<()> {
+        let data = [0u8; 4];
+        for pcr in 0..8 {
+            self.measure(pcr, EventType::Separator, &data, &data)?;
+        }
+        Ok(())
+    }
+
+    /// The current value of `pcr`
+    pub fn pcr(&self, pcr: usize) -> Option<&Digest> {
+        self.pcrs.get(pcr)
+    }
+
+    /// The current values of every PCR
+    pub fn pcrs(&self) -> &[Digest; PCR_COUNT] {
+        &self.pcrs
+    }
+
+    /// The event log, in the TCG crypto agile format
+    pub fn event_log(&self) -> &[u8] {
+        &self.log
+    }
+
+    /// The number of measurements recorded in the log
+    pub fn event_count(&self) -> usize {
+        self.events
+    }
+}
+
+/// Measure the firmware and the boot images of the VM described by
+/// `config`
+pub fn measure_boot(
+    config: &vm::VirtualMachineConfig,
+    info: &BootInfo,
+) -> Result<MeasuredBoot> {
+    let mut measurements = MeasuredBoot::new();
+    measurements.measure(
+        FIRMWARE_PCR,
+        EventType::PostCode,
+        config.firmware_image(info)?,
+        config.firmware().unwrap_or("bios.bin").as_bytes(),
+    )?;
+    measurements.separate()?;
+
+    let image = match config.boot_method() {
+        vm::BootMethod::DirectKernel(image) | vm::BootMethod::Pvh(image) => {
+            image
+        }
+        vm::BootMethod::Firmware => return Ok(measurements),
+    };
+
+    let find_module = |name: &str| {
+        info.find_module(name)
+            .map(|module| module.data())
+            .ok_or_else(|| {
+                Error::InvalidValue(format!("No such module '{}'", name))
+            })
+    };
+    measurements.measure(
+        KERNEL_PCR,
+        EventType::Ipl,
+        find_module(&image.kernel)?,
+        image.kernel.as_bytes(),
+    )?;
+    if let Some(initramfs) = &image.initramfs {
+        measurements.measure(
+            KERNEL_PCR,
+            EventType::Ipl,
+            find_module(initramfs)?,
+            initramfs.as_bytes(),
+        )?;
+    }
+
+    // The command line is measured without its NULL terminator
+    let cmdline = match image.cmdline.iter().position(|byte| *byte == 0) {
+        Some(len) => &image.cmdline[..len],
+        None => &image.cmdline[..],
+    };
+    measurements.measure(CMDLINE_PCR, EventType::Ipl, cmdline, cmdline)?;
+    Ok(measurements)
+}
+
+/// The `measurements` management console command
+pub fn measurements_command(
+    out: &mut dyn fmt::Write,
+    args: &[&str],
+) -> fmt::Result {
+    let vm_id = match args.get(0).and_then(|vm| vm::find_vm(vm)) {
+        Some(vm_id) => vm_id,
+        None => return writeln!(out, "usage: measurements <vm>"),
+    };
+    let vm = match unsafe { vm::get_vm(vm_id) } {
+        Some(vm) => vm,
+        None => return writeln!(out, "No VM {}", vm_id),
+    };
+    let vm = vm.read();
+    let measurements = match vm.config.measurements() {
+        Some(measurements) => measurements,
+        None => {
+            return writeln!(
+                out,
+                "Measured boot is not enabled for VM {}",
+                vm_id
+            )
+        }
+    };
+
+    writeln!(
+        out,
+        "{} events ({} bytes of log)",
+        measurements.event_count(),
+        measurements.event_log().len()
+    )?;
+    for pcr in 0..PCR_COUNT {
+        let digest = measurements.pcr(pcr).unwrap();
+        if digest.iter().all(|byte| *byte == 0) {
+            continue;
+        }
+        write!(out, "PCR{:<2} ", pcr)?;
+        for byte in digest.iter() {
+            write!(out, "{:02x}", byte)?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use byteorder::{ByteOrder, LittleEndian};
+
+    fn hex(digest: &Digest) -> alloc::string::String {
+        digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn test_sha256() {
+        assert_eq!(
+            hex(&sha256(b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hex(&sha256(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        let message =
+            b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+        assert_eq!(
+            hex(&sha256(message)),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+
+        // Incremental updates across block boundaries
+        let mut hash = Sha256::new();
+        for chunk in message.chunks(7) {
+            hash.update(chunk);
+        }
+        assert_eq!(hash.finish(), sha256(message));
+    }
+
+    #[test]
+    fn test_measure() {
+        let mut measurements = MeasuredBoot::new();
+        let header_len = measurements.event_log().len();
+        assert_eq!(header_len, 32 + 33);
+        assert_eq!(&measurements.event_log()[32..47], b"Spec ID Event03");
+
+        measurements
+            .measure(KERNEL_PCR, EventType::Ipl, b"abc", b"kernel")
+            .unwrap();
+        let mut expected = [0u8; 64];
+        expected[32..].copy_from_slice(&sha256(b"abc"));
+        assert_eq!(measurements.pcr(KERNEL_PCR), Some(&sha256(&expected)));
+        assert_eq!(measurements.pcr(0), Some(&[0; DIGEST_SIZE]));
+        assert_eq!(measurements.event_count(), 1);
+
+        let event = &measurements.event_log()[header_len..];
+        assert_eq!(event.len(), 4 + 4 + 4 + 2 + 32 + 4 + 6);
+        assert_eq!(LittleEndian::read_u32(&event[0..4]), KERNEL_PCR as u32);
+        assert_eq!(LittleEndian::read_u32(&event[4..8]), 0xd);
+        assert_eq!(LittleEndian::read_u16(&event[12..14]), TPM_ALG_SHA256);
+        assert_eq!(&event[14..46], &sha256(b"abc"));
+        assert_eq!(&event[50..], b"kernel");
+
+        assert!(measurements
+            .measure(PCR_COUNT, EventType::Ipl, b"abc", b"")
+            .is_err());
+    }
+}
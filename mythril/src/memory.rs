@@ -1,6 +1,9 @@
 use crate::error::{Error, Result};
+use crate::pagewalk::{self, PageTableMemory, PagingContext, PagingMode};
 use crate::vmcs;
 use alloc::boxed::Box;
+use alloc::collections::BTreeSet;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use bitflags::bitflags;
 use core::borrow::{Borrow, BorrowMut};
@@ -9,9 +12,9 @@ use core::default::Default;
 use core::fmt;
 use core::ops::{Add, Deref, Index, IndexMut};
 use num_enum::TryFromPrimitive;
+use spin::Mutex;
 use ux;
 use x86::bits64::paging::*;
-use x86::controlregs::Cr0;
 
 #[repr(align(4096))]
 pub struct Raw4kPage(pub [u8; 4096]);
@@ -21,6 +24,11 @@ impl Default for Raw4kPage {
     }
 }
 
+#[inline]
+fn pml5_index(addr: u64) -> ux::u9 {
+    ux::u9::new(((addr >> 48usize) & 0b111111111) as u16)
+}
+
 #[inline]
 fn pml4_index(addr: u64) -> ux::u9 {
     ux::u9::new(((addr >> 39usize) & 0b111111111) as u16)
@@ -60,20 +68,23 @@ fn huge_page_offset(addr: u64) -> ux::u30 {
 pub enum GuestVirtAddr {
     NoPaging(GuestPhysAddr),
     Paging4Level(Guest4LevelPagingAddr),
-    //TODO: 5 level paging
+    Paging5Level(Guest5LevelPagingAddr),
 }
 
 impl GuestVirtAddr {
     // Convert a 64 bit number to a virtual address in the context of the current
     // guest configuration (as read from a VMCS)
     pub fn new(val: u64, vmcs: &vmcs::ActiveVmcs) -> Result<Self> {
-        let cr0 = Cr0::from_bits_truncate(
-            vmcs.read_field(vmcs::VmcsField::GuestCr0)? as usize,
-        );
-        if cr0.contains(Cr0::CR0_ENABLE_PAGING) {
-            Ok(GuestVirtAddr::Paging4Level(Guest4LevelPagingAddr::new(val)))
-        } else {
-            Ok(GuestVirtAddr::NoPaging(GuestPhysAddr::new(val)))
+        match PagingMode::from_vmcs(vmcs)? {
+            PagingMode::Disabled => {
+                Ok(GuestVirtAddr::NoPaging(GuestPhysAddr::new(val)))
+            }
+            PagingMode::Level5 => {
+                Ok(GuestVirtAddr::Paging5Level(Guest5LevelPagingAddr::new(val)))
+            }
+            _ => {
+                Ok(GuestVirtAddr::Paging4Level(Guest4LevelPagingAddr::new(val)))
+            }
         }
     }
 
@@ -81,6 +92,17 @@ impl GuestVirtAddr {
         match self {
             Self::NoPaging(addr) => addr.as_u64(),
             Self::Paging4Level(addr) => addr.as_u64(),
+            Self::Paging5Level(addr) => addr.as_u64(),
+        }
+    }
+
+    /// Returns true if this address is canonical for the paging mode it
+    /// was created in
+    pub fn is_canonical(&self) -> bool {
+        match self {
+            Self::NoPaging(_) => true,
+            Self::Paging4Level(addr) => is_canonical(addr.as_u64()),
+            Self::Paging5Level(addr) => is_canonical_la57(addr.as_u64()),
         }
     }
 }
@@ -92,6 +114,7 @@ impl Add<usize> for GuestVirtAddr {
         match self {
             Self::NoPaging(addr) => Self::NoPaging(addr + rhs),
             Self::Paging4Level(addr) => Self::Paging4Level(addr + rhs),
+            Self::Paging5Level(addr) => Self::Paging5Level(addr + rhs),
         }
     }
 }
@@ -144,6 +167,50 @@ impl Add<usize> for Guest4LevelPagingAddr {
     }
 }
 
+#[derive(PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Debug)]
+pub struct Guest5LevelPagingAddr(u64);
+impl Guest5LevelPagingAddr {
+    pub fn new(addr: u64) -> Self {
+        Self(addr)
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+
+    pub fn p1_index(&self) -> ux::u9 {
+        pt_index(self.0)
+    }
+
+    pub fn p2_index(&self) -> ux::u9 {
+        pd_index(self.0)
+    }
+
+    pub fn p3_index(&self) -> ux::u9 {
+        pdpt_index(self.0)
+    }
+
+    pub fn p4_index(&self) -> ux::u9 {
+        pml4_index(self.0)
+    }
+
+    pub fn p5_index(&self) -> ux::u9 {
+        pml5_index(self.0)
+    }
+
+    pub fn page_offset(&self) -> ux::u12 {
+        page_offset(self.0)
+    }
+}
+
+impl Add<usize> for Guest5LevelPagingAddr {
+    type Output = Guest5LevelPagingAddr;
+
+    fn add(self, rhs: usize) -> Self::Output {
+        Guest5LevelPagingAddr(self.0 + (rhs as u64))
+    }
+}
+
 #[derive(PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
 pub struct GuestPhysAddr(u64);
 
@@ -248,8 +315,117 @@ impl HostPhysFrame {
     }
 }
 
+// Frames released by guests, which must be zeroed before they are reused
+static RELEASED_FRAMES: Mutex<Vec<HostPhysFrame>> = Mutex::new(Vec::new());
+
+// Zeroed frames that are ready to be mapped into a guest
+static ZEROED_FRAMES: Mutex<Vec<HostPhysFrame>> = Mutex::new(Vec::new());
+
+/// Return a frame of guest memory (that is no longer mapped in any guest)
+/// to be reused
+///
+/// The frame may hold guest data, so it is zeroed in the background (see
+/// `background::ScrubTask`) before it is reused.
+pub fn release_frame(frame: HostPhysFrame) {
+    RELEASED_FRAMES.lock().push(frame);
+}
+
+/// Zero up to `count` released frames, returning the number that remain
+pub fn scrub_frames(count: usize) -> usize {
+    for _ in 0..count {
+        let mut frame = match RELEASED_FRAMES.lock().pop() {
+            Some(frame) => frame,
+            None => return 0,
+        };
+        for byte in unsafe { frame.as_mut_array() }.iter_mut() {
+            *byte = 0;
+        }
+        ZEROED_FRAMES.lock().push(frame);
+    }
+    RELEASED_FRAMES.lock().len()
+}
+
+/// Allocate a zeroed frame for guest memory, reusing a scrubbed frame if
+/// there is one
+pub fn allocate_zeroed_frame() -> Result<HostPhysFrame> {
+    if let Some(frame) = ZEROED_FRAMES.lock().pop() {
+        return Ok(frame);
+    }
+    let page = Box::into_raw(Box::new(Raw4kPage::default()));
+    HostPhysFrame::from_start_address(HostPhysAddr::new(page as u64))
+}
+
+/// Receives notifications when the mappings of a `GuestAddressSpace` change
+///
+/// This allows other translation structures (e.g., the DMA remapping tables
+/// of an IOMMU) to be kept in sync with the EPT.
+pub trait MappingObserver: Send + Sync {
+    /// Called after `guest_addr` has been mapped to `host_frame`
+    fn frame_mapped(
+        &self,
+        guest_addr: GuestPhysAddr,
+        host_frame: HostPhysFrame,
+        readonly: bool,
+    ) -> Result<()>;
+
+    /// Called after the mapping for `guest_addr` has been removed
+    fn frame_unmapped(&self, guest_addr: GuestPhysAddr) -> Result<()>;
+}
+
 pub struct GuestAddressSpace {
     root: Box<EptPml4Table>,
+    observers: Vec<Arc<dyn MappingObserver>>,
+    lazy: Option<LazyMemory>,
+    // Pages whose frames were reclaimed (see `reclaim_frame`)
+    reclaimed: BTreeSet<u64>,
+}
+
+// Guest RAM that is only allocated when it is first touched
+struct LazyMemory {
+    // One bit per page, set once the page has been mapped
+    populated: Vec<u64>,
+    pages: u64,
+    // The number of pages that have not been mapped
+    remaining: u64,
+    // All of the pages below this have been mapped
+    cursor: u64,
+}
+
+impl LazyMemory {
+    fn new(pages: u64) -> Self {
+        Self {
+            populated: vec![0; ((pages + 63) / 64) as usize],
+            pages,
+            remaining: pages,
+            cursor: 0,
+        }
+    }
+
+    // Pages outside of the lazily allocated range are never populated
+    // on demand, so they are considered populated
+    fn is_populated(&self, page: u64) -> bool {
+        page >= self.pages
+            || self.populated[(page / 64) as usize] & (1 << (page % 64)) != 0
+    }
+
+    // Record that `page` has been mapped
+    fn mark_populated(&mut self, page: u64) {
+        if !self.is_populated(page) {
+            self.populated[(page / 64) as usize] |= 1 << (page % 64);
+            self.remaining -= 1;
+        }
+    }
+
+    // The lowest page that has not been mapped (if any)
+    fn next_unpopulated(&mut self) -> Option<u64> {
+        while self.cursor < self.pages {
+            if !self.is_populated(self.cursor) {
+                return Some(self.cursor);
+            }
+            self.cursor += 1;
+        }
+        None
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -262,20 +438,192 @@ pub enum GuestAccess {
     Fetch(PrivilegeLevel),
 }
 
+/// An exception that is delivered to the guest when it provides an invalid
+/// address to an emulated operation
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum GuestFault {
+    /// A general protection fault (#GP) with the given error code
+    GeneralProtection(u32),
+    /// A page fault (#PF) for the linear address `addr`
+    PageFault { addr: u64, error_code: u32 },
+}
+
+impl GuestFault {
+    /// The exception vector of this fault
+    pub fn vector(&self) -> u8 {
+        match self {
+            GuestFault::GeneralProtection(_) => 13,
+            GuestFault::PageFault { .. } => 14,
+        }
+    }
+
+    /// The error code pushed by this fault
+    pub fn error_code(&self) -> u32 {
+        match self {
+            GuestFault::GeneralProtection(code) => *code,
+            GuestFault::PageFault { error_code, .. } => *error_code,
+        }
+    }
+}
+
+impl From<GuestFault> for Error {
+    fn from(fault: GuestFault) -> Error {
+        Error::GuestFault(fault)
+    }
+}
+
+// Returns true if bits 63:width-1 of `addr` are all equal
+fn is_canonical_width(addr: u64, width: u32) -> bool {
+    let upper = addr >> (width - 1);
+    upper == 0 || upper == (!0u64 >> (width - 1))
+}
+
+/// Returns true if `addr` is canonical for 48-bit linear addresses
+pub fn is_canonical(addr: u64) -> bool {
+    is_canonical_width(addr, 48)
+}
+
+/// Returns true if `addr` is canonical for 57-bit linear addresses (with
+/// 5-level paging)
+pub fn is_canonical_la57(addr: u64) -> bool {
+    is_canonical_width(addr, 57)
+}
+
+/// Returns true if `addr` is canonical for the current paging mode of the
+/// host
+///
+/// Mythril sets up 4-level paging itself, but the host may be running
+/// with 5-level paging (and wider linear addresses) if it was entered
+/// with CR4.LA57 already set.
+pub fn is_host_canonical(addr: u64) -> bool {
+    let cr4 = unsafe { x86::controlregs::cr4() }.bits() as u64;
+    if cr4 & (1 << 12) != 0 {
+        is_canonical_la57(addr)
+    } else {
+        is_canonical(addr)
+    }
+}
+
+/// Verify that an access of `length` bytes starting at the guest linear
+/// address `addr` is canonical and does not wrap around the address space.
+pub fn validate_linear_range(addr: GuestVirtAddr, length: usize) -> Result<()> {
+    if length == 0 {
+        return Ok(());
+    }
+    let start = addr.as_u64();
+    let last = start
+        .checked_add(length as u64 - 1)
+        .ok_or(GuestFault::GeneralProtection(0))?;
+    match addr {
+        // Without paging, linear addresses are at most 32 bits
+        GuestVirtAddr::NoPaging(_) => {
+            if last > u32::MAX as u64 {
+                return Err(GuestFault::GeneralProtection(0).into());
+            }
+        }
+        // Both ends must be canonical and on the same side of the
+        // non-canonical hole
+        GuestVirtAddr::Paging4Level(_) => {
+            if !is_canonical(start)
+                || !is_canonical(last)
+                || (start >> 47) != (last >> 47)
+            {
+                return Err(GuestFault::GeneralProtection(0).into());
+            }
+        }
+        GuestVirtAddr::Paging5Level(_) => {
+            if !is_canonical_la57(start)
+                || !is_canonical_la57(last)
+                || (start >> 56) != (last >> 56)
+            {
+                return Err(GuestFault::GeneralProtection(0).into());
+            }
+        }
+    }
+    Ok(())
+}
+
 impl GuestAddressSpace {
     pub fn new() -> Result<Self> {
         Ok(GuestAddressSpace {
             root: Box::new(EptPml4Table::default()),
+            observers: vec![],
+            lazy: None,
+            reclaimed: BTreeSet::new(),
         })
     }
 
+    /// Allocate the RAM below `size` (in bytes) when the guest first
+    /// touches each page, rather than up front
+    ///
+    /// Pages that are mapped explicitly (e.g., firmware and images) are
+    /// never allocated lazily, even if they are later unmapped.
+    pub fn set_lazy_memory(&mut self, size: u64) {
+        let mut lazy = LazyMemory::new(size / HostPhysFrame::SIZE as u64);
+        for (addr, _, _) in self.mappings() {
+            lazy.mark_populated(addr.as_u64() / HostPhysFrame::SIZE as u64);
+        }
+        self.lazy = Some(lazy);
+    }
+
+    /// Map a new frame at `addr` if it is lazily allocated RAM that has
+    /// not been touched yet, or RAM that was reclaimed, returning true if a
+    /// frame was mapped
+    pub fn populate(&mut self, addr: GuestPhysAddr) -> Result<bool> {
+        let page = addr.as_u64() / HostPhysFrame::SIZE as u64;
+        match &self.lazy {
+            _ if self.reclaimed.remove(&page) => (),
+            Some(lazy) if !lazy.is_populated(page) => (),
+            _ => return Ok(false),
+        }
+        self.map_new_frame(
+            GuestPhysAddr::new(page * HostPhysFrame::SIZE as u64),
+            false,
+        )?;
+        Ok(true)
+    }
+
+    /// Map up to `count` of the lazily allocated pages that have not been
+    /// touched yet, returning the number of pages that remain unmapped
+    pub fn prefault(&mut self, count: usize) -> Result<u64> {
+        for _ in 0..count {
+            let page = match self
+                .lazy
+                .as_mut()
+                .and_then(|lazy| lazy.next_unpopulated())
+            {
+                Some(page) => page,
+                None => break,
+            };
+            self.map_new_frame(
+                GuestPhysAddr::new(page * HostPhysFrame::SIZE as u64),
+                false,
+            )?;
+        }
+        Ok(self.lazy.as_ref().map(|lazy| lazy.remaining).unwrap_or(0))
+    }
+
+    /// Notify `observer` of all future changes to the mappings
+    pub fn add_observer(&mut self, observer: Arc<dyn MappingObserver>) {
+        self.observers.push(observer);
+    }
+
     pub fn map_frame(
         &mut self,
         guest_addr: GuestPhysAddr,
         host_frame: HostPhysFrame,
         readonly: bool,
     ) -> Result<()> {
-        map_guest_memory(&mut self.root, guest_addr, host_frame, readonly)
+        map_guest_memory(&mut self.root, guest_addr, host_frame, readonly)?;
+        if let Some(lazy) = self.lazy.as_mut() {
+            lazy.mark_populated(
+                guest_addr.as_u64() / HostPhysFrame::SIZE as u64,
+            );
+        }
+        for observer in self.observers.iter() {
+            observer.frame_mapped(guest_addr, host_frame, readonly)?;
+        }
+        Ok(())
     }
 
     pub fn map_new_frame(
@@ -283,20 +631,164 @@ impl GuestAddressSpace {
         guest_addr: GuestPhysAddr,
         readonly: bool,
     ) -> Result<()> {
-        let page = Box::into_raw(Box::new(Raw4kPage::default()));
-        let page =
-            HostPhysFrame::from_start_address(HostPhysAddr::new(page as u64))?;
+        let page = allocate_zeroed_frame()?;
         self.map_frame(guest_addr, page, readonly)
     }
 
+    /// Remove the mapping for `guest_addr` (if any), returning the frame
+    /// that was mapped there
+    ///
+    /// The frame is not freed, and the caller is responsible for invalidating
+    /// any cached translations (e.g., with INVEPT).
+    pub fn unmap_frame(
+        &mut self,
+        guest_addr: GuestPhysAddr,
+    ) -> Result<Option<HostPhysFrame>> {
+        let ept_pte = match self.leaf_entry(guest_addr) {
+            Some(ept_pte) => ept_pte,
+            None => return Ok(None),
+        };
+        let frame = HostPhysFrame::from_start_address(ept_pte.addr())?;
+        ept_pte.set_unused();
+        for observer in self.observers.iter() {
+            observer.frame_unmapped(guest_addr)?;
+        }
+        Ok(Some(frame))
+    }
+
+    // The EPT entry that maps the 4KB page at `guest_addr` (if any)
+    fn leaf_entry(
+        &mut self,
+        guest_addr: GuestPhysAddr,
+    ) -> Option<&mut EptPageTableEntry> {
+        let ept_pml4e = &self.root[guest_addr.p4_index()];
+        if ept_pml4e.is_unused() {
+            return None;
+        }
+        let ept_pdpt =
+            ept_pml4e.addr().as_u64() as *const EptPageDirectoryPointerTable;
+        let ept_pdpe = unsafe { &(*ept_pdpt)[guest_addr.p3_index()] };
+        if ept_pdpe.is_unused() {
+            return None;
+        }
+        let ept_pdt = ept_pdpe.addr().as_u64() as *const EptPageDirectory;
+        let ept_pde = unsafe { &(*ept_pdt)[guest_addr.p2_index()] };
+        if ept_pde.is_unused() {
+            return None;
+        }
+        let ept_pt = ept_pde.addr().as_u64() as *mut EptPageTable;
+        let ept_pte = unsafe { &mut (*ept_pt)[guest_addr.p1_index()] };
+        if ept_pte.is_unused() {
+            return None;
+        }
+        Some(ept_pte)
+    }
+
+    /// Take back the frame of guest RAM at `guest_addr` (e.g., a page given
+    /// up by a balloon driver), returning true if a frame was reclaimed
+    ///
+    /// The frame is released to be reused, possibly by another VM (see
+    /// `release_frame`). If the guest touches the page again, it is given a
+    /// new zeroed frame (see `populate`). Read-only mappings (e.g., of boot
+    /// modules) are not reclaimed. As with `unmap_frame`, the caller must
+    /// invalidate cached translations.
+    pub fn reclaim_frame(&mut self, guest_addr: GuestPhysAddr) -> Result<bool> {
+        match self.leaf_entry(guest_addr) {
+            Some(pte) if pte.flags().contains(EptTableFlags::WRITE_ACCESS) => {}
+            _ => return Ok(false),
+        }
+        let frame = match self.unmap_frame(guest_addr)? {
+            Some(frame) => frame,
+            None => return Ok(false),
+        };
+        release_frame(frame);
+        self.reclaimed
+            .insert(guest_addr.as_u64() / HostPhysFrame::SIZE as u64);
+        Ok(true)
+    }
+
+    /// The number of pages whose frames were reclaimed and not yet
+    /// repopulated
+    pub fn reclaimed_pages(&self) -> usize {
+        self.reclaimed.len()
+    }
+
+    /// All of the (4KB) mappings in this address space, with whether each
+    /// mapping is read-only
+    pub fn mappings(&self) -> Vec<(GuestPhysAddr, HostPhysFrame, bool)> {
+        let mut mappings = vec![];
+        for (p4, pml4e) in self.root.entries.iter().enumerate() {
+            if pml4e.is_unused() {
+                continue;
+            }
+            let pdpt = unsafe {
+                &*(pml4e.addr().as_u64() as *const EptPageDirectoryPointerTable)
+            };
+            for (p3, pdpe) in pdpt.entries.iter().enumerate() {
+                if pdpe.is_unused() {
+                    continue;
+                }
+                let pdt = unsafe {
+                    &*(pdpe.addr().as_u64() as *const EptPageDirectory)
+                };
+                for (p2, pde) in pdt.entries.iter().enumerate() {
+                    if pde.is_unused() {
+                        continue;
+                    }
+                    let pt = unsafe {
+                        &*(pde.addr().as_u64() as *const EptPageTable)
+                    };
+                    for (p1, pte) in pt.entries.iter().enumerate() {
+                        if pte.is_unused() {
+                            continue;
+                        }
+                        let addr = (p4 as u64) << 39
+                            | (p3 as u64) << 30
+                            | (p2 as u64) << 21
+                            | (p1 as u64) << 12;
+                        let frame = HostPhysFrame(pte.addr());
+                        let readonly =
+                            !pte.flags().contains(EptTableFlags::WRITE_ACCESS);
+                        mappings.push((
+                            GuestPhysAddr::new(addr),
+                            frame,
+                            readonly,
+                        ));
+                    }
+                }
+            }
+        }
+        mappings
+    }
+
+    /// The host physical address of the root (PML4) of the EPT
+    pub fn root_address(&self) -> HostPhysAddr {
+        HostPhysAddr::new(&*self.root as *const _ as u64)
+    }
+
+    /// Replace the mapping for `guest_addr` (if any) with `host_frame`
+    ///
+    /// As with `unmap_frame`, the caller must invalidate cached translations.
+    pub fn remap_frame(
+        &mut self,
+        guest_addr: GuestPhysAddr,
+        host_frame: HostPhysFrame,
+        readonly: bool,
+    ) -> Result<()> {
+        self.unmap_frame(guest_addr)?;
+        self.map_frame(guest_addr, host_frame, readonly)
+    }
+
     pub fn eptp(&self) -> u64 {
         // //TODO: check available memory types
         (&*self.root as *const _ as u64) | (4 - 1) << 3 | 6
     }
 
+    /// Translate the guest linear address `addr` using the guest page
+    /// tables described by `paging` (see `pagewalk::walk`)
     pub fn translate_linear_address(
         &self,
-        cr3: GuestPhysAddr,
+        paging: &PagingContext,
         addr: GuestVirtAddr,
         access: GuestAccess,
     ) -> Result<GuestPhysAddr> {
@@ -304,51 +796,12 @@ impl GuestAddressSpace {
             GuestVirtAddr::NoPaging(addr) => {
                 Ok(GuestPhysAddr::new(addr.as_u64()))
             }
-            GuestVirtAddr::Paging4Level(vaddr) => {
-                self.translate_pl4_address(cr3, vaddr, access)
+            GuestVirtAddr::Paging4Level(_) | GuestVirtAddr::Paging5Level(_) => {
+                Ok(pagewalk::walk(paging, self, addr.as_u64(), access)?.addr)
             }
         }
     }
 
-    //FIXME: this should check that the pages exist, access restrictions, guest page size,
-    //       and lots of other things
-    fn translate_pl4_address(
-        &self,
-        cr3: GuestPhysAddr,
-        addr: Guest4LevelPagingAddr,
-        _access: GuestAccess,
-    ) -> Result<GuestPhysAddr> {
-        let guest_pml4_root = self.find_host_frame(cr3)?;
-
-        let guest_pml4 =
-            guest_pml4_root.start_address().as_u64() as *const PML4;
-        let guest_pml4e =
-            unsafe { (*guest_pml4)[u16::from(addr.p4_index()) as usize] };
-        let guest_pml4e_addr =
-            GuestPhysAddr::new(guest_pml4e.address().as_u64());
-        let guest_pml4e_host_frame = self.find_host_frame(guest_pml4e_addr)?;
-
-        let guest_pdpt =
-            guest_pml4e_host_frame.start_address().as_u64() as *const PDPT;
-        let guest_pdpte =
-            unsafe { (*guest_pdpt)[u16::from(addr.p3_index()) as usize] };
-        let guest_pdpte_addr =
-            GuestPhysAddr::new(guest_pdpte.address().as_u64());
-        let guest_pdpte_host_frame = self.find_host_frame(guest_pdpte_addr)?;
-
-        let guest_pdt =
-            guest_pdpte_host_frame.start_address().as_u64() as *const PD;
-        let guest_pdte =
-            unsafe { (*guest_pdt)[u16::from(addr.p2_index()) as usize] };
-        let _guest_pdte_addr =
-            GuestPhysAddr::new(guest_pdte.address().as_u64());
-
-        let translated_vaddr = guest_pdte.address().as_u64()
-            + (u32::from(addr.large_page_offset()) as u64);
-
-        Ok(GuestPhysAddr::new(translated_vaddr))
-    }
-
     //FIXME this ignores read/write/exec permissions and 2MB/1GB pages (and lots of other stuff)
     pub fn find_host_frame(
         &self,
@@ -385,15 +838,59 @@ impl GuestAddressSpace {
         HostPhysFrame::from_start_address(ept_pte.addr())
     }
 
+    /// Verify that a guest-provided range of `length` bytes at `addr` is
+    /// valid and backed by guest memory, before it is used in emulation.
+    ///
+    /// Invalid ranges result in a `GuestFault`, so a guest cannot provoke
+    /// hypervisor errors with crafted addresses.
+    pub fn validate_range(
+        &self,
+        paging: &PagingContext,
+        addr: GuestVirtAddr,
+        length: usize,
+        access: GuestAccess,
+    ) -> Result<()> {
+        validate_linear_range(addr, length)?;
+        if length == 0 {
+            return Ok(());
+        }
+
+        let view = GuestAddressSpaceView::with_paging(*paging, self);
+        let first = addr.as_u64() & !(HostPhysFrame::SIZE as u64 - 1);
+        let last = addr.as_u64() + (length as u64 - 1);
+        let mut page = addr;
+        let mut page_start = first;
+        loop {
+            // Page faults are reflected to the guest as they are, but
+            // accesses outside of guest memory raise #GP
+            match view
+                .translate_linear_address(page, access)
+                .and_then(|physaddr| view.find_host_frame(physaddr))
+            {
+                Ok(_) => (),
+                Err(Error::GuestFault(fault)) => return Err(fault.into()),
+                Err(_) => return Err(GuestFault::GeneralProtection(0).into()),
+            }
+
+            if last - page_start < HostPhysFrame::SIZE as u64 {
+                return Ok(());
+            }
+            let step =
+                (page_start + HostPhysFrame::SIZE as u64) - page.as_u64();
+            page = page + step as usize;
+            page_start += HostPhysFrame::SIZE as u64;
+        }
+    }
+
     pub fn frame_iter(
         &self,
-        cr3: GuestPhysAddr,
+        paging: &PagingContext,
         addr: GuestVirtAddr,
         access: GuestAccess,
     ) -> Result<FrameIter> {
         //TODO: align the addr to 4096 boundary
         Ok(FrameIter {
-            view: GuestAddressSpaceView::new(cr3, self),
+            view: GuestAddressSpaceView::with_paging(*paging, self),
             addr: addr,
             access: access,
         })
@@ -401,28 +898,41 @@ impl GuestAddressSpace {
 
     pub fn read_bytes(
         &self,
-        cr3: GuestPhysAddr,
+        paging: &PagingContext,
         addr: GuestVirtAddr,
-        mut length: usize,
+        length: usize,
         access: GuestAccess,
     ) -> Result<Vec<u8>> {
-        let mut out = vec![];
-        let iter = self.frame_iter(cr3, addr, access)?;
+        let mut out = vec![0u8; length];
+        self.read_bytes_into(paging, addr, &mut out, access)?;
+        Ok(out)
+    }
+
+    /// Fill `out` with the bytes starting at `addr` without allocating
+    pub fn read_bytes_into(
+        &self,
+        paging: &PagingContext,
+        addr: GuestVirtAddr,
+        mut out: &mut [u8],
+        access: GuestAccess,
+    ) -> Result<()> {
+        if out.is_empty() {
+            return Ok(());
+        }
+        self.validate_range(paging, addr, out.len(), access)?;
+        let iter = self.frame_iter(paging, addr, access)?;
 
         let mut start_offset = addr.as_u64() as usize % HostPhysFrame::SIZE;
         for frame in iter {
             let frame = frame?;
             let array = unsafe { frame.as_array() };
-            let slice = if start_offset + length <= HostPhysFrame::SIZE {
-                &array[start_offset..start_offset + length]
-            } else {
-                &array[start_offset..]
-            };
-            out.extend_from_slice(slice);
-
-            length -= slice.len();
+            let len =
+                core::cmp::min(HostPhysFrame::SIZE - start_offset, out.len());
+            out[..len]
+                .copy_from_slice(&array[start_offset..start_offset + len]);
+            out = &mut out[len..];
 
-            if length == 0 {
+            if out.is_empty() {
                 break;
             }
 
@@ -430,17 +940,56 @@ impl GuestAddressSpace {
             start_offset = 0;
         }
 
-        Ok(out)
+        Ok(())
+    }
+
+    // Map the untouched lazily allocated pages in the given range, so they
+    // can be written by the hypervisor (e.g., for device DMA)
+    fn populate_range(
+        &mut self,
+        paging: &PagingContext,
+        addr: GuestVirtAddr,
+        length: usize,
+        access: GuestAccess,
+    ) -> Result<()> {
+        validate_linear_range(addr, length)?;
+        let pages = {
+            let view = GuestAddressSpaceView::with_paging(*paging, self);
+            let first = addr.as_u64() & !(HostPhysFrame::SIZE as u64 - 1);
+            let count = (addr.as_u64() + length as u64 - first
+                + (HostPhysFrame::SIZE as u64 - 1))
+                / HostPhysFrame::SIZE as u64;
+            (0..count)
+                .map(|i| {
+                    let page = if i == 0 {
+                        addr
+                    } else {
+                        addr + (first + i * HostPhysFrame::SIZE as u64
+                            - addr.as_u64())
+                            as usize
+                    };
+                    view.translate_linear_address(page, access)
+                })
+                .collect::<Result<Vec<_>>>()?
+        };
+        for page in pages {
+            self.populate(page)?;
+        }
+        Ok(())
     }
 
     pub fn write_bytes(
         &mut self,
-        cr3: GuestPhysAddr,
+        paging: &PagingContext,
         addr: GuestVirtAddr,
         mut bytes: &[u8],
         access: GuestAccess,
     ) -> Result<()> {
-        let iter = self.frame_iter(cr3, addr, access)?;
+        if self.lazy.is_some() && !bytes.is_empty() {
+            self.populate_range(paging, addr, bytes.len(), access)?;
+        }
+        self.validate_range(paging, addr, bytes.len(), access)?;
+        let iter = self.frame_iter(paging, addr, access)?;
 
         let mut start_offset = addr.as_u64() as usize % HostPhysFrame::SIZE;
         for frame in iter {
@@ -465,6 +1014,60 @@ impl GuestAddressSpace {
     }
 }
 
+impl PageTableMemory for GuestAddressSpace {
+    fn read_entry(&self, addr: GuestPhysAddr, size: usize) -> Result<u64> {
+        let frame = self.find_host_frame(addr)?;
+        let ptr = frame.start_address().as_u64() + (addr.as_u64() & 0xfff);
+        Ok(unsafe { pagewalk::read_host_entry(ptr as *const u8, size) })
+    }
+
+    fn set_entry_bits(
+        &self,
+        addr: GuestPhysAddr,
+        size: usize,
+        bits: u64,
+    ) -> Result<()> {
+        let frame = self.find_host_frame(addr)?;
+        let ptr = frame.start_address().as_u64() + (addr.as_u64() & 0xfff);
+        unsafe { pagewalk::set_host_entry_bits(ptr as *const u8, size, bits) };
+        Ok(())
+    }
+}
+
+/// Find the permissions of the EPT mapping for `addr` without locking the
+/// `GuestAddressSpace` that owns the tables
+///
+/// # Safety
+///
+/// `root` must be the root of a live EPT. The tables may be modified
+/// concurrently, so the result is only a hint (e.g., that a fault was caused
+/// by a stale translation).
+pub unsafe fn ept_permissions(
+    root: HostPhysAddr,
+    addr: GuestPhysAddr,
+) -> Option<EptTableFlags> {
+    let pml4e = &(*(root.as_u64() as *const EptPml4Table))[addr.p4_index()];
+    if pml4e.is_unused() {
+        return None;
+    }
+    let pdpt = pml4e.addr().as_u64() as *const EptPageDirectoryPointerTable;
+    let pdpe = &(*pdpt)[addr.p3_index()];
+    if pdpe.is_unused() {
+        return None;
+    }
+    let pdt = pdpe.addr().as_u64() as *const EptPageDirectory;
+    let pde = &(*pdt)[addr.p2_index()];
+    if pde.is_unused() {
+        return None;
+    }
+    let pt = pde.addr().as_u64() as *const EptPageTable;
+    let pte = &(*pt)[addr.p1_index()];
+    if pte.is_unused() {
+        return None;
+    }
+    Some(pte.flags())
+}
+
 pub type GuestAddressSpaceView<'a> =
     GuestAddressSpaceWrapper<&'a GuestAddressSpace>;
 pub type GuestAddressSpaceViewMut<'a> =
@@ -472,21 +1075,27 @@ pub type GuestAddressSpaceViewMut<'a> =
 
 pub struct GuestAddressSpaceWrapper<T> {
     space: T,
-    cr3: GuestPhysAddr,
+    paging: PagingContext,
 }
 
 impl<T> GuestAddressSpaceWrapper<T>
 where
     T: Borrow<GuestAddressSpace>,
 {
+    /// A view of `space` through the 4-level page tables at `cr3`
     pub fn new(cr3: GuestPhysAddr, space: T) -> Self {
-        Self { space, cr3 }
+        Self::with_paging(PagingContext::new(PagingMode::Level4, cr3), space)
     }
 
+    pub fn with_paging(paging: PagingContext, space: T) -> Self {
+        Self { space, paging }
+    }
+
+    /// A view of `space` through the current page tables of the guest of
+    /// `vmcs`
     pub fn from_vmcs(vmcs: &vmcs::ActiveVmcs, space: T) -> Result<Self> {
-        let cr3 = vmcs.read_field(vmcs::VmcsField::GuestCr3)?;
-        let cr3 = GuestPhysAddr::new(cr3);
-        Ok(Self { space, cr3 })
+        let paging = PagingContext::from_vmcs(vmcs)?;
+        Ok(Self { space, paging })
     }
 
     pub fn frame_iter(
@@ -494,7 +1103,7 @@ where
         addr: GuestVirtAddr,
         access: GuestAccess,
     ) -> Result<FrameIter> {
-        self.space.borrow().frame_iter(self.cr3, addr, access)
+        self.space.borrow().frame_iter(&self.paging, addr, access)
     }
 
     pub fn read_bytes(
@@ -505,7 +1114,29 @@ where
     ) -> Result<Vec<u8>> {
         self.space
             .borrow()
-            .read_bytes(self.cr3, addr, length, access)
+            .read_bytes(&self.paging, addr, length, access)
+    }
+
+    pub fn read_bytes_into(
+        &self,
+        addr: GuestVirtAddr,
+        out: &mut [u8],
+        access: GuestAccess,
+    ) -> Result<()> {
+        self.space
+            .borrow()
+            .read_bytes_into(&self.paging, addr, out, access)
+    }
+
+    pub fn validate_range(
+        &self,
+        addr: GuestVirtAddr,
+        length: usize,
+        access: GuestAccess,
+    ) -> Result<()> {
+        self.space
+            .borrow()
+            .validate_range(&self.paging, addr, length, access)
     }
 
     pub fn translate_linear_address(
@@ -515,7 +1146,7 @@ where
     ) -> Result<GuestPhysAddr> {
         self.space
             .borrow()
-            .translate_linear_address(self.cr3, addr, access)
+            .translate_linear_address(&self.paging, addr, access)
     }
 }
 
@@ -531,7 +1162,21 @@ where
     ) -> Result<()> {
         self.space
             .borrow_mut()
-            .write_bytes(self.cr3, addr, bytes, access)
+            .write_bytes(&self.paging, addr, bytes, access)
+    }
+
+    /// Access the underlying `GuestAddressSpace` mutably
+    pub fn space_mut(&mut self) -> &mut GuestAddressSpace {
+        self.space.borrow_mut()
+    }
+
+    /// A view of the same space, through the same paging structures, that
+    /// borrows this one (e.g., to deliver several events with one view)
+    pub fn reborrow(&mut self) -> GuestAddressSpaceViewMut<'_> {
+        GuestAddressSpaceWrapper::with_paging(
+            self.paging,
+            self.space.borrow_mut(),
+        )
     }
 }
 
@@ -780,3 +1425,128 @@ fn map_guest_memory(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_validate_linear_range() {
+        let paged = |addr| {
+            GuestVirtAddr::Paging4Level(Guest4LevelPagingAddr::new(addr))
+        };
+        let unpaged = |addr| GuestVirtAddr::NoPaging(GuestPhysAddr::new(addr));
+
+        assert!(validate_linear_range(paged(0x1000), 0x1000).is_ok());
+        assert!(validate_linear_range(paged(0xffff800000000000), 8).is_ok());
+        assert!(validate_linear_range(paged(0x0000800000000000), 1).is_err());
+        assert!(validate_linear_range(paged(0x00007ffffffffffc), 8).is_err());
+        assert!(validate_linear_range(paged(u64::MAX - 3), 8).is_err());
+        assert!(validate_linear_range(paged(u64::MAX), 0).is_ok());
+
+        assert!(validate_linear_range(unpaged(0xfffffffc), 4).is_ok());
+        match validate_linear_range(unpaged(0xfffffffc), 8) {
+            Err(Error::GuestFault(fault)) => {
+                assert_eq!(fault, GuestFault::GeneralProtection(0));
+                assert_eq!(fault.vector(), 13);
+            }
+            _ => panic!("Expected a guest fault"),
+        }
+    }
+
+    #[test]
+    fn test_lazy_memory() {
+        let mut lazy = LazyMemory::new(130);
+        assert_eq!(lazy.populated.len(), 3);
+        assert!(!lazy.is_populated(129));
+        assert!(lazy.is_populated(130));
+
+        lazy.mark_populated(0);
+        lazy.mark_populated(0);
+        lazy.mark_populated(2);
+        lazy.mark_populated(200);
+        assert_eq!(lazy.remaining, 128);
+        assert_eq!(lazy.next_unpopulated(), Some(1));
+        lazy.mark_populated(1);
+        assert_eq!(lazy.next_unpopulated(), Some(3));
+        assert_eq!(lazy.cursor, 3);
+    }
+
+    #[test]
+    fn test_reclaim_frame() {
+        let mut space = GuestAddressSpace::new().unwrap();
+        let addr = GuestPhysAddr::new(0x1000);
+        space.map_new_frame(addr, false).unwrap();
+        space
+            .map_new_frame(GuestPhysAddr::new(0x2000), true)
+            .unwrap();
+
+        assert!(space.reclaim_frame(GuestPhysAddr::new(0x1800)).unwrap());
+        assert!(space.find_host_frame(addr).is_err());
+        assert!(!space.reclaim_frame(addr).unwrap());
+        assert!(!space.reclaim_frame(GuestPhysAddr::new(0x2000)).unwrap());
+        assert!(!space.reclaim_frame(GuestPhysAddr::new(0x3000)).unwrap());
+        assert_eq!(space.reclaimed_pages(), 1);
+
+        // Touching the page gives it a new frame
+        assert!(space.populate(addr).unwrap());
+        assert!(space.find_host_frame(addr).is_ok());
+        assert!(!space.populate(addr).unwrap());
+        assert_eq!(space.reclaimed_pages(), 0);
+    }
+
+    #[test]
+    fn test_populate() {
+        let mut space = GuestAddressSpace::new().unwrap();
+        space.set_lazy_memory(4 * 4096);
+        let addr = GuestPhysAddr::new(0x1010);
+        assert!(space.find_host_frame(addr).is_err());
+        assert!(space.populate(addr).unwrap());
+        assert!(space.find_host_frame(addr).is_ok());
+        assert!(!space.populate(addr).unwrap());
+        assert!(!space.populate(GuestPhysAddr::new(0x4000)).unwrap());
+
+        // Writes populate the pages they touch
+        let paging = PagingContext::new(PagingMode::Disabled, addr);
+        space
+            .write_bytes(
+                &paging,
+                GuestVirtAddr::NoPaging(GuestPhysAddr::new(0x2ffe)),
+                &[1, 2, 3, 4],
+                GuestAccess::Write(PrivilegeLevel(0)),
+            )
+            .unwrap();
+        assert_eq!(space.prefault(usize::MAX).unwrap(), 0);
+        assert_eq!(space.mappings().len(), 4);
+    }
+
+    #[test]
+    fn test_scrub_frames() {
+        let page = Box::into_raw(Box::new(Raw4kPage([0xaa; 4096])));
+        let frame =
+            HostPhysFrame::from_start_address(HostPhysAddr::new(page as u64))
+                .unwrap();
+        release_frame(frame);
+        assert_eq!(scrub_frames(usize::MAX), 0);
+        assert!(unsafe { frame.as_array() }.iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn test_validate_la57_range() {
+        let paged = |addr| {
+            GuestVirtAddr::Paging5Level(Guest5LevelPagingAddr::new(addr))
+        };
+
+        // Addresses beyond 48 bits are canonical with 5-level paging
+        assert!(validate_linear_range(paged(0x0000800000000000), 8).is_ok());
+        assert!(validate_linear_range(paged(0xff00000000000000), 8).is_ok());
+        assert!(validate_linear_range(paged(0x0100000000000000), 1).is_err());
+        assert!(validate_linear_range(paged(0x00fffffffffffffc), 8).is_err());
+
+        assert!(paged(0x00ffffffffffffff).is_canonical());
+        assert!(!GuestVirtAddr::Paging4Level(Guest4LevelPagingAddr::new(
+            0x00ffffffffffffff
+        ))
+        .is_canonical());
+    }
+}
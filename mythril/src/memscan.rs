@@ -0,0 +1,263 @@
+//! Guest memory scanning for incident response
+//!
+//! The `memscan` management console command searches the memory of a
+//! paused VM (see `watch`) for byte patterns, without any cooperation from
+//! the guest. Each match is written as soon as it is found, as the guest
+//! physical address of its first byte and the pattern that matched.
+//!
+//! Patterns are written as a single console argument, either as hex digits
+//! (where a `?` matches any nibble, so `4d5a??00` matches 'MZ' followed by
+//! any byte and a zero) or as `s:` followed by literal text. Matches may
+//! span pages, as long as the pages are contiguous in guest physical
+//! memory.
+//!
+//! Running VMs cannot be scanned, as their memory may change during the
+//! scan.
+
+use crate::coredump;
+use crate::error::{Error, Result};
+use crate::vm;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// The longest pattern (in bytes)
+pub const MAX_PATTERN_LEN: usize = 64;
+
+/// The most matches reported by a single scan
+pub const MAX_MATCHES: usize = 64;
+
+/// A byte pattern, where each byte is matched under a mask
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pattern {
+    text: String,
+    bytes: Vec<(u8, u8)>,
+}
+
+impl Pattern {
+    /// Parse a pattern (see the module documentation for the syntax)
+    pub fn parse(text: &str) -> Result<Self> {
+        let invalid =
+            || Error::InvalidValue(format!("Invalid pattern '{}'", text));
+        let bytes = if let Some(literal) = text.strip_prefix("s:") {
+            literal.bytes().map(|byte| (byte, 0xff)).collect::<Vec<_>>()
+        } else {
+            if text.len() % 2 != 0 {
+                return Err(invalid());
+            }
+            let nibble = |digit: u8| match digit {
+                b'?' => Some((0, 0)),
+                digit => (digit as char).to_digit(16).map(|v| (v as u8, 0xf)),
+            };
+            text.as_bytes()
+                .chunks(2)
+                .map(|pair| {
+                    let (high, high_mask) = nibble(pair[0])?;
+                    let (low, low_mask) = nibble(pair[1])?;
+                    Some((high << 4 | low, high_mask << 4 | low_mask))
+                })
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(invalid)?
+        };
+        if bytes.is_empty() || bytes.len() > MAX_PATTERN_LEN {
+            return Err(invalid());
+        }
+        Ok(Self {
+            text: text.into(),
+            bytes,
+        })
+    }
+
+    /// The length of the pattern in bytes
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Returns true if `data` starts with this pattern
+    pub fn matches(&self, data: &[u8]) -> bool {
+        data.len() >= self.bytes.len()
+            && self
+                .bytes
+                .iter()
+                .zip(data)
+                .all(|((value, mask), byte)| byte & mask == *value)
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+/// Searches a sequence of memory chunks for a set of patterns
+///
+/// The end of each chunk is kept, so matches that continue in to the next
+/// chunk are found if the chunks are contiguous.
+pub struct Scanner<'a> {
+    patterns: &'a [Pattern],
+    tail: Vec<u8>,
+    tail_addr: u64,
+}
+
+impl<'a> Scanner<'a> {
+    pub fn new(patterns: &'a [Pattern]) -> Self {
+        Self {
+            patterns,
+            tail: vec![],
+            tail_addr: 0,
+        }
+    }
+
+    /// Scan the chunk `data` at `addr`, calling `on_match` with the address
+    /// and pattern of each match (until it returns false). Returns false if
+    /// the scan was stopped by `on_match`.
+    pub fn scan(
+        &mut self,
+        addr: u64,
+        data: &[u8],
+        mut on_match: impl FnMut(u64, &Pattern) -> bool,
+    ) -> bool {
+        if self.tail_addr + self.tail.len() as u64 != addr {
+            self.tail.clear();
+        }
+        let mut window = core::mem::replace(&mut self.tail, vec![]);
+        let start = addr - window.len() as u64;
+        let carried = window.len();
+        window.extend_from_slice(data);
+
+        for offset in 0..window.len() {
+            for pattern in self.patterns.iter() {
+                // Matches that end in the carried bytes were already found
+                // in the previous chunk
+                if offset + pattern.len() > carried
+                    && pattern.matches(&window[offset..])
+                    && !on_match(start + offset as u64, pattern)
+                {
+                    return false;
+                }
+            }
+        }
+
+        let keep = self
+            .patterns
+            .iter()
+            .map(|pattern| pattern.len() - 1)
+            .max()
+            .unwrap_or(0)
+            .min(window.len());
+        self.tail_addr = start + (window.len() - keep) as u64;
+        self.tail = window.split_off(window.len() - keep);
+        true
+    }
+}
+
+/// The `memscan` management console command
+pub fn memscan_command(out: &mut dyn fmt::Write, args: &[&str]) -> fmt::Result {
+    let vm_id = match args.get(0).and_then(|vm| vm::find_vm(vm)) {
+        Some(vm_id) if args.len() > 1 => vm_id,
+        _ => return writeln!(out, "usage: memscan <vm> <pattern>..."),
+    };
+    let patterns = match args[1..]
+        .iter()
+        .map(|text| Pattern::parse(text))
+        .collect::<Result<Vec<_>>>()
+    {
+        Ok(patterns) => patterns,
+        Err(Error::InvalidValue(msg)) => return writeln!(out, "{}", msg),
+        Err(_) => return writeln!(out, "Invalid pattern"),
+    };
+
+    let core = match coredump::paused_vcpus(vm_id).first() {
+        Some((core, _)) => *core,
+        None => {
+            return writeln!(
+                out,
+                "VM {} is not paused (pause it with a watch first)",
+                vm::vm_name(vm_id).unwrap_or("?")
+            )
+        }
+    };
+
+    // The VM is paused, so its memory is not being modified
+    let vm = match unsafe { vm::get_vm_for_core_id(core) } {
+        Some(vm) => vm,
+        None => return writeln!(out, "No VM {}", vm_id),
+    };
+    let vm = vm.read();
+    let mappings = vm.guest_space.mappings();
+
+    let mut scanner = Scanner::new(&patterns);
+    let mut matches = 0;
+    let mut result = Ok(());
+    for (addr, frame, _) in mappings.iter() {
+        let data = unsafe { frame.as_array() };
+        let complete = scanner.scan(addr.as_u64(), data, |addr, pattern| {
+            matches += 1;
+            result = writeln!(out, "0x{:x}: {}", addr, pattern);
+            result.is_ok() && matches < MAX_MATCHES
+        });
+        result?;
+        if !complete {
+            return writeln!(
+                out,
+                "Stopped after {} matches (the limit for a scan)",
+                MAX_MATCHES
+            );
+        }
+    }
+    writeln!(out, "{} matches in {} pages", matches, mappings.len())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pattern_parse() {
+        let pattern = Pattern::parse("4d5a??0?").unwrap();
+        assert_eq!(pattern.len(), 4);
+        assert!(pattern.matches(&[0x4d, 0x5a, 0x12, 0x05, 0xff]));
+        assert!(!pattern.matches(&[0x4d, 0x5a, 0x12, 0x15]));
+        assert!(!pattern.matches(&[0x4d, 0x5a]));
+
+        assert!(Pattern::parse("s:evil").unwrap().matches(b"evil!"));
+        assert!(Pattern::parse("4d5").is_err());
+        assert!(Pattern::parse("4g").is_err());
+        assert!(Pattern::parse("s:").is_err());
+    }
+
+    #[test]
+    fn test_scan_across_chunks() {
+        let patterns = [
+            Pattern::parse("s:abcd").unwrap(),
+            Pattern::parse("s:b").unwrap(),
+        ];
+        let mut scanner = Scanner::new(&patterns);
+        let mut matches = vec![];
+        let mut record = |addr, pattern: &Pattern| {
+            matches.push((addr, pattern.text.clone()));
+            true
+        };
+        assert!(scanner.scan(0x1000, b"xxab", &mut record));
+        assert!(scanner.scan(0x1004, b"cdab", &mut record));
+        // The next chunk is not contiguous, so nothing is carried over
+        assert!(scanner.scan(0x3000, b"cd", &mut record));
+        assert_eq!(
+            matches,
+            vec![
+                (0x1003, "s:b".into()),
+                (0x1002, "s:abcd".into()),
+                (0x1007, "s:b".into()),
+            ]
+        );
+
+        let mut count = 0;
+        let mut scanner = Scanner::new(&patterns);
+        assert!(!scanner.scan(0, b"bbb", |_, _| {
+            count += 1;
+            count < 2
+        }));
+        assert_eq!(count, 2);
+    }
+}
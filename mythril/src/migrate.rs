@@ -0,0 +1,458 @@
+//! Migration of vCPUs between cores
+//!
+//! Every core runs at most one vCPU (see `sched`), so a vCPU can only move
+//! to an idle core: one whose own vCPU has moved away, or whose VM has
+//! stopped and will not be restarted (see `release`). A move is requested
+//! with `VirtualMachineMsg::Migrate` (e.g., by the `migrate` console
+//! command), and takes place at the end of the vCPU's next exit:
+//!
+//! - `depart` clears the VMCS with `ActiveVmcs::deactivate`, so the
+//!   processor writes back any VMCS state it cached and the VMCS can be
+//!   loaded by another core. The core keeps its VMX region for the next
+//!   vCPU that moves to it.
+//! - The timer wheel of the core (which only holds the timers of its vCPU)
+//!   and the vCPU's scheduler entry go with the vCPU.
+//! - The core leaves the vCPU's host stack for its own idle stack, and only
+//!   then hands the vCPU over, so the two cores never share a stack.
+//! - On the target, `arrive` loads the VMCS, rewrites the host state, VPID
+//!   and posted-interrupt destination for the new core (see
+//!   `VCpu::rehome`), installs the timer wheel and launches the guest with
+//!   the registers saved at its last exit.
+//!
+//! A vCPU keeps its identity (the core it was created for, which is also
+//! its APIC id) wherever it runs. Messages and timers name the vCPU, and
+//! are delivered to the core it currently runs on (see `host_core`).
+
+use crate::apic;
+use crate::console;
+use crate::crash;
+use crate::health;
+use crate::offline;
+use crate::percore::{self, CoreId};
+use crate::sched::{self, SchedEntity, SchedParams};
+use crate::time::{self, TimerWheel};
+use crate::vcpu::VCpu;
+use crate::vm::{self, VirtualMachine};
+use crate::vmcs::Vmcs;
+use crate::vmexit::GuestCpuState;
+use crate::{error, vmx};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::{Mutex, RwLock};
+
+/// The size of the stack each core runs on while it is idle
+const IDLE_STACK_SIZE: usize = 64 * 1024;
+
+extern "C" {
+    fn vmlaunch_state(guest_cpu: *mut GuestCpuState) -> !;
+    fn switch_stack(
+        arg: *mut Departure,
+        stack: u64,
+        entry: extern "C" fn(*mut Departure) -> !,
+    ) -> !;
+}
+
+// The cores each vCPU runs on
+#[derive(Debug, Default)]
+struct Placement {
+    // (vCPU, core) for every vCPU that has moved away from its own core
+    moved: Vec<(CoreId, CoreId)>,
+}
+
+impl Placement {
+    const fn new() -> Self {
+        Placement { moved: Vec::new() }
+    }
+
+    // The core running `vcpu`, or None if its own core runs another vCPU
+    // (so it has stopped for good)
+    fn host(&self, vcpu: CoreId) -> Option<CoreId> {
+        match self.moved.iter().find(|(moved, _)| *moved == vcpu) {
+            Some((_, core)) => Some(*core),
+            None if self.moved.iter().any(|(_, core)| *core == vcpu) => None,
+            None => Some(vcpu),
+        }
+    }
+
+    // The vCPU running on `core` (or None if its vCPU has moved away)
+    fn guest(&self, core: CoreId) -> Option<CoreId> {
+        match self.moved.iter().find(|(_, host)| *host == core) {
+            Some((vcpu, _)) => Some(*vcpu),
+            None if self.moved.iter().any(|(vcpu, _)| *vcpu == core) => None,
+            None => Some(core),
+        }
+    }
+
+    fn record(&mut self, vcpu: CoreId, core: CoreId) {
+        self.moved.retain(|(moved, _)| *moved != vcpu);
+        if vcpu != core {
+            self.moved.push((vcpu, core));
+        }
+    }
+}
+
+static PLACEMENT: Mutex<Placement> = Mutex::new(Placement::new());
+
+// The idle cores, each with the vCPU handed to it (if any)
+static IDLE: Mutex<Vec<(CoreId, Option<Handoff>)>> = Mutex::new(Vec::new());
+
+// The top of the idle stack of each core that has been idle
+static IDLE_STACKS: Mutex<Vec<(CoreId, u64)>> = Mutex::new(Vec::new());
+
+/// The core currently running `vcpu` (the core it was created for, unless
+/// it has moved), or None if it will not run again
+pub fn host_core(vcpu: CoreId) -> Option<CoreId> {
+    PLACEMENT.lock().host(vcpu)
+}
+
+/// The vCPU running on the current core
+///
+/// An idle core is taken to run its own vCPU.
+pub fn current_vcpu() -> CoreId {
+    let core = percore::read_core_id();
+    PLACEMENT.lock().guest(core).unwrap_or(core)
+}
+
+/// The vCPU running on `core`, or None if the core is idle
+pub fn vcpu_on(core: CoreId) -> Option<CoreId> {
+    if is_idle(core) {
+        return None;
+    }
+    PLACEMENT.lock().guest(core)
+}
+
+/// Returns true if `core` is idle and no vCPU is moving to it
+pub fn is_idle(core: CoreId) -> bool {
+    IDLE.lock()
+        .iter()
+        .any(|(idle, handoff)| *idle == core && handoff.is_none())
+}
+
+/// The idle cores that no vCPU is moving to
+pub fn idle_cores() -> Vec<CoreId> {
+    IDLE.lock()
+        .iter()
+        .filter(|(_, handoff)| handoff.is_none())
+        .map(|(core, _)| *core)
+        .collect()
+}
+
+/// Stop offering the idle core `core` to moving vCPUs (e.g., because it is
+/// going offline)
+///
+/// Returns false if the core is not idle, or a vCPU is already moving to
+/// it.
+pub fn withdraw(core: CoreId) -> bool {
+    let mut idle = IDLE.lock();
+    match idle
+        .iter()
+        .position(|(idle, handoff)| *idle == core && handoff.is_none())
+    {
+        Some(index) => {
+            idle.remove(index);
+            true
+        }
+        None => false,
+    }
+}
+
+// A vCPU in transit between cores. The VMCS is moved out of the VCpu
+// (which holds a stale copy) while it is not active on any core.
+struct Handoff {
+    vcpu: *mut VCpu,
+    guest_cpu: *mut GuestCpuState,
+    vmcs: Vmcs,
+    timers: Option<TimerWheel>,
+    entity: SchedEntity,
+    params: SchedParams,
+}
+
+// The VCpu and its host stack are only accessed by one core at a time
+unsafe impl Send for Handoff {}
+
+// What a core takes to its idle stack
+struct Departure {
+    vmx: vmx::Vmx,
+    handoff: Option<(CoreId, Handoff)>,
+    vm: Option<Arc<RwLock<VirtualMachine>>>,
+}
+
+/// Move the vCPU of the current core to the idle core `target`, with the
+/// guest registers saved at the end of its exit in `guest_cpu`
+///
+/// This is only called at the end of the exit handler, as the core leaves
+/// the vCPU's host stack for good. If `target` is no longer idle, the vCPU
+/// resumes on the current core.
+pub fn depart(
+    vcpu: &mut VCpu,
+    guest_cpu: &mut GuestCpuState,
+    target: CoreId,
+) -> ! {
+    let vcpu_id = current_vcpu();
+    let (vm_id, params, name) = {
+        let vm = vcpu.vm.read();
+        (vm.id, vm.config.sched_params(), vm.name.clone())
+    };
+    let entity = SchedEntity {
+        vm_id,
+        vcpu: vcpu_id,
+    };
+    info!(
+        "Moving vCPU {} of VM {} from core {} to core {}",
+        vcpu_id,
+        name,
+        percore::read_core_id(),
+        target
+    );
+
+    sched::remove(entity);
+    let timers = unsafe { time::take_timer_wheel() };
+    let (vmcs, vmx) = unsafe { core::ptr::read(&vcpu.vmcs) }
+        .deactivate()
+        .expect("Failed to clear the VMCS of a moving vcpu");
+
+    let handoff = Handoff {
+        vcpu: vcpu as *mut VCpu,
+        guest_cpu: guest_cpu as *mut GuestCpuState,
+        vmcs,
+        timers,
+        entity,
+        params,
+    };
+    unsafe {
+        enter_idle(Departure {
+            vmx,
+            handoff: Some((target, handoff)),
+            vm: None,
+        })
+    }
+}
+
+/// Give up the current core, whose vCPU has stopped for good, so other
+/// vCPUs can move to it
+pub fn release(vcpu: &mut VCpu) -> ! {
+    let entity = {
+        let vm = vcpu.vm.read();
+        SchedEntity {
+            vm_id: vm.id,
+            vcpu: current_vcpu(),
+        }
+    };
+    sched::remove(entity);
+    let (vmcs, vmx) = unsafe { core::ptr::read(&vcpu.vmcs) }
+        .deactivate()
+        .expect("Failed to clear the VMCS of a stopped vcpu");
+
+    // The stopped VCpu still refers to its VMCS, so it is not freed
+    core::mem::forget(vmcs);
+    unsafe {
+        enter_idle(Departure {
+            vmx,
+            handoff: None,
+            vm: Some(vcpu.vm.clone()),
+        })
+    }
+}
+
+fn idle_stack(core: CoreId) -> u64 {
+    let mut stacks = IDLE_STACKS.lock();
+    if let Some((_, top)) = stacks.iter().find(|(idle, _)| *idle == core) {
+        return *top;
+    }
+    let stack = vec![0u8; IDLE_STACK_SIZE];
+    let top = (stack.as_ptr() as u64 + stack.len() as u64) & !0xf;
+    core::mem::forget(stack);
+    stacks.push((core, top));
+    top
+}
+
+unsafe fn enter_idle(departure: Departure) -> ! {
+    let stack = idle_stack(percore::read_core_id());
+    switch_stack(Box::into_raw(Box::new(departure)), stack, idle_entry)
+}
+
+// Hand `handoff` to the idle core `target`. The vCPU is only placed on the
+// target once it is handed over (and before the target can take it), so
+// messages for the vCPU only go to a core that is about to run it.
+fn deliver(
+    target: CoreId,
+    handoff: Handoff,
+) -> core::result::Result<(), Handoff> {
+    let mut idle = IDLE.lock();
+    match idle
+        .iter_mut()
+        .find(|(core, pending)| *core == target && pending.is_none())
+    {
+        Some((_, pending)) => {
+            PLACEMENT.lock().record(handoff.entity.vcpu, target);
+            *pending = Some(handoff);
+            Ok(())
+        }
+        None => Err(handoff),
+    }
+}
+
+// Take the vCPU handed to the idle core `core`, which is then no longer
+// idle
+fn take_incoming(core: CoreId) -> Option<Handoff> {
+    let mut idle = IDLE.lock();
+    let index = idle
+        .iter()
+        .position(|(idle, pending)| *idle == core && pending.is_some())?;
+    idle.remove(index).1
+}
+
+// The first function run on the idle stack of a core
+extern "C" fn idle_entry(departure: *mut Departure) -> ! {
+    let Departure { vmx, handoff, vm } = *unsafe { Box::from_raw(departure) };
+    let core = percore::read_core_id();
+    health::idle();
+
+    if let Some((target, handoff)) = handoff {
+        if let Err(handoff) = deliver(target, handoff) {
+            warn!(
+                "Core {} is not idle, the vCPU stays on core {}",
+                target, core
+            );
+            // The core keeps its vCPU, so it cannot go offline
+            if offline::cancel(core) {
+                warn!("Core {} stays online", core);
+            }
+            unsafe { arrive(handoff, vmx) }
+        }
+    }
+
+    if offline::is_requested(core) {
+        offline::park(vmx);
+    }
+    IDLE.lock().push((core, None));
+    info!("Core {} is idle", core);
+
+    // Interrupts are not delivered on an idle core, so console input is
+    // polled (as it is while a vCPU is paused)
+    loop {
+        // A panic on another core parks every core, idle or not
+        if crash::is_crashing() {
+            crash::park(None);
+        }
+        if let Some(handoff) = take_incoming(core) {
+            unsafe { arrive(handoff, vmx) }
+        }
+        if offline::is_requested(core) {
+            offline::park(vmx);
+        }
+        if let Some(vm) = vm.as_ref() {
+            let vm = vm.read();
+            let key = match vm.config.physical_devices().serial.as_ref() {
+                Some(serial) if serial.data_ready() => Some(serial.read()),
+                _ => None,
+            };
+            drop(vm);
+            if let Some(key) = key {
+                console::handle_key(key);
+            }
+        }
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+// Run the vCPU of `handoff` on the current core, whose VMX region is `vmx`
+unsafe fn arrive(handoff: Handoff, vmx: vmx::Vmx) -> ! {
+    let core = percore::read_core_id();
+    let vcpu = &mut *handoff.vcpu;
+    let active = handoff
+        .vmcs
+        .activate(vmx)
+        .expect("Failed to load the VMCS of a moved vcpu");
+    core::ptr::write(&mut vcpu.vmcs, active);
+    vcpu.rehome()
+        .expect("Failed to update the VMCS of a moved vcpu");
+
+    match handoff.timers {
+        Some(wheel) => time::install_timer_wheel(wheel),
+        None => time::init_timer_wheel()
+            .expect("Failed to initialize per-core timer wheel"),
+    }
+    if let Err(e) = sched::admit(core, handoff.entity, handoff.params) {
+        warn!("Failed to admit a moved vcpu to core {}: {:?}", core, e);
+    }
+    PLACEMENT.lock().record(handoff.entity.vcpu, core);
+    info!(
+        "vCPU {} now runs on core {} (APIC id {})",
+        handoff.entity.vcpu,
+        core,
+        apic::get_local_apic().id()
+    );
+
+    // The VMCS was cleared, so the guest is launched rather than resumed
+    vmlaunch_state(handoff.guest_cpu)
+}
+
+#[no_mangle]
+pub extern "C" fn vmlaunch_failure_handler(rflags: u64) {
+    error::check_vm_insruction(rflags, "Failed to launch moved vcpu".into())
+        .expect("vmlaunch failed");
+}
+
+/// The `migrate` management console command
+pub fn migrate_command(out: &mut dyn fmt::Write, args: &[&str]) -> fmt::Result {
+    let cores = args
+        .iter()
+        .map(|arg| arg.parse::<u32>().map(CoreId::from))
+        .collect::<core::result::Result<Vec<_>, _>>();
+    match cores.as_ref().map(|cores| cores.as_slice()) {
+        Ok([]) => {
+            for (vcpu, core) in PLACEMENT.lock().moved.iter() {
+                writeln!(out, "vCPU {} runs on core {}", vcpu, core)?;
+            }
+            let idle = IDLE.lock();
+            let idle =
+                idle.iter().map(|(core, _)| core.raw).collect::<Vec<_>>();
+            writeln!(out, "Idle cores: {:?}", idle)
+        }
+        Ok([vcpu, target]) => {
+            if !is_idle(*target) {
+                return writeln!(out, "Core {} is not idle", target);
+            }
+            match vm::send_vm_msg_core(
+                vm::VirtualMachineMsg::Migrate(*target),
+                *vcpu,
+            ) {
+                Ok(()) => {
+                    writeln!(out, "Moving vCPU {} to core {}", vcpu, target)
+                }
+                Err(e) => {
+                    writeln!(out, "Failed to move vCPU {}: {:?}", vcpu, e)
+                }
+            }
+        }
+        _ => writeln!(out, "usage: migrate [<vcpu> <idle core>]"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_placement() {
+        let core = CoreId::from;
+        let mut placement = Placement::new();
+        assert_eq!(placement.host(core(1)), Some(core(1)));
+        assert_eq!(placement.guest(core(2)), Some(core(2)));
+
+        // vCPU 1 moves to core 2 (whose VM stopped), leaving core 1 idle
+        placement.record(core(1), core(2));
+        assert_eq!(placement.host(core(1)), Some(core(2)));
+        assert_eq!(placement.guest(core(2)), Some(core(1)));
+        assert_eq!(placement.host(core(2)), None);
+        assert_eq!(placement.guest(core(1)), None);
+
+        // Moving back home forgets the move
+        placement.record(core(1), core(1));
+        assert!(placement.moved.is_empty());
+        assert_eq!(placement.guest(core(1)), Some(core(1)));
+    }
+}
@@ -1,5 +1,6 @@
 use crate::boot_info::{self, BootInfo};
 use crate::global_alloc;
+use crate::hostmem::{self, HostMemoryMap, HostRegionKind};
 use crate::memory::HostPhysAddr;
 use alloc::vec::Vec;
 
@@ -53,27 +54,26 @@ where
         mythril_bounds[0].0, mythril_bounds[0].1
     );
 
-    let excluded = modules.chain(mythril_bounds.iter().copied());
-
-    // TODO(alschwalm): For now, we just use the portion of the largest available
-    // region that is above the highest excluded region.
-    let max_excluded = excluded
-        .max_by(|left, right| left.1.cmp(&right.1))
-        .expect("No max excluded region");
-
-    let largest_region = available
-        .max_by(|left, right| (left.1 - left.0).cmp(&(right.1 - right.0)))
-        .expect("No largest region");
-
-    if largest_region.0 > max_excluded.1 {
-        largest_region
-    } else if max_excluded.1 > largest_region.0
-        && max_excluded.1 < largest_region.1
-    {
-        (max_excluded.1, largest_region.1)
-    } else {
-        panic!("Unable to find suitable global alloc region")
+    let mut map =
+        HostMemoryMap::new(available).expect("Invalid multiboot memory map");
+    for (start, end) in modules {
+        map.reserve(start, end, HostRegionKind::BootModule)
+            .expect("Failed to reserve boot module");
     }
+    map.reserve(
+        mythril_bounds[0].0,
+        mythril_bounds[0].1,
+        HostRegionKind::HypervisorImage,
+    )
+    .expect("Failed to reserve the mythril binary");
+
+    let heap = map
+        .largest_free()
+        .expect("Unable to find suitable global alloc region");
+    map.reserve(heap.0, heap.1, HostRegionKind::Heap)
+        .expect("Failed to reserve the global alloc region");
+    hostmem::init(map);
+    heap
 }
 
 pub fn early_init_multiboot(addr: HostPhysAddr) -> BootInfo {
@@ -1,6 +1,7 @@
 use crate::acpi;
 use crate::boot_info::{self, BootInfo};
 use crate::global_alloc;
+use crate::hostmem::{self, HostMemoryMap, HostRegionKind};
 use crate::memory::HostPhysAddr;
 use alloc::vec::Vec;
 
@@ -39,15 +40,23 @@ fn setup_global_alloc_region(info: &multiboot2::BootInformation) -> (u64, u64) {
         .elf_sections_tag()
         .expect("Missing multiboot elf sections tag");
 
+    // The sections that are not loaded have an address of zero, and the
+    // image is the span of the others
     debug!("Elf sections:");
-    let sections = sections_tag.sections().map(|section| {
-        debug!(
-            "  0x{:x}-0x{:x}",
-            section.start_address(),
-            section.end_address()
-        );
-        (section.start_address(), section.end_address())
-    });
+    let image = sections_tag
+        .sections()
+        .filter(|section| section.start_address() != 0)
+        .map(|section| {
+            debug!(
+                "  0x{:x}-0x{:x}",
+                section.start_address(),
+                section.end_address()
+            );
+            (section.start_address(), section.end_address())
+        })
+        .fold((u64::MAX, 0), |image, section| {
+            (image.0.min(section.0), image.1.max(section.1))
+        });
 
     // Avoid allocating over the BootInformation structure itself
     let multiboot_info =
@@ -58,29 +67,28 @@ fn setup_global_alloc_region(info: &multiboot2::BootInformation) -> (u64, u64) {
         info.end_address()
     );
 
-    let excluded = modules
-        .chain(sections)
-        .chain(multiboot_info.iter().copied());
-
-    // TODO: For now, we just use the portion of the largest available
-    // region that is above the highest excluded region.
-    let max_excluded = excluded
-        .max_by(|left, right| left.1.cmp(&right.1))
-        .expect("No max excluded region");
-
-    let largest_region = available
-        .max_by(|left, right| (left.1 - left.0).cmp(&(right.1 - right.0)))
-        .expect("No largest region");
-
-    if largest_region.0 > max_excluded.1 {
-        largest_region
-    } else if max_excluded.1 > largest_region.0
-        && max_excluded.1 < largest_region.1
-    {
-        (max_excluded.1, largest_region.1)
-    } else {
-        panic!("Unable to find suitable global alloc region")
+    let mut map =
+        HostMemoryMap::new(available).expect("Invalid multiboot memory map");
+    for (start, end) in modules {
+        map.reserve(start, end, HostRegionKind::BootModule)
+            .expect("Failed to reserve boot module");
     }
+    map.reserve(image.0, image.1, HostRegionKind::HypervisorImage)
+        .expect("Failed to reserve the mythril binary");
+    map.reserve(
+        multiboot_info[0].0,
+        multiboot_info[0].1,
+        HostRegionKind::BootInfo,
+    )
+    .expect("Failed to reserve the multiboot info");
+
+    let heap = map
+        .largest_free()
+        .expect("Unable to find suitable global alloc region");
+    map.reserve(heap.0, heap.1, HostRegionKind::Heap)
+        .expect("Failed to reserve the global alloc region");
+    hostmem::init(map);
+    heap
 }
 
 pub fn early_init_multiboot2(addr: HostPhysAddr) -> BootInfo {
@@ -0,0 +1,291 @@
+//! # Persistent per-VM non-volatile storage
+//!
+//! Each virtual machine may be given an `Nvram` store that is owned by the
+//! hypervisor rather than by any particular device instance. The store is
+//! split into fixed areas for the RTC CMOS bytes (and the time set on the
+//! RTC), the TPM state and the UEFI variable store, and all writes are
+//! passed through to an `NvramBackend`. The backend decides how long the
+//! contents actually live: the `MemoryBackend` keeps them for the lifetime
+//! of the hypervisor (so they survive guest reboots), and may be seeded
+//! with an image saved by a previous run.
+
+use crate::error::{Error, Result};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+const NVRAM_MAGIC: &[u8; 8] = b"MYTHNVRM";
+const NVRAM_VERSION: u32 = 2;
+
+const NVRAM_HEADER_SIZE: usize = 0x100;
+
+const CMOS_OFFSET: usize = NVRAM_HEADER_SIZE;
+const CMOS_SIZE: usize = 0x100;
+
+const RTC_OFFSET_OFFSET: usize = CMOS_OFFSET + CMOS_SIZE;
+const RTC_OFFSET_SIZE: usize = 8;
+
+const TPM_STATE_OFFSET: usize = 0x1000;
+const TPM_STATE_SIZE: usize = 0x4000;
+
+const UEFI_VARIABLES_OFFSET: usize = TPM_STATE_OFFSET + TPM_STATE_SIZE;
+// The size of the variable store of (4MB) OVMF builds
+const UEFI_VARIABLES_SIZE: usize = 0x84000;
+
+/// The total size of an nvram image (including the header)
+pub const NVRAM_SIZE: usize = UEFI_VARIABLES_OFFSET + UEFI_VARIABLES_SIZE;
+
+/// The regions of an `Nvram` store
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum NvramArea {
+    /// The RTC CMOS bytes
+    Cmos,
+
+    /// The offset (in seconds) of the time the guest set on its RTC from
+    /// the RTC's time base
+    RtcOffset,
+
+    /// The persistent state of a virtual TPM
+    TpmState,
+
+    /// The UEFI variable store (used when the guest runs UEFI firmware)
+    UefiVariables,
+}
+
+impl NvramArea {
+    fn range(&self) -> Range<usize> {
+        match self {
+            NvramArea::Cmos => CMOS_OFFSET..CMOS_OFFSET + CMOS_SIZE,
+            NvramArea::RtcOffset => {
+                RTC_OFFSET_OFFSET..RTC_OFFSET_OFFSET + RTC_OFFSET_SIZE
+            }
+            NvramArea::TpmState => {
+                TPM_STATE_OFFSET..TPM_STATE_OFFSET + TPM_STATE_SIZE
+            }
+            NvramArea::UefiVariables => {
+                UEFI_VARIABLES_OFFSET
+                    ..UEFI_VARIABLES_OFFSET + UEFI_VARIABLES_SIZE
+            }
+        }
+    }
+
+    /// The size of this area in bytes
+    pub fn size(&self) -> usize {
+        self.range().len()
+    }
+}
+
+/// The storage behind an `Nvram` store
+pub trait NvramBackend: Send + Sync {
+    /// Read the persisted nvram image (if anything has been persisted)
+    fn load(&mut self) -> Result<Option<Vec<u8>>>;
+
+    /// Persist `data` at `offset` in the nvram image
+    fn store(&mut self, offset: usize, data: &[u8]) -> Result<()>;
+}
+
+/// An `NvramBackend` that keeps the image in hypervisor memory
+///
+/// The contents survive for as long as the backend does, but are lost when
+/// the host is reset. An initial image may be supplied (e.g., from a boot
+/// module) to restore a state saved by a previous run.
+#[derive(Default)]
+pub struct MemoryBackend {
+    image: Vec<u8>,
+}
+
+impl MemoryBackend {
+    /// Create a new `MemoryBackend`, optionally seeded with an existing image
+    pub fn new(initial: Option<&[u8]>) -> Self {
+        Self {
+            image: initial.map(|data| data.to_vec()).unwrap_or_default(),
+        }
+    }
+
+    /// The current image held by this backend
+    pub fn image(&self) -> &[u8] {
+        &self.image
+    }
+}
+
+impl NvramBackend for MemoryBackend {
+    fn load(&mut self) -> Result<Option<Vec<u8>>> {
+        if self.image.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(self.image.clone()))
+        }
+    }
+
+    fn store(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+        if self.image.len() < offset + data.len() {
+            self.image.resize(offset + data.len(), 0);
+        }
+        self.image[offset..offset + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+/// A persistent non-volatile store for a single virtual machine
+pub struct Nvram {
+    image: Vec<u8>,
+    fresh: bool,
+    backend: Box<dyn NvramBackend>,
+}
+
+impl Nvram {
+    /// Open the nvram image held by `backend`
+    ///
+    /// If the backend does not contain a valid image, a new (zeroed) image
+    /// is created and persisted. In that case `is_fresh` will return true.
+    pub fn new(mut backend: Box<dyn NvramBackend>) -> Result<Self> {
+        match backend.load()? {
+            Some(image) if Self::is_valid_image(&image) => Ok(Self {
+                image: image,
+                fresh: false,
+                backend: backend,
+            }),
+            Some(_) => {
+                warn!("Discarding invalid nvram image");
+                Self::format(backend)
+            }
+            None => Self::format(backend),
+        }
+    }
+
+    fn format(mut backend: Box<dyn NvramBackend>) -> Result<Self> {
+        let mut image = vec![0u8; NVRAM_SIZE];
+        image[..NVRAM_MAGIC.len()].copy_from_slice(NVRAM_MAGIC);
+        image[NVRAM_MAGIC.len()..NVRAM_MAGIC.len() + 4]
+            .copy_from_slice(&NVRAM_VERSION.to_le_bytes());
+
+        // The UEFI variable store starts out as erased flash
+        for byte in image[NvramArea::UefiVariables.range()].iter_mut() {
+            *byte = 0xff;
+        }
+        backend.store(0, &image)?;
+        Ok(Self {
+            image: image,
+            fresh: true,
+            backend: backend,
+        })
+    }
+
+    fn is_valid_image(image: &[u8]) -> bool {
+        if image.len() != NVRAM_SIZE || !image.starts_with(NVRAM_MAGIC) {
+            return false;
+        }
+        let mut version = [0u8; 4];
+        version
+            .copy_from_slice(&image[NVRAM_MAGIC.len()..NVRAM_MAGIC.len() + 4]);
+        u32::from_le_bytes(version) == NVRAM_VERSION
+    }
+
+    fn area_range(
+        area: NvramArea,
+        offset: usize,
+        len: usize,
+    ) -> Result<Range<usize>> {
+        let range = area.range();
+        if offset + len > range.len() {
+            return Err(Error::InvalidValue(format!(
+                "Nvram access out of bounds for {:?}: offset=0x{:x} len=0x{:x}",
+                area, offset, len
+            )));
+        }
+        Ok(range.start + offset..range.start + offset + len)
+    }
+
+    /// Returns true if this image was created (rather than restored) when
+    /// the store was opened
+    pub fn is_fresh(&self) -> bool {
+        self.fresh
+    }
+
+    /// The current contents of the given area
+    pub fn area(&self, area: NvramArea) -> &[u8] {
+        &self.image[area.range()]
+    }
+
+    /// Read from `area` at `offset` into `buf`
+    pub fn read(
+        &self,
+        area: NvramArea,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<()> {
+        let range = Self::area_range(area, offset, buf.len())?;
+        buf.copy_from_slice(&self.image[range]);
+        Ok(())
+    }
+
+    /// Write `data` to `area` at `offset`, persisting it to the backend
+    pub fn write(
+        &mut self,
+        area: NvramArea,
+        offset: usize,
+        data: &[u8],
+    ) -> Result<()> {
+        let range = Self::area_range(area, offset, data.len())?;
+        if &self.image[range.clone()] == data {
+            return Ok(());
+        }
+        self.image[range.clone()].copy_from_slice(data);
+        self.backend.store(range.start, data)
+    }
+
+    /// Close this store, returning the backend that holds the image
+    pub fn into_backend(self) -> Box<dyn NvramBackend> {
+        self.backend
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fresh_nvram() {
+        let nvram = Nvram::new(Box::new(MemoryBackend::new(None))).unwrap();
+        assert!(nvram.is_fresh());
+        assert_eq!(nvram.area(NvramArea::Cmos).len(), CMOS_SIZE);
+        assert!(nvram.area(NvramArea::Cmos).iter().all(|b| *b == 0));
+        assert!(nvram
+            .area(NvramArea::UefiVariables)
+            .iter()
+            .all(|b| *b == 0xff));
+    }
+
+    #[test]
+    fn test_nvram_persists() {
+        let mut nvram = Nvram::new(Box::new(MemoryBackend::new(None))).unwrap();
+        nvram.write(NvramArea::Cmos, 0x10, &[0xaa]).unwrap();
+        nvram
+            .write(NvramArea::UefiVariables, 0, &[1, 2, 3, 4])
+            .unwrap();
+
+        let nvram = Nvram::new(nvram.into_backend()).unwrap();
+        assert!(!nvram.is_fresh());
+        assert_eq!(nvram.area(NvramArea::Cmos)[0x10], 0xaa);
+
+        let mut buf = [0u8; 4];
+        nvram.read(NvramArea::UefiVariables, 0, &mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_nvram_out_of_bounds() {
+        let mut nvram = Nvram::new(Box::new(MemoryBackend::new(None))).unwrap();
+        assert!(nvram.write(NvramArea::Cmos, CMOS_SIZE, &[0]).is_err());
+        assert!(nvram
+            .write(NvramArea::TpmState, TPM_STATE_SIZE - 1, &[0, 0])
+            .is_err());
+    }
+
+    #[test]
+    fn test_nvram_invalid_image() {
+        let backend = MemoryBackend::new(Some(&[0u8; 16]));
+        let nvram = Nvram::new(Box::new(backend)).unwrap();
+        assert!(nvram.is_fresh());
+    }
+}
@@ -0,0 +1,260 @@
+//! Taking host cores offline
+//!
+//! A core that reports hardware errors can be taken out of service without
+//! rebooting the hypervisor (see `offline_command`). Its vCPU is first moved
+//! to an idle core (see `migrate`), so there must be one. Once the core is
+//! idle it parks:
+//!
+//! - its timer wheel is dropped and its local APIC timer is stopped, so it
+//!   generates no more host interrupts
+//! - it leaves VMX operation (in which INIT is blocked)
+//! - it halts with interrupts disabled
+//!
+//! A parked core can then be handed back to the firmware with an INIT IPI,
+//! after which it waits for a SIPI, as it did before the hypervisor started
+//! it. Devices interrupt the bootstrap processor, so it is never taken
+//! offline.
+
+use crate::apic;
+use crate::crash;
+use crate::error::{Error, Result};
+use crate::health;
+use crate::migrate;
+use crate::percore::{self, CoreId};
+use crate::time;
+use crate::vm;
+use crate::vmx;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+
+/// How far a core has gone offline
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OfflineState {
+    /// The core will park once its vCPU has moved away
+    Requested,
+    /// The core is halted outside of VMX operation
+    Parked,
+    /// The core was sent INIT, and is waiting for a SIPI
+    HandedBack,
+}
+
+impl fmt::Display for OfflineState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OfflineState::Requested => write!(f, "going offline"),
+            OfflineState::Parked => write!(f, "parked"),
+            OfflineState::HandedBack => write!(f, "handed back to firmware"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct OfflineCores(Vec<(CoreId, OfflineState)>);
+
+impl OfflineCores {
+    const fn new() -> Self {
+        OfflineCores(Vec::new())
+    }
+
+    fn state(&self, core: CoreId) -> Option<OfflineState> {
+        self.0
+            .iter()
+            .find(|(offline, _)| *offline == core)
+            .map(|(_, state)| *state)
+    }
+
+    fn request(&mut self, core: CoreId) -> Result<()> {
+        match self.state(core) {
+            Some(state) => Err(Error::InvalidValue(format!(
+                "Core {} is already {}",
+                core, state
+            ))),
+            None => {
+                self.0.push((core, OfflineState::Requested));
+                Ok(())
+            }
+        }
+    }
+
+    fn set(&mut self, core: CoreId, state: OfflineState) {
+        self.0.retain(|(offline, _)| *offline != core);
+        self.0.push((core, state));
+    }
+
+    fn cancel(&mut self, core: CoreId) -> bool {
+        if self.state(core) != Some(OfflineState::Requested) {
+            return false;
+        }
+        self.0.retain(|(offline, _)| *offline != core);
+        true
+    }
+
+    fn hand_back(&mut self, core: CoreId) -> Result<()> {
+        match self.state(core) {
+            Some(OfflineState::Parked) => {
+                self.set(core, OfflineState::HandedBack);
+                Ok(())
+            }
+            Some(state) => {
+                Err(Error::InvalidValue(format!("Core {} is {}", core, state)))
+            }
+            None => Err(Error::InvalidValue(format!(
+                "Core {} is not offline",
+                core
+            ))),
+        }
+    }
+}
+
+static OFFLINE: Mutex<OfflineCores> = Mutex::new(OfflineCores::new());
+
+/// Returns true if `core` has been asked to go offline (and has not yet
+/// parked)
+pub fn is_requested(core: CoreId) -> bool {
+    OFFLINE.lock().state(core) == Some(OfflineState::Requested)
+}
+
+/// Withdraw a request for `core` to go offline (e.g., because its vCPU
+/// could not move away). Returns false if the core was not going offline.
+pub fn cancel(core: CoreId) -> bool {
+    OFFLINE.lock().cancel(core)
+}
+
+/// Take `core` offline, moving its vCPU to an idle core first
+///
+/// Returns the vCPU and the core it is moving to, if the core was not
+/// already idle. The core parks asynchronously.
+pub fn take_offline(core: CoreId) -> Result<Option<(CoreId, CoreId)>> {
+    if apic::ApicId::from(core.raw).is_bsp() {
+        return Err(Error::InvalidValue(
+            "The BSP receives device interrupts and cannot go offline".into(),
+        ));
+    }
+
+    let mut offline = OFFLINE.lock();
+    if let Some(state) = offline.state(core) {
+        return Err(Error::InvalidValue(format!(
+            "Core {} is already {}",
+            core, state
+        )));
+    }
+    if migrate::withdraw(core) {
+        return offline.request(core).map(|_| None);
+    }
+
+    let vcpu = migrate::vcpu_on(core).ok_or_else(|| {
+        Error::InvalidValue(format!("Core {} has no vCPU to move", core))
+    })?;
+    let target = migrate::idle_cores()
+        .into_iter()
+        .find(|idle| *idle != core && offline.state(*idle).is_none())
+        .ok_or_else(|| {
+            Error::InvalidValue(format!("No idle core can take vCPU {}", vcpu))
+        })?;
+    offline.request(core)?;
+    drop(offline);
+
+    let msg = vm::VirtualMachineMsg::Migrate(target);
+    if let Err(e) = vm::send_vm_msg_core(msg, vcpu) {
+        cancel(core);
+        return Err(e);
+    }
+    Ok(Some((vcpu, target)))
+}
+
+/// Park the current core, which is idle and has been asked to go offline,
+/// leaving VMX operation with `vmx`
+pub fn park(vmx: vmx::Vmx) -> ! {
+    let core = percore::read_core_id();
+    unsafe {
+        drop(time::take_timer_wheel());
+        apic::get_local_apic_mut().stop_timer();
+    }
+    if let Err(e) = vmx.disable() {
+        warn!("Failed to leave VMX operation on core {}: {:?}", core, e);
+    }
+    OFFLINE.lock().set(core, OfflineState::Parked);
+    health::idle();
+    info!("Core {} is offline", core);
+    log::logger().flush();
+    crash::halt()
+}
+
+/// Send INIT to the parked `core`, handing it back to the firmware
+pub fn hand_back(core: CoreId) -> Result<()> {
+    OFFLINE.lock().hand_back(core)?;
+    unsafe {
+        apic::get_local_apic_mut().send_ipi(
+            core.raw.into(),
+            apic::DstShorthand::NoShorthand,
+            apic::TriggerMode::Edge,
+            apic::Level::Assert,
+            apic::DstMode::Physical,
+            apic::DeliveryMode::Init,
+            0,
+        );
+    }
+    Ok(())
+}
+
+/// The `offline` management console command
+pub fn offline_command(out: &mut dyn fmt::Write, args: &[&str]) -> fmt::Result {
+    let core = match args.get(0).map(|arg| arg.parse::<u32>()) {
+        None => {
+            for (core, state) in OFFLINE.lock().0.iter() {
+                writeln!(out, "Core {} is {}", core, state)?;
+            }
+            return Ok(());
+        }
+        Some(Ok(core)) => CoreId::from(core),
+        Some(Err(_)) => {
+            return writeln!(out, "usage: offline [<core> [init]]");
+        }
+    };
+
+    let result = match args.get(1) {
+        None => take_offline(core).map(|moving| match moving {
+            Some((vcpu, target)) => format!(
+                "Moving vCPU {} to core {}, then parking core {}",
+                vcpu, target, core
+            ),
+            None => format!("Parking core {}", core),
+        }),
+        Some(&"init") => hand_back(core)
+            .map(|_| format!("Core {} was handed back to firmware", core)),
+        Some(_) => return writeln!(out, "usage: offline [<core> [init]]"),
+    };
+    match result {
+        Ok(msg) => writeln!(out, "{}", msg),
+        Err(Error::InvalidValue(msg)) => writeln!(out, "{}", msg),
+        Err(e) => writeln!(out, "{:?}", e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_offline_states() {
+        let core = CoreId::from(3);
+        let mut offline = OfflineCores::new();
+        assert!(offline.hand_back(core).is_err());
+
+        offline.request(core).unwrap();
+        assert!(offline.request(core).is_err());
+        assert!(offline.hand_back(core).is_err());
+        assert!(offline.cancel(core));
+        assert!(!offline.cancel(core));
+        assert_eq!(offline.state(core), None);
+
+        offline.request(core).unwrap();
+        offline.set(core, OfflineState::Parked);
+        assert!(!offline.cancel(core));
+        offline.hand_back(core).unwrap();
+        assert_eq!(offline.state(core), Some(OfflineState::HandedBack));
+        assert!(offline.hand_back(core).is_err());
+        assert_eq!(offline.0.len(), 1);
+    }
+}
@@ -0,0 +1,680 @@
+//! Guest page table walks
+//!
+//! The emulation of guest instructions must translate guest linear
+//! addresses exactly as the processor would, including the permission
+//! checks and accessed/dirty bit updates, so a guest sees the same faults
+//! from emulated accesses as from real ones. This module implements the
+//! walk for each of the paging modes (see Chapter 4 of the SDM). Failed
+//! translations produce a `GuestFault::PageFault`, which is injected into
+//! the guest like any other guest fault.
+//!
+//! Protection keys are not checked.
+
+use crate::error::Result;
+use crate::memory::{GuestAccess, GuestFault, GuestPhysAddr};
+use crate::vmcs;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+const PTE_PRESENT: u64 = 1 << 0;
+const PTE_WRITABLE: u64 = 1 << 1;
+const PTE_USER: u64 = 1 << 2;
+const PTE_ACCESSED: u64 = 1 << 5;
+const PTE_DIRTY: u64 = 1 << 6;
+const PTE_PAGE_SIZE: u64 = 1 << 7;
+const PTE_NO_EXECUTE: u64 = 1 << 63;
+
+// The bits of a PAE PDPTE that must be zero (besides those above MAXPHYADDR)
+const PDPTE_RESERVED: u64 = 0x1e6 | PTE_NO_EXECUTE;
+
+// The bits of a 32-bit 4MB page PDE that must be zero
+const PDE_4MB_RESERVED: u64 = 1 << 21;
+
+// The page fault error code bits (see Section 4.7 of the SDM)
+const PF_PROTECTION: u32 = 1 << 0;
+const PF_WRITE: u32 = 1 << 1;
+const PF_USER: u32 = 1 << 2;
+const PF_RESERVED: u32 = 1 << 3;
+const PF_FETCH: u32 = 1 << 4;
+
+const CR0_WP: u64 = 1 << 16;
+const CR0_PG: u64 = 1 << 31;
+const CR4_PSE: u64 = 1 << 4;
+const CR4_PAE: u64 = 1 << 5;
+const CR4_LA57: u64 = 1 << 12;
+const CR4_SMEP: u64 = 1 << 20;
+const CR4_SMAP: u64 = 1 << 21;
+const EFER_NXE: u64 = 1 << 11;
+const EFER_LMA: u64 = 1 << 10;
+const RFLAGS_AC: u64 = 1 << 18;
+
+/// The paging mode of a guest (see Section 4.1.1 of the SDM)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PagingMode {
+    /// Linear addresses are physical addresses
+    Disabled,
+    /// Two levels of 32-bit entries
+    Bits32,
+    /// Four PDPTEs (held by the processor) and two levels of 64-bit entries
+    Pae,
+    /// Four levels of 64-bit entries
+    Level4,
+    /// Five levels of 64-bit entries
+    Level5,
+}
+
+impl PagingMode {
+    /// The mode selected by the given control registers
+    pub fn from_registers(cr0: u64, cr4: u64, efer: u64) -> Self {
+        if cr0 & CR0_PG == 0 {
+            PagingMode::Disabled
+        } else if cr4 & CR4_PAE == 0 {
+            PagingMode::Bits32
+        } else if efer & EFER_LMA == 0 {
+            PagingMode::Pae
+        } else if cr4 & CR4_LA57 == 0 {
+            PagingMode::Level4
+        } else {
+            PagingMode::Level5
+        }
+    }
+
+    /// The current mode of the guest of `vmcs`
+    pub fn from_vmcs(vmcs: &vmcs::ActiveVmcs) -> Result<Self> {
+        Ok(Self::from_registers(
+            vmcs.read_field(vmcs::VmcsField::GuestCr0)?,
+            vmcs.read_field(vmcs::VmcsField::GuestCr4)?,
+            vmcs.read_field(vmcs::VmcsField::GuestIa32Efer)?,
+        ))
+    }
+}
+
+/// The guest state that controls the translation of linear addresses
+#[derive(Clone, Copy, Debug)]
+pub struct PagingContext {
+    pub mode: PagingMode,
+    pub cr3: GuestPhysAddr,
+    /// The PDPTEs loaded by the processor (used only by PAE paging)
+    pub pdptes: [u64; 4],
+    /// CR0.WP: supervisor writes honor read-only pages
+    pub write_protect: bool,
+    /// CR4.PSE: 32-bit paging supports 4MB pages
+    pub page_size_extensions: bool,
+    /// EFER.NXE: the XD bit prevents instruction fetches
+    pub no_execute: bool,
+    /// CR4.SMEP: supervisor fetches from user pages fault
+    pub smep: bool,
+    /// CR4.SMAP: supervisor data accesses to user pages fault (unless
+    /// RFLAGS.AC is set)
+    pub smap: bool,
+    /// RFLAGS.AC
+    pub alignment_check: bool,
+    /// The physical address width (bits above this are reserved)
+    pub max_phys_addr: u32,
+}
+
+impl PagingContext {
+    /// A context for `mode` with the page tables at `cr3` and no optional
+    /// protection features enabled
+    pub fn new(mode: PagingMode, cr3: GuestPhysAddr) -> Self {
+        Self {
+            mode,
+            cr3,
+            pdptes: [0; 4],
+            write_protect: false,
+            page_size_extensions: false,
+            no_execute: false,
+            smep: false,
+            smap: false,
+            alignment_check: false,
+            max_phys_addr: 52,
+        }
+    }
+
+    /// The current context of the guest of `vmcs`
+    pub fn from_vmcs(vmcs: &vmcs::ActiveVmcs) -> Result<Self> {
+        let cr0 = vmcs.read_field(vmcs::VmcsField::GuestCr0)?;
+        let cr4 = vmcs.read_field(vmcs::VmcsField::GuestCr4)?;
+        let efer = vmcs.read_field(vmcs::VmcsField::GuestIa32Efer)?;
+        let rflags = vmcs.read_field(vmcs::VmcsField::GuestRflags)?;
+        let cr3 = vmcs.read_field(vmcs::VmcsField::GuestCr3)?;
+        let mode = PagingMode::from_registers(cr0, cr4, efer);
+
+        let mut pdptes = [0; 4];
+        if mode == PagingMode::Pae {
+            let fields = [
+                vmcs::VmcsField::GuestPdptr0,
+                vmcs::VmcsField::GuestPdptr1,
+                vmcs::VmcsField::GuestPdptr2,
+                vmcs::VmcsField::GuestPdptr3,
+            ];
+            for (pdpte, field) in pdptes.iter_mut().zip(fields.iter()) {
+                *pdpte = vmcs.read_field(*field)?;
+            }
+        }
+
+        // The guest sees the host's physical address width (processors
+        // that do not report it support 36 bits)
+        let max_phys_addr = match raw_cpuid::native_cpuid::cpuid_count(
+            0x80000008, 0,
+        )
+        .eax & 0xff
+        {
+            width @ 32..=52 => width,
+            _ => 36,
+        };
+
+        Ok(Self {
+            mode,
+            cr3: GuestPhysAddr::new(cr3),
+            pdptes,
+            write_protect: cr0 & CR0_WP != 0,
+            page_size_extensions: cr4 & CR4_PSE != 0,
+            no_execute: efer & EFER_NXE != 0,
+            smep: cr4 & CR4_SMEP != 0,
+            smap: cr4 & CR4_SMAP != 0,
+            alignment_check: rflags & RFLAGS_AC != 0,
+            max_phys_addr,
+        })
+    }
+
+    // The mask of the physical address bits of a 64-bit entry
+    fn address_mask(&self) -> u64 {
+        !0u64 << 12 & !(!0u64 << self.max_phys_addr)
+    }
+
+    // The reserved bits of a 64-bit entry (excluding those specific to
+    // large pages)
+    fn reserved_mask(&self) -> u64 {
+        // Bits 52-62 are ignored by the processor (and used by some
+        // operating systems)
+        let mut reserved = !0u64 << self.max_phys_addr & ((1 << 52) - 1);
+        if !self.no_execute {
+            reserved |= PTE_NO_EXECUTE;
+        }
+        reserved
+    }
+}
+
+/// The guest memory holding the page tables
+///
+/// Entries are updated atomically, as the processor does, because other
+/// vCPUs may be accessing the same tables.
+pub trait PageTableMemory {
+    /// Read the 4 or 8 byte entry at `addr`
+    fn read_entry(&self, addr: GuestPhysAddr, size: usize) -> Result<u64>;
+
+    /// Set `bits` in the 4 or 8 byte entry at `addr`
+    fn set_entry_bits(
+        &self,
+        addr: GuestPhysAddr,
+        size: usize,
+        bits: u64,
+    ) -> Result<()>;
+}
+
+/// Read a page table entry from host memory
+///
+/// # Safety
+///
+/// `ptr` must point to `size` bytes of mapped memory, aligned to `size`
+pub unsafe fn read_host_entry(ptr: *const u8, size: usize) -> u64 {
+    match size {
+        4 => (*(ptr as *const AtomicU32)).load(Ordering::Relaxed) as u64,
+        _ => (*(ptr as *const AtomicU64)).load(Ordering::Relaxed),
+    }
+}
+
+/// Atomically set bits in a page table entry in host memory
+///
+/// # Safety
+///
+/// `ptr` must point to `size` bytes of mapped memory, aligned to `size`
+pub unsafe fn set_host_entry_bits(ptr: *const u8, size: usize, bits: u64) {
+    match size {
+        4 => {
+            (*(ptr as *const AtomicU32))
+                .fetch_or(bits as u32, Ordering::SeqCst);
+        }
+        _ => {
+            (*(ptr as *const AtomicU64)).fetch_or(bits, Ordering::SeqCst);
+        }
+    }
+}
+
+/// The result of a successful walk
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Translation {
+    /// The guest physical address of the translated linear address
+    pub addr: GuestPhysAddr,
+    /// The size of the page that maps the address
+    pub page_size: u64,
+    /// Whether the page is writable (by user mode, if it is a user page)
+    pub writable: bool,
+    /// Whether the page is accessible from user mode
+    pub user: bool,
+    /// Whether instructions may be fetched from the page
+    pub executable: bool,
+}
+
+struct Walk<'a> {
+    paging: &'a PagingContext,
+    addr: u64,
+    write: bool,
+    fetch: bool,
+    user_access: bool,
+}
+
+impl<'a> Walk<'a> {
+    fn fault(&self, present: bool, reserved: bool) -> GuestFault {
+        let mut error_code = 0;
+        if present {
+            error_code |= PF_PROTECTION;
+        }
+        if reserved {
+            error_code |= PF_RESERVED;
+        }
+        if self.write {
+            error_code |= PF_WRITE;
+        }
+        if self.user_access {
+            error_code |= PF_USER;
+        }
+        if self.fetch && (self.paging.no_execute || self.paging.smep) {
+            error_code |= PF_FETCH;
+        }
+        GuestFault::PageFault {
+            addr: self.addr,
+            error_code,
+        }
+    }
+
+    fn is_permitted(&self, writable: bool, user: bool, nx: bool) -> bool {
+        let paging = self.paging;
+        if self.fetch && nx {
+            return false;
+        }
+        if self.user_access {
+            return user && (writable || !self.write);
+        }
+        if self.write && !writable && paging.write_protect {
+            return false;
+        }
+        if user && self.fetch && paging.smep {
+            return false;
+        }
+        if user && !self.fetch && paging.smap && !paging.alignment_check {
+            return false;
+        }
+        true
+    }
+}
+
+/// Translate the guest linear address `addr` for `access`
+///
+/// On success, the accessed flags of the paging-structure entries used for
+/// the translation are set (and the dirty flag of the final entry, for a
+/// write). Translations that would fault on the processor fail with a
+/// `GuestFault::PageFault`. Other errors indicate that the page tables are
+/// not in guest memory.
+pub fn walk(
+    paging: &PagingContext,
+    memory: &impl PageTableMemory,
+    addr: u64,
+    access: GuestAccess,
+) -> Result<Translation> {
+    let (level, write, fetch) = match access {
+        GuestAccess::Read(level) => (level, false, false),
+        GuestAccess::Write(level) => (level, true, false),
+        GuestAccess::Fetch(level) => (level, false, true),
+    };
+    let walk = Walk {
+        paging,
+        addr,
+        write,
+        fetch,
+        user_access: level.0 == 3,
+    };
+
+    // The index shifts of each level, the entry size and the first table
+    let (shifts, size, mut table): (&[u32], usize, u64) = match paging.mode {
+        PagingMode::Disabled => {
+            return Ok(Translation {
+                addr: GuestPhysAddr::new(addr),
+                page_size: 4096,
+                writable: true,
+                user: true,
+                executable: true,
+            })
+        }
+        PagingMode::Bits32 => (&[22, 12], 4, paging.cr3.as_u64() & 0xfffff000),
+        PagingMode::Pae => {
+            let pdpte = paging.pdptes[((addr >> 30) & 3) as usize];
+            if pdpte & PTE_PRESENT == 0 {
+                return Err(walk.fault(false, false).into());
+            }
+            let reserved = PDPTE_RESERVED | !0u64 << paging.max_phys_addr;
+            if pdpte & reserved != 0 {
+                return Err(walk.fault(true, true).into());
+            }
+            (&[21, 12], 8, pdpte & paging.address_mask())
+        }
+        PagingMode::Level4 => (
+            &[39, 30, 21, 12],
+            8,
+            paging.cr3.as_u64() & paging.address_mask(),
+        ),
+        PagingMode::Level5 => (
+            &[48, 39, 30, 21, 12],
+            8,
+            paging.cr3.as_u64() & paging.address_mask(),
+        ),
+    };
+    let index_mask = if size == 4 { 0x3ff } else { 0x1ff };
+
+    let mut entries = [(GuestPhysAddr::new(0), 0u64); 5];
+    let mut depth = 0;
+    let mut writable = true;
+    let mut user = true;
+    let mut nx = false;
+    let mut base = 0;
+    let mut page_size = 0;
+    for shift in shifts.iter() {
+        let entry_addr = GuestPhysAddr::new(
+            table + ((addr >> shift) & index_mask) * size as u64,
+        );
+        let entry = memory.read_entry(entry_addr, size)?;
+        if entry & PTE_PRESENT == 0 {
+            return Err(walk.fault(false, false).into());
+        }
+        if size == 8 && entry & paging.reserved_mask() != 0 {
+            return Err(walk.fault(true, true).into());
+        }
+
+        writable &= entry & PTE_WRITABLE != 0;
+        user &= entry & PTE_USER != 0;
+        nx |= paging.no_execute && entry & PTE_NO_EXECUTE != 0;
+        entries[depth] = (entry_addr, entry);
+        depth += 1;
+
+        let large = entry & PTE_PAGE_SIZE != 0;
+        match (*shift, size) {
+            (12, _) => {
+                base = entry & paging.address_mask();
+                page_size = 1 << 12;
+                break;
+            }
+            // 4MB pages (with PSE-36 address bits 32-39)
+            (22, 4) if large && paging.page_size_extensions => {
+                if entry & PDE_4MB_RESERVED != 0 {
+                    return Err(walk.fault(true, true).into());
+                }
+                base = (entry & 0xffc00000) | ((entry >> 13) & 0xff) << 32;
+                page_size = 1 << 22;
+                break;
+            }
+            // 2MB and 1GB pages (the low bit of the address is the PAT bit)
+            (21, 8) | (30, 8) if large => {
+                page_size = 1 << shift;
+                let reserved = (page_size - 1) & !0x1fff;
+                if entry & reserved != 0 {
+                    return Err(walk.fault(true, true).into());
+                }
+                base = entry & paging.address_mask() & !(page_size - 1);
+                break;
+            }
+            (39, 8) | (48, 8) if large => {
+                return Err(walk.fault(true, true).into());
+            }
+            _ => {
+                table = if size == 4 {
+                    entry & 0xfffff000
+                } else {
+                    entry & paging.address_mask()
+                };
+            }
+        }
+    }
+
+    if !walk.is_permitted(writable, user, nx) {
+        return Err(walk.fault(true, false).into());
+    }
+
+    for (i, (entry_addr, entry)) in entries[..depth].iter().enumerate() {
+        let mut bits = PTE_ACCESSED;
+        if write && i == depth - 1 {
+            bits |= PTE_DIRTY;
+        }
+        if entry & bits != bits {
+            memory.set_entry_bits(*entry_addr, size, bits)?;
+        }
+    }
+
+    Ok(Translation {
+        addr: GuestPhysAddr::new(base | (addr & (page_size - 1))),
+        page_size,
+        writable,
+        user,
+        executable: !nx,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::Error;
+    use crate::memory::PrivilegeLevel;
+    use alloc::collections::BTreeMap;
+    use core::cell::RefCell;
+
+    #[derive(Default)]
+    struct TestMemory(RefCell<BTreeMap<u64, u64>>);
+
+    impl TestMemory {
+        fn set(&self, addr: u64, entry: u64) {
+            self.0.borrow_mut().insert(addr, entry);
+        }
+
+        fn get(&self, addr: u64) -> u64 {
+            self.0.borrow().get(&addr).copied().unwrap_or(0)
+        }
+    }
+
+    impl PageTableMemory for TestMemory {
+        fn read_entry(&self, addr: GuestPhysAddr, _size: usize) -> Result<u64> {
+            Ok(self.get(addr.as_u64()))
+        }
+
+        fn set_entry_bits(
+            &self,
+            addr: GuestPhysAddr,
+            _size: usize,
+            bits: u64,
+        ) -> Result<()> {
+            let entry = self.get(addr.as_u64());
+            self.set(addr.as_u64(), entry | bits);
+            Ok(())
+        }
+    }
+
+    const P: u64 = PTE_PRESENT;
+    const W: u64 = PTE_WRITABLE;
+    const U: u64 = PTE_USER;
+
+    fn read(level: u8) -> GuestAccess {
+        GuestAccess::Read(PrivilegeLevel(level))
+    }
+
+    fn write(level: u8) -> GuestAccess {
+        GuestAccess::Write(PrivilegeLevel(level))
+    }
+
+    fn fetch(level: u8) -> GuestAccess {
+        GuestAccess::Fetch(PrivilegeLevel(level))
+    }
+
+    fn page_fault(result: Result<Translation>) -> Option<(u64, u32)> {
+        match result {
+            Err(Error::GuestFault(GuestFault::PageFault {
+                addr,
+                error_code,
+            })) => Some((addr, error_code)),
+            _ => None,
+        }
+    }
+
+    // Map 0x400123 through 4-level tables at 0x1000-0x4000 to 0x9000
+    fn level4_tables(leaf: u64) -> TestMemory {
+        let memory = TestMemory::default();
+        memory.set(0x1000, 0x2000 | P | W | U);
+        memory.set(0x2000, 0x3000 | P | W | U);
+        memory.set(0x3000 + 2 * 8, 0x4000 | P | W | U);
+        memory.set(0x4000, 0x9000 | leaf);
+        memory
+    }
+
+    #[test]
+    fn test_level4() {
+        let paging =
+            PagingContext::new(PagingMode::Level4, GuestPhysAddr::new(0x1000));
+        let memory = level4_tables(P | W | U);
+        let translation = walk(&paging, &memory, 0x400123, read(0)).unwrap();
+        assert_eq!(translation.addr, GuestPhysAddr::new(0x9123));
+        assert_eq!(translation.page_size, 4096);
+
+        // Reads set the accessed flags, writes also set the dirty flag
+        assert_eq!(memory.get(0x4000), 0x9000 | P | W | U | PTE_ACCESSED);
+        assert_eq!(memory.get(0x1000) & PTE_ACCESSED, PTE_ACCESSED);
+        walk(&paging, &memory, 0x400123, write(3)).unwrap();
+        assert_eq!(memory.get(0x4000) & PTE_DIRTY, PTE_DIRTY);
+
+        assert_eq!(
+            page_fault(walk(&paging, &memory, 0x600000, write(3))),
+            Some((0x600000, PF_WRITE | PF_USER))
+        );
+    }
+
+    #[test]
+    fn test_level5() {
+        let mut paging =
+            PagingContext::new(PagingMode::Level5, GuestPhysAddr::new(0x5000));
+        let memory = level4_tables(P | W);
+        memory.set(0x5000 + 8, 0x1000 | P | W | U);
+        let addr = (1 << 48) | 0x400123;
+        assert_eq!(
+            walk(&paging, &memory, addr, read(0)).unwrap().addr,
+            GuestPhysAddr::new(0x9123)
+        );
+
+        // A 4-level walk ignores bits 48 and above
+        paging.mode = PagingMode::Level4;
+        paging.cr3 = GuestPhysAddr::new(0x1000);
+        assert_eq!(
+            walk(&paging, &memory, 0x400123, read(0)).unwrap().addr,
+            GuestPhysAddr::new(0x9123)
+        );
+    }
+
+    #[test]
+    fn test_large_pages() {
+        let paging =
+            PagingContext::new(PagingMode::Level4, GuestPhysAddr::new(0x1000));
+        let memory = TestMemory::default();
+        memory.set(0x1000, 0x2000 | P | W);
+        memory.set(0x2000, 0x40000000 | P | W | PTE_PAGE_SIZE);
+        memory.set(0x2000 + 8, 0x3000 | P | W);
+        memory.set(0x3000, 0x200000 | P | W | PTE_PAGE_SIZE);
+        memory.set(0x3000 + 8, 0x202000 | P | W | PTE_PAGE_SIZE);
+
+        let huge = walk(&paging, &memory, 0x1234567, read(0)).unwrap();
+        assert_eq!(huge.addr, GuestPhysAddr::new(0x41234567));
+        assert_eq!(huge.page_size, 1 << 30);
+
+        let large = walk(&paging, &memory, 0x40012345, read(0)).unwrap();
+        assert_eq!(large.addr, GuestPhysAddr::new(0x212345));
+        assert_eq!(large.page_size, 1 << 21);
+
+        // Address bits below the page size are reserved (except PAT)
+        assert_eq!(
+            page_fault(walk(&paging, &memory, 0x40200000, read(0))),
+            Some((0x40200000, PF_PROTECTION | PF_RESERVED))
+        );
+    }
+
+    #[test]
+    fn test_bits32_and_pae() {
+        let mut paging =
+            PagingContext::new(PagingMode::Bits32, GuestPhysAddr::new(0x1000));
+        let memory = TestMemory::default();
+        memory.set(0x1000 + 4, 0x2000 | P | W);
+        memory.set(0x2000 + 4, 0x7000 | P | W);
+        memory.set(0x1000 + 8, 0x0140_0000 | 0x2000 | P | W | PTE_PAGE_SIZE);
+        assert_eq!(
+            walk(&paging, &memory, 0x401abc, read(0)).unwrap().addr,
+            GuestPhysAddr::new(0x7abc)
+        );
+
+        // PS is ignored unless CR4.PSE is set
+        assert!(walk(&paging, &memory, 0x800000, read(0)).is_err());
+        paging.page_size_extensions = true;
+        assert_eq!(
+            walk(&paging, &memory, 0x812345, read(0)).unwrap().addr,
+            GuestPhysAddr::new(0x1_0141_2345)
+        );
+
+        paging.mode = PagingMode::Pae;
+        paging.pdptes = [0, 0x3000 | P, 0, 0];
+        memory.set(0x3000 + 2 * 8, 0x4000 | P | W);
+        memory.set(0x4000 + 3 * 8, 0x8000 | P | W);
+        assert_eq!(
+            walk(&paging, &memory, 0x40403210, read(0)).unwrap().addr,
+            GuestPhysAddr::new(0x8210)
+        );
+        assert_eq!(
+            page_fault(walk(&paging, &memory, 0x3210, read(0))),
+            Some((0x3210, 0))
+        );
+    }
+
+    #[test]
+    fn test_permissions() {
+        let mut paging =
+            PagingContext::new(PagingMode::Level4, GuestPhysAddr::new(0x1000));
+        paging.no_execute = true;
+
+        // A read-only, non-executable user page
+        let memory = level4_tables(P | U | PTE_NO_EXECUTE);
+        assert!(walk(&paging, &memory, 0x400000, read(3)).is_ok());
+        assert_eq!(
+            page_fault(walk(&paging, &memory, 0x400000, write(3))),
+            Some((0x400000, PF_PROTECTION | PF_WRITE | PF_USER))
+        );
+        assert_eq!(
+            page_fault(walk(&paging, &memory, 0x400000, fetch(0))),
+            Some((0x400000, PF_PROTECTION | PF_FETCH))
+        );
+
+        // Supervisor writes only honor read-only pages with CR0.WP
+        assert!(walk(&paging, &memory, 0x400000, write(0)).is_ok());
+        paging.write_protect = true;
+        assert!(walk(&paging, &memory, 0x400000, write(0)).is_err());
+
+        // SMAP prevents supervisor reads of user pages unless RFLAGS.AC
+        paging.smap = true;
+        assert!(walk(&paging, &memory, 0x400000, read(0)).is_err());
+        paging.alignment_check = true;
+        assert!(walk(&paging, &memory, 0x400000, read(0)).is_ok());
+
+        // Supervisor pages are not accessible from user mode, and the XD
+        // bit is reserved unless EFER.NXE is set
+        let memory = level4_tables(P | W | PTE_NO_EXECUTE);
+        assert_eq!(
+            page_fault(walk(&paging, &memory, 0x400000, read(3))),
+            Some((0x400000, PF_PROTECTION | PF_USER))
+        );
+        paging.no_execute = false;
+        assert_eq!(
+            page_fault(walk(&paging, &memory, 0x400000, read(0))),
+            Some((0x400000, PF_PROTECTION | PF_RESERVED))
+        );
+    }
+}
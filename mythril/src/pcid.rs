@@ -0,0 +1,56 @@
+//! Process-context identifiers for the hypervisor's address space
+//!
+//! The hypervisor runs every core in a single address space. When the
+//! processor supports PCIDs, `init` tags that address space with
+//! `HOST_PCID`. The VMCS host state is read from the current CR3 and CR4,
+//! so every VM exit returns to the tagged address space.
+//!
+//! The hypervisor never switches to another address space or changes its
+//! own mappings, so there are no host context switches or invalidations
+//! for PCIDs to avoid. Switching between guests (and between a guest and
+//! the host) relies on VPIDs, which keep the translations of each guest
+//! apart from those of the host across VM entries and exits.
+//!
+//! Whether PCIDs are enabled is reported with the exit latency histograms
+//! of the `exitlat` console command (see `health`), so their effect on
+//! exit handling can be measured.
+
+use raw_cpuid::CpuId;
+use x86::controlregs::{cr3, cr3_write, cr4};
+
+/// The PCID of the hypervisor's address space
+pub const HOST_PCID: u64 = 1;
+
+const CR4_PCIDE: u64 = 1 << 17;
+const CR3_PCID_MASK: u64 = 0xfff;
+
+/// Returns true if the processor supports PCIDs
+pub fn supported() -> bool {
+    CpuId::new()
+        .get_feature_info()
+        .map_or(false, |info| info.has_pcid())
+}
+
+/// Returns true if PCIDs are enabled on the current core
+pub fn enabled() -> bool {
+    unsafe { cr4() }.bits() as u64 & CR4_PCIDE != 0
+}
+
+/// Enable PCIDs on the current core (if supported), tagging the current
+/// address space with `HOST_PCID`
+///
+/// This must run on each core before its VMCS host state is initialized.
+pub unsafe fn init() {
+    if !supported() || enabled() {
+        return;
+    }
+
+    // PCIDE can only be set while the current PCID is zero
+    let untagged = cr3() & !CR3_PCID_MASK;
+    cr3_write(untagged);
+    llvm_asm!("movq %cr4, %rax; orq %rdx, %rax; movq %rax, %cr4;"
+              :
+              : "{rdx}"(CR4_PCIDE)
+              : "rax");
+    cr3_write(untagged | HOST_PCID);
+}
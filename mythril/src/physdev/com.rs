@@ -1,4 +1,5 @@
 use crate::error::Result;
+use alloc::vec::Vec;
 use bitflags::bitflags;
 use x86::io::{inb, outb};
 
@@ -12,9 +13,12 @@ pub mod SerialOffset {
     pub const IER: u16 = 1;
     pub const DLH: u16 = 1;
     pub const IIR: u16 = 2;
+    pub const FCR: u16 = 2;
     pub const LCR: u16 = 3;
+    pub const MCR: u16 = 4;
     pub const LSR: u16 = 5;
     pub const MSR: u16 = 6;
+    pub const SCR: u16 = 7;
 }
 
 bitflags! {
@@ -43,6 +47,30 @@ bitflags! {
     }
 }
 
+// The delta bits of the modem status register
+const MSR_DELTAS: u8 = 0x0f;
+
+// The most input read from the port by a single `drain`
+const MAX_DRAIN: usize = 64;
+
+/// A change in the line or modem state of a serial port
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SerialSignal {
+    /// The line was held in the break state
+    Break,
+
+    /// The modem status lines changed. The value holds the current state of
+    /// the lines, in the upper nibble of the MSR layout.
+    ModemStatus(u8),
+}
+
+/// Input read from a serial port
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SerialInput {
+    Data(u8),
+    Signal(SerialSignal),
+}
+
 pub struct Uart8250 {
     base: u16,
 }
@@ -50,7 +78,11 @@ pub struct Uart8250 {
 impl Uart8250 {
     pub fn new(base: u16) -> Result<Self> {
         let mut uart = Self { base };
-        uart.write_ier(IerFlags::RECV_DATA_AVAIL_INTERRUPT);
+        uart.write_ier(
+            IerFlags::RECV_DATA_AVAIL_INTERRUPT
+                | IerFlags::RECEIVER_LINE_STATUS_INTERRUPT
+                | IerFlags::MODEM_STATUS_INTERRUPT,
+        );
         Ok(uart)
     }
 
@@ -70,10 +102,52 @@ impl Uart8250 {
         })
     }
 
+    /// Returns true if a received byte is waiting to be read
+    pub fn data_ready(&self) -> bool {
+        self.read_lsr().contains(LsrFlags::DATA_READY)
+    }
+
     pub fn read(&self) -> u8 {
         unsafe { inb(self.base + SerialOffset::DATA) }
     }
 
+    /// Read the next input waiting at the port, if any
+    ///
+    /// Reading the input acknowledges the interrupt it raised. A break is
+    /// received along with a zero byte, which is not reported as data.
+    pub fn poll(&self) -> Option<SerialInput> {
+        let lsr = self.read_lsr();
+        if lsr.contains(LsrFlags::BREAK_INTERRUPT) {
+            if lsr.contains(LsrFlags::DATA_READY) {
+                self.read();
+            }
+            return Some(SerialInput::Signal(SerialSignal::Break));
+        }
+        if lsr.contains(LsrFlags::DATA_READY) {
+            return Some(SerialInput::Data(self.read()));
+        }
+        let msr = unsafe { inb(self.base + SerialOffset::MSR) };
+        if msr & MSR_DELTAS != 0 {
+            return Some(SerialInput::Signal(SerialSignal::ModemStatus(
+                msr & !MSR_DELTAS,
+            )));
+        }
+        None
+    }
+
+    /// Read all of the input waiting at the port (up to a limit, as input
+    /// may keep arriving)
+    pub fn drain(&self) -> Vec<SerialInput> {
+        let mut input = vec![];
+        while input.len() < MAX_DRAIN {
+            match self.poll() {
+                Some(next) => input.push(next),
+                None => break,
+            }
+        }
+        input
+    }
+
     pub fn write(&mut self, data: u8) {
         while !self
             .read_lsr()
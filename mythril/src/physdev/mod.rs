@@ -1,3 +1,4 @@
 pub mod com;
 pub mod keyboard;
 pub mod pit;
+pub mod rtc;
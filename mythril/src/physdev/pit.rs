@@ -32,7 +32,7 @@ pub enum AccessMode {
     Word = 0b11,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 #[repr(u8)]
 pub enum OperatingMode {
     Mode0 = 0b000, // interrupt on terminal count
@@ -0,0 +1,208 @@
+//! The host real-time clock, and conversion between calendar dates and
+//! seconds since the unix epoch
+
+use crate::error::{Error, Result};
+use crate::virtdev::Port;
+use x86::io::{inb, outb};
+
+pub const RTC_ADDRESS: Port = 0x0070;
+pub const RTC_DATA: Port = 0x0071;
+
+pub const RTC_SECONDS: u8 = 0x00;
+pub const RTC_MINUTES: u8 = 0x02;
+pub const RTC_HOURS: u8 = 0x04;
+pub const RTC_DAY_OF_MONTH: u8 = 0x07;
+pub const RTC_MONTH: u8 = 0x08;
+pub const RTC_YEAR: u8 = 0x09;
+pub const RTC_STATUS_A: u8 = 0x0a;
+pub const RTC_STATUS_B: u8 = 0x0b;
+pub const RTC_CENTURY: u8 = 0x32;
+
+/// Status register A: an update cycle is in progress
+pub const RTC_A_UIP: u8 = 1 << 7;
+
+/// Status register B: the clock registers are binary (rather than BCD)
+pub const RTC_B_BINARY: u8 = 1 << 2;
+
+/// Status register B: hours are in 24 hour format
+pub const RTC_B_24H: u8 = 1 << 1;
+
+/// The PM flag of the hours register in 12 hour format
+pub const RTC_HOURS_PM: u8 = 1 << 7;
+
+const SECONDS_PER_DAY: i64 = 86400;
+
+pub fn bcd_to_binary(val: u8) -> u8 {
+    (val >> 4) * 10 + (val & 0x0f)
+}
+
+pub fn binary_to_bcd(val: u8) -> u8 {
+    ((val / 10) << 4) | (val % 10)
+}
+
+/// A calendar date and time (UTC)
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// The date and time `secs` seconds after the unix epoch
+    pub fn from_unix(secs: i64) -> Self {
+        let days = secs.div_euclid(SECONDS_PER_DAY);
+        let time = secs.rem_euclid(SECONDS_PER_DAY);
+
+        // See http://howardhinnant.github.io/date_algorithms.html
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + (month <= 2) as i64;
+
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+
+    /// The number of seconds since the unix epoch
+    pub fn to_unix(&self) -> i64 {
+        let month = self.month as i64;
+        let year = self.year as i64 - (month <= 2) as i64;
+        let era = year.div_euclid(400);
+        let yoe = year.rem_euclid(400);
+        let mp = (month + 9) % 12;
+        let doy = (153 * mp + 2) / 5 + self.day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146097 + doe - 719468;
+
+        days * SECONDS_PER_DAY
+            + self.hour as i64 * 3600
+            + self.minute as i64 * 60
+            + self.second as i64
+    }
+
+    /// The day of the week, from 1 (Sunday) to 7, as the RTC reports it
+    pub fn day_of_week(&self) -> u8 {
+        // The epoch was a Thursday
+        ((self.to_unix().div_euclid(SECONDS_PER_DAY) + 4).rem_euclid(7) + 1)
+            as u8
+    }
+}
+
+unsafe fn read_register(reg: u8) -> u8 {
+    // Keep NMIs enabled
+    outb(RTC_ADDRESS, reg & 0x7f);
+    inb(RTC_DATA)
+}
+
+unsafe fn read_date_time() -> Result<DateTime> {
+    let mut polls = 0;
+    while read_register(RTC_STATUS_A) & RTC_A_UIP != 0 {
+        polls += 1;
+        if polls > 1_000_000 {
+            return Err(Error::NotSupported);
+        }
+    }
+
+    let status_b = read_register(RTC_STATUS_B);
+    let decode = |val: u8| {
+        if status_b & RTC_B_BINARY != 0 {
+            val
+        } else {
+            bcd_to_binary(val)
+        }
+    };
+
+    let raw_hour = read_register(RTC_HOURS);
+    let mut hour = decode(raw_hour & !RTC_HOURS_PM);
+    if status_b & RTC_B_24H == 0 {
+        hour %= 12;
+        if raw_hour & RTC_HOURS_PM != 0 {
+            hour += 12;
+        }
+    }
+
+    // Not every platform has a century register, so assume this century
+    // when it looks invalid
+    let century = match decode(read_register(RTC_CENTURY)) {
+        century @ 19..=21 => century,
+        _ => 20,
+    };
+
+    Ok(DateTime {
+        year: century as u16 * 100 + decode(read_register(RTC_YEAR)) as u16,
+        month: decode(read_register(RTC_MONTH)),
+        day: decode(read_register(RTC_DAY_OF_MONTH)),
+        hour: hour,
+        minute: decode(read_register(RTC_MINUTES)),
+        second: decode(read_register(RTC_SECONDS)),
+    })
+}
+
+/// Read the host RTC, returning the number of seconds since the unix epoch
+///
+/// The RTC is read until two consecutive reads agree, so the result is
+/// not torn by an update cycle.
+pub fn read_unix_time() -> Result<i64> {
+    let mut last = unsafe { read_date_time()? };
+    loop {
+        let current = unsafe { read_date_time()? };
+        if current == last {
+            return Ok(current.to_unix());
+        }
+        last = current;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bcd() {
+        assert_eq!(bcd_to_binary(0x59), 59);
+        assert_eq!(binary_to_bcd(59), 0x59);
+        assert_eq!(binary_to_bcd(0), 0);
+    }
+
+    #[test]
+    fn test_date_time() {
+        assert_eq!(DateTime::from_unix(0).year, 1970);
+        assert_eq!(DateTime::from_unix(0).day_of_week(), 5);
+
+        let leap_day = DateTime {
+            year: 2020,
+            month: 2,
+            day: 29,
+            hour: 23,
+            minute: 59,
+            second: 58,
+        };
+        assert_eq!(leap_day.to_unix(), 1583020798);
+        assert_eq!(DateTime::from_unix(1583020798), leap_day);
+        assert_eq!(leap_day.day_of_week(), 7);
+        assert_eq!(
+            DateTime::from_unix(1583020800),
+            DateTime {
+                year: 2020,
+                month: 3,
+                day: 1,
+                ..DateTime::default()
+            }
+        );
+    }
+}
@@ -0,0 +1,497 @@
+//! Support for booting kernels through the PVH entry point
+//!
+//! A PVH kernel is an ELF image with a `XEN_ELFNOTE_PHYS32_ENTRY` note. The
+//! kernel is entered directly in 32-bit protected mode (with paging disabled)
+//! with `ebx` holding the guest physical address of an `hvm_start_info`
+//! structure. See https://xenbits.xen.org/docs/unstable/misc/pvh.html
+
+use crate::acpi::tables::AcpiTables;
+use crate::biosdata::{
+    BiosData, BDA_ADDR, EBDA_ADDR, IRET_STUB, IRET_STUB_ADDR, IVT_ADDR,
+};
+use crate::boot_info::BootInfo;
+use crate::error::{Error, Result};
+use crate::memory::{
+    GuestAccess, GuestAddressSpace, GuestPhysAddr, GuestVirtAddr,
+    PrivilegeLevel,
+};
+use crate::pagewalk::{PagingContext, PagingMode};
+use crate::smbios::{SmbiosTables, SMBIOS_ANCHOR_ADDR, SMBIOS_TABLES_ADDR};
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, LittleEndian};
+
+const XEN_ELFNOTE_PHYS32_ENTRY: u32 = 18;
+const HVM_START_MAGIC_VALUE: u32 = 0x336ec578;
+const HVM_START_INFO_VERSION: u32 = 1;
+
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+
+const E820_RAM: u32 = 1;
+const E820_RESERVED: u32 = 2;
+
+const START_INFO_ADDR: u64 = 0x6000;
+const MODLIST_ADDR: u64 = 0x6040;
+const MEMMAP_ADDR: u64 = 0x6080;
+const CMDLINE_ADDR: u64 = 0x20000;
+const CMDLINE_MAX: usize = 0x10000;
+
+// The kernel and initramfs are loaded above the first 1MB, which holds the
+// boot structures and the BIOS area
+const HIGH_MEMORY_START: u64 = 0x100000;
+
+// The ACPI tables are placed in the (reserved) BIOS area, with the RSDP
+// where a legacy search would find it. The SMBIOS tables take the 0xf0000
+// segment.
+const ACPI_RSDP_ADDR: u64 = 0xe0000;
+const ACPI_TABLES_ADDR: u64 = 0xe0040;
+const ACPI_TABLES_MAX: usize = 0x10000 - 0x40;
+
+/// The initial state of the boot processor for a PVH guest
+#[derive(Copy, Clone, Debug)]
+pub struct PvhEntry {
+    /// The 32-bit physical entry point of the kernel
+    pub entry: u32,
+
+    /// The guest physical address of the `hvm_start_info` (passed in `ebx`)
+    pub start_info: u32,
+}
+
+struct ElfSegment {
+    kind: u32,
+    offset: usize,
+    paddr: u64,
+    filesz: usize,
+    memsz: usize,
+}
+
+fn elf_segments(image: &[u8]) -> Result<Vec<ElfSegment>> {
+    if image.len() < 52 || &image[..4] != b"\x7fELF" {
+        return Err(Error::InvalidValue("Kernel is not an ELF image".into()));
+    }
+
+    let is_64bit = match image[4] {
+        1 => false,
+        2 => true,
+        class => {
+            return Err(Error::InvalidValue(format!(
+                "Invalid ELF class: {}",
+                class
+            )))
+        }
+    };
+    if image[5] != 1 {
+        return Err(Error::InvalidValue(
+            "Only little-endian ELF images are supported".into(),
+        ));
+    }
+
+    let (phoff, phentsize, phnum, min_phentsize) = if is_64bit {
+        if image.len() < 64 {
+            return Err(Error::InvalidValue("Truncated ELF header".into()));
+        }
+        (
+            LittleEndian::read_u64(&image[0x20..0x28]) as usize,
+            LittleEndian::read_u16(&image[0x36..0x38]) as usize,
+            LittleEndian::read_u16(&image[0x38..0x3a]) as usize,
+            0x38,
+        )
+    } else {
+        (
+            LittleEndian::read_u32(&image[0x1c..0x20]) as usize,
+            LittleEndian::read_u16(&image[0x2a..0x2c]) as usize,
+            LittleEndian::read_u16(&image[0x2c..0x2e]) as usize,
+            0x20,
+        )
+    };
+    if phnum > 0 && phentsize < min_phentsize {
+        return Err(Error::InvalidValue(format!(
+            "Invalid ELF program header size: {}",
+            phentsize
+        )));
+    }
+
+    let mut segments = vec![];
+    for i in 0..phnum {
+        let header = i
+            .checked_mul(phentsize)
+            .and_then(|offset| offset.checked_add(phoff))
+            .and_then(|start| image.get(start..start.checked_add(phentsize)?))
+            .ok_or_else(|| {
+                Error::InvalidValue("Truncated ELF program header".into())
+            })?;
+
+        let segment = if is_64bit {
+            ElfSegment {
+                kind: LittleEndian::read_u32(&header[0x00..0x04]),
+                offset: LittleEndian::read_u64(&header[0x08..0x10]) as usize,
+                paddr: LittleEndian::read_u64(&header[0x18..0x20]),
+                filesz: LittleEndian::read_u64(&header[0x20..0x28]) as usize,
+                memsz: LittleEndian::read_u64(&header[0x28..0x30]) as usize,
+            }
+        } else {
+            ElfSegment {
+                kind: LittleEndian::read_u32(&header[0x00..0x04]),
+                offset: LittleEndian::read_u32(&header[0x04..0x08]) as usize,
+                paddr: LittleEndian::read_u32(&header[0x0c..0x10]) as u64,
+                filesz: LittleEndian::read_u32(&header[0x10..0x14]) as usize,
+                memsz: LittleEndian::read_u32(&header[0x14..0x18]) as usize,
+            }
+        };
+
+        match segment.offset.checked_add(segment.filesz) {
+            Some(end) if end <= image.len() => (),
+            _ => {
+                return Err(Error::InvalidValue(
+                    "ELF segment extends past the end of the image".into(),
+                ))
+            }
+        }
+        segments.push(segment);
+    }
+    Ok(segments)
+}
+
+/// Find the PVH entry point in the given ELF image (if it has one)
+pub fn find_pvh_entry(image: &[u8]) -> Result<Option<u32>> {
+    for segment in elf_segments(image)? {
+        if segment.kind != PT_NOTE {
+            continue;
+        }
+
+        let mut notes = &image[segment.offset..segment.offset + segment.filesz];
+        while notes.len() >= 12 {
+            let namesz = LittleEndian::read_u32(&notes[0..4]) as usize;
+            let descsz = LittleEndian::read_u32(&notes[4..8]) as usize;
+            let kind = LittleEndian::read_u32(&notes[8..12]);
+
+            // The name and descriptor are each padded to 4 bytes
+            let name_start = 12;
+            let desc_start = name_start + ((namesz + 3) & !3);
+            let next = desc_start + ((descsz + 3) & !3);
+            if next > notes.len() {
+                break;
+            }
+
+            let name = &notes[name_start..name_start + namesz];
+            if name == b"Xen\0" && kind == XEN_ELFNOTE_PHYS32_ENTRY {
+                let desc = &notes[desc_start..desc_start + descsz];
+                return match descsz {
+                    4 => Ok(Some(LittleEndian::read_u32(desc))),
+                    8 => Ok(Some(LittleEndian::read_u64(desc) as u32)),
+                    _ => Err(Error::InvalidValue(format!(
+                        "Invalid PVH entry note size: {}",
+                        descsz
+                    ))),
+                };
+            }
+            notes = &notes[next..];
+        }
+    }
+    Ok(None)
+}
+
+// Find the (page aligned) address at the top of guest memory for an
+// initramfs of `len` bytes, which must not overlap the loaded kernel
+fn initramfs_addr(
+    segments: &[ElfSegment],
+    mem_top: u64,
+    len: usize,
+) -> Result<u64> {
+    let addr = mem_top
+        .checked_sub(len as u64)
+        .map(|addr| addr & !4095)
+        .filter(|addr| *addr >= HIGH_MEMORY_START)
+        .ok_or_else(|| {
+            Error::InvalidValue(format!(
+                "Initramfs does not fit in guest memory ({} bytes)",
+                len
+            ))
+        })?;
+    let end = addr + len as u64;
+
+    let overlap = segments.iter().find(|segment| {
+        segment.kind == PT_LOAD
+            && segment.paddr < end
+            && addr < segment.paddr + segment.memsz as u64
+    });
+    if let Some(segment) = overlap {
+        return Err(Error::InvalidValue(format!(
+            "Initramfs at 0x{:x} overlaps the kernel segment at 0x{:x}",
+            addr, segment.paddr
+        )));
+    }
+    Ok(addr)
+}
+
+fn write_phys(
+    space: &mut GuestAddressSpace,
+    addr: u64,
+    bytes: &[u8],
+) -> Result<()> {
+    space.write_bytes(
+        &PagingContext::new(PagingMode::Disabled, GuestPhysAddr::new(0)),
+        GuestVirtAddr::NoPaging(GuestPhysAddr::new(addr)),
+        bytes,
+        GuestAccess::Write(PrivilegeLevel(0)),
+    )
+}
+
+/// Write the real-mode IVT and BIOS data area that the firmware would have
+/// set up during POST
+pub fn load_bios_data(
+    data: &BiosData,
+    space: &mut GuestAddressSpace,
+) -> Result<()> {
+    write_phys(space, IVT_ADDR, data.ivt())?;
+    write_phys(space, BDA_ADDR, data.bda())?;
+    write_phys(space, EBDA_ADDR, data.ebda())?;
+    write_phys(space, IRET_STUB_ADDR, &IRET_STUB)
+}
+
+/// Place the SMBIOS tables in the (reserved) BIOS area, where the guest
+/// finds them by scanning the 0xf0000 segment
+pub fn load_smbios(
+    smbios: &SmbiosTables,
+    space: &mut GuestAddressSpace,
+) -> Result<()> {
+    let anchor = smbios.relocate()?;
+    write_phys(space, SMBIOS_ANCHOR_ADDR, &anchor)?;
+    write_phys(space, SMBIOS_TABLES_ADDR, smbios.tables())
+}
+
+/// Load the PVH kernel (and optional initramfs) into the guest address space
+///
+/// # Arguments
+///
+/// * `kernel_name` - The boot module containing the ELF kernel image
+/// * `initramfs_name` - The boot module containing the initramfs (if any)
+/// * `cmdline` - The NULL terminated kernel command line
+/// * `memory` - The amount of VM memory (in MB)
+/// * `acpi` - The ACPI tables of the guest (if any)
+pub fn load_pvh(
+    kernel_name: impl AsRef<str>,
+    initramfs_name: Option<impl AsRef<str>>,
+    cmdline: &[u8],
+    memory: u64,
+    acpi: Option<&AcpiTables>,
+    space: &mut GuestAddressSpace,
+    info: &BootInfo,
+) -> Result<PvhEntry> {
+    let kernel = info
+        .find_module(kernel_name.as_ref())
+        .ok_or_else(|| {
+            Error::InvalidValue(format!(
+                "No such kernel '{}'",
+                kernel_name.as_ref()
+            ))
+        })?
+        .data();
+
+    let entry = find_pvh_entry(kernel)?.ok_or_else(|| {
+        Error::InvalidValue(format!(
+            "Kernel '{}' has no PVH entry point",
+            kernel_name.as_ref()
+        ))
+    })?;
+
+    let mem_top = core::cmp::min(memory << 20, 0xffffffff);
+    if mem_top <= HIGH_MEMORY_START {
+        return Err(Error::InvalidValue(format!(
+            "Too little memory for a PVH guest ({}MB)",
+            memory
+        )));
+    }
+
+    let segments = elf_segments(kernel)?;
+    for segment in segments.iter() {
+        if segment.kind != PT_LOAD {
+            continue;
+        }
+        let end = segment.paddr.checked_add(segment.memsz as u64);
+        if segment.filesz > segment.memsz
+            || segment.paddr < HIGH_MEMORY_START
+            || end.map_or(true, |end| end > mem_top)
+        {
+            return Err(Error::InvalidValue(format!(
+                "Kernel segment at 0x{:x} does not fit in guest memory",
+                segment.paddr
+            )));
+        }
+        // Guest memory is freshly allocated (and therefore zeroed), so
+        // there is no need to clear the area between filesz and memsz.
+        write_phys(
+            space,
+            segment.paddr,
+            &kernel[segment.offset..segment.offset + segment.filesz],
+        )?;
+    }
+
+    if cmdline.len() > CMDLINE_MAX {
+        return Err(Error::InvalidValue(format!(
+            "Kernel command line too long ({} > {})",
+            cmdline.len(),
+            CMDLINE_MAX
+        )));
+    }
+    write_phys(space, CMDLINE_ADDR, cmdline)?;
+
+    let mut nr_modules = 0;
+    if let Some(initramfs_name) = initramfs_name {
+        let initramfs = info
+            .find_module(initramfs_name.as_ref())
+            .ok_or_else(|| {
+                Error::InvalidValue(format!(
+                    "No such initramfs '{}'",
+                    initramfs_name.as_ref()
+                ))
+            })?
+            .data();
+
+        // Place the initramfs at the top of memory (below 4GB)
+        let initrd_addr = initramfs_addr(&segments, mem_top, initramfs.len())?;
+        write_phys(space, initrd_addr, initramfs)?;
+
+        let mut modlist = [0u8; 32];
+        LittleEndian::write_u64(&mut modlist[0..8], initrd_addr);
+        LittleEndian::write_u64(&mut modlist[8..16], initramfs.len() as u64);
+        write_phys(space, MODLIST_ADDR, &modlist)?;
+        nr_modules = 1;
+    }
+
+    let mut rsdp_addr = 0;
+    if let Some(acpi) = acpi {
+        let (rsdp, tables) = acpi.relocate(ACPI_TABLES_ADDR as u32);
+        if tables.len() > ACPI_TABLES_MAX {
+            return Err(Error::InvalidValue(format!(
+                "ACPI tables too large ({} > {})",
+                tables.len(),
+                ACPI_TABLES_MAX
+            )));
+        }
+        write_phys(space, ACPI_RSDP_ADDR, &rsdp)?;
+        write_phys(space, ACPI_TABLES_ADDR, &tables)?;
+        rsdp_addr = ACPI_RSDP_ADDR;
+    }
+
+    let memmap = [
+        (0x0, EBDA_ADDR, E820_RAM),
+        (EBDA_ADDR, 0x400, E820_RESERVED),
+        (0xe0000, 0x20000, E820_RESERVED),
+        (HIGH_MEMORY_START, mem_top - HIGH_MEMORY_START, E820_RAM),
+    ];
+    let mut raw_memmap = [0u8; 24 * 4];
+    for (entry, raw) in memmap.iter().zip(raw_memmap.chunks_mut(24)) {
+        LittleEndian::write_u64(&mut raw[0..8], entry.0);
+        LittleEndian::write_u64(&mut raw[8..16], entry.1);
+        LittleEndian::write_u32(&mut raw[16..20], entry.2);
+    }
+    write_phys(space, MEMMAP_ADDR, &raw_memmap)?;
+
+    let mut start_info = [0u8; 56];
+    LittleEndian::write_u32(&mut start_info[0..4], HVM_START_MAGIC_VALUE);
+    LittleEndian::write_u32(&mut start_info[4..8], HVM_START_INFO_VERSION);
+    LittleEndian::write_u32(&mut start_info[12..16], nr_modules);
+    LittleEndian::write_u64(&mut start_info[16..24], MODLIST_ADDR);
+    LittleEndian::write_u64(&mut start_info[24..32], CMDLINE_ADDR);
+    LittleEndian::write_u64(&mut start_info[32..40], rsdp_addr);
+    LittleEndian::write_u64(&mut start_info[40..48], MEMMAP_ADDR);
+    LittleEndian::write_u32(&mut start_info[48..52], memmap.len() as u32);
+    write_phys(space, START_INFO_ADDR, &start_info)?;
+
+    info!("PVH entry: 0x{:x}", entry);
+
+    Ok(PvhEntry {
+        entry: entry,
+        start_info: START_INFO_ADDR as u32,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn build_elf64(notes: &[u8]) -> Vec<u8> {
+        let mut image = vec![0u8; 0x100];
+        image[..4].copy_from_slice(b"\x7fELF");
+        image[4] = 2; // ELFCLASS64
+        image[5] = 1; // ELFDATA2LSB
+        LittleEndian::write_u64(&mut image[0x20..0x28], 0x40);
+        LittleEndian::write_u16(&mut image[0x36..0x38], 0x38);
+        LittleEndian::write_u16(&mut image[0x38..0x3a], 1);
+
+        // A single PT_NOTE header at 0x40, with the notes at 0x80
+        LittleEndian::write_u32(&mut image[0x40..0x44], PT_NOTE);
+        LittleEndian::write_u64(&mut image[0x48..0x50], 0x80);
+        LittleEndian::write_u64(&mut image[0x60..0x68], notes.len() as u64);
+        image[0x80..0x80 + notes.len()].copy_from_slice(notes);
+        image
+    }
+
+    fn build_note(name: &[u8], kind: u32, desc: &[u8]) -> Vec<u8> {
+        let mut note = vec![0u8; 12];
+        LittleEndian::write_u32(&mut note[0..4], name.len() as u32);
+        LittleEndian::write_u32(&mut note[4..8], desc.len() as u32);
+        LittleEndian::write_u32(&mut note[8..12], kind);
+        note.extend_from_slice(name);
+        note.resize((note.len() + 3) & !3, 0);
+        note.extend_from_slice(desc);
+        note.resize((note.len() + 3) & !3, 0);
+        note
+    }
+
+    #[test]
+    fn test_find_pvh_entry() {
+        let mut notes = build_note(b"GNU\0", 3, &[0xaa; 20]);
+        notes.extend(build_note(
+            b"Xen\0",
+            XEN_ELFNOTE_PHYS32_ENTRY,
+            &0x1000000u32.to_le_bytes(),
+        ));
+        let image = build_elf64(&notes);
+        assert_eq!(find_pvh_entry(&image).unwrap(), Some(0x1000000));
+    }
+
+    #[test]
+    fn test_find_pvh_entry_missing() {
+        let notes = build_note(b"Xen\0", 1, &[0; 4]);
+        let image = build_elf64(&notes);
+        assert_eq!(find_pvh_entry(&image).unwrap(), None);
+    }
+
+    #[test]
+    fn test_find_pvh_entry_not_elf() {
+        assert!(find_pvh_entry(&[0u8; 128]).is_err());
+    }
+
+    #[test]
+    fn test_elf_segments_truncated() {
+        let mut image = build_elf64(&[0; 16]);
+        LittleEndian::write_u64(&mut image[0x60..0x68], u64::MAX);
+        assert!(elf_segments(&image).is_err());
+
+        let mut image = build_elf64(&[0; 16]);
+        LittleEndian::write_u16(&mut image[0x36..0x38], 0x10);
+        assert!(elf_segments(&image).is_err());
+    }
+
+    #[test]
+    fn test_initramfs_addr() {
+        let kernel = [ElfSegment {
+            kind: PT_LOAD,
+            offset: 0,
+            paddr: 0x1000000,
+            filesz: 0x1000,
+            memsz: 0x200000,
+        }];
+        assert_eq!(
+            initramfs_addr(&kernel, 0x4000000, 0x1800).unwrap(),
+            0x3ffe000
+        );
+
+        // Overlapping the kernel, or below (and beyond) guest memory
+        assert!(initramfs_addr(&kernel, 0x1100000, 0x1000).is_err());
+        assert!(initramfs_addr(&kernel, 0x4000000, 0x3f80000).is_err());
+        assert!(initramfs_addr(&kernel, 0x4000000, 0x5000000).is_err());
+    }
+}
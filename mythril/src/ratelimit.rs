@@ -0,0 +1,467 @@
+//! Rate limiting of guest output
+//!
+//! A guest that writes to its UART in a tight loop can monopolize its core
+//! and the physical serial line shared by all VMs. A VM may be configured
+//! with a `ConsoleRateLimit`, in which case the bytes it transmits are
+//! metered by a token bucket. Bytes beyond the limit are either dropped
+//! or delay the vCPU (which transmitted them) until the bucket refills.
+//!
+//! The messages a guest logs by hypercall (see `emulate::hypercall`) are
+//! metered the same way, and messages beyond the limit are suppressed.
+//!
+//! The emulation work a guest causes is limited by its
+//! `EmulationRateLimits`: the port and MMIO accesses handled by the
+//! hypervisor, and the interrupts injected by emulated devices and timers.
+//! Beyond either limit, the vCPU is delayed until the bucket refills, so a
+//! guest hammering a device cannot keep its lock (or the shared resource
+//! behind it) busy for the other vCPUs and VMs.
+
+use crate::percore;
+use crate::vm;
+use core::fmt;
+use core::time::Duration;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// A token bucket, refilled at a fixed rate up to its capacity
+///
+/// Times are measured from an arbitrary (but fixed) point, usually the
+/// system start time.
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    rate: u64,
+    capacity: u64,
+    tokens: u64,
+    last_refill: Duration,
+}
+
+impl TokenBucket {
+    /// Create a full bucket refilled at `rate` tokens per second
+    pub fn new(rate: u64, capacity: u64, now: Duration) -> Self {
+        Self {
+            rate: rate.max(1),
+            capacity: capacity.max(1),
+            tokens: capacity.max(1),
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, now: Duration) {
+        if now <= self.last_refill {
+            return;
+        }
+        let elapsed = (now - self.last_refill).as_nanos();
+        let added = elapsed * self.rate as u128 / NANOS_PER_SEC;
+        if self.tokens as u128 + added >= self.capacity as u128 {
+            self.tokens = self.capacity;
+            self.last_refill = now;
+        } else if added > 0 {
+            // Only account for the time that produced whole tokens, so
+            // slow rates still make progress
+            self.tokens += added as u64;
+            self.last_refill += Duration::from_nanos(
+                (added * NANOS_PER_SEC / self.rate as u128) as u64,
+            );
+        }
+    }
+
+    /// Take a token if one is available
+    pub fn try_take(&mut self, now: Duration) -> bool {
+        self.refill(now);
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+
+    /// The time until the next token is available
+    pub fn wait_time(&mut self, now: Duration) -> Duration {
+        self.refill(now);
+        if self.tokens > 0 {
+            return Duration::from_secs(0);
+        }
+        let next = self.last_refill
+            + Duration::from_nanos(
+                ((NANOS_PER_SEC + self.rate as u128 - 1) / self.rate as u128)
+                    as u64,
+            );
+        if next > now {
+            next - now
+        } else {
+            Duration::from_secs(0)
+        }
+    }
+}
+
+/// What happens to output beyond the rate limit
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConsoleLimitPolicy {
+    /// Discard the output
+    Drop,
+    /// Delay the vCPU that produced the output until it is within the limit
+    Throttle,
+}
+
+/// The rate limit applied to the UART output of a VM
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConsoleRateLimit {
+    /// The sustained rate of output
+    pub bytes_per_second: u64,
+    /// The number of bytes that may be written at once
+    pub burst: u64,
+    pub policy: ConsoleLimitPolicy,
+}
+
+/// The console output counters of a VM
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ConsoleCounters {
+    /// The number of bytes written to the console
+    pub transmitted: u64,
+    /// The number of bytes discarded by `ConsoleLimitPolicy::Drop`
+    pub dropped: u64,
+    /// The number of bytes delayed by `ConsoleLimitPolicy::Throttle`
+    pub throttled: u64,
+    /// The total time vCPUs were delayed by `ConsoleLimitPolicy::Throttle`
+    pub throttle_time: Duration,
+}
+
+/// The rate limiting state of the console output of a VM
+pub struct ConsoleLimiter {
+    limit: Option<ConsoleRateLimit>,
+    bucket: Option<TokenBucket>,
+    counters: ConsoleCounters,
+}
+
+/// The decision for a single byte of console output
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConsoleAction {
+    /// Write the byte now
+    Transmit,
+    /// Discard the byte
+    Drop,
+    /// Wait for the given time, then write the byte
+    Delay(Duration),
+}
+
+impl ConsoleLimiter {
+    pub fn new(limit: Option<ConsoleRateLimit>) -> Self {
+        Self {
+            limit,
+            bucket: None,
+            counters: ConsoleCounters::default(),
+        }
+    }
+
+    pub fn limit(&self) -> Option<ConsoleRateLimit> {
+        self.limit
+    }
+
+    pub fn counters(&self) -> ConsoleCounters {
+        self.counters
+    }
+
+    /// Decide what to do with a byte the guest transmits at `now`
+    ///
+    /// A delayed byte is accounted for as if it had been transmitted at
+    /// the end of the delay, so the caller must not retry it.
+    pub fn transmit(&mut self, now: Duration) -> ConsoleAction {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => {
+                self.counters.transmitted += 1;
+                return ConsoleAction::Transmit;
+            }
+        };
+        let bucket = self.bucket.get_or_insert_with(|| {
+            TokenBucket::new(limit.bytes_per_second, limit.burst, now)
+        });
+        if bucket.try_take(now) {
+            self.counters.transmitted += 1;
+            return ConsoleAction::Transmit;
+        }
+
+        match limit.policy {
+            ConsoleLimitPolicy::Drop => {
+                self.counters.dropped += 1;
+                ConsoleAction::Drop
+            }
+            ConsoleLimitPolicy::Throttle => {
+                let wait = bucket.wait_time(now);
+                bucket.try_take(now + wait);
+                self.counters.transmitted += 1;
+                self.counters.throttled += 1;
+                self.counters.throttle_time += wait;
+                ConsoleAction::Delay(wait)
+            }
+        }
+    }
+}
+
+/// A limit on the rate of events of some kind
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    /// The sustained rate of events
+    pub per_second: u64,
+    /// The number of events that may happen at once
+    pub burst: u64,
+}
+
+/// The rate limits on the emulation work done for a VM
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EmulationRateLimits {
+    /// Port and MMIO accesses emulated by the hypervisor
+    pub io: Option<RateLimit>,
+    /// Interrupts injected in to the guest
+    pub interrupts: Option<RateLimit>,
+}
+
+/// The counters of a `Throttle`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ThrottleCounters {
+    /// The number of events
+    pub events: u64,
+    /// The number of events beyond the limit, which delayed the vCPU
+    pub throttled: u64,
+    /// The total time vCPUs were delayed
+    pub throttle_time: Duration,
+}
+
+/// Delays events beyond an (optional) rate limit
+pub struct Throttle {
+    limit: Option<RateLimit>,
+    bucket: Option<TokenBucket>,
+    counters: ThrottleCounters,
+}
+
+impl Throttle {
+    pub fn new(limit: Option<RateLimit>) -> Self {
+        Self {
+            limit,
+            bucket: None,
+            counters: ThrottleCounters::default(),
+        }
+    }
+
+    pub fn counters(&self) -> ThrottleCounters {
+        self.counters
+    }
+
+    /// Account for an event at `now`, returning the time the vCPU must
+    /// wait before handling it (zero if it is within the limit)
+    ///
+    /// As with `ConsoleLimitPolicy::Throttle`, a delayed event is accounted
+    /// for as if it happened at the end of the delay.
+    pub fn admit(&mut self, now: Duration) -> Duration {
+        self.counters.events += 1;
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return Duration::from_secs(0),
+        };
+        let bucket = self.bucket.get_or_insert_with(|| {
+            TokenBucket::new(limit.per_second, limit.burst, now)
+        });
+        if bucket.try_take(now) {
+            return Duration::from_secs(0);
+        }
+        let wait = bucket.wait_time(now);
+        bucket.try_take(now + wait);
+        self.counters.throttled += 1;
+        self.counters.throttle_time += wait;
+        wait
+    }
+}
+
+/// The throttles on the emulation work done for a VM
+pub struct EmulationLimiter {
+    pub io: Throttle,
+    pub interrupts: Throttle,
+}
+
+impl EmulationLimiter {
+    pub fn new(limits: EmulationRateLimits) -> Self {
+        Self {
+            io: Throttle::new(limits.io),
+            interrupts: Throttle::new(limits.interrupts),
+        }
+    }
+}
+
+/// The rate limit applied to the log messages of a VM
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GuestLogRateLimit {
+    /// The sustained rate of messages
+    pub messages_per_second: u64,
+    /// The number of messages that may be logged at once
+    pub burst: u64,
+}
+
+impl Default for GuestLogRateLimit {
+    fn default() -> Self {
+        Self {
+            messages_per_second: 10,
+            burst: 20,
+        }
+    }
+}
+
+/// The rate limiting state of the log messages of a VM
+pub struct GuestLogLimiter {
+    limit: GuestLogRateLimit,
+    bucket: Option<TokenBucket>,
+    suppressed: u64,
+}
+
+impl GuestLogLimiter {
+    pub fn new(limit: GuestLogRateLimit) -> Self {
+        Self {
+            limit,
+            bucket: None,
+            suppressed: 0,
+        }
+    }
+
+    /// Decide whether a message logged at `now` is within the limit
+    ///
+    /// Returns the number of messages suppressed since the last message
+    /// that was within the limit, or `None` if this message is suppressed.
+    pub fn admit(&mut self, now: Duration) -> Option<u64> {
+        let limit = self.limit;
+        let bucket = self.bucket.get_or_insert_with(|| {
+            TokenBucket::new(limit.messages_per_second, limit.burst, now)
+        });
+        if bucket.try_take(now) {
+            Some(core::mem::replace(&mut self.suppressed, 0))
+        } else {
+            self.suppressed += 1;
+            None
+        }
+    }
+}
+
+/// The `uartstats` management console command
+pub fn uartstats_command(
+    out: &mut dyn fmt::Write,
+    args: &[&str],
+) -> fmt::Result {
+    let vm_id = match args.get(0).and_then(|vm| vm::find_vm(vm)) {
+        Some(vm_id) => vm_id,
+        None => return writeln!(out, "usage: uartstats <vm>"),
+    };
+    let vm = match unsafe { vm::get_vm(vm_id) } {
+        Some(vm) => vm,
+        None => return writeln!(out, "No VM {}", vm_id),
+    };
+    let vm = vm.read();
+    let limiter = vm.console.lock();
+    let counters = limiter.counters();
+    match limiter.limit() {
+        Some(limit) => writeln!(
+            out,
+            "limit: {} bytes/s (burst {}, {:?})",
+            limit.bytes_per_second, limit.burst, limit.policy
+        )?,
+        None => writeln!(out, "limit: none")?,
+    }
+    writeln!(
+        out,
+        "transmitted: {} dropped: {} throttled: {} ({}ms)",
+        counters.transmitted,
+        counters.dropped,
+        counters.throttled,
+        counters.throttle_time.as_millis()
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(10, 2, Duration::from_secs(1));
+        assert!(bucket.try_take(Duration::from_secs(1)));
+        assert!(bucket.try_take(Duration::from_secs(1)));
+        assert!(!bucket.try_take(Duration::from_secs(1)));
+        assert_eq!(
+            bucket.wait_time(Duration::from_secs(1)),
+            Duration::from_millis(100)
+        );
+        assert!(!bucket.try_take(Duration::from_millis(1050)));
+        assert!(bucket.try_take(Duration::from_millis(1100)));
+
+        // The bucket never holds more than its capacity
+        assert!(bucket.try_take(Duration::from_secs(10)));
+        assert!(bucket.try_take(Duration::from_secs(10)));
+        assert!(!bucket.try_take(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_console_limiter() {
+        let now = Duration::from_secs(5);
+        let mut unlimited = ConsoleLimiter::new(None);
+        assert_eq!(unlimited.transmit(now), ConsoleAction::Transmit);
+
+        let mut limit = ConsoleRateLimit {
+            bytes_per_second: 1000,
+            burst: 1,
+            policy: ConsoleLimitPolicy::Drop,
+        };
+        let mut dropping = ConsoleLimiter::new(Some(limit));
+        assert_eq!(dropping.transmit(now), ConsoleAction::Transmit);
+        assert_eq!(dropping.transmit(now), ConsoleAction::Drop);
+        assert_eq!(dropping.counters().dropped, 1);
+
+        limit.policy = ConsoleLimitPolicy::Throttle;
+        let mut throttling = ConsoleLimiter::new(Some(limit));
+        assert_eq!(throttling.transmit(now), ConsoleAction::Transmit);
+        assert_eq!(
+            throttling.transmit(now),
+            ConsoleAction::Delay(Duration::from_millis(1))
+        );
+        assert_eq!(
+            throttling.transmit(now),
+            ConsoleAction::Delay(Duration::from_millis(2))
+        );
+        let counters = throttling.counters();
+        assert_eq!((counters.transmitted, counters.throttled), (3, 2));
+        assert_eq!(counters.throttle_time, Duration::from_millis(3));
+    }
+
+    #[test]
+    fn test_throttle() {
+        let now = Duration::from_secs(2);
+        let mut unlimited = Throttle::new(None);
+        assert_eq!(unlimited.admit(now), Duration::from_secs(0));
+
+        let mut throttle = Throttle::new(Some(RateLimit {
+            per_second: 100,
+            burst: 2,
+        }));
+        assert_eq!(throttle.admit(now), Duration::from_secs(0));
+        assert_eq!(throttle.admit(now), Duration::from_secs(0));
+        assert_eq!(throttle.admit(now), Duration::from_millis(10));
+        assert_eq!(throttle.admit(now), Duration::from_millis(20));
+        assert_eq!(
+            throttle.admit(now + Duration::from_millis(50)),
+            Duration::from_secs(0)
+        );
+        let counters = throttle.counters();
+        assert_eq!((counters.events, counters.throttled), (5, 2));
+        assert_eq!(counters.throttle_time, Duration::from_millis(30));
+    }
+
+    #[test]
+    fn test_guest_log_limiter() {
+        let now = Duration::from_secs(1);
+        let mut limiter = GuestLogLimiter::new(GuestLogRateLimit {
+            messages_per_second: 10,
+            burst: 2,
+        });
+        assert_eq!(limiter.admit(now), Some(0));
+        assert_eq!(limiter.admit(now), Some(0));
+        assert_eq!(limiter.admit(now), None);
+        assert_eq!(limiter.admit(now), None);
+        assert_eq!(limiter.admit(now + Duration::from_millis(100)), Some(2));
+    }
+}
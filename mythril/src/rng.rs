@@ -0,0 +1,605 @@
+//! Random numbers for the hypervisor
+//!
+//! Random bytes are produced by an HMAC-DRBG (with SHA-256, see NIST SP
+//! 800-90A § 10.1.2) that is seeded from the registered `EntropySource`s:
+//!
+//!  - RDSEED, when the processor supports it
+//!  - The jitter in the time (in TSC ticks) taken by a memory access loop
+//!  - The arrival time of host interrupts (see `add_interrupt_timing`)
+//!
+//! The samples of each source are checked with the repetition count and
+//! adaptive proportion health tests of NIST SP 800-90B § 4.4, and a source
+//! that fails either test is no longer used. The DRBG is reseeded after
+//! every `RESEED_INTERVAL` requests.
+//!
+//! The generator is used for random (version 4) UUIDs, the nonces of sealed
+//! streams (see `seal`), the virtio entropy device of the guest, the guest
+//! RDRAND and RDSEED instructions that seed its KASLR (see
+//! `emulate::random`), the initial sequence numbers of the vsock
+//! management transport and TPM2_GetRandom of the virtual TPM. Consumers that need many random bytes (like the
+//! entropy device) should instantiate their own `HmacDrbg` seeded from
+//! `fill_bytes`, rather than contending for the global generator.
+
+use crate::error::{Error, Result};
+use crate::measure::{Digest, Sha256, DIGEST_SIZE};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+/// The entropy (in bits) needed to seed the DRBG
+pub const SEED_BITS: u32 = 256;
+
+/// The number of requests served before the DRBG is reseeded
+pub const RESEED_INTERVAL: u64 = 1 << 16;
+
+/// The most bytes produced by a single request to the DRBG
+pub const MAX_REQUEST_SIZE: usize = 1 << 16;
+
+// The most samples taken to gather `SEED_BITS`
+const MAX_SEED_SAMPLES: usize = 4096;
+
+// The health test cutoffs for an assumed min-entropy of one bit per
+// sample (see SP 800-90B § 4.4.1 and § 4.4.2)
+const REPETITION_CUTOFF: u32 = 21;
+const PROPORTION_WINDOW: u32 = 512;
+const PROPORTION_CUTOFF: u32 = 410;
+
+const RDSEED_RETRIES: usize = 10;
+const JITTER_ROUNDS: usize = 32;
+const JITTER_BUFFER_SIZE: usize = 4096;
+const CACHE_LINE_SIZE: usize = 64;
+
+const HMAC_BLOCK_SIZE: usize = 64;
+
+/// HMAC-SHA256 (see RFC 2104) of the concatenation of `parts`
+pub fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> Digest {
+    let mut block = [0u8; HMAC_BLOCK_SIZE];
+    if key.len() > HMAC_BLOCK_SIZE {
+        let mut hash = Sha256::new();
+        hash.update(key);
+        block[..DIGEST_SIZE].copy_from_slice(&hash.finish());
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut ipad = [0x36u8; HMAC_BLOCK_SIZE];
+    let mut opad = [0x5cu8; HMAC_BLOCK_SIZE];
+    for ((i, o), b) in ipad.iter_mut().zip(opad.iter_mut()).zip(block.iter()) {
+        *i ^= b;
+        *o ^= b;
+    }
+
+    let mut inner = Sha256::new();
+    inner.update(&ipad);
+    for part in parts {
+        inner.update(part);
+    }
+    let mut outer = Sha256::new();
+    outer.update(&opad);
+    outer.update(&inner.finish());
+    outer.finish()
+}
+
+/// A deterministic random bit generator (HMAC-DRBG with SHA-256)
+pub struct HmacDrbg {
+    key: Digest,
+    value: Digest,
+    reseed_counter: u64,
+}
+
+impl HmacDrbg {
+    /// Instantiate a DRBG from `seed` (the entropy input and nonce)
+    pub fn new(seed: &[u8]) -> Self {
+        let mut drbg = Self {
+            key: [0x00; DIGEST_SIZE],
+            value: [0x01; DIGEST_SIZE],
+            reseed_counter: 1,
+        };
+        drbg.update(seed);
+        drbg
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.key = hmac_sha256(&self.key, &[&self.value, &[0x00], data]);
+        self.value = hmac_sha256(&self.key, &[&self.value]);
+        if !data.is_empty() {
+            self.key = hmac_sha256(&self.key, &[&self.value, &[0x01], data]);
+            self.value = hmac_sha256(&self.key, &[&self.value]);
+        }
+    }
+
+    /// Mix `seed` into the state of the DRBG
+    pub fn reseed(&mut self, seed: &[u8]) {
+        self.update(seed);
+        self.reseed_counter = 1;
+    }
+
+    /// The number of requests served since the DRBG was last (re)seeded
+    pub fn requests(&self) -> u64 {
+        self.reseed_counter - 1
+    }
+
+    /// Fill `out` with random bytes, returning an error if `out` is longer
+    /// than `MAX_REQUEST_SIZE`
+    pub fn generate(&mut self, out: &mut [u8]) -> Result<()> {
+        if out.len() > MAX_REQUEST_SIZE {
+            return Err(Error::InvalidValue(format!(
+                "Invalid DRBG request size: {}",
+                out.len()
+            )));
+        }
+        for chunk in out.chunks_mut(DIGEST_SIZE) {
+            self.value = hmac_sha256(&self.key, &[&self.value]);
+            chunk.copy_from_slice(&self.value[..chunk.len()]);
+        }
+        self.update(&[]);
+        self.reseed_counter += 1;
+        Ok(())
+    }
+}
+
+/// A source of entropy for seeding the DRBG
+pub trait EntropySource: Send {
+    /// The name of the source (e.g., for the `rng` console command)
+    fn name(&self) -> &'static str;
+
+    /// The (conservatively estimated) entropy of each sample, in bits
+    fn entropy_per_sample(&self) -> u32;
+
+    /// Take a sample, or None if the source has nothing to offer now
+    fn sample(&mut self) -> Option<u64>;
+}
+
+/// Entropy from the RDSEED instruction
+pub struct RdseedSource;
+
+impl RdseedSource {
+    /// Returns None if the processor does not support RDSEED
+    pub fn new() -> Option<Self> {
+        let features = unsafe { core::arch::x86_64::__cpuid_count(7, 0) };
+        if features.ebx & (1 << 18) != 0 {
+            Some(RdseedSource)
+        } else {
+            None
+        }
+    }
+}
+
+#[target_feature(enable = "rdseed")]
+unsafe fn rdseed() -> Option<u64> {
+    let mut value = 0;
+    for _ in 0..RDSEED_RETRIES {
+        if core::arch::x86_64::_rdseed64_step(&mut value) == 1 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+impl EntropySource for RdseedSource {
+    fn name(&self) -> &'static str {
+        "rdseed"
+    }
+
+    fn entropy_per_sample(&self) -> u32 {
+        64
+    }
+
+    fn sample(&mut self) -> Option<u64> {
+        unsafe { rdseed() }
+    }
+}
+
+/// Entropy from the jitter in the time taken by memory accesses
+pub struct JitterSource {
+    buffer: Vec<u8>,
+}
+
+impl JitterSource {
+    pub fn new() -> Self {
+        Self {
+            buffer: vec![0; JITTER_BUFFER_SIZE],
+        }
+    }
+}
+
+impl EntropySource for JitterSource {
+    fn name(&self) -> &'static str {
+        "jitter"
+    }
+
+    fn entropy_per_sample(&self) -> u32 {
+        1
+    }
+
+    fn sample(&mut self) -> Option<u64> {
+        let mut sample = 0u64;
+        for _ in 0..JITTER_ROUNDS {
+            let start = unsafe { x86::time::rdtsc() };
+            for byte in self.buffer.iter_mut().step_by(CACHE_LINE_SIZE) {
+                unsafe {
+                    let val = core::ptr::read_volatile(byte);
+                    core::ptr::write_volatile(byte, val ^ start as u8);
+                }
+            }
+            let delta = unsafe { x86::time::rdtsc() }.wrapping_sub(start);
+            sample = sample.rotate_left(7) ^ delta;
+        }
+        Some(sample)
+    }
+}
+
+// The interrupt timings not yet sampled, and the number of interrupts
+static INTERRUPT_POOL: AtomicU64 = AtomicU64::new(0);
+static INTERRUPT_EVENTS: AtomicU64 = AtomicU64::new(0);
+
+/// Record the arrival of a host interrupt with `vector`
+pub fn add_interrupt_timing(vector: u8) {
+    let tsc = unsafe { x86::time::rdtsc() };
+    let pool = INTERRUPT_POOL.load(Ordering::Relaxed);
+    INTERRUPT_POOL.store(
+        pool.rotate_left(13) ^ tsc ^ ((vector as u64) << 56),
+        Ordering::Relaxed,
+    );
+    INTERRUPT_EVENTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Entropy from the arrival time of host interrupts
+pub struct InterruptSource {
+    events: u64,
+}
+
+impl InterruptSource {
+    pub fn new() -> Self {
+        Self { events: 0 }
+    }
+}
+
+impl EntropySource for InterruptSource {
+    fn name(&self) -> &'static str {
+        "interrupts"
+    }
+
+    fn entropy_per_sample(&self) -> u32 {
+        1
+    }
+
+    fn sample(&mut self) -> Option<u64> {
+        // Only offer a sample once more interrupts have arrived
+        let events = INTERRUPT_EVENTS.load(Ordering::Relaxed);
+        if events == self.events {
+            return None;
+        }
+        self.events = events;
+        Some(INTERRUPT_POOL.load(Ordering::Relaxed))
+    }
+}
+
+/// The continuous health tests of a noise source (see SP 800-90B § 4.4),
+/// applied to the low byte of each sample
+#[derive(Debug, Default)]
+pub struct HealthTests {
+    last: Option<u8>,
+    repetitions: u32,
+    window_symbol: u8,
+    window_samples: u32,
+    window_matches: u32,
+    failed: bool,
+}
+
+impl HealthTests {
+    /// Check `sample`, returning false if the source has failed
+    pub fn check(&mut self, sample: u64) -> bool {
+        let symbol = sample as u8;
+
+        // Repetition count test
+        if self.last == Some(symbol) {
+            self.repetitions += 1;
+            if self.repetitions >= REPETITION_CUTOFF {
+                self.failed = true;
+            }
+        } else {
+            self.last = Some(symbol);
+            self.repetitions = 1;
+        }
+
+        // Adaptive proportion test
+        if self.window_samples == 0 {
+            self.window_symbol = symbol;
+            self.window_matches = 0;
+        }
+        if symbol == self.window_symbol {
+            self.window_matches += 1;
+            if self.window_matches >= PROPORTION_CUTOFF {
+                self.failed = true;
+            }
+        }
+        self.window_samples = (self.window_samples + 1) % PROPORTION_WINDOW;
+
+        !self.failed
+    }
+
+    pub fn failed(&self) -> bool {
+        self.failed
+    }
+}
+
+struct Source {
+    source: Box<dyn EntropySource>,
+    health: HealthTests,
+    samples: u64,
+}
+
+/// A DRBG that reseeds itself from a set of entropy sources
+pub struct Rng {
+    sources: Vec<Source>,
+    drbg: Option<HmacDrbg>,
+    reseeds: u64,
+}
+
+impl Rng {
+    pub fn new() -> Self {
+        Self {
+            sources: vec![],
+            drbg: None,
+            reseeds: 0,
+        }
+    }
+
+    /// Add `source` to those used to seed the DRBG
+    pub fn add_source(&mut self, source: Box<dyn EntropySource>) {
+        self.sources.push(Source {
+            source,
+            health: HealthTests::default(),
+            samples: 0,
+        });
+    }
+
+    /// Gather `SEED_BITS` of entropy from the healthy sources
+    fn gather_seed(&mut self) -> Result<Digest> {
+        let mut pool = Sha256::new();
+        let mut bits = 0;
+        let mut samples = 0;
+        while bits < SEED_BITS && samples < MAX_SEED_SAMPLES {
+            let mut sampled = false;
+            for source in self.sources.iter_mut() {
+                if source.health.failed() {
+                    continue;
+                }
+                let sample = match source.source.sample() {
+                    Some(sample) => sample,
+                    None => continue,
+                };
+                sampled = true;
+                source.samples += 1;
+                if !source.health.check(sample) {
+                    warn!(
+                        "Entropy source '{}' failed its health tests",
+                        source.source.name()
+                    );
+                    continue;
+                }
+                pool.update(&sample.to_le_bytes());
+                bits += source.source.entropy_per_sample();
+            }
+            if !sampled {
+                break;
+            }
+            samples += 1;
+        }
+
+        if bits < SEED_BITS {
+            return Err(Error::DeviceError(format!(
+                "Insufficient entropy: {} of {} bits",
+                bits, SEED_BITS
+            )));
+        }
+        Ok(pool.finish())
+    }
+
+    /// Reseed (or instantiate) the DRBG from the entropy sources
+    pub fn reseed(&mut self) -> Result<()> {
+        let seed = self.gather_seed()?;
+        match self.drbg.as_mut() {
+            Some(drbg) => drbg.reseed(&seed),
+            None => self.drbg = Some(HmacDrbg::new(&seed)),
+        }
+        self.reseeds += 1;
+        Ok(())
+    }
+
+    /// Fill `out` with random bytes
+    pub fn fill_bytes(&mut self, out: &mut [u8]) -> Result<()> {
+        for chunk in out.chunks_mut(MAX_REQUEST_SIZE) {
+            let reseed = match self.drbg.as_ref() {
+                Some(drbg) => drbg.requests() >= RESEED_INTERVAL,
+                None => true,
+            };
+            if reseed {
+                self.reseed()?;
+            }
+            match self.drbg.as_mut() {
+                Some(drbg) => drbg.generate(chunk)?,
+                None => unreachable!(),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Rng {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Reseeds: {}", self.reseeds)?;
+        for source in self.sources.iter() {
+            writeln!(
+                f,
+                "{:<12} samples: {:<10} {}",
+                source.source.name(),
+                source.samples,
+                if source.health.failed() {
+                    "failed"
+                } else {
+                    "healthy"
+                }
+            )?;
+        }
+        Ok(())
+    }
+}
+
+static RNG: Mutex<Option<Rng>> = Mutex::new(None);
+
+/// Initialize the global generator with the sources available on this
+/// processor, and seed it
+pub fn init() -> Result<()> {
+    let mut rng = Rng::new();
+    match RdseedSource::new() {
+        Some(source) => rng.add_source(Box::new(source)),
+        None => info!("RDSEED is not supported, using timing entropy only"),
+    }
+    rng.add_source(Box::new(JitterSource::new()));
+    rng.add_source(Box::new(InterruptSource::new()));
+    rng.reseed()?;
+    *RNG.lock() = Some(rng);
+    Ok(())
+}
+
+/// Fill `out` with random bytes from the global generator
+pub fn fill_bytes(out: &mut [u8]) -> Result<()> {
+    match RNG.lock().as_mut() {
+        Some(rng) => rng.fill_bytes(out),
+        None => Err(Error::InvalidValue(
+            "The random number generator is not initialized".into(),
+        )),
+    }
+}
+
+/// A random u64 from the global generator
+pub fn random_u64() -> Result<u64> {
+    let mut bytes = [0u8; 8];
+    fill_bytes(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// The `rng` management console command
+pub fn rng_command(out: &mut dyn fmt::Write, _args: &[&str]) -> fmt::Result {
+    match RNG.lock().as_ref() {
+        Some(rng) => write!(out, "{}", rng),
+        None => writeln!(out, "The random number generator is not initialized"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct CountingSource(u64);
+
+    impl EntropySource for CountingSource {
+        fn name(&self) -> &'static str {
+            "counting"
+        }
+
+        fn entropy_per_sample(&self) -> u32 {
+            8
+        }
+
+        fn sample(&mut self) -> Option<u64> {
+            self.0 += 1;
+            Some(self.0)
+        }
+    }
+
+    struct StuckSource;
+
+    impl EntropySource for StuckSource {
+        fn name(&self) -> &'static str {
+            "stuck"
+        }
+
+        fn entropy_per_sample(&self) -> u32 {
+            1
+        }
+
+        fn sample(&mut self) -> Option<u64> {
+            Some(0x42)
+        }
+    }
+
+    #[test]
+    fn test_hmac_sha256() {
+        // RFC 4231 test case 2
+        let mac = hmac_sha256(b"Jefe", &[b"what do ya want ", b"for nothing?"]);
+        assert_eq!(&mac[..4], &[0x5b, 0xdc, 0xc1, 0x46]);
+        assert_eq!(&mac[28..], &[0x64, 0xec, 0x38, 0x43]);
+    }
+
+    #[test]
+    fn test_hmac_drbg() {
+        let mut a = HmacDrbg::new(b"seed");
+        let mut b = HmacDrbg::new(b"seed");
+        let mut out_a = [0u8; 40];
+        let mut out_b = [0u8; 40];
+        a.generate(&mut out_a).unwrap();
+        b.generate(&mut out_b).unwrap();
+        assert_eq!(out_a, out_b);
+        assert_eq!(a.requests(), 1);
+
+        // Successive requests and reseeding change the output
+        a.generate(&mut out_a).unwrap();
+        assert_ne!(out_a, out_b);
+        b.reseed(b"more");
+        assert_eq!(b.requests(), 0);
+        b.generate(&mut out_b).unwrap();
+        assert_ne!(out_a, out_b);
+
+        let mut large = vec![0u8; MAX_REQUEST_SIZE + 1];
+        assert!(a.generate(&mut large).is_err());
+    }
+
+    #[test]
+    fn test_health_tests() {
+        let mut health = HealthTests::default();
+        for _ in 0..REPETITION_CUTOFF - 1 {
+            assert!(health.check(7));
+        }
+        assert!(!health.check(7));
+        assert!(!health.check(8));
+
+        // A symbol that is too common within a window
+        let mut health = HealthTests::default();
+        let mut passed = 0;
+        for i in 0..PROPORTION_WINDOW {
+            let sample = if i % 10 == 9 { i as u64 } else { 1 };
+            if !health.check(sample) {
+                break;
+            }
+            passed += 1;
+        }
+        assert!(passed < PROPORTION_WINDOW);
+        assert!(health.failed());
+    }
+
+    #[test]
+    fn test_rng_sources() {
+        let mut rng = Rng::new();
+        assert!(rng.reseed().is_err());
+
+        // The stuck source is dropped, and the DRBG is seeded from the
+        // counting source
+        rng.add_source(Box::new(StuckSource));
+        rng.add_source(Box::new(CountingSource(0)));
+        let mut out = [0u8; 16];
+        rng.fill_bytes(&mut out).unwrap();
+        assert_ne!(out, [0; 16]);
+        assert!(rng.sources[0].health.failed());
+        assert_eq!(rng.reseeds, 1);
+        assert_eq!(rng.sources[0].samples, REPETITION_CUTOFF as u64);
+
+        let mut large = vec![0u8; 2 * MAX_REQUEST_SIZE + 1];
+        rng.fill_bytes(&mut large).unwrap();
+        assert_eq!(rng.drbg.as_ref().unwrap().requests(), 4);
+    }
+}
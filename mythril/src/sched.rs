@@ -0,0 +1,809 @@
+//! Scheduling policies for the vCPUs sharing a core
+//!
+//! The cores of the host are divided into pools (see `add_pool`), each
+//! with a `PolicyKind`. Every core has an instance of its pool's
+//! `SchedulerPolicy`, which the vCPUs placed on the core are admitted to
+//! (with their VM's `SchedParams`), and which decides which vCPU runs
+//! next and for how long:
+//!
+//! * `StaticPartition` - each core runs a single vCPU until it exits. This
+//!   is the default for cores outside any pool.
+//! * `WeightedRoundRobin` - the vCPUs take turns, each running for the
+//!   pool's quantum times its weight (for batch guests).
+//! * `Deadline` - each vCPU is guaranteed a budget of time every period,
+//!   and the vCPU with the earliest deadline runs first (for real-time
+//!   guests). vCPUs are only admitted while the budgets of the core add up
+//!   to at most the whole core.
+//!
+//! The pools, and the parameters of each VM, are read from the `sched`
+//! boot module (see `SchedConfig`).
+//!
+//! Currently every vCPU owns its core, so a policy only ever has one vCPU
+//! to choose. It still decides how long that vCPU may run: after each exit
+//! handled in full, the vCPU is charged for its time and asks the policy
+//! for its next slice (see `run_next`), so, e.g., a `Deadline` vCPU is held
+//! to its budget.
+//!
+//! Independently of the policies, the SMT siblings of a physical core can
+//! be reserved for a single VM (`SmtPolicy::Isolated`), globally or per
+//! VM, to close cross-VM side channels through the shared core. A VM with
+//! isolated siblings is never placed on a core whose sibling runs another
+//! VM; the sibling must run the same VM or be left idle.
+
+use crate::error::{Error, Result};
+use crate::percore::CoreId;
+use crate::time;
+use crate::{declare_per_core, get_per_core_mut};
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+
+/// The default quantum of a `WeightedRoundRobin` pool
+pub const DEFAULT_QUANTUM: Duration = Duration::from_millis(10);
+
+/// A vCPU, as seen by the scheduler
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SchedEntity {
+    /// The id of the VM
+    pub vm_id: u32,
+    /// The core the vCPU was created for (which is also its APIC id)
+    pub vcpu: CoreId,
+}
+
+/// The scheduling parameters of the vCPUs of a VM
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SchedParams {
+    /// The relative share of a `WeightedRoundRobin` core (at least 1)
+    Weight(u32),
+
+    /// A guaranteed `budget` of time every `period` on a `Deadline` core
+    Deadline { period: Duration, budget: Duration },
+}
+
+impl Default for SchedParams {
+    fn default() -> Self {
+        SchedParams::Weight(1)
+    }
+}
+
+/// A decision of a `SchedulerPolicy`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Decision {
+    /// The vCPU to run
+    pub entity: SchedEntity,
+    /// How long the vCPU may run before the policy is consulted again
+    /// (`None` if it may run until it blocks)
+    pub slice: Option<Duration>,
+}
+
+/// A policy for choosing which of the vCPUs on a core runs next
+pub trait SchedulerPolicy: Send {
+    /// The kind of the policy
+    fn kind(&self) -> PolicyKind;
+
+    /// Add a vCPU to the core
+    fn admit(&mut self, entity: SchedEntity, params: SchedParams)
+        -> Result<()>;
+
+    /// Remove a vCPU from the core
+    fn remove(&mut self, entity: SchedEntity);
+
+    /// The vCPU to run at `now` (or `None` if the core should idle)
+    fn pick_next(&mut self, now: Duration) -> Option<Decision>;
+
+    /// Charge `entity` for running for `ran`
+    fn account(&mut self, entity: SchedEntity, ran: Duration);
+}
+
+/// The policies that can be selected for a pool of cores
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PolicyKind {
+    StaticPartition,
+    WeightedRoundRobin { quantum: Duration },
+    Deadline,
+}
+
+impl PolicyKind {
+    /// Create an (empty) instance of the policy for a core
+    pub fn build(&self) -> Box<dyn SchedulerPolicy> {
+        match *self {
+            PolicyKind::StaticPartition => Box::new(StaticPartition::default()),
+            PolicyKind::WeightedRoundRobin { quantum } => {
+                Box::new(WeightedRoundRobin::new(quantum))
+            }
+            PolicyKind::Deadline => Box::new(Deadline::default()),
+        }
+    }
+
+    /// Parse a policy name ('static', 'rr[:<quantum in ms>]' or 'deadline')
+    pub fn parse(s: &str) -> Result<Self> {
+        let invalid =
+            || Error::InvalidValue(format!("Invalid scheduler policy '{}'", s));
+        let mut parts = s.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some("static"), None) => Ok(PolicyKind::StaticPartition),
+            (Some("deadline"), None) => Ok(PolicyKind::Deadline),
+            (Some("rr"), None) => Ok(PolicyKind::WeightedRoundRobin {
+                quantum: DEFAULT_QUANTUM,
+            }),
+            (Some("rr"), Some(ms)) => match ms.parse::<u64>() {
+                Ok(ms) if ms > 0 => Ok(PolicyKind::WeightedRoundRobin {
+                    quantum: Duration::from_millis(ms),
+                }),
+                _ => Err(invalid()),
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for PolicyKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyKind::StaticPartition => write!(f, "static"),
+            PolicyKind::WeightedRoundRobin { quantum } => {
+                write!(f, "rr:{}", quantum.as_millis())
+            }
+            PolicyKind::Deadline => write!(f, "deadline"),
+        }
+    }
+}
+
+/// Each core runs a single vCPU for as long as it wants
+#[derive(Default)]
+pub struct StaticPartition {
+    entity: Option<SchedEntity>,
+}
+
+impl SchedulerPolicy for StaticPartition {
+    fn kind(&self) -> PolicyKind {
+        PolicyKind::StaticPartition
+    }
+
+    fn admit(
+        &mut self,
+        entity: SchedEntity,
+        _params: SchedParams,
+    ) -> Result<()> {
+        match self.entity {
+            Some(current) if current != entity => {
+                Err(Error::InvalidValue(format!(
+                    "Core already runs vCPU {} of VM {}",
+                    current.vcpu, current.vm_id
+                )))
+            }
+            _ => {
+                self.entity = Some(entity);
+                Ok(())
+            }
+        }
+    }
+
+    fn remove(&mut self, entity: SchedEntity) {
+        if self.entity == Some(entity) {
+            self.entity = None;
+        }
+    }
+
+    fn pick_next(&mut self, _now: Duration) -> Option<Decision> {
+        self.entity.map(|entity| Decision {
+            entity,
+            slice: None,
+        })
+    }
+
+    fn account(&mut self, _entity: SchedEntity, _ran: Duration) {}
+}
+
+/// The vCPUs of a core take turns, in proportion to their weights
+pub struct WeightedRoundRobin {
+    quantum: Duration,
+    queue: VecDeque<(SchedEntity, u32)>,
+}
+
+impl WeightedRoundRobin {
+    pub fn new(quantum: Duration) -> Self {
+        WeightedRoundRobin {
+            quantum,
+            queue: VecDeque::new(),
+        }
+    }
+}
+
+impl SchedulerPolicy for WeightedRoundRobin {
+    fn kind(&self) -> PolicyKind {
+        PolicyKind::WeightedRoundRobin {
+            quantum: self.quantum,
+        }
+    }
+
+    fn admit(
+        &mut self,
+        entity: SchedEntity,
+        params: SchedParams,
+    ) -> Result<()> {
+        let weight = match params {
+            SchedParams::Weight(weight) if weight > 0 => weight,
+            params => {
+                return Err(Error::InvalidValue(format!(
+                    "Invalid round-robin parameters: {:?}",
+                    params
+                )))
+            }
+        };
+        self.remove(entity);
+        self.queue.push_back((entity, weight));
+        Ok(())
+    }
+
+    fn remove(&mut self, entity: SchedEntity) {
+        self.queue.retain(|(queued, _)| *queued != entity);
+    }
+
+    fn pick_next(&mut self, _now: Duration) -> Option<Decision> {
+        let (entity, weight) = self.queue.pop_front()?;
+        self.queue.push_back((entity, weight));
+        Some(Decision {
+            entity,
+            slice: Some(self.quantum * weight),
+        })
+    }
+
+    fn account(&mut self, _entity: SchedEntity, _ran: Duration) {}
+}
+
+struct Reservation {
+    entity: SchedEntity,
+    period: Duration,
+    budget: Duration,
+    deadline: Duration,
+    remaining: Duration,
+}
+
+/// Earliest deadline first, with each vCPU limited to its budget in each
+/// of its periods
+#[derive(Default)]
+pub struct Deadline {
+    reservations: Vec<Reservation>,
+}
+
+impl Deadline {
+    // The fraction of the core reserved by the admitted vCPUs (in parts
+    // per million)
+    fn utilization(&self) -> u128 {
+        self.reservations
+            .iter()
+            .map(|r| r.budget.as_nanos() * 1_000_000 / r.period.as_nanos())
+            .sum()
+    }
+}
+
+impl SchedulerPolicy for Deadline {
+    fn kind(&self) -> PolicyKind {
+        PolicyKind::Deadline
+    }
+
+    fn admit(
+        &mut self,
+        entity: SchedEntity,
+        params: SchedParams,
+    ) -> Result<()> {
+        let (period, budget) = match params {
+            SchedParams::Deadline { period, budget }
+                if budget > Duration::from_secs(0) && budget <= period =>
+            {
+                (period, budget)
+            }
+            params => {
+                return Err(Error::InvalidValue(format!(
+                    "Invalid deadline parameters: {:?}",
+                    params
+                )))
+            }
+        };
+        self.remove(entity);
+        let utilization = budget.as_nanos() * 1_000_000 / period.as_nanos();
+        if self.utilization() + utilization > 1_000_000 {
+            return Err(Error::InvalidValue(format!(
+                "Cannot reserve {:?} every {:?} for vCPU {} of VM {}: the \
+                 core is fully reserved",
+                budget, period, entity.vcpu, entity.vm_id
+            )));
+        }
+        // The first period starts when the vCPU is first considered
+        self.reservations.push(Reservation {
+            entity,
+            period,
+            budget,
+            deadline: Duration::from_secs(0),
+            remaining: Duration::from_secs(0),
+        });
+        Ok(())
+    }
+
+    fn remove(&mut self, entity: SchedEntity) {
+        self.reservations.retain(|r| r.entity != entity);
+    }
+
+    fn pick_next(&mut self, now: Duration) -> Option<Decision> {
+        for reservation in self.reservations.iter_mut() {
+            if reservation.deadline <= now {
+                reservation.deadline = now + reservation.period;
+                reservation.remaining = reservation.budget;
+            }
+        }
+        self.reservations
+            .iter()
+            .filter(|r| r.remaining > Duration::from_secs(0))
+            .min_by_key(|r| r.deadline)
+            .map(|r| Decision {
+                entity: r.entity,
+                slice: Some(r.remaining),
+            })
+    }
+
+    fn account(&mut self, entity: SchedEntity, ran: Duration) {
+        if let Some(reservation) =
+            self.reservations.iter_mut().find(|r| r.entity == entity)
+        {
+            reservation.remaining = reservation
+                .remaining
+                .checked_sub(ran)
+                .unwrap_or_else(|| Duration::from_secs(0));
+        }
+    }
+}
+
+/// A named set of cores sharing a scheduler policy
+#[derive(Clone, Debug)]
+pub struct CorePool {
+    pub name: String,
+    pub cores: Vec<CoreId>,
+    pub policy: PolicyKind,
+}
+
+static POOLS: Mutex<Vec<CorePool>> = Mutex::new(Vec::new());
+
+static SMT_ISOLATED: AtomicBool = AtomicBool::new(false);
+
+declare_per_core! {
+    static mut SCHEDULER: Option<Box<dyn SchedulerPolicy>> = None;
+}
+
+/// Create a pool of `cores` scheduled with `policy`
+///
+/// A core can only be in one pool, and pools must be created before the
+/// vCPUs on their cores are started.
+pub fn add_pool(
+    name: &str,
+    cores: Vec<CoreId>,
+    policy: PolicyKind,
+) -> Result<()> {
+    let mut pools = POOLS.lock();
+    if pools.iter().any(|pool| pool.name == name) {
+        return Err(Error::InvalidValue(format!(
+            "Core pool '{}' already exists",
+            name
+        )));
+    }
+    if let Some(core) = cores
+        .iter()
+        .find(|core| pools.iter().any(|pool| pool.cores.contains(core)))
+    {
+        return Err(Error::InvalidValue(format!(
+            "Core {} is already in a pool",
+            core
+        )));
+    }
+    pools.push(CorePool {
+        name: name.into(),
+        cores,
+        policy,
+    });
+    Ok(())
+}
+
+/// The policy of the pool containing `core`
+pub fn pool_policy(core: CoreId) -> PolicyKind {
+    POOLS
+        .lock()
+        .iter()
+        .find(|pool| pool.cores.contains(&core))
+        .map(|pool| pool.policy)
+        .unwrap_or(PolicyKind::StaticPartition)
+}
+
+/// Admit a vCPU to the scheduler of the current core (`core`), creating
+/// the scheduler from the core's pool policy if needed
+pub fn admit(
+    core: CoreId,
+    entity: SchedEntity,
+    params: SchedParams,
+) -> Result<()> {
+    get_per_core_mut!(SCHEDULER)
+        .get_or_insert_with(|| pool_policy(core).build())
+        .admit(entity, params)
+}
+
+/// Remove a vCPU from the scheduler of the current core (e.g., when it
+/// moves to another core)
+pub fn remove(entity: SchedEntity) {
+    if let Some(scheduler) = get_per_core_mut!(SCHEDULER).as_mut() {
+        scheduler.remove(entity);
+    }
+}
+
+// Charge `entity` for running for `ran`, and return the decision of
+// `policy` at `now` (or `None` if the core should idle)
+fn charge(
+    policy: &mut dyn SchedulerPolicy,
+    entity: SchedEntity,
+    ran: Duration,
+    now: Duration,
+) -> Option<Decision> {
+    policy.account(entity, ran);
+    policy.pick_next(now)
+}
+
+/// Charge `entity` (running on the current core) for running for `ran`,
+/// and return how long it may run before the policy must be consulted
+/// again (`None` if it may run until it exits)
+///
+/// If the policy of the core holds the vCPU back (e.g., because it has
+/// used its `Deadline` budget for this period), this waits until the
+/// policy runs it again.
+pub fn run_next(entity: SchedEntity, ran: Duration) -> Option<Duration> {
+    let scheduler = match get_per_core_mut!(SCHEDULER).as_mut() {
+        Some(scheduler) => scheduler,
+        None => return None,
+    };
+    let mut decision = charge(scheduler.as_mut(), entity, ran, time::uptime());
+    loop {
+        // Each vCPU owns its core, so any decision is to run `entity`
+        if let Some(decision) = decision {
+            return decision.slice;
+        }
+        core::sync::atomic::spin_loop_hint();
+        decision = scheduler.pick_next(time::uptime());
+    }
+}
+
+/// The scheduler settings of the hypervisor (from the `sched` boot module)
+#[derive(Debug, Default)]
+pub struct SchedConfig {
+    pools: Vec<CorePool>,
+    params: BTreeMap<CoreId, SchedParams>,
+}
+
+impl SchedConfig {
+    /// Parse the settings, one per line:
+    ///
+    /// * `pool <name> <policy> <core>[,<core>...]` - create a pool with
+    ///   the given policy (see `PolicyKind::parse`)
+    /// * `weight <core> <weight>` - the weight of the VM on `core`
+    /// * `deadline <core> <period ms> <budget ms>` - the reservation of the
+    ///   VM on `core`
+    ///
+    /// Empty lines and lines starting with '#' are ignored.
+    pub fn parse(config: &str) -> Result<Self> {
+        let mut parsed = SchedConfig::default();
+        for line in config.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || {
+                Error::InvalidValue(format!(
+                    "Invalid scheduler setting '{}'",
+                    line
+                ))
+            };
+            let number = |s: &str| s.parse::<u64>().map_err(|_| invalid());
+            let core = |s: &str| number(s).map(|id| CoreId::from(id as u32));
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            match words[..] {
+                ["pool", name, policy, cores] => {
+                    let cores = cores
+                        .split(',')
+                        .map(core)
+                        .collect::<Result<Vec<_>>>()?;
+                    parsed.pools.push(CorePool {
+                        name: name.into(),
+                        cores: cores,
+                        policy: PolicyKind::parse(policy)?,
+                    });
+                }
+                ["weight", vm, weight] => {
+                    let weight = number(weight)? as u32;
+                    parsed
+                        .params
+                        .insert(core(vm)?, SchedParams::Weight(weight));
+                }
+                ["deadline", vm, period, budget] => {
+                    let params = SchedParams::Deadline {
+                        period: Duration::from_millis(number(period)?),
+                        budget: Duration::from_millis(number(budget)?),
+                    };
+                    parsed.params.insert(core(vm)?, params);
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(parsed)
+    }
+
+    /// Create the configured pools (see `add_pool`)
+    pub fn apply(&self) -> Result<()> {
+        for pool in self.pools.iter() {
+            add_pool(&pool.name, pool.cores.clone(), pool.policy)?;
+        }
+        Ok(())
+    }
+
+    /// The parameters of the VM whose first vCPU is on `core`
+    pub fn params(&self, core: CoreId) -> SchedParams {
+        self.params.get(&core).copied().unwrap_or_default()
+    }
+}
+
+/// Whether the SMT siblings of a core may run vCPUs of different VMs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SmtPolicy {
+    /// Siblings may run any VM
+    Shared,
+    /// Siblings only run vCPUs of the same VM (or are idle)
+    Isolated,
+}
+
+/// Set the SMT policy of the VMs that do not set their own (by default,
+/// `SmtPolicy::Shared`)
+pub fn set_default_smt_policy(policy: SmtPolicy) {
+    SMT_ISOLATED.store(policy == SmtPolicy::Isolated, Ordering::Relaxed);
+}
+
+pub fn default_smt_policy() -> SmtPolicy {
+    if SMT_ISOLATED.load(Ordering::Relaxed) {
+        SmtPolicy::Isolated
+    } else {
+        SmtPolicy::Shared
+    }
+}
+
+/// The number of low bits of an APIC id that select the SMT thread of a
+/// physical core (from the CPUID extended topology leaf)
+pub fn smt_shift() -> u32 {
+    use raw_cpuid::native_cpuid::cpuid_count;
+    if cpuid_count(0, 0).eax < 0xb {
+        return 0;
+    }
+    let smt = cpuid_count(0xb, 0);
+    // The first level must be the SMT level
+    if (smt.ecx >> 8) & 0xff == 1 {
+        smt.eax & 0x1f
+    } else {
+        0
+    }
+}
+
+/// Returns true if `a` and `b` are SMT siblings (given the `smt_shift`)
+pub fn smt_siblings(a: CoreId, b: CoreId, shift: u32) -> bool {
+    a != b && a.raw >> shift == b.raw >> shift
+}
+
+/// A VM placed on some cores, for checking `SmtPolicy` constraints
+pub struct Placement<'a> {
+    pub vm_id: u32,
+    pub cores: &'a [CoreId],
+    pub smt_policy: SmtPolicy,
+}
+
+/// Check that placing `new` alongside `placed` does not put vCPUs of
+/// different VMs on the siblings of a core, if either VM is isolated
+pub fn check_smt_placement(
+    new: &Placement,
+    placed: &[Placement],
+    shift: u32,
+) -> Result<()> {
+    for other in placed.iter().filter(|other| other.vm_id != new.vm_id) {
+        if new.smt_policy == SmtPolicy::Shared
+            && other.smt_policy == SmtPolicy::Shared
+        {
+            continue;
+        }
+        for core in new.cores.iter() {
+            if let Some(sibling) = other
+                .cores
+                .iter()
+                .find(|sibling| smt_siblings(*core, **sibling, shift))
+            {
+                return Err(Error::InvalidValue(format!(
+                    "Core {} of VM {} is an SMT sibling of core {} of VM {}, \
+                     and their siblings are isolated",
+                    core, new.vm_id, sibling, other.vm_id
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn sched_command(out: &mut dyn fmt::Write, _args: &[&str]) -> fmt::Result {
+    let smt = match default_smt_policy() {
+        SmtPolicy::Shared => "shared",
+        SmtPolicy::Isolated => "isolated",
+    };
+    writeln!(out, "SMT siblings: {} (by default)", smt)?;
+    let pools = POOLS.lock();
+    if pools.is_empty() {
+        return writeln!(out, "All cores use the static policy");
+    }
+    for pool in pools.iter() {
+        write!(out, "{}: {}, cores", pool.name, pool.policy)?;
+        for core in pool.cores.iter() {
+            write!(out, " {}", core.raw)?;
+        }
+        writeln!(out)?;
+    }
+    writeln!(out, "Other cores use the static policy")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entity(vcpu: u32) -> SchedEntity {
+        SchedEntity {
+            vm_id: 1,
+            vcpu: vcpu.into(),
+        }
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let mut policy = PolicyKind::parse("rr:5").unwrap().build();
+        assert_eq!(format!("{}", policy.kind()), "rr:5");
+        policy.admit(entity(1), SchedParams::Weight(1)).unwrap();
+        policy.admit(entity(2), SchedParams::Weight(3)).unwrap();
+        assert!(policy.admit(entity(3), SchedParams::Weight(0)).is_err());
+
+        let now = Duration::from_secs(0);
+        let picks = (0..3)
+            .map(|_| policy.pick_next(now).unwrap())
+            .map(|d| (d.entity.vcpu.raw, d.slice.unwrap().as_millis()))
+            .collect::<Vec<_>>();
+        assert_eq!(picks, [(1, 5), (2, 15), (1, 5)]);
+
+        // A static core runs exactly one vCPU
+        let mut policy = PolicyKind::StaticPartition.build();
+        policy.admit(entity(1), SchedParams::default()).unwrap();
+        assert!(policy.admit(entity(2), SchedParams::default()).is_err());
+        assert_eq!(policy.pick_next(now).unwrap().slice, None);
+    }
+
+    #[test]
+    fn test_deadline() {
+        let ms = Duration::from_millis;
+        let mut policy = PolicyKind::Deadline.build();
+        let rt = SchedParams::Deadline {
+            period: ms(10),
+            budget: ms(4),
+        };
+        let batch = SchedParams::Deadline {
+            period: ms(40),
+            budget: ms(20),
+        };
+        policy.admit(entity(1), rt).unwrap();
+        policy.admit(entity(2), batch).unwrap();
+
+        // The core is now 90% reserved
+        let full = SchedParams::Deadline {
+            period: ms(10),
+            budget: ms(2),
+        };
+        assert!(policy.admit(entity(3), full).is_err());
+
+        // The real-time vCPU runs first, for its budget
+        let decision = policy.pick_next(ms(0)).unwrap();
+        assert_eq!((decision.entity, decision.slice), (entity(1), Some(ms(4))));
+        policy.account(entity(1), ms(4));
+        let decision = policy.pick_next(ms(4)).unwrap();
+        assert_eq!(decision.entity, entity(2));
+        policy.account(entity(2), ms(6));
+
+        // A new period restores the budget (and the earlier deadline)
+        let decision = policy.pick_next(ms(10)).unwrap();
+        assert_eq!((decision.entity, decision.slice), (entity(1), Some(ms(4))));
+        policy.account(entity(1), ms(4));
+        let decision = policy.pick_next(ms(14)).unwrap();
+        assert_eq!(
+            (decision.entity, decision.slice),
+            (entity(2), Some(ms(14)))
+        );
+    }
+
+    #[test]
+    fn test_deadline_holds_back() {
+        let ms = Duration::from_millis;
+        let mut policy = PolicyKind::Deadline.build();
+        let params = SchedParams::Deadline {
+            period: ms(10),
+            budget: ms(4),
+        };
+        policy.admit(entity(1), params).unwrap();
+        assert_eq!(policy.pick_next(ms(0)).unwrap().slice, Some(ms(4)));
+
+        // Once the budget is used, the vCPU waits for its next period
+        let decision = charge(policy.as_mut(), entity(1), ms(3), ms(3));
+        assert_eq!(decision.unwrap().slice, Some(ms(1)));
+        assert!(charge(policy.as_mut(), entity(1), ms(1), ms(4)).is_none());
+        assert!(policy.pick_next(ms(9)).is_none());
+        assert_eq!(policy.pick_next(ms(10)).unwrap().slice, Some(ms(4)));
+    }
+
+    #[test]
+    fn test_sched_config() {
+        let config = SchedConfig::parse(
+            "# Real-time guests on cores 2 and 3\n\
+             pool rt deadline 2,3\n\
+             pool batch rr:20 4\n\
+             \n\
+             deadline 2 10 4\n\
+             weight 4 3\n",
+        )
+        .unwrap();
+        assert_eq!(config.pools.len(), 2);
+        assert_eq!(config.pools[0].cores, [CoreId::from(2), CoreId::from(3)]);
+        assert_eq!(
+            config.pools[1].policy,
+            PolicyKind::WeightedRoundRobin {
+                quantum: Duration::from_millis(20)
+            }
+        );
+        assert_eq!(
+            config.params(CoreId::from(2)),
+            SchedParams::Deadline {
+                period: Duration::from_millis(10),
+                budget: Duration::from_millis(4),
+            }
+        );
+        assert_eq!(config.params(CoreId::from(4)), SchedParams::Weight(3));
+        assert_eq!(config.params(CoreId::from(5)), SchedParams::default());
+
+        assert!(SchedConfig::parse("pool rt fifo 1").is_err());
+        assert!(SchedConfig::parse("weight x 1").is_err());
+        assert!(SchedConfig::parse("deadline 1 10").is_err());
+    }
+
+    #[test]
+    fn test_smt_placement() {
+        let cores = |ids: &[u32]| {
+            ids.iter().map(|id| CoreId::from(*id)).collect::<Vec<_>>()
+        };
+        let (vm1, vm2, vm3) = (cores(&[0, 1]), cores(&[2]), cores(&[3]));
+        let placement = |vm_id, cores, smt_policy| Placement {
+            vm_id,
+            cores,
+            smt_policy,
+        };
+        let placed = [
+            placement(1, &vm1[..], SmtPolicy::Isolated),
+            placement(2, &vm2[..], SmtPolicy::Shared),
+        ];
+
+        // Cores 2 and 3 are siblings (with two threads per core)
+        assert!(smt_siblings(CoreId::from(2), CoreId::from(3), 1));
+        assert!(!smt_siblings(CoreId::from(1), CoreId::from(2), 1));
+        let shared = placement(3, &vm3[..], SmtPolicy::Shared);
+        assert!(check_smt_placement(&shared, &placed[..1], 1).is_ok());
+        assert!(check_smt_placement(&shared, &placed, 1).is_ok());
+        let isolated = placement(3, &vm3[..], SmtPolicy::Isolated);
+        assert!(check_smt_placement(&isolated, &placed, 1).is_err());
+
+        // Without SMT, no cores are siblings
+        assert!(check_smt_placement(&isolated, &placed, 0).is_ok());
+    }
+}
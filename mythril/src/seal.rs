@@ -0,0 +1,394 @@
+//! Authenticated encryption of snapshots and migration streams
+//!
+//! A VM can be given a seal key through the management console (see
+//! `snapkey_command`). Page streams of a VM with a seal key (see
+//! `snapshot`) are sealed before they leave the hypervisor, so the contents
+//! of guest memory are not exposed on the migration path or wherever the
+//! stream is stored. A sealed stream is:
+//!
+//! - `SEAL_MAGIC`, a version and a random `NONCE_SIZE` byte nonce
+//! - the page stream, encrypted with ChaCha20
+//! - a Poly1305 tag (`TAG_SIZE` bytes)
+//!
+//! That is, the stream is a single ChaCha20-Poly1305 message (see RFC 8439)
+//! with the header as its associated data. The stream is sealed a page at a
+//! time, so the construction is driven incrementally over the `chacha20`
+//! and `poly1305` crates rather than through a one-shot AEAD interface. The
+//! tag is checked before any of the stream is decrypted.
+
+use crate::error::{Error, Result};
+use crate::rng;
+use crate::vm;
+use alloc::vec::Vec;
+use chacha20::stream_cipher::{
+    NewStreamCipher, SyncStreamCipher, SyncStreamCipherSeek,
+};
+use chacha20::ChaCha20;
+use core::convert::TryInto;
+use core::fmt;
+use poly1305::universal_hash::{NewUniversalHash, UniversalHash};
+use poly1305::Poly1305;
+use spin::Mutex;
+
+pub const SEAL_MAGIC: &[u8; 8] = b"MYTHSEAL";
+pub const SEAL_VERSION: u32 = 2;
+
+/// The size of a seal key (in bytes)
+pub const KEY_SIZE: usize = 32;
+
+/// The size of the nonce in the header of a sealed stream
+pub const NONCE_SIZE: usize = 12;
+
+/// The size of the tag at the end of a sealed stream
+pub const TAG_SIZE: usize = 16;
+
+const SEAL_HEADER_SIZE: usize = 12 + NONCE_SIZE;
+const CHACHA_BLOCK_SIZE: u64 = 64;
+const POLY1305_BLOCK_SIZE: usize = 16;
+
+// The seal keys of the VMs that have one, by VM id
+static KEYS: Mutex<Vec<(u32, SealKey)>> = Mutex::new(Vec::new());
+
+/// A key for sealing the page streams of a VM
+///
+/// The key is cleared from memory when it is dropped, and is never
+/// formatted.
+#[derive(Clone)]
+pub struct SealKey([u8; KEY_SIZE]);
+
+impl SealKey {
+    pub fn new(bytes: [u8; KEY_SIZE]) -> Self {
+        SealKey(bytes)
+    }
+
+    /// Parse a key written as `2 * KEY_SIZE` hexadecimal digits
+    pub fn parse(s: &str) -> Result<Self> {
+        let invalid = || {
+            Error::InvalidValue(format!(
+                "A seal key must be {} hexadecimal digits",
+                KEY_SIZE * 2
+            ))
+        };
+        if s.len() != KEY_SIZE * 2 {
+            return Err(invalid());
+        }
+        // Only digits, so no sign is accepted as part of a byte
+        let digit = |c: u8| (c as char).to_digit(16).ok_or_else(invalid);
+        let mut bytes = [0u8; KEY_SIZE];
+        for (byte, pair) in bytes.iter_mut().zip(s.as_bytes().chunks(2)) {
+            *byte = (digit(pair[0])? << 4 | digit(pair[1])?) as u8;
+        }
+        Ok(SealKey(bytes))
+    }
+}
+
+impl Drop for SealKey {
+    fn drop(&mut self) {
+        for byte in self.0.iter_mut() {
+            // Volatile, so the clear is not elided as a dead store
+            unsafe { core::ptr::write_volatile(byte, 0) };
+        }
+    }
+}
+
+impl fmt::Debug for SealKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SealKey(..)")
+    }
+}
+
+/// Set (or with `None`, remove) the seal key of the VM `vm_id`
+pub fn set_key(vm_id: u32, key: Option<SealKey>) {
+    let mut keys = KEYS.lock();
+    keys.retain(|(id, _)| *id != vm_id);
+    if let Some(key) = key {
+        keys.push((vm_id, key));
+    }
+}
+
+/// The seal key of the VM `vm_id`, if it has one
+pub fn key(vm_id: u32) -> Option<SealKey> {
+    KEYS.lock()
+        .iter()
+        .find(|(id, _)| *id == vm_id)
+        .map(|(_, key)| key.clone())
+}
+
+/// Returns true if `data` begins with the header of a sealed stream
+pub fn is_sealed(data: &[u8]) -> bool {
+    data.starts_with(SEAL_MAGIC)
+}
+
+// An incremental ChaCha20-Poly1305 (see RFC 8439 § 2.8)
+struct ChaCha20Poly1305 {
+    cipher: ChaCha20,
+    mac: Poly1305,
+    // Ciphertext that does not yet fill a Poly1305 block
+    partial: [u8; POLY1305_BLOCK_SIZE],
+    partial_len: usize,
+    aad_len: u64,
+    len: u64,
+}
+
+impl ChaCha20Poly1305 {
+    fn new(key: &SealKey, nonce: &[u8; NONCE_SIZE], aad: &[u8]) -> Self {
+        let mut cipher = ChaCha20::new(
+            chacha20::Key::from_slice(&key.0),
+            chacha20::Nonce::from_slice(nonce),
+        );
+
+        // The first block of the keystream keys the MAC, and the message is
+        // encrypted from the second block
+        let mut mac_key = poly1305::Key::default();
+        cipher.apply_keystream(&mut mac_key);
+        let mut mac = Poly1305::new(&mac_key);
+        for byte in mac_key.iter_mut() {
+            // Volatile, so the clear is not elided as a dead store
+            unsafe { core::ptr::write_volatile(byte, 0) };
+        }
+        cipher.seek(CHACHA_BLOCK_SIZE);
+
+        mac.update_padded(aad);
+        ChaCha20Poly1305 {
+            cipher,
+            mac,
+            partial: [0; POLY1305_BLOCK_SIZE],
+            partial_len: 0,
+            aad_len: aad.len() as u64,
+            len: 0,
+        }
+    }
+
+    fn apply_keystream(&mut self, data: &mut [u8]) {
+        self.cipher.apply_keystream(data);
+    }
+
+    fn authenticate(&mut self, mut ciphertext: &[u8]) {
+        self.len += ciphertext.len() as u64;
+        if self.partial_len > 0 {
+            let count = core::cmp::min(
+                ciphertext.len(),
+                POLY1305_BLOCK_SIZE - self.partial_len,
+            );
+            self.partial[self.partial_len..self.partial_len + count]
+                .copy_from_slice(&ciphertext[..count]);
+            self.partial_len += count;
+            ciphertext = &ciphertext[count..];
+            if self.partial_len < POLY1305_BLOCK_SIZE {
+                return;
+            }
+            self.mac.update(&self.partial.into());
+            self.partial_len = 0;
+        }
+
+        let whole = ciphertext.len() - ciphertext.len() % POLY1305_BLOCK_SIZE;
+        self.mac.update_padded(&ciphertext[..whole]);
+        let rest = &ciphertext[whole..];
+        self.partial[..rest.len()].copy_from_slice(rest);
+        self.partial_len = rest.len();
+    }
+
+    fn tag(mut self) -> [u8; TAG_SIZE] {
+        self.mac.update_padded(&self.partial[..self.partial_len]);
+        let mut lengths = [0u8; POLY1305_BLOCK_SIZE];
+        lengths[..8].copy_from_slice(&self.aad_len.to_le_bytes());
+        lengths[8..].copy_from_slice(&self.len.to_le_bytes());
+        self.mac.update(&lengths.into());
+        self.mac.finalize().into_bytes().into()
+    }
+}
+
+/// Seals a stream as it is written
+pub struct Sealer {
+    aead: ChaCha20Poly1305,
+}
+
+impl Sealer {
+    /// Create a sealer with a random nonce, appending the header of the
+    /// sealed stream to `out`
+    pub fn new(key: &SealKey, out: &mut Vec<u8>) -> Result<Self> {
+        let mut nonce = [0u8; NONCE_SIZE];
+        rng::fill_bytes(&mut nonce)?;
+        Ok(Self::with_nonce(key, nonce, out))
+    }
+
+    // A nonce must never be used twice with the same key (outside of
+    // tests, nonces are random)
+    pub(crate) fn with_nonce(
+        key: &SealKey,
+        nonce: [u8; NONCE_SIZE],
+        out: &mut Vec<u8>,
+    ) -> Self {
+        let start = out.len();
+        out.extend_from_slice(SEAL_MAGIC);
+        out.extend_from_slice(&SEAL_VERSION.to_le_bytes());
+        out.extend_from_slice(&nonce);
+        Sealer {
+            aead: ChaCha20Poly1305::new(key, &nonce, &out[start..]),
+        }
+    }
+
+    /// Encrypt the next `data` of the stream in place
+    pub fn seal(&mut self, data: &mut [u8]) {
+        self.aead.apply_keystream(data);
+        self.aead.authenticate(data);
+    }
+
+    /// Append the tag that ends the sealed stream to `out`
+    pub fn finish(self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.aead.tag());
+    }
+}
+
+/// Authenticate and decrypt the sealed stream `data`
+pub fn open(key: &SealKey, data: &[u8]) -> Result<Vec<u8>> {
+    if data.len() < SEAL_HEADER_SIZE + TAG_SIZE || !is_sealed(data) {
+        return Err(Error::InvalidValue(
+            "Invalid sealed stream: missing header".into(),
+        ));
+    }
+    let version = u32::from_le_bytes(data[8..12].try_into().unwrap());
+    if version != SEAL_VERSION {
+        return Err(Error::NotSupported);
+    }
+
+    let (body, tag) = data.split_at(data.len() - TAG_SIZE);
+    let (header, ciphertext) = body.split_at(SEAL_HEADER_SIZE);
+    let nonce = header[12..].try_into().unwrap();
+    let mut aead = ChaCha20Poly1305::new(key, &nonce, header);
+    aead.authenticate(ciphertext);
+    let expected = aead.tag();
+    // Compare every byte, so the time taken does not reveal the position
+    // of the first difference
+    let difference = expected
+        .iter()
+        .zip(tag)
+        .fold(0, |difference, (a, b)| difference | (a ^ b));
+    if difference != 0 {
+        return Err(Error::InvalidValue(
+            "Sealed stream failed authentication (wrong key or modified)"
+                .into(),
+        ));
+    }
+
+    let mut stream = ciphertext.to_vec();
+    ChaCha20Poly1305::new(key, &nonce, header).apply_keystream(&mut stream);
+    Ok(stream)
+}
+
+/// The `snapkey` management console command
+pub fn snapkey_command(out: &mut dyn fmt::Write, args: &[&str]) -> fmt::Result {
+    let vm_id = match args.get(0).and_then(|vm| vm::find_vm(vm)) {
+        Some(vm_id) => vm_id,
+        None => return writeln!(out, "usage: snapkey <vm> [<key>|clear]"),
+    };
+    let name = vm::vm_name(vm_id).unwrap_or("?");
+    match args.get(1) {
+        None => {
+            if key(vm_id).is_some() {
+                writeln!(out, "Snapshots of VM {} are sealed", name)
+            } else {
+                writeln!(out, "Snapshots of VM {} are not sealed", name)
+            }
+        }
+        Some(&"clear") => {
+            set_key(vm_id, None);
+            writeln!(out, "Snapshots of VM {} will not be sealed", name)
+        }
+        Some(arg) => match SealKey::parse(arg) {
+            Ok(new) => {
+                set_key(vm_id, Some(new));
+                writeln!(out, "Snapshots of VM {} will be sealed", name)
+            }
+            Err(Error::InvalidValue(msg)) => writeln!(out, "{}", msg),
+            Err(e) => writeln!(out, "{:?}", e),
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_seal_open() {
+        let key = SealKey::new([0x5a; KEY_SIZE]);
+        let message = b"the quick brown fox jumps over the lazy dog, twice";
+
+        // Seal in uneven pieces, as a page stream would be
+        let mut out = vec![];
+        let mut sealer = Sealer::with_nonce(&key, [7; NONCE_SIZE], &mut out);
+        for piece in message.chunks(13) {
+            let mut piece = piece.to_vec();
+            sealer.seal(&mut piece);
+            out.extend_from_slice(&piece);
+        }
+        sealer.finish(&mut out);
+        assert!(is_sealed(&out));
+        assert_eq!(out.len(), SEAL_HEADER_SIZE + message.len() + TAG_SIZE);
+        assert!(!out.windows(5).any(|window| window == &b"quick"[..]));
+        assert_eq!(open(&key, &out).unwrap(), &message[..]);
+
+        // A modified stream, or the wrong key, fails authentication
+        let mut modified = out.clone();
+        modified[SEAL_HEADER_SIZE + 4] ^= 1;
+        assert!(open(&key, &modified).is_err());
+        assert!(open(&SealKey::new([0x5b; KEY_SIZE]), &out).is_err());
+        assert!(open(&key, &out[..out.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_chacha20_poly1305() {
+        // The AEAD test vector of RFC 8439 § 2.8.2
+        let mut key = [0u8; KEY_SIZE];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = 0x80 + i as u8;
+        }
+        let nonce =
+            [7, 0, 0, 0, 0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47];
+        let aad = [
+            0x50, 0x51, 0x52, 0x53, 0xc0, 0xc1, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6,
+            0xc7,
+        ];
+        let mut message = b"Ladies and Gentlemen of the class of '99: \
+            If I could offer you only one tip for the future, sunscreen \
+            would be it."
+            .to_vec();
+
+        // Encrypt in uneven pieces, so blocks span calls
+        let mut aead = ChaCha20Poly1305::new(&SealKey::new(key), &nonce, &aad);
+        for piece in message.chunks_mut(7) {
+            aead.apply_keystream(piece);
+            aead.authenticate(piece);
+        }
+        assert_eq!(message.len(), 114);
+        assert_eq!(
+            message[..16],
+            [
+                0xd3, 0x1a, 0x8d, 0x34, 0x64, 0x8e, 0x60, 0xdb, 0x7b, 0x86,
+                0xaf, 0xbc, 0x53, 0xef, 0x7e, 0xc2
+            ]
+        );
+        assert_eq!(message[112..], [0x61, 0x16]);
+        assert_eq!(
+            aead.tag(),
+            [
+                0x1a, 0xe1, 0x0b, 0x59, 0x4f, 0x09, 0xe2, 0x6a, 0x7e, 0x90,
+                0x2e, 0xcb, 0xd0, 0x60, 0x06, 0x91
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_key() {
+        let key = SealKey::parse(
+            "000102030405060708090a0b0c0d0e0f\
+             101112131415161718191a1b1c1d1e1f",
+        )
+        .unwrap();
+        assert_eq!(key.0[0x1f], 0x1f);
+        assert!(SealKey::parse("0001").is_err());
+        assert!(SealKey::parse(&"zz".repeat(KEY_SIZE)).is_err());
+        assert!(SealKey::parse(&"+1".repeat(KEY_SIZE)).is_err());
+    }
+}
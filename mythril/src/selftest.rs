@@ -0,0 +1,166 @@
+//! Interrupt and port IO selftest
+//!
+//! The selftest VM runs a small guest built into the hypervisor (assembled
+//! from `selftest_guest.S` as its firmware) that exercises the emulated
+//! PIC, PIT, CMOS, PCI and debug port devices, and the delivery of
+//! interrupts to the guest from the PIT (through the PIC and the I/O APIC)
+//! and the local APIC timer. Each test that fails sets its bit in the value
+//! the guest writes to the debug-exit port (see `TESTS`), which is logged
+//! and reported by the `selftest` management console command.
+//!
+//! Booting with a (possibly empty) module named `selftest` runs the
+//! selftest VM on the BSP in place of the default VM.
+
+use crate::boot_info::BootInfo;
+use crate::error::Result;
+use crate::percore;
+use crate::physdev;
+use crate::virtdev::{self, debug::DebugExit};
+use crate::vm::{self, VirtualMachine};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::{Mutex, RwLock};
+
+/// The name of the boot module that selects the selftest VM
+pub const SELFTEST_MODULE: &str = "selftest";
+
+/// The port the guest writes its results to
+pub const DEBUG_EXIT_PORT: virtdev::Port = 0xf4;
+
+/// The tests performed by the guest, by the bit they set on failure
+pub const TESTS: &[&str] = &[
+    "pic imr",
+    "cmos",
+    "pci config",
+    "debug port",
+    "pit channel 2",
+    "pit irq0",
+    "lapic timer",
+    "lapic eoi",
+    "ioapic",
+];
+
+// The memory of the selftest VM (in MB). The CMOS reports the memory above
+// 16MB, so this must be larger than that.
+const SELFTEST_MEMORY: u64 = 32;
+
+#[cfg(not(feature = "test"))]
+static SELFTEST_IMAGE: &[u8] =
+    include_bytes!(concat!(env!("OUT_DIR"), "/selftest_guest.bin"));
+
+// The guest is only assembled for the hypervisor build (see build.rs)
+#[cfg(feature = "test")]
+static SELFTEST_IMAGE: &[u8] = &[];
+
+// The debug-exit device of each selftest VM
+static SELFTESTS: Mutex<Vec<(u32, Arc<RwLock<DebugExit>>)>> =
+    Mutex::new(Vec::new());
+
+/// The names of the tests that failed, given the value written by the
+/// guest to the debug-exit port
+pub fn failures(code: u32) -> impl Iterator<Item = &'static str> {
+    TESTS
+        .iter()
+        .enumerate()
+        .filter(move |(bit, _)| code & (1 << bit) != 0)
+        .map(|(_, name)| *name)
+}
+
+fn report(code: u32) {
+    if code == 0 {
+        info!("selftest: all {} tests passed", TESTS.len());
+        return;
+    }
+    for name in failures(code) {
+        error!("selftest: {} failed", name);
+    }
+}
+
+/// Create the selftest VM for the given core
+pub fn selftest_vm(
+    core: percore::CoreId,
+    info: &BootInfo,
+) -> Result<Arc<RwLock<VirtualMachine>>> {
+    let physical_config = vm::PhysicalDeviceConfig {
+        serial: Some(physdev::com::Uart8250::new(0x3f8)?),
+        ps2_keyboard: None,
+    };
+    let mut config = vm::VirtualMachineConfig::new(
+        vec![core],
+        SELFTEST_MEMORY,
+        physical_config,
+    );
+    config.set_builtin_firmware(SELFTEST_IMAGE);
+
+    let debug_exit = DebugExit::new(DEBUG_EXIT_PORT, report);
+    let pic = virtdev::pic::Pic8259::new();
+    let ioapic = virtdev::ioapic::IoApic::new();
+    let device_map = config.virtual_devices_mut();
+    device_map.register_device(virtdev::debug::DebugPort::new(0x402))?;
+    device_map.register_device(debug_exit.clone())?;
+    device_map.register_device(pic.clone())?;
+    device_map.register_device(ioapic.clone())?;
+    device_map.register_device(virtdev::lapic::LocalApic::new())?;
+    device_map.register_device(virtdev::pit::Pit8254::new())?;
+    device_map.register_device(virtdev::pci::PciRootComplex::new())?;
+    device_map.register_device(virtdev::rtc::CmosRtc::new(
+        SELFTEST_MEMORY,
+        &[],
+        None,
+        virtdev::rtc::RtcPolicy::default(),
+    )?)?;
+    config.set_pic(pic);
+    config.set_ioapic(ioapic);
+
+    SELFTESTS.lock().push((core.raw, debug_exit));
+    VirtualMachine::new(core.raw, config, info)
+}
+
+/// The `selftest` management console command
+pub fn selftest_command(
+    out: &mut dyn fmt::Write,
+    _args: &[&str],
+) -> fmt::Result {
+    let selftests = SELFTESTS.lock();
+    if selftests.is_empty() {
+        return writeln!(
+            out,
+            "No selftest VM (boot with a '{}' module to run one)",
+            SELFTEST_MODULE
+        );
+    }
+    for (vm_id, debug_exit) in selftests.iter() {
+        match debug_exit.read().code() {
+            None => writeln!(out, "VM {}: running", vm_id)?,
+            Some(0) => {
+                writeln!(out, "VM {}: {} tests passed", vm_id, TESTS.len())?
+            }
+            Some(code) => {
+                write!(out, "VM {}: failed:", vm_id)?;
+                for name in failures(code) {
+                    write!(out, " '{}'", name)?;
+                }
+                writeln!(out)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_failures() {
+        assert_eq!(failures(0).count(), 0);
+        assert_eq!(
+            failures(0b100001).collect::<Vec<_>>(),
+            vec!["pic imr", "pit irq0"]
+        );
+        assert_eq!(failures(1 << 8).collect::<Vec<_>>(), vec!["ioapic"]);
+        // Unknown bits are ignored
+        assert_eq!(failures(1 << 31).count(), 0);
+    }
+}
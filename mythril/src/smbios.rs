@@ -0,0 +1,439 @@
+//! Generation of the SMBIOS tables of a guest
+//!
+//! Each VM gets a minimal set of SMBIOS 2.8 structures: the BIOS (type 0)
+//! and system (type 1) information, one processor (type 4) per core, and a
+//! physical memory array (type 16) with a single memory device (type 17)
+//! of the VM's actual memory size. The strings are configurable through
+//! `SmbiosStrings`, and the system UUID is the UUID of the VM.
+//!
+//! The tables are passed to the firmware through fw_cfg, or (for a PVH
+//! boot) placed directly in the BIOS area at `SMBIOS_ANCHOR_ADDR`, where a
+//! legacy scan of the 0xF0000 segment finds the entry point.
+
+use crate::error::{Error, Result};
+use crate::identity::Uuid;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// The guest physical address of the entry point when the tables are
+/// placed directly in guest memory
+pub const SMBIOS_ANCHOR_ADDR: u64 = 0xf0000;
+
+/// The guest physical address of the structure table when the tables are
+/// placed directly in guest memory
+pub const SMBIOS_TABLES_ADDR: u64 = 0xf0020;
+
+/// The maximum size of the structure table placed at `SMBIOS_TABLES_ADDR`
+/// (the top of the segment holds the legacy BIOS stubs, see `biosdata`)
+pub const SMBIOS_TABLES_MAX: usize = 0xff00 - 0x20;
+
+const ENTRY_POINT_LEN: usize = 0x1f;
+const SMBIOS_MAJOR: u8 = 2;
+const SMBIOS_MINOR: u8 = 8;
+
+const TYPE_BIOS: u8 = 0;
+const TYPE_SYSTEM: u8 = 1;
+const TYPE_PROCESSOR: u8 = 4;
+const TYPE_MEMORY_ARRAY: u8 = 16;
+const TYPE_MEMORY_DEVICE: u8 = 17;
+const TYPE_END: u8 = 127;
+
+// The handles of the structures (processors are numbered from
+// HANDLE_PROCESSOR)
+const HANDLE_BIOS: u16 = 0x0000;
+const HANDLE_SYSTEM: u16 = 0x0100;
+const HANDLE_MEMORY_ARRAY: u16 = 0x1000;
+const HANDLE_MEMORY_DEVICE: u16 = 0x1100;
+const HANDLE_PROCESSOR: u16 = 0x0400;
+const HANDLE_END: u16 = 0x7f00;
+
+// No error information (for the memory structures) and no cache
+// information (for the processors)
+const HANDLE_NO_ERROR_INFO: u16 = 0xfffe;
+const HANDLE_NOT_PROVIDED: u16 = 0xffff;
+
+/// The strings reported in the SMBIOS tables of a guest
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SmbiosStrings {
+    pub bios_vendor: String,
+    pub bios_version: String,
+    pub bios_date: String,
+    pub manufacturer: String,
+    pub product: String,
+    pub version: String,
+    pub serial: String,
+}
+
+impl Default for SmbiosStrings {
+    fn default() -> Self {
+        SmbiosStrings {
+            bios_vendor: "Mythril".into(),
+            bios_version: "1.0".into(),
+            bios_date: "01/01/2020".into(),
+            manufacturer: "Mythril".into(),
+            product: "Mythril Virtual Machine".into(),
+            version: "1.0".into(),
+            serial: String::new(),
+        }
+    }
+}
+
+// A structure being built: the formatted area (starting with the header)
+// and its strings
+struct Structure {
+    formatted: Vec<u8>,
+    strings: Vec<u8>,
+    count: u8,
+}
+
+impl Structure {
+    fn new(kind: u8, len: usize, handle: u16) -> Self {
+        let mut formatted = vec![0u8; len];
+        formatted[0] = kind;
+        formatted[1] = len as u8;
+        formatted[2..4].copy_from_slice(&handle.to_le_bytes());
+        Structure {
+            formatted,
+            strings: vec![],
+            count: 0,
+        }
+    }
+
+    fn set_u8(&mut self, offset: usize, val: u8) {
+        self.formatted[offset] = val;
+    }
+
+    fn set_u16(&mut self, offset: usize, val: u16) {
+        self.formatted[offset..offset + 2].copy_from_slice(&val.to_le_bytes());
+    }
+
+    fn set_u32(&mut self, offset: usize, val: u32) {
+        self.formatted[offset..offset + 4].copy_from_slice(&val.to_le_bytes());
+    }
+
+    fn set_u64(&mut self, offset: usize, val: u64) {
+        self.formatted[offset..offset + 8].copy_from_slice(&val.to_le_bytes());
+    }
+
+    // Strings are referenced by their (1-based) number, and an empty string
+    // is reported as 'no string'
+    fn set_string(&mut self, offset: usize, s: &str) {
+        if s.is_empty() {
+            return;
+        }
+        self.count += 1;
+        self.strings.extend(s.bytes().filter(|b| *b != 0));
+        self.strings.push(0);
+        self.formatted[offset] = self.count;
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        // The string set ends with an extra NULL (so a structure without
+        // strings ends with two)
+        if self.strings.is_empty() {
+            self.strings.push(0);
+        }
+        self.strings.push(0);
+        self.formatted.extend(self.strings);
+        self.formatted
+    }
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    0u8.wrapping_sub(bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)))
+}
+
+// SMBIOS stores the first three fields of the UUID in little-endian
+// byte order
+fn encode_uuid(uuid: &Uuid) -> [u8; 16] {
+    let mut bytes = uuid.0;
+    bytes[0..4].reverse();
+    bytes[4..6].reverse();
+    bytes[6..8].reverse();
+    bytes
+}
+
+fn bios_information(strings: &SmbiosStrings) -> Vec<u8> {
+    let mut bios = Structure::new(TYPE_BIOS, 0x18, HANDLE_BIOS);
+    bios.set_string(0x04, &strings.bios_vendor);
+    bios.set_string(0x05, &strings.bios_version);
+    bios.set_u16(0x06, 0xe800);
+    bios.set_string(0x08, &strings.bios_date);
+    // Characteristics are not supported, and the system is a VM
+    bios.set_u64(0x0a, 1 << 3);
+    bios.set_u8(0x13, 1 << 4);
+    bios.set_u8(0x16, 0xff);
+    bios.set_u8(0x17, 0xff);
+    bios.finish()
+}
+
+fn system_information(strings: &SmbiosStrings, uuid: &Uuid) -> Vec<u8> {
+    let mut system = Structure::new(TYPE_SYSTEM, 0x1b, HANDLE_SYSTEM);
+    system.set_string(0x04, &strings.manufacturer);
+    system.set_string(0x05, &strings.product);
+    system.set_string(0x06, &strings.version);
+    system.set_string(0x07, &strings.serial);
+    system.formatted[0x08..0x18].copy_from_slice(&encode_uuid(uuid));
+    // Woken up by the power switch
+    system.set_u8(0x18, 0x06);
+    system.finish()
+}
+
+fn processor_information(index: usize, strings: &SmbiosStrings) -> Vec<u8> {
+    let handle = HANDLE_PROCESSOR + index as u16;
+    let mut cpu = Structure::new(TYPE_PROCESSOR, 0x2a, handle);
+    cpu.set_string(0x04, &format!("CPU {}", index));
+    // A central processor of an unknown family
+    cpu.set_u8(0x05, 0x03);
+    cpu.set_u8(0x06, 0x02);
+    cpu.set_string(0x07, &strings.manufacturer);
+    // The socket is populated and the processor is enabled
+    cpu.set_u8(0x18, 0x41);
+    cpu.set_u8(0x19, 0x01);
+    cpu.set_u16(0x1a, HANDLE_NOT_PROVIDED);
+    cpu.set_u16(0x1c, HANDLE_NOT_PROVIDED);
+    cpu.set_u16(0x1e, HANDLE_NOT_PROVIDED);
+    // Each core of the VM is a single-threaded processor
+    cpu.set_u8(0x23, 1);
+    cpu.set_u8(0x24, 1);
+    cpu.set_u8(0x25, 1);
+    cpu.set_u16(0x26, 0x02);
+    cpu.set_u16(0x28, 0x02);
+    cpu.finish()
+}
+
+fn memory_array(memory: u64) -> Vec<u8> {
+    let mut array =
+        Structure::new(TYPE_MEMORY_ARRAY, 0x17, HANDLE_MEMORY_ARRAY);
+    // System memory on the system board, without error correction
+    array.set_u8(0x04, 0x03);
+    array.set_u8(0x05, 0x03);
+    array.set_u8(0x06, 0x03);
+    // The maximum capacity is in KB (with larger sizes in the extended
+    // field)
+    let kb = memory << 10;
+    if kb < 0x8000_0000 {
+        array.set_u32(0x07, kb as u32);
+    } else {
+        array.set_u32(0x07, 0x8000_0000);
+        array.set_u64(0x0f, memory << 20);
+    }
+    array.set_u16(0x0b, HANDLE_NO_ERROR_INFO);
+    array.set_u16(0x0d, 1);
+    array.finish()
+}
+
+fn memory_device(memory: u64, strings: &SmbiosStrings) -> Vec<u8> {
+    let mut device =
+        Structure::new(TYPE_MEMORY_DEVICE, 0x28, HANDLE_MEMORY_DEVICE);
+    device.set_u16(0x04, HANDLE_MEMORY_ARRAY);
+    device.set_u16(0x06, HANDLE_NO_ERROR_INFO);
+    device.set_u16(0x08, 64);
+    device.set_u16(0x0a, 64);
+    // The size is in MB (with larger sizes in the extended field)
+    if memory < 0x7fff {
+        device.set_u16(0x0c, memory as u16);
+    } else {
+        device.set_u16(0x0c, 0x7fff);
+        device.set_u32(0x1c, memory as u32);
+    }
+    // A DIMM of RAM
+    device.set_u8(0x0e, 0x09);
+    device.set_string(0x10, "DIMM 0");
+    device.set_u8(0x12, 0x07);
+    device.set_u16(0x13, 0x02);
+    device.set_string(0x17, &strings.manufacturer);
+    device.finish()
+}
+
+/// The SMBIOS entry point and structure table of a guest
+pub struct SmbiosTables {
+    anchor: Vec<u8>,
+    tables: Vec<u8>,
+}
+
+impl SmbiosTables {
+    /// Build the tables of a VM
+    ///
+    /// # Arguments
+    ///
+    /// * `strings` - The strings of the BIOS and system information
+    /// * `uuid` - The system UUID
+    /// * `cpus` - The number of cores of the VM
+    /// * `memory` - The amount of VM memory (in MB)
+    pub fn new(
+        strings: &SmbiosStrings,
+        uuid: &Uuid,
+        cpus: usize,
+        memory: u64,
+    ) -> Result<Self> {
+        let mut structures =
+            vec![bios_information(strings), system_information(strings, uuid)];
+        for index in 0..cpus {
+            structures.push(processor_information(index, strings));
+        }
+        structures.push(memory_array(memory));
+        structures.push(memory_device(memory, strings));
+        structures.push(Structure::new(TYPE_END, 4, HANDLE_END).finish());
+
+        let tables = structures.concat();
+        if tables.len() > u16::MAX as usize {
+            return Err(Error::InvalidValue(format!(
+                "SMBIOS tables too large ({} bytes)",
+                tables.len()
+            )));
+        }
+        let max_size = structures.iter().map(|s| s.len()).max().unwrap_or(0);
+
+        // The (32-bit) SMBIOS 2.1 entry point, with the address of the
+        // structure table left for the firmware to fill in
+        let mut anchor = vec![0u8; ENTRY_POINT_LEN];
+        anchor[0x00..0x04].copy_from_slice(b"_SM_");
+        anchor[0x05] = ENTRY_POINT_LEN as u8;
+        anchor[0x06] = SMBIOS_MAJOR;
+        anchor[0x07] = SMBIOS_MINOR;
+        anchor[0x08..0x0a].copy_from_slice(&(max_size as u16).to_le_bytes());
+        anchor[0x10..0x15].copy_from_slice(b"_DMI_");
+        anchor[0x16..0x18]
+            .copy_from_slice(&(tables.len() as u16).to_le_bytes());
+        anchor[0x1c..0x1e]
+            .copy_from_slice(&(structures.len() as u16).to_le_bytes());
+        anchor[0x1e] = (SMBIOS_MAJOR << 4) | SMBIOS_MINOR;
+
+        let mut smbios = SmbiosTables { anchor, tables };
+        smbios.set_tables_addr(0);
+        Ok(smbios)
+    }
+
+    fn set_tables_addr(&mut self, addr: u32) {
+        self.anchor[0x18..0x1c].copy_from_slice(&addr.to_le_bytes());
+        self.anchor[0x04] = 0;
+        self.anchor[0x15] = 0;
+        self.anchor[0x15] = checksum(&self.anchor[0x10..]);
+        self.anchor[0x04] = checksum(&self.anchor);
+    }
+
+    /// The entry point (as passed to the firmware, which fills in the
+    /// address of the structure table)
+    pub fn anchor(&self) -> &[u8] {
+        &self.anchor
+    }
+
+    /// The structure table
+    pub fn tables(&self) -> &[u8] {
+        &self.tables
+    }
+
+    /// The entry point for placing the tables directly in guest memory
+    /// (at `SMBIOS_ANCHOR_ADDR`, with the structure table at
+    /// `SMBIOS_TABLES_ADDR`)
+    pub fn relocate(&self) -> Result<Vec<u8>> {
+        if self.tables.len() > SMBIOS_TABLES_MAX {
+            return Err(Error::InvalidValue(format!(
+                "SMBIOS tables too large ({} > {})",
+                self.tables.len(),
+                SMBIOS_TABLES_MAX
+            )));
+        }
+        let mut relocated = SmbiosTables {
+            anchor: self.anchor.clone(),
+            tables: vec![],
+        };
+        relocated.set_tables_addr(SMBIOS_TABLES_ADDR as u32);
+        Ok(relocated.anchor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Split the structure table into (formatted area, strings) pairs
+    fn structures(mut tables: &[u8]) -> Vec<(&[u8], Vec<&str>)> {
+        let mut res = vec![];
+        while !tables.is_empty() {
+            let len = tables[1] as usize;
+            let mut end = len;
+            while &tables[end..end + 2] != b"\0\0" {
+                end += 1;
+            }
+            let strings = tables[len..end]
+                .split(|b| *b == 0)
+                .filter(|s| !s.is_empty())
+                .map(|s| core::str::from_utf8(s).unwrap())
+                .collect();
+            res.push((&tables[..len], strings));
+            tables = &tables[end + 2..];
+        }
+        res
+    }
+
+    #[test]
+    fn test_smbios_tables() {
+        let strings = SmbiosStrings {
+            product: "Test Machine".into(),
+            ..SmbiosStrings::default()
+        };
+        let uuid = Uuid::parse("6e5c9ba2-93f1-4d4a-8e0c-2b7d1f3a9c01").unwrap();
+        let smbios = SmbiosTables::new(&strings, &uuid, 2, 256).unwrap();
+
+        let tables = structures(smbios.tables());
+        let kinds = tables.iter().map(|(s, _)| s[0]).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                TYPE_BIOS,
+                TYPE_SYSTEM,
+                TYPE_PROCESSOR,
+                TYPE_PROCESSOR,
+                TYPE_MEMORY_ARRAY,
+                TYPE_MEMORY_DEVICE,
+                TYPE_END
+            ]
+        );
+
+        let (system, system_strings) = &tables[1];
+        assert_eq!(system[0x05], 2);
+        assert_eq!(system_strings[1], "Test Machine");
+        assert_eq!(system[0x08..0x0c], [0xa2, 0x9b, 0x5c, 0x6e]);
+        assert_eq!(system[0x0e..0x10], [0x4a, 0x4d]);
+        assert_eq!(system[0x10..0x18], uuid.0[8..]);
+
+        // 256MB of memory in a single device
+        let (array, _) = &tables[4];
+        assert_eq!(array[0x07..0x0b], (256u32 << 10).to_le_bytes());
+        let (device, device_strings) = &tables[5];
+        assert_eq!(device[0x0c..0x0e], 256u16.to_le_bytes());
+        assert_eq!(device_strings[0], "DIMM 0");
+
+        let anchor = smbios.anchor();
+        assert_eq!(&anchor[..4], b"_SM_");
+        assert_eq!(
+            anchor[0x16..0x18],
+            (smbios.tables().len() as u16).to_le_bytes()
+        );
+        assert_eq!(anchor[0x1c], 7);
+        assert_eq!(checksum(anchor), 0);
+        assert_eq!(checksum(&anchor[0x10..]), 0);
+    }
+
+    #[test]
+    fn test_smbios_relocate() {
+        let uuid = Uuid::from_name("vm1");
+        let smbios =
+            SmbiosTables::new(&SmbiosStrings::default(), &uuid, 1, 0x10000)
+                .unwrap();
+        let anchor = smbios.relocate().unwrap();
+        assert_eq!(anchor[0x18..0x1c], 0xf0020u32.to_le_bytes());
+        assert_eq!(checksum(&anchor), 0);
+        assert_eq!(checksum(&anchor[0x10..]), 0);
+
+        // 64GB of memory needs the extended size of the memory device
+        let tables = structures(smbios.tables());
+        let (array, _) = &tables[3];
+        assert_eq!(array[0x07..0x0b], (64u32 << 20).to_le_bytes());
+        let (device, _) = &tables[4];
+        assert_eq!(device[0x0c..0x0e], 0x7fffu16.to_le_bytes());
+        assert_eq!(device[0x1c..0x20], 0x10000u32.to_le_bytes());
+    }
+}
@@ -0,0 +1,464 @@
+//! Guest memory snapshots
+//!
+//! Snapshots and migration streams describe guest memory as a sequence of
+//! page records. Each record begins with the guest physical address of the
+//! page, with the encoding of its contents in the low (offset) bits:
+//!
+//! - `PAGE_ZERO`: the page is all zero, and has no contents
+//! - `PAGE_LZ4`: a 16-bit length, followed by the page as an LZ4 block
+//!   (see `compress`)
+//! - `PAGE_RAW`: the uncompressed page (when compression does not help)
+//!
+//! A stream may also hold a `RECORD_TIME` record (with an address of zero),
+//! holding the guest time when the VM stopped (see `timekeeping`), so the
+//! guest time can be made continuous when the stream is restored.
+//!
+//! The stream begins with `STREAM_MAGIC` and a version, and ends with a
+//! `PAGE_END` record. Idle guests are mostly zero pages, so a snapshot is
+//! usually a small fraction of the size of guest memory.
+//!
+//! The `snapshot` management console command writes the memory of a paused
+//! VM in this format, base64 encoded (like `coredump`). If the VM has a
+//! seal key, the stream is sealed (see `seal`) before it is encoded.
+
+use crate::compress;
+use crate::coredump::{self, Base64Writer};
+use crate::error::{Error, Result};
+use crate::memory::{GuestAddressSpace, GuestPhysAddr, HostPhysFrame};
+use crate::seal::{self, SealKey, Sealer};
+use crate::time;
+use crate::timekeeping::{self, TimeState, TIME_STATE_SIZE};
+use crate::vm;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::fmt;
+
+pub const STREAM_MAGIC: &[u8; 8] = b"MYTHSNAP";
+pub const STREAM_VERSION: u32 = 2;
+const STREAM_HEADER_SIZE: usize = 16;
+
+pub const PAGE_ZERO: u64 = 0;
+pub const PAGE_LZ4: u64 = 1;
+pub const PAGE_RAW: u64 = 2;
+pub const RECORD_TIME: u64 = 0xffe;
+pub const PAGE_END: u64 = 0xfff;
+
+const PAGE_KIND_MASK: u64 = HostPhysFrame::SIZE as u64 - 1;
+
+/// The number of pages and bytes written to (or read from) a stream
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PageStreamStats {
+    pub pages: u64,
+    pub zero_pages: u64,
+    pub compressed_pages: u64,
+    pub bytes: u64,
+}
+
+impl PageStreamStats {
+    /// The size of the stream relative to the guest memory it describes,
+    /// in parts per thousand
+    pub fn ratio_permille(&self) -> u64 {
+        let size = self.pages * HostPhysFrame::SIZE as u64;
+        if size == 0 {
+            return 0;
+        }
+        self.bytes * 1000 / size
+    }
+}
+
+/// Encodes guest pages as a page stream
+pub struct PageStreamWriter {
+    stats: PageStreamStats,
+    scratch: Vec<u8>,
+}
+
+impl PageStreamWriter {
+    /// Create a writer, appending the stream header to `out`
+    pub fn new(out: &mut Vec<u8>) -> Self {
+        let start = out.len();
+        out.extend_from_slice(STREAM_MAGIC);
+        out.extend_from_slice(&STREAM_VERSION.to_le_bytes());
+        out.extend_from_slice(&0u32.to_le_bytes());
+        Self {
+            stats: PageStreamStats {
+                bytes: (out.len() - start) as u64,
+                ..Default::default()
+            },
+            scratch: Vec::with_capacity(HostPhysFrame::SIZE),
+        }
+    }
+
+    /// Append the record for the page at `addr` to `out`
+    pub fn write_page(
+        &mut self,
+        addr: GuestPhysAddr,
+        page: &[u8; HostPhysFrame::SIZE],
+        out: &mut Vec<u8>,
+    ) {
+        let start = out.len();
+        let addr = addr.as_u64() & !PAGE_KIND_MASK;
+        self.stats.pages += 1;
+
+        if page.iter().all(|byte| *byte == 0) {
+            self.stats.zero_pages += 1;
+            out.extend_from_slice(&(addr | PAGE_ZERO).to_le_bytes());
+        } else {
+            self.scratch.clear();
+            compress::compress(page, &mut self.scratch);
+            if self.scratch.len() + 2 < HostPhysFrame::SIZE {
+                self.stats.compressed_pages += 1;
+                out.extend_from_slice(&(addr | PAGE_LZ4).to_le_bytes());
+                out.extend_from_slice(
+                    &(self.scratch.len() as u16).to_le_bytes(),
+                );
+                out.extend_from_slice(&self.scratch);
+            } else {
+                out.extend_from_slice(&(addr | PAGE_RAW).to_le_bytes());
+                out.extend_from_slice(page);
+            }
+        }
+        self.stats.bytes += (out.len() - start) as u64;
+    }
+
+    /// Append a record of the guest time `state` to `out`
+    pub fn write_time(&mut self, state: &TimeState, out: &mut Vec<u8>) {
+        out.extend_from_slice(&RECORD_TIME.to_le_bytes());
+        out.extend_from_slice(&state.to_bytes());
+        self.stats.bytes += (8 + TIME_STATE_SIZE) as u64;
+    }
+
+    /// Append the end of stream record to `out`, returning the statistics
+    /// for the stream
+    pub fn finish(mut self, out: &mut Vec<u8>) -> PageStreamStats {
+        out.extend_from_slice(&PAGE_END.to_le_bytes());
+        self.stats.bytes += 8;
+        self.stats
+    }
+}
+
+fn invalid_stream(reason: &str) -> Error {
+    Error::InvalidValue(format!("Invalid page stream: {}", reason))
+}
+
+/// Decodes the pages of a page stream
+pub struct PageStreamReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+    page: Vec<u8>,
+    time: Option<TimeState>,
+}
+
+impl<'a> PageStreamReader<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self> {
+        if seal::is_sealed(data) {
+            return Err(invalid_stream("sealed (open it with its key first)"));
+        }
+        if data.len() < STREAM_HEADER_SIZE || &data[..8] != STREAM_MAGIC {
+            return Err(invalid_stream("missing header"));
+        }
+        let version = u32::from_le_bytes(data[8..12].try_into().unwrap());
+        // Version 1 streams are the same, without time records
+        if version == 0 || version > STREAM_VERSION {
+            return Err(Error::NotSupported);
+        }
+        Ok(Self {
+            data,
+            offset: STREAM_HEADER_SIZE,
+            page: Vec::with_capacity(HostPhysFrame::SIZE),
+            time: None,
+        })
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let data = self
+            .data
+            .get(self.offset..self.offset + len)
+            .ok_or_else(|| invalid_stream("truncated"))?;
+        self.offset += len;
+        Ok(data)
+    }
+
+    /// The guest time recorded in the stream (if any has been read yet)
+    pub fn time(&self) -> Option<TimeState> {
+        self.time
+    }
+
+    /// The next page in the stream, or `None` at the end of the stream
+    pub fn next_page(&mut self) -> Result<Option<(GuestPhysAddr, &[u8])>> {
+        let record = loop {
+            let record = u64::from_le_bytes(self.take(8)?.try_into().unwrap());
+            if record != RECORD_TIME {
+                break record;
+            }
+            self.time =
+                Some(TimeState::from_bytes(self.take(TIME_STATE_SIZE)?)?);
+        };
+        let addr = GuestPhysAddr::new(record & !PAGE_KIND_MASK);
+        self.page.clear();
+        match record & PAGE_KIND_MASK {
+            PAGE_END => return Ok(None),
+            PAGE_ZERO => self.page.resize(HostPhysFrame::SIZE, 0),
+            PAGE_LZ4 => {
+                let len = self.take(2)?;
+                let len = u16::from_le_bytes([len[0], len[1]]) as usize;
+                let block = self.take(len)?;
+                compress::decompress(
+                    block,
+                    &mut self.page,
+                    HostPhysFrame::SIZE,
+                )?;
+                if self.page.len() != HostPhysFrame::SIZE {
+                    return Err(invalid_stream("short page"));
+                }
+            }
+            PAGE_RAW => {
+                let page = self.take(HostPhysFrame::SIZE)?;
+                self.page.extend_from_slice(page);
+            }
+            _ => return Err(invalid_stream("unknown record")),
+        }
+        Ok(Some((addr, &self.page)))
+    }
+}
+
+/// Encode all of the memory mapped in `space` as a page stream
+pub fn save_pages(
+    space: &GuestAddressSpace,
+    out: &mut Vec<u8>,
+) -> PageStreamStats {
+    let mut writer = PageStreamWriter::new(out);
+    for (addr, frame, _) in space.mappings() {
+        writer.write_page(addr, unsafe { frame.as_array() }, out);
+    }
+    writer.finish(out)
+}
+
+/// Write the pages of a page stream to `space`, mapping new frames for
+/// pages that are not already mapped
+///
+/// The guest time recorded in the stream (if any) is returned, so the
+/// caller can restore the guest time of each vCPU (see
+/// `TimeState::restore_fixup`).
+pub fn restore_pages(
+    space: &mut GuestAddressSpace,
+    data: &[u8],
+) -> Result<(PageStreamStats, Option<TimeState>)> {
+    let mut stats = PageStreamStats::default();
+    let mut reader = PageStreamReader::new(data)?;
+    while let Some((addr, page)) = reader.next_page()? {
+        let mut frame = match space.find_host_frame(addr) {
+            Ok(frame) => frame,
+            Err(_) => {
+                space.map_new_frame(addr, false)?;
+                space.find_host_frame(addr)?
+            }
+        };
+        unsafe { frame.as_mut_array() }.copy_from_slice(page);
+        stats.pages += 1;
+    }
+    stats.bytes = reader.offset as u64;
+    Ok((stats, reader.time()))
+}
+
+/// Authenticate and decrypt the sealed page stream `data` with `key`, then
+/// restore its pages to `space` (see `restore_pages`)
+pub fn restore_sealed_pages(
+    space: &mut GuestAddressSpace,
+    data: &[u8],
+    key: &SealKey,
+) -> Result<(PageStreamStats, Option<TimeState>)> {
+    let stream = seal::open(key, data)?;
+    restore_pages(space, &stream)
+}
+
+/// The `snapshot` management console command
+pub fn snapshot_command(
+    out: &mut dyn fmt::Write,
+    args: &[&str],
+) -> fmt::Result {
+    let vm_id = match args.get(0).and_then(|vm| vm::find_vm(vm)) {
+        Some(vm_id) => vm_id,
+        None => return writeln!(out, "usage: snapshot <vm>"),
+    };
+    let (core, registers) = match coredump::paused_vcpus(vm_id).first() {
+        Some(paused) => *paused,
+        None => {
+            return writeln!(
+                out,
+                "VM {} is not paused (pause it with a watch first)",
+                vm::vm_name(vm_id).unwrap_or("?")
+            )
+        }
+    };
+
+    // The VM is paused, so its memory is not being modified
+    let vm = match unsafe { vm::get_vm_for_core_id(core) } {
+        Some(vm) => vm,
+        None => return writeln!(out, "No VM {}", vm_id),
+    };
+    let vm = vm.read();
+
+    // Encode the stream a page at a time, so only one page is buffered
+    let mut buffer = Vec::with_capacity(HostPhysFrame::SIZE + 16);
+    let mut sealer = match seal::key(vm_id) {
+        Some(key) => match Sealer::new(&key, &mut buffer) {
+            Ok(sealer) => Some(sealer),
+            Err(e) => {
+                return writeln!(out, "Unable to seal the snapshot: {:?}", e)
+            }
+        },
+        None => None,
+    };
+    let sealed_header = buffer.len();
+    writeln!(out, "-----BEGIN MYTHRIL SNAPSHOT-----")?;
+    let mut encoder = Base64Writer::new(out);
+    let mut writer = PageStreamWriter::new(&mut buffer);
+
+    // The guest time is that of the first vCPU when it paused, at the wall
+    // clock time of the snapshot
+    writer.write_time(
+        &TimeState {
+            tsc: registers.tsc,
+            tsc_frequency: time::frequency(),
+            wall_clock: timekeeping::wall_clock_now(),
+        },
+        &mut buffer,
+    );
+    if let Some(sealer) = sealer.as_mut() {
+        sealer.seal(&mut buffer[sealed_header..]);
+    }
+    encoder.write(&buffer)?;
+    buffer.clear();
+    for (addr, frame, _) in vm.guest_space.mappings() {
+        writer.write_page(addr, unsafe { frame.as_array() }, &mut buffer);
+        if let Some(sealer) = sealer.as_mut() {
+            sealer.seal(&mut buffer);
+        }
+        encoder.write(&buffer)?;
+        buffer.clear();
+    }
+    let stats = writer.finish(&mut buffer);
+    if let Some(mut sealer) = sealer {
+        sealer.seal(&mut buffer);
+        sealer.finish(&mut buffer);
+    }
+    encoder.write(&buffer)?;
+    encoder.finish()?;
+    writeln!(out, "-----END MYTHRIL SNAPSHOT-----")?;
+    writeln!(
+        out,
+        "{} pages ({} zero, {} compressed), {} bytes ({}.{}% of memory)",
+        stats.pages,
+        stats.zero_pages,
+        stats.compressed_pages,
+        stats.bytes,
+        stats.ratio_permille() / 10,
+        stats.ratio_permille() % 10
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_page_stream() {
+        let mut page = [0u8; HostPhysFrame::SIZE];
+        let mut out = vec![];
+        let mut writer = PageStreamWriter::new(&mut out);
+        writer.write_page(GuestPhysAddr::new(0x1000), &page, &mut out);
+        page[..16].copy_from_slice(b"0123456789abcdef");
+        writer.write_page(GuestPhysAddr::new(0x2000), &page, &mut out);
+
+        // A pseudo-random page does not compress
+        let mut state = 0x2545f491u32;
+        for byte in page.iter_mut() {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            *byte = state as u8;
+        }
+        writer.write_page(GuestPhysAddr::new(0x3000), &page, &mut out);
+        let time = TimeState {
+            tsc: 0x1000,
+            tsc_frequency: 1_000_000,
+            wall_clock: None,
+        };
+        writer.write_time(&time, &mut out);
+        let stats = writer.finish(&mut out);
+
+        assert_eq!(stats.pages, 3);
+        assert_eq!(stats.zero_pages, 1);
+        assert_eq!(stats.compressed_pages, 1);
+        assert_eq!(stats.bytes, out.len() as u64);
+        assert!(out.len() < 2 * HostPhysFrame::SIZE);
+
+        let mut reader = PageStreamReader::new(&out).unwrap();
+        let (addr, data) = reader.next_page().unwrap().unwrap();
+        assert_eq!(addr, GuestPhysAddr::new(0x1000));
+        assert!(data.iter().all(|byte| *byte == 0));
+        let (addr, data) = reader.next_page().unwrap().unwrap();
+        assert_eq!(addr, GuestPhysAddr::new(0x2000));
+        assert_eq!(&data[..16], b"0123456789abcdef");
+        assert!(data[16..].iter().all(|byte| *byte == 0));
+        let (addr, data) = reader.next_page().unwrap().unwrap();
+        assert_eq!(addr, GuestPhysAddr::new(0x3000));
+        assert_eq!(data, &page[..]);
+        assert_eq!(reader.time(), None);
+        assert!(reader.next_page().unwrap().is_none());
+        assert_eq!(reader.time(), Some(time));
+    }
+
+    #[test]
+    fn test_invalid_stream() {
+        assert!(PageStreamReader::new(b"MYTHSNAP").is_err());
+        let mut out = vec![];
+        let writer = PageStreamWriter::new(&mut out);
+        writer.finish(&mut out);
+        out.truncate(out.len() - 1);
+        let mut reader = PageStreamReader::new(&out).unwrap();
+        assert!(reader.next_page().is_err());
+    }
+
+    #[test]
+    fn test_save_restore() {
+        let mut space = GuestAddressSpace::new().unwrap();
+        space.map_new_frame(GuestPhysAddr::new(0x0), false).unwrap();
+        space
+            .map_new_frame(GuestPhysAddr::new(0x5000), false)
+            .unwrap();
+        let mut frame =
+            space.find_host_frame(GuestPhysAddr::new(0x5000)).unwrap();
+        let data = unsafe { frame.as_mut_array() };
+        data[..4].copy_from_slice(b"test");
+
+        let mut out = vec![];
+        let stats = save_pages(&space, &mut out);
+        assert_eq!((stats.pages, stats.zero_pages), (2, 1));
+
+        let mut restored = GuestAddressSpace::new().unwrap();
+        let (stats, time) = restore_pages(&mut restored, &out).unwrap();
+        assert_eq!(stats.pages, 2);
+        assert_eq!(time, None);
+        assert_eq!(stats.bytes, out.len() as u64);
+        let frame = restored
+            .find_host_frame(GuestPhysAddr::new(0x5000))
+            .unwrap();
+        assert_eq!(&unsafe { frame.as_array() }[..4], b"test");
+        assert_eq!(restored.mappings().len(), 2);
+
+        let key = SealKey::new([0x42; seal::KEY_SIZE]);
+        let mut sealed = vec![];
+        let mut sealer =
+            Sealer::with_nonce(&key, [1; seal::NONCE_SIZE], &mut sealed);
+        let start = sealed.len();
+        save_pages(&space, &mut sealed);
+        sealer.seal(&mut sealed[start..]);
+        sealer.finish(&mut sealed);
+        let mut restored = GuestAddressSpace::new().unwrap();
+        assert!(restore_pages(&mut restored, &sealed).is_err());
+        let (stats, _) =
+            restore_sealed_pages(&mut restored, &sealed, &key).unwrap();
+        assert_eq!(stats.pages, 2);
+    }
+}
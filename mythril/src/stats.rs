@@ -0,0 +1,259 @@
+//! Machine-readable statistics
+//!
+//! The counters of a VM and each of its vCPUs are exported in a compact,
+//! self-describing binary format, so external monitoring agents can scrape
+//! them without parsing the output of the management console. A guest can
+//! retrieve the statistics of its own VM with the `HC_MYTHRIL_STATS`
+//! hypercall, and the `statsdump` console command prints those of any VM
+//! (hex encoded).
+//!
+//! All integers are little-endian. The export starts with a header:
+//!
+//! ```text
+//! magic: "MYST" | version: u16 | record count: u16
+//! ```
+//!
+//! followed by the records. Each record starts with
+//!
+//! ```text
+//! kind: u8 (0 for a VM, 1 for a vCPU) | id: u32 | field count: u16
+//! ```
+//!
+//! where the id is the VM id or the APIC ID of the vCPU, and is followed by
+//! its fields:
+//!
+//! ```text
+//! name length: u8 | name (ASCII) | value: u64
+//! ```
+//!
+//! Consumers should ignore the records and fields they do not know, so new
+//! counters can be added without changing the version.
+
+use crate::error::{Error, Result};
+use crate::health;
+use crate::vm::{self, VirtualMachine};
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, LittleEndian};
+use core::fmt;
+
+/// The magic number at the start of an export
+pub const STATS_MAGIC: [u8; 4] = *b"MYST";
+
+/// The version of the export format
+pub const STATS_VERSION: u16 = 1;
+
+const HEADER_SIZE: usize = 8;
+const RECORD_HEADER_SIZE: usize = 7;
+
+/// The kind of object a record describes
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum RecordKind {
+    Vm = 0,
+    VCpu = 1,
+}
+
+/// The counters of a VM or vCPU
+#[derive(Clone, Debug, PartialEq)]
+pub struct StatsRecord {
+    pub kind: RecordKind,
+    pub id: u32,
+    pub fields: Vec<(String, u64)>,
+}
+
+impl StatsRecord {
+    pub fn new(kind: RecordKind, id: u32) -> Self {
+        Self {
+            kind,
+            id,
+            fields: vec![],
+        }
+    }
+
+    /// Add the counter `name` (at most 255 ASCII characters)
+    pub fn field(&mut self, name: &str, value: u64) {
+        self.fields.push((name.to_string(), value));
+    }
+
+    /// The value of the counter `name`, if the record has it
+    pub fn get(&self, name: &str) -> Option<u64> {
+        self.fields
+            .iter()
+            .find(|(field, _)| field == name)
+            .map(|(_, value)| *value)
+    }
+}
+
+/// Serialize `records` in the export format
+pub fn encode(records: &[StatsRecord]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(HEADER_SIZE);
+    out.extend_from_slice(&STATS_MAGIC);
+    out.extend_from_slice(&STATS_VERSION.to_le_bytes());
+    out.extend_from_slice(&(records.len() as u16).to_le_bytes());
+    for record in records {
+        out.push(record.kind as u8);
+        out.extend_from_slice(&record.id.to_le_bytes());
+        out.extend_from_slice(&(record.fields.len() as u16).to_le_bytes());
+        for (name, value) in record.fields.iter() {
+            let name = &name.as_bytes()[..core::cmp::min(name.len(), 255)];
+            out.push(name.len() as u8);
+            out.extend_from_slice(name);
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+    out
+}
+
+// Take `len` bytes from the front of `data`
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if data.len() < len {
+        return Err(Error::InvalidValue("Truncated stats export".into()));
+    }
+    let (head, tail) = data.split_at(len);
+    *data = tail;
+    Ok(head)
+}
+
+/// Parse an export produced by `encode`, skipping records of unknown kinds
+pub fn decode(mut data: &[u8]) -> Result<Vec<StatsRecord>> {
+    let header = take(&mut data, HEADER_SIZE)?;
+    if header[0..4] != STATS_MAGIC
+        || LittleEndian::read_u16(&header[4..6]) != STATS_VERSION
+    {
+        return Err(Error::InvalidValue("Invalid stats export header".into()));
+    }
+    let count = LittleEndian::read_u16(&header[6..8]);
+    let mut records = vec![];
+    for _ in 0..count {
+        let header = take(&mut data, RECORD_HEADER_SIZE)?;
+        let kind = match header[0] {
+            0 => Some(RecordKind::Vm),
+            1 => Some(RecordKind::VCpu),
+            _ => None,
+        };
+        let mut record = StatsRecord::new(
+            kind.unwrap_or(RecordKind::Vm),
+            LittleEndian::read_u32(&header[1..5]),
+        );
+        for _ in 0..LittleEndian::read_u16(&header[5..7]) {
+            let len = take(&mut data, 1)?[0] as usize;
+            let name = String::from_utf8_lossy(take(&mut data, len)?);
+            let value = LittleEndian::read_u64(take(&mut data, 8)?);
+            record.field(&name, value);
+        }
+        if kind.is_some() {
+            records.push(record);
+        }
+    }
+    Ok(records)
+}
+
+/// The records of `vm` and each of its vCPUs
+pub fn vm_records(vm: &VirtualMachine) -> Vec<StatsRecord> {
+    let mut record = StatsRecord::new(RecordKind::Vm, vm.id);
+    record.field("vcpus", vm.config.cpus().len() as u64);
+    record.field("memory_bytes", vm.config.memory() << 20);
+    record.field("reclaimed_pages", vm.guest_space.reclaimed_pages() as u64);
+    record.field("uart_dropped", vm.console.lock().counters().dropped);
+    {
+        let emulation = vm.emulation.lock();
+        let (io, interrupts) =
+            (emulation.io.counters(), emulation.interrupts.counters());
+        record.field("io_throttled", io.throttled);
+        record.field("interrupts_throttled", interrupts.throttled);
+        record.field(
+            "throttle_time_ns",
+            (io.throttle_time + interrupts.throttle_time).as_nanos() as u64,
+        );
+    }
+    let mut records = vec![record];
+
+    for core in vm.config.cpus() {
+        let counters = match health::core_counters(*core) {
+            Some(counters) => counters,
+            None => continue,
+        };
+        let mut record = StatsRecord::new(RecordKind::VCpu, core.raw);
+        record.field("exits", counters.exits);
+        record.field("injections", counters.injections);
+        record.field("pending_interrupts", counters.pending_interrupts);
+        record.field(
+            "steal_time_ns",
+            counters.accounting.exit_time.as_nanos() as u64,
+        );
+        record.field(
+            "guest_time_ns",
+            counters.accounting.guest_time.as_nanos() as u64,
+        );
+        for (reason, count) in counters.exit_reasons {
+            record.field(&format!("exit_reason_{}", reason), count);
+        }
+        records.push(record);
+    }
+    records
+}
+
+/// Export the statistics of `vm`
+pub fn export_vm(vm: &VirtualMachine) -> Vec<u8> {
+    encode(&vm_records(vm))
+}
+
+/// The `statsdump` management console command
+pub fn statsdump_command(
+    out: &mut dyn fmt::Write,
+    args: &[&str],
+) -> fmt::Result {
+    let vm_id = match args.get(0).and_then(|vm| vm::find_vm(vm)) {
+        Some(vm_id) if args.len() == 1 => vm_id,
+        _ => return writeln!(out, "usage: statsdump <vm>"),
+    };
+    let vm = match unsafe { vm::get_vm(vm_id) } {
+        Some(vm) => vm,
+        None => return writeln!(out, "No VM {}", vm_id),
+    };
+    let export = export_vm(&vm.read());
+    for line in export.chunks(32) {
+        for byte in line {
+            write!(out, "{:02x}", byte)?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stats_roundtrip() {
+        let mut vm = StatsRecord::new(RecordKind::Vm, 1);
+        vm.field("memory_bytes", 256 << 20);
+        let mut vcpu = StatsRecord::new(RecordKind::VCpu, 2);
+        vcpu.field("exits", 12);
+        vcpu.field("exit_reason_48", 7);
+
+        let export = encode(&[vm.clone(), vcpu.clone()]);
+        assert_eq!(&export[0..8], b"MYST\x01\x00\x02\x00");
+        assert_eq!(export.len(), 8 + (7 + 1 + 12 + 8) + (7 + 2 + 5 + 14 + 16));
+        assert_eq!(decode(&export).unwrap(), vec![vm, vcpu.clone()]);
+        assert_eq!(vcpu.get("exit_reason_48"), Some(7));
+        assert_eq!(vcpu.get("exit_reason_10"), None);
+
+        assert!(decode(&export[..export.len() - 1]).is_err());
+        assert!(decode(b"MYST\x02\x00\x00\x00").is_err());
+    }
+
+    #[test]
+    fn test_stats_unknown_records() {
+        let mut export = encode(&[]);
+        export[6] = 2;
+        // A record of an unknown kind with a single field
+        export.extend_from_slice(&[9, 1, 0, 0, 0, 1, 0, 1, b'x']);
+        export.extend_from_slice(&5u64.to_le_bytes());
+        let vcpu = StatsRecord::new(RecordKind::VCpu, 3);
+        export.extend_from_slice(&encode(&[vcpu.clone()])[HEADER_SIZE..]);
+        assert_eq!(decode(&export).unwrap(), vec![vcpu]);
+    }
+}
@@ -0,0 +1,161 @@
+//! Guest kernel symbol lookup
+//!
+//! A VM may be configured with the symbol map of its kernel (in the
+//! `System.map` format produced by `nm`), so guest instruction pointers in
+//! logs and error messages can be reported as `function+offset`.
+
+use crate::error::{Error, Result};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// The symbols of a guest kernel, sorted by address
+#[derive(Debug, Default)]
+pub struct SymbolMap {
+    symbols: Vec<(u64, String)>,
+}
+
+impl SymbolMap {
+    /// Parse a symbol map in the `System.map` format (one
+    /// `<address> <type> <name>` entry per line)
+    ///
+    /// Absolute symbols do not refer to code or data, so they are ignored.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let text = core::str::from_utf8(data).map_err(|_| {
+            Error::InvalidValue("Symbol map is not valid UTF-8".into())
+        })?;
+
+        let mut symbols = vec![];
+        for (i, line) in text.lines().enumerate() {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let (addr, kind, name) = match fields.as_slice() {
+                [addr, kind, name] | [addr, kind, name, _] => {
+                    (addr, kind, name)
+                }
+                [] => continue,
+                _ => {
+                    return Err(Error::InvalidValue(format!(
+                        "Invalid symbol map entry on line {}",
+                        i + 1
+                    )))
+                }
+            };
+            if kind.eq_ignore_ascii_case("a") {
+                continue;
+            }
+            let addr = u64::from_str_radix(addr, 16).map_err(|_| {
+                Error::InvalidValue(format!(
+                    "Invalid symbol address on line {}",
+                    i + 1
+                ))
+            })?;
+            symbols.push((addr, String::from(*name)));
+        }
+        symbols.sort_by_key(|(addr, _)| *addr);
+        Ok(Self { symbols })
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// The symbol containing `addr` and the offset of `addr` within it
+    ///
+    /// The map does not record the size of symbols, so an address is taken
+    /// to belong to the nearest preceding symbol. Addresses past the last
+    /// symbol (usually a marker such as `_end`) are not resolved.
+    pub fn lookup(&self, addr: u64) -> Option<(&str, u64)> {
+        let index = match self
+            .symbols
+            .binary_search_by_key(&addr, |(start, _)| *start)
+        {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) if index == self.symbols.len() => return None,
+            Err(index) => index - 1,
+        };
+        let (start, name) = &self.symbols[index];
+        Some((name.as_str(), addr - start))
+    }
+
+    /// Format `addr` along with the symbol that contains it (if any)
+    pub fn describe(&self, addr: u64) -> SymbolizedAddress {
+        SymbolizedAddress {
+            addr,
+            symbol: self.lookup(addr),
+        }
+    }
+}
+
+/// An address with the symbol it was resolved to, formatted as
+/// `0xaddr <symbol+0xoffset>`
+pub struct SymbolizedAddress<'a> {
+    addr: u64,
+    symbol: Option<(&'a str, u64)>,
+}
+
+impl<'a> fmt::Display for SymbolizedAddress<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{:x}", self.addr)?;
+        match self.symbol {
+            Some((name, 0)) => write!(f, " <{}>", name),
+            Some((name, offset)) => write!(f, " <{}+0x{:x}>", name, offset),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MAP: &[u8] = b"\
+0000000000000000 A VDSO32_PRELINK
+ffffffff81000000 T _stext
+ffffffff81001000 T do_one_initcall
+ffffffff81000800 t early_idt_handler_common
+ffffffff81002000 T _etext
+";
+
+    #[test]
+    fn test_lookup() {
+        let map = SymbolMap::parse(MAP).unwrap();
+        assert_eq!(map.len(), 4);
+        assert_eq!(map.lookup(0xffffffff81000000), Some(("_stext", 0)));
+        assert_eq!(
+            map.lookup(0xffffffff81000810),
+            Some(("early_idt_handler_common", 0x10))
+        );
+        assert_eq!(
+            map.lookup(0xffffffff81001fff),
+            Some(("do_one_initcall", 0xfff))
+        );
+        assert_eq!(map.lookup(0x1000), None);
+        assert_eq!(map.lookup(0xffffffff81002001), None);
+    }
+
+    #[test]
+    fn test_describe() {
+        let map = SymbolMap::parse(MAP).unwrap();
+        assert_eq!(
+            format!("{}", map.describe(0xffffffff81001010)),
+            "0xffffffff81001010 <do_one_initcall+0x10>"
+        );
+        assert_eq!(
+            format!("{}", map.describe(0xffffffff81000000)),
+            "0xffffffff81000000 <_stext>"
+        );
+        assert_eq!(format!("{}", map.describe(0x1000)), "0x1000");
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(SymbolMap::parse(b"ffffffff81000000 T\n").is_err());
+        assert!(SymbolMap::parse(b"xyz T _stext\n").is_err());
+        assert!(SymbolMap::parse(b"\n\n").unwrap().is_empty());
+    }
+}
@@ -4,12 +4,14 @@
 //!
 //! This module contains types and traits related to time keeping in
 //! Mythril. Note that this does not include _date_ information, only
-//! abstract system clock, counter, and timer information.
+//! abstract system clock, counter, and timer information (and the wall
+//! clock time the system started at, see `physdev::rtc` for dates).
 
 use crate::apic;
 use crate::error::Result;
 use crate::interrupt;
 use crate::lock::ro_after_init::RoAfterInit;
+use crate::migrate;
 use crate::percore;
 use crate::tsc;
 use crate::vcpu;
@@ -28,6 +30,7 @@ declare_per_core! {
 static TIME_SRC: RoAfterInit<&'static dyn TimeSource> =
     RoAfterInit::uninitialized();
 static START_TIME: RoAfterInit<Instant> = RoAfterInit::uninitialized();
+static WALL_CLOCK_BASE: RoAfterInit<i64> = RoAfterInit::uninitialized();
 
 /// Determine the best available global system `TimeSource` and calibrate it.
 pub unsafe fn init_global_time() -> Result<()> {
@@ -37,6 +40,22 @@ pub unsafe fn init_global_time() -> Result<()> {
     Ok(())
 }
 
+/// Set the wall clock time at system start, from the current wall clock
+/// time (in seconds since the unix epoch).
+pub unsafe fn init_wall_clock(now: i64) {
+    RoAfterInit::init(&WALL_CLOCK_BASE, now - uptime().as_secs() as i64);
+}
+
+/// The wall clock time at system start (in seconds since the unix epoch),
+/// if it is known.
+pub fn wall_clock_base() -> Option<i64> {
+    if RoAfterInit::is_initialized(&WALL_CLOCK_BASE) {
+        Some(*WALL_CLOCK_BASE)
+    } else {
+        None
+    }
+}
+
 /// Get the current instant from the global system `TimeSource`.
 pub fn now() -> Instant {
     TIME_SRC.now()
@@ -48,12 +67,18 @@ pub fn system_start_time() -> Instant {
     *START_TIME
 }
 
+/// The time elapsed since the system was started
+pub fn uptime() -> Duration {
+    now() - system_start_time()
+}
+
 /// Returns whether the global system `TimeSource` has be initialized.
 pub fn is_global_time_ready() -> bool {
     RoAfterInit::is_initialized(&TIME_SRC)
 }
 
-fn frequency() -> u64 {
+/// The frequency of the global system `TimeSource` (in ticks per second)
+pub fn frequency() -> u64 {
     TIME_SRC.frequency()
 }
 
@@ -243,10 +268,26 @@ pub unsafe fn get_timer_wheel_mut() -> &'static mut TimerWheel {
         .expect("TimerWheel has not been initialized")
 }
 
+/// Remove the current core's TimerWheel, so it can be installed on the
+/// core its vCPU is moving to (see `migrate`)
+pub unsafe fn take_timer_wheel() -> Option<TimerWheel> {
+    get_per_core_mut!(TIMER_WHEEL).take()
+}
+
+/// Make `wheel` the current core's TimerWheel (replacing any existing
+/// wheel), and arm the local APIC for its soonest timer
+pub unsafe fn install_timer_wheel(mut wheel: TimerWheel) {
+    wheel.update_interrupt_timer();
+    *get_per_core_mut!(TIMER_WHEEL) = Some(wheel);
+}
+
 /// Timer identifier that may be used to cancel a running timer
 #[derive(Eq, PartialEq, PartialOrd, Ord, Clone, Debug)]
 pub struct TimerId {
     timer_id: u64,
+
+    // The vCPU whose wheel holds the timer. The wheel moves with the vCPU,
+    // so this is not always the core the timer is running on.
     core_id: percore::CoreId,
 }
 
@@ -328,9 +369,15 @@ impl TimerWheel {
         }
     }
 
+    /// Returns true if any timer in this wheel has elapsed (so its
+    /// interrupt is waiting to be delivered)
+    pub fn has_elapsed(&self) -> bool {
+        self.timers.values().any(|timer| timer.elapsed())
+    }
+
     /// Determines if a given TimerId is associated with this wheel
     pub fn is_local_timer(&self, id: &TimerId) -> bool {
-        id.core_id == percore::read_core_id()
+        id.core_id == migrate::current_vcpu()
     }
 
     /// Register a timer with this TimerWheel
@@ -338,7 +385,7 @@ impl TimerWheel {
         let counter = self.counter;
         let id = TimerId {
             timer_id: counter,
-            core_id: percore::read_core_id(),
+            core_id: migrate::current_vcpu(),
         };
         self.timers.insert(id.clone(), timer.start());
         self.counter = self.counter.wrapping_add(1);
@@ -365,6 +412,31 @@ impl TimerWheel {
         self.update_interrupt_timer();
     }
 
+    /// Delay every timer in this wheel by `stopped` (the time the guest
+    /// did not see while its vCPU was stopped)
+    ///
+    /// Periodic timers that would elapse more than once skip the periods
+    /// that were missed, so each timer elapses at most once.
+    pub fn adjust_for_stop(&mut self, stopped: Duration) {
+        let now = now();
+        for timer in self.timers.values_mut() {
+            timer.started += stopped;
+            if !timer.is_periodic()
+                || now <= timer.started
+                || timer.duration == Duration::default()
+            {
+                continue;
+            }
+            let missed =
+                (now - timer.started).as_nanos() / timer.duration.as_nanos();
+            if missed > 1 {
+                let skipped = timer.duration.as_nanos() * (missed - 1);
+                timer.started += Duration::from_nanos(skipped as u64);
+            }
+        }
+        self.update_interrupt_timer();
+    }
+
     /// Returns an iterator over the timers in this wheel
     pub fn iter<'a>(&'a self) -> impl Iterator<Item = &RunningTimer> + 'a {
         self.timers.values()
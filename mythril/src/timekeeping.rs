@@ -0,0 +1,231 @@
+//! Guest time continuity when a vCPU stops running
+//!
+//! A vCPU stops running while it is paused (see `watch`), and between a
+//! snapshot of its VM and the restore of that snapshot (see `snapshot`).
+//! When it runs again, the clocks the guest can observe are adjusted
+//! together, so the guest either sees time jump forward once by the time
+//! it was stopped, or sees no time pass at all (see `TimePolicy`):
+//!
+//! - the TSC, through the TSC offset of the vCPU
+//! - the timers on the timer wheel of the core (the LAPIC, PIT and RTC
+//!   timers), which are delayed by the time the guest did not see. Periodic
+//!   timers elapse at most once on resume, rather than once for each period
+//!   that was missed.
+//! - the time read by the emulated PIT and RTC (see `guest_uptime`)
+//!
+//! Mythril does not provide a paravirtual clock, so there are no pvclock
+//! fields to update.
+
+use crate::error::{Error, Result};
+use crate::time;
+use crate::vcpu::VCpu;
+use crate::vmcs::VmcsField;
+use crate::{declare_per_core, get_per_core, get_per_core_mut};
+use core::convert::TryInto;
+use core::time::Duration;
+
+/// The size of an encoded `TimeState`
+pub const TIME_STATE_SIZE: usize = 24;
+
+// The encoding of an unknown wall clock time
+const NO_WALL_CLOCK: i64 = i64::MIN;
+
+declare_per_core! {
+    // The total time the vCPU on this core did not see
+    static mut STOPPED: Duration = Duration::from_secs(0);
+}
+
+/// How the guest sees the time its vCPU was stopped
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimePolicy {
+    /// Guest time jumps forward by the time the vCPU was stopped, so it
+    /// stays in step with the wall clock
+    Jump,
+
+    /// Guest time does not advance while the vCPU is stopped
+    Pause,
+}
+
+impl Default for TimePolicy {
+    fn default() -> Self {
+        TimePolicy::Jump
+    }
+}
+
+/// The guest time of a vCPU when it stopped running
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TimeState {
+    /// The guest TSC
+    pub tsc: u64,
+
+    /// The frequency of the guest TSC (in ticks per second)
+    pub tsc_frequency: u64,
+
+    /// The wall clock time (in seconds since the unix epoch), if known
+    pub wall_clock: Option<i64>,
+}
+
+/// The adjustments that make the guest time continuous after its vCPU was
+/// stopped (see `apply`)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimeFixup {
+    /// The new TSC offset of the vCPU
+    pub tsc_offset: u64,
+
+    /// The time the guest does not see
+    pub stopped: Duration,
+}
+
+fn read_host_tsc() -> u64 {
+    unsafe { x86::time::rdtsc() }
+}
+
+/// The current wall clock time (in seconds since the unix epoch), if known
+pub fn wall_clock_now() -> Option<i64> {
+    time::wall_clock_base().map(|base| base + time::uptime().as_secs() as i64)
+}
+
+/// The time elapsed since the system was started, less the time the vCPU
+/// on the current core did not see
+///
+/// This is the time that emulated devices should present to the guest.
+pub fn guest_uptime() -> Duration {
+    let stopped = *get_per_core!(STOPPED);
+    time::uptime()
+        .checked_sub(stopped)
+        .unwrap_or_else(Duration::default)
+}
+
+impl TimeState {
+    /// The current guest time of `vcpu`, which must be the vCPU of the
+    /// current core
+    pub fn capture(vcpu: &VCpu) -> Result<Self> {
+        let offset = vcpu.vmcs.read_field(VmcsField::TscOffset)?;
+        Ok(Self {
+            tsc: read_host_tsc().wrapping_add(offset),
+            tsc_frequency: time::frequency(),
+            wall_clock: wall_clock_now(),
+        })
+    }
+
+    /// Encode this state (e.g., for a snapshot)
+    pub fn to_bytes(&self) -> [u8; TIME_STATE_SIZE] {
+        let mut bytes = [0u8; TIME_STATE_SIZE];
+        bytes[0..8].copy_from_slice(&self.tsc.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.tsc_frequency.to_le_bytes());
+        bytes[16..24].copy_from_slice(
+            &self.wall_clock.unwrap_or(NO_WALL_CLOCK).to_le_bytes(),
+        );
+        bytes
+    }
+
+    /// Decode a state encoded by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != TIME_STATE_SIZE {
+            return Err(Error::InvalidValue(format!(
+                "Invalid time state length: {}",
+                bytes.len()
+            )));
+        }
+        let field = |i: usize| bytes[i..i + 8].try_into().unwrap();
+        let wall_clock = i64::from_le_bytes(field(16));
+        Ok(Self {
+            tsc: u64::from_le_bytes(field(0)),
+            tsc_frequency: u64::from_le_bytes(field(8)),
+            wall_clock: if wall_clock == NO_WALL_CLOCK {
+                None
+            } else {
+                Some(wall_clock)
+            },
+        })
+    }
+
+    /// The adjustments for a vCPU that stopped in this state and was
+    /// stopped for `elapsed`, given the current host TSC
+    ///
+    /// The guest TSC keeps its frequency, as the TSC is not scaled. A guest
+    /// restored on a host with a different TSC frequency will see its TSC
+    /// run faster or slower.
+    pub fn fixup(
+        &self,
+        policy: TimePolicy,
+        host_tsc: u64,
+        elapsed: Duration,
+    ) -> TimeFixup {
+        let seen = match policy {
+            TimePolicy::Jump => elapsed,
+            TimePolicy::Pause => Duration::default(),
+        };
+        let ticks =
+            self.tsc_frequency as u128 * seen.as_nanos() / 1_000_000_000;
+        let tsc = self.tsc.wrapping_add(ticks as u64);
+        TimeFixup {
+            tsc_offset: tsc.wrapping_sub(host_tsc),
+            stopped: elapsed - seen,
+        }
+    }
+
+    /// The adjustments for a vCPU restored in this state (e.g., from a
+    /// snapshot or a migration stream)
+    ///
+    /// The time the vCPU was stopped is measured by the wall clock, so it
+    /// is taken to be zero if either wall clock time is unknown.
+    pub fn restore_fixup(&self, policy: TimePolicy) -> TimeFixup {
+        let elapsed = match (self.wall_clock, wall_clock_now()) {
+            (Some(then), Some(now)) if now > then => {
+                Duration::from_secs((now - then) as u64)
+            }
+            _ => Duration::default(),
+        };
+        self.fixup(policy, read_host_tsc(), elapsed)
+    }
+}
+
+/// Apply `fixup` to `vcpu`, which must be the vCPU of the current core
+pub fn apply(vcpu: &mut VCpu, fixup: &TimeFixup) -> Result<()> {
+    vcpu.vmcs
+        .write_field(VmcsField::TscOffset, fixup.tsc_offset)?;
+    unsafe { time::get_timer_wheel_mut() }.adjust_for_stop(fixup.stopped);
+    *get_per_core_mut!(STOPPED) += fixup.stopped;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_time_state_encoding() {
+        let state = TimeState {
+            tsc: 0x1234_5678_9abc,
+            tsc_frequency: 2_000_000_000,
+            wall_clock: Some(1_600_000_000),
+        };
+        assert_eq!(TimeState::from_bytes(&state.to_bytes()).unwrap(), state);
+        let state = TimeState {
+            wall_clock: None,
+            ..state
+        };
+        assert_eq!(TimeState::from_bytes(&state.to_bytes()).unwrap(), state);
+        assert!(TimeState::from_bytes(&[0; 16]).is_err());
+    }
+
+    #[test]
+    fn test_fixup() {
+        let state = TimeState {
+            tsc: 5_000,
+            tsc_frequency: 1_000_000,
+            wall_clock: None,
+        };
+        let elapsed = Duration::from_millis(2);
+
+        let fixup = state.fixup(TimePolicy::Jump, 3_000, elapsed);
+        assert_eq!(fixup.tsc_offset, 5_000 + 2_000 - 3_000);
+        assert_eq!(fixup.stopped, Duration::default());
+
+        // The guest TSC may be behind the host TSC
+        let fixup = state.fixup(TimePolicy::Pause, 9_000, elapsed);
+        assert_eq!(fixup.tsc_offset.wrapping_add(9_000), 5_000);
+        assert_eq!(fixup.stopped, elapsed);
+    }
+}
@@ -1,26 +1,50 @@
 use crate::apic;
+use crate::background;
+use crate::chaos;
+use crate::console;
+use crate::crash;
 use crate::emulate;
+use crate::emulate::cpuid::CpuidTable;
 use crate::error::{self, Error, Result};
+use crate::evtchn;
+use crate::exitpolicy::{ExitAction, ExitPolicy};
+use crate::health;
+use crate::hostmem::{HostPage, HostRegionKind};
 use crate::interrupt;
+use crate::interrupt::pending::{PendingEvent, PendingEvents};
+use crate::interrupt::posted::PostedInterruptDescriptor;
 use crate::ioapic;
-use crate::memory::Raw4kPage;
+use crate::iommu;
+use crate::lifecycle;
+use crate::memory::{
+    self, EptTableFlags, GuestPhysAddr, HostPhysAddr, HostPhysFrame, Raw4kPage,
+};
+use crate::migrate;
+use crate::pcid;
 use crate::percore;
+use crate::physdev;
+use crate::pvh;
+use crate::ratelimit;
 use crate::registers::{GdtrBase, IdtrBase};
+use crate::rng;
+use crate::sched;
 use crate::time;
+use crate::virtdev::watchdog::WatchdogAction;
 use crate::vm::VirtualMachine;
-use crate::{virtdev, vm, vmcs, vmexit, vmx};
+use crate::vmexit::ExtendedExitInformation;
+use crate::{virtdev, vm, vmcheck, vmcs, vmexit, vmx, watch};
 use alloc::boxed::Box;
-use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::mem;
 use core::pin::Pin;
+use core::time::Duration;
 use spin::RwLock;
 use x86::controlregs::{cr0, cr3, cr4};
 use x86::msr;
 
 extern "C" {
-    pub fn vmlaunch_wrapper() -> u64;
+    pub fn vmlaunch_wrapper(rbx: u64, rdx: u64) -> u64;
     static GDT64_CODE: u64;
     static GDT64_DATA: u64;
 }
@@ -34,16 +58,22 @@ pub fn mp_entry_point() -> ! {
             .expect("Failed to initialize per-core timer wheel");
     }
 
+    let id = percore::read_core_id();
     let vm = unsafe {
-        let id = percore::read_core_id();
         vm::get_vm_for_core_id(id)
             .expect(&format!("Failed to find VM associated with {}", id))
     };
+    let (vm_id, params) = {
+        let vm = vm.read();
+        (vm.id, vm.config.sched_params())
+    };
+    sched::admit(id, sched::SchedEntity { vm_id, vcpu: id }, params)
+        .expect("Failed to admit vcpu to the core scheduler");
     let vcpu = VCpu::new(vm).expect("Failed to create vcpu");
     vcpu.launch().expect("Failed to launch vm")
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 #[repr(u8)]
 pub enum InjectedInterruptType {
     ExternalInterrupt = 0,
@@ -65,10 +95,52 @@ pub enum InjectedInterruptType {
 pub struct VCpu {
     pub vm: Arc<RwLock<VirtualMachine>>,
     pub vmcs: vmcs::ActiveVmcs,
-    pending_interrupts: BTreeMap<u8, InjectedInterruptType>,
+    pending_events: PendingEvents,
+    pic: Option<Arc<RwLock<virtdev::pic::Pic8259>>>,
+    ioapic: Option<Arc<RwLock<virtdev::ioapic::IoApic>>>,
+    posted_interrupts: Box<PostedInterruptDescriptor>,
+    virtual_apic: Option<HostPage>,
+    // The current TPR threshold, if the processor exits when the guest
+    // lowers its task priority below it
+    tpr_threshold: Option<u8>,
+    guest_msrs: Box<vmcs::MsrArea>,
+    shadow: Option<vmcs::VmcsShadow>,
+    host_msrs: Box<vmcs::MsrArea>,
+    apic_timer: virtdev::lapic::ApicTimer,
+    idle_poll: Option<Duration>,
+    exit_policy: ExitPolicy,
+    // The core this vCPU was created for, which is its identity (and APIC
+    // id) wherever it runs (see `migrate`)
+    id: percore::CoreId,
+    // The CPUID table of the VM, as of the last exit handled in full
+    cpuid: Arc<CpuidTable>,
     stack: Vec<u8>,
+    // The core this vCPU moves to at the end of the current exit
+    migration: Option<percore::CoreId>,
+    // The rate of the VMX-preemption timer (the TSC rate divided by 2^n),
+    // if the processor has one
+    preemption_timer_shift: Option<u64>,
+    // When this vCPU was last charged for its time (see `sched::run_next`)
+    sched_charged: time::Instant,
+    // True while this vCPU is an AP waiting for a startup IPI, so no
+    // events are injected (see `initialize_pvh_entry`)
+    waiting_for_sipi: bool,
 }
 
+// The guest physical address of the local APIC registers
+const GUEST_APIC_BASE: u64 = 0xfee00000;
+
+// The value of the version register of the virtual local APIC
+const VIRTUAL_APIC_VERSION: u32 = 0x00050014;
+
+// The guest activity states of a running, a halted and a waiting processor
+const GUEST_ACTIVITY_ACTIVE: u64 = 0;
+const GUEST_ACTIVITY_HLT: u64 = 1;
+const GUEST_ACTIVITY_WAIT_FOR_SIPI: u64 = 3;
+
+// The most input read from the host serial port for a single interrupt
+const MAX_SERIAL_INPUT: usize = 16;
+
 impl VCpu {
     /// Create a new `VCpu` assocaited with the given `VirtualMachine`
     ///
@@ -76,17 +148,48 @@ impl VCpu {
     /// address on to the per-core host stack so it can be retrieved on
     /// VMEXIT.
     pub fn new(vm: Arc<RwLock<VirtualMachine>>) -> Result<Pin<Box<Self>>> {
+        // The host state of the VMCS is taken from the current CR3 and CR4
+        unsafe { pcid::init() };
         let vmx = vmx::Vmx::enable()?;
         let vmcs = vmcs::Vmcs::new()?.activate(vmx)?;
 
         // Allocate 1MB for host stack space
         let stack = vec![0u8; 1024 * 1024];
 
+        let idle_poll = vm.read().config.idle_poll();
+        let exit_policy = vm.read().config.exit_policy().clone();
+        let apic_timer_frequency = vm.read().config.apic_timer_frequency();
+        let invlpg_exiting = vm.read().config.invlpg_exiting();
+        let debugctl_policy = vm.read().config.debugctl_policy();
+        let pic = vm.read().config.pic().cloned();
+        let ioapic = vm.read().config.ioapic().cloned();
+        let cpuid = vm.read().cpuid.clone();
+
         let mut vcpu = Box::pin(Self {
             vm: vm,
             vmcs: vmcs,
             stack: stack,
-            pending_interrupts: BTreeMap::new(),
+            pending_events: PendingEvents::new(),
+            pic: pic,
+            ioapic: ioapic,
+            posted_interrupts: Box::new(PostedInterruptDescriptor::new(
+                interrupt::POSTED_INTERRUPT_VECTOR,
+                apic::get_local_apic().id().raw,
+            )),
+            virtual_apic: None,
+            tpr_threshold: None,
+            guest_msrs: Box::new(vmcs::MsrArea::new()),
+            shadow: None,
+            host_msrs: Box::new(vmcs::MsrArea::new()),
+            apic_timer: virtdev::lapic::ApicTimer::new(apic_timer_frequency),
+            idle_poll: idle_poll,
+            exit_policy: exit_policy,
+            id: percore::read_core_id(),
+            cpuid: cpuid,
+            migration: None,
+            preemption_timer_shift: None,
+            sched_charged: time::now(),
+            waiting_for_sipi: false,
         });
 
         // All VCpus in a VM must share the same address space (except for the
@@ -94,8 +197,7 @@ impl VCpu {
         let eptp = vcpu.vm.read().guest_space.eptp();
         vcpu.vmcs.write_field(vmcs::VmcsField::EptPointer, eptp)?;
 
-        let stack_base = vcpu.stack.as_ptr() as u64 + vcpu.stack.len() as u64
-            - mem::size_of::<*const Self>() as u64;
+        let stack_base = vcpu.stack_base();
 
         // 'push' the address of this VCpu to the host stack for the vmexit
         let raw_vcpu: *mut Self = (&mut *vcpu) as *mut Self;
@@ -105,22 +207,419 @@ impl VCpu {
 
         Self::initialize_host_vmcs(&mut vcpu.vmcs, stack_base)?;
         Self::initialize_guest_vmcs(&mut vcpu.vmcs)?;
-        Self::initialize_ctrl_vmcs(&mut vcpu.vmcs)?;
+        Self::initialize_ctrl_vmcs(&mut vcpu.vmcs, debugctl_policy)?;
+        vcpu.initialize_msr_areas()?;
+        vcpu.initialize_tpr_shadow()?;
+        vcpu.initialize_posted_interrupts()?;
+        vcpu.initialize_heartbeat()?;
+        vcpu.initialize_shadow_vmcs()?;
+
+        // Background tasks only run while polling for wakeup events, so they
+        // are only started when the VM has requested idle polling
+        if vcpu.idle_poll.is_some() {
+            background::spawn(Box::new(background::ScrubTask));
+            if vcpu.vm.read().config.lazy_memory() {
+                background::spawn(Box::new(background::PrefaultTask::new(
+                    vcpu.vm.clone(),
+                )));
+            }
+        }
+
+        // HLT is only intercepted when the VM has requested idle polling.
+        // Otherwise the guest halts the core directly.
+        if vcpu.idle_poll.is_some() {
+            let field = vcpu
+                .vmcs
+                .read_field(vmcs::VmcsField::CpuBasedVmExecControl)?;
+            vcpu.vmcs.write_field(
+                vmcs::VmcsField::CpuBasedVmExecControl,
+                field | vmcs::CpuBasedCtrlFlags::HLT_EXITING.bits(),
+            )?;
+        }
+
+        // With "enable INVPCID" set, INVLPG exiting also causes INVPCID to
+        // exit (see `emulate::invalidate`)
+        if invlpg_exiting {
+            let field = vcpu
+                .vmcs
+                .read_field(vmcs::VmcsField::CpuBasedVmExecControl)?;
+            vcpu.vmcs.write_with_fixed(
+                vmcs::VmcsField::CpuBasedVmExecControl,
+                field | vmcs::CpuBasedCtrlFlags::INVLPG_EXITING.bits(),
+                msr::IA32_VMX_PROCBASED_CTLS,
+            )?;
+        }
+
+        let pvh_entry = vcpu.vm.read().pvh_entry;
+        if let Some(entry) = pvh_entry {
+            let bsp = vcpu.is_bsp();
+            Self::initialize_pvh_entry(&mut vcpu.vmcs, &entry, bsp)?;
+            vcpu.waiting_for_sipi = !bsp;
+        }
 
         Ok(vcpu)
     }
 
+    // Give the guest a shadow of the fields it may read without an exit
+    fn initialize_shadow_vmcs(&mut self) -> Result<()> {
+        let fields = self.vm.read().config.shadow_fields().to_vec();
+        if fields.is_empty() {
+            return Ok(());
+        }
+        let mut shadow = match vmcs::VmcsShadow::new(
+            &fields,
+            self.vmcs.vmx.capabilities(),
+        ) {
+            Ok(shadow) => shadow,
+            Err(Error::NotSupported) => {
+                info!("VMCS shadowing is not supported, so the shadow fields are ignored");
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        self.vmcs.enable_shadowing(&mut shadow)?;
+        self.vmcs.sync_shadow(&mut shadow)?;
+        self.shadow = Some(shadow);
+        Ok(())
+    }
+
+    /// The identity of this vCPU (the core it was created for)
+    pub fn id(&self) -> percore::CoreId {
+        self.id
+    }
+
+    /// The CPUID table of the VM, as of the last exit handled in full
+    pub fn cpuid(&self) -> &CpuidTable {
+        &self.cpuid
+    }
+
+    /// The number of interrupts waiting to be injected in to the guest
+    pub fn pending_interrupt_count(&self) -> usize {
+        self.pending_events.len()
+    }
+
     pub fn inject_interrupt(
         &mut self,
         vector: u8,
         kind: InjectedInterruptType,
     ) {
-        self.pending_interrupts.insert(vector, kind);
+        self.throttle_emulation(true);
+
+        let irq = match kind {
+            InjectedInterruptType::ExternalInterrupt => {
+                virtdev::pic::legacy_irq(vector)
+            }
+            _ => None,
+        };
+
+        if let Some(irq) = irq {
+            if self.raise_gsi(irq as u32) {
+                return;
+            }
+        }
+        self.pending_events.push(PendingEvent::new(vector, kind));
+    }
+
+    /// Raise an interrupt on `gsi`, returning false if the guest has not
+    /// routed it
+    ///
+    /// The interrupt is delivered through the I/O APIC if the guest has
+    /// unmasked the entry of the GSI, or otherwise (for the legacy IRQs)
+    /// through the PIC once the guest has initialized it.
+    pub fn raise_gsi(&mut self, gsi: u32) -> bool {
+        if self.route_gsi(gsi) {
+            return true;
+        }
+        if gsi >= virtdev::pic::LEGACY_IRQS as u32 {
+            return false;
+        }
+        match self.pic.as_ref() {
+            Some(pic) if pic.read().is_initialized() => {
+                pic.write().request(gsi as u8);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Delay this vCPU while the guest is beyond its limit on emulated port
+    // and MMIO accesses (or, if `interrupt`, on injected interrupts). As
+    // for console output, the VM lock is not held while throttled.
+    fn throttle_emulation(&self, interrupt: bool) {
+        let wait = {
+            let vm = self.vm.read();
+            let mut limiter = vm.emulation.lock();
+            let throttle = if interrupt {
+                &mut limiter.interrupts
+            } else {
+                &mut limiter.io
+            };
+            throttle.admit(time::uptime())
+        };
+        if wait > Duration::from_secs(0) {
+            time::busy_wait(wait);
+        }
+    }
+
+    // Deliver an interrupt on `gsi` through the I/O APIC, to the vCPU of
+    // the destination. Returns false if the entry of the GSI is masked.
+    fn route_gsi(&mut self, gsi: u32) -> bool {
+        let route =
+            match self.ioapic.as_ref().and_then(|io| io.read().route(gsi)) {
+                Some(route) => route,
+                None => return false,
+            };
+        if let virtdev::ioapic::IoApicDestination::Physical(apic_id) =
+            route.destination
+        {
+            let core_id = percore::CoreId::from(apic_id as u32);
+            if core_id != migrate::current_vcpu()
+                && self.vm.read().config.cpus().contains(&core_id)
+            {
+                let msg =
+                    vm::VirtualMachineMsg::Interrupt(route.vector, route.kind);
+                match vm::send_vm_msg_core(msg, core_id) {
+                    Ok(()) => return true,
+                    Err(e) => warn!(
+                        "Failed to route GSI {} to core {}: {:?}",
+                        gsi, apic_id, e
+                    ),
+                }
+            }
+        }
+        self.pending_events
+            .push(PendingEvent::new(route.vector, route.kind));
+        true
+    }
+
+    // Returns true if the virtual PIC has an interrupt to deliver
+    fn pic_pending(&self) -> bool {
+        self.pic
+            .as_ref()
+            .map(|pic| pic.read().has_pending())
+            .unwrap_or(false)
+    }
+
+    // The task priority used to hold back injected interrupts, which is 0
+    // unless the processor exits when the guest lowers it
+    fn task_priority(&self) -> u8 {
+        match (self.tpr_threshold, self.virtual_apic.as_ref()) {
+            (Some(_), Some(page)) => page.0[virtdev::lapic::TPR],
+            _ => 0,
+        }
+    }
+
+    // Returns true if a pending event is masked by the task priority
+    fn is_masked(event: &PendingEvent, tpr: u8) -> bool {
+        event.is_maskable()
+            && virtdev::lapic::is_masked_by_tpr(event.vector, tpr)
+    }
+
+    // Returns true if there is an event that is not held back by the task
+    // priority (though the guest may still block it for now)
+    fn has_deliverable_interrupt(&self) -> bool {
+        let tpr = self.task_priority();
+        self.pending_events
+            .iter()
+            .any(|event| !Self::is_masked(event, tpr))
+            || self.pic_pending()
+    }
+
+    // Set the TPR threshold so the guest exits when it lowers its task
+    // priority enough to accept a held back interrupt
+    fn update_tpr_threshold(&mut self) -> Result<()> {
+        let current = match self.tpr_threshold {
+            Some(current) => current,
+            None => return Ok(()),
+        };
+        let tpr = self.task_priority();
+        let threshold = virtdev::lapic::tpr_threshold(
+            self.pending_events.interrupt_vectors(),
+            tpr,
+        );
+        if threshold != current {
+            self.vmcs
+                .write_field(vmcs::VmcsField::TprThreshold, threshold as u64)?;
+            self.tpr_threshold = Some(threshold);
+        }
+        Ok(())
+    }
+
+    // Take the next event to inject, preferring those queued directly
+    // over interrupts from the virtual PIC. Events the guest is blocking
+    // (given its `interruptibility` and whether RFLAGS.IF is set) or that
+    // are masked by its task priority are skipped.
+    fn next_event(
+        &mut self,
+        interruptibility: vmcs::InterruptibilityState,
+        interrupts_enabled: bool,
+    ) -> Option<PendingEvent> {
+        let tpr = self.task_priority();
+        let shadowed = interruptibility.intersects(
+            vmcs::InterruptibilityState::STI_BLOCKING
+                | vmcs::InterruptibilityState::MOV_SS_BLOCKING,
+        );
+        let nmi_blocked = shadowed
+            || interruptibility
+                .contains(vmcs::InterruptibilityState::NMI_BLOCKING);
+        let interrupts_blocked = shadowed || !interrupts_enabled;
+
+        let next = self.pending_events.take(|event| match event.kind {
+            InjectedInterruptType::NonMaskableInterrupt => !nmi_blocked,
+            InjectedInterruptType::ExternalInterrupt => {
+                !interrupts_blocked && !Self::is_masked(event, tpr)
+            }
+            _ => true,
+        });
+        if next.is_some() || interrupts_blocked {
+            return next;
+        }
+        self.pic
+            .as_ref()
+            .and_then(|pic| pic.write().acknowledge())
+            .map(|vector| {
+                PendingEvent::new(
+                    vector,
+                    InjectedInterruptType::ExternalInterrupt,
+                )
+            })
+    }
+
+    /// Post the MSIs from the assigned host device `source_id` in
+    /// `segment` to this `VCpu` with the given guest `vector`
+    ///
+    /// Returns the message that must be programmed in the MSI capability of
+    /// the device.
+    pub fn post_device_interrupt(
+        &self,
+        segment: u16,
+        source_id: u16,
+        vector: u8,
+    ) -> Result<iommu::MsiMessage> {
+        iommu::post_device_interrupt(
+            segment,
+            source_id,
+            vector,
+            &self.posted_interrupts,
+        )
+    }
+
+    // Inject any interrupts that were posted while this core was not in
+    // guest mode (or that the processor could not deliver directly)
+    fn inject_posted_interrupts(&mut self) {
+        for vector in self.posted_interrupts.take_pending() {
+            self.inject_interrupt(
+                vector,
+                InjectedInterruptType::ExternalInterrupt,
+            );
+        }
+    }
+
+    // Returns true if there is an event that should wake a halted guest
+    fn has_wakeup_event(&self) -> bool {
+        !self.pending_events.is_empty()
+            || self.pic_pending()
+            || self.posted_interrupts.is_outstanding()
+            || time::get_timer_wheel().iter().any(|timer| timer.elapsed())
+    }
+
+    // Handle a guest HLT by busy-polling for wakeup events for the
+    // configured window. If nothing arrives, the guest is resumed in the
+    // halted state, so the core halts until the next interrupt.
+    fn poll_idle(&mut self, window: Duration) -> Result<()> {
+        let deadline = time::now() + window;
+        while time::now() < deadline {
+            if self.has_wakeup_event() {
+                return Ok(());
+            }
+            if !background::run_step() {
+                core::sync::atomic::spin_loop_hint();
+            }
+        }
+        if self.has_wakeup_event() {
+            return Ok(());
+        }
+
+        self.vmcs.write_field(
+            vmcs::VmcsField::GuestActivityState,
+            GUEST_ACTIVITY_HLT,
+        )
+    }
+
+    // The initial host stack pointer on VMEXIT (just below the address of
+    // this VCpu)
+    fn stack_base(&self) -> u64 {
+        self.stack.as_ptr() as u64 + self.stack.len() as u64
+            - mem::size_of::<*const Self>() as u64
+    }
+
+    /// Take the core this vCPU was asked to move to (see `migrate`)
+    pub fn take_migration(&mut self) -> Option<percore::CoreId> {
+        self.migration.take()
+    }
+
+    /// Update the parts of the VMCS that depend on the core, after this
+    /// vCPU has moved to the current core (see `migrate`)
+    ///
+    /// The VMCS must already be active on the current core.
+    pub fn rehome(&mut self) -> Result<()> {
+        let stack_base = self.stack_base();
+        Self::initialize_host_vmcs(&mut self.vmcs, stack_base)?;
+        self.host_msrs.set(msr::IA32_KERNEL_GSBASE, unsafe {
+            msr::rdmsr(msr::IA32_KERNEL_GSBASE)
+        })?;
+
+        // The VPID is chosen by core, and this core may have cached
+        // translations under it for another guest
+        let vpid = percore::read_core_id().raw as u16 + 1;
+        self.vmcs
+            .write_field(vmcs::VmcsField::VirtualProcessorId, vpid as u64)?;
+        self.vmcs
+            .vmx
+            .invvpid(vmx::InvVpidMode::SingleContext(vpid))?;
+        let eptp = self.vm.read().guest_space.eptp();
+        self.vmcs.vmx.invept(vmx::InvEptMode::SingleContext(eptp))?;
+
+        self.posted_interrupts
+            .set_destination(apic::get_local_apic().id().raw);
+        Ok(())
+    }
+
+    // Whether this is the bootstrap processor of the VM (its first core)
+    fn is_bsp(&self) -> bool {
+        self.vm.read().config.cpus().first() == Some(&self.id)
     }
 
     /// Begin execution in the guest context for this core
     pub fn launch(self: Pin<Box<Self>>) -> Result<!> {
-        let rflags = unsafe { vmlaunch_wrapper() };
+        let rbx = if self.is_bsp() {
+            self.vm
+                .read()
+                .pvh_entry
+                .map(|entry| entry.start_info as u64)
+                .unwrap_or(0)
+        } else {
+            0
+        };
+
+        // At reset, edx holds the processor signature (family, model and
+        // stepping), which firmware uses to identify the CPU.
+        let rdx =
+            raw_cpuid::native_cpuid::cpuid_count(1, 0).eax as u64 & 0x0fff3fff;
+
+        if self.vm.read().config.start_paused() {
+            let mut guest_cpu =
+                vmexit::GuestCpuState::new(core::ptr::null_mut());
+            guest_cpu.rbx = rbx;
+            guest_cpu.rdx = rdx;
+            watch::pause_at_entry(&self, &guest_cpu)?;
+        }
+
+        if cfg!(debug_assertions) {
+            vmcheck::check_vm_entry(&self.vmcs)?;
+        }
+
+        let rflags = unsafe { vmlaunch_wrapper(rbx, rdx) };
         error::check_vm_insruction(rflags, "Failed to launch vm".into())?;
 
         unreachable!()
@@ -226,14 +725,16 @@ impl VCpu {
 
         vmcs.write_field(vmcs::VmcsField::GuestInterruptibilityInfo, 0x00)?;
         vmcs.write_field(vmcs::VmcsField::GuestActivityState, 0x00)?;
-        vmcs.write_field(vmcs::VmcsField::GuestDr7, 0x00)?;
+        vmcs.write_field(vmcs::VmcsField::GuestDr7, 0x400)?;
+        vmcs.write_field(vmcs::VmcsField::GuestIa32Debugctl, 0)?;
         vmcs.write_field(vmcs::VmcsField::GuestRsp, 0x00)?;
         vmcs.write_field(vmcs::VmcsField::GuestRflags, 1 << 1)?; // Reserved rflags
 
         vmcs.write_field(vmcs::VmcsField::VmcsLinkPointer, 0xffffffff)?;
         vmcs.write_field(vmcs::VmcsField::VmcsLinkPointerHigh, 0xffffffff)?;
 
-        //TODO: get actual EFER (use MSR for vt-x v1)
+        // The guest EFER is loaded on VM entry and saved on VM exit. Writes
+        // are intercepted to maintain EFER.LMA (see `emulate::msr`).
         vmcs.write_field(vmcs::VmcsField::GuestIa32Efer, 0x00)?;
 
         let (guest_cr0, guest_cr4) = {
@@ -243,14 +744,18 @@ impl VCpu {
             cr0_fixed0 &= !(1 << 31); // disable PG
             let cr4_fixed0 = unsafe { msr::rdmsr(msr::IA32_VMX_CR4_FIXED0) };
 
+            // Changes to CR0.PG always exit, as they may switch the guest
+            // in or out of long mode
             vmcs.write_field(
                 vmcs::VmcsField::Cr0GuestHostMask,
-                cr0_fixed0 & 0x00000000ffffffff,
+                (cr0_fixed0 & 0x00000000ffffffff) | (1 << 31),
             )?;
 
+            // Changes to CR4.LA57 always exit, so they can be checked
+            // against the guest's CPUID and paging mode
             vmcs.write_field(
                 vmcs::VmcsField::Cr4GuestHostMask,
-                cr4_fixed0 & 0x00000000ffffffff,
+                (cr4_fixed0 & 0x00000000ffffffff) | (1 << 12),
             )?;
 
             (cr0_fixed0, cr4_fixed0)
@@ -258,7 +763,8 @@ impl VCpu {
 
         vmcs.write_field(vmcs::VmcsField::GuestCr0, guest_cr0)?;
         vmcs.write_field(vmcs::VmcsField::GuestCr4, guest_cr4)?;
-        vmcs.write_field(vmcs::VmcsField::Cr0ReadShadow, 0x00)?;
+        // The architectural reset value of CR0 is CD | NW | ET
+        vmcs.write_field(vmcs::VmcsField::Cr0ReadShadow, 0x60000010)?;
         vmcs.write_field(vmcs::VmcsField::Cr4ReadShadow, 0x00)?;
 
         vmcs.write_field(vmcs::VmcsField::GuestCr3, 0x00)?;
@@ -268,11 +774,110 @@ impl VCpu {
         Ok(())
     }
 
-    fn initialize_ctrl_vmcs(vmcs: &mut vmcs::ActiveVmcs) -> Result<()> {
+    // Only the BSP starts at the PVH entry point. The APs wait for the
+    // guest to start them with a startup IPI, as they would at power on.
+    // The guest's IPI reaches them as a message from the vCPU that sent it
+    // (see `send_ipi`), so where the wait-for-SIPI state is not supported
+    // they wait in the HLT state instead. Nothing is injected while they
+    // wait, so only the startup IPI can wake them.
+    fn initialize_pvh_entry(
+        vmcs: &mut vmcs::ActiveVmcs,
+        entry: &pvh::PvhEntry,
+        bsp: bool,
+    ) -> Result<()> {
+        if bsp {
+            return Self::initialize_pvh_guest_vmcs(vmcs, entry);
+        }
+
+        // IA32_VMX_MISC[8] reports support for the wait-for-SIPI state
+        let misc = unsafe { msr::rdmsr(msr::IA32_VMX_MISC) };
+        let state = if misc & (1 << 8) != 0 {
+            GUEST_ACTIVITY_WAIT_FOR_SIPI
+        } else {
+            GUEST_ACTIVITY_HLT
+        };
+        vmcs.write_field(vmcs::VmcsField::GuestActivityState, state)?;
+        Ok(())
+    }
+
+    fn handle_startup_ipi(&mut self) -> Result<()> {
+        let vector =
+            self.vmcs.read_field(vmcs::VmcsField::ExitQualification)? & 0xff;
+        self.start_ap(vector as u8)
+    }
+
+    // Start a waiting AP in real mode at the page given by the SIPI vector
+    fn start_ap(&mut self, vector: u8) -> Result<()> {
+        let vector = vector as u64;
+        self.waiting_for_sipi = false;
+        self.vmcs
+            .write_field(vmcs::VmcsField::GuestCsSelector, vector << 8)?;
+        self.vmcs
+            .write_field(vmcs::VmcsField::GuestCsBase, vector << 12)?;
+        self.vmcs.write_field(vmcs::VmcsField::GuestRip, 0)?;
+        self.vmcs.write_field(
+            vmcs::VmcsField::GuestActivityState,
+            GUEST_ACTIVITY_ACTIVE,
+        )?;
+        Ok(())
+    }
+
+    /// Setup the guest state for the PVH entry point (32-bit protected mode
+    /// with flat segments and paging disabled)
+    fn initialize_pvh_guest_vmcs(
+        vmcs: &mut vmcs::ActiveVmcs,
+        entry: &pvh::PvhEntry,
+    ) -> Result<()> {
+        vmcs.write_field(vmcs::VmcsField::GuestCsSelector, 0x10)?;
+        vmcs.write_field(vmcs::VmcsField::GuestCsBase, 0x00)?;
+        vmcs.write_field(vmcs::VmcsField::GuestCsLimit, 0xffffffff)?;
+        vmcs.write_field(vmcs::VmcsField::GuestCsArBytes, 0xc09b)?;
+
+        for (selector, base, limit, ar) in &[
+            (
+                vmcs::VmcsField::GuestDsSelector,
+                vmcs::VmcsField::GuestDsBase,
+                vmcs::VmcsField::GuestDsLimit,
+                vmcs::VmcsField::GuestDsArBytes,
+            ),
+            (
+                vmcs::VmcsField::GuestEsSelector,
+                vmcs::VmcsField::GuestEsBase,
+                vmcs::VmcsField::GuestEsLimit,
+                vmcs::VmcsField::GuestEsArBytes,
+            ),
+            (
+                vmcs::VmcsField::GuestSsSelector,
+                vmcs::VmcsField::GuestSsBase,
+                vmcs::VmcsField::GuestSsLimit,
+                vmcs::VmcsField::GuestSsArBytes,
+            ),
+        ] {
+            vmcs.write_field(*selector, 0x18)?;
+            vmcs.write_field(*base, 0x00)?;
+            vmcs.write_field(*limit, 0xffffffff)?;
+            vmcs.write_field(*ar, 0xc093)?;
+        }
+
+        vmcs.write_field(vmcs::VmcsField::GuestTrLimit, 0x67)?;
+
+        let cr0 = vmcs.read_field(vmcs::VmcsField::GuestCr0)? | 1 << 0; // PE
+        vmcs.write_field(vmcs::VmcsField::GuestCr0, cr0)?;
+        vmcs.write_field(vmcs::VmcsField::Cr0ReadShadow, 1 << 0)?;
+
+        vmcs.write_field(vmcs::VmcsField::GuestRip, entry.entry as u64)?;
+        Ok(())
+    }
+
+    fn initialize_ctrl_vmcs(
+        vmcs: &mut vmcs::ActiveVmcs,
+        debugctl_policy: emulate::msr::DebugCtlPolicy,
+    ) -> Result<()> {
         vmcs.write_with_fixed(
             vmcs::VmcsField::CpuBasedVmExecControl,
             (vmcs::CpuBasedCtrlFlags::UNCOND_IO_EXITING
                 | vmcs::CpuBasedCtrlFlags::ACTIVATE_MSR_BITMAP
+                | vmcs::CpuBasedCtrlFlags::USE_TSC_OFFSETING
                 | vmcs::CpuBasedCtrlFlags::ACTIVATE_SECONDARY_CONTROLS)
                 .bits(),
             msr::IA32_VMX_PROCBASED_CTLS,
@@ -290,6 +895,24 @@ impl VCpu {
             msr::IA32_VMX_PROCBASED_CTLS2,
         )?;
 
+        // Guest RDRAND and RDSEED are served from the hypervisor's
+        // generator (see `emulate::random`), where they can be intercepted
+        let allowed =
+            unsafe { msr::rdmsr(msr::IA32_VMX_PROCBASED_CTLS2) } >> 32;
+        let random_exiting = (vmcs::SecondaryExecFlags::RDRAND_EXITING
+            | vmcs::SecondaryExecFlags::RDSEED_EXITING)
+            .bits()
+            & allowed;
+        if random_exiting != 0 {
+            let field =
+                vmcs.read_field(vmcs::VmcsField::SecondaryVmExecControl)?;
+            vmcs.write_with_fixed(
+                vmcs::VmcsField::SecondaryVmExecControl,
+                field | random_exiting,
+                msr::IA32_VMX_PROCBASED_CTLS2,
+            )?;
+        }
+
         // The value 0 is forbidden for the VPID, so we use the sequential
         // processor id plus 1.
         //
@@ -310,14 +933,19 @@ impl VCpu {
         vmcs.write_with_fixed(
             vmcs::VmcsField::VmExitControls,
             (vmcs::VmExitCtrlFlags::IA32E_MODE
-                | vmcs::VmExitCtrlFlags::ACK_INTR_ON_EXIT)
+                | vmcs::VmExitCtrlFlags::SAVE_DEBUG_CNTRLS
+                | vmcs::VmExitCtrlFlags::ACK_INTR_ON_EXIT
+                | vmcs::VmExitCtrlFlags::SAVE_GUEST_EFER
+                | vmcs::VmExitCtrlFlags::LOAD_HOST_EFER)
                 .bits(),
             msr::IA32_VMX_EXIT_CTLS,
         )?;
 
         vmcs.write_with_fixed(
             vmcs::VmcsField::VmEntryControls,
-            0,
+            (vmcs::VmEntryCtrlFlags::LOAD_DEBUG_CNTRLS
+                | vmcs::VmEntryCtrlFlags::LOAD_GUEST_EFER)
+                .bits(),
             msr::IA32_VMX_ENTRY_CTLS,
         )?;
 
@@ -330,6 +958,11 @@ impl VCpu {
         // the guest
         msr_page.0[3] |= 1 << 3;
 
+        // Exit on writes to IA32_EFER, so EFER.LMA can be maintained
+        emulate::msr::intercept_write(&mut msr_page, msr::IA32_EFER);
+
+        emulate::msr::intercept_debugctl(&mut msr_page, debugctl_policy);
+
         let msr_bitmap = Box::into_raw(Box::new(msr_page));
 
         vmcs.write_field(vmcs::VmcsField::MsrBitmap, msr_bitmap as u64)?;
@@ -353,6 +986,234 @@ impl VCpu {
         Ok(())
     }
 
+    // If the processor supports it, shadow the guest TPR in a virtual-APIC
+    // page, so MOV to and from CR8 do not VMEXIT. Interrupts masked by the
+    // guest task priority are then held back, and the TPR threshold is set
+    // so the processor exits when the guest is able to accept them.
+    fn initialize_tpr_shadow(&mut self) -> Result<()> {
+        let proc_allowed =
+            unsafe { msr::rdmsr(msr::IA32_VMX_PROCBASED_CTLS) >> 32 };
+        if proc_allowed & vmcs::CpuBasedCtrlFlags::TPR_SHADOW.bits() == 0 {
+            info!("The TPR shadow is not supported");
+            return Ok(());
+        }
+
+        let mut virtual_apic = HostPage::new(HostRegionKind::ApicPage)?;
+        virtual_apic.0[0x30..0x34]
+            .copy_from_slice(&VIRTUAL_APIC_VERSION.to_le_bytes());
+
+        let field = self
+            .vmcs
+            .read_field(vmcs::VmcsField::CpuBasedVmExecControl)?;
+        let field = (field | vmcs::CpuBasedCtrlFlags::TPR_SHADOW.bits())
+            & !(vmcs::CpuBasedCtrlFlags::CR8_LOAD_EXITING
+                | vmcs::CpuBasedCtrlFlags::CR8_STORE_EXITING)
+                .bits();
+        self.vmcs.write_with_fixed(
+            vmcs::VmcsField::CpuBasedVmExecControl,
+            field,
+            msr::IA32_VMX_PROCBASED_CTLS,
+        )?;
+        self.vmcs.write_field(
+            vmcs::VmcsField::VirtualApicPageAddr,
+            virtual_apic.address(),
+        )?;
+        self.vmcs.write_field(vmcs::VmcsField::TprThreshold, 0)?;
+
+        self.virtual_apic = Some(virtual_apic);
+        self.tpr_threshold = Some(0);
+        Ok(())
+    }
+
+    // If the processor supports it, enable posted-interrupt processing (and
+    // the virtual local APIC it requires), so posted interrupts are
+    // delivered without a VMEXIT. Otherwise, the notification vector causes
+    // a VMEXIT and the posted interrupts are injected.
+    fn initialize_posted_interrupts(&mut self) -> Result<()> {
+        let (pin_allowed, proc2_allowed) = unsafe {
+            (
+                msr::rdmsr(msr::IA32_VMX_PINBASED_CTLS) >> 32,
+                msr::rdmsr(msr::IA32_VMX_PROCBASED_CTLS2) >> 32,
+            )
+        };
+        let secondary = vmcs::SecondaryExecFlags::VIRTUAL_INTR_DELIVERY
+            | vmcs::SecondaryExecFlags::APIC_REGISTER_VIRT;
+        if pin_allowed & vmcs::PinBasedCtrlFlags::POSTED_INTERRUPT.bits() == 0
+            || self.virtual_apic.is_none()
+            || proc2_allowed & secondary.bits() != secondary.bits()
+        {
+            info!("Posted-interrupt processing is not supported");
+            return Ok(());
+        }
+
+        // Guest accesses to the local APIC are virtualized through the
+        // APIC-access page (which is shared by all VCpus of the VM)
+        let apic_access = {
+            let mut vm = self.vm.write();
+            let addr = GuestPhysAddr::new(GUEST_APIC_BASE);
+            match vm.guest_space.find_host_frame(addr) {
+                Ok(frame) => frame,
+                Err(_) => {
+                    let page = Box::into_raw(Box::new(Raw4kPage::default()));
+                    let frame = HostPhysFrame::from_start_address(
+                        HostPhysAddr::new(page as u64),
+                    )?;
+                    vm.guest_space.map_frame(addr, frame, false)?;
+                    frame
+                }
+            }
+        };
+
+        self.vmcs.write_field(
+            vmcs::VmcsField::ApicAccessAddr,
+            apic_access.start_address().as_u64(),
+        )?;
+
+        let field = self
+            .vmcs
+            .read_field(vmcs::VmcsField::SecondaryVmExecControl)?;
+        self.vmcs.write_with_fixed(
+            vmcs::VmcsField::SecondaryVmExecControl,
+            field | secondary.bits(),
+            msr::IA32_VMX_PROCBASED_CTLS2,
+        )?;
+        for field in [
+            vmcs::VmcsField::EoiExitBitmap0,
+            vmcs::VmcsField::EoiExitBitmap1,
+            vmcs::VmcsField::EoiExitBitmap2,
+            vmcs::VmcsField::EoiExitBitmap3,
+        ]
+        .iter()
+        {
+            self.vmcs.write_field(*field, 0)?;
+        }
+        self.vmcs.write_field(vmcs::VmcsField::GuestIntrStatus, 0)?;
+
+        let field = self
+            .vmcs
+            .read_field(vmcs::VmcsField::PinBasedVmExecControl)?;
+        self.vmcs.write_with_fixed(
+            vmcs::VmcsField::PinBasedVmExecControl,
+            field | vmcs::PinBasedCtrlFlags::POSTED_INTERRUPT.bits(),
+            msr::IA32_VMX_PINBASED_CTLS,
+        )?;
+        self.vmcs.write_field(
+            vmcs::VmcsField::PostedIntrNv,
+            self.posted_interrupts.notification_vector() as u64,
+        )?;
+        self.vmcs.write_field(
+            vmcs::VmcsField::PostedIntrDescAddr,
+            self.posted_interrupts.address(),
+        )?;
+
+        // With virtual-interrupt delivery, the TPR threshold is not used
+        // (so interrupts are injected regardless of the guest TPR)
+        self.tpr_threshold = None;
+        Ok(())
+    }
+
+    // Swap the MSRs that are not part of the VMCS guest state on every
+    // entry and exit. The guest FS and GS bases are saved and loaded with
+    // the segment registers (including after a guest WRMSR to
+    // IA32_FS_BASE or IA32_GS_BASE), but IA32_KERNEL_GS_BASE is not, so
+    // without this the value written by the guest would only live in the
+    // physical MSR of whatever core it last ran on.
+    fn initialize_msr_areas(&mut self) -> Result<()> {
+        self.guest_msrs.add(msr::IA32_KERNEL_GSBASE, 0)?;
+        self.host_msrs.add(msr::IA32_KERNEL_GSBASE, unsafe {
+            msr::rdmsr(msr::IA32_KERNEL_GSBASE)
+        })?;
+
+        // The same area is used to store the guest values on exit and to
+        // reload them on the next entry
+        let guest_area = self.guest_msrs.address();
+        let guest_count = self.guest_msrs.len() as u64;
+        self.vmcs
+            .write_field(vmcs::VmcsField::VmExitMsrStoreAddr, guest_area)?;
+        self.vmcs
+            .write_field(vmcs::VmcsField::VmExitMsrStoreCount, guest_count)?;
+        self.vmcs
+            .write_field(vmcs::VmcsField::VmEntryMsrLoadAddr, guest_area)?;
+        self.vmcs
+            .write_field(vmcs::VmcsField::VmEntryMsrLoadCount, guest_count)?;
+
+        self.vmcs.write_field(
+            vmcs::VmcsField::VmExitMsrLoadAddr,
+            self.host_msrs.address(),
+        )?;
+        self.vmcs.write_field(
+            vmcs::VmcsField::VmExitMsrLoadCount,
+            self.host_msrs.len() as u64,
+        )
+    }
+
+    // If the processor supports it, use the VMX-preemption timer to force
+    // an exit at least every `health::HEARTBEAT_PERIOD`, so this core keeps
+    // publishing heartbeats even while the guest is idle.
+    fn initialize_heartbeat(&mut self) -> Result<()> {
+        let (pin_allowed, misc) = unsafe {
+            (
+                msr::rdmsr(msr::IA32_VMX_PINBASED_CTLS) >> 32,
+                msr::rdmsr(msr::IA32_VMX_MISC),
+            )
+        };
+        let supported =
+            pin_allowed & vmcs::PinBasedCtrlFlags::PREEMPT_TIMER.bits() != 0;
+        health::set_periodic(supported);
+        if !supported {
+            return Ok(());
+        }
+
+        // The timer counts down at the TSC rate divided by 2^(MISC[4:0])
+        self.preemption_timer_shift = Some(misc & 0x1f);
+        self.set_preemption_timer(None)?;
+
+        let field = self
+            .vmcs
+            .read_field(vmcs::VmcsField::PinBasedVmExecControl)?;
+        self.vmcs.write_with_fixed(
+            vmcs::VmcsField::PinBasedVmExecControl,
+            field | vmcs::PinBasedCtrlFlags::PREEMPT_TIMER.bits(),
+            msr::IA32_VMX_PINBASED_CTLS,
+        )?;
+        Ok(())
+    }
+
+    // Force an exit after `period` (if set), or at the next heartbeat,
+    // whichever comes first
+    fn set_preemption_timer(&mut self, period: Option<Duration>) -> Result<()> {
+        let shift = match self.preemption_timer_shift {
+            Some(shift) => shift,
+            None => return Ok(()),
+        };
+        let period = period
+            .map(|period| period.min(health::HEARTBEAT_PERIOD))
+            .unwrap_or(health::HEARTBEAT_PERIOD);
+        let now = time::now();
+        let ticks = (now + period).0 - now.0;
+        let value = core::cmp::min(ticks >> shift, u32::MAX as u64);
+        self.vmcs
+            .write_field(vmcs::VmcsField::VmxPreemptionTimerValue, value)
+    }
+
+    // Charge this vCPU for its time since it was last charged, and let it
+    // run for no longer than the next slice its core's policy gives it
+    //
+    // Exits completed by `handle_fast_vmexit` restart the preemption timer
+    // without consulting the policy, so a slice may be overrun, but the
+    // time is still charged at the next exit handled here.
+    fn reschedule(&mut self) -> Result<()> {
+        let entity = sched::SchedEntity {
+            vm_id: self.vm.read().id,
+            vcpu: self.id,
+        };
+        let slice = sched::run_next(entity, time::now() - self.sched_charged);
+
+        // Time spent held back by the policy is not charged
+        self.sched_charged = time::now();
+        self.set_preemption_timer(slice)
+    }
+
     fn skip_emulated_instruction(&mut self) -> Result<()> {
         let mut rip = self.vmcs.read_field(vmcs::VmcsField::GuestRip)?;
         rip += self
@@ -363,6 +1224,118 @@ impl VCpu {
         Ok(())
     }
 
+    /// Attempt to handle one of the most frequent VMEXITs, without decoding
+    /// the full exit reason, taking any locks or allocating.
+    ///
+    /// Only exits that need no interrupt, timer or exit policy work are
+    /// completed here. Their latency is also recorded in a histogram of its
+    /// own (see `health::fast_exit_finished`).
+    ///
+    /// Returns false if the exit must be handled by `handle_vmexit`.
+    pub fn handle_fast_vmexit(
+        &mut self,
+        guest_cpu: &mut vmexit::GuestCpuState,
+    ) -> Result<bool> {
+        // Exit policies are only applied by the full handler, so the exits
+        // of a VM with a policy are never completed here (a rule could
+        // otherwise be bypassed, e.g., for CPUID)
+        if !self.exit_policy.is_empty() {
+            return Ok(false);
+        }
+
+        // Interrupts (including those of elapsed timers) are only injected
+        // by the full handler. Interrupts raised through the PIC are only
+        // requested by the full handler too, which has already opened an
+        // interrupt window for any that are pending.
+        if !self.pending_events.is_empty()
+            || time::get_timer_wheel().has_elapsed()
+        {
+            return Ok(false);
+        }
+
+        let reason = self.vmcs.read_field(vmcs::VmcsField::VmExitReason)?;
+        if reason & vmexit::ExitReasonFlags::VM_ENTRY_FAIL.bits() != 0 {
+            return Ok(false);
+        }
+
+        match reason & 0xffff {
+            vmexit::basic_reason::CPUID => {
+                emulate::cpuid::emulate_cpuid(self, guest_cpu)?;
+                self.skip_emulated_instruction()?;
+            }
+
+            // The write has already been performed on the virtual-APIC page,
+            // but writes to the timer registers must (re)arm the timer, and
+            // writes to the ICR send an IPI
+            //
+            // This is the only APIC exit completed here. With APIC-register
+            // virtualization, the processor completes the other accesses to
+            // the local APIC on the virtual-APIC page itself. APIC-access
+            // exits (reason 44) are only left for the accesses it cannot
+            // virtualize (e.g., reads of the timer's current count), which
+            // can only be completed by decoding the faulting instruction
+            // from guest memory, under the VM lock, so they are never
+            // handled here.
+            vmexit::basic_reason::APIC_WRITE => {
+                let offset = (self
+                    .vmcs
+                    .read_field(vmcs::VmcsField::ExitQualification)?
+                    & 0xfff) as u16;
+                if virtdev::lapic::ApicTimer::is_timer_register(offset)
+                    || offset as usize == virtdev::lapic::ICR_LOW
+                {
+                    return Ok(false);
+                }
+            }
+
+            // If the access is permitted by the current EPT, the violation
+            // was caused by a stale translation (e.g., the page was mapped by
+            // another VCpu), so there is nothing to emulate.
+            vmexit::basic_reason::EPT_VIOLATION => {
+                let info =
+                    vmexit::EptInformation::from_active_vmcs(&self.vmcs)?;
+                let mut required = EptTableFlags::empty();
+                if info.read {
+                    required |= EptTableFlags::READ_ACCESS;
+                }
+                if info.write {
+                    required |= EptTableFlags::WRITE_ACCESS;
+                }
+                if info.exec {
+                    required |= EptTableFlags::PRIV_EXEC_ACCESS;
+                }
+
+                let eptp = self.vmcs.read_field(vmcs::VmcsField::EptPointer)?;
+                let root = HostPhysAddr::new(eptp & !0xfff);
+                let permissions = unsafe {
+                    memory::ept_permissions(root, info.guest_phys_addr)
+                };
+                match permissions {
+                    Some(permissions) if permissions.contains(required) => {
+                        self.vmcs
+                            .vmx
+                            .invept(vmx::InvEptMode::SingleContext(eptp))?;
+                    }
+                    _ => return Ok(false),
+                }
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Deliver `fault` to the guest on the next VM entry
+    ///
+    /// Exceptions take priority over pending external interrupts, so any
+    /// pending interrupts are left for a following interrupt window.
+    fn inject_guest_fault(&mut self, fault: memory::GuestFault) -> Result<()> {
+        self.pending_events.push(PendingEvent::exception(
+            fault.vector(),
+            Some(fault.error_code()),
+        ));
+        self.inject_pending_event()
+    }
+
     /// Handle an arbitrary guest VMEXIT.
     ///
     /// This is the rust 'entry' point when a guest exists.
@@ -376,8 +1349,32 @@ impl VCpu {
         guest_cpu: &mut vmexit::GuestCpuState,
         exit: vmexit::ExitReason,
     ) -> Result<()> {
-        // Process the exit reason
-        self.handle_vmexit_impl(guest_cpu, exit.clone())?;
+        // Pick up any change to the CPUID policy of the VM
+        self.cpuid = self.vm.read().cpuid.clone();
+
+        // Process the exit reason. Faults caused by the guest (e.g., a bad
+        // address passed to an emulated instruction) are reflected back to
+        // it without completing the instruction.
+        match self.handle_vmexit_impl(guest_cpu, exit.clone()) {
+            Err(Error::GuestFault(fault)) => {
+                // The faulting address of a #PF is reported through CR2,
+                // which is restored from the guest state on entry
+                if let memory::GuestFault::PageFault { addr, .. } = fault {
+                    guest_cpu.cr2 = addr;
+                }
+                return self.inject_guest_fault(fault);
+            }
+            res => res?,
+        }
+
+        // Publish the state after this exit to the shadow VMCS
+        if let Some(shadow) = self.shadow.as_mut() {
+            self.vmcs.sync_shadow(shadow)?;
+        }
+
+        self.poll_watchdog(guest_cpu)?;
+        self.poll_nic()?;
+        self.reschedule()?;
 
         // Always check for expired timers
         unsafe {
@@ -388,67 +1385,318 @@ impl VCpu {
             }
         }
 
-        // If there are no pending interrupts, we're done
-        if self.pending_interrupts.is_empty() {
+        self.inject_pending_event()
+    }
+
+    // Inject the next pending event that the guest can take now. If there
+    // are others, the guest exits once it can take them (after it has
+    // handled this one). Interrupts masked by the guest task priority wait
+    // for a TPR-below-threshold exit instead.
+    fn inject_pending_event(&mut self) -> Result<()> {
+        if self.waiting_for_sipi {
             return Ok(());
         }
+        self.update_tpr_threshold()?;
+        let mut window = vmcs::InterruptWindowControl::read(&self.vmcs)?;
+        if self.has_deliverable_interrupt() {
+            let interruptibility = vmcs::InterruptibilityState::from_bits(
+                self.vmcs
+                    .read_field(vmcs::VmcsField::GuestInterruptibilityInfo)?,
+            )
+            .ok_or_else(|| {
+                Error::InvalidValue("Invalid interruptibility state".into())
+            })?;
+            let rflags = self.vmcs.read_field(vmcs::VmcsField::GuestRflags)?;
 
-        let interruptibility = vmcs::InterruptibilityState::from_bits(
-            self.vmcs
-                .read_field(vmcs::VmcsField::GuestInterruptibilityInfo)?,
-        )
-        .ok_or_else(|| {
-            Error::InvalidValue("Invalid interruptibility state".into())
-        })?;
+            if let Some(event) =
+                self.next_event(interruptibility, rflags & 0b1000000000 != 0)
+            {
+                if let Some(error_code) = event.error_code {
+                    self.vmcs.write_field(
+                        vmcs::VmcsField::VmEntryExceptionErrorCode,
+                        error_code as u64,
+                    )?;
+                }
+                self.vmcs.write_field(
+                    vmcs::VmcsField::VmEntryIntrInfoField,
+                    event.entry_info(),
+                )?;
+                health::event_injected();
+            }
+            self.update_tpr_threshold()?;
+        }
 
-        let rflags = self.vmcs.read_field(vmcs::VmcsField::GuestRflags)?;
+        let tpr = self.task_priority();
+        let is_nmi = |event: &PendingEvent| {
+            event.kind == InjectedInterruptType::NonMaskableInterrupt
+        };
+        window.set_nmi_window(self.pending_events.iter().any(is_nmi));
+        window.set_interrupt_window(
+            self.pending_events
+                .iter()
+                .any(|event| !is_nmi(event) && !Self::is_masked(event, tpr))
+                || self.pic_pending(),
+        );
+        window.commit(&mut self.vmcs)
+    }
 
-        // If the guest is not currently interruptible, set the interrupt window exiting
-        // and exit. Otherwise, ensure that it is disabled.
-        let field = self
-            .vmcs
-            .read_field(vmcs::VmcsField::CpuBasedVmExecControl)?;
-        if !interruptibility.is_empty() || rflags & 0b1000000000 == 0 {
-            self.vmcs.write_field(
-                vmcs::VmcsField::CpuBasedVmExecControl,
-                field
-                    | vmcs::CpuBasedCtrlFlags::INTERRUPT_WINDOW_EXITING.bits(),
-            )?;
+    /// Stop the guest (see `lifecycle`), restarting it from the reset
+    /// vector if the restart policy of the VM allows it
+    pub fn stop(
+        &mut self,
+        guest_cpu: &mut vmexit::GuestCpuState,
+        reason: lifecycle::StopReason,
+    ) -> Result<()> {
+        let vm = self.vm.read();
+        let policy = vm.config.restart_policy();
+        let delay = vm.restarts.lock().stopped(policy, reason, time::uptime());
+        let name = vm.name.clone();
+        let rip = self.vmcs.read_field(vmcs::VmcsField::GuestRip)?;
+        info!(
+            "VM {} stopped ({}) at {}",
+            name,
+            reason,
+            vm.symbols.describe(rip)
+        );
+        drop(vm);
+
+        let delay = match delay {
+            Some(delay) => delay,
+            None => self.halt_stopped(&name),
+        };
+        info!("Restarting VM {} in {:?}", name, delay);
+        time::busy_wait(delay);
+        self.reset(guest_cpu)
+    }
+
+    // Halt a stopped guest for good. The core becomes idle, so other vCPUs
+    // can move to it.
+    fn halt_stopped(&mut self, name: &str) -> ! {
+        info!("VM {} will not be restarted", name);
+        migrate::release(self)
+    }
+
+    /// Reset the VM at the request of its guest (e.g., through port 0x92)
+    /// or when its watchdog expires
+    ///
+    /// A reset is not a stop, so the restart policy does not apply: every
+    /// vCPU of the VM returns to its power-on state at once.
+    fn guest_reset(
+        &mut self,
+        guest_cpu: &mut vmexit::GuestCpuState,
+    ) -> Result<()> {
+        let vm = self.vm.read();
+        let resets = vm.restarts.lock().guest_reset();
+        info!("Resetting VM {} ({} resets)", vm.name, resets);
+        let current = migrate::current_vcpu();
+        for core in vm.config.cpus().iter().filter(|core| **core != current) {
+            vm::send_vm_msg_core(vm::VirtualMachineMsg::Reset, *core)?;
+        }
+
+        // The watchdog of the previous boot would otherwise expire while
+        // the guest is starting again
+        if let Some(watchdog) = vm.config.watchdog() {
+            watchdog.write().stop();
+        }
+
+        // The controller must not write to the rings of the previous boot
+        if let Some(nic) = vm.config.nic() {
+            nic.write().reset();
+        }
+        drop(vm);
+        self.reset(guest_cpu)
+    }
+
+    // Take the action of the VM's watchdog if it has expired
+    fn poll_watchdog(
+        &mut self,
+        guest_cpu: &mut vmexit::GuestCpuState,
+    ) -> Result<()> {
+        let watchdog = match self.vm.read().config.watchdog() {
+            Some(watchdog) => watchdog.clone(),
+            None => return Ok(()),
+        };
+        let action = watchdog.write().poll(time::uptime());
+        match action {
+            Some(WatchdogAction::Nmi) => {
+                self.inject_interrupt(
+                    2,
+                    InjectedInterruptType::NonMaskableInterrupt,
+                );
+                Ok(())
+            }
+            Some(WatchdogAction::Reset) => self.guest_reset(guest_cpu),
+            Some(WatchdogAction::Log) | None => Ok(()),
+        }
+    }
+
+    // Apply a hotplug request to one of the VM's PCI Express slots. A host
+    // function is attached to the VM's IOMMU domain before the guest can
+    // see it (it is detached once the guest releases it).
+    fn pcie_hotplug(
+        &mut self,
+        event: virtdev::pci::PcieHotplugEvent,
+        responses: &mut virtdev::ResponseEventArray,
+    ) -> Result<()> {
+        let host = match event {
+            virtdev::pci::PcieHotplugEvent::Insert { host, .. } => host,
+            virtdev::pci::PcieHotplugEvent::Remove { .. } => None,
+        };
+        let mut vm = self.vm.write();
+        if let Some(host) = host {
+            if let Err(e) = vm.attach_host_device(
+                0,
+                host.source_id,
+                iommu::FaultPolicy::Quarantine,
+            ) {
+                warn!(
+                    "Unable to attach host function 0x{:x}: {:?}",
+                    host.source_id, e
+                );
+                return Ok(());
+            }
+        }
+        let result = vm.dispatch_event(
+            virtdev::pci::PciRootComplex::PCI_CONFIG_ADDRESS,
+            virtdev::DeviceEvent::PcieHotplugRequested(event),
+            self,
+            responses,
+        );
+        if let (Some(host), Err(_)) = (host, result.as_ref()) {
+            iommu::detach_device(0, host.source_id);
+        }
+        result
+    }
+
+    // Deliver the frames waiting for the VM's network controller
+    fn poll_nic(&mut self) -> Result<()> {
+        let mut responses = virtdev::ResponseEventArray::default();
+        {
+            let vm = self.vm.read();
+            match vm.config.nic() {
+                Some(nic) => {
+                    nic.write().poll(&vm.guest_space, &mut responses)?
+                }
+                None => return Ok(()),
+            }
+        }
+        for response in responses {
+            if let virtdev::DeviceEventResponse::Interrupt((vector, kind)) =
+                response
+            {
+                self.inject_interrupt(vector, kind);
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the guest to its state at power on (or its PVH entry point)
+    fn reset(&mut self, guest_cpu: &mut vmexit::GuestCpuState) -> Result<()> {
+        guest_cpu.clear_registers();
+        self.pending_events = PendingEvents::new();
+        self.vmcs
+            .write_field(vmcs::VmcsField::VmEntryIntrInfoField, 0)?;
+        Self::initialize_guest_vmcs(&mut self.vmcs)?;
+
+        // The event channel page belongs to the previous boot of the guest
+        let vm = self.vm.read();
+        *vm.event_channels.lock() = evtchn::EventChannels::default();
+
+        // The TPM is reset with the platform (once, by the BSP), so the
+        // firmware can start it again
+        let bsp = vm.config.cpus().first() == Some(&self.id);
+        if let Some(tpm) = vm.config.tpm().filter(|_| bsp) {
+            tpm.write().reset();
+        }
+
+        self.waiting_for_sipi = false;
+        if let Some(entry) = vm.pvh_entry {
+            Self::initialize_pvh_entry(&mut self.vmcs, &entry, bsp)?;
+            self.waiting_for_sipi = !bsp;
+        }
+        Ok(())
+    }
+
+    /// Signal the event channel bound to `source` (if the guest has bound
+    /// one)
+    pub fn signal_event_channel(
+        &mut self,
+        source: evtchn::EventSource,
+    ) -> Result<()> {
+        let vector = {
+            let vm = self.vm.read();
+            let mut channels = vm.event_channels.lock();
+            channels.notify(&vm.guest_space, source)?
+        };
+        if let Some(vector) = vector {
+            self.inject_interrupt(
+                vector,
+                InjectedInterruptType::ExternalInterrupt,
+            );
+        }
+        Ok(())
+    }
+
+    // Handle a (trap-like) APIC-write exit, after the guest's write has been
+    // performed on the virtual-APIC page
+    fn handle_apic_write(&mut self) -> Result<()> {
+        let offset =
+            (self.vmcs.read_field(vmcs::VmcsField::ExitQualification)? & 0xfff)
+                as u16;
+        if offset as usize == virtdev::lapic::ICR_LOW {
+            return self.send_ipi();
+        }
+        if !virtdev::lapic::ApicTimer::is_timer_register(offset) {
             return Ok(());
-        } else {
-            self.vmcs.write_field(
-                vmcs::VmcsField::CpuBasedVmExecControl,
-                field
-                    & !vmcs::CpuBasedCtrlFlags::INTERRUPT_WINDOW_EXITING.bits(),
-            )?;
         }
+        let value = match self.virtual_apic_register(offset as usize) {
+            Some(value) => value,
+            None => return Ok(()),
+        };
+        self.apic_timer.register_written(offset, value)
+    }
 
-        // At this point, we must have at least one pending interrupt, and the guest
-        // can accept interrupts, so do the injection.
-        if let Some(pending) = self.pending_interrupts.pop_first() {
-            self.vmcs.write_field(
-                vmcs::VmcsField::VmEntryIntrInfoField,
-                0x80000000 | pending.0 as u64 | ((pending.1 as u64) << 8),
-            )?;
+    // The value of the register at `offset` in the virtual-APIC page
+    fn virtual_apic_register(&self, offset: usize) -> Option<u32> {
+        self.virtual_apic.as_ref().map(|page| {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&page.0[offset..offset + 4]);
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    // Deliver the IPI the guest wrote to its ICR. Only startup IPIs (with
+    // a physical destination or the all-excluding-self shorthand) are
+    // delivered, to start the APs of a PVH boot.
+    fn send_ipi(&mut self) -> Result<()> {
+        let low = self.virtual_apic_register(virtdev::lapic::ICR_LOW);
+        let high = self.virtual_apic_register(virtdev::lapic::ICR_HIGH);
+        let (low, high) = match (low, high) {
+            (Some(low), Some(high)) => (low, high),
+            _ => return Ok(()),
+        };
+        let mode = (low >> 8) & 0b111;
+        let logical = low & (1 << 11) != 0;
+        if mode != apic::DeliveryMode::StartUp as u32 || logical {
+            return Ok(());
         }
 
-        // If there are still pending interrupts, set the interrupt window so
-        // we should get a chance to do the injection once the guest is finished
-        // handling the one we just injected.
-        if !self.pending_interrupts.is_empty() {
-            self.vmcs.write_field(
-                vmcs::VmcsField::CpuBasedVmExecControl,
-                field
-                    | vmcs::CpuBasedCtrlFlags::INTERRUPT_WINDOW_EXITING.bits(),
-            )?;
+        let cpus = self.vm.read().config.cpus().to_vec();
+        let shorthand = (low >> 18) & 0b11;
+        let targets = if shorthand == apic::DstShorthand::NoShorthand as u32 {
+            let core_id = percore::CoreId::from(high >> 24);
+            cpus.into_iter().filter(|core| *core == core_id).collect()
+        } else if shorthand == apic::DstShorthand::AllExcludingSelf as u32 {
+            cpus
         } else {
-            self.vmcs.write_field(
-                vmcs::VmcsField::CpuBasedVmExecControl,
-                field
-                    & !vmcs::CpuBasedCtrlFlags::INTERRUPT_WINDOW_EXITING.bits(),
+            vec![]
+        };
+        for core_id in targets.into_iter().filter(|core| *core != self.id) {
+            vm::send_vm_msg_core(
+                vm::VirtualMachineMsg::StartupIpi(low as u8),
+                core_id,
             )?;
         }
-
         Ok(())
     }
 
@@ -456,26 +1704,94 @@ impl VCpu {
         &mut self,
         responses: &mut virtdev::ResponseEventArray,
     ) -> Result<()> {
-        let vm = self.vm.read();
+        // Read everything the host UART has received, unless the console is
+        // switched to another VM part way through. The rest of the input is
+        // then handed over with the console.
+        for _ in 0..MAX_SERIAL_INPUT {
+            let input = self
+                .vm
+                .read()
+                .config
+                .physical_devices()
+                .serial
+                .as_ref()
+                .and_then(|serial| serial.poll());
+            match input {
+                Some(input) => self.receive_serial_input(input, responses)?,
+                None => break,
+            }
+            if responses
+                .iter()
+                .any(|r| matches!(r, virtdev::DeviceEventResponse::NextConsole))
+            {
+                break;
+            }
+        }
+        Ok(())
+    }
 
-        let serial_info = vm
-            .config
-            .physical_devices()
-            .serial
-            .as_ref()
-            .map(|serial| (serial.read(), serial.base_port()));
-        drop(vm);
+    fn receive_serial_input(
+        &mut self,
+        input: physdev::com::SerialInput,
+        responses: &mut virtdev::ResponseEventArray,
+    ) -> Result<()> {
+        let event = match input {
+            physdev::com::SerialInput::Data(key) => {
+                // Input for the management console is not seen by the
+                // guest. Console commands may inspect this VM, so it must
+                // not be locked while they run.
+                if console::handle_key(key) {
+                    return Ok(());
+                }
+                virtdev::DeviceEvent::HostUartReceived(key)
+            }
+            physdev::com::SerialInput::Signal(signal) => {
+                virtdev::DeviceEvent::HostUartSignal(signal)
+            }
+        };
 
-        let mut vm = self.vm.write();
-        if let Some((key, port)) = serial_info {
-            vm.dispatch_event(
-                port,
-                virtdev::DeviceEvent::HostUartReceived(key),
-                self,
-                responses,
-            )
-        } else {
-            Ok(())
+        // The input is dropped if no guest UART uses the host serial port
+        let port = self.vm.read().config.host_serial_port();
+        if let Some(port) = port {
+            let mut vm = self.vm.write();
+            return vm.dispatch_event(port, event, self, responses);
+        }
+        Ok(())
+    }
+
+    // Apply the exit policy of the VM, returning true if the exit must
+    // not be passed to the regular handlers
+    fn apply_exit_policy(
+        &mut self,
+        guest_cpu: &mut vmexit::GuestCpuState,
+        info: &vmexit::ExitInformation,
+    ) -> Result<bool> {
+        match self.exit_policy.evaluate(info, guest_cpu.rcx as u32) {
+            ExitAction::Handle => Ok(false),
+            ExitAction::Ignore => {
+                match info {
+                    vmexit::ExitInformation::RdMsr => {
+                        guest_cpu.write_msr_result(0);
+                    }
+                    vmexit::ExitInformation::IoInstruction(io)
+                        if io.input && !io.string =>
+                    {
+                        guest_cpu.write_gpr(
+                            vmexit::Gpr::Rax,
+                            0,
+                            vmexit::OperandWidth::from_bytes(io.size as usize)?,
+                        );
+                    }
+                    _ => (),
+                }
+                self.skip_emulated_instruction()?;
+                Ok(true)
+            }
+            ExitAction::Kill => {
+                warn!("Exit {:?} is denied by the exit policy", info);
+                self.stop(guest_cpu, lifecycle::StopReason::Killed)?;
+                Ok(true)
+            }
         }
     }
 
@@ -486,6 +1802,10 @@ impl VCpu {
     ) -> Result<()> {
         let mut responses = virtdev::ResponseEventArray::default();
 
+        if self.apply_exit_policy(guest_cpu, &exit.info)? {
+            return Ok(());
+        }
+
         match exit.info {
             //TODO(alschwalm): Once we have guest x2apic support, remove this
             vmexit::ExitInformation::RdMsr => {
@@ -494,13 +1814,16 @@ impl VCpu {
                         let mut real_apic_base =
                             unsafe { msr::rdmsr(msr::IA32_APIC_BASE) };
                         real_apic_base &= !(1 << 10); // mask X2APIC_ENABLE
-                        guest_cpu.rdx = real_apic_base >> 32;
-                        guest_cpu.rax = real_apic_base & 0xffffffff;
+                        guest_cpu.write_msr_result(real_apic_base);
                     }
-                    _ => unreachable!(),
+                    _ => emulate::msr::emulate_rdmsr(self, guest_cpu)?,
                 }
                 self.skip_emulated_instruction()?;
             }
+            vmexit::ExitInformation::WrMsr => {
+                emulate::msr::emulate_wrmsr(self, guest_cpu)?;
+                self.skip_emulated_instruction()?;
+            }
             vmexit::ExitInformation::CrAccess(info) => {
                 emulate::controlreg::emulate_access(self, guest_cpu, info)?;
                 self.skip_emulated_instruction()?;
@@ -511,15 +1834,28 @@ impl VCpu {
                 self.skip_emulated_instruction()?;
             }
             vmexit::ExitInformation::IoInstruction(info) => {
-                emulate::portio::emulate_portio(
+                self.throttle_emulation(false);
+                let complete = emulate::portio::emulate_portio(
                     self,
                     guest_cpu,
                     info,
                     &mut responses,
                 )?;
-                self.skip_emulated_instruction()?;
+                if complete {
+                    self.skip_emulated_instruction()?;
+                }
             }
             vmexit::ExitInformation::EptViolation(info) => {
+                // The first touch of lazily allocated memory maps a frame
+                // and then retries the access
+                let addr = GuestPhysAddr::new(
+                    self.vmcs
+                        .read_field(vmcs::VmcsField::GuestPhysicalAddress)?,
+                );
+                if self.vm.write().guest_space.populate(addr)? {
+                    return Ok(());
+                }
+                self.throttle_emulation(false);
                 emulate::memio::handle_ept_violation(
                     self,
                     guest_cpu,
@@ -528,24 +1864,147 @@ impl VCpu {
                 )?;
                 self.skip_emulated_instruction()?;
             }
-            vmexit::ExitInformation::InterruptWindow => {}
+            vmexit::ExitInformation::InterruptWindow
+            | vmexit::ExitInformation::NonMaskableInterruptWindow => {}
+            // The interrupts held back by the old task priority are injected
+            // (if they are still masked, the threshold is updated)
+            vmexit::ExitInformation::TprBelowThreshold => {}
+            vmexit::ExitInformation::Hlt => {
+                self.skip_emulated_instruction()?;
+                if let Some(window) = self.idle_poll {
+                    self.poll_idle(window)?;
+                }
+            }
+            vmexit::ExitInformation::InvlPg => {
+                emulate::invalidate::emulate_invlpg(self)?;
+                self.skip_emulated_instruction()?;
+            }
+            vmexit::ExitInformation::Invpcid(info) => {
+                emulate::invalidate::emulate_invpcid(self, guest_cpu, info)?;
+                self.skip_emulated_instruction()?;
+            }
+            vmexit::ExitInformation::RdRand(info)
+            | vmexit::ExitInformation::RdSeed(info) => {
+                emulate::random::emulate_random(self, guest_cpu, info)?;
+                self.skip_emulated_instruction()?;
+            }
+            vmexit::ExitInformation::VmCall => {
+                emulate::hypercall::emulate_vmcall(self, guest_cpu)?;
+                self.skip_emulated_instruction()?;
+            }
+            vmexit::ExitInformation::ApicWrite => self.handle_apic_write()?,
+            vmexit::ExitInformation::StartUpIpi => self.handle_startup_ipi()?,
+            vmexit::ExitInformation::VmxPreemptionTimerExpired => {}
+            vmexit::ExitInformation::TripleFault => {
+                self.stop(guest_cpu, lifecycle::StopReason::TripleFault)?
+            }
             vmexit::ExitInformation::ExternalInterrupt(info) => unsafe {
+                rng::add_interrupt_timing(info.vector);
                 match info.vector {
                     interrupt::UART_VECTOR => {
                         self.handle_uart_keypress(&mut responses)?
                     }
+                    interrupt::IOMMU_FAULT_VECTOR => {
+                        iommu::handle_fault_event()?
+                    }
+                    interrupt::POSTED_INTERRUPT_VECTOR => {
+                        self.inject_posted_interrupts()
+                    }
                     interrupt::IPC_VECTOR => {
+                        // Another core has panicked, so stop the guest here
+                        if crash::is_crashing() {
+                            let rip = self
+                                .vmcs
+                                .read_field(vmcs::VmcsField::GuestRip)
+                                .ok();
+                            crash::park(rip);
+                        }
                         let msg =
                             vm::recv_vm_msg().ok_or_else(|| Error::NotFound)?;
                         match msg {
-                            vm::VirtualMachineMsg::GrantConsole(serial) => {
-                                let mut vm = self.vm.write();
-                                vm.config.physical_devices_mut().serial =
-                                    Some(serial);
+                            vm::VirtualMachineMsg::GrantConsole(
+                                serial,
+                                pending,
+                            ) => {
+                                self.vm
+                                    .write()
+                                    .config
+                                    .physical_devices_mut()
+                                    .serial = Some(serial);
+                                for input in pending {
+                                    self.receive_serial_input(
+                                        input,
+                                        &mut responses,
+                                    )?;
+                                }
                             }
                             vm::VirtualMachineMsg::CancelTimer(timer_id) => {
                                 time::cancel_timer(&timer_id)?;
                             }
+                            vm::VirtualMachineMsg::Hotplug(event) => {
+                                let mut vm = self.vm.write();
+                                vm.dispatch_event(
+                                    virtdev::acpi::AcpiRuntime::GPE_BLOCK_START,
+                                    virtdev::DeviceEvent::HotplugRequested(
+                                        event,
+                                    ),
+                                    self,
+                                    &mut responses,
+                                )?;
+                            }
+                            vm::VirtualMachineMsg::PcieHotplug(event) => {
+                                self.pcie_hotplug(event, &mut responses)?;
+                            }
+                            vm::VirtualMachineMsg::Reset => {
+                                self.reset(guest_cpu)?;
+                            }
+                            vm::VirtualMachineMsg::StartupIpi(vector) => {
+                                // A startup IPI is ignored unless the AP
+                                // is waiting for one
+                                if self.waiting_for_sipi {
+                                    self.start_ap(vector)?;
+                                }
+                            }
+                            vm::VirtualMachineMsg::Migrate(target) => {
+                                // The vCPU can only leave once the exit
+                                // has been handled
+                                self.migration = Some(target);
+                            }
+                            vm::VirtualMachineMsg::Interrupt(vector, kind) => {
+                                self.pending_events
+                                    .push(PendingEvent::new(vector, kind));
+                            }
+                            vm::VirtualMachineMsg::Kick
+                                if !self.waiting_for_sipi =>
+                            {
+                                // The exit itself ends the HLT, so the guest
+                                // resumes after the halt instruction
+                                self.vmcs.write_field(
+                                    vmcs::VmcsField::GuestActivityState,
+                                    GUEST_ACTIVITY_ACTIVE,
+                                )?;
+                            }
+                            vm::VirtualMachineMsg::Kick => (),
+                            vm::VirtualMachineMsg::Inject(injection) => {
+                                chaos::apply(self, injection)?;
+                            }
+                            vm::VirtualMachineMsg::EventChannel(source) => {
+                                self.signal_event_channel(source)?;
+                            }
+                            vm::VirtualMachineMsg::Balloon(pages) => {
+                                let mut vm = self.vm.write();
+                                match vm.config.balloon() {
+                                    Some(base) => vm.dispatch_event(
+                                        base,
+                                        virtdev::DeviceEvent::BalloonRequested(
+                                            pages,
+                                        ),
+                                        self,
+                                        &mut responses,
+                                    )?,
+                                    None => warn!("VM has no balloon device"),
+                                }
+                            }
                         }
                     }
                     _ => (),
@@ -557,6 +2016,8 @@ impl VCpu {
             },
             _ => {
                 info!("{}", self.vmcs);
+                let rip = self.vmcs.read_field(vmcs::VmcsField::GuestRip)?;
+                info!("Guest rip: {}", self.vm.read().symbols.describe(rip));
                 panic!("No handler for exit reason: {:?}", exit);
             }
         }
@@ -566,6 +2027,17 @@ impl VCpu {
                 virtdev::DeviceEventResponse::Interrupt((vector, kind)) => {
                     self.inject_interrupt(vector, kind);
                 }
+                virtdev::DeviceEventResponse::Gsi(gsi) => {
+                    self.throttle_emulation(true);
+                    if !self.raise_gsi(gsi) {
+                        debug!("Dropping interrupt on unrouted GSI {}", gsi);
+                    }
+                }
+                virtdev::DeviceEventResponse::HostFunctionReleased(
+                    source_id,
+                ) => {
+                    iommu::detach_device(0, source_id);
+                }
                 virtdev::DeviceEventResponse::NextConsole => {
                     info!("Received Ctrl-a three times. Switching console to next VM");
 
@@ -581,8 +2053,11 @@ impl VCpu {
 
                     let next_vmid = (vmid + 1) % vm::max_vm_id();
 
+                    // Input that arrived after the switch belongs to the
+                    // next VM
+                    let pending = serial.drain();
                     vm::send_vm_msg(
-                        vm::VirtualMachineMsg::GrantConsole(serial),
+                        vm::VirtualMachineMsg::GrantConsole(serial, pending),
                         next_vmid,
                     )?;
 
@@ -598,14 +2073,47 @@ impl VCpu {
                         )
                     })?;
                 }
+                virtdev::DeviceEventResponse::GuestDebugLine(line) => {
+                    info!("[{}] {}", self.vm.read().name, line);
+                }
+                virtdev::DeviceEventResponse::ResetRequested => {
+                    self.guest_reset(guest_cpu)?
+                }
+                virtdev::DeviceEventResponse::GuestPanicked => {
+                    self.stop(guest_cpu, lifecycle::StopReason::GuestPanic)?
+                }
+                virtdev::DeviceEventResponse::ShutdownRequested => {
+                    self.stop(guest_cpu, lifecycle::StopReason::Shutdown)?
+                }
+                virtdev::DeviceEventResponse::EventChannel(source) => {
+                    self.signal_event_channel(source)?
+                }
+                virtdev::DeviceEventResponse::GuestMappingsChanged => {
+                    let eptp = self.vm.read().guest_space.eptp();
+                    self.vmcs
+                        .vmx
+                        .invept(vmx::InvEptMode::SingleContext(eptp))?;
+                }
                 virtdev::DeviceEventResponse::GuestUartTransmitted(val) => {
-                    let vm = self.vm.read();
-                    if vm.config.physical_devices().serial.is_some() {
-                        //TODO: This should be a write to the physical serial device
-                        let buff = &[val];
-                        let s = alloc::string::String::from_utf8_lossy(buff);
-                        crate::logger::write_console(&s);
+                    // The VM lock is not held while throttled, so other vCPUs
+                    // of the VM can continue to make progress
+                    let action =
+                        self.vm.read().console.lock().transmit(time::uptime());
+                    match action {
+                        ratelimit::ConsoleAction::Transmit => (),
+                        ratelimit::ConsoleAction::Drop => continue,
+                        ratelimit::ConsoleAction::Delay(wait) => {
+                            time::busy_wait(wait)
+                        }
                     }
+
+                    let vm = self.vm.read();
+                    crate::consoleroute::guest_output(
+                        vm.id,
+                        &vm.name,
+                        val,
+                        vm.config.physical_devices().serial.is_some(),
+                    );
                 }
             }
         }
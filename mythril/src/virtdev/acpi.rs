@@ -1,23 +1,56 @@
 use crate::error::Result;
-use crate::time;
-use crate::virtdev::{DeviceEvent, DeviceRegion, EmulatedDevice, Event, Port};
+use crate::virtdev::acpi_pm::AcpiPm;
+use crate::virtdev::{
+    DeviceEvent, DeviceEventResponse, DeviceRegion, EmulatedDevice, Event,
+    Port, ResponseEventArray,
+};
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use spin::RwLock;
 
-const PMTIMER_HZ: u64 = 3579545;
+// The SCI is wired to GSI 9 (as described by the FADT and MADT)
+const SCI_GSI: u32 = 9;
+
+// The GPE0 bits used by the QEMU-style DSDT for each hotplug source
+const GPE_PCI_HOTPLUG: u16 = 1 << 1;
+const GPE_CPU_HOTPLUG: u16 = 1 << 2;
+const GPE_MEMORY_HOTPLUG: u16 = 1 << 3;
+
+const MAX_HOTPLUG_CPUS: usize = 256;
+
+/// A hotplug notification generated by the hypervisor for a guest
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HotplugEvent {
+    /// The CPU with the given APIC ID was added
+    CpuAdded(u8),
+
+    /// The CPU with the given APIC ID was removed
+    CpuRemoved(u8),
+
+    /// The guest memory devices changed
+    MemoryChanged,
+
+    /// A device was inserted in the given PCI slot
+    PciSlotInserted(u8),
+
+    /// The guest should release the device in the given PCI slot
+    PciSlotRemoved(u8),
+}
 
 pub struct AcpiRuntime {
-    pm_base: Port,
+    pm: Arc<RwLock<AcpiPm>>,
+    gpe_status: u16,
+    gpe_enable: u16,
+    pci_slots_up: u32,
+    pci_slots_down: u32,
+    pci_slots_removable: u32,
+    cpus_present: [u8; MAX_HOTPLUG_CPUS / 8],
 }
 
 impl AcpiRuntime {
-    // This should actually be determind by the ACPI tables we're passing to the guest
-    const FADT_SMI_COMMAND: Port = 0xb2;
-
     // Seabios expects us to pass PCI hotplug info via ACPI like QEMU.
     // See https://github.com/qemu/qemu/blob/master/docs/specs/acpi_pci_hotplug.txt
-    const GPE_BLOCK_START: Port = 0xafe0;
+    pub const GPE_BLOCK_START: Port = 0xafe0;
     const GPE_BLOCK_END: Port = 0xafe3;
     const PCI_SLOT_INJECTION_START: Port = 0xae00;
     const PCI_SLOT_INJECTION_END: Port = 0xae03;
@@ -28,27 +61,168 @@ impl AcpiRuntime {
     const PCI_REMOVABILITY_STATUS_START: Port = 0xae0c;
     const PCI_REMOVABILITY_STATUS_END: Port = 0xae0f;
 
-    pub fn new(pm_base: Port) -> Result<Arc<RwLock<Self>>> {
-        Ok(Arc::new(RwLock::new(AcpiRuntime { pm_base })))
+    // A bitmap of present CPUs (by APIC ID), as with QEMU's legacy CPU
+    // hotplug interface
+    const CPU_PRESENT_START: Port = 0xaf00;
+    const CPU_PRESENT_END: Port =
+        Self::CPU_PRESENT_START + (MAX_HOTPLUG_CPUS / 8) as Port - 1;
+
+    /// Create a new `AcpiRuntime`, raising the SCI once ACPI is enabled
+    /// through `pm`
+    ///
+    /// The CPUs with APIC IDs less than `num_cpus` are initially present.
+    pub fn new(
+        pm: Arc<RwLock<AcpiPm>>,
+        num_cpus: usize,
+    ) -> Result<Arc<RwLock<Self>>> {
+        let mut runtime = AcpiRuntime {
+            pm,
+            gpe_status: 0,
+            gpe_enable: 0,
+            pci_slots_up: 0,
+            pci_slots_down: 0,
+
+            // The host bridge and LPC bridge cannot be removed
+            pci_slots_removable: !0b11,
+            cpus_present: [0u8; MAX_HOTPLUG_CPUS / 8],
+        };
+        for apic_id in 0..num_cpus.min(MAX_HOTPLUG_CPUS) {
+            runtime.set_cpu_present(apic_id as u8, true);
+        }
+        Ok(Arc::new(RwLock::new(runtime)))
+    }
+
+    fn set_cpu_present(&mut self, apic_id: u8, present: bool) {
+        let byte = &mut self.cpus_present[apic_id as usize / 8];
+        let mask = 1 << (apic_id % 8);
+        if present {
+            *byte |= mask;
+        } else {
+            *byte &= !mask;
+        }
+    }
+
+    fn sci_pending(&self) -> bool {
+        self.gpe_status & self.gpe_enable != 0 && self.pm.read().sci_enabled()
+    }
+
+    fn update_sci(&self, responses: &mut ResponseEventArray) {
+        // The SCI is level triggered, but we can only inject an edge, so
+        // raise it whenever an enabled event becomes pending. Events that
+        // are pending when ACPI is enabled are raised once the guest
+        // enables their GPE.
+        if self.sci_pending() {
+            responses.push(DeviceEventResponse::Gsi(SCI_GSI));
+        }
     }
 
-    fn pm1a_cnt(&self) -> Port {
-        self.pm_base + 0x04
+    fn on_hotplug(
+        &mut self,
+        event: HotplugEvent,
+        responses: &mut ResponseEventArray,
+    ) {
+        let gpe = match event {
+            HotplugEvent::CpuAdded(apic_id) => {
+                self.set_cpu_present(apic_id, true);
+                GPE_CPU_HOTPLUG
+            }
+            HotplugEvent::CpuRemoved(apic_id) => {
+                self.set_cpu_present(apic_id, false);
+                GPE_CPU_HOTPLUG
+            }
+            HotplugEvent::MemoryChanged => GPE_MEMORY_HOTPLUG,
+            HotplugEvent::PciSlotInserted(slot) => {
+                self.pci_slots_up |= 1 << (slot % 32);
+                GPE_PCI_HOTPLUG
+            }
+            HotplugEvent::PciSlotRemoved(slot) => {
+                self.pci_slots_down |= 1 << (slot % 32);
+                GPE_PCI_HOTPLUG
+            }
+        };
+
+        let already_pending = self.sci_pending();
+        self.gpe_status |= gpe;
+        if !already_pending {
+            self.update_sci(responses);
+        }
     }
 
-    fn pmtimer(&self) -> Port {
-        self.pm_base + 0x08
+    fn read_register(&self, port: Port) -> Option<u8> {
+        let val = match port {
+            Self::GPE_BLOCK_START..=Self::GPE_BLOCK_END => {
+                let block = [self.gpe_status, self.gpe_enable];
+                let offset = (port - Self::GPE_BLOCK_START) as usize;
+                block[offset / 2].to_le_bytes()[offset % 2]
+            }
+            Self::PCI_SLOT_INJECTION_START..=Self::PCI_SLOT_INJECTION_END => {
+                let offset = port - Self::PCI_SLOT_INJECTION_START;
+                self.pci_slots_up.to_le_bytes()[offset as usize]
+            }
+            Self::PCI_SLOT_REMOVAL_NOTIFY_START
+                ..=Self::PCI_SLOT_REMOVAL_NOTIFY_END => {
+                let offset = port - Self::PCI_SLOT_REMOVAL_NOTIFY_START;
+                self.pci_slots_down.to_le_bytes()[offset as usize]
+            }
+            Self::PCI_REMOVABILITY_STATUS_START
+                ..=Self::PCI_REMOVABILITY_STATUS_END => {
+                let offset = port - Self::PCI_REMOVABILITY_STATUS_START;
+                self.pci_slots_removable.to_le_bytes()[offset as usize]
+            }
+            Self::CPU_PRESENT_START..=Self::CPU_PRESENT_END => {
+                self.cpus_present[(port - Self::CPU_PRESENT_START) as usize]
+            }
+            _ => return None,
+        };
+        Some(val)
+    }
+
+    fn write_register(
+        &mut self,
+        port: Port,
+        val: u8,
+        responses: &mut ResponseEventArray,
+    ) {
+        match port {
+            Self::GPE_BLOCK_START..=Self::GPE_BLOCK_END => {
+                let offset = (port - Self::GPE_BLOCK_START) as usize;
+                let shift = (offset % 2) * 8;
+                if offset < 2 {
+                    // The status bits are write-1-to-clear
+                    self.gpe_status &= !((val as u16) << shift);
+
+                    // The guest has handled the hotplug events, so the
+                    // pending slot notifications are consumed
+                    if self.gpe_status & GPE_PCI_HOTPLUG == 0 {
+                        self.pci_slots_up = 0;
+                        self.pci_slots_down = 0;
+                    }
+                } else {
+                    self.gpe_enable = (self.gpe_enable & !(0xff << shift))
+                        | (val as u16) << shift;
+                    self.update_sci(responses);
+                }
+            }
+            Self::PCI_DEVICE_EJECT_START..=Self::PCI_DEVICE_EJECT_END => {
+                let offset = port - Self::PCI_DEVICE_EJECT_START;
+                let slots = (val as u32) << (offset * 8);
+                if slots != 0 {
+                    info!("Guest ejected PCI slots (bitmap=0x{:x})", slots);
+                }
+            }
+            _ => {
+                info!(
+                    "Attempt to write to AcpiRuntime port=0x{:x}, val=0x{:x}. Ignoring",
+                    port, val
+                );
+            }
+        }
     }
 }
 
 impl EmulatedDevice for AcpiRuntime {
     fn services(&self) -> Vec<DeviceRegion> {
         vec![
-            DeviceRegion::PortIo(
-                Self::FADT_SMI_COMMAND..=Self::FADT_SMI_COMMAND,
-            ),
-            DeviceRegion::PortIo(self.pm1a_cnt()..=self.pm1a_cnt()),
-            DeviceRegion::PortIo(self.pmtimer()..=self.pmtimer()),
             DeviceRegion::PortIo(Self::GPE_BLOCK_START..=Self::GPE_BLOCK_END),
             DeviceRegion::PortIo(
                 Self::PCI_SLOT_INJECTION_START..=Self::PCI_SLOT_INJECTION_END,
@@ -64,24 +238,32 @@ impl EmulatedDevice for AcpiRuntime {
                 Self::PCI_REMOVABILITY_STATUS_START
                     ..=Self::PCI_REMOVABILITY_STATUS_END,
             ),
+            DeviceRegion::PortIo(
+                Self::CPU_PRESENT_START..=Self::CPU_PRESENT_END,
+            ),
         ]
     }
 
     fn on_event(&mut self, event: Event) -> Result<()> {
         match event.kind {
+            DeviceEvent::HotplugRequested(hotplug) => {
+                self.on_hotplug(hotplug, event.responses);
+            }
             DeviceEvent::PortRead(port, mut val) => {
-                if port == self.pmtimer() {
-                    let on_duration = time::now() - time::system_start_time();
-                    let pm_time = (on_duration.as_nanos() * PMTIMER_HZ as u128)
-                        / 1_000_000_000;
-                    val.copy_from_u32(pm_time as u32);
+                // Multi-byte accesses are little endian across consecutive
+                // registers
+                for (i, byte) in val.as_mut_slice().iter_mut().enumerate() {
+                    *byte = self.read_register(port + i as Port).unwrap_or(0);
                 }
             }
             DeviceEvent::PortWrite(port, val) => {
-                info!(
-                    "Attempt to write to AcpiRuntime port=0x{:x}, val={}. Ignoring",
-                    port, val
-                );
+                for (i, byte) in val.as_slice().iter().enumerate() {
+                    self.write_register(
+                        port + i as Port,
+                        *byte,
+                        event.responses,
+                    );
+                }
             }
             _ => (),
         }
@@ -89,3 +271,114 @@ impl EmulatedDevice for AcpiRuntime {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::{
+        GuestAddressSpace, GuestAddressSpaceViewMut, GuestPhysAddr,
+    };
+    use crate::virtdev::{PortReadRequest, PortWriteRequest};
+    use alloc::boxed::Box;
+    use core::convert::TryFrom;
+
+    fn define_test_view() -> GuestAddressSpaceViewMut<'static> {
+        let space: &'static mut GuestAddressSpace =
+            Box::leak(Box::new(GuestAddressSpace::new().unwrap()));
+        GuestAddressSpaceViewMut::new(GuestPhysAddr::new(0), space)
+    }
+
+    fn send(
+        device: &mut dyn EmulatedDevice,
+        kind: DeviceEvent,
+    ) -> ResponseEventArray {
+        let mut responses = ResponseEventArray::default();
+        let event =
+            Event::new(kind, define_test_view(), &mut responses).unwrap();
+        device.on_event(event).unwrap();
+        responses
+    }
+
+    fn write_byte(
+        device: &mut dyn EmulatedDevice,
+        port: Port,
+        val: u8,
+    ) -> usize {
+        let data = [val];
+        let request = PortWriteRequest::try_from(&data[..]).unwrap();
+        send(device, DeviceEvent::PortWrite(port, request)).len()
+    }
+
+    fn read_u32(runtime: &mut AcpiRuntime, port: Port) -> u32 {
+        let mut buff = [0u8; 4];
+        send(
+            runtime,
+            DeviceEvent::PortRead(port, PortReadRequest::FourBytes(&mut buff)),
+        );
+        u32::from_le_bytes(buff)
+    }
+
+    #[test]
+    fn test_pci_hotplug_sci() {
+        let pm = AcpiPm::new(0xb000);
+        let runtime = AcpiRuntime::new(pm.clone(), 1).unwrap();
+        let runtime = &mut *runtime.write();
+
+        // Nothing is delivered until the guest enables ACPI and the GPE
+        let responses = send(
+            runtime,
+            DeviceEvent::HotplugRequested(HotplugEvent::PciSlotInserted(5)),
+        );
+        assert_eq!(responses.len(), 0);
+        assert_eq!(
+            write_byte(&mut *pm.write(), AcpiPm::FADT_SMI_COMMAND, 0xf1),
+            0
+        );
+
+        // Enabling the pending GPE raises the SCI on GSI 9
+        let data = [0x02];
+        let responses = send(
+            runtime,
+            DeviceEvent::PortWrite(
+                AcpiRuntime::GPE_BLOCK_START + 2,
+                PortWriteRequest::try_from(&data[..]).unwrap(),
+            ),
+        );
+        assert_eq!(responses.len(), 1);
+        assert!(matches!(responses[0], DeviceEventResponse::Gsi(9)));
+        assert_eq!(
+            read_u32(runtime, AcpiRuntime::PCI_SLOT_INJECTION_START),
+            1 << 5
+        );
+        assert_eq!(
+            read_u32(runtime, AcpiRuntime::GPE_BLOCK_START),
+            0x0002_0002
+        );
+
+        // Acknowledging the event clears the status and slot bitmap
+        write_byte(runtime, AcpiRuntime::GPE_BLOCK_START, 0x02);
+        assert_eq!(
+            read_u32(runtime, AcpiRuntime::GPE_BLOCK_START),
+            0x0002_0000
+        );
+        assert_eq!(read_u32(runtime, AcpiRuntime::PCI_SLOT_INJECTION_START), 0);
+    }
+
+    #[test]
+    fn test_cpu_hotplug_bitmap() {
+        let runtime = AcpiRuntime::new(AcpiPm::new(0xb000), 2).unwrap();
+        let runtime = &mut *runtime.write();
+        assert_eq!(read_u32(runtime, AcpiRuntime::CPU_PRESENT_START), 0b11);
+
+        send(
+            runtime,
+            DeviceEvent::HotplugRequested(HotplugEvent::CpuAdded(8)),
+        );
+        send(
+            runtime,
+            DeviceEvent::HotplugRequested(HotplugEvent::CpuRemoved(0)),
+        );
+        assert_eq!(read_u32(runtime, AcpiRuntime::CPU_PRESENT_START), 0x0102);
+        assert_eq!(runtime.gpe_status, GPE_CPU_HOTPLUG);
+    }
+}
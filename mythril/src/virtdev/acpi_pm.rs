@@ -0,0 +1,265 @@
+//! Emulation of the ACPI PM1 register blocks and PM timer
+//!
+//! The PM1a event block (status and enable), the PM1a control block and
+//! the PM timer are at consecutive offsets from the PM base port (as
+//! described to the guest by the FADT), and the SMI command port switches
+//! the guest between legacy and ACPI mode.
+//!
+//! The PM timer runs at `PMTIMER_HZ` from the guest uptime, so guests can
+//! calibrate their timers against it. Setting SLP_EN with the S5 sleep type
+//! in the control block (an ACPI soft-off) stops the guest, as if it had
+//! asked to shut down. No other sleep states are supported.
+
+use crate::error::Result;
+use crate::timekeeping;
+use crate::virtdev::{
+    DeviceEvent, DeviceEventResponse, DeviceRegion, EmulatedDevice, Event,
+    Port, ResponseEventArray,
+};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::time::Duration;
+use spin::RwLock;
+
+/// The frequency of the PM timer (in Hz)
+pub const PMTIMER_HZ: u64 = 3579545;
+
+/// The SLP_TYP of the S5 (soft-off) state, which must match the `_S5`
+/// package of the guest DSDT
+pub const SLP_TYP_S5: u8 = 0;
+
+// The offsets of the registers from the PM base
+const PM1A_STS: Port = 0x00;
+const PM1A_EN: Port = 0x02;
+const PM1A_CNT: Port = 0x04;
+const PM_TMR: Port = 0x08;
+const PM_TMR_LEN: Port = 4;
+
+// PM1 control bits
+const PM1_CNT_SCI_EN: u16 = 1 << 0;
+const PM1_CNT_SLP_TYP_SHIFT: u16 = 10;
+const PM1_CNT_SLP_TYP_MASK: u16 = 0b111 << PM1_CNT_SLP_TYP_SHIFT;
+const PM1_CNT_SLP_EN: u16 = 1 << 13;
+
+/// The value written to the SMI command port to switch to ACPI mode
+pub const ACPI_ENABLE: u8 = 0xf1;
+
+/// The value written to the SMI command port to switch to legacy mode
+pub const ACPI_DISABLE: u8 = 0xf0;
+
+fn pm_timer(now: Duration) -> u32 {
+    (now.as_nanos() * PMTIMER_HZ as u128 / 1_000_000_000) as u32
+}
+
+pub struct AcpiPm {
+    pm_base: Port,
+    pm1_status: u16,
+    pm1_enable: u16,
+    pm1_control: u16,
+}
+
+impl AcpiPm {
+    /// The SMI command port (as described to the guest by the FADT)
+    pub const FADT_SMI_COMMAND: Port = 0xb2;
+
+    /// Create a new `AcpiPm` with the PM registers at `pm_base`
+    pub fn new(pm_base: Port) -> Arc<RwLock<Self>> {
+        Arc::new(RwLock::new(AcpiPm {
+            pm_base,
+            pm1_status: 0,
+            pm1_enable: 0,
+            pm1_control: 0,
+        }))
+    }
+
+    /// Returns true if the guest has switched to ACPI mode, so the SCI can
+    /// be raised
+    pub fn sci_enabled(&self) -> bool {
+        self.pm1_control & PM1_CNT_SCI_EN != 0
+    }
+
+    fn read_register(&self, offset: Port, now: Duration) -> u8 {
+        let (register, start) = match offset {
+            PM1A_STS..=0x01 => (self.pm1_status as u32, PM1A_STS),
+            PM1A_EN..=0x03 => (self.pm1_enable as u32, PM1A_EN),
+            PM1A_CNT..=0x05 => (self.pm1_control as u32, PM1A_CNT),
+            PM_TMR..=0x0b => (pm_timer(now), PM_TMR),
+            _ => return 0,
+        };
+        register.to_le_bytes()[(offset - start) as usize]
+    }
+
+    fn write_register(
+        &mut self,
+        offset: Port,
+        val: u8,
+        responses: &mut ResponseEventArray,
+    ) {
+        let (register, shift) = match offset {
+            PM1A_STS..=0x01 => (&mut self.pm1_status, (offset - PM1A_STS) * 8),
+            PM1A_EN..=0x03 => (&mut self.pm1_enable, (offset - PM1A_EN) * 8),
+            PM1A_CNT..=0x05 => (&mut self.pm1_control, (offset - PM1A_CNT) * 8),
+            _ => return,
+        };
+        let val = (val as u16) << shift;
+        let mask = 0xff << shift;
+        match offset {
+            // The status bits are write-1-to-clear
+            PM1A_STS..=0x01 => *register &= !val,
+            PM1A_EN..=0x03 => *register = (*register & !mask) | val,
+            _ => {
+                // The SCI_EN bit is owned by the hardware, and SLP_EN is
+                // write-only
+                let owned = PM1_CNT_SCI_EN | PM1_CNT_SLP_EN;
+                *register = (*register & (!mask | owned)) | (val & !owned);
+                if val & PM1_CNT_SLP_EN != 0 {
+                    let slp_typ =
+                        (val & PM1_CNT_SLP_TYP_MASK) >> PM1_CNT_SLP_TYP_SHIFT;
+                    self.sleep(slp_typ as u8, responses);
+                }
+            }
+        }
+    }
+
+    fn sleep(&mut self, slp_typ: u8, responses: &mut ResponseEventArray) {
+        if slp_typ == SLP_TYP_S5 {
+            info!("Guest requested ACPI soft-off");
+            responses.push(DeviceEventResponse::ShutdownRequested);
+        } else {
+            warn!("Ignoring unsupported ACPI sleep type {}", slp_typ);
+        }
+    }
+
+    fn write_smi_command(&mut self, val: u8) {
+        match val {
+            ACPI_ENABLE => self.pm1_control |= PM1_CNT_SCI_EN,
+            ACPI_DISABLE => self.pm1_control &= !PM1_CNT_SCI_EN,
+            _ => info!("Ignoring ACPI SMI command 0x{:x}", val),
+        }
+    }
+}
+
+impl EmulatedDevice for AcpiPm {
+    fn services(&self) -> Vec<DeviceRegion> {
+        vec![
+            DeviceRegion::PortIo(
+                Self::FADT_SMI_COMMAND..=Self::FADT_SMI_COMMAND,
+            ),
+            DeviceRegion::PortIo(self.pm_base..=self.pm_base + PM1A_CNT + 1),
+            DeviceRegion::PortIo(
+                self.pm_base + PM_TMR..=self.pm_base + PM_TMR + PM_TMR_LEN - 1,
+            ),
+        ]
+    }
+
+    fn on_event(&mut self, event: Event) -> Result<()> {
+        match event.kind {
+            DeviceEvent::PortRead(port, mut val) => {
+                let now = timekeeping::guest_uptime();
+                let offset = port.wrapping_sub(self.pm_base);
+
+                // Multi-byte accesses are little endian across consecutive
+                // registers
+                for (i, byte) in val.as_mut_slice().iter_mut().enumerate() {
+                    *byte = if port == Self::FADT_SMI_COMMAND {
+                        0
+                    } else {
+                        self.read_register(offset + i as Port, now)
+                    };
+                }
+            }
+            DeviceEvent::PortWrite(port, val) => {
+                let bytes = val.as_slice();
+                if port == Self::FADT_SMI_COMMAND {
+                    self.write_smi_command(bytes[0]);
+                } else {
+                    let offset = port.wrapping_sub(self.pm_base);
+                    for (i, byte) in bytes.iter().enumerate() {
+                        self.write_register(
+                            offset + i as Port,
+                            *byte,
+                            event.responses,
+                        );
+                    }
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::{
+        GuestAddressSpace, GuestAddressSpaceViewMut, GuestPhysAddr,
+    };
+    use crate::virtdev::PortWriteRequest;
+    use alloc::boxed::Box;
+    use core::convert::TryFrom;
+
+    fn define_test_view() -> GuestAddressSpaceViewMut<'static> {
+        let space: &'static mut GuestAddressSpace =
+            Box::leak(Box::new(GuestAddressSpace::new().unwrap()));
+        GuestAddressSpaceViewMut::new(GuestPhysAddr::new(0), space)
+    }
+
+    fn write(pm: &mut AcpiPm, port: Port, data: &[u8]) -> ResponseEventArray {
+        let mut responses = ResponseEventArray::default();
+        let request = PortWriteRequest::try_from(data).unwrap();
+        let event = Event::new(
+            DeviceEvent::PortWrite(port, request),
+            define_test_view(),
+            &mut responses,
+        )
+        .unwrap();
+        pm.on_event(event).unwrap();
+        responses
+    }
+
+    #[test]
+    fn test_pm_timer() {
+        let pm = AcpiPm::new(0xb000);
+        let pm = pm.read();
+        assert_eq!(pm_timer(Duration::from_secs(1)), PMTIMER_HZ as u32);
+        let now = Duration::from_millis(10);
+        assert_eq!(pm.read_register(PM_TMR, now), 0xd3);
+        assert_eq!(pm.read_register(PM_TMR + 1, now), 0x8b);
+
+        // The timer wraps at 32 bits
+        assert_eq!(pm_timer(Duration::from_secs(1200)), 0x0007_6d30);
+    }
+
+    #[test]
+    fn test_pm1_control() {
+        let pm = AcpiPm::new(0xb000);
+        let mut pm = pm.write();
+        write(&mut pm, AcpiPm::FADT_SMI_COMMAND, &[ACPI_ENABLE]);
+        assert!(pm.sci_enabled());
+
+        // The guest cannot clear SCI_EN, and SLP_EN reads as zero
+        let slp_typ = 3 << PM1_CNT_SLP_TYP_SHIFT;
+        let control = PM1_CNT_SLP_EN | slp_typ;
+        let responses =
+            write(&mut pm, 0xb000 + PM1A_CNT, &control.to_le_bytes());
+        assert_eq!(responses.len(), 0);
+        assert_eq!(pm.pm1_control, PM1_CNT_SCI_EN | slp_typ);
+
+        // A soft-off stops the guest
+        let control =
+            PM1_CNT_SLP_EN | (SLP_TYP_S5 as u16) << PM1_CNT_SLP_TYP_SHIFT;
+        let responses =
+            write(&mut pm, 0xb000 + PM1A_CNT + 1, &[(control >> 8) as u8]);
+        assert!(matches!(
+            responses.as_slice(),
+            [DeviceEventResponse::ShutdownRequested]
+        ));
+
+        // The status bits are write-1-to-clear
+        pm.pm1_status = 0x8101;
+        write(&mut pm, 0xb000 + PM1A_STS, &0x01ff_8001u32.to_le_bytes());
+        assert_eq!(pm.pm1_status, 0x0100);
+        assert_eq!(pm.pm1_enable, 0x01ff);
+    }
+}
@@ -1,7 +1,9 @@
+use crate::consoleroute::MAX_LINE_LEN;
 use crate::error::Result;
 use crate::virtdev::{
     DeviceEvent, DeviceEventResponse, DeviceRegion, EmulatedDevice, Event, Port,
 };
+use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::convert::TryInto;
@@ -38,3 +40,136 @@ impl EmulatedDevice for DebugPort {
         Ok(())
     }
 }
+
+/// The port of the Bochs-style debug console
+pub const DEBUG_CONSOLE_PORT: Port = 0xe9;
+
+/// A Bochs-style debug console (the "port 0xE9 hack")
+///
+/// Each byte the guest writes is collected into lines, which are written to
+/// the hypervisor log prefixed with the name of the VM (see
+/// `DeviceEventResponse::GuestDebugLine`). This needs no setup in the
+/// guest, so it is useful for debugging firmware and early boot code.
+/// Reads return 0xE9, so guests can detect the console.
+#[derive(Default)]
+pub struct DebugConsole {
+    line: Vec<u8>,
+}
+
+impl DebugConsole {
+    pub fn new() -> Arc<RwLock<Self>> {
+        Arc::new(RwLock::new(Self::default()))
+    }
+
+    // Add a byte to the current line, returning the line if it is complete
+    fn write(&mut self, byte: u8) -> Option<String> {
+        match byte {
+            b'\n' => (),
+            b'\r' => return None,
+            _ => {
+                self.line.push(byte);
+                if self.line.len() < MAX_LINE_LEN {
+                    return None;
+                }
+            }
+        }
+        let line = String::from_utf8_lossy(&self.line).into_owned();
+        self.line.clear();
+        Some(line)
+    }
+}
+
+impl EmulatedDevice for DebugConsole {
+    fn services(&self) -> Vec<DeviceRegion> {
+        vec![DeviceRegion::PortIo(
+            DEBUG_CONSOLE_PORT..=DEBUG_CONSOLE_PORT,
+        )]
+    }
+
+    fn on_event(&mut self, event: Event) -> Result<()> {
+        match event.kind {
+            DeviceEvent::PortRead(_port, mut val) => {
+                val.copy_from_u32(DEBUG_CONSOLE_PORT as u32);
+            }
+            DeviceEvent::PortWrite(_port, val) => {
+                // Like Bochs, only the low byte of a wider write is used
+                if let Some(line) = self.write(val.as_u8()) {
+                    event
+                        .responses
+                        .push(DeviceEventResponse::GuestDebugLine(line));
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+/// A device that records the value written by the guest to signal that it
+/// is finished (compatible with QEMU's `isa-debug-exit`)
+///
+/// The guest is not stopped, so it is expected to halt after the write.
+pub struct DebugExit {
+    port: Port,
+    code: Option<u32>,
+    report: fn(u32),
+}
+
+impl DebugExit {
+    /// Create a debug-exit device at `port`, which calls `report` with the
+    /// value written by the guest
+    pub fn new(port: Port, report: fn(u32)) -> Arc<RwLock<Self>> {
+        Arc::new(RwLock::new(Self {
+            port,
+            code: None,
+            report,
+        }))
+    }
+
+    /// The value written by the guest (if it has exited)
+    pub fn code(&self) -> Option<u32> {
+        self.code
+    }
+}
+
+impl EmulatedDevice for DebugExit {
+    fn services(&self) -> Vec<DeviceRegion> {
+        vec![DeviceRegion::PortIo(self.port..=self.port)]
+    }
+
+    fn on_event(&mut self, event: Event) -> Result<()> {
+        match event.kind {
+            DeviceEvent::PortWrite(_port, val) => {
+                let code = val.as_u32();
+                self.code = Some(code);
+                (self.report)(code);
+            }
+            DeviceEvent::PortRead(_port, mut val) => val.copy_from_u32(0),
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_debug_console_lines() {
+        let mut console = DebugConsole::default();
+        let mut lines = vec![];
+        for byte in b"boot\r\nstage 2\n\n".iter() {
+            lines.extend(console.write(*byte));
+        }
+        assert_eq!(lines, vec!["boot", "stage 2", ""]);
+
+        // Long lines are split
+        let lines = (0..MAX_LINE_LEN + 1)
+            .filter_map(|_| console.write(b'x'))
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].len(), MAX_LINE_LEN);
+        assert_eq!(console.line, b"x");
+    }
+}
@@ -0,0 +1,262 @@
+//! A stub of the pair of 8237 DMA controllers and their page registers
+//!
+//! The first controller (channels 0-3, 8-bit transfers) is at ports
+//! 0x00-0x0F, and the second (channels 4-7, 16-bit transfers) has its
+//! registers at the even ports of 0xC0-0xDF. The page registers (which
+//! hold the upper bits of the transfer addresses) are at 0x80-0x8F, which
+//! includes the unused port 0x80 that guests write for I/O delays and POST
+//! codes.
+//!
+//! The registers hold what the guest writes to them, so firmware and
+//! drivers probing for the controllers find them, but no transfers are
+//! ever performed (there are no ISA devices to request them). The
+//! status register never reports a terminal count.
+
+use crate::error::Result;
+use crate::virtdev::{DeviceEvent, DeviceRegion, EmulatedDevice, Event, Port};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::RwLock;
+
+// The registers of a controller (as an index from its first port)
+const REG_CHANNEL_LAST: u8 = 0x07;
+const REG_STATUS_COMMAND: u8 = 0x08;
+const REG_REQUEST: u8 = 0x09;
+const REG_SINGLE_MASK: u8 = 0x0a;
+const REG_MODE: u8 = 0x0b;
+const REG_CLEAR_FLIP_FLOP: u8 = 0x0c;
+const REG_TEMP_MASTER_CLEAR: u8 = 0x0d;
+const REG_CLEAR_MASK: u8 = 0x0e;
+const REG_ALL_MASK: u8 = 0x0f;
+
+const ALL_CHANNELS_MASKED: u8 = 0x0f;
+
+#[derive(Debug)]
+struct Controller {
+    address: [u16; 4],
+    count: [u16; 4],
+    mode: [u8; 4],
+    command: u8,
+    request: u8,
+    mask: u8,
+
+    // Selects the low (false) or high (true) byte of the next access to an
+    // address or count register
+    flip_flop: bool,
+}
+
+impl Default for Controller {
+    fn default() -> Self {
+        Controller {
+            address: [0; 4],
+            count: [0; 4],
+            mode: [0; 4],
+            command: 0,
+            request: 0,
+            mask: ALL_CHANNELS_MASKED,
+            flip_flop: false,
+        }
+    }
+}
+
+impl Controller {
+    fn master_clear(&mut self) {
+        self.command = 0;
+        self.request = 0;
+        self.mask = ALL_CHANNELS_MASKED;
+        self.flip_flop = false;
+    }
+
+    fn channel_register(&mut self, reg: u8) -> &mut u16 {
+        let channel = (reg / 2) as usize;
+        if reg % 2 == 0 {
+            &mut self.address[channel]
+        } else {
+            &mut self.count[channel]
+        }
+    }
+
+    fn read(&mut self, reg: u8) -> u8 {
+        match reg {
+            0..=REG_CHANNEL_LAST => {
+                let high = self.flip_flop;
+                self.flip_flop = !self.flip_flop;
+                let val = *self.channel_register(reg);
+                if high {
+                    (val >> 8) as u8
+                } else {
+                    val as u8
+                }
+            }
+            REG_ALL_MASK => self.mask,
+            // The status (no terminal counts and no pending requests) and
+            // temporary registers
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, reg: u8, val: u8) {
+        match reg {
+            0..=REG_CHANNEL_LAST => {
+                let high = self.flip_flop;
+                self.flip_flop = !self.flip_flop;
+                let register = self.channel_register(reg);
+                *register = if high {
+                    (*register & 0x00ff) | (val as u16) << 8
+                } else {
+                    (*register & 0xff00) | val as u16
+                };
+            }
+            REG_STATUS_COMMAND => self.command = val,
+            REG_REQUEST => {
+                let bit = 1 << (val & 0b11);
+                if val & 0b100 != 0 {
+                    self.request |= bit;
+                } else {
+                    self.request &= !bit;
+                }
+            }
+            REG_SINGLE_MASK => {
+                let bit = 1 << (val & 0b11);
+                if val & 0b100 != 0 {
+                    self.mask |= bit;
+                } else {
+                    self.mask &= !bit;
+                }
+            }
+            REG_MODE => self.mode[(val & 0b11) as usize] = val,
+            REG_CLEAR_FLIP_FLOP => self.flip_flop = false,
+            REG_TEMP_MASTER_CLEAR => self.master_clear(),
+            REG_CLEAR_MASK => self.mask = 0,
+            REG_ALL_MASK => self.mask = val & ALL_CHANNELS_MASKED,
+            _ => (),
+        }
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct Dma8237 {
+    // The 8-bit (channels 0-3) and 16-bit (channels 4-7) controllers
+    controllers: [Controller; 2],
+    pages: [u8; 16],
+}
+
+impl Dma8237 {
+    const DMA1_BASE: Port = 0x0000;
+    const DMA1_LAST: Port = 0x000f;
+    const PAGE_BASE: Port = 0x0080;
+    const PAGE_LAST: Port = 0x008f;
+    const DMA2_BASE: Port = 0x00c0;
+    const DMA2_LAST: Port = 0x00df;
+
+    pub fn new() -> Arc<RwLock<Self>> {
+        Arc::new(RwLock::new(Dma8237::default()))
+    }
+
+    fn read_port(&mut self, port: Port) -> u8 {
+        match port {
+            Self::DMA1_BASE..=Self::DMA1_LAST => {
+                self.controllers[0].read((port - Self::DMA1_BASE) as u8)
+            }
+            Self::PAGE_BASE..=Self::PAGE_LAST => {
+                self.pages[(port - Self::PAGE_BASE) as usize]
+            }
+            // The registers of the second controller are at even ports
+            Self::DMA2_BASE..=Self::DMA2_LAST if port % 2 == 0 => {
+                self.controllers[1].read(((port - Self::DMA2_BASE) / 2) as u8)
+            }
+            _ => 0xff,
+        }
+    }
+
+    fn write_port(&mut self, port: Port, val: u8) {
+        match port {
+            Self::DMA1_BASE..=Self::DMA1_LAST => {
+                self.controllers[0].write((port - Self::DMA1_BASE) as u8, val)
+            }
+            Self::PAGE_BASE..=Self::PAGE_LAST => {
+                self.pages[(port - Self::PAGE_BASE) as usize] = val
+            }
+            Self::DMA2_BASE..=Self::DMA2_LAST if port % 2 == 0 => self
+                .controllers[1]
+                .write(((port - Self::DMA2_BASE) / 2) as u8, val),
+            _ => (),
+        }
+    }
+}
+
+impl EmulatedDevice for Dma8237 {
+    fn services(&self) -> Vec<DeviceRegion> {
+        vec![
+            DeviceRegion::PortIo(Self::DMA1_BASE..=Self::DMA1_LAST),
+            DeviceRegion::PortIo(Self::PAGE_BASE..=Self::PAGE_LAST),
+            DeviceRegion::PortIo(Self::DMA2_BASE..=Self::DMA2_LAST),
+        ]
+    }
+
+    fn on_event(&mut self, event: Event) -> Result<()> {
+        // Wider accesses are handled as consecutive byte accesses
+        match event.kind {
+            DeviceEvent::PortRead(port, mut val) => {
+                for (i, byte) in val.as_mut_slice().iter_mut().enumerate() {
+                    *byte = self.read_port(port + i as Port);
+                }
+            }
+            DeviceEvent::PortWrite(port, val) => {
+                for (i, byte) in val.as_slice().iter().enumerate() {
+                    self.write_port(port + i as Port, *byte);
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_channel_registers() {
+        let mut dma = Dma8237::default();
+
+        // Channel 2 (the floppy channel) of the first controller
+        dma.write_port(0x0c, 0);
+        dma.write_port(0x04, 0x34);
+        dma.write_port(0x04, 0x12);
+        dma.write_port(0x81, 0x05);
+        assert_eq!(dma.controllers[0].address[2], 0x1234);
+        dma.write_port(0x0c, 0);
+        assert_eq!((dma.read_port(0x04), dma.read_port(0x04)), (0x34, 0x12));
+        assert_eq!(dma.read_port(0x81), 0x05);
+
+        // Channel 5 of the second controller
+        dma.write_port(0xd8, 0);
+        dma.write_port(0xc4, 0xff);
+        dma.write_port(0xc4, 0x00);
+        assert_eq!(dma.controllers[1].address[1], 0x00ff);
+        assert_eq!(dma.read_port(0xc5), 0xff);
+    }
+
+    #[test]
+    fn test_masks() {
+        let mut dma = Dma8237::default();
+        assert_eq!(dma.read_port(0x0f), 0x0f);
+
+        // Unmask channel 2, then mask it again
+        dma.write_port(0x0a, 0b010);
+        assert_eq!(dma.read_port(0x0f), 0b1011);
+        dma.write_port(0x0a, 0b110);
+        assert_eq!(dma.read_port(0x0f), 0x0f);
+
+        dma.write_port(0x0e, 0);
+        assert_eq!(dma.read_port(0x0f), 0);
+
+        // A master clear masks every channel and resets the flip-flop
+        dma.read_port(0x00);
+        dma.write_port(0x0d, 0);
+        assert_eq!(dma.read_port(0x0f), 0x0f);
+        assert!(!dma.controllers[0].flip_flop);
+    }
+}
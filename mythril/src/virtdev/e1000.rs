@@ -0,0 +1,1338 @@
+//! Emulation of the Intel 82540EM (e1000) gigabit ethernet controller
+//!
+//! The 82540EM is supported by the inbox drivers of most guests (including
+//! installers and Windows), so guests without virtio drivers still have a
+//! network. The controller is a PCI function (see `PciDevice`) whose
+//! registers are in a 128KB memory BAR. The BAR is preset to
+//! `E1000_REGISTER_BASE`, and the registers are only decoded there (the
+//! regions of a `DeviceMap` cannot move), so the guest must not move it.
+//!
+//! Frames are moved through the legacy descriptor rings. Transmit supports
+//! legacy, context and data descriptors, including checksum insertion and
+//! TCP segmentation (which Linux enables on this controller). Frames for
+//! the guest are taken from a `NetBackend` when the guest advances the
+//! receive tail, and as the vCPUs of the VM handle exits (see
+//! `E1000::poll`). The EEPROM is readable through both EERD and the
+//! bit-banged microwire interface of EECD, and the PHY through MDIC. The
+//! link is always up at 1000Mb/s full duplex.
+//!
+//! Frames are exchanged with other VMs through a `SwitchPort`, a port of a
+//! switch in the hypervisor that connects the e1000 controllers of all
+//! VMs.
+
+use crate::error::{Error, Result};
+use crate::memory::{GuestAddressSpace, GuestPhysAddr};
+use crate::vcpu;
+use crate::virtdev::pci::{PciBar, PciConfig, PciDevice, PciFunctionId};
+use crate::virtdev::pic::LEGACY_IRQ_VECTOR_BASE;
+use crate::virtdev::virtio::{read_guest, write_guest};
+use crate::virtdev::{
+    DeviceEvent, DeviceEventResponse, DeviceRegion, EmulatedDevice, Event,
+    ResponseEventArray,
+};
+use alloc::boxed::Box;
+use alloc::collections::vec_deque::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use spin::{Mutex, RwLock};
+
+/// The guest physical address at which the registers are decoded
+pub const E1000_REGISTER_BASE: u64 = 0xfebc_0000;
+
+/// The size of the register BAR
+pub const E1000_REGISTER_SIZE: u32 = 0x2_0000;
+
+/// The identity of the 82540EM
+pub const E1000_FUNCTION_ID: PciFunctionId = PciFunctionId {
+    vendor_id: 0x8086,
+    device_id: 0x100e,
+    class: 0x02,
+    subclass: 0x00,
+};
+
+// The most frames queued for a guest by a `SwitchPort`
+const MAX_QUEUED_FRAMES: usize = 64;
+
+// The largest frame a guest may send, including a TCP segmentation
+// payload
+const MAX_TX_FRAME: usize = 0x1_0000 + 256;
+
+const MIN_FRAME_LEN: usize = 60;
+const FCS_LEN: usize = 4;
+const DESCRIPTOR_SIZE: u64 = 16;
+const VLAN_TPID: u16 = 0x8100;
+
+// The register offsets
+mod reg {
+    pub const CTRL: u64 = 0x0000;
+    pub const STATUS: u64 = 0x0008;
+    pub const EECD: u64 = 0x0010;
+    pub const EERD: u64 = 0x0014;
+    pub const MDIC: u64 = 0x0020;
+    pub const ICR: u64 = 0x00c0;
+    pub const ICS: u64 = 0x00c8;
+    pub const IMS: u64 = 0x00d0;
+    pub const IMC: u64 = 0x00d8;
+    pub const RCTL: u64 = 0x0100;
+    pub const TCTL: u64 = 0x0400;
+    pub const RDBAL: u64 = 0x2800;
+    pub const RDBAH: u64 = 0x2804;
+    pub const RDLEN: u64 = 0x2808;
+    pub const RDH: u64 = 0x2810;
+    pub const RDT: u64 = 0x2818;
+    pub const TDBAL: u64 = 0x3800;
+    pub const TDBAH: u64 = 0x3804;
+    pub const TDLEN: u64 = 0x3808;
+    pub const TDH: u64 = 0x3810;
+    pub const TDT: u64 = 0x3818;
+    pub const MTA: u64 = 0x5200;
+    pub const RAL: u64 = 0x5400;
+    pub const RAH: u64 = 0x5404;
+}
+
+const CTRL_RST: u32 = 1 << 26;
+const CTRL_VME: u32 = 1 << 30;
+
+// Full duplex, link up, 1000Mb/s
+const STATUS_LINK_UP: u32 = 0x83;
+
+const EECD_SK: u32 = 0x01;
+const EECD_CS: u32 = 0x02;
+const EECD_DI: u32 = 0x04;
+const EECD_DO: u32 = 0x08;
+const EECD_REQ: u32 = 0x40;
+const EECD_GNT: u32 = 0x80;
+const EECD_PRES: u32 = 0x100;
+const EEPROM_READ_OPCODE: u32 = 6;
+
+const EERD_START: u32 = 0x01;
+const EERD_DONE: u32 = 0x10;
+
+const MDIC_OP_WRITE: u32 = 1 << 26;
+const MDIC_OP_READ: u32 = 2 << 26;
+const MDIC_READY: u32 = 1 << 28;
+const MDIC_ERROR: u32 = 1 << 30;
+const PHY_ADDRESS: u32 = 1;
+
+const ICR_TXDW: u32 = 0x01;
+const ICR_TXQE: u32 = 0x02;
+const ICR_RXDMT0: u32 = 0x10;
+const ICR_RXT0: u32 = 0x80;
+const ICR_INT_ASSERTED: u32 = 1 << 31;
+
+const RCTL_EN: u32 = 1 << 1;
+const RCTL_UPE: u32 = 1 << 3;
+const RCTL_MPE: u32 = 1 << 4;
+const RCTL_BAM: u32 = 1 << 15;
+const RCTL_BSEX: u32 = 1 << 25;
+const RCTL_SECRC: u32 = 1 << 26;
+
+const RAH_AV: u32 = 1 << 31;
+const RECEIVE_ADDRESSES: u64 = 16;
+
+const TCTL_EN: u32 = 1 << 1;
+
+// The command bits of a transmit descriptor (in its 'lower' dword)
+const TXD_CMD_EOP: u32 = 0x0100_0000;
+const TXD_CMD_TCP: u32 = 0x0100_0000;
+const TXD_CMD_IP: u32 = 0x0200_0000;
+const TXD_CMD_IC: u32 = 0x0400_0000;
+const TXD_CMD_TSE: u32 = 0x0400_0000;
+const TXD_CMD_RS: u32 = 0x0800_0000;
+const TXD_CMD_DEXT: u32 = 0x2000_0000;
+const TXD_CMD_VLE: u32 = 0x4000_0000;
+const TXD_DTYP_DATA: u32 = 0x0010_0000;
+const TXD_STAT_DD: u8 = 0x01;
+const TXD_POPTS_IXSM: u8 = 0x01;
+const TXD_POPTS_TXSM: u8 = 0x02;
+
+const RXD_STAT_DD: u8 = 0x01;
+const RXD_STAT_EOP: u8 = 0x02;
+const RXD_STAT_IXSM: u8 = 0x04;
+const RXD_STAT_VP: u8 = 0x08;
+
+// PCI command register bits
+const PCI_COMMAND_MASTER: u16 = 0x04;
+const PCI_COMMAND_INTX_DISABLE: u16 = 0x400;
+
+const EEPROM_WORDS: usize = 64;
+const EEPROM_CHECKSUM: u16 = 0xbaba;
+
+// The EEPROM contents (as with QEMU), excluding the MAC address (words 0
+// to 2) and the checksum (word 63)
+const EEPROM_TEMPLATE: [u16; EEPROM_WORDS] = [
+    0x0000, 0x0000, 0x0000, 0x0000, 0xffff, 0x0000, 0x0000, 0x0000, 0x3000,
+    0x1000, 0x6403, 0x100e, 0x8086, 0x100e, 0x8086, 0x3040, 0x0008, 0x2000,
+    0x7e14, 0x0048, 0x1000, 0x00d8, 0x0000, 0x2700, 0x6cc9, 0x3150, 0x0722,
+    0x040b, 0x0984, 0x0000, 0xc000, 0x0706, 0x1008, 0x0000, 0x0f04, 0x7fff,
+    0x4d01, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff,
+    0xffff, 0xffff, 0xffff, 0x0100, 0x4000, 0x121c, 0xffff, 0xffff, 0xffff,
+    0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff,
+    0x0000,
+];
+
+// The registers of the (M88E1011) PHY
+const PHY_REGISTERS: usize = 32;
+const PHY_CTRL: usize = 0;
+const PHY_STATUS: usize = 1;
+const PHY_ID1: usize = 2;
+const PHY_ID2: usize = 3;
+const PHY_CTRL_SELF_CLEARING: u16 = 0x8200;
+
+/// The network behind an `E1000`
+pub trait NetBackend: Send + Sync {
+    /// Send `frame` (without its FCS) from the guest
+    fn transmit(&mut self, frame: &[u8]);
+
+    /// Take the next frame for the guest (if there is one)
+    fn receive(&mut self) -> Option<Vec<u8>>;
+
+    /// Returns true if a frame is waiting for the guest
+    fn has_pending(&self) -> bool;
+}
+
+type FrameQueue = Arc<Mutex<VecDeque<Vec<u8>>>>;
+
+// The ports of the switch, by MAC address, each with the frames waiting
+// to be received through it
+static SWITCH: Mutex<Vec<([u8; 6], FrameQueue)>> = Mutex::new(Vec::new());
+
+/// A port of the switch that connects the e1000 controllers of all VMs
+///
+/// A frame is forwarded to the port with its destination address, or to
+/// every other port if it is a broadcast or multicast frame, or if no port
+/// has the address. Each port queues at most `MAX_QUEUED_FRAMES`, so frames
+/// for a guest that does not receive them are dropped.
+pub struct SwitchPort {
+    queue: FrameQueue,
+}
+
+impl SwitchPort {
+    /// Connect a port for the controller with address `mac`
+    pub fn connect(mac: [u8; 6]) -> Self {
+        let queue = FrameQueue::default();
+        SWITCH.lock().push((mac, queue.clone()));
+        Self { queue }
+    }
+}
+
+impl Drop for SwitchPort {
+    fn drop(&mut self) {
+        SWITCH
+            .lock()
+            .retain(|(_, queue)| !Arc::ptr_eq(queue, &self.queue));
+    }
+}
+
+impl NetBackend for SwitchPort {
+    fn transmit(&mut self, frame: &[u8]) {
+        if frame.len() < 6 {
+            return;
+        }
+        let switch = SWITCH.lock();
+        let others = switch
+            .iter()
+            .filter(|(_, queue)| !Arc::ptr_eq(queue, &self.queue));
+        let unicast = frame[0] & 1 == 0
+            && switch.iter().any(|(mac, _)| mac[..] == frame[..6]);
+        for (mac, queue) in others {
+            if unicast && mac[..] != frame[..6] {
+                continue;
+            }
+            let mut queue = queue.lock();
+            if queue.len() < MAX_QUEUED_FRAMES {
+                queue.push_back(frame.to_vec());
+            }
+        }
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        self.queue.lock().pop_front()
+    }
+
+    fn has_pending(&self) -> bool {
+        !self.queue.lock().is_empty()
+    }
+}
+
+// The state of the microwire interface to the EEPROM
+#[derive(Default)]
+struct EepromState {
+    old_eecd: u32,
+    val_in: u32,
+    bits_in: u32,
+    bits_out: u32,
+    reading: bool,
+}
+
+// The offload settings of the last transmit context descriptor
+#[derive(Default, Clone, Copy)]
+struct TxContext {
+    ipcss: usize,
+    ipcso: usize,
+    ipcse: usize,
+    tucss: usize,
+    tucso: usize,
+    tucse: usize,
+    hdr_len: usize,
+    mss: usize,
+    ipv4: bool,
+    tcp: bool,
+}
+
+// The frame being gathered from transmit descriptors
+#[derive(Default)]
+struct TxPacket {
+    data: Vec<u8>,
+    sum_needed: u8,
+    tso: bool,
+    vlan: Option<u16>,
+
+    // Checksum settings from a legacy descriptor
+    legacy: Option<(usize, usize)>,
+}
+
+pub struct E1000 {
+    config: PciConfig,
+    backend: Box<dyn NetBackend>,
+    mac: [u8; 6],
+    regs: Vec<u32>,
+    eeprom: [u16; EEPROM_WORDS],
+    eeprom_state: EepromState,
+    phy: [u16; PHY_REGISTERS],
+    context: TxContext,
+    tx: TxPacket,
+
+    // A frame taken from the backend that did not yet fit in the receive
+    // ring
+    rx_pending: Option<Vec<u8>>,
+
+    // Whether the interrupt was asserted (as it is raised on the edge)
+    asserted: bool,
+}
+
+impl E1000 {
+    /// A controller with the address `mac`, connected to `backend`, that
+    /// raises `irq` until the guest assigns another interrupt line
+    pub fn new(
+        mac: [u8; 6],
+        irq: u8,
+        backend: Box<dyn NetBackend>,
+    ) -> Result<Arc<RwLock<Self>>> {
+        let mut config = PciConfig::new(E1000_FUNCTION_ID);
+        config.set_bar(0, PciBar::Memory32(E1000_REGISTER_SIZE))?;
+        config.write(0x10, &(E1000_REGISTER_BASE as u32).to_le_bytes());
+        config.set_interrupt_pin(1);
+        config.set_interrupt_line(irq);
+
+        let mut eeprom = EEPROM_TEMPLATE;
+        for (i, word) in mac.chunks_exact(2).enumerate() {
+            eeprom[i] = LittleEndian::read_u16(word);
+        }
+        let sum = eeprom[..EEPROM_WORDS - 1]
+            .iter()
+            .fold(0u16, |sum, word| sum.wrapping_add(*word));
+        eeprom[EEPROM_WORDS - 1] = EEPROM_CHECKSUM.wrapping_sub(sum);
+
+        let mut e1000 = Self {
+            config,
+            backend,
+            mac,
+            regs: vec![0; E1000_REGISTER_SIZE as usize / 4],
+            eeprom,
+            eeprom_state: EepromState::default(),
+            phy: [0; PHY_REGISTERS],
+            context: TxContext::default(),
+            tx: TxPacket::default(),
+            rx_pending: None,
+            asserted: false,
+        };
+        e1000.reset();
+        Ok(Arc::new(RwLock::new(e1000)))
+    }
+
+    /// Return the registers to their state at power on (e.g., when the VM
+    /// is reset)
+    pub fn reset(&mut self) {
+        for reg in self.regs.iter_mut() {
+            *reg = 0;
+        }
+        self.set_reg(reg::STATUS, STATUS_LINK_UP);
+        self.set_reg(reg::RAL, LittleEndian::read_u32(&self.mac[..4]));
+        self.set_reg(
+            reg::RAH,
+            LittleEndian::read_u16(&self.mac[4..]) as u32 | RAH_AV,
+        );
+
+        self.phy = [0; PHY_REGISTERS];
+        self.phy[PHY_CTRL] = 0x1140;
+        self.phy[PHY_STATUS] = 0x796d;
+        self.phy[PHY_ID1] = 0x0141;
+        self.phy[PHY_ID2] = 0x0c20;
+        self.phy[4] = 0x0de1;
+        self.phy[5] = 0x41e1;
+        self.phy[9] = 0x0e00;
+        self.phy[10] = 0x3c00;
+        self.phy[16] = 0x0360;
+        self.phy[17] = 0xac00;
+        self.phy[20] = 0x0d60;
+
+        self.eeprom_state = EepromState::default();
+        self.context = TxContext::default();
+        self.tx = TxPacket::default();
+        self.asserted = false;
+    }
+
+    /// The address of the controller
+    pub fn mac(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    /// Returns true if a frame is waiting to be received by the guest
+    pub fn receive_pending(&self) -> bool {
+        self.rx_pending.is_some() || self.backend.has_pending()
+    }
+
+    /// Move the frames waiting for the guest in to the receive ring (while
+    /// it has room)
+    pub fn poll(
+        &mut self,
+        space: &GuestAddressSpace,
+        responses: &mut ResponseEventArray,
+    ) -> Result<()> {
+        let enabled = self.reg(reg::RCTL) & RCTL_EN != 0;
+        while enabled && self.dma_enabled() {
+            let frame = match self.rx_pending.take() {
+                Some(frame) => frame,
+                None => match self.backend.receive() {
+                    Some(frame) => frame,
+                    None => break,
+                },
+            };
+            if !self.accepts(&frame) {
+                continue;
+            }
+            if !self.receive(space, &frame)? {
+                self.rx_pending = Some(frame);
+                break;
+            }
+        }
+        self.update_interrupt(responses);
+        Ok(())
+    }
+
+    fn reg(&self, offset: u64) -> u32 {
+        self.regs[offset as usize / 4]
+    }
+
+    fn set_reg(&mut self, offset: u64, val: u32) {
+        self.regs[offset as usize / 4] = val;
+    }
+
+    fn dma_enabled(&self) -> bool {
+        self.config.command() & PCI_COMMAND_MASTER != 0
+    }
+
+    fn read_register(&mut self, offset: u64) -> u32 {
+        match offset {
+            reg::EECD => self.read_eecd(),
+            reg::ICR => {
+                let icr = self.reg(reg::ICR);
+                self.set_reg(reg::ICR, 0);
+                self.asserted = false;
+                if icr & self.reg(reg::IMS) != 0 {
+                    icr | ICR_INT_ASSERTED
+                } else {
+                    icr
+                }
+            }
+            reg::ICS | reg::IMC => 0,
+            _ => self.reg(offset),
+        }
+    }
+
+    fn write_register(
+        &mut self,
+        offset: u64,
+        val: u32,
+        space: &GuestAddressSpace,
+    ) -> Result<()> {
+        match offset {
+            reg::CTRL => {
+                if val & CTRL_RST != 0 {
+                    self.reset();
+                }
+                self.set_reg(reg::CTRL, val & !CTRL_RST);
+            }
+            reg::STATUS => (),
+            reg::EECD => self.write_eecd(val),
+            reg::EERD => self.write_eerd(val),
+            reg::MDIC => self.write_mdic(val),
+            reg::ICR => {
+                let icr = self.reg(reg::ICR);
+                self.set_reg(reg::ICR, icr & !val);
+            }
+            reg::ICS => self.cause(val),
+            reg::IMS => {
+                let ims = self.reg(reg::IMS);
+                self.set_reg(reg::IMS, ims | val);
+            }
+            reg::IMC => {
+                let ims = self.reg(reg::IMS);
+                self.set_reg(reg::IMS, ims & !val);
+            }
+            reg::RDH | reg::TDH | reg::RDT | reg::TDT => {
+                self.set_reg(offset, val & 0xffff);
+                if offset == reg::TDT {
+                    self.transmit(space)?;
+                }
+            }
+            reg::RDLEN | reg::TDLEN => self.set_reg(offset, val & 0xf_ff80),
+            _ => self.set_reg(offset, val),
+        }
+        Ok(())
+    }
+
+    fn read_eecd(&self) -> u32 {
+        let state = &self.eeprom_state;
+        let mut eecd = EECD_PRES | EECD_GNT | state.old_eecd;
+        let word = self.eeprom[(state.bits_out as usize >> 4) & 0x3f];
+        if !state.reading || (word >> ((state.bits_out & 0xf) ^ 0xf)) & 1 != 0 {
+            eecd |= EECD_DO;
+        }
+        eecd
+    }
+
+    // Clock the microwire interface, which shifts in a 3-bit opcode and a
+    // 6-bit word address, then shifts out the word (MSB first) on each
+    // falling edge of SK
+    fn write_eecd(&mut self, val: u32) {
+        let state = &mut self.eeprom_state;
+        let old = state.old_eecd;
+        state.old_eecd = val & (EECD_SK | EECD_CS | EECD_DI | EECD_REQ);
+        if val & EECD_CS == 0 {
+            return;
+        }
+        if (val ^ old) & EECD_CS != 0 {
+            *state = EepromState {
+                old_eecd: state.old_eecd,
+                ..EepromState::default()
+            };
+        }
+        if (val ^ old) & EECD_SK == 0 {
+            return;
+        }
+        if val & EECD_SK == 0 {
+            state.bits_out = state.bits_out.wrapping_add(1);
+            return;
+        }
+        state.val_in = (state.val_in << 1) | ((val & EECD_DI != 0) as u32);
+        state.bits_in += 1;
+        if state.bits_in == 9 && !state.reading {
+            state.bits_out = ((state.val_in & 0x3f) << 4).wrapping_sub(1);
+            state.reading = (state.val_in >> 6) & 7 == EEPROM_READ_OPCODE;
+        }
+    }
+
+    fn write_eerd(&mut self, val: u32) {
+        if val & EERD_START == 0 {
+            self.set_reg(reg::EERD, val);
+            return;
+        }
+        let index = (val >> 8) & 0xff;
+        let word = self.eeprom.get(index as usize).copied().unwrap_or(0);
+        self.set_reg(reg::EERD, (word as u32) << 16 | index << 8 | EERD_DONE);
+    }
+
+    fn write_mdic(&mut self, val: u32) {
+        let phy = (val >> 21) & 0x1f;
+        let index = ((val >> 16) & 0x1f) as usize;
+        let op = val & (3 << 26);
+        let mut result = val & !(0xffff | MDIC_ERROR);
+        if phy != PHY_ADDRESS {
+            result |= MDIC_ERROR;
+        } else if op == MDIC_OP_READ {
+            result |= self.phy[index] as u32;
+        } else if op == MDIC_OP_WRITE {
+            // The status and identity are read-only
+            if !matches!(index, PHY_STATUS | PHY_ID1 | PHY_ID2) {
+                let mut data = val as u16;
+                if index == PHY_CTRL {
+                    data &= !PHY_CTRL_SELF_CLEARING;
+                }
+                self.phy[index] = data;
+            }
+            result |= val & 0xffff;
+        }
+        self.set_reg(reg::MDIC, result | MDIC_READY);
+    }
+
+    fn cause(&mut self, causes: u32) {
+        let icr = self.reg(reg::ICR);
+        self.set_reg(reg::ICR, icr | causes);
+    }
+
+    // Raise the interrupt if an unmasked cause became pending
+    fn update_interrupt(&mut self, responses: &mut ResponseEventArray) {
+        let pending = self.reg(reg::ICR) & self.reg(reg::IMS) != 0;
+        let disabled = self.config.command() & PCI_COMMAND_INTX_DISABLE != 0;
+        if pending && !self.asserted && !disabled {
+            let line = self.config.interrupt_line();
+            if line < 16 {
+                responses.push(DeviceEventResponse::Interrupt((
+                    LEGACY_IRQ_VECTOR_BASE + line,
+                    vcpu::InjectedInterruptType::ExternalInterrupt,
+                )));
+            }
+        }
+        self.asserted = pending;
+    }
+
+    fn ring_base(&self, low: u64, high: u64) -> u64 {
+        (self.reg(high) as u64) << 32 | (self.reg(low) & !0xf) as u64
+    }
+
+    // Process the transmit descriptors from the head to the tail
+    fn transmit(&mut self, space: &GuestAddressSpace) -> Result<()> {
+        if self.reg(reg::TCTL) & TCTL_EN == 0 || !self.dma_enabled() {
+            return Ok(());
+        }
+        let base = self.ring_base(reg::TDBAL, reg::TDBAH);
+        let count = self.reg(reg::TDLEN) as u64 / DESCRIPTOR_SIZE;
+        let tail = self.reg(reg::TDT) as u64;
+        let mut head = self.reg(reg::TDH) as u64;
+        if count == 0 || head >= count || tail >= count {
+            return Ok(());
+        }
+
+        let mut causes = 0;
+        while head != tail {
+            let addr = base + head * DESCRIPTOR_SIZE;
+            let mut desc = [0u8; DESCRIPTOR_SIZE as usize];
+            read_guest(space, addr, &mut desc)?;
+            self.process_tx_descriptor(space, &desc)?;
+
+            let lower = LittleEndian::read_u32(&desc[8..12]);
+            if lower & TXD_CMD_RS != 0 {
+                write_guest(space, addr + 12, &[desc[12] | TXD_STAT_DD])?;
+                causes |= ICR_TXDW;
+            }
+            head = (head + 1) % count;
+        }
+        self.set_reg(reg::TDH, head as u32);
+        self.cause(causes | ICR_TXQE);
+        Ok(())
+    }
+
+    fn process_tx_descriptor(
+        &mut self,
+        space: &GuestAddressSpace,
+        desc: &[u8],
+    ) -> Result<()> {
+        let lower = LittleEndian::read_u32(&desc[8..12]);
+        if lower & TXD_CMD_DEXT != 0 && lower & TXD_DTYP_DATA == 0 {
+            self.context = TxContext {
+                ipcss: desc[0] as usize,
+                ipcso: desc[1] as usize,
+                ipcse: LittleEndian::read_u16(&desc[2..4]) as usize,
+                tucss: desc[4] as usize,
+                tucso: desc[5] as usize,
+                tucse: LittleEndian::read_u16(&desc[6..8]) as usize,
+                hdr_len: desc[13] as usize,
+                mss: LittleEndian::read_u16(&desc[14..16]) as usize,
+                ipv4: lower & TXD_CMD_IP != 0,
+                tcp: lower & TXD_CMD_TCP != 0,
+            };
+            return Ok(());
+        }
+
+        let len = if lower & TXD_CMD_DEXT != 0 {
+            // The offloads are selected by the first data descriptor
+            if self.tx.data.is_empty() {
+                self.tx.sum_needed = desc[13];
+                self.tx.tso = lower & TXD_CMD_TSE != 0;
+            }
+            lower as usize & 0xf_ffff
+        } else {
+            if self.tx.data.is_empty() && lower & TXD_CMD_IC != 0 {
+                self.tx.legacy = Some((desc[13] as usize, desc[10] as usize));
+            }
+            lower as usize & 0xffff
+        };
+        if lower & TXD_CMD_VLE != 0 {
+            self.tx.vlan = Some(LittleEndian::read_u16(&desc[14..16]));
+        }
+
+        let start = self.tx.data.len();
+        if start + len > MAX_TX_FRAME {
+            warn!(
+                "e1000: Dropping oversized transmit of {} bytes",
+                start + len
+            );
+            self.tx = TxPacket::default();
+            return Ok(());
+        }
+        self.tx.data.resize(start + len, 0);
+        let addr = LittleEndian::read_u64(&desc[..8]);
+        read_guest(space, addr, &mut self.tx.data[start..])?;
+
+        if lower & TXD_CMD_EOP != 0 {
+            let packet = core::mem::replace(&mut self.tx, TxPacket::default());
+            self.send_packet(packet);
+        }
+        Ok(())
+    }
+
+    // Send a gathered packet, segmenting it and inserting checksums as
+    // requested by the guest
+    fn send_packet(&mut self, packet: TxPacket) {
+        let ctx = self.context;
+        if let Some((css, cso)) = packet.legacy {
+            let mut frame = packet.data;
+            insert_checksum(&mut frame, cso, css, 0);
+            return self.send_frame(frame, packet.vlan);
+        }
+        if !packet.tso || ctx.mss == 0 || packet.data.len() <= ctx.hdr_len {
+            let mut frame = packet.data;
+            self.insert_offload_checksums(&mut frame, packet.sum_needed);
+            return self.send_frame(frame, packet.vlan);
+        }
+
+        // Every segment repeats the headers, so they must fit in `hdr_len`
+        let ip_hdr_len = if ctx.ipv4 { 20 } else { 40 };
+        let l4_hdr_len = if ctx.tcp { 20 } else { 8 };
+        if ctx.ipcss + ip_hdr_len > ctx.hdr_len
+            || ctx.tucss + l4_hdr_len > ctx.hdr_len
+        {
+            warn!(
+                "e1000: Dropping segmentation with a {} byte header",
+                ctx.hdr_len
+            );
+            return;
+        }
+
+        let (header, payload) = packet.data.split_at(ctx.hdr_len);
+        let segments = (payload.len() + ctx.mss - 1) / ctx.mss;
+        for (i, chunk) in payload.chunks(ctx.mss).enumerate() {
+            let mut frame = header.to_vec();
+            frame.extend_from_slice(chunk);
+
+            let ip = ctx.ipcss;
+            let ip_len = (frame.len() - ip) as u16;
+            if ctx.ipv4 {
+                BigEndian::write_u16(&mut frame[ip + 2..], ip_len);
+                let id = BigEndian::read_u16(&frame[ip + 4..]);
+                BigEndian::write_u16(
+                    &mut frame[ip + 4..],
+                    id.wrapping_add(i as u16),
+                );
+            } else {
+                // The payload length of an IPv6 header
+                let payload_len = match ip_len.checked_sub(40) {
+                    Some(len) => len,
+                    None => break,
+                };
+                BigEndian::write_u16(&mut frame[ip + 4..], payload_len);
+            }
+
+            let l4 = ctx.tucss;
+            let l4_len = frame.len() - l4;
+            if ctx.tcp {
+                let seq = BigEndian::read_u32(&frame[l4 + 4..]);
+                BigEndian::write_u32(
+                    &mut frame[l4 + 4..],
+                    seq.wrapping_add((i * ctx.mss) as u32),
+                );
+                // Only the last segment keeps PSH and FIN
+                if i + 1 < segments {
+                    frame[l4 + 13] &= !0x09;
+                }
+            } else {
+                BigEndian::write_u16(&mut frame[l4 + 4..], l4_len as u16);
+            }
+
+            // The guest leaves the length out of the pseudo-header sum
+            if packet.sum_needed & TXD_POPTS_TXSM != 0
+                && ctx.tucso + 2 <= frame.len()
+            {
+                let sum = BigEndian::read_u16(&frame[ctx.tucso..]) as u32
+                    + l4_len as u32;
+                let sum = (sum >> 16) + (sum & 0xffff);
+                BigEndian::write_u16(&mut frame[ctx.tucso..], sum as u16);
+            }
+            self.insert_offload_checksums(&mut frame, packet.sum_needed);
+            self.send_frame(frame, packet.vlan);
+        }
+    }
+
+    fn insert_offload_checksums(&self, frame: &mut [u8], sum_needed: u8) {
+        let ctx = &self.context;
+        if sum_needed & TXD_POPTS_TXSM != 0 {
+            insert_checksum(frame, ctx.tucso, ctx.tucss, ctx.tucse);
+        }
+        if sum_needed & TXD_POPTS_IXSM != 0 {
+            insert_checksum(frame, ctx.ipcso, ctx.ipcss, ctx.ipcse);
+        }
+    }
+
+    fn send_frame(&mut self, mut frame: Vec<u8>, vlan: Option<u16>) {
+        if let Some(tci) = vlan {
+            if self.reg(reg::CTRL) & CTRL_VME != 0 && frame.len() >= 12 {
+                let mut tag = [0u8; 4];
+                BigEndian::write_u16(&mut tag[..2], VLAN_TPID);
+                BigEndian::write_u16(&mut tag[2..], tci);
+                frame.splice(12..12, tag.iter().copied());
+            }
+        }
+        self.backend.transmit(&frame);
+    }
+
+    // Returns true if the receive filters accept `frame`
+    fn accepts(&self, frame: &[u8]) -> bool {
+        if frame.len() < 6 {
+            return false;
+        }
+        let rctl = self.reg(reg::RCTL);
+        let multicast = frame[0] & 1 != 0;
+        if (rctl & RCTL_UPE != 0 && !multicast)
+            || (rctl & RCTL_MPE != 0 && multicast)
+            || (rctl & RCTL_BAM != 0 && frame[..6] == [0xff; 6])
+        {
+            return true;
+        }
+
+        if !multicast {
+            return (0..RECEIVE_ADDRESSES).any(|i| {
+                let high = self.reg(reg::RAH + i * 8);
+                let mut addr = [0u8; 8];
+                LittleEndian::write_u32(
+                    &mut addr[..4],
+                    self.reg(reg::RAL + i * 8),
+                );
+                LittleEndian::write_u32(&mut addr[4..], high);
+                high & RAH_AV != 0 && addr[..6] == frame[..6]
+            });
+        }
+
+        // The multicast table is indexed by 12 bits of the address, at an
+        // offset selected by RCTL.MO
+        let shift = [4, 3, 2, 0][(rctl >> 12) as usize & 3];
+        let hash = (LittleEndian::read_u16(&frame[4..6]) >> shift) & 0xfff;
+        let entry = self.reg(reg::MTA + (hash as u64 >> 5) * 4);
+        entry & (1 << (hash & 0x1f)) != 0
+    }
+
+    fn rx_buffer_size(&self) -> usize {
+        let rctl = self.reg(reg::RCTL);
+        let size = (rctl >> 16) & 3;
+        match (rctl & RCTL_BSEX != 0, size) {
+            (false, 0) => 2048,
+            (false, size) => 2048 >> size,
+            (true, 0) => 2048,
+            (true, size) => 32768 >> size,
+        }
+    }
+
+    // Write `frame` to the receive ring, returning false if there are not
+    // enough descriptors available
+    fn receive(
+        &mut self,
+        space: &GuestAddressSpace,
+        frame: &[u8],
+    ) -> Result<bool> {
+        let base = self.ring_base(reg::RDBAL, reg::RDBAH);
+        let count = self.reg(reg::RDLEN) as u64 / DESCRIPTOR_SIZE;
+        let tail = self.reg(reg::RDT) as u64;
+        let mut head = self.reg(reg::RDH) as u64;
+        if count == 0 || head >= count || tail >= count {
+            return Ok(false);
+        }
+
+        let mut data = frame.to_vec();
+        let mut special = 0;
+        let mut status = RXD_STAT_IXSM;
+        if self.reg(reg::CTRL) & CTRL_VME != 0
+            && data.len() >= 16
+            && BigEndian::read_u16(&data[12..]) == VLAN_TPID
+        {
+            special = BigEndian::read_u16(&data[14..]);
+            status |= RXD_STAT_VP;
+            data.drain(12..16);
+        }
+        if data.len() < MIN_FRAME_LEN {
+            data.resize(MIN_FRAME_LEN, 0);
+        }
+        if self.reg(reg::RCTL) & RCTL_SECRC == 0 {
+            data.extend_from_slice(&[0; FCS_LEN]);
+        }
+
+        let buffer_size = self.rx_buffer_size();
+        let needed = ((data.len() + buffer_size - 1) / buffer_size) as u64;
+        let available = (tail + count - head) % count;
+        if available < needed {
+            return Ok(false);
+        }
+
+        let chunks = data.chunks(buffer_size);
+        let last = chunks.len() - 1;
+        for (i, chunk) in chunks.enumerate() {
+            let addr = base + head * DESCRIPTOR_SIZE;
+            let mut desc = [0u8; DESCRIPTOR_SIZE as usize];
+            read_guest(space, addr, &mut desc)?;
+            write_guest(space, LittleEndian::read_u64(&desc[..8]), chunk)?;
+
+            LittleEndian::write_u16(&mut desc[8..10], chunk.len() as u16);
+            LittleEndian::write_u16(&mut desc[10..12], 0);
+            desc[12] = status | RXD_STAT_DD;
+            if i == last {
+                desc[12] |= RXD_STAT_EOP;
+            }
+            desc[13] = 0;
+            LittleEndian::write_u16(&mut desc[14..16], special);
+            write_guest(space, addr + 8, &desc[8..])?;
+            head = (head + 1) % count;
+        }
+        self.set_reg(reg::RDH, head as u32);
+
+        // RCTL.RDMTS selects the threshold as a half, a quarter or an
+        // eighth of the ring
+        let threshold = count >> (((self.reg(reg::RCTL) >> 8) & 3) + 1);
+        let mut causes = ICR_RXT0;
+        if (tail + count - head) % count <= threshold {
+            causes |= ICR_RXDMT0;
+        }
+        self.cause(causes);
+        Ok(true)
+    }
+}
+
+// Store the internet checksum of `frame[css..=cse]` (or to the end of the
+// frame if `cse` is 0) at `cso`
+fn insert_checksum(frame: &mut [u8], cso: usize, css: usize, cse: usize) {
+    let end = if cse != 0 && cse < frame.len() {
+        cse + 1
+    } else {
+        frame.len()
+    };
+    if css >= end || cso + 2 > end {
+        return;
+    }
+    let mut sum = frame[css..end].chunks(2).fold(0u32, |sum, word| {
+        let high = (word[0] as u32) << 8;
+        sum + high + word.get(1).copied().unwrap_or(0) as u32
+    });
+    while sum >> 16 != 0 {
+        sum = (sum >> 16) + (sum & 0xffff);
+    }
+    let sum = match !(sum as u16) {
+        0 => 0xffff,
+        sum => sum,
+    };
+    BigEndian::write_u16(&mut frame[cso..], sum);
+}
+
+impl PciDevice for E1000 {
+    fn config(&self) -> &PciConfig {
+        &self.config
+    }
+
+    fn config_mut(&mut self) -> &mut PciConfig {
+        &mut self.config
+    }
+
+    fn config_written(
+        &mut self,
+        offset: u16,
+        _len: usize,
+        _responses: &mut ResponseEventArray,
+    ) -> Result<()> {
+        // The guest sizes the BAR by writing all ones to it, then restores it
+        let sizing = !(E1000_REGISTER_SIZE - 1) as u64;
+        match self.config.bar_address(0) {
+            Some(addr)
+                if (0x10..0x14).contains(&offset)
+                    && addr != E1000_REGISTER_BASE
+                    && addr != sizing =>
+            {
+                warn!(
+                    "e1000: BAR moved to 0x{:x}, but the registers are only decoded at 0x{:x}",
+                    addr, E1000_REGISTER_BASE
+                );
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+impl EmulatedDevice for E1000 {
+    fn services(&self) -> Vec<DeviceRegion> {
+        vec![DeviceRegion::MemIo(
+            GuestPhysAddr::new(E1000_REGISTER_BASE)
+                ..=GuestPhysAddr::new(
+                    E1000_REGISTER_BASE + E1000_REGISTER_SIZE as u64 - 1,
+                ),
+        )]
+    }
+
+    fn on_event(&mut self, event: Event) -> Result<()> {
+        let mut space = event.space;
+        match event.kind {
+            DeviceEvent::MemRead(addr, mut req) => {
+                let offset = addr.as_u64() - E1000_REGISTER_BASE;
+                let data = req.as_mut_slice();
+                if offset % 4 != 0 || data.len() != 4 {
+                    return Err(Error::InvalidValue(format!(
+                        "e1000: Invalid read of {} bytes at 0x{:x}",
+                        data.len(),
+                        offset
+                    )));
+                }
+                let val = self.read_register(offset);
+                LittleEndian::write_u32(data, val);
+            }
+            DeviceEvent::MemWrite(addr, req) => {
+                let offset = addr.as_u64() - E1000_REGISTER_BASE;
+                let data = req.as_slice();
+                if offset % 4 != 0 || data.len() != 4 {
+                    return Err(Error::InvalidValue(format!(
+                        "e1000: Invalid write of {} bytes at 0x{:x}",
+                        data.len(),
+                        offset
+                    )));
+                }
+                let val = LittleEndian::read_u32(data);
+                self.write_register(offset, val, space.space_mut())?;
+
+                // New receive descriptors may make room for waiting frames
+                if offset == reg::RDT {
+                    return self.poll(space.space_mut(), event.responses);
+                }
+                self.update_interrupt(event.responses);
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::virtdev::virtio::test::setup_space;
+
+    const MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+    const TX_RING: u64 = 0x1000;
+    const RX_RING: u64 = 0x2000;
+    const BUFFERS: u64 = 0x4000;
+
+    #[derive(Default)]
+    struct TestBackend {
+        sent: Arc<Mutex<Vec<Vec<u8>>>>,
+        received: FrameQueue,
+    }
+
+    impl NetBackend for TestBackend {
+        fn transmit(&mut self, frame: &[u8]) {
+            self.sent.lock().push(frame.to_vec());
+        }
+
+        fn receive(&mut self) -> Option<Vec<u8>> {
+            self.received.lock().pop_front()
+        }
+
+        fn has_pending(&self) -> bool {
+            !self.received.lock().is_empty()
+        }
+    }
+
+    fn setup() -> (E1000, Arc<Mutex<Vec<Vec<u8>>>>, FrameQueue) {
+        let backend = TestBackend::default();
+        let sent = backend.sent.clone();
+        let received = backend.received.clone();
+        let e1000 = E1000::new(MAC, 11, Box::new(backend)).unwrap();
+        let mut e1000 = Arc::try_unwrap(e1000).ok().unwrap().into_inner();
+        e1000.config.write(0x04, &[0x06, 0x00]);
+        (e1000, sent, received)
+    }
+
+    fn write(
+        e1000: &mut E1000,
+        space: &GuestAddressSpace,
+        offset: u64,
+        val: u32,
+    ) {
+        e1000.write_register(offset, val, space).unwrap();
+    }
+
+    #[test]
+    fn test_eeprom() {
+        let (mut e1000, _, _) = setup();
+        let space = setup_space();
+        let sum = e1000
+            .eeprom
+            .iter()
+            .fold(0u16, |sum, word| sum.wrapping_add(*word));
+        assert_eq!(sum, EEPROM_CHECKSUM);
+
+        write(&mut e1000, &space, reg::EERD, 1 << 8 | EERD_START);
+        assert_eq!(e1000.read_register(reg::EERD), 0x1200_0110);
+
+        // Read word 2 through the microwire interface
+        let clock = |e1000: &mut E1000, di: u32| {
+            e1000.write_eecd(EECD_CS | di);
+            e1000.write_eecd(EECD_CS | EECD_SK | di);
+        };
+        e1000.write_eecd(0);
+        for bit in (0..9).rev() {
+            let di = if (6 << 6 | 2) >> bit & 1 != 0 {
+                EECD_DI
+            } else {
+                0
+            };
+            clock(&mut e1000, di);
+        }
+        let mut word = 0u16;
+        for _ in 0..16 {
+            clock(&mut e1000, 0);
+            word = word << 1 | (e1000.read_eecd() & EECD_DO != 0) as u16;
+        }
+        assert_eq!(word, 0x5634);
+    }
+
+    #[test]
+    fn test_mdic() {
+        let (mut e1000, _, _) = setup();
+        let space = setup_space();
+        write(
+            &mut e1000,
+            &space,
+            reg::MDIC,
+            MDIC_OP_READ | 1 << 21 | 2 << 16,
+        );
+        assert_eq!(e1000.read_register(reg::MDIC) & 0xffff, 0x0141);
+        assert_ne!(e1000.read_register(reg::MDIC) & MDIC_READY, 0);
+
+        write(&mut e1000, &space, reg::MDIC, MDIC_OP_READ | 2 << 21);
+        assert_ne!(e1000.read_register(reg::MDIC) & MDIC_ERROR, 0);
+    }
+
+    #[test]
+    fn test_transmit() {
+        let (mut e1000, sent, _) = setup();
+        let space = setup_space();
+        let mut responses = ResponseEventArray::default();
+        write(&mut e1000, &space, reg::IMS, ICR_TXDW);
+        write(&mut e1000, &space, reg::TDBAL, TX_RING as u32);
+        write(&mut e1000, &space, reg::TDLEN, 8 * DESCRIPTOR_SIZE as u32);
+        write(&mut e1000, &space, reg::TCTL, TCTL_EN);
+
+        // A frame split across two legacy descriptors
+        write_guest(&space, BUFFERS, &[0xaa; 40]).unwrap();
+        write_guest(&space, BUFFERS + 0x100, &[0xbb; 24]).unwrap();
+        let mut desc = [0u8; 32];
+        LittleEndian::write_u64(&mut desc[..8], BUFFERS);
+        LittleEndian::write_u32(&mut desc[8..12], 40);
+        LittleEndian::write_u64(&mut desc[16..24], BUFFERS + 0x100);
+        LittleEndian::write_u32(
+            &mut desc[24..28],
+            24 | TXD_CMD_EOP | TXD_CMD_RS,
+        );
+        write_guest(&space, TX_RING, &desc).unwrap();
+        write(&mut e1000, &space, reg::TDT, 2);
+        e1000.update_interrupt(&mut responses);
+
+        assert_eq!(sent.lock().len(), 1);
+        assert_eq!(&sent.lock()[0][38..42], &[0xaa, 0xaa, 0xbb, 0xbb]);
+        assert_eq!(e1000.reg(reg::TDH), 2);
+        let mut status = [0u8; 1];
+        read_guest(&space, TX_RING + 16 + 12, &mut status).unwrap();
+        assert_eq!(status[0], TXD_STAT_DD);
+        assert_eq!(responses.len(), 1);
+        assert_eq!(
+            e1000.read_register(reg::ICR),
+            ICR_INT_ASSERTED | ICR_TXDW | ICR_TXQE
+        );
+    }
+
+    #[test]
+    fn test_tcp_segmentation() {
+        let (mut e1000, sent, _) = setup();
+        let space = setup_space();
+        write(&mut e1000, &space, reg::TDBAL, TX_RING as u32);
+        write(&mut e1000, &space, reg::TDLEN, 8 * DESCRIPTOR_SIZE as u32);
+        write(&mut e1000, &space, reg::TCTL, TCTL_EN);
+
+        // Ethernet, IPv4 and TCP headers (with PSH and FIN), then a payload
+        // of 2.5 segments
+        let mut packet = vec![0u8; 54];
+        packet[12] = 0x08;
+        packet[14] = 0x45;
+        BigEndian::write_u16(&mut packet[18..], 0x100);
+        BigEndian::write_u32(&mut packet[38..], 1000);
+        packet[47] = 0x19;
+        packet.extend((0..250).map(|i| i as u8));
+        write_guest(&space, BUFFERS, &packet).unwrap();
+
+        let mut desc = [0u8; 32];
+        desc[0] = 14;
+        desc[1] = 24;
+        desc[2] = 33;
+        desc[4] = 34;
+        desc[5] = 50;
+        LittleEndian::write_u32(
+            &mut desc[8..12],
+            250 | TXD_CMD_DEXT | TXD_CMD_TSE | TXD_CMD_IP | TXD_CMD_TCP,
+        );
+        desc[13] = 54;
+        LittleEndian::write_u16(&mut desc[14..16], 100);
+        LittleEndian::write_u64(&mut desc[16..24], BUFFERS);
+        LittleEndian::write_u32(
+            &mut desc[24..28],
+            packet.len() as u32
+                | TXD_CMD_DEXT
+                | TXD_DTYP_DATA
+                | TXD_CMD_TSE
+                | TXD_CMD_EOP,
+        );
+        desc[29] = TXD_POPTS_IXSM | TXD_POPTS_TXSM;
+        write_guest(&space, TX_RING, &desc).unwrap();
+        write(&mut e1000, &space, reg::TDT, 2);
+
+        let sent = sent.lock();
+        assert_eq!(sent.len(), 3);
+        for (i, frame) in sent.iter().enumerate() {
+            let payload = if i == 2 { 50 } else { 100 };
+            assert_eq!(frame.len(), 54 + payload);
+            assert_eq!(BigEndian::read_u16(&frame[16..]), 40 + payload as u16);
+            assert_eq!(BigEndian::read_u16(&frame[18..]), 0x100 + i as u16);
+            assert_eq!(
+                BigEndian::read_u32(&frame[38..]),
+                1000 + i as u32 * 100
+            );
+            assert_eq!(frame[47], if i == 2 { 0x19 } else { 0x10 });
+            assert_eq!(frame[54], (i * 100) as u8);
+
+            // The IP header sums to zero with its checksum
+            let mut header = frame[14..34].to_vec();
+            header[10] = 0;
+            header[11] = 0;
+            insert_checksum(&mut header, 10, 0, 0);
+            assert_eq!(&header[10..12], &frame[24..26]);
+            assert_ne!(BigEndian::read_u16(&frame[50..]), 0);
+        }
+    }
+
+    #[test]
+    fn test_segmentation_short_header() {
+        let (mut e1000, sent, _) = setup();
+        let space = setup_space();
+        write(&mut e1000, &space, reg::TDBAL, TX_RING as u32);
+        write(&mut e1000, &space, reg::TDLEN, 8 * DESCRIPTOR_SIZE as u32);
+        write(&mut e1000, &space, reg::TCTL, TCTL_EN);
+
+        let packet = vec![0u8; 200];
+        write_guest(&space, BUFFERS, &packet).unwrap();
+
+        // An IPv6 context whose header length cannot hold the IPv6 header
+        let mut desc = [0u8; 32];
+        desc[0] = 14;
+        desc[2] = 33;
+        desc[4] = 34;
+        desc[5] = 50;
+        LittleEndian::write_u32(
+            &mut desc[8..12],
+            150 | TXD_CMD_DEXT | TXD_CMD_TSE | TXD_CMD_TCP,
+        );
+        desc[13] = 50;
+        LittleEndian::write_u16(&mut desc[14..16], 100);
+        LittleEndian::write_u64(&mut desc[16..24], BUFFERS);
+        LittleEndian::write_u32(
+            &mut desc[24..28],
+            packet.len() as u32
+                | TXD_CMD_DEXT
+                | TXD_DTYP_DATA
+                | TXD_CMD_TSE
+                | TXD_CMD_EOP,
+        );
+        write_guest(&space, TX_RING, &desc).unwrap();
+        write(&mut e1000, &space, reg::TDT, 2);
+
+        assert!(sent.lock().is_empty());
+        assert_eq!(e1000.read_register(reg::TDH), 2);
+    }
+
+    #[test]
+    fn test_receive() {
+        let (mut e1000, _, received) = setup();
+        let space = setup_space();
+        let mut responses = ResponseEventArray::default();
+        write(&mut e1000, &space, reg::IMS, ICR_RXT0);
+        write(&mut e1000, &space, reg::RDBAL, RX_RING as u32);
+        write(&mut e1000, &space, reg::RDLEN, 8 * DESCRIPTOR_SIZE as u32);
+        write(
+            &mut e1000,
+            &space,
+            reg::RCTL,
+            RCTL_EN | RCTL_SECRC | 3 << 16,
+        );
+        for i in 0..8 {
+            let mut desc = [0u8; 16];
+            LittleEndian::write_u64(&mut desc[..8], BUFFERS + i * 0x100);
+            write_guest(&space, RX_RING + i * 16, &desc).unwrap();
+        }
+        write(&mut e1000, &space, reg::RDT, 3);
+
+        // Frames for another address are filtered, and those that do not
+        // fit wait for the guest to add descriptors
+        let mut frame = MAC.to_vec();
+        frame.resize(300, 0x77);
+        let mut other = frame.clone();
+        other[5] = 0;
+        received.lock().push_back(other);
+        received.lock().push_back(frame.clone());
+        received.lock().push_back(frame.clone());
+        e1000.poll(&space, &mut responses).unwrap();
+        assert_eq!(e1000.reg(reg::RDH), 2);
+        assert!(e1000.receive_pending());
+        assert_eq!(responses.len(), 1);
+
+        let mut desc = [0u8; 32];
+        read_guest(&space, RX_RING, &mut desc).unwrap();
+        assert_eq!(LittleEndian::read_u16(&desc[8..10]), 256);
+        assert_eq!(desc[12], RXD_STAT_DD | RXD_STAT_IXSM);
+        assert_eq!(LittleEndian::read_u16(&desc[24..26]), 44);
+        assert_eq!(desc[28], RXD_STAT_DD | RXD_STAT_EOP | RXD_STAT_IXSM);
+        let mut data = [0u8; 6];
+        read_guest(&space, BUFFERS, &mut data).unwrap();
+        assert_eq!(data, MAC);
+
+        write(&mut e1000, &space, reg::RDT, 7);
+        e1000.poll(&space, &mut responses).unwrap();
+        assert_eq!(e1000.reg(reg::RDH), 4);
+        assert!(!e1000.receive_pending());
+    }
+
+    #[test]
+    fn test_switch() {
+        let mac_a = [0x52, 0x54, 0, 0xee, 0, 1];
+        let mac_b = [0x52, 0x54, 0, 0xee, 0, 2];
+        let mac_c = [0x52, 0x54, 0, 0xee, 0, 3];
+        let mut a = SwitchPort::connect(mac_a);
+        let mut b = SwitchPort::connect(mac_b);
+        let c = SwitchPort::connect(mac_c);
+
+        let mut frame = mac_b.to_vec();
+        frame.extend_from_slice(&mac_a);
+        a.transmit(&frame);
+        assert_eq!(b.receive(), Some(frame));
+        assert!(!c.has_pending());
+
+        let broadcast = [0xff; 12];
+        b.transmit(&broadcast);
+        assert_eq!(a.receive().as_deref(), Some(&broadcast[..]));
+        assert!(c.has_pending());
+        assert!(!b.has_pending());
+    }
+}
@@ -0,0 +1,438 @@
+//! A stub of the floppy disk controller (an 82077AA) at ports 0x3F0-0x3F7
+//!
+//! Firmware and older kernels probe the floppy controller during boot, and
+//! some wait forever for a controller that never becomes ready. This stub
+//! behaves like a controller with empty drives: it completes resets (and
+//! the SENSE INTERRUPT STATUS polling that follows them), accepts the
+//! setup commands (SPECIFY, CONFIGURE, SEEK, RECALIBRATE, ...), answers
+//! VERSION as an enhanced controller, and fails every command that needs
+//! media with 'missing address mark'. The disk change line stays active, as
+//! it does while no diskette is inserted.
+//!
+//! Port 0x3F6 belongs to the IDE controller (see `ide`), so it is not
+//! serviced here. Interrupts use IRQ 6 (while the guest has enabled them
+//! in the DOR), and DMA is never performed.
+
+use crate::error::Result;
+use crate::vcpu;
+use crate::virtdev::pic::LEGACY_IRQ_VECTOR_BASE;
+use crate::virtdev::{
+    DeviceEvent, DeviceEventResponse, DeviceRegion, EmulatedDevice, Event,
+    Port, ResponseEventArray,
+};
+use alloc::collections::vec_deque::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use core::convert::TryFrom;
+use spin::RwLock;
+
+/// The legacy IRQ of the floppy controller
+pub const FDC_IRQ: u8 = 6;
+
+const DRIVES: usize = 4;
+
+// The result of VERSION for an enhanced (82077AA) controller
+const VERSION_ENHANCED: u8 = 0x90;
+
+bitflags! {
+    /// The digital output register
+    pub struct DigitalOutput: u8 {
+        const DRIVE_SELECT = 0b11;
+        /// The controller is held in reset while this is clear
+        const NOT_RESET = 1 << 2;
+        /// Enables interrupts (and DMA)
+        const IRQ_DMA = 1 << 3;
+        const MOTORS = 0xf << 4;
+    }
+}
+
+bitflags! {
+    /// The main status register
+    pub struct MainStatus: u8 {
+        const COMMAND_BUSY = 1 << 4;
+        /// The data register has a result byte for the guest
+        const DATA_TO_GUEST = 1 << 6;
+        const REQUEST_FOR_MASTER = 1 << 7;
+    }
+}
+
+// Status register 0 bits
+const ST0_SEEK_END: u8 = 0x20;
+const ST0_ABNORMAL: u8 = 0x40;
+const ST0_INVALID: u8 = 0x80;
+const ST0_READY_CHANGED: u8 = 0xc0;
+
+// Status register 1 (missing address mark) and 3 (track 0) bits
+const ST1_MISSING_ADDRESS_MARK: u8 = 0x01;
+const ST3_TRACK_0: u8 = 0x10;
+
+// The commands (without the MT, MFM and SK bits)
+mod cmd {
+    pub const READ_TRACK: u8 = 0x02;
+    pub const SPECIFY: u8 = 0x03;
+    pub const SENSE_DRIVE_STATUS: u8 = 0x04;
+    pub const WRITE_DATA: u8 = 0x05;
+    pub const READ_DATA: u8 = 0x06;
+    pub const RECALIBRATE: u8 = 0x07;
+    pub const SENSE_INTERRUPT: u8 = 0x08;
+    pub const WRITE_DELETED: u8 = 0x09;
+    pub const READ_ID: u8 = 0x0a;
+    pub const READ_DELETED: u8 = 0x0c;
+    pub const FORMAT_TRACK: u8 = 0x0d;
+    pub const DUMPREG: u8 = 0x0e;
+    pub const SEEK: u8 = 0x0f;
+    pub const VERSION: u8 = 0x10;
+    pub const PERPENDICULAR_MODE: u8 = 0x12;
+    pub const CONFIGURE: u8 = 0x13;
+    pub const LOCK: u8 = 0x14;
+}
+
+// The number of parameter bytes that follow each command byte (or None
+// for invalid commands)
+fn parameter_count(command: u8) -> Option<usize> {
+    match command & 0x1f {
+        cmd::READ_TRACK
+        | cmd::WRITE_DATA
+        | cmd::READ_DATA
+        | cmd::WRITE_DELETED
+        | cmd::READ_DELETED => Some(8),
+        cmd::FORMAT_TRACK => Some(5),
+        cmd::CONFIGURE => Some(3),
+        cmd::SPECIFY | cmd::SEEK => Some(2),
+        cmd::SENSE_DRIVE_STATUS
+        | cmd::RECALIBRATE
+        | cmd::READ_ID
+        | cmd::PERPENDICULAR_MODE => Some(1),
+        cmd::SENSE_INTERRUPT | cmd::DUMPREG | cmd::VERSION | cmd::LOCK => {
+            Some(0)
+        }
+        _ => None,
+    }
+}
+
+#[derive(Debug)]
+pub struct FloppyController {
+    dor: DigitalOutput,
+    tdr: u8,
+
+    // The command being received (command byte first)
+    command: Vec<u8>,
+    result: VecDeque<u8>,
+
+    // The ST0 of each interrupt not yet sensed by the guest
+    interrupts: VecDeque<u8>,
+    cylinders: [u8; DRIVES],
+    specify: [u8; 2],
+    config: [u8; 3],
+    perpendicular: u8,
+    lock: bool,
+}
+
+impl Default for FloppyController {
+    fn default() -> Self {
+        let mut fdc = Self {
+            dor: DigitalOutput::NOT_RESET | DigitalOutput::IRQ_DMA,
+            tdr: 0,
+            command: vec![],
+            result: VecDeque::new(),
+            interrupts: VecDeque::new(),
+            cylinders: [0; DRIVES],
+            specify: [0; 2],
+            config: [0; 3],
+            perpendicular: 0,
+            lock: false,
+        };
+        fdc.reset();
+        fdc
+    }
+}
+
+impl FloppyController {
+    const SRA: Port = 0x3f0;
+    const SRB: Port = 0x3f1;
+    const DOR: Port = 0x3f2;
+    const TDR: Port = 0x3f3;
+    const MSR_DSR: Port = 0x3f4;
+    const FIFO: Port = 0x3f5;
+    const DIR_CCR: Port = 0x3f7;
+
+    pub fn new() -> Arc<RwLock<Self>> {
+        Arc::new(RwLock::new(Self::default()))
+    }
+
+    // Abort any command, and queue the 'ready changed' interrupts that a
+    // controller reports for each drive after a reset
+    fn reset(&mut self) {
+        self.command.clear();
+        self.result.clear();
+        self.interrupts =
+            (0..DRIVES as u8).map(|d| ST0_READY_CHANGED | d).collect();
+        self.cylinders = [0; DRIVES];
+        if !self.lock {
+            self.config = [0; 3];
+            self.perpendicular = 0;
+        }
+    }
+
+    fn in_reset(&self) -> bool {
+        !self.dor.contains(DigitalOutput::NOT_RESET)
+    }
+
+    fn main_status(&self) -> MainStatus {
+        if self.in_reset() {
+            return MainStatus::empty();
+        }
+        let mut status = MainStatus::REQUEST_FOR_MASTER;
+        if !self.result.is_empty() {
+            status |= MainStatus::DATA_TO_GUEST | MainStatus::COMMAND_BUSY;
+        } else if !self.command.is_empty() {
+            status |= MainStatus::COMMAND_BUSY;
+        }
+        status
+    }
+
+    fn raise_interrupt(&self, responses: &mut ResponseEventArray) {
+        if self.dor.contains(DigitalOutput::IRQ_DMA) {
+            responses.push(DeviceEventResponse::Interrupt((
+                LEGACY_IRQ_VECTOR_BASE + FDC_IRQ,
+                vcpu::InjectedInterruptType::ExternalInterrupt,
+            )));
+        }
+    }
+
+    fn write_dor(&mut self, val: u8, responses: &mut ResponseEventArray) {
+        let was_reset = self.in_reset();
+        self.dor = DigitalOutput::from_bits_truncate(val);
+        if self.in_reset() {
+            self.reset();
+        } else if was_reset {
+            // Leaving reset interrupts the guest
+            self.raise_interrupt(responses);
+        }
+    }
+
+    fn read_register(&mut self, port: Port) -> u8 {
+        match port {
+            Self::SRA | Self::SRB => 0,
+            Self::DOR => self.dor.bits(),
+            Self::TDR => self.tdr,
+            Self::MSR_DSR => self.main_status().bits(),
+            Self::FIFO => {
+                let byte = self.result.pop_front();
+                byte.unwrap_or(ST0_INVALID)
+            }
+            // The disk change line is active while the drive is empty
+            Self::DIR_CCR => 0x80,
+            _ => 0xff,
+        }
+    }
+
+    fn write_register(
+        &mut self,
+        port: Port,
+        val: u8,
+        responses: &mut ResponseEventArray,
+    ) {
+        match port {
+            Self::DOR => self.write_dor(val, responses),
+            Self::TDR => self.tdr = val & 0x3,
+            Self::MSR_DSR => {
+                // The software reset bit clears itself
+                if val & 0x80 != 0 && !self.in_reset() {
+                    self.reset();
+                    self.raise_interrupt(responses);
+                }
+            }
+            Self::FIFO => self.write_fifo(val, responses),
+            _ => (),
+        }
+    }
+
+    fn write_fifo(&mut self, val: u8, responses: &mut ResponseEventArray) {
+        if self.in_reset() {
+            return;
+        }
+
+        // A new command discards the unread result of the last one
+        if self.command.is_empty() {
+            self.result.clear();
+        }
+        self.command.push(val);
+        match parameter_count(self.command[0]) {
+            Some(count) if self.command.len() <= count => (),
+            Some(_) => {
+                let command = core::mem::replace(&mut self.command, vec![]);
+                self.execute(&command, responses);
+            }
+            None => {
+                debug!("fdc: Invalid command 0x{:x}", val);
+                self.command.clear();
+                self.result.push_back(ST0_INVALID);
+            }
+        }
+    }
+
+    fn execute(&mut self, command: &[u8], responses: &mut ResponseEventArray) {
+        let params = &command[1..];
+        let drive = params.get(0).map_or(0, |p| p & 0x3);
+        let head = params.get(0).map_or(0, |p| (p >> 2) & 0x1);
+        match command[0] & 0x1f {
+            cmd::SPECIFY => self.specify.copy_from_slice(params),
+            cmd::CONFIGURE => self.config.copy_from_slice(params),
+            cmd::PERPENDICULAR_MODE => self.perpendicular = params[0],
+            cmd::SENSE_DRIVE_STATUS => {
+                let mut st3 = head << 2 | drive;
+                if self.cylinders[drive as usize] == 0 {
+                    st3 |= ST3_TRACK_0;
+                }
+                self.result.push_back(st3);
+            }
+            cmd::RECALIBRATE | cmd::SEEK => {
+                let cylinder = params.get(1).copied().unwrap_or(0);
+                self.cylinders[drive as usize] = cylinder;
+                self.interrupts.push_back(ST0_SEEK_END | drive);
+                self.raise_interrupt(responses);
+            }
+            cmd::SENSE_INTERRUPT => match self.interrupts.pop_front() {
+                Some(st0) => {
+                    self.result.push_back(st0);
+                    self.result.push_back(self.cylinders[(st0 & 0x3) as usize]);
+                }
+                None => self.result.push_back(ST0_INVALID),
+            },
+            cmd::VERSION => self.result.push_back(VERSION_ENHANCED),
+            cmd::LOCK => {
+                self.lock = command[0] & 0x80 != 0;
+                self.result.push_back((self.lock as u8) << 4);
+            }
+            cmd::DUMPREG => {
+                self.result.extend(self.cylinders.iter().copied());
+                self.result.extend(self.specify.iter().copied());
+                // The sector count (EOT), then the lock and perpendicular
+                // settings, followed by the configuration
+                self.result.push_back(0);
+                self.result
+                    .push_back((self.lock as u8) << 7 | self.perpendicular);
+                self.result.extend(self.config[1..].iter().copied());
+            }
+            // Everything else needs media, so it ends abnormally after
+            // failing to find an address mark
+            _ => {
+                let cylinder = params.get(1).copied().unwrap_or(0);
+                let sector = params.get(3).copied().unwrap_or(1);
+                let size = params.get(4).copied().unwrap_or(2);
+                self.result.extend(
+                    [
+                        ST0_ABNORMAL | head << 2 | drive,
+                        ST1_MISSING_ADDRESS_MARK,
+                        0,
+                        cylinder,
+                        head,
+                        sector,
+                        size,
+                    ]
+                    .iter()
+                    .copied(),
+                );
+                self.raise_interrupt(responses);
+            }
+        }
+    }
+}
+
+impl EmulatedDevice for FloppyController {
+    fn services(&self) -> Vec<DeviceRegion> {
+        vec![
+            DeviceRegion::PortIo(Self::SRA..=Self::FIFO),
+            DeviceRegion::PortIo(Self::DIR_CCR..=Self::DIR_CCR),
+        ]
+    }
+
+    fn on_event(&mut self, event: Event) -> Result<()> {
+        match event.kind {
+            DeviceEvent::PortRead(port, mut val) => {
+                val.copy_from_u32(self.read_register(port) as u32);
+            }
+            DeviceEvent::PortWrite(port, val) => {
+                self.write_register(port, u8::try_from(val)?, event.responses);
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn command(
+        fdc: &mut FloppyController,
+        bytes: &[u8],
+        responses: &mut ResponseEventArray,
+    ) -> Vec<u8> {
+        for byte in bytes {
+            assert!(fdc.main_status().contains(MainStatus::REQUEST_FOR_MASTER));
+            fdc.write_register(FloppyController::FIFO, *byte, responses);
+        }
+        let mut result = vec![];
+        while fdc.main_status().contains(MainStatus::DATA_TO_GUEST) {
+            result.push(fdc.read_register(FloppyController::FIFO));
+        }
+        result
+    }
+
+    #[test]
+    fn test_reset_sequence() {
+        let mut fdc = FloppyController::default();
+        let mut responses = ResponseEventArray::default();
+
+        // Held in reset, the controller is not ready
+        fdc.write_register(FloppyController::DOR, 0x00, &mut responses);
+        assert!(fdc.main_status().is_empty());
+        fdc.write_register(FloppyController::DOR, 0x0c, &mut responses);
+        assert_eq!(responses.len(), 1);
+
+        // Each drive reports a ready change
+        for drive in 0..4 {
+            let result =
+                command(&mut fdc, &[cmd::SENSE_INTERRUPT], &mut responses);
+            assert_eq!(result, [ST0_READY_CHANGED | drive, 0]);
+        }
+        let result = command(&mut fdc, &[cmd::SENSE_INTERRUPT], &mut responses);
+        assert_eq!(result, [ST0_INVALID]);
+
+        assert_eq!(
+            command(&mut fdc, &[cmd::VERSION], &mut responses),
+            [VERSION_ENHANCED]
+        );
+        assert!(
+            command(&mut fdc, &[cmd::SPECIFY, 0xaf, 0x02], &mut responses)
+                .is_empty()
+        );
+        assert_eq!(command(&mut fdc, &[0x1f], &mut responses), [ST0_INVALID]);
+    }
+
+    #[test]
+    fn test_no_media() {
+        let mut fdc = FloppyController::default();
+        let mut responses = ResponseEventArray::default();
+
+        // Seeking works without a diskette
+        assert!(
+            command(&mut fdc, &[cmd::SEEK, 0x01, 5], &mut responses).is_empty()
+        );
+        fdc.interrupts.retain(|st0| st0 & ST0_SEEK_END != 0);
+        let result = command(&mut fdc, &[cmd::SENSE_INTERRUPT], &mut responses);
+        assert_eq!(result, [ST0_SEEK_END | 1, 5]);
+
+        // Reads fail, and the disk change line is active
+        let read = [0xe6, 0x00, 0, 0, 1, 2, 18, 0x1b, 0xff];
+        let result = command(&mut fdc, &read, &mut responses);
+        assert_eq!(result.len(), 7);
+        assert_eq!(result[0], ST0_ABNORMAL);
+        assert_eq!(result[1], ST1_MISSING_ADDRESS_MARK);
+        assert_eq!(fdc.read_register(FloppyController::DIR_CCR) & 0x80, 0x80);
+    }
+}
@@ -0,0 +1,515 @@
+//! Emulation of the high precision event timer
+//!
+//! Like the PIT, the main counter does not tick. Its value is derived from
+//! the guest uptime (which is based on the host TSC) whenever it is read,
+//! so it runs at `HPET_FREQUENCY` and stays continuous across pauses.
+//!
+//! Each of the `HPET_TIMERS` comparators raises a legacy IRQ with a timer
+//! on the per-core timer wheel. In legacy replacement mode, timers 0 and 1
+//! raise IRQ0 and IRQ8 (replacing the PIT and RTC). Otherwise, each timer
+//! raises the IRQ selected by its route (from those in
+//! `HPET_TIMER_ROUTES`). Interrupts are delivered as edge triggered, so the
+//! interrupt status register always reads as zero.
+//!
+//! Only timer 0 is capable of periodic mode. A periodic timer interrupts
+//! every period from the time its comparator is written, which matches the
+//! usual programming of the comparator to the current count plus the
+//! period.
+
+use crate::error::Result;
+use crate::memory::GuestPhysAddr;
+use crate::time;
+use crate::timekeeping;
+use crate::virtdev::pic::{LEGACY_IRQS, LEGACY_IRQ_VECTOR_BASE};
+use crate::virtdev::{DeviceEvent, DeviceRegion, EmulatedDevice, Event};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::time::Duration;
+use spin::RwLock;
+
+/// The guest physical address of the HPET registers
+pub const HPET_BASE: u64 = 0xfed00000;
+
+/// The frequency of the main counter (in Hz)
+pub const HPET_FREQUENCY: u64 = 10_000_000;
+
+/// The number of timers (comparators)
+pub const HPET_TIMERS: usize = 3;
+
+/// The IRQs that timers can be routed to outside of legacy replacement
+/// mode (as a bitmap)
+pub const HPET_TIMER_ROUTES: u32 = (1 << 9) | (1 << 10) | (1 << 11);
+
+const HPET_REGION_SIZE: u64 = 0x400;
+
+// The period of the main counter, in femtoseconds
+const HPET_PERIOD_FS: u64 = 1_000_000_000_000_000 / HPET_FREQUENCY;
+const NS_PER_TICK: u64 = 1_000_000_000 / HPET_FREQUENCY;
+
+// The registers
+const HPET_CAPABILITIES: u64 = 0x000;
+const HPET_CONFIG: u64 = 0x010;
+const HPET_INTERRUPT_STATUS: u64 = 0x020;
+const HPET_MAIN_COUNTER: u64 = 0x0f0;
+const HPET_TIMER_BASE: u64 = 0x100;
+const HPET_TIMER_STRIDE: u64 = 0x20;
+const HPET_TIMER_CONFIG: u64 = 0x00;
+const HPET_TIMER_COMPARATOR: u64 = 0x08;
+
+// The general capabilities: revision 1, a 64-bit main counter and legacy
+// replacement routing, with the vendor ID of Intel
+const HPET_CAPABILITIES_VALUE: u64 = 0x01
+    | ((HPET_TIMERS as u64 - 1) << 8)
+    | (1 << 13)
+    | (1 << 15)
+    | (0x8086 << 16)
+    | (HPET_PERIOD_FS << 32);
+
+const HPET_CONFIG_ENABLE: u64 = 1 << 0;
+const HPET_CONFIG_LEGACY: u64 = 1 << 1;
+
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+const TIMER_64BIT_CAPABLE: u64 = 1 << 5;
+const TIMER_VALUE_SET: u64 = 1 << 6;
+const TIMER_32BIT_MODE: u64 = 1 << 8;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE_MASK: u64 = 0x1f << TIMER_ROUTE_SHIFT;
+
+// The bits of a timer configuration writable by the guest (FSB delivery is
+// not supported, and the interrupt type is ignored)
+const TIMER_CONFIG_WRITABLE: u64 = (1 << 1)
+    | TIMER_INTERRUPT_ENABLE
+    | TIMER_PERIODIC
+    | TIMER_32BIT_MODE
+    | TIMER_ROUTE_MASK;
+
+fn ticks(duration: Duration) -> u64 {
+    (duration.as_nanos() / NS_PER_TICK as u128) as u64
+}
+
+fn ticks_duration(ticks: u64) -> Duration {
+    Duration::from_nanos(ticks.saturating_mul(NS_PER_TICK))
+}
+
+#[derive(Debug, Default)]
+struct HpetTimer {
+    config: u64,
+    comparator: u64,
+    // The period of a periodic timer (in ticks)
+    period: u64,
+    // The main counter when a periodic timer was started
+    period_start: u64,
+    timer: Option<time::TimerId>,
+}
+
+impl HpetTimer {
+    fn new(index: usize) -> Self {
+        let mut config = TIMER_64BIT_CAPABLE | (HPET_TIMER_ROUTES as u64) << 32;
+        if index == 0 {
+            config |= TIMER_PERIODIC_CAPABLE;
+        }
+        Self {
+            config,
+            comparator: u64::MAX,
+            ..Self::default()
+        }
+    }
+
+    fn is_periodic(&self) -> bool {
+        self.config & TIMER_PERIODIC != 0 && self.period != 0
+    }
+
+    fn mask(&self) -> u64 {
+        if self.config & TIMER_32BIT_MODE != 0 {
+            u32::MAX as u64
+        } else {
+            u64::MAX
+        }
+    }
+
+    // The comparator at `counter` (which advances by the period in periodic
+    // mode)
+    fn comparator(&self, counter: u64) -> u64 {
+        if !self.is_periodic() {
+            return self.comparator;
+        }
+        let elapsed = counter.wrapping_sub(self.period_start) & self.mask();
+        let periods = elapsed / self.period + 1;
+        self.period_start
+            .wrapping_add(periods.wrapping_mul(self.period))
+            & self.mask()
+    }
+
+    fn write_config(&mut self, val: u64) {
+        self.config = (self.config & !TIMER_CONFIG_WRITABLE)
+            | (val & TIMER_CONFIG_WRITABLE);
+        if self.config & TIMER_PERIODIC_CAPABLE == 0 {
+            self.config &= !TIMER_PERIODIC;
+        }
+        let route = (self.config & TIMER_ROUTE_MASK) >> TIMER_ROUTE_SHIFT;
+        if HPET_TIMER_ROUTES & (1 << route) == 0 {
+            self.config &= !TIMER_ROUTE_MASK;
+        }
+        if val & TIMER_VALUE_SET != 0 && self.config & TIMER_PERIODIC != 0 {
+            self.config |= TIMER_VALUE_SET;
+        }
+    }
+
+    fn write_comparator(&mut self, val: u64, counter: u64) {
+        let val = val & self.mask();
+        if self.config & TIMER_PERIODIC == 0 {
+            self.comparator = val;
+            return;
+        }
+
+        // The first write after setting the value-set bit sets the
+        // comparator, and any other sets the period
+        if self.config & TIMER_VALUE_SET != 0 {
+            self.config &= !TIMER_VALUE_SET;
+            self.comparator = val;
+            self.period = val.wrapping_sub(counter) & self.mask();
+        } else {
+            self.period = val;
+        }
+        self.period_start = counter & self.mask();
+    }
+}
+
+pub struct Hpet {
+    config: u64,
+    // The main counter while it is halted, or otherwise the offset of the
+    // counter from the guest uptime (in ticks)
+    counter: u64,
+    timers: [HpetTimer; HPET_TIMERS],
+}
+
+impl Default for Hpet {
+    fn default() -> Self {
+        Self {
+            config: 0,
+            counter: 0,
+            timers: [HpetTimer::new(0), HpetTimer::new(1), HpetTimer::new(2)],
+        }
+    }
+}
+
+impl Hpet {
+    pub fn new() -> Arc<RwLock<Self>> {
+        Arc::new(RwLock::new(Hpet::default()))
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.config & HPET_CONFIG_ENABLE != 0
+    }
+
+    /// The value of the main counter at `now`
+    fn main_counter(&self, now: Duration) -> u64 {
+        if self.is_enabled() {
+            ticks(now).wrapping_add(self.counter)
+        } else {
+            self.counter
+        }
+    }
+
+    /// The IRQ raised by timer `index`
+    fn irq(&self, index: usize) -> u8 {
+        match index {
+            0 if self.config & HPET_CONFIG_LEGACY != 0 => 0,
+            1 if self.config & HPET_CONFIG_LEGACY != 0 => 8,
+            _ => {
+                let config = self.timers[index].config;
+                ((config & TIMER_ROUTE_MASK) >> TIMER_ROUTE_SHIFT) as u8
+            }
+        }
+    }
+
+    /// The delay until timer `index` interrupts, its period (if it is
+    /// periodic) and the IRQ it raises, or None if it is disabled
+    fn timer_deadline(
+        &self,
+        index: usize,
+        now: Duration,
+    ) -> Option<(Duration, Option<Duration>, u8)> {
+        let timer = &self.timers[index];
+        let irq = self.irq(index);
+        if !self.is_enabled()
+            || timer.config & TIMER_INTERRUPT_ENABLE == 0
+            || irq >= LEGACY_IRQS
+        {
+            return None;
+        }
+        let counter = self.main_counter(now) & timer.mask();
+        let delay =
+            timer.comparator(counter).wrapping_sub(counter) & timer.mask();
+        let period = if timer.is_periodic() {
+            Some(ticks_duration(timer.period))
+        } else {
+            None
+        };
+        Some((ticks_duration(delay), period, irq))
+    }
+
+    fn read_register(&self, offset: u64, now: Duration) -> u64 {
+        match offset {
+            HPET_CAPABILITIES => HPET_CAPABILITIES_VALUE,
+            HPET_CONFIG => self.config,
+            HPET_INTERRUPT_STATUS => 0,
+            HPET_MAIN_COUNTER => self.main_counter(now),
+            _ => match self.timer_register(offset) {
+                Some((index, HPET_TIMER_CONFIG)) => self.timers[index].config,
+                Some((index, HPET_TIMER_COMPARATOR)) => {
+                    let timer = &self.timers[index];
+                    timer.comparator(self.main_counter(now) & timer.mask())
+                }
+                _ => 0,
+            },
+        }
+    }
+
+    /// Handle a write to the register at `offset`, returning true if the
+    /// timers must be updated
+    fn write_register(&mut self, offset: u64, val: u64, now: Duration) -> bool {
+        match offset {
+            HPET_CONFIG => {
+                let counter = self.main_counter(now);
+                self.config = val & (HPET_CONFIG_ENABLE | HPET_CONFIG_LEGACY);
+                self.counter = if self.is_enabled() {
+                    counter.wrapping_sub(ticks(now))
+                } else {
+                    counter
+                };
+                true
+            }
+            // The main counter is only writable while it is halted
+            HPET_MAIN_COUNTER if !self.is_enabled() => {
+                self.counter = val;
+                false
+            }
+            _ => match self.timer_register(offset) {
+                Some((index, HPET_TIMER_CONFIG)) => {
+                    self.timers[index].write_config(val);
+                    true
+                }
+                Some((index, HPET_TIMER_COMPARATOR)) => {
+                    let counter = self.main_counter(now);
+                    self.timers[index].write_comparator(val, counter);
+                    true
+                }
+                _ => false,
+            },
+        }
+    }
+
+    // The timer and register (relative to the timer) at `offset`
+    fn timer_register(&self, offset: u64) -> Option<(usize, u64)> {
+        let relative = offset.checked_sub(HPET_TIMER_BASE)?;
+        let index = (relative / HPET_TIMER_STRIDE) as usize;
+        if index >= HPET_TIMERS {
+            return None;
+        }
+        Some((index, relative % HPET_TIMER_STRIDE))
+    }
+
+    fn update_timers(&mut self, now: Duration) -> Result<()> {
+        for index in 0..HPET_TIMERS {
+            if let Some(timer) = self.timers[index].timer.take() {
+                time::cancel_timer(&timer)?;
+            }
+            let timer = match self.timer_deadline(index, now) {
+                Some((_, Some(period), irq)) => Some(time::set_periodic_timer(
+                    period,
+                    LEGACY_IRQ_VECTOR_BASE + irq,
+                )),
+                Some((delay, None, irq)) => Some(time::set_oneshot_timer(
+                    delay,
+                    LEGACY_IRQ_VECTOR_BASE + irq,
+                )),
+                None => None,
+            };
+            self.timers[index].timer = timer;
+        }
+        Ok(())
+    }
+
+    fn read(&self, offset: u64, data: &mut [u8], now: Duration) {
+        let register = self.read_register(offset & !0b111, now).to_le_bytes();
+        let start = (offset & 0b111) as usize;
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = register.get(start + i).copied().unwrap_or(0);
+        }
+    }
+
+    // Handle a (32 or 64-bit) write, returning true if the timers must be
+    // updated
+    fn write(&mut self, offset: u64, data: &[u8], now: Duration) -> bool {
+        let aligned = offset & !0b111;
+        let mut register = self.read_register(aligned, now).to_le_bytes();
+        let start = (offset & 0b111) as usize;
+        for (i, byte) in data.iter().enumerate() {
+            if let Some(dest) = register.get_mut(start + i) {
+                *dest = *byte;
+            }
+        }
+        let val = u64::from_le_bytes(register);
+
+        // The halves of a comparator written with 32-bit accesses are set
+        // separately, so the period is not taken from a partial value
+        if let Some((index, HPET_TIMER_COMPARATOR)) =
+            self.timer_register(aligned)
+        {
+            if data.len() < 8 && start == 0 && !self.timers[index].is_periodic()
+            {
+                let mut comparator =
+                    self.timers[index].comparator.to_le_bytes();
+                comparator[..data.len()].copy_from_slice(data);
+                let val = u64::from_le_bytes(comparator);
+                return self.write_register(aligned, val, now);
+            }
+        }
+        self.write_register(aligned, val, now)
+    }
+}
+
+impl EmulatedDevice for Hpet {
+    fn services(&self) -> Vec<DeviceRegion> {
+        vec![DeviceRegion::MemIo(
+            GuestPhysAddr::new(HPET_BASE)
+                ..=GuestPhysAddr::new(HPET_BASE + HPET_REGION_SIZE - 1),
+        )]
+    }
+
+    fn on_event(&mut self, event: Event) -> Result<()> {
+        let now = timekeeping::guest_uptime();
+        match event.kind {
+            DeviceEvent::MemRead(addr, mut req) => {
+                self.read(addr.as_u64() - HPET_BASE, req.as_mut_slice(), now);
+            }
+            DeviceEvent::MemWrite(addr, req) => {
+                if self.write(addr.as_u64() - HPET_BASE, req.as_slice(), now) {
+                    self.update_timers(now)?;
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn at(ticks: u64) -> Duration {
+        ticks_duration(ticks)
+    }
+
+    fn read(hpet: &Hpet, offset: u64, now: u64) -> u64 {
+        let mut data = [0u8; 8];
+        hpet.read(offset, &mut data, at(now));
+        u64::from_le_bytes(data)
+    }
+
+    fn write(hpet: &mut Hpet, offset: u64, val: u64, now: u64) -> bool {
+        hpet.write(offset, &val.to_le_bytes(), at(now))
+    }
+
+    #[test]
+    fn test_hpet_main_counter() {
+        let mut hpet = Hpet::default();
+        let caps = read(&hpet, HPET_CAPABILITIES, 0);
+        assert_eq!(caps >> 32, 100_000_000);
+        assert_eq!((caps >> 8) & 0x1f, HPET_TIMERS as u64 - 1);
+
+        // The counter only runs while enabled
+        write(&mut hpet, HPET_MAIN_COUNTER, 1000, 50);
+        assert_eq!(read(&hpet, HPET_MAIN_COUNTER, 100), 1000);
+        write(&mut hpet, HPET_CONFIG, HPET_CONFIG_ENABLE, 100);
+        assert_eq!(read(&hpet, HPET_MAIN_COUNTER, 350), 1250);
+        write(&mut hpet, HPET_MAIN_COUNTER, 0, 400);
+        assert_eq!(read(&hpet, HPET_MAIN_COUNTER, 400), 1300);
+        write(&mut hpet, HPET_CONFIG, 0, 500);
+        assert_eq!(read(&hpet, HPET_MAIN_COUNTER, 900), 1400);
+
+        // 32-bit reads of the halves of the counter
+        let mut data = [0u8; 4];
+        hpet.read(HPET_MAIN_COUNTER + 4, &mut data, at(900));
+        assert_eq!(data, [0; 4]);
+        hpet.read(HPET_MAIN_COUNTER, &mut data, at(900));
+        assert_eq!(u32::from_le_bytes(data), 1400);
+    }
+
+    #[test]
+    fn test_hpet_oneshot_timer() {
+        let mut hpet = Hpet::default();
+        let timer1 = HPET_TIMER_BASE + HPET_TIMER_STRIDE;
+        let config = read(&hpet, timer1 + HPET_TIMER_CONFIG, 0);
+        assert_eq!(config & TIMER_PERIODIC_CAPABLE, 0);
+        assert_eq!((config >> 32) as u32, HPET_TIMER_ROUTES);
+
+        // Route timer 1 to IRQ 10, firing at a count of 5000
+        let route = 10 << TIMER_ROUTE_SHIFT;
+        assert!(write(&mut hpet, timer1, TIMER_INTERRUPT_ENABLE | route, 0));
+        assert!(write(&mut hpet, timer1 + HPET_TIMER_COMPARATOR, 5000, 0));
+        assert_eq!(hpet.timer_deadline(1, at(0)), None);
+        write(&mut hpet, HPET_CONFIG, HPET_CONFIG_ENABLE, 1000);
+        assert_eq!(
+            hpet.timer_deadline(1, at(3000)),
+            Some((at(3000), None, 10))
+        );
+
+        // Routes that are not supported are ignored
+        write(&mut hpet, timer1, TIMER_INTERRUPT_ENABLE | 4 << 9, 0);
+        assert_eq!(hpet.timer_deadline(1, at(3000)).map(|t| t.2), Some(0));
+
+        // In legacy replacement mode, timer 1 raises IRQ8
+        write(
+            &mut hpet,
+            HPET_CONFIG,
+            HPET_CONFIG_ENABLE | HPET_CONFIG_LEGACY,
+            0,
+        );
+        assert_eq!(hpet.timer_deadline(1, at(0)).map(|t| t.2), Some(8));
+    }
+
+    #[test]
+    fn test_hpet_periodic_timer() {
+        let mut hpet = Hpet::default();
+        write(
+            &mut hpet,
+            HPET_CONFIG,
+            HPET_CONFIG_ENABLE | HPET_CONFIG_LEGACY,
+            0,
+        );
+        let config = TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC | TIMER_VALUE_SET;
+        write(&mut hpet, HPET_TIMER_BASE, config, 0);
+        write(
+            &mut hpet,
+            HPET_TIMER_BASE + HPET_TIMER_COMPARATOR,
+            1100,
+            100,
+        );
+        assert_eq!(
+            hpet.timer_deadline(0, at(100)),
+            Some((at(1000), Some(at(1000)), 0))
+        );
+        assert_eq!(
+            read(&hpet, HPET_TIMER_BASE + HPET_TIMER_COMPARATOR, 2500),
+            3100
+        );
+
+        // Without the value-set bit, a write sets the period
+        write(&mut hpet, HPET_TIMER_BASE, config & !TIMER_VALUE_SET, 3000);
+        write(
+            &mut hpet,
+            HPET_TIMER_BASE + HPET_TIMER_COMPARATOR,
+            500,
+            3000,
+        );
+        assert_eq!(
+            read(&hpet, HPET_TIMER_BASE + HPET_TIMER_COMPARATOR, 3200),
+            3500
+        );
+    }
+}
@@ -22,14 +22,10 @@ impl EmulatedDevice for IgnoredDevice {
             // Ignore #IGNNE stuff
             DeviceRegion::PortIo(241..=241),
             DeviceRegion::PortIo(240..=240),
-            // IO delay port
-            DeviceRegion::PortIo(128..=128),
-            //TODO: don't know what this is yet
-            DeviceRegion::PortIo(135..=135),
-            // Unused UART interfaces
-            DeviceRegion::PortIo(0x2F8..=0x2F8 + 7),
-            DeviceRegion::PortIo(0x3E8..=0x3E8 + 7),
-            DeviceRegion::PortIo(0x2E8..=0x2E8 + 7),
+            // Parallel ports (probed by the firmware during POST)
+            DeviceRegion::PortIo(0x378..=0x378 + 2),
+            DeviceRegion::PortIo(0x278..=0x278 + 2),
+            DeviceRegion::PortIo(0x3BC..=0x3BC + 2),
         ]
     }
 
@@ -0,0 +1,319 @@
+//! Emulation of the guest I/O APIC
+//!
+//! The guest programs the 24 redirection entries through the usual
+//! IOREGSEL/IOWIN register pair. Legacy interrupts raised by devices (at
+//! `pic::LEGACY_IRQ_VECTOR_BASE + irq`) are then routed by the vCPU through
+//! the entry of the matching GSI (ISA IRQs are identity mapped), in
+//! preference to the PIC, once the guest has unmasked it.
+//!
+//! Interrupts are edge triggered from the point of view of the guest: the
+//! remote IRR of level triggered entries is never set, so no EOI is needed
+//! to deliver the next one.
+
+use crate::error::Result;
+use crate::ioapic::{DeliveryMode, DestinationMode};
+use crate::memory::GuestPhysAddr;
+use crate::vcpu::InjectedInterruptType;
+use crate::virtdev::{DeviceEvent, DeviceRegion, EmulatedDevice, Event};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use spin::RwLock;
+
+/// The guest physical address of the I/O APIC registers
+pub const IOAPIC_BASE: u64 = 0xfec00000;
+
+/// The number of redirection entries (and so GSIs) of the I/O APIC
+pub const IOAPIC_PINS: usize = 24;
+
+// The offsets of the register select and window registers
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+const IOAPIC_REGION_SIZE: u64 = 0x1000;
+
+// The indirect registers
+const IOAPICID: u32 = 0x00;
+const IOAPICVER: u32 = 0x01;
+const IOAPICARB: u32 = 0x02;
+const IOREDTBL: u32 = 0x10;
+
+const IOAPIC_VERSION: u32 = 0x11;
+const IOAPICID_MASK: u32 = 0x0f << 24;
+
+// The bits of a redirection entry that are writable by the guest (all but
+// the delivery status and remote IRR)
+const IOREDTBL_WRITABLE: u64 = 0xff00_0000_0001_afff;
+const IOREDTBL_MASKED: u64 = 1 << 16;
+
+/// The destination of an interrupt routed by the I/O APIC
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IoApicDestination {
+    /// The vCPU with the given APIC ID
+    Physical(u8),
+
+    /// The vCPU that raised the interrupt (for logical, broadcast and
+    /// lowest priority destinations)
+    Any,
+}
+
+/// An interrupt routed by the I/O APIC
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IoApicRoute {
+    pub vector: u8,
+    pub kind: InjectedInterruptType,
+    pub destination: IoApicDestination,
+}
+
+pub struct IoApic {
+    id: u32,
+    select: u32,
+    entries: [u64; IOAPIC_PINS],
+}
+
+impl Default for IoApic {
+    fn default() -> Self {
+        Self {
+            id: 0,
+            select: 0,
+            entries: [IOREDTBL_MASKED; IOAPIC_PINS],
+        }
+    }
+}
+
+impl IoApic {
+    pub fn new() -> Arc<RwLock<Self>> {
+        Arc::new(RwLock::new(IoApic::default()))
+    }
+
+    /// The redirection entry of `gsi`
+    pub fn entry(&self, gsi: u32) -> Option<u64> {
+        self.entries.get(gsi as usize).copied()
+    }
+
+    /// Route an interrupt on `gsi`, returning None if the entry is masked
+    /// (or the interrupt cannot be delivered)
+    pub fn route(&self, gsi: u32) -> Option<IoApicRoute> {
+        let entry = self.entry(gsi)?;
+        if entry & IOREDTBL_MASKED != 0 {
+            return None;
+        }
+
+        let mode = DeliveryMode::try_from(((entry >> 8) & 0b111) as u8);
+        let (vector, kind) = match mode {
+            Ok(DeliveryMode::Fixed) | Ok(DeliveryMode::LowestPriority) => (
+                (entry & 0xff) as u8,
+                InjectedInterruptType::ExternalInterrupt,
+            ),
+            Ok(DeliveryMode::NMI) => {
+                (2, InjectedInterruptType::NonMaskableInterrupt)
+            }
+            mode => {
+                warn!("Unsupported delivery mode for GSI {}: {:?}", gsi, mode);
+                return None;
+            }
+        };
+
+        // Lowest priority, logical and broadcast interrupts are delivered to
+        // whichever vCPU raised them
+        let physical = (entry >> 11) & 1 == DestinationMode::Physical as u64;
+        let destination = (entry >> 56) as u8;
+        let destination = match mode {
+            Ok(DeliveryMode::Fixed) | Ok(DeliveryMode::NMI)
+                if physical && destination != 0xff =>
+            {
+                IoApicDestination::Physical(destination)
+            }
+            _ => IoApicDestination::Any,
+        };
+        Some(IoApicRoute {
+            vector,
+            kind,
+            destination,
+        })
+    }
+
+    fn read_register(&self, register: u32) -> u32 {
+        match register {
+            IOAPICID | IOAPICARB => self.id,
+            IOAPICVER => ((IOAPIC_PINS as u32 - 1) << 16) | IOAPIC_VERSION,
+            IOREDTBL..=0xff => {
+                let index = ((register - IOREDTBL) / 2) as usize;
+                match self.entries.get(index) {
+                    Some(entry) if register & 1 == 0 => *entry as u32,
+                    Some(entry) => (*entry >> 32) as u32,
+                    None => 0,
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, register: u32, val: u32) {
+        match register {
+            IOAPICID => self.id = val & IOAPICID_MASK,
+            IOREDTBL..=0xff => {
+                let index = ((register - IOREDTBL) / 2) as usize;
+                let entry = match self.entries.get_mut(index) {
+                    Some(entry) => entry,
+                    None => return,
+                };
+                let (val, mask) = if register & 1 == 0 {
+                    (val as u64, 0xffff_ffff & IOREDTBL_WRITABLE)
+                } else {
+                    ((val as u64) << 32, !0xffff_ffff & IOREDTBL_WRITABLE)
+                };
+                *entry = (*entry & !mask) | (val & mask);
+            }
+            _ => (),
+        }
+    }
+}
+
+impl EmulatedDevice for IoApic {
+    fn services(&self) -> Vec<DeviceRegion> {
+        vec![DeviceRegion::MemIo(
+            GuestPhysAddr::new(IOAPIC_BASE)
+                ..=GuestPhysAddr::new(IOAPIC_BASE + IOAPIC_REGION_SIZE - 1),
+        )]
+    }
+
+    fn on_event(&mut self, event: Event) -> Result<()> {
+        match event.kind {
+            DeviceEvent::MemRead(addr, mut req) => {
+                let val = match addr.as_u64() - IOAPIC_BASE {
+                    IOREGSEL => self.select,
+                    IOWIN => self.read_register(self.select),
+                    _ => 0,
+                };
+                for (i, byte) in req.as_mut_slice().iter_mut().enumerate() {
+                    *byte = val.to_le_bytes().get(i).copied().unwrap_or(0);
+                }
+            }
+            DeviceEvent::MemWrite(addr, req) => {
+                let mut bytes = [0u8; 4];
+                let data = req.as_slice();
+                let len = core::cmp::min(data.len(), bytes.len());
+                bytes[..len].copy_from_slice(&data[..len]);
+                let val = u32::from_le_bytes(bytes);
+                match addr.as_u64() - IOAPIC_BASE {
+                    IOREGSEL => self.select = val & 0xff,
+                    IOWIN => self.write_register(self.select, val),
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::{GuestAddressSpace, GuestAddressSpaceViewMut};
+    use crate::virtdev::{MemReadRequest, MemWriteRequest, ResponseEventArray};
+    use alloc::boxed::Box;
+
+    fn define_test_view() -> GuestAddressSpaceViewMut<'static> {
+        let space: &'static mut GuestAddressSpace =
+            Box::leak(Box::new(GuestAddressSpace::new().unwrap()));
+        GuestAddressSpaceViewMut::new(GuestPhysAddr::new(0), space)
+    }
+
+    fn write(ioapic: &mut IoApic, offset: u64, val: u32) {
+        let mut responses = ResponseEventArray::default();
+        let data = val.to_le_bytes();
+        let event = Event::new(
+            DeviceEvent::MemWrite(
+                GuestPhysAddr::new(IOAPIC_BASE + offset),
+                MemWriteRequest::new(&data),
+            ),
+            define_test_view(),
+            &mut responses,
+        )
+        .unwrap();
+        ioapic.on_event(event).unwrap();
+    }
+
+    fn read(ioapic: &mut IoApic, offset: u64) -> u32 {
+        let mut responses = ResponseEventArray::default();
+        let mut data = [0u8; 4];
+        let event = Event::new(
+            DeviceEvent::MemRead(
+                GuestPhysAddr::new(IOAPIC_BASE + offset),
+                MemReadRequest::new(&mut data),
+            ),
+            define_test_view(),
+            &mut responses,
+        )
+        .unwrap();
+        ioapic.on_event(event).unwrap();
+        u32::from_le_bytes(data)
+    }
+
+    fn write_register(ioapic: &mut IoApic, register: u32, val: u32) {
+        write(ioapic, IOREGSEL, register);
+        write(ioapic, IOWIN, val);
+    }
+
+    fn read_register(ioapic: &mut IoApic, register: u32) -> u32 {
+        write(ioapic, IOREGSEL, register);
+        read(ioapic, IOWIN)
+    }
+
+    #[test]
+    fn test_ioapic_registers() {
+        let mut ioapic = IoApic::default();
+        assert_eq!(read_register(&mut ioapic, IOAPICVER), 0x0017_0011);
+        write_register(&mut ioapic, IOAPICID, 0xffff_ffff);
+        assert_eq!(read_register(&mut ioapic, IOAPICID), 0x0f00_0000);
+        assert_eq!(read(&mut ioapic, IOREGSEL), IOAPICID);
+
+        // Entries are masked on reset
+        assert_eq!(
+            read_register(&mut ioapic, IOREDTBL),
+            IOREDTBL_MASKED as u32
+        );
+        assert_eq!(ioapic.route(0), None);
+
+        // The delivery status and remote IRR are read-only
+        write_register(&mut ioapic, IOREDTBL + 4, 0x0000_f031);
+        write_register(&mut ioapic, IOREDTBL + 5, 0x0300_0000);
+        assert_eq!(read_register(&mut ioapic, IOREDTBL + 4), 0x0000_a031);
+        assert_eq!(ioapic.entry(2), Some(0x0300_0000_0000_a031));
+        assert_eq!(read_register(&mut ioapic, IOREDTBL + 48), 0);
+    }
+
+    #[test]
+    fn test_ioapic_route() {
+        let mut ioapic = IoApic::default();
+        write_register(&mut ioapic, IOREDTBL + 8, 0x0000_0041);
+        write_register(&mut ioapic, IOREDTBL + 9, 0x0200_0000);
+        assert_eq!(
+            ioapic.route(4),
+            Some(IoApicRoute {
+                vector: 0x41,
+                kind: InjectedInterruptType::ExternalInterrupt,
+                destination: IoApicDestination::Physical(2),
+            })
+        );
+
+        // Logical destinations may be any vCPU
+        write_register(&mut ioapic, IOREDTBL + 8, 0x0000_0841);
+        assert_eq!(
+            ioapic.route(4).map(|route| route.destination),
+            Some(IoApicDestination::Any)
+        );
+
+        write_register(&mut ioapic, IOREDTBL + 2, 0x0000_0400);
+        assert_eq!(
+            ioapic.route(1).map(|route| (route.vector, route.kind)),
+            Some((2, InjectedInterruptType::NonMaskableInterrupt))
+        );
+
+        // INIT is not supported
+        write_register(&mut ioapic, IOREDTBL + 2, 0x0000_0500);
+        assert_eq!(ioapic.route(1), None);
+        assert_eq!(ioapic.route(24), None);
+    }
+}
@@ -0,0 +1,819 @@
+//! An emulated Intel DMA remapping unit (a 'vIOMMU')
+//!
+//! This exposes a minimal VT-d register block to the guest, so the guest
+//! can use its own DMA remapping for device isolation. The guest programs
+//! root, context and second-level tables in its own memory, which are
+//! walked by `IntelIommu::translate` whenever an emulated device performs
+//! DMA. Invalidations are accepted both through the register interface and
+//! through the invalidation queue, though no translations are cached so
+//! they only need to be acknowledged.
+//!
+//! The guest discovers the unit through the DMAR table returned by
+//! `IntelIommu::dmar_table`.
+
+use crate::error::{Error, Result};
+use crate::memory::{GuestAddressSpace, GuestPhysAddr, HostPhysFrame};
+use crate::vcpu;
+use crate::virtdev::{
+    DeviceEvent, DeviceEventResponse, DeviceRegion, EmulatedDevice, Event,
+    ResponseEventArray,
+};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, LittleEndian};
+use spin::RwLock;
+
+/// The default base address of the register block (as used by QEMU)
+pub const DEFAULT_REGISTER_BASE: u64 = 0xfed90000;
+
+const REGISTER_BLOCK_SIZE: u64 = 0x1000;
+
+/// Register offsets (see `VT-d § 10.4`)
+mod reg {
+    pub const VER: u64 = 0x00;
+    pub const CAP: u64 = 0x08;
+    pub const ECAP: u64 = 0x10;
+    pub const GCMD: u64 = 0x18;
+    pub const GSTS: u64 = 0x1c;
+    pub const RTADDR: u64 = 0x20;
+    pub const CCMD: u64 = 0x28;
+    pub const FSTS: u64 = 0x34;
+    pub const FECTL: u64 = 0x38;
+    pub const FEDATA: u64 = 0x3c;
+    pub const FEADDR: u64 = 0x40;
+    pub const FEUADDR: u64 = 0x44;
+    pub const IQH: u64 = 0x80;
+    pub const IQT: u64 = 0x88;
+    pub const IQA: u64 = 0x90;
+    pub const ICS: u64 = 0x9c;
+    pub const IECTL: u64 = 0xa0;
+    pub const IEDATA: u64 = 0xa4;
+    pub const IEADDR: u64 = 0xa8;
+    pub const IEUADDR: u64 = 0xac;
+
+    // These are located by the IRO and FRO capability fields
+    pub const IVA: u64 = 0x200;
+    pub const IOTLB: u64 = 0x208;
+    pub const FRCD_LOW: u64 = 0x220;
+    pub const FRCD_HIGH: u64 = 0x228;
+}
+
+// Version 1.0
+const VERSION: u32 = 0x10;
+
+// 256 domains, 39 and 48 bit guest address widths, a 48 bit maximum
+// address width and a single fault recording register (at 0x220)
+const CAPABILITIES: u64 =
+    0b010 | 0b00110 << 8 | (48 - 1) << 16 | (reg::FRCD_LOW / 16) << 24;
+
+// Coherent page walks, queued invalidation and the IOTLB registers at 0x200
+const EXTENDED_CAPABILITIES: u64 = 1 << 0 | 1 << 1 | (reg::IVA / 16) << 8;
+
+// Global command/status bits
+const GCMD_TRANSLATION_ENABLE: u32 = 1 << 31;
+const GCMD_SET_ROOT_TABLE: u32 = 1 << 30;
+const GCMD_QUEUED_INVALIDATION_ENABLE: u32 = 1 << 26;
+
+// Fault status bits
+const FSTS_PRIMARY_FAULT_OVERFLOW: u32 = 1 << 0;
+const FSTS_PRIMARY_PENDING_FAULT: u32 = 1 << 1;
+const FSTS_INVALIDATION_QUEUE_ERROR: u32 = 1 << 4;
+
+// Event control bits (for both FECTL and IECTL)
+const EVENT_INTERRUPT_MASK: u32 = 1 << 31;
+const EVENT_INTERRUPT_PENDING: u32 = 1 << 30;
+
+// Invalidation completion status
+const ICS_WAIT_COMPLETE: u32 = 1 << 0;
+
+// Register based invalidation
+const CCMD_INVALIDATE: u64 = 1 << 63;
+const IOTLB_INVALIDATE: u64 = 1 << 63;
+
+// Fault recording
+const FRCD_FAULT: u64 = 1 << 63;
+const FRCD_TYPE_READ: u64 = 1 << 62;
+
+// Invalidation queue descriptors
+const QUEUE_INDEX_MASK: u64 = 0x7fff0;
+const DESCRIPTOR_SIZE: u64 = 16;
+const DESCRIPTOR_CONTEXT_CACHE: u64 = 1;
+const DESCRIPTOR_IOTLB: u64 = 2;
+const DESCRIPTOR_DEVICE_TLB: u64 = 3;
+const DESCRIPTOR_INTERRUPT_ENTRY_CACHE: u64 = 4;
+const DESCRIPTOR_WAIT: u64 = 5;
+const WAIT_INTERRUPT: u64 = 1 << 4;
+const WAIT_STATUS_WRITE: u64 = 1 << 5;
+
+// Translation structure fields
+const ENTRY_PRESENT: u64 = 1 << 0;
+const CONTEXT_FAULT_PROCESSING_DISABLE: u64 = 1 << 1;
+const CONTEXT_TRANSLATION_TYPE: u64 = 0b11 << 2;
+const SL_READ: u64 = 1 << 0;
+const SL_WRITE: u64 = 1 << 1;
+const ADDRESS_MASK: u64 = 0x000fffff_fffff000;
+
+/// The reasons for a DMA remapping fault (see `VT-d § 7.1.3`)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FaultReason {
+    RootNotPresent = 0x1,
+    ContextNotPresent = 0x2,
+    InvalidContext = 0x3,
+    AddressBeyondWidth = 0x4,
+    WriteNotPermitted = 0x5,
+    ReadNotPermitted = 0x6,
+    InvalidTableAddress = 0x7,
+}
+
+/// An emulated Intel DMA remapping hardware unit
+pub struct IntelIommu {
+    base: u64,
+    gsts: u32,
+
+    // The value of RTADDR, which only takes effect on SRTP
+    root_table_register: u64,
+    root_table: u64,
+    ccmd: u64,
+    iva: u64,
+    iotlb: u64,
+
+    fsts: u32,
+    fectl: u32,
+    fedata: u32,
+    feaddr: u32,
+    feuaddr: u32,
+    fault_record: (u64, u64),
+
+    iqh: u64,
+    iqt: u64,
+    iqa: u64,
+    ics: u32,
+    iectl: u32,
+    iedata: u32,
+    ieaddr: u32,
+    ieuaddr: u32,
+}
+
+fn read_guest_u64(space: &GuestAddressSpace, addr: u64) -> Result<u64> {
+    let frame = space.find_host_frame(GuestPhysAddr::new(addr))?;
+    let offset = addr as usize % HostPhysFrame::SIZE;
+    let array = unsafe { frame.as_array() };
+    Ok(LittleEndian::read_u64(&array[offset..offset + 8]))
+}
+
+fn write_guest_u32(
+    space: &GuestAddressSpace,
+    addr: u64,
+    val: u32,
+) -> Result<()> {
+    let mut frame = space.find_host_frame(GuestPhysAddr::new(addr))?;
+    let offset = addr as usize % HostPhysFrame::SIZE;
+    let array = unsafe { frame.as_mut_array() };
+    LittleEndian::write_u32(&mut array[offset..offset + 4], val);
+    Ok(())
+}
+
+impl IntelIommu {
+    pub fn new(base: u64) -> Arc<RwLock<Self>> {
+        Arc::new(RwLock::new(Self {
+            base: base,
+            gsts: 0,
+            root_table_register: 0,
+            root_table: 0,
+            ccmd: 0,
+            iva: 0,
+            iotlb: 0,
+            fsts: 0,
+            fectl: EVENT_INTERRUPT_MASK,
+            fedata: 0,
+            feaddr: 0,
+            feuaddr: 0,
+            fault_record: (0, 0),
+            iqh: 0,
+            iqt: 0,
+            iqa: 0,
+            ics: 0,
+            iectl: EVENT_INTERRUPT_MASK,
+            iedata: 0,
+            ieaddr: 0,
+            ieuaddr: 0,
+        }))
+    }
+
+    /// Build the DMAR ACPI table describing this unit
+    ///
+    /// The unit covers every device on PCI segment 0. Interrupt remapping
+    /// is not supported.
+    pub fn dmar_table(&self) -> Vec<u8> {
+        let mut table = vec![];
+        table.extend_from_slice(b"DMAR");
+        table.extend_from_slice(&[0u8; 4]); // Length (filled in below)
+        table.push(1); // Revision
+        table.push(0); // Checksum (filled in below)
+        table.extend_from_slice(b"MYTHRL");
+        table.extend_from_slice(b"MYTHRIL ");
+        table.extend_from_slice(&1u32.to_le_bytes()); // OEM Revision
+        table.extend_from_slice(b"MYTH");
+        table.extend_from_slice(&1u32.to_le_bytes()); // Creator Revision
+
+        table.push(48 - 1); // Host Address Width
+        table.push(0); // Flags
+        table.extend_from_slice(&[0u8; 10]);
+
+        // DMA Remapping Hardware Unit Definition
+        table.extend_from_slice(&0u16.to_le_bytes()); // Type
+        table.extend_from_slice(&16u16.to_le_bytes()); // Length
+        table.push(1); // Flags (INCLUDE_PCI_ALL)
+        table.push(0);
+        table.extend_from_slice(&0u16.to_le_bytes()); // Segment
+        table.extend_from_slice(&self.base.to_le_bytes());
+
+        let length = table.len() as u32;
+        table[4..8].copy_from_slice(&length.to_le_bytes());
+        let sum = table.iter().fold(0u8, |acc, val| acc.wrapping_add(*val));
+        table[9] = 0u8.wrapping_sub(sum);
+        table
+    }
+
+    /// Translate a DMA access by `source_id` to `iova` using the tables
+    /// programmed by the guest
+    ///
+    /// If the access is not permitted, the fault is recorded (and reported
+    /// to the guest) and an error is returned.
+    pub fn translate(
+        &mut self,
+        source_id: u16,
+        iova: u64,
+        write: bool,
+        space: &GuestAddressSpace,
+        responses: &mut ResponseEventArray,
+    ) -> Result<GuestPhysAddr> {
+        if self.gsts & GCMD_TRANSLATION_ENABLE == 0 {
+            return Ok(GuestPhysAddr::new(iova));
+        }
+
+        match self.walk(source_id, iova, write, space) {
+            Ok(addr) => Ok(addr),
+            Err((reason, report)) => {
+                if report {
+                    self.record_fault(source_id, iova, write, reason);
+                    self.update_fault_event(responses);
+                }
+                Err(Error::InvalidValue(format!(
+                    "DMA remapping fault for 0x{:x} at 0x{:x}: {:?}",
+                    source_id, iova, reason
+                )))
+            }
+        }
+    }
+
+    // Walk the guest translation structures. On failure, returns the fault
+    // reason and whether the fault should be reported.
+    fn walk(
+        &self,
+        source_id: u16,
+        iova: u64,
+        write: bool,
+        space: &GuestAddressSpace,
+    ) -> core::result::Result<GuestPhysAddr, (FaultReason, bool)> {
+        let bus = (source_id >> 8) as u64;
+        let devfn = (source_id & 0xff) as u64;
+
+        let root = read_guest_u64(space, self.root_table + bus * 16)
+            .map_err(|_| (FaultReason::InvalidTableAddress, true))?;
+        if root & ENTRY_PRESENT == 0 {
+            return Err((FaultReason::RootNotPresent, true));
+        }
+
+        let context_addr = (root & ADDRESS_MASK) + devfn * 16;
+        let (context_low, context_high) = read_guest_u64(space, context_addr)
+            .and_then(|low| Ok((low, read_guest_u64(space, context_addr + 8)?)))
+            .map_err(|_| (FaultReason::InvalidTableAddress, true))?;
+        if context_low & ENTRY_PRESENT == 0 {
+            return Err((FaultReason::ContextNotPresent, true));
+        }
+        let report = context_low & CONTEXT_FAULT_PROCESSING_DISABLE == 0;
+
+        // Only second-level translation of untranslated requests is
+        // supported, with the widths advertised in SAGAW
+        let levels = match context_high & 0b111 {
+            1 => 3,
+            2 => 4,
+            _ => return Err((FaultReason::InvalidContext, report)),
+        };
+        if context_low & CONTEXT_TRANSLATION_TYPE != 0 {
+            return Err((FaultReason::InvalidContext, report));
+        }
+        if iova >> (12 + 9 * levels) != 0 {
+            return Err((FaultReason::AddressBeyondWidth, report));
+        }
+
+        let denied = if write {
+            FaultReason::WriteNotPermitted
+        } else {
+            FaultReason::ReadNotPermitted
+        };
+        let required = if write { SL_WRITE } else { SL_READ };
+
+        let mut table = context_low & ADDRESS_MASK;
+        for level in (1..=levels).rev() {
+            let index = (iova >> (12 + 9 * (level - 1))) & 0x1ff;
+            let entry = read_guest_u64(space, table + index * 8)
+                .map_err(|_| (FaultReason::InvalidTableAddress, report))?;
+            if entry & required == 0 {
+                return Err((denied, report));
+            }
+            table = entry & ADDRESS_MASK;
+        }
+        Ok(GuestPhysAddr::new(table | (iova & 0xfff)))
+    }
+
+    fn record_fault(
+        &mut self,
+        source_id: u16,
+        iova: u64,
+        write: bool,
+        reason: FaultReason,
+    ) {
+        // There is only one fault recording register
+        if self.fault_record.1 & FRCD_FAULT != 0 {
+            self.fsts |= FSTS_PRIMARY_FAULT_OVERFLOW;
+            return;
+        }
+        let access = if write { 0 } else { FRCD_TYPE_READ };
+        self.fault_record = (
+            iova & !0xfff,
+            FRCD_FAULT | access | (reason as u64) << 32 | source_id as u64,
+        );
+    }
+
+    fn fault_status(&self) -> u32 {
+        let mut status = self.fsts;
+        if self.fault_record.1 & FRCD_FAULT != 0 {
+            status |= FSTS_PRIMARY_PENDING_FAULT;
+        }
+        status
+    }
+
+    fn inject(vector: u32, responses: &mut ResponseEventArray) {
+        responses.push(DeviceEventResponse::Interrupt((
+            vector as u8,
+            vcpu::InjectedInterruptType::ExternalInterrupt,
+        )));
+    }
+
+    // Deliver (or mark pending) a fault event if any fault is outstanding
+    fn update_fault_event(&mut self, responses: &mut ResponseEventArray) {
+        if self.fault_status() == 0 {
+            self.fectl &= !EVENT_INTERRUPT_PENDING;
+        } else if self.fectl & EVENT_INTERRUPT_MASK != 0 {
+            self.fectl |= EVENT_INTERRUPT_PENDING;
+        } else {
+            Self::inject(self.fedata, responses);
+        }
+    }
+
+    fn update_invalidation_event(
+        &mut self,
+        responses: &mut ResponseEventArray,
+    ) {
+        if self.ics & ICS_WAIT_COMPLETE == 0 {
+            self.iectl &= !EVENT_INTERRUPT_PENDING;
+        } else if self.iectl & EVENT_INTERRUPT_MASK != 0 {
+            self.iectl |= EVENT_INTERRUPT_PENDING;
+        } else {
+            Self::inject(self.iedata, responses);
+        }
+    }
+
+    fn process_invalidation_queue(
+        &mut self,
+        space: &GuestAddressSpace,
+        responses: &mut ResponseEventArray,
+    ) -> Result<()> {
+        if self.gsts & GCMD_QUEUED_INVALIDATION_ENABLE == 0
+            || self.fsts & FSTS_INVALIDATION_QUEUE_ERROR != 0
+        {
+            return Ok(());
+        }
+
+        let base = self.iqa & !0xfff;
+        let queue_size = (256 << (self.iqa & 0b111)) * DESCRIPTOR_SIZE;
+        while self.iqh != self.iqt {
+            let low = read_guest_u64(space, base + self.iqh)?;
+            let high = read_guest_u64(space, base + self.iqh + 8)?;
+            match low & 0xf {
+                // Nothing is cached, so invalidations complete immediately
+                DESCRIPTOR_CONTEXT_CACHE
+                | DESCRIPTOR_IOTLB
+                | DESCRIPTOR_DEVICE_TLB
+                | DESCRIPTOR_INTERRUPT_ENTRY_CACHE => (),
+                DESCRIPTOR_WAIT => {
+                    if low & WAIT_STATUS_WRITE != 0 {
+                        write_guest_u32(
+                            space,
+                            high & !0b11,
+                            (low >> 32) as u32,
+                        )?;
+                    }
+                    if low & WAIT_INTERRUPT != 0 {
+                        self.ics |= ICS_WAIT_COMPLETE;
+                        self.update_invalidation_event(responses);
+                    }
+                }
+                ty => {
+                    warn!("Invalid IOMMU invalidation descriptor: 0x{:x}", ty);
+                    self.fsts |= FSTS_INVALIDATION_QUEUE_ERROR;
+                    self.update_fault_event(responses);
+                    break;
+                }
+            }
+            self.iqh = (self.iqh + DESCRIPTOR_SIZE) % queue_size;
+        }
+        Ok(())
+    }
+
+    fn write_global_command(&mut self, command: u32) {
+        if command & GCMD_SET_ROOT_TABLE != 0 {
+            self.root_table = self.root_table_register & ADDRESS_MASK;
+            self.gsts |= GCMD_SET_ROOT_TABLE;
+        }
+
+        if command & GCMD_TRANSLATION_ENABLE != 0 {
+            self.gsts |= GCMD_TRANSLATION_ENABLE;
+        } else {
+            self.gsts &= !GCMD_TRANSLATION_ENABLE;
+        }
+
+        if command & GCMD_QUEUED_INVALIDATION_ENABLE != 0 {
+            if self.gsts & GCMD_QUEUED_INVALIDATION_ENABLE == 0 {
+                self.iqh = 0;
+            }
+            self.gsts |= GCMD_QUEUED_INVALIDATION_ENABLE;
+        } else {
+            self.gsts &= !GCMD_QUEUED_INVALIDATION_ENABLE;
+        }
+    }
+
+    // Returns the value of a 64-bit register
+    fn read_qword(&self, offset: u64) -> u64 {
+        match offset {
+            reg::CAP => CAPABILITIES,
+            reg::ECAP => EXTENDED_CAPABILITIES,
+            reg::RTADDR => self.root_table_register,
+            reg::CCMD => self.ccmd,
+            reg::IQH => self.iqh,
+            reg::IQT => self.iqt,
+            reg::IQA => self.iqa,
+            reg::IVA => self.iva,
+            reg::IOTLB => self.iotlb,
+            reg::FRCD_LOW => self.fault_record.0,
+            reg::FRCD_HIGH => self.fault_record.1,
+            _ => 0,
+        }
+    }
+
+    fn read_dword(&self, offset: u64) -> u32 {
+        match offset {
+            reg::VER => VERSION,
+            reg::GCMD => 0,
+            reg::GSTS => self.gsts,
+            reg::FSTS => self.fault_status(),
+            reg::FECTL => self.fectl,
+            reg::FEDATA => self.fedata,
+            reg::FEADDR => self.feaddr,
+            reg::FEUADDR => self.feuaddr,
+            reg::ICS => self.ics,
+            reg::IECTL => self.iectl,
+            reg::IEDATA => self.iedata,
+            reg::IEADDR => self.ieaddr,
+            reg::IEUADDR => self.ieuaddr,
+            offset => {
+                (self.read_qword(offset & !0b111) >> ((offset & 0b100) * 8))
+                    as u32
+            }
+        }
+    }
+
+    fn write_qword(
+        &mut self,
+        offset: u64,
+        val: u64,
+        upper: bool,
+        space: &GuestAddressSpace,
+        responses: &mut ResponseEventArray,
+    ) -> Result<()> {
+        match offset {
+            reg::RTADDR => self.root_table_register = val,
+            reg::CCMD => {
+                // Report the invalidation as performed at the requested
+                // granularity
+                self.ccmd = val;
+                if upper && val & CCMD_INVALIDATE != 0 {
+                    let granularity = (val >> 61) & 0b11;
+                    self.ccmd = (val & !CCMD_INVALIDATE & !(0b11 << 59))
+                        | granularity << 59;
+                }
+            }
+            reg::IOTLB => {
+                self.iotlb = val;
+                if upper && val & IOTLB_INVALIDATE != 0 {
+                    let granularity = (val >> 60) & 0b11;
+                    self.iotlb = (val & !IOTLB_INVALIDATE & !(0b11 << 57))
+                        | granularity << 57;
+                }
+            }
+            reg::IVA => self.iva = val,
+            reg::IQA => self.iqa = val,
+            reg::IQT => {
+                self.iqt = val & QUEUE_INDEX_MASK;
+                if !upper {
+                    self.process_invalidation_queue(space, responses)?;
+                }
+            }
+            reg::FRCD_HIGH => {
+                if upper && val & FRCD_FAULT != 0 {
+                    self.fault_record = (0, 0);
+                    self.update_fault_event(responses);
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn write_dword(
+        &mut self,
+        offset: u64,
+        val: u32,
+        space: &GuestAddressSpace,
+        responses: &mut ResponseEventArray,
+    ) -> Result<()> {
+        match offset {
+            reg::GCMD => self.write_global_command(val),
+            reg::FSTS => {
+                self.fsts &= !(val
+                    & (FSTS_PRIMARY_FAULT_OVERFLOW
+                        | FSTS_INVALIDATION_QUEUE_ERROR));
+                if val & FSTS_INVALIDATION_QUEUE_ERROR != 0 {
+                    self.process_invalidation_queue(space, responses)?;
+                }
+                self.update_fault_event(responses);
+            }
+            reg::FECTL => {
+                self.fectl = (self.fectl & !EVENT_INTERRUPT_MASK)
+                    | (val & EVENT_INTERRUPT_MASK);
+                if self.fectl & EVENT_INTERRUPT_MASK == 0
+                    && self.fectl & EVENT_INTERRUPT_PENDING != 0
+                {
+                    self.fectl &= !EVENT_INTERRUPT_PENDING;
+                    Self::inject(self.fedata, responses);
+                }
+            }
+            reg::FEDATA => self.fedata = val,
+            reg::FEADDR => self.feaddr = val,
+            reg::FEUADDR => self.feuaddr = val,
+            reg::ICS => {
+                self.ics &= !(val & ICS_WAIT_COMPLETE);
+                self.update_invalidation_event(responses);
+            }
+            reg::IECTL => {
+                self.iectl = (self.iectl & !EVENT_INTERRUPT_MASK)
+                    | (val & EVENT_INTERRUPT_MASK);
+                if self.iectl & EVENT_INTERRUPT_MASK == 0
+                    && self.iectl & EVENT_INTERRUPT_PENDING != 0
+                {
+                    self.iectl &= !EVENT_INTERRUPT_PENDING;
+                    Self::inject(self.iedata, responses);
+                }
+            }
+            reg::IEDATA => self.iedata = val,
+            reg::IEADDR => self.ieaddr = val,
+            reg::IEUADDR => self.ieuaddr = val,
+            reg::VER | reg::GSTS => (),
+            offset => {
+                let aligned = offset & !0b111;
+                let shift = (offset & 0b100) * 8;
+                let old = self.read_qword(aligned);
+                let val =
+                    (old & !(0xffffffff << shift)) | (val as u64) << shift;
+                self.write_qword(aligned, val, shift != 0, space, responses)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl EmulatedDevice for IntelIommu {
+    fn services(&self) -> Vec<DeviceRegion> {
+        vec![DeviceRegion::MemIo(
+            GuestPhysAddr::new(self.base)
+                ..=GuestPhysAddr::new(self.base + REGISTER_BLOCK_SIZE - 1),
+        )]
+    }
+
+    fn on_event(&mut self, event: Event) -> Result<()> {
+        match event.kind {
+            DeviceEvent::MemRead(addr, mut req) => {
+                let offset = addr.as_u64() - self.base;
+                let data = req.as_mut_slice();
+                if data.len() != 4 && data.len() != 8 {
+                    return Err(Error::InvalidValue(format!(
+                        "Invalid IOMMU register read size: {}",
+                        data.len()
+                    )));
+                }
+                for (i, chunk) in data.chunks_mut(4).enumerate() {
+                    let val = self.read_dword(offset + i as u64 * 4);
+                    chunk.copy_from_slice(&val.to_le_bytes());
+                }
+            }
+            DeviceEvent::MemWrite(addr, req) => {
+                let offset = addr.as_u64() - self.base;
+                let data = req.as_slice();
+                if data.len() != 4 && data.len() != 8 {
+                    return Err(Error::InvalidValue(format!(
+                        "Invalid IOMMU register write size: {}",
+                        data.len()
+                    )));
+                }
+                let mut space = event.space;
+                for (i, chunk) in data.chunks(4).enumerate() {
+                    self.write_dword(
+                        offset + i as u64 * 4,
+                        LittleEndian::read_u32(chunk),
+                        space.space_mut(),
+                        event.responses,
+                    )?;
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::acpi::dmar::{RemappingStructure, DMAR};
+    use crate::acpi::rsdt::SDT;
+
+    fn write_guest_u64(space: &GuestAddressSpace, addr: u64, val: u64) {
+        write_guest_u32(space, addr, val as u32).unwrap();
+        write_guest_u32(space, addr + 4, (val >> 32) as u32).unwrap();
+    }
+
+    fn setup_space() -> GuestAddressSpace {
+        let mut space = GuestAddressSpace::new().unwrap();
+        for i in 0..8 {
+            space
+                .map_new_frame(GuestPhysAddr::new(i * 0x1000), false)
+                .unwrap();
+        }
+        space
+    }
+
+    #[test]
+    fn test_dmar_table() {
+        let iommu = IntelIommu::new(DEFAULT_REGISTER_BASE);
+        let table = iommu.read().dmar_table();
+        let sdt = unsafe { SDT::new(table.as_ptr()).unwrap() };
+        let dmar = DMAR::new(&sdt).unwrap();
+        assert_eq!(dmar.host_address_width, 48);
+        match dmar.structures().next() {
+            Some(Ok(RemappingStructure::HardwareUnit {
+                register_base,
+                ..
+            })) => assert_eq!(register_base, DEFAULT_REGISTER_BASE),
+            other => panic!("Unexpected structure: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_translate() {
+        let space = setup_space();
+        let mut responses = ResponseEventArray::new();
+        let iommu = IntelIommu::new(DEFAULT_REGISTER_BASE);
+        let mut iommu = iommu.write();
+
+        // Untranslated until the guest enables translation
+        assert_eq!(
+            iommu
+                .translate(0x08, 0x1234, false, &space, &mut responses)
+                .unwrap()
+                .as_u64(),
+            0x1234
+        );
+
+        // Root table at 0x1000, context table at 0x2000, a 3 level
+        // second-level table at 0x3000-0x5000 mapping 0x0 to 0x7000 (RO)
+        write_guest_u64(&space, 0x1000, 0x2000 | ENTRY_PRESENT);
+        write_guest_u64(&space, 0x2000 + 0x08 * 16, 0x3000 | ENTRY_PRESENT);
+        write_guest_u64(&space, 0x2000 + 0x08 * 16 + 8, 1 | 1 << 8);
+        write_guest_u64(&space, 0x3000, 0x4000 | SL_READ | SL_WRITE);
+        write_guest_u64(&space, 0x4000, 0x5000 | SL_READ | SL_WRITE);
+        write_guest_u64(&space, 0x5000, 0x7000 | SL_READ);
+
+        iommu
+            .write_dword(reg::RTADDR, 0x1000, &space, &mut responses)
+            .unwrap();
+        iommu
+            .write_dword(reg::GCMD, GCMD_SET_ROOT_TABLE, &space, &mut responses)
+            .unwrap();
+        iommu
+            .write_dword(
+                reg::GCMD,
+                GCMD_TRANSLATION_ENABLE,
+                &space,
+                &mut responses,
+            )
+            .unwrap();
+        assert_eq!(iommu.read_dword(reg::GSTS), 0xc0000000);
+
+        assert_eq!(
+            iommu
+                .translate(0x08, 0x123, false, &space, &mut responses)
+                .unwrap()
+                .as_u64(),
+            0x7123
+        );
+
+        // A write to a read-only page is recorded (but the event is masked)
+        assert!(iommu
+            .translate(0x08, 0x123, true, &space, &mut responses)
+            .is_err());
+        assert_eq!(iommu.read_dword(reg::FSTS), FSTS_PRIMARY_PENDING_FAULT);
+        assert_eq!(iommu.read_dword(reg::FRCD_HIGH), 0x08);
+        assert_eq!(
+            iommu.read_dword(reg::FRCD_HIGH + 4),
+            0x80000000 | FaultReason::WriteNotPermitted as u32
+        );
+        assert!(responses.is_empty());
+
+        // Unmasking the fault event delivers the interrupt
+        iommu
+            .write_dword(reg::FEDATA, 0x40, &space, &mut responses)
+            .unwrap();
+        iommu
+            .write_dword(reg::FECTL, 0, &space, &mut responses)
+            .unwrap();
+        assert_eq!(responses.len(), 1);
+
+        // Another device has no context entry
+        assert!(iommu
+            .translate(0x10, 0x123, false, &space, &mut responses)
+            .is_err());
+        assert_eq!(
+            iommu.read_dword(reg::FSTS),
+            FSTS_PRIMARY_PENDING_FAULT | FSTS_PRIMARY_FAULT_OVERFLOW
+        );
+    }
+
+    #[test]
+    fn test_invalidation_queue() {
+        let space = setup_space();
+        let mut responses = ResponseEventArray::new();
+        let iommu = IntelIommu::new(DEFAULT_REGISTER_BASE);
+        let mut iommu = iommu.write();
+
+        // A queue at 0x1000 with an IOTLB invalidation and a wait
+        // descriptor that writes 0x1234 to 0x2000
+        write_guest_u64(&space, 0x1000, DESCRIPTOR_IOTLB | 1 << 4);
+        write_guest_u64(
+            &space,
+            0x1010,
+            DESCRIPTOR_WAIT | WAIT_STATUS_WRITE | WAIT_INTERRUPT | 0x1234 << 32,
+        );
+        write_guest_u64(&space, 0x1018, 0x2000);
+
+        iommu
+            .write_dword(reg::IQA, 0x1000, &space, &mut responses)
+            .unwrap();
+        iommu
+            .write_dword(
+                reg::GCMD,
+                GCMD_QUEUED_INVALIDATION_ENABLE,
+                &space,
+                &mut responses,
+            )
+            .unwrap();
+        iommu
+            .write_dword(reg::IQT, 0x20, &space, &mut responses)
+            .unwrap();
+
+        assert_eq!(iommu.read_dword(reg::IQH), 0x20);
+        assert_eq!(read_guest_u64(&space, 0x2000).unwrap(), 0x1234);
+        assert_eq!(iommu.read_dword(reg::ICS), ICS_WAIT_COMPLETE);
+        assert_eq!(iommu.read_dword(reg::IECTL), 0xc0000000);
+
+        // An invalid descriptor stops the queue
+        write_guest_u64(&space, 0x1020, 0xf);
+        iommu
+            .write_dword(reg::IQT, 0x30, &space, &mut responses)
+            .unwrap();
+        assert_eq!(iommu.read_dword(reg::IQH), 0x20);
+        assert_eq!(iommu.read_dword(reg::FSTS), FSTS_INVALIDATION_QUEUE_ERROR);
+    }
+}
@@ -1,10 +1,162 @@
 use crate::error::Result;
 use crate::memory::GuestPhysAddr;
+use crate::time;
 use crate::virtdev::{DeviceRegion, EmulatedDevice, Event};
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::time::Duration;
 use spin::RwLock;
 
+/// The default frequency of the local APIC timer (before division)
+///
+/// This is reported to the guest as the core crystal clock frequency
+/// (CPUID leaf 0x15), which Linux uses as the APIC timer frequency.
+pub const DEFAULT_APIC_TIMER_FREQUENCY: u64 = 25_000_000;
+
+/// The offset of the task-priority register in the local APIC page
+pub const TPR: usize = 0x80;
+
+/// The offsets of the low and high halves of the interrupt command
+/// register in the local APIC page
+pub const ICR_LOW: usize = 0x300;
+pub const ICR_HIGH: usize = 0x310;
+
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_MODE_MASK: u32 = 0b11 << 17;
+const LVT_TIMER_MODE_PERIODIC: u32 = 0b01 << 17;
+const LVT_TIMER_MODE_TSC_DEADLINE: u32 = 0b10 << 17;
+
+/// The timer of a virtual local APIC
+///
+/// The guest's writes to the timer registers are performed on the
+/// virtual-APIC page, and then reported by an APIC-write VM exit. The
+/// timer is then (re)armed on the timer wheel of the current core, which
+/// injects the LVT vector when it expires.
+pub struct ApicTimer {
+    frequency: u64,
+    lvt: u32,
+    initial_count: u32,
+    divide_config: u32,
+    timer: Option<time::TimerId>,
+}
+
+impl ApicTimer {
+    pub const LVT_TIMER: u16 = 0x320;
+    pub const INITIAL_COUNT: u16 = 0x380;
+    pub const CURRENT_COUNT: u16 = 0x390;
+    pub const DIVIDE_CONFIG: u16 = 0x3e0;
+
+    /// Create a (masked) timer that counts at `frequency` Hz before
+    /// division
+    pub fn new(frequency: u64) -> Self {
+        Self {
+            frequency,
+            lvt: LVT_MASKED,
+            initial_count: 0,
+            divide_config: 0,
+            timer: None,
+        }
+    }
+
+    /// Returns true if `offset` is one of the timer registers
+    pub fn is_timer_register(offset: u16) -> bool {
+        match offset {
+            Self::LVT_TIMER
+            | Self::INITIAL_COUNT
+            | Self::CURRENT_COUNT
+            | Self::DIVIDE_CONFIG => true,
+            _ => false,
+        }
+    }
+
+    /// The divisor selected by a value of the divide configuration
+    /// register (bits 0, 1 and 3)
+    pub fn divisor(divide_config: u32) -> u32 {
+        let value = (divide_config & 0b11) | ((divide_config >> 1) & 0b100);
+        if value == 0b111 {
+            1
+        } else {
+            2 << value
+        }
+    }
+
+    /// The time from the start of the count to its expiration
+    pub fn period(&self) -> Option<Duration> {
+        if self.initial_count == 0 || self.frequency == 0 {
+            return None;
+        }
+        let ticks = self.initial_count as u128
+            * Self::divisor(self.divide_config) as u128;
+        let nanos = ticks * 1_000_000_000 / self.frequency as u128;
+        Some(Duration::from_nanos(nanos as u64))
+    }
+
+    /// Handle a guest write of `value` to the timer register at `offset`
+    pub fn register_written(&mut self, offset: u16, value: u32) -> Result<()> {
+        match offset {
+            Self::LVT_TIMER => self.lvt = value,
+            Self::INITIAL_COUNT => self.initial_count = value,
+            // The divide configuration only affects the next count
+            Self::DIVIDE_CONFIG => {
+                self.divide_config = value;
+                return Ok(());
+            }
+            _ => return Ok(()),
+        }
+
+        if let Some(id) = self.timer.take() {
+            time::cancel_timer(&id)?;
+        }
+        if self.lvt & LVT_MASKED != 0 {
+            return Ok(());
+        }
+        let period = match self.period() {
+            Some(period) => period,
+            None => return Ok(()),
+        };
+
+        let vector = (self.lvt & 0xff) as u8;
+        self.timer = match self.lvt & LVT_TIMER_MODE_MASK {
+            LVT_TIMER_MODE_PERIODIC => {
+                Some(time::set_periodic_timer(period, vector))
+            }
+            LVT_TIMER_MODE_TSC_DEADLINE => {
+                warn!("TSC-deadline mode of the APIC timer is not supported");
+                None
+            }
+            _ => Some(time::set_oneshot_timer(period, vector)),
+        };
+        Ok(())
+    }
+}
+
+/// The priority class of an interrupt vector or task priority
+pub fn priority_class(priority: u8) -> u8 {
+    priority >> 4
+}
+
+/// Returns true if the guest task priority `tpr` masks the fixed interrupt
+/// `vector` (i.e., the interrupt is not above the priority class of the TPR)
+///
+/// Other interrupts (including those from the PIC, which are delivered as
+/// ExtINT) are not affected by the task priority.
+pub fn is_masked_by_tpr(vector: u8, tpr: u8) -> bool {
+    priority_class(vector) <= priority_class(tpr)
+}
+
+/// The TPR threshold at which the guest becomes able to accept one of the
+/// interrupt `vectors` that are masked by `tpr`, or 0 if none are masked
+///
+/// A VM exit occurs when the guest lowers its task priority class below the
+/// threshold, so this picks the highest priority class that is masked.
+pub fn tpr_threshold(vectors: impl Iterator<Item = u8>, tpr: u8) -> u8 {
+    vectors
+        .filter(|vector| is_masked_by_tpr(*vector, tpr))
+        .map(priority_class)
+        .max()
+        .unwrap_or(0)
+}
+
 #[derive(Default)]
 pub struct LocalApic;
 
@@ -16,22 +168,51 @@ impl LocalApic {
 
 impl EmulatedDevice for LocalApic {
     fn services(&self) -> Vec<DeviceRegion> {
-        vec![
-            DeviceRegion::MemIo(
-                GuestPhysAddr::new(0xfee00000)..=GuestPhysAddr::new(0xfee010f0),
-            ),
-            //FIXME: this is actually the 1st HPET
-            DeviceRegion::MemIo(
-                GuestPhysAddr::new(0xfed00000)..=GuestPhysAddr::new(0xfed010f0),
-            ),
-            //FIXME: this is actually the io apic
-            DeviceRegion::MemIo(
-                GuestPhysAddr::new(0xfec00000)..=GuestPhysAddr::new(0xfec010f0),
-            ),
-        ]
+        vec![DeviceRegion::MemIo(
+            GuestPhysAddr::new(0xfee00000)..=GuestPhysAddr::new(0xfee010f0),
+        )]
     }
 
     fn on_event(&mut self, _event: Event) -> Result<()> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_timer_divisor() {
+        assert_eq!(ApicTimer::divisor(0b0000), 2);
+        assert_eq!(ApicTimer::divisor(0b0011), 16);
+        assert_eq!(ApicTimer::divisor(0b1000), 32);
+        assert_eq!(ApicTimer::divisor(0b1010), 128);
+        assert_eq!(ApicTimer::divisor(0b1011), 1);
+    }
+
+    #[test]
+    fn test_task_priority() {
+        assert!(!is_masked_by_tpr(0x31, 0x00));
+        assert!(is_masked_by_tpr(0x31, 0x30));
+        assert!(is_masked_by_tpr(0x3f, 0x3a));
+        assert!(!is_masked_by_tpr(0x41, 0x3f));
+
+        let pending = [0x31, 0x52, 0xef];
+        assert_eq!(tpr_threshold(pending.iter().copied(), 0x00), 0);
+        assert_eq!(tpr_threshold(pending.iter().copied(), 0x40), 3);
+        assert_eq!(tpr_threshold(pending.iter().copied(), 0x5f), 5);
+        assert_eq!(tpr_threshold(pending.iter().copied(), 0xff), 0xe);
+    }
+
+    #[test]
+    fn test_timer_period() {
+        let mut timer = ApicTimer::new(DEFAULT_APIC_TIMER_FREQUENCY);
+        assert_eq!(timer.period(), None);
+        timer.divide_config = 0b1011;
+        timer.initial_count = 25_000;
+        assert_eq!(timer.period(), Some(Duration::from_millis(1)));
+        timer.divide_config = 0b0001;
+        assert_eq!(timer.period(), Some(Duration::from_millis(4)));
+    }
+}
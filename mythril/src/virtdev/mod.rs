@@ -1,7 +1,10 @@
 use crate::error::{Error, Result};
+use crate::evtchn;
 use crate::memory::{GuestAddressSpaceViewMut, GuestPhysAddr};
+use crate::physdev;
 use crate::vcpu;
 use alloc::collections::btree_map::BTreeMap;
+use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use arrayvec::ArrayVec;
@@ -9,22 +12,38 @@ use core::cmp::Ordering;
 use core::convert::{TryFrom, TryInto};
 use core::fmt;
 use core::ops::RangeInclusive;
-use spin::RwLock;
+use spin::{Mutex, RwLock};
 
 pub mod acpi;
-pub mod com;
+pub mod acpi_pm;
 pub mod debug;
-pub mod dma;
+pub mod dma8237;
+pub mod e1000;
+pub mod fdc;
+pub mod hpet;
+pub mod ide;
 pub mod ignore;
-pub mod keyboard;
+pub mod ioapic;
+pub mod iommu;
 pub mod lapic;
+pub mod msi;
+pub mod pam;
 pub mod pci;
+pub mod pflash;
 pub mod pic;
 pub mod pit;
 pub mod pos;
+pub mod ps2;
+pub mod pvpanic;
 pub mod qemu_fw_cfg;
 pub mod rtc;
+pub mod sysctrl;
+pub mod tpm;
+pub mod trace;
+pub mod uart;
 pub mod vga;
+pub mod virtio;
+pub mod watchdog;
 
 const MAX_EVENT_RESPONSES: usize = 8;
 pub type ResponseEventArray =
@@ -34,17 +53,54 @@ pub type Port = u16;
 #[derive(Debug)]
 pub enum DeviceEvent<'a> {
     HostUartReceived(u8),
+    HostUartSignal(physdev::com::SerialSignal),
+    HotplugRequested(acpi::HotplugEvent),
+    PcieHotplugRequested(pci::PcieHotplugEvent),
+
+    /// Set the target size of the memory balloon (in pages)
+    BalloonRequested(u32),
     MemRead(GuestPhysAddr, MemReadRequest<'a>),
     MemWrite(GuestPhysAddr, MemWriteRequest<'a>),
     PortRead(Port, PortReadRequest<'a>),
     PortWrite(Port, PortWriteRequest<'a>),
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum DeviceEventResponse {
     GuestUartTransmitted(u8),
+
+    /// A line written to the debug console (see `debug::DebugConsole`), to
+    /// be logged with the name of the VM
+    GuestDebugLine(String),
     NextConsole,
     Interrupt((u8, vcpu::InjectedInterruptType)),
+
+    /// Raise the given GSI through the guest's interrupt controllers (its
+    /// I/O APIC entry, or the PIC for the legacy IRQs)
+    Gsi(u32),
+
+    /// The guest released the host function with the given requester id
+    /// from a hotplug slot, so it must be detached from the VM's IOMMU
+    /// domain (see `pci::PcieHostFunction`)
+    HostFunctionReleased(u16),
+
+    /// The guest asserted a reset line (e.g., through the PS/2 controller
+    /// or port 0x92), so every vCPU of the VM is reset
+    ResetRequested,
+
+    /// The guest reported a panic (through the pvpanic device)
+    GuestPanicked,
+
+    /// The guest asked to be shut down (e.g., through its vsock agent or an
+    /// ACPI soft-off)
+    ShutdownRequested,
+
+    /// Signal the event channel bound to the given source (see `evtchn`)
+    EventChannel(evtchn::EventSource),
+
+    /// The device changed the guest EPT mappings, so any cached translations
+    /// must be invalidated before the guest resumes
+    GuestMappingsChanged,
 }
 
 pub struct Event<'a> {
@@ -164,6 +220,7 @@ impl DeviceInteraction for GuestPhysAddr {
 pub struct DeviceMap {
     portio_map: BTreeMap<PortIoRegion, Arc<RwLock<dyn EmulatedDevice>>>,
     memio_map: BTreeMap<MemIoRegion, Arc<RwLock<dyn EmulatedDevice>>>,
+    traces: Vec<(String, Arc<Mutex<trace::DeviceTrace>>)>,
 }
 
 impl DeviceMap {
@@ -217,6 +274,43 @@ impl DeviceMap {
         }
         Ok(())
     }
+
+    /// Record the most recent `capacity` events delivered to the device
+    /// responsible for `op` (see `trace`), under the given name
+    ///
+    /// All of the regions serviced by the device are traced.
+    pub fn trace_device(
+        &mut self,
+        op: impl DeviceInteraction,
+        name: &str,
+        capacity: usize,
+    ) -> Result<Arc<Mutex<trace::DeviceTrace>>> {
+        let dev = op.find_device(self).cloned().ok_or_else(|| {
+            Error::MissingDevice(format!("No device to trace as '{}'", name))
+        })?;
+        let trace = Arc::new(Mutex::new(trace::DeviceTrace::new(capacity)));
+        let traced: Arc<RwLock<dyn EmulatedDevice>> =
+            trace::TracingDevice::new(dev.clone(), trace.clone());
+
+        // Compare the data pointers only, as the vtables of the same device
+        // may differ
+        let is_dev = |other: &Arc<RwLock<dyn EmulatedDevice>>| {
+            Arc::as_ptr(other) as *const u8 == Arc::as_ptr(&dev) as *const u8
+        };
+        for entry in self.portio_map.values_mut().filter(|d| is_dev(d)) {
+            *entry = traced.clone();
+        }
+        for entry in self.memio_map.values_mut().filter(|d| is_dev(d)) {
+            *entry = traced.clone();
+        }
+        self.traces.push((name.into(), trace.clone()));
+        Ok(trace)
+    }
+
+    /// The traced devices, by name
+    pub fn traces(&self) -> &[(String, Arc<Mutex<trace::DeviceTrace>>)] {
+        &self.traces
+    }
 }
 
 pub trait EmulatedDevice: Send + Sync {
@@ -225,8 +319,138 @@ pub trait EmulatedDevice: Send + Sync {
     fn on_event(&mut self, _event: Event) -> Result<()> {
         Ok(())
     }
+
+    /// Handle a string write to `port` (e.g., by REP OUTSW) of `data`, as
+    /// consecutive writes of `stride` bytes each
+    ///
+    /// By default, each write is delivered to `on_event` separately.
+    /// Devices that move a lot of data through a port (e.g., an IDE data
+    /// port) may handle the whole transfer at once instead.
+    fn on_port_write_bulk(
+        &mut self,
+        port: Port,
+        data: &[u8],
+        stride: usize,
+        mut space: GuestAddressSpaceViewMut,
+        responses: &mut ResponseEventArray,
+    ) -> Result<()> {
+        if stride == 0 || data.len() % stride != 0 {
+            return Err(Error::InvalidValue(format!(
+                "Invalid bulk port write of {} bytes with stride {}",
+                data.len(),
+                stride
+            )));
+        }
+        for element in data.chunks_exact(stride) {
+            let request = PortWriteRequest::try_from(element)?;
+            self.on_event(Event::new(
+                DeviceEvent::PortWrite(port, request),
+                space.reborrow(),
+                responses,
+            )?)?;
+        }
+        Ok(())
+    }
+}
+
+/// The value of a port access of 1, 2 or 4 bytes
+///
+/// The bytes are in little-endian order, as they are in the guest's
+/// registers and in the buffer of a string instruction (INS or OUTS), so a
+/// device sees the same bytes whichever instruction the guest used. The
+/// numeric constructors and accessors convert to and from that order, so
+/// devices never need to handle the bytes themselves.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PortIoValue {
+    bytes: [u8; 4],
+    len: usize,
 }
 
+impl PortIoValue {
+    /// The value of a one byte access
+    pub fn from_u8(val: u8) -> Self {
+        Self {
+            bytes: [val, 0, 0, 0],
+            len: 1,
+        }
+    }
+
+    /// The value of a two byte access
+    pub fn from_u16(val: u16) -> Self {
+        let mut bytes = [0u8; 4];
+        bytes[..2].copy_from_slice(&val.to_le_bytes());
+        Self { bytes, len: 2 }
+    }
+
+    /// The value of a four byte access
+    pub fn from_u32(val: u32) -> Self {
+        Self {
+            bytes: val.to_le_bytes(),
+            len: 4,
+        }
+    }
+
+    /// The value of an access of `len` bytes, holding the low bytes of
+    /// `val` (e.g., from the RAX of an OUT instruction)
+    pub fn from_sized(val: u32, len: usize) -> Result<Self> {
+        match len {
+            1 | 2 | 4 => {
+                let mut bytes = val.to_le_bytes();
+                for byte in bytes[len..].iter_mut() {
+                    *byte = 0;
+                }
+                Ok(Self { bytes, len })
+            }
+            len => Err(Error::InvalidValue(format!(
+                "Invalid port access size: {}",
+                len
+            ))),
+        }
+    }
+
+    /// The size of the access in bytes
+    pub fn size(&self) -> usize {
+        self.len
+    }
+
+    /// The low byte of the value
+    pub fn as_u8(&self) -> u8 {
+        self.bytes[0]
+    }
+
+    /// The low two bytes of the value (zero extended for a one byte access)
+    pub fn as_u16(&self) -> u16 {
+        u16::from_le_bytes([self.bytes[0], self.bytes[1]])
+    }
+
+    /// The value, zero extended to 32 bits
+    pub fn as_u32(&self) -> u32 {
+        u32::from_le_bytes(self.bytes)
+    }
+
+    /// The bytes of the access, in little-endian order
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.bytes[..self.len]
+    }
+}
+
+impl TryFrom<&[u8]> for PortIoValue {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self> {
+        let mut value = Self::from_sized(0, bytes.len())?;
+        value.as_bytes_mut().copy_from_slice(bytes);
+        Ok(value)
+    }
+}
+
+/// A request to read from a port, filled in by the device
+///
+/// Like a `PortIoValue`, the buffer is in little-endian order.
 #[derive(Debug)]
 pub enum PortReadRequest<'a> {
     OneByte(&'a mut [u8; 1]),
@@ -234,6 +458,7 @@ pub enum PortReadRequest<'a> {
     FourBytes(&'a mut [u8; 4]),
 }
 
+/// A write to a port, in little-endian order (see `PortIoValue`)
 #[derive(Debug)]
 pub enum PortWriteRequest<'a> {
     OneByte(&'a [u8; 1]),
@@ -262,10 +487,22 @@ impl<'a> PortReadRequest<'a> {
         }
     }
 
+    /// Complete the read with the low bytes of `val` (as many as the size
+    /// of the access)
     pub fn copy_from_u32(&mut self, val: u32) {
-        let arr = val.to_be_bytes();
         let len = self.len();
-        self.as_mut_slice().copy_from_slice(&arr[4 - len..]);
+        self.as_mut_slice()
+            .copy_from_slice(&val.to_le_bytes()[..len]);
+    }
+
+    /// The value read so far
+    pub fn value(&self) -> PortIoValue {
+        let mut value = PortIoValue {
+            bytes: [0; 4],
+            len: self.len(),
+        };
+        value.as_bytes_mut().copy_from_slice(self.as_slice());
+        value
     }
 }
 
@@ -321,13 +558,29 @@ impl<'a> PortWriteRequest<'a> {
         }
     }
 
-    pub fn as_u32(&self) -> u32 {
-        let arr = match self {
-            Self::OneByte(val) => [0, 0, 0, val[0]],
-            Self::TwoBytes(val) => [0, 0, val[0], val[1]],
-            Self::FourBytes(val) => *val.clone(),
+    /// The value written
+    pub fn value(&self) -> PortIoValue {
+        let mut value = PortIoValue {
+            bytes: [0; 4],
+            len: self.as_slice().len(),
         };
-        u32::from_be_bytes(arr)
+        value.as_bytes_mut().copy_from_slice(self.as_slice());
+        value
+    }
+
+    /// The low byte of the value written
+    pub fn as_u8(&self) -> u8 {
+        self.value().as_u8()
+    }
+
+    /// The low two bytes of the value written
+    pub fn as_u16(&self) -> u16 {
+        self.value().as_u16()
+    }
+
+    /// The value written, zero extended to 32 bits
+    pub fn as_u32(&self) -> u32 {
+        self.value().as_u32()
     }
 }
 
@@ -371,7 +624,7 @@ impl<'a> TryFrom<PortWriteRequest<'a>> for u16 {
 
     fn try_from(value: PortWriteRequest<'a>) -> Result<Self> {
         match value {
-            PortWriteRequest::TwoBytes(val) => Ok(u16::from_be_bytes(*val)),
+            PortWriteRequest::TwoBytes(val) => Ok(u16::from_le_bytes(*val)),
             val => Err(Error::InvalidValue(format!(
                 "Value {} cannot be converted to u16",
                 val
@@ -385,7 +638,7 @@ impl<'a> TryFrom<PortWriteRequest<'a>> for u32 {
 
     fn try_from(value: PortWriteRequest<'a>) -> Result<Self> {
         match value {
-            PortWriteRequest::FourBytes(val) => Ok(u32::from_be_bytes(*val)),
+            PortWriteRequest::FourBytes(val) => Ok(u32::from_le_bytes(*val)),
             val => Err(Error::InvalidValue(format!(
                 "Value {} cannot be converted to u32",
                 val
@@ -468,6 +721,10 @@ impl<'a> MemReadRequest<'a> {
     pub fn as_slice(&self) -> &[u8] {
         self.data
     }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        self.data
+    }
 }
 
 impl<'a> fmt::Display for MemReadRequest<'a> {
@@ -479,7 +736,7 @@ impl<'a> fmt::Display for MemReadRequest<'a> {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::virtdev::com::*;
+    use crate::virtdev::uart::*;
     use core::convert::TryInto;
 
     // This is just a dummy device so we can have arbitrary port ranges
@@ -508,13 +765,56 @@ mod test {
     #[test]
     fn test_device_map() {
         let mut map = DeviceMap::default();
-        let com = Uart8250::new(0);
+        let com = Uart16550::new(0, 4, SerialBackend::Discard);
         map.register_device(com).unwrap();
         let _dev = map.find_device(0u16).unwrap();
 
         assert_eq!(map.find_device(10u16).is_none(), true);
     }
 
+    #[derive(Default)]
+    struct WriteRecorder {
+        writes: Vec<(Port, u32)>,
+    }
+
+    impl EmulatedDevice for WriteRecorder {
+        fn services(&self) -> Vec<DeviceRegion> {
+            vec![DeviceRegion::PortIo(0x1f0..=0x1f0)]
+        }
+
+        fn on_event(&mut self, event: Event) -> Result<()> {
+            if let DeviceEvent::PortWrite(port, val) = event.kind {
+                self.writes.push((port, val.as_u32()));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_port_write_bulk_fallback() {
+        let mut space = crate::memory::GuestAddressSpace::new().unwrap();
+        let mut responses = ResponseEventArray::new();
+        let mut recorder = WriteRecorder::default();
+        let mut write = |data: &[u8], stride| {
+            let view = GuestAddressSpaceViewMut::new(
+                GuestPhysAddr::new(0),
+                &mut space,
+            );
+            recorder.on_port_write_bulk(
+                0x1f0,
+                data,
+                stride,
+                view,
+                &mut responses,
+            )
+        };
+        // Each element is little endian, as with a single OUT
+        write(&[0x34, 0x12, 0x78, 0x56], 2).unwrap();
+        assert!(write(&[1, 2, 3], 2).is_err());
+        assert!(write(&[1, 2, 3], 3).is_err());
+        assert_eq!(recorder.writes, vec![(0x1f0, 0x1234), (0x1f0, 0x5678)]);
+    }
+
     #[test]
     fn test_write_request_try_from() {
         let val: Result<PortWriteRequest> =
@@ -525,11 +825,17 @@ mod test {
         assert_eq!(val.is_err(), true);
 
         let val: PortWriteRequest =
-            [0x12, 0x34, 0x56, 0x78][..].try_into().unwrap();
+            [0x78, 0x56, 0x34, 0x12][..].try_into().unwrap();
         assert_eq!(val.as_u32(), 0x12345678);
+        assert_eq!(val.as_u16(), 0x5678);
+        assert_eq!(val.as_u8(), 0x78);
+        assert_eq!(u32::try_from(val).unwrap(), 0x12345678);
 
-        let val: PortWriteRequest = [0x12, 0x34][..].try_into().unwrap();
+        let val: PortWriteRequest = [0x34, 0x12][..].try_into().unwrap();
         assert_eq!(val.as_u32(), 0x1234);
+        assert_eq!(u16::try_from(val).unwrap(), 0x1234);
+        let val: PortWriteRequest = [0x34, 0x12][..].try_into().unwrap();
+        assert!(u8::try_from(val).is_err());
     }
 
     #[test]
@@ -537,16 +843,66 @@ mod test {
         let mut arr = [0x00, 0x00];
         let mut val = PortReadRequest::TwoBytes(&mut arr);
         val.copy_from_u32(0x1234u32);
-        assert_eq!([0x12, 0x34], val.as_slice());
-        assert_eq!(0x1234, u16::from_be_bytes(arr));
+        assert_eq!([0x34, 0x12], val.as_slice());
+        assert_eq!(val.value(), PortIoValue::from_u16(0x1234));
+        assert_eq!(0x1234, u16::from_le_bytes(arr));
+
+        // Only the low bytes of the value fit in a narrower read
+        let mut arr = [0x00];
+        let mut val = PortReadRequest::OneByte(&mut arr);
+        val.copy_from_u32(0x1234u32);
+        assert_eq!(arr, [0x34]);
+
+        let mut arr = [0x00; 4];
+        let mut val = PortReadRequest::FourBytes(&mut arr);
+        val.copy_from_u32(0x12345678);
+        assert_eq!(val.value().as_u32(), 0x12345678);
+        assert_eq!(arr, [0x78, 0x56, 0x34, 0x12]);
+    }
+
+    #[test]
+    fn test_portio_value_sizes() {
+        let byte = PortIoValue::from_u8(0xab);
+        assert_eq!(byte.size(), 1);
+        assert_eq!(byte.as_bytes(), [0xab]);
+        assert_eq!(
+            (byte.as_u8(), byte.as_u16(), byte.as_u32()),
+            (0xab, 0xab, 0xab)
+        );
+
+        let word = PortIoValue::from_u16(0xabcd);
+        assert_eq!(word.size(), 2);
+        assert_eq!(word.as_bytes(), [0xcd, 0xab]);
+        assert_eq!(
+            (word.as_u8(), word.as_u16(), word.as_u32()),
+            (0xcd, 0xabcd, 0xabcd)
+        );
+
+        let dword = PortIoValue::from_u32(0x1234abcd);
+        assert_eq!(dword.size(), 4);
+        assert_eq!(dword.as_bytes(), [0xcd, 0xab, 0x34, 0x12]);
+        assert_eq!(dword.as_u16(), 0xabcd);
+        assert_eq!(dword.as_u32(), 0x1234abcd);
+
+        // A value taken from a register keeps only the bytes of the access
+        assert_eq!(
+            PortIoValue::from_sized(0x1234abcd, 1).unwrap(),
+            PortIoValue::from_u8(0xcd)
+        );
+        assert_eq!(PortIoValue::from_sized(0x1234abcd, 2).unwrap(), word);
+        assert_eq!(PortIoValue::from_sized(0x1234abcd, 4).unwrap(), dword);
+        assert!(PortIoValue::from_sized(0, 3).is_err());
+
+        assert_eq!(PortIoValue::try_from(&[0xcd, 0xab][..]).unwrap(), word);
+        assert!(PortIoValue::try_from(&[0; 8][..]).is_err());
     }
 
     #[test]
     fn test_conflicting_portio_device() {
         let mut map = DeviceMap::default();
-        let com = Uart8250::new(0);
+        let com = Uart16550::new(0, 4, SerialBackend::Discard);
         map.register_device(com).unwrap();
-        let com = Uart8250::new(0);
+        let com = Uart16550::new(0, 4, SerialBackend::Discard);
 
         assert!(map.register_device(com).is_err());
     }
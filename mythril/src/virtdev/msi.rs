@@ -0,0 +1,595 @@
+//! Message signaled interrupts of emulated PCI functions
+//!
+//! `MsiCapability` and `MsixCapability` add the capability structures to
+//! the config space of a `PciDevice`, and track the state the guest driver
+//! programs in to them. When the device signals one of its vectors, the
+//! message is decoded as a write to the guest local APIC and delivered as
+//! an `Interrupt` response (so it is injected like any other interrupt).
+//! Vectors that are masked are left pending until the guest unmasks them.
+//!
+//! Devices forward config space writes (from `PciDevice::config_written`)
+//! and, for MSI-X, accesses to the table and PBA in their BAR.
+
+use crate::error::{Error, Result};
+use crate::vcpu::InjectedInterruptType;
+use crate::virtdev::pci::PciConfig;
+use crate::virtdev::{DeviceEventResponse, ResponseEventArray};
+use alloc::vec::Vec;
+
+/// The capability ID of MSI
+pub const PCI_CAP_ID_MSI: u8 = 0x05;
+
+/// The capability ID of MSI-X
+pub const PCI_CAP_ID_MSIX: u8 = 0x11;
+
+// The address range of MSIs targeting a local APIC
+const MSI_ADDRESS_MASK: u64 = 0xfff0_0000;
+const MSI_ADDRESS_BASE: u64 = 0xfee0_0000;
+const MSI_ADDRESS_REMAPPABLE: u64 = 1 << 4;
+
+// The delivery mode of an MSI (bits 8 to 10 of the data)
+const MSI_DELIVERY_FIXED: u32 = 0b000;
+const MSI_DELIVERY_LOWEST_PRIORITY: u32 = 0b001;
+const MSI_DELIVERY_NMI: u32 = 0b100;
+
+// The layout of an MSI capability with a 64-bit address and per-vector
+// masking (relative to the capability)
+const MSI_CONTROL: u16 = 0x02;
+const MSI_ADDRESS: u16 = 0x04;
+const MSI_DATA: u16 = 0x0c;
+const MSI_MASK: u16 = 0x10;
+const MSI_PENDING: u16 = 0x14;
+const MSI_CAPABILITY_SIZE: u16 = 0x18;
+
+const MSI_CONTROL_ENABLE: u16 = 1 << 0;
+const MSI_CONTROL_MULTIPLE_ENABLE_SHIFT: u16 = 4;
+const MSI_CONTROL_64BIT: u16 = 1 << 7;
+const MSI_CONTROL_PER_VECTOR_MASK: u16 = 1 << 8;
+
+/// The most vectors an MSI capability can have
+pub const MSI_MAX_VECTORS: usize = 32;
+
+// The layout of an MSI-X capability (relative to the capability)
+const MSIX_CONTROL: u16 = 0x02;
+const MSIX_TABLE: u16 = 0x04;
+const MSIX_PBA: u16 = 0x08;
+const MSIX_CAPABILITY_SIZE: u16 = 0x0c;
+
+const MSIX_CONTROL_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_CONTROL_ENABLE: u16 = 1 << 15;
+
+/// The most vectors an MSI-X table can have
+pub const MSIX_MAX_VECTORS: usize = 2048;
+
+/// The size of an entry of the MSI-X table
+pub const MSIX_ENTRY_SIZE: u64 = 16;
+const MSIX_ENTRY_VECTOR_CONTROL: usize = 12;
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+
+/// Decode the message `address` and `data` written by a device, returning
+/// the interrupt it raises in the guest (if it targets a local APIC)
+///
+/// All VCpus share the interrupts of emulated devices, so the destination
+/// is not used.
+pub fn decode_message(
+    address: u64,
+    data: u32,
+) -> Option<(u8, InjectedInterruptType)> {
+    if address & MSI_ADDRESS_MASK != MSI_ADDRESS_BASE {
+        warn!("Ignoring MSI to 0x{:x}", address);
+        return None;
+    }
+    if address & MSI_ADDRESS_REMAPPABLE != 0 {
+        warn!("Ignoring remappable format MSI (0x{:x})", address);
+        return None;
+    }
+    let vector = (data & 0xff) as u8;
+    match (data >> 8) & 0b111 {
+        MSI_DELIVERY_FIXED | MSI_DELIVERY_LOWEST_PRIORITY => {
+            Some((vector, InjectedInterruptType::ExternalInterrupt))
+        }
+        MSI_DELIVERY_NMI => {
+            Some((2, InjectedInterruptType::NonMaskableInterrupt))
+        }
+        mode => {
+            warn!("Ignoring MSI with delivery mode 0b{:b}", mode);
+            None
+        }
+    }
+}
+
+// Add the interrupt raised by a message to the responses, returning false
+// if there is no room (so the vector is left pending)
+fn send_message(
+    address: u64,
+    data: u32,
+    responses: &mut ResponseEventArray,
+) -> bool {
+    match decode_message(address, data) {
+        Some(interrupt) => responses
+            .try_push(DeviceEventResponse::Interrupt(interrupt))
+            .is_ok(),
+        None => true,
+    }
+}
+
+/// The MSI capability of an emulated PCI function
+///
+/// The capability has a 64-bit message address and per-vector masking.
+pub struct MsiCapability {
+    offset: u16,
+    vectors: usize,
+}
+
+impl MsiCapability {
+    /// Add an MSI capability with `vectors` vectors (a power of two up to
+    /// `MSI_MAX_VECTORS`) to `config`
+    pub fn new(config: &mut PciConfig, vectors: usize) -> Result<Self> {
+        if !vectors.is_power_of_two() || vectors > MSI_MAX_VECTORS {
+            return Err(Error::InvalidValue(format!(
+                "Invalid number of MSI vectors: {}",
+                vectors
+            )));
+        }
+        let control = (vectors.trailing_zeros() as u16) << 1
+            | MSI_CONTROL_64BIT
+            | MSI_CONTROL_PER_VECTOR_MASK;
+        let mut body = [0u8; MSI_CAPABILITY_SIZE as usize - 2];
+        body[0..2].copy_from_slice(&control.to_le_bytes());
+        let offset = config.add_capability(PCI_CAP_ID_MSI, &body)?;
+
+        // The guest enables MSI and the number of vectors it uses, and
+        // programs the message and masks of the vectors
+        let multiple_enable = 0b111 << MSI_CONTROL_MULTIPLE_ENABLE_SHIFT;
+        config.set_writable(
+            offset + MSI_CONTROL,
+            &(MSI_CONTROL_ENABLE | multiple_enable).to_le_bytes(),
+        );
+        config.set_writable(offset + MSI_ADDRESS, &[0xfc, 0xff, 0xff, 0xff]);
+        config.set_writable(offset + MSI_ADDRESS + 4, &[0xff; 4]);
+        config.set_writable(offset + MSI_DATA, &[0xff; 2]);
+        let mask = (((1u64 << vectors) - 1) as u32).to_le_bytes();
+        config.set_writable(offset + MSI_MASK, &mask);
+
+        Ok(Self { offset, vectors })
+    }
+
+    /// The offset of the capability in config space
+    pub fn offset(&self) -> u16 {
+        self.offset
+    }
+
+    /// Returns true if the guest has enabled MSI
+    pub fn is_enabled(&self, config: &PciConfig) -> bool {
+        config.read_u16(self.offset + MSI_CONTROL) & MSI_CONTROL_ENABLE != 0
+    }
+
+    // The number of vectors enabled by the guest
+    fn enabled_vectors(&self, config: &PciConfig) -> usize {
+        let control = config.read_u16(self.offset + MSI_CONTROL);
+        let count =
+            1 << ((control >> MSI_CONTROL_MULTIPLE_ENABLE_SHIFT) & 0b111);
+        core::cmp::min(count, self.vectors)
+    }
+
+    // The message sent for `vector`, which replaces the low bits of the
+    // data programmed by the guest
+    fn message(&self, config: &PciConfig, vector: usize) -> (u64, u32) {
+        let address = config.read_u32(self.offset + MSI_ADDRESS) as u64
+            | (config.read_u32(self.offset + MSI_ADDRESS + 4) as u64) << 32;
+        let count = self.enabled_vectors(config) as u32;
+        let data = config.read_u16(self.offset + MSI_DATA) as u32;
+        (address, (data & !(count - 1)) | vector as u32)
+    }
+
+    /// Signal `vector` of the function
+    ///
+    /// Returns false if MSI is not enabled (so the device should use its
+    /// INTx pin instead).
+    pub fn notify(
+        &self,
+        config: &mut PciConfig,
+        vector: usize,
+        responses: &mut ResponseEventArray,
+    ) -> bool {
+        if !self.is_enabled(config) {
+            return false;
+        }
+        if vector >= self.enabled_vectors(config) {
+            return true;
+        }
+        let bit = 1 << vector;
+        if config.read_u32(self.offset + MSI_MASK) & bit == 0 {
+            let (address, data) = self.message(config, vector);
+            if send_message(address, data, responses) {
+                return true;
+            }
+        }
+        let pending = config.read_u32(self.offset + MSI_PENDING);
+        config.set_u32(self.offset + MSI_PENDING, pending | bit);
+        true
+    }
+
+    /// Handle a guest write to config space, sending the messages of
+    /// pending vectors that are now unmasked
+    pub fn config_written(
+        &self,
+        config: &mut PciConfig,
+        offset: u16,
+        len: usize,
+        responses: &mut ResponseEventArray,
+    ) {
+        let end = offset as usize + len;
+        if end <= self.offset as usize
+            || offset >= self.offset + MSI_CAPABILITY_SIZE
+            || !self.is_enabled(config)
+        {
+            return;
+        }
+        let mut pending = config.read_u32(self.offset + MSI_PENDING);
+        let mask = config.read_u32(self.offset + MSI_MASK);
+        for vector in 0..self.enabled_vectors(config) {
+            let bit = 1 << vector;
+            if pending & bit == 0 || mask & bit != 0 {
+                continue;
+            }
+            let (address, data) = self.message(config, vector);
+            if !send_message(address, data, responses) {
+                break;
+            }
+            pending &= !bit;
+        }
+        config.set_u32(self.offset + MSI_PENDING, pending);
+    }
+}
+
+/// The MSI-X capability of an emulated PCI function, and its table and
+/// pending bit array (PBA)
+///
+/// The table and PBA live in a memory BAR of the function, so the device
+/// forwards the accesses to them with `read_mmio` and `write_mmio`.
+pub struct MsixCapability {
+    offset: u16,
+    table_offset: u64,
+    pba_offset: u64,
+    table: Vec<[u8; MSIX_ENTRY_SIZE as usize]>,
+    pending: Vec<u64>,
+}
+
+impl MsixCapability {
+    /// Add an MSI-X capability with `vectors` vectors to `config`. The
+    /// table and PBA are at `table_offset` and `pba_offset` (8 byte
+    /// aligned) in the BAR at index `bar`.
+    pub fn new(
+        config: &mut PciConfig,
+        vectors: usize,
+        bar: u8,
+        table_offset: u32,
+        pba_offset: u32,
+    ) -> Result<Self> {
+        if vectors == 0
+            || vectors > MSIX_MAX_VECTORS
+            || bar > 5
+            || table_offset & 0b111 != 0
+            || pba_offset & 0b111 != 0
+        {
+            return Err(Error::InvalidValue(format!(
+                "Invalid MSI-X table: {} vectors in BAR {} at 0x{:x}",
+                vectors, bar, table_offset
+            )));
+        }
+        let mut body = [0u8; MSIX_CAPABILITY_SIZE as usize - 2];
+        body[0..2].copy_from_slice(&((vectors - 1) as u16).to_le_bytes());
+        body[2..6].copy_from_slice(&(table_offset | bar as u32).to_le_bytes());
+        body[6..10].copy_from_slice(&(pba_offset | bar as u32).to_le_bytes());
+        let offset = config.add_capability(PCI_CAP_ID_MSIX, &body)?;
+        config.set_writable(
+            offset + MSIX_CONTROL,
+            &(MSIX_CONTROL_ENABLE | MSIX_CONTROL_FUNCTION_MASK).to_le_bytes(),
+        );
+
+        // All vectors are masked on reset
+        let mut entry = [0u8; MSIX_ENTRY_SIZE as usize];
+        entry[MSIX_ENTRY_VECTOR_CONTROL] = MSIX_ENTRY_MASKED as u8;
+        Ok(Self {
+            offset,
+            table_offset: table_offset as u64,
+            pba_offset: pba_offset as u64,
+            table: vec![entry; vectors],
+            pending: vec![0; (vectors + 63) / 64],
+        })
+    }
+
+    /// The offset of the capability in config space
+    pub fn offset(&self) -> u16 {
+        self.offset
+    }
+
+    fn control(&self, config: &PciConfig) -> u16 {
+        config.read_u16(self.offset + MSIX_CONTROL)
+    }
+
+    /// Returns true if the guest has enabled MSI-X
+    pub fn is_enabled(&self, config: &PciConfig) -> bool {
+        self.control(config) & MSIX_CONTROL_ENABLE != 0
+    }
+
+    fn is_masked(&self, config: &PciConfig, vector: usize) -> bool {
+        let entry = &self.table[vector];
+        self.control(config) & MSIX_CONTROL_FUNCTION_MASK != 0
+            || entry[MSIX_ENTRY_VECTOR_CONTROL] as u32 & MSIX_ENTRY_MASKED != 0
+    }
+
+    fn is_pending(&self, vector: usize) -> bool {
+        self.pending[vector / 64] & (1 << (vector % 64)) != 0
+    }
+
+    fn set_pending(&mut self, vector: usize, pending: bool) {
+        let bit = 1 << (vector % 64);
+        if pending {
+            self.pending[vector / 64] |= bit;
+        } else {
+            self.pending[vector / 64] &= !bit;
+        }
+    }
+
+    fn message(&self, vector: usize) -> (u64, u32) {
+        let entry = &self.table[vector];
+        let mut address = [0u8; 8];
+        let mut data = [0u8; 4];
+        address.copy_from_slice(&entry[0..8]);
+        data.copy_from_slice(&entry[8..12]);
+        (u64::from_le_bytes(address), u32::from_le_bytes(data))
+    }
+
+    /// Signal `vector` of the function
+    ///
+    /// Returns false if MSI-X is not enabled (so the device should use MSI
+    /// or its INTx pin instead).
+    pub fn notify(
+        &mut self,
+        config: &PciConfig,
+        vector: usize,
+        responses: &mut ResponseEventArray,
+    ) -> bool {
+        if !self.is_enabled(config) {
+            return false;
+        }
+        if vector >= self.table.len() {
+            return true;
+        }
+        if !self.is_masked(config, vector) {
+            let (address, data) = self.message(vector);
+            if send_message(address, data, responses) {
+                return true;
+            }
+        }
+        self.set_pending(vector, true);
+        true
+    }
+
+    // Send the messages of pending vectors that are no longer masked
+    fn deliver_pending(
+        &mut self,
+        config: &PciConfig,
+        responses: &mut ResponseEventArray,
+    ) {
+        if !self.is_enabled(config) {
+            return;
+        }
+        for vector in 0..self.table.len() {
+            if !self.is_pending(vector) || self.is_masked(config, vector) {
+                continue;
+            }
+            let (address, data) = self.message(vector);
+            if !send_message(address, data, responses) {
+                break;
+            }
+            self.set_pending(vector, false);
+        }
+    }
+
+    /// Handle a guest write to config space (which may clear the function
+    /// mask)
+    pub fn config_written(
+        &mut self,
+        config: &PciConfig,
+        offset: u16,
+        len: usize,
+        responses: &mut ResponseEventArray,
+    ) {
+        let control = self.offset + MSIX_CONTROL;
+        if (offset as usize + len) > control as usize && offset < control + 2 {
+            self.deliver_pending(config, responses);
+        }
+    }
+
+    fn table_size(&self) -> u64 {
+        self.table.len() as u64 * MSIX_ENTRY_SIZE
+    }
+
+    fn pba_size(&self) -> u64 {
+        self.pending.len() as u64 * 8
+    }
+
+    /// Read the table or PBA at `offset` in the BAR, returning false if the
+    /// access is not to either of them
+    pub fn read_mmio(&self, offset: u64, data: &mut [u8]) -> bool {
+        let len = data.len() as u64;
+        if offset >= self.table_offset
+            && offset + len <= self.table_offset + self.table_size()
+        {
+            let start = offset - self.table_offset;
+            for (i, byte) in data.iter_mut().enumerate() {
+                let pos = start + i as u64;
+                let entry = &self.table[(pos / MSIX_ENTRY_SIZE) as usize];
+                *byte = entry[(pos % MSIX_ENTRY_SIZE) as usize];
+            }
+            true
+        } else if offset >= self.pba_offset
+            && offset + len <= self.pba_offset + self.pba_size()
+        {
+            let start = offset - self.pba_offset;
+            for (i, byte) in data.iter_mut().enumerate() {
+                let pos = start + i as u64;
+                let bits = self.pending[(pos / 8) as usize];
+                *byte = (bits >> ((pos % 8) * 8)) as u8;
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Write the table at `offset` in the BAR, returning false if the
+    /// access is not to the table or PBA (which is read-only)
+    ///
+    /// Unmasking a pending vector sends its message.
+    pub fn write_mmio(
+        &mut self,
+        config: &PciConfig,
+        offset: u64,
+        data: &[u8],
+        responses: &mut ResponseEventArray,
+    ) -> bool {
+        let len = data.len() as u64;
+        if offset >= self.pba_offset
+            && offset + len <= self.pba_offset + self.pba_size()
+        {
+            return true;
+        }
+        if offset < self.table_offset
+            || offset + len > self.table_offset + self.table_size()
+        {
+            return false;
+        }
+        let start = offset - self.table_offset;
+        for (i, byte) in data.iter().enumerate() {
+            let pos = start + i as u64;
+            let entry = &mut self.table[(pos / MSIX_ENTRY_SIZE) as usize];
+            entry[(pos % MSIX_ENTRY_SIZE) as usize] = *byte;
+        }
+        self.deliver_pending(config, responses);
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::virtdev::pci::PciFunctionId;
+
+    fn test_config() -> PciConfig {
+        PciConfig::new(PciFunctionId {
+            vendor_id: 0x1af4,
+            device_id: 0x1041,
+            class: 0x02,
+            subclass: 0x00,
+        })
+    }
+
+    fn interrupts(responses: &ResponseEventArray) -> Vec<u8> {
+        responses
+            .iter()
+            .filter_map(|response| match response {
+                DeviceEventResponse::Interrupt((vector, _)) => Some(*vector),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_decode_message() {
+        assert!(matches!(
+            decode_message(0xfee0_0000, 0x0031),
+            Some((0x31, InjectedInterruptType::ExternalInterrupt))
+        ));
+        assert!(matches!(
+            decode_message(0xfee0_1000, 0x0400),
+            Some((2, InjectedInterruptType::NonMaskableInterrupt))
+        ));
+        assert!(decode_message(0xfee0_0010, 0x0031).is_none());
+        assert!(decode_message(0xfec0_0000, 0x0031).is_none());
+        assert!(decode_message(0xfee0_0000, 0x0731).is_none());
+    }
+
+    #[test]
+    fn test_msi() {
+        let mut config = test_config();
+        let msi = MsiCapability::new(&mut config, 4).unwrap();
+        assert!(MsiCapability::new(&mut config, 3).is_err());
+        let offset = msi.offset();
+        let mut responses = ResponseEventArray::default();
+        assert!(!msi.notify(&mut config, 0, &mut responses));
+
+        // Enable 2 vectors, with vector 1 masked
+        config.write(offset + MSI_ADDRESS, &0xfee0_0000u32.to_le_bytes());
+        config.write(offset + MSI_DATA, &0x0040u16.to_le_bytes());
+        config.write(offset + MSI_MASK, &[0b10, 0, 0, 0]);
+        config.write(offset + MSI_CONTROL, &[0x11, 0]);
+        assert_eq!(config.read_u16(offset + MSI_CONTROL), 0x0195);
+
+        assert!(msi.notify(&mut config, 0, &mut responses));
+        assert!(msi.notify(&mut config, 1, &mut responses));
+        assert!(msi.notify(&mut config, 2, &mut responses));
+        assert_eq!(interrupts(&responses), vec![0x40]);
+        assert_eq!(config.read_u32(offset + MSI_PENDING), 0b10);
+
+        // The pending bits are read-only to the guest
+        config.write(offset + MSI_PENDING, &[0; 4]);
+        assert_eq!(config.read_u32(offset + MSI_PENDING), 0b10);
+
+        let mut responses = ResponseEventArray::default();
+        config.write(offset + MSI_MASK, &[0; 4]);
+        msi.config_written(&mut config, offset + MSI_MASK, 4, &mut responses);
+        assert_eq!(interrupts(&responses), vec![0x41]);
+        assert_eq!(config.read_u32(offset + MSI_PENDING), 0);
+    }
+
+    #[test]
+    fn test_msix() {
+        let mut config = test_config();
+        let mut msix =
+            MsixCapability::new(&mut config, 3, 1, 0x2000, 0x3000).unwrap();
+        let offset = msix.offset();
+        assert_eq!(config.read_u16(offset + MSIX_CONTROL), 0x0002);
+        assert_eq!(config.read_u32(offset + MSIX_TABLE), 0x2001);
+        assert_eq!(config.read_u32(offset + MSIX_PBA), 0x3001);
+
+        let mut responses = ResponseEventArray::default();
+        assert!(!msix.notify(&config, 0, &mut responses));
+
+        // Program vector 2, and enable MSI-X with the function masked
+        for (field, value) in [(0u64, 0xfee0_0000u32), (4, 0), (8, 0x51)].iter()
+        {
+            assert!(msix.write_mmio(
+                &config,
+                0x2020 + field,
+                &value.to_le_bytes(),
+                &mut responses,
+            ));
+        }
+        config.write(offset + MSIX_CONTROL, &[0, 0xc0]);
+        assert!(msix.notify(&config, 2, &mut responses));
+        assert!(responses.is_empty());
+
+        let mut pba = [0u8; 8];
+        assert!(msix.read_mmio(0x3000, &mut pba));
+        assert_eq!(pba, [0b100, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(!msix.read_mmio(0x1000, &mut pba));
+
+        // Clearing the function mask leaves the vector masked
+        config.write(offset + MSIX_CONTROL, &[0, 0x80]);
+        msix.config_written(&config, offset + MSIX_CONTROL, 2, &mut responses);
+        assert!(responses.is_empty());
+
+        assert!(msix.write_mmio(&config, 0x202c, &[0; 4], &mut responses));
+        assert_eq!(interrupts(&responses), vec![0x51]);
+        assert!(msix.read_mmio(0x3000, &mut pba));
+        assert_eq!(pba[0], 0);
+
+        let mut entry = [0u8; 4];
+        assert!(msix.read_mmio(0x2028, &mut entry));
+        assert_eq!(u32::from_le_bytes(entry), 0x51);
+    }
+}
@@ -0,0 +1,380 @@
+use crate::error::{Error, Result};
+use crate::memory::{GuestAddressSpace, GuestPhysAddr, HostPhysFrame};
+
+/// The start of the legacy BIOS area controlled by the PAM registers
+pub const PAM_REGION_START: u64 = 0xc0000;
+
+/// The (inclusive) end of the legacy BIOS area
+pub const PAM_REGION_END: u64 = 0xfffff;
+
+/// The number of PAM registers in the host bridge config space
+pub const PAM_REGISTER_COUNT: usize = 7;
+
+// The legacy region is a copy of the top of the firmware flash (which sits
+// just below 4GB)
+const FLASH_ALIAS_OFFSET: u64 = 0xfff00000;
+
+const REGION_PAGES: usize =
+    ((PAM_REGION_END + 1 - PAM_REGION_START) as usize) / HostPhysFrame::SIZE;
+
+/// The access mode for a segment of the legacy BIOS area
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PamMode {
+    /// All accesses are forwarded to the ROM (i.e., DRAM is disabled)
+    Disabled,
+
+    /// Reads are serviced by DRAM, writes are forwarded to the ROM
+    ReadOnly,
+
+    /// Reads are forwarded to the ROM, writes are serviced by DRAM
+    WriteOnly,
+
+    /// All accesses are serviced by DRAM
+    ReadWrite,
+}
+
+impl From<u8> for PamMode {
+    fn from(bits: u8) -> Self {
+        match bits & 0b11 {
+            0b00 => PamMode::Disabled,
+            0b01 => PamMode::ReadOnly,
+            0b10 => PamMode::WriteOnly,
+            _ => PamMode::ReadWrite,
+        }
+    }
+}
+
+/// A region of the legacy BIOS area controlled by one nibble of one PAM
+/// register
+#[derive(Copy, Clone, Debug)]
+struct PamSegment {
+    start: u64,
+    size: u64,
+    register: usize,
+    shift: u8,
+}
+
+impl PamSegment {
+    const fn new(start: u64, size: u64, register: usize, shift: u8) -> Self {
+        Self {
+            start: start,
+            size: size,
+            register: register,
+            shift: shift,
+        }
+    }
+
+    fn contains(&self, addr: u64) -> bool {
+        addr >= self.start && addr < self.start + self.size
+    }
+}
+
+// PAM0 only controls 0xF0000-0xFFFFF (in its high nibble). The other
+// registers each control two 16KB segments.
+const PAM_SEGMENTS: [PamSegment; 13] = [
+    PamSegment::new(0xf0000, 0x10000, 0, 4),
+    PamSegment::new(0xc0000, 0x4000, 1, 0),
+    PamSegment::new(0xc4000, 0x4000, 1, 4),
+    PamSegment::new(0xc8000, 0x4000, 2, 0),
+    PamSegment::new(0xcc000, 0x4000, 2, 4),
+    PamSegment::new(0xd0000, 0x4000, 3, 0),
+    PamSegment::new(0xd4000, 0x4000, 3, 4),
+    PamSegment::new(0xd8000, 0x4000, 4, 0),
+    PamSegment::new(0xdc000, 0x4000, 4, 4),
+    PamSegment::new(0xe0000, 0x4000, 5, 0),
+    PamSegment::new(0xe4000, 0x4000, 5, 4),
+    PamSegment::new(0xe8000, 0x4000, 6, 0),
+    PamSegment::new(0xec000, 0x4000, 6, 4),
+];
+
+/// The Programmable Attribute Map registers of the host bridge
+///
+/// These control whether accesses to 0xC0000-0xFFFFF are serviced by DRAM
+/// (the 'shadow' of the firmware) or by the ROM. The modes are applied to
+/// the guest EPT mappings where possible, and the remaining accesses (e.g.,
+/// writes to a read-only segment) arrive as memory events.
+///
+/// The region starts out as writable DRAM that holds a copy of the
+/// firmware, so every segment is initially in `PamMode::ReadWrite`.
+pub struct PamRegisters {
+    registers: [u8; PAM_REGISTER_COUNT],
+    dram_frames: Option<[Option<HostPhysFrame>; REGION_PAGES]>,
+}
+
+// The register values matching the initial (read/write DRAM) mapping
+const PAM_INITIAL_REGISTERS: [u8; PAM_REGISTER_COUNT] =
+    [0x30, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33];
+
+impl PamRegisters {
+    pub fn new() -> Self {
+        Self {
+            registers: PAM_INITIAL_REGISTERS,
+            dram_frames: None,
+        }
+    }
+
+    /// Read the value of PAM register `index`
+    pub fn read(&self, index: usize) -> u8 {
+        self.registers[index]
+    }
+
+    /// The current mode of the segment containing `addr`
+    pub fn mode(&self, addr: u64) -> Option<PamMode> {
+        PAM_SEGMENTS
+            .iter()
+            .find(|segment| segment.contains(addr))
+            .map(|segment| {
+                PamMode::from(self.registers[segment.register] >> segment.shift)
+            })
+    }
+
+    /// Write PAM register `index` and update the guest mappings
+    ///
+    /// Returns true if the EPT mappings were modified (in which case cached
+    /// translations must be invalidated).
+    pub fn write(
+        &mut self,
+        index: usize,
+        val: u8,
+        space: &mut GuestAddressSpace,
+    ) -> Result<bool> {
+        if index >= PAM_REGISTER_COUNT {
+            return Err(Error::InvalidValue(format!(
+                "Invalid PAM register: {}",
+                index
+            )));
+        }
+
+        // Capture the DRAM backing the region before any of it is remapped
+        if self.dram_frames.is_none() {
+            let mut frames = [None; REGION_PAGES];
+            for (i, frame) in frames.iter_mut().enumerate() {
+                let addr = PAM_REGION_START + (i * HostPhysFrame::SIZE) as u64;
+                *frame = space.find_host_frame(GuestPhysAddr::new(addr)).ok();
+            }
+            self.dram_frames = Some(frames);
+        }
+
+        let old = self.registers[index];
+        self.registers[index] = val;
+
+        let mut changed = false;
+        for segment in PAM_SEGMENTS.iter().filter(|seg| seg.register == index) {
+            let old_mode = PamMode::from(old >> segment.shift);
+            let new_mode = PamMode::from(val >> segment.shift);
+            if old_mode != new_mode {
+                self.apply_segment(segment, new_mode, space)?;
+                changed = true;
+            }
+        }
+        Ok(changed)
+    }
+
+    fn dram_frame(&self, addr: u64) -> Option<HostPhysFrame> {
+        self.dram_frames.as_ref().and_then(|frames| {
+            frames[(addr - PAM_REGION_START) as usize / HostPhysFrame::SIZE]
+        })
+    }
+
+    fn rom_frame(
+        space: &GuestAddressSpace,
+        addr: u64,
+    ) -> Option<HostPhysFrame> {
+        space
+            .find_host_frame(GuestPhysAddr::new(addr + FLASH_ALIAS_OFFSET))
+            .ok()
+    }
+
+    fn apply_segment(
+        &self,
+        segment: &PamSegment,
+        mode: PamMode,
+        space: &mut GuestAddressSpace,
+    ) -> Result<()> {
+        let pages = segment.size as usize / HostPhysFrame::SIZE;
+        for page in 0..pages {
+            let addr = segment.start + (page * HostPhysFrame::SIZE) as u64;
+            let guest_addr = GuestPhysAddr::new(addr);
+            let frame = match mode {
+                PamMode::ReadWrite | PamMode::ReadOnly => self.dram_frame(addr),
+                PamMode::Disabled => Self::rom_frame(space, addr),
+
+                // EPT cannot express write-only memory, so the whole
+                // segment is emulated
+                PamMode::WriteOnly => None,
+            };
+
+            match frame {
+                Some(frame) => space.remap_frame(
+                    guest_addr,
+                    frame,
+                    mode != PamMode::ReadWrite,
+                )?,
+                None => {
+                    space.unmap_frame(guest_addr)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle a guest read from an address in the legacy region that is not
+    /// directly mapped
+    pub fn on_mem_read(
+        &self,
+        mut addr: u64,
+        data: &mut [u8],
+        space: &GuestAddressSpace,
+    ) -> Result<()> {
+        // An access may cross into a page of another segment
+        let mut done = 0;
+        while done < data.len() {
+            let offset = addr as usize % HostPhysFrame::SIZE;
+            let len = (HostPhysFrame::SIZE - offset).min(data.len() - done);
+            self.read_page(addr, &mut data[done..done + len], space);
+            done += len;
+            addr += len as u64;
+        }
+        Ok(())
+    }
+
+    // Read `data` from within the page at `addr`
+    fn read_page(&self, addr: u64, data: &mut [u8], space: &GuestAddressSpace) {
+        let frame = match self.mode(addr) {
+            Some(PamMode::ReadOnly) | Some(PamMode::ReadWrite) => {
+                self.dram_frame(addr)
+            }
+            _ => Self::rom_frame(space, addr),
+        };
+
+        match frame {
+            Some(frame) => {
+                let offset = addr as usize % HostPhysFrame::SIZE;
+                let array = unsafe { frame.as_array() };
+                data.copy_from_slice(&array[offset..offset + data.len()]);
+            }
+
+            // Nothing decodes this address, so the read floats high
+            None => {
+                for byte in data.iter_mut() {
+                    *byte = 0xff;
+                }
+            }
+        }
+    }
+
+    /// Handle a guest write to an address in the legacy region that is not
+    /// directly mapped
+    pub fn on_mem_write(&self, mut addr: u64, data: &[u8]) -> Result<()> {
+        let mut done = 0;
+        while done < data.len() {
+            let offset = addr as usize % HostPhysFrame::SIZE;
+            let len = (HostPhysFrame::SIZE - offset).min(data.len() - done);
+            self.write_page(addr, &data[done..done + len]);
+            done += len;
+            addr += len as u64;
+        }
+        Ok(())
+    }
+
+    // Write `data` within the page at `addr`
+    fn write_page(&self, addr: u64, data: &[u8]) {
+        match self.mode(addr) {
+            Some(PamMode::WriteOnly) | Some(PamMode::ReadWrite) => {
+                if let Some(mut frame) = self.dram_frame(addr) {
+                    let offset = addr as usize % HostPhysFrame::SIZE;
+                    let array = unsafe { frame.as_mut_array() };
+                    array[offset..offset + data.len()].copy_from_slice(data);
+                }
+            }
+
+            // Writes to the ROM are dropped
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pam_segment_modes() {
+        let mut space = GuestAddressSpace::new().unwrap();
+        for i in 0..REGION_PAGES {
+            let addr = PAM_REGION_START + (i * HostPhysFrame::SIZE) as u64;
+            space
+                .map_new_frame(GuestPhysAddr::new(addr), false)
+                .unwrap();
+        }
+
+        // The registers match the initial (read/write DRAM) mapping
+        let mut pam = PamRegisters::new();
+        assert_eq!(pam.mode(0xf0000), Some(PamMode::ReadWrite));
+        assert_eq!(pam.mode(0xc0000), Some(PamMode::ReadWrite));
+        assert_eq!(pam.mode(0xbffff), None);
+        assert!(!pam.write(0, 0x30, &mut space).unwrap());
+
+        assert!(pam.write(0, 0x00, &mut space).unwrap());
+        assert_eq!(pam.mode(0xfffff), Some(PamMode::Disabled));
+
+        assert!(pam.write(5, 0x21, &mut space).unwrap());
+        assert_eq!(pam.mode(0xe0000), Some(PamMode::ReadOnly));
+        assert_eq!(pam.mode(0xe4000), Some(PamMode::WriteOnly));
+
+        // Rewriting the same value does not change any mappings
+        assert!(!pam.write(5, 0x21, &mut space).unwrap());
+    }
+
+    #[test]
+    fn test_pam_write_only_segment() {
+        let mut space = GuestAddressSpace::new().unwrap();
+        for i in 0..REGION_PAGES {
+            let addr = PAM_REGION_START + (i * HostPhysFrame::SIZE) as u64;
+            space
+                .map_new_frame(GuestPhysAddr::new(addr), false)
+                .unwrap();
+        }
+
+        let mut pam = PamRegisters::new();
+        pam.write(6, 0x02, &mut space).unwrap();
+
+        // The segment is no longer mapped, so accesses must be emulated
+        assert!(space.find_host_frame(GuestPhysAddr::new(0xe8000)).is_err());
+        pam.on_mem_write(0xe8010, &[0xaa, 0xbb]).unwrap();
+
+        // With no ROM behind the segment, reads float high
+        let mut buf = [0u8; 2];
+        pam.on_mem_read(0xe8010, &mut buf, &space).unwrap();
+        assert_eq!(buf, [0xff, 0xff]);
+
+        // Once DRAM is readable again, the written data is visible
+        pam.write(6, 0x03, &mut space).unwrap();
+        pam.on_mem_read(0xe8010, &mut buf, &space).unwrap();
+        assert_eq!(buf, [0xaa, 0xbb]);
+    }
+
+    #[test]
+    fn test_pam_access_across_pages() {
+        let mut space = GuestAddressSpace::new().unwrap();
+        for i in 0..REGION_PAGES {
+            let addr = PAM_REGION_START + (i * HostPhysFrame::SIZE) as u64;
+            space
+                .map_new_frame(GuestPhysAddr::new(addr), false)
+                .unwrap();
+        }
+
+        // 0xc3000-0xc3fff is in a write-only segment, 0xc4000 onwards in a
+        // disabled one (with no ROM behind it)
+        let mut pam = PamRegisters::new();
+        pam.write(1, 0x02, &mut space).unwrap();
+
+        let data = [0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88];
+        pam.on_mem_write(0xc3ffd, &data).unwrap();
+        pam.write(1, 0x03, &mut space).unwrap();
+
+        let mut buf = [0u8; 8];
+        pam.on_mem_read(0xc3ffd, &mut buf, &space).unwrap();
+        assert_eq!(buf, [0x11, 0x22, 0x33, 0xff, 0xff, 0xff, 0xff, 0xff]);
+    }
+}
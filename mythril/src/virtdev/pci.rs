@@ -1,8 +1,19 @@
 use crate::error::{Error, Result};
-use crate::virtdev::{DeviceEvent, DeviceRegion, EmulatedDevice, Event, Port};
+use crate::memory::{
+    GuestAddressSpace, GuestAddressSpaceViewMut, GuestPhysAddr, HostPhysAddr,
+    HostPhysFrame,
+};
+use crate::virtdev::pam::{
+    PamRegisters, PAM_REGION_END, PAM_REGION_START, PAM_REGISTER_COUNT,
+};
+use crate::virtdev::{
+    DeviceEvent, DeviceEventResponse, DeviceRegion, EmulatedDevice, Event,
+    Port, PortWriteRequest, ResponseEventArray,
+};
 use alloc::collections::btree_map::BTreeMap;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use bitflags::bitflags;
 use core::convert::TryInto;
 use num_enum::TryFromPrimitive;
 use spin::RwLock;
@@ -22,6 +33,128 @@ enum DeviceId {
     // differences). We use the correct name P35.
     P35Mch = 0x29c0,
     Ich9 = 0x2918,
+    Ich9PcieRootPort = 0x2940,
+}
+
+// Offsets and values in the common config space header
+const PCI_STATUS: u8 = 0x06;
+const PCI_STATUS_CAP_LIST: u16 = 1 << 4;
+const PCI_SUBCLASS: u8 = 0x0a;
+const PCI_CLASS: u8 = 0x0b;
+const PCI_HEADER_TYPE: u8 = 0x0e;
+const PCI_HEADER_TYPE_BRIDGE: u8 = 0x01;
+const PCI_HEADER_TYPE_MULTIFUNCTION: u8 = 0x80;
+const PCI_CAPABILITY_LIST: u8 = 0x34;
+const PCI_INTERRUPT_LINE: u8 = 0x3c;
+const PCI_INTERRUPT_PIN: u8 = 0x3d;
+const PCI_INTERRUPT_LINE_UNKNOWN: u8 = 0xff;
+
+const PCI_CLASS_BRIDGE: u8 = 0x06;
+const PCI_SUBCLASS_PCI_BRIDGE: u8 = 0x04;
+
+// The type 1 (bridge) header
+const PCI_SECONDARY_BUS: u8 = 0x19;
+
+// The PCI Express capability of the root ports
+const PCIE_CAP_OFFSET: u8 = 0x40;
+const PCIE_CAP_ID: u8 = 0x10;
+const PCIE_CAP_FLAGS: u8 = PCIE_CAP_OFFSET + 0x02;
+const PCIE_LINK_CAP: u8 = PCIE_CAP_OFFSET + 0x0c;
+const PCIE_LINK_STATUS: u8 = PCIE_CAP_OFFSET + 0x12;
+const PCIE_SLOT_CAP: u8 = PCIE_CAP_OFFSET + 0x14;
+const PCIE_SLOT_CONTROL: u8 = PCIE_CAP_OFFSET + 0x18;
+const PCIE_SLOT_CONTROL_HIGH: u8 = PCIE_SLOT_CONTROL + 1;
+const PCIE_SLOT_STATUS: u8 = PCIE_CAP_OFFSET + 0x1a;
+const PCIE_SLOT_STATUS_HIGH: u8 = PCIE_SLOT_STATUS + 1;
+
+// Capability version 2, a root port with an implemented slot
+const PCIE_CAP_FLAGS_ROOT_PORT: u16 = 0x2 | (0x4 << 4) | (1 << 8);
+const PCIE_LINK_CAP_DLL_ACTIVE_REPORTING: u32 = 1 << 20;
+const PCIE_LINK_STATUS_DLL_ACTIVE: u16 = 1 << 13;
+
+bitflags! {
+    struct SlotCapabilities: u32 {
+        const ATTENTION_BUTTON = 1 << 0;
+        const POWER_CONTROLLER = 1 << 1;
+        const MRL_SENSOR = 1 << 2;
+        const ATTENTION_INDICATOR = 1 << 3;
+        const POWER_INDICATOR = 1 << 4;
+        const HOTPLUG_SURPRISE = 1 << 5;
+        const HOTPLUG_CAPABLE = 1 << 6;
+        const NO_COMMAND_COMPLETED = 1 << 18;
+    }
+}
+
+bitflags! {
+    struct SlotControl: u16 {
+        const ATTENTION_BUTTON_ENABLE = 1 << 0;
+        const POWER_FAULT_ENABLE = 1 << 1;
+        const MRL_SENSOR_CHANGED_ENABLE = 1 << 2;
+        const PRESENCE_DETECT_CHANGED_ENABLE = 1 << 3;
+        const COMMAND_COMPLETED_ENABLE = 1 << 4;
+        const HOTPLUG_INTERRUPT_ENABLE = 1 << 5;
+        const POWER_CONTROLLER_OFF = 1 << 10;
+        const DLL_STATE_CHANGED_ENABLE = 1 << 12;
+    }
+}
+
+bitflags! {
+    struct SlotStatus: u16 {
+        const ATTENTION_BUTTON_PRESSED = 1 << 0;
+        const POWER_FAULT_DETECTED = 1 << 1;
+        const MRL_SENSOR_CHANGED = 1 << 2;
+        const PRESENCE_DETECT_CHANGED = 1 << 3;
+        const COMMAND_COMPLETED = 1 << 4;
+        const MRL_SENSOR_STATE = 1 << 5;
+        const PRESENCE_DETECT_STATE = 1 << 6;
+        const DLL_STATE_CHANGED = 1 << 8;
+    }
+}
+
+impl SlotStatus {
+    // The bits that are cleared by writing a 1
+    fn write_1_to_clear() -> Self {
+        Self::ATTENTION_BUTTON_PRESSED
+            | Self::POWER_FAULT_DETECTED
+            | Self::MRL_SENSOR_CHANGED
+            | Self::PRESENCE_DETECT_CHANGED
+            | Self::COMMAND_COMPLETED
+            | Self::DLL_STATE_CHANGED
+    }
+
+    // The events that are enabled by the given slot control
+    fn enabled_events(control: SlotControl) -> Self {
+        let mut events = Self::empty();
+        let pairs = [
+            (
+                SlotControl::ATTENTION_BUTTON_ENABLE,
+                Self::ATTENTION_BUTTON_PRESSED,
+            ),
+            (SlotControl::POWER_FAULT_ENABLE, Self::POWER_FAULT_DETECTED),
+            (
+                SlotControl::MRL_SENSOR_CHANGED_ENABLE,
+                Self::MRL_SENSOR_CHANGED,
+            ),
+            (
+                SlotControl::PRESENCE_DETECT_CHANGED_ENABLE,
+                Self::PRESENCE_DETECT_CHANGED,
+            ),
+            (
+                SlotControl::COMMAND_COMPLETED_ENABLE,
+                Self::COMMAND_COMPLETED,
+            ),
+            (
+                SlotControl::DLL_STATE_CHANGED_ENABLE,
+                Self::DLL_STATE_CHANGED,
+            ),
+        ];
+        for (enable, event) in pairs.iter() {
+            if control.contains(*enable) {
+                events.insert(*event);
+            }
+        }
+        events
+    }
 }
 
 #[repr(C)]
@@ -80,6 +213,12 @@ struct PciToPciBridgeSpace {
     _data: [u32; 64],
 }
 
+impl PciToPciBridgeSpace {
+    fn new() -> Self {
+        Self { _data: [0u32; 64] }
+    }
+}
+
 #[repr(C)]
 #[repr(packed)]
 struct PciToCardbusBridgeSpace {
@@ -108,9 +247,315 @@ impl PciConfigSpace {
         }
     }
 
+    fn as_registers_mut(&mut self) -> &mut [u32; 64] {
+        match self {
+            PciConfigSpace::Type0(space) => unsafe {
+                core::mem::transmute(space)
+            },
+            PciConfigSpace::Type1(space) => unsafe {
+                core::mem::transmute(space)
+            },
+            PciConfigSpace::Type2(space) => unsafe {
+                core::mem::transmute(space)
+            },
+        }
+    }
+
     fn read_register(&self, register: u8) -> u32 {
         self.as_registers()[register as usize]
     }
+
+    fn read_byte(&self, offset: u8) -> u8 {
+        (self.read_register(offset >> 2) >> ((offset & 0b11) * 8)) as u8
+    }
+
+    fn read_word(&self, offset: u8) -> u16 {
+        self.read_byte(offset) as u16 | (self.read_byte(offset + 1) as u16) << 8
+    }
+
+    fn write_byte(&mut self, offset: u8, val: u8) {
+        let register = &mut self.as_registers_mut()[(offset >> 2) as usize];
+        let shift = (offset & 0b11) * 8;
+        *register = (*register & !(0xff << shift)) | ((val as u32) << shift);
+    }
+
+    fn write_word(&mut self, offset: u8, val: u16) {
+        for (i, byte) in val.to_le_bytes().iter().enumerate() {
+            self.write_byte(offset + i as u8, *byte);
+        }
+    }
+
+    fn write_dword(&mut self, offset: u8, val: u32) {
+        for (i, byte) in val.to_le_bytes().iter().enumerate() {
+            self.write_byte(offset + i as u8, *byte);
+        }
+    }
+}
+
+/// The size of the config space of a function, excluding the extended
+/// config space of PCI Express
+pub const PCI_CONFIG_SIZE: usize = 256;
+
+/// The size of the memory-mapped (ECAM) config space of all 256 buses
+pub const PCI_ECAM_SIZE: u64 = 256 << 20;
+
+/// The guest physical address of the ECAM region (as with QEMU's q35)
+pub const PCI_ECAM_BASE: u64 = 0xb000_0000;
+
+// The registers of a type 0 header that are writable by default
+const PCI_COMMAND: u16 = 0x04;
+const PCI_COMMAND_WRITABLE: u16 = 0x0407;
+const PCI_COMMAND_MEMORY: u16 = 1 << 1;
+const PCI_BAR_0: u16 = 0x10;
+const PCI_BAR_COUNT: usize = 6;
+
+// The first capability follows the type 0 header
+const PCI_FIRST_CAPABILITY: u16 = 0x40;
+
+/// The kind and size (in bytes, a power of two) of a base address register
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PciBar {
+    /// A range of IO ports
+    Io(u32),
+
+    /// A range of memory below 4GB
+    Memory32(u32),
+
+    /// A range of memory anywhere in the address space, which uses two
+    /// consecutive BARs
+    Memory64(u64),
+}
+
+impl PciBar {
+    fn size(&self) -> u64 {
+        match *self {
+            PciBar::Io(size) | PciBar::Memory32(size) => size as u64,
+            PciBar::Memory64(size) => size,
+        }
+    }
+
+    // The low bits of the BAR that describe it
+    fn flags(&self) -> u32 {
+        match self {
+            PciBar::Io(_) => 0b01,
+            PciBar::Memory32(_) => 0b0000,
+            PciBar::Memory64(_) => 0b0100,
+        }
+    }
+
+    fn flags_mask(&self) -> u64 {
+        match self {
+            PciBar::Io(_) => 0b11,
+            _ => 0b1111,
+        }
+    }
+}
+
+/// The config space of an emulated PCI function (see `PciDevice`)
+///
+/// Registers are read-only to the guest unless they are made writable,
+/// except for the command register, the interrupt line and the BARs. BARs
+/// are writable down to their size, so the guest can size and place them
+/// in the usual way.
+pub struct PciConfig {
+    bytes: [u8; PCI_CONFIG_SIZE],
+    write_mask: [u8; PCI_CONFIG_SIZE],
+    bars: [Option<PciBar>; PCI_BAR_COUNT],
+    next_capability: u16,
+    last_capability: Option<u16>,
+}
+
+impl PciConfig {
+    /// The config space of a function with the given identity and a type 0
+    /// header
+    pub fn new(id: PciFunctionId) -> Self {
+        let mut config = Self {
+            bytes: [0; PCI_CONFIG_SIZE],
+            write_mask: [0; PCI_CONFIG_SIZE],
+            bars: [None; PCI_BAR_COUNT],
+            next_capability: PCI_FIRST_CAPABILITY,
+            last_capability: None,
+        };
+        config.set_u16(0x00, id.vendor_id);
+        config.set_u16(0x02, id.device_id);
+        config.bytes[PCI_SUBCLASS as usize] = id.subclass;
+        config.bytes[PCI_CLASS as usize] = id.class;
+        config.set_writable(PCI_COMMAND, &PCI_COMMAND_WRITABLE.to_le_bytes());
+        config.set_writable(PCI_INTERRUPT_LINE as u16, &[0xff]);
+        config
+    }
+
+    /// Set the register at `offset`, regardless of whether it is writable
+    /// by the guest (e.g., to update a status register)
+    pub fn set_u16(&mut self, offset: u16, val: u16) {
+        self.set_bytes(offset, &val.to_le_bytes());
+    }
+
+    /// Set the register at `offset`, regardless of whether it is writable
+    /// by the guest
+    pub fn set_u32(&mut self, offset: u16, val: u32) {
+        self.set_bytes(offset, &val.to_le_bytes());
+    }
+
+    fn set_bytes(&mut self, offset: u16, data: &[u8]) {
+        let offset = offset as usize;
+        self.bytes[offset..offset + data.len()].copy_from_slice(data);
+    }
+
+    /// Allow the guest to write the bits of `mask` in the registers at
+    /// `offset`
+    pub fn set_writable(&mut self, offset: u16, mask: &[u8]) {
+        let offset = offset as usize;
+        self.write_mask[offset..offset + mask.len()].copy_from_slice(mask);
+    }
+
+    /// Set the interrupt pin used by the function (1 for INTA, or 0 if it
+    /// does not use one)
+    pub fn set_interrupt_pin(&mut self, pin: u8) {
+        self.bytes[PCI_INTERRUPT_PIN as usize] = pin;
+    }
+
+    /// The interrupt line assigned by the guest (as set by its firmware)
+    pub fn interrupt_line(&self) -> u8 {
+        self.bytes[PCI_INTERRUPT_LINE as usize]
+    }
+
+    /// Preset the interrupt line, for guests that use it as found
+    pub fn set_interrupt_line(&mut self, line: u8) {
+        self.bytes[PCI_INTERRUPT_LINE as usize] = line;
+    }
+
+    /// The value of the command register
+    pub fn command(&self) -> u16 {
+        self.read_u16(PCI_COMMAND)
+    }
+
+    /// Give the function a BAR at `index`
+    pub fn set_bar(&mut self, index: usize, bar: PciBar) -> Result<()> {
+        let size = bar.size();
+        let slots = match bar {
+            PciBar::Memory64(_) => 2,
+            _ => 1,
+        };
+        if index + slots > PCI_BAR_COUNT
+            || !size.is_power_of_two()
+            || size <= bar.flags_mask()
+        {
+            return Err(Error::InvalidValue(format!(
+                "Invalid PCI BAR {}: {:?}",
+                index, bar
+            )));
+        }
+
+        // The address bits below the size (and the flags) are read-only
+        let mask = !(size - 1) & !bar.flags_mask();
+        let offset = PCI_BAR_0 + index as u16 * 4;
+        self.set_u32(offset, bar.flags());
+        self.set_writable(offset, &(mask as u32).to_le_bytes());
+        if slots == 2 {
+            self.set_u32(offset + 4, 0);
+            self.set_writable(offset + 4, &((mask >> 32) as u32).to_le_bytes());
+        }
+        self.bars[index] = Some(bar);
+        Ok(())
+    }
+
+    /// The address the guest assigned to the BAR at `index` (if there is
+    /// one)
+    pub fn bar_address(&self, index: usize) -> Option<u64> {
+        let bar = (*self.bars.get(index)?)?;
+        let offset = PCI_BAR_0 + index as u16 * 4;
+        let mut addr = self.read_u32(offset) as u64 & !bar.flags_mask();
+        if let PciBar::Memory64(_) = bar {
+            addr |= (self.read_u32(offset + 4) as u64) << 32;
+        }
+        Some(addr)
+    }
+
+    /// Append a capability with the given ID and `body` (following the ID
+    /// and next pointer) to the capability list, returning its offset
+    pub fn add_capability(&mut self, id: u8, body: &[u8]) -> Result<u16> {
+        let offset = self.next_capability;
+        let end = offset as usize + 2 + body.len();
+        if end > PCI_CONFIG_SIZE {
+            return Err(Error::InvalidValue(format!(
+                "No room for PCI capability 0x{:x}",
+                id
+            )));
+        }
+        self.bytes[offset as usize] = id;
+        self.bytes[offset as usize + 1] = 0;
+        self.set_bytes(offset + 2, body);
+
+        match self.last_capability {
+            Some(last) => self.bytes[last as usize + 1] = offset as u8,
+            None => {
+                self.bytes[PCI_CAPABILITY_LIST as usize] = offset as u8;
+                let status = self.read_u16(PCI_STATUS as u16);
+                self.set_u16(PCI_STATUS as u16, status | PCI_STATUS_CAP_LIST);
+            }
+        }
+        self.last_capability = Some(offset);
+        // Capabilities are dword aligned
+        self.next_capability = ((end + 3) & !3) as u16;
+        Ok(offset)
+    }
+
+    pub fn read_u16(&self, offset: u16) -> u16 {
+        let mut bytes = [0u8; 2];
+        self.read(offset, &mut bytes);
+        u16::from_le_bytes(bytes)
+    }
+
+    pub fn read_u32(&self, offset: u16) -> u32 {
+        let mut bytes = [0u8; 4];
+        self.read(offset, &mut bytes);
+        u32::from_le_bytes(bytes)
+    }
+
+    /// Read the config space at `offset`. The extended config space (past
+    /// `PCI_CONFIG_SIZE`) reads as zero.
+    pub fn read(&self, offset: u16, data: &mut [u8]) {
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = self.bytes.get(offset as usize + i).copied().unwrap_or(0);
+        }
+    }
+
+    /// Write `data` to the config space at `offset`, as the guest would
+    /// (so only the writable bits change)
+    pub fn write(&mut self, offset: u16, data: &[u8]) {
+        for (i, byte) in data.iter().enumerate() {
+            let offset = offset as usize + i;
+            if offset >= PCI_CONFIG_SIZE {
+                break;
+            }
+            let mask = self.write_mask[offset];
+            self.bytes[offset] = (self.bytes[offset] & !mask) | (byte & mask);
+        }
+    }
+}
+
+/// An emulated PCI function, attached to the root complex with
+/// `PciRootComplex::add_device`
+///
+/// Devices that decode their BARs also register an `EmulatedDevice` for
+/// the regions (so both are usually implemented by the same type). Message
+/// signaled interrupts are provided by the capabilities in `virtdev::msi`.
+pub trait PciDevice: Send + Sync {
+    fn config(&self) -> &PciConfig;
+
+    fn config_mut(&mut self) -> &mut PciConfig;
+
+    /// Called after the guest wrote `len` bytes of config space at `offset`
+    /// (e.g., to move a BAR or to enable decoding)
+    fn config_written(
+        &mut self,
+        _offset: u16,
+        _len: usize,
+        _responses: &mut ResponseEventArray,
+    ) -> Result<()> {
+        Ok(())
+    }
 }
 
 #[derive(PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
@@ -138,27 +583,182 @@ impl Into<u16> for PciBdf {
     }
 }
 
-pub struct PciDevice {
+// A function of the chipset, with a fixed layout of config space
+struct PciFunction {
     config_space: PciConfigSpace,
     bdf: PciBdf,
 }
 
+/// The identity of a PCI function that can be hot-added to a guest
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PciFunctionId {
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+}
+
+/// A host function passed through to the guest in a hotplug slot
+///
+/// The DMA of the function is confined to the VM by the IOMMU, and its
+/// memory BAR is mapped wherever the guest places BAR 0 of the function in
+/// the slot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PcieHostFunction {
+    /// The requester id (bus, device and function) of the host function,
+    /// in PCI segment 0
+    pub source_id: u16,
+
+    /// The host physical address of the memory BAR of the function
+    pub bar: u64,
+
+    /// The size of the memory BAR (a power of two of at least a page)
+    pub bar_size: u32,
+}
+
+/// A request to change the contents of a PCI Express hotplug slot
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PcieHotplugEvent {
+    /// Insert the given function into the (physical) slot number, passing
+    /// through `host` if the function is backed by a host function
+    Insert {
+        slot: u8,
+        function: PciFunctionId,
+        host: Option<PcieHostFunction>,
+    },
+
+    /// Ask the guest to release the function in the given slot
+    ///
+    /// The function is only removed once the guest powers off the slot.
+    Remove { slot: u8 },
+}
+
+/// The hotplug slot below a PCI Express root port
+struct PcieSlot {
+    number: u8,
+    device: Option<PciConfig>,
+    host: Option<PcieHostFunction>,
+    // The guest address the BAR of the host function is mapped at
+    mapped: Option<u64>,
+    removal_pending: bool,
+}
+
+impl PcieSlot {
+    // Map the BAR of the host function where the guest placed BAR 0 (if
+    // memory decoding is enabled), returning true if the mapping changed
+    fn update_mapping(
+        &mut self,
+        space: &mut GuestAddressSpace,
+    ) -> Result<bool> {
+        let host = match self.host {
+            Some(host) => host,
+            None => return Ok(false),
+        };
+        let target = self
+            .device
+            .as_ref()
+            .filter(|config| config.command() & PCI_COMMAND_MEMORY != 0)
+            .and_then(|config| config.bar_address(0))
+            .filter(|addr| *addr != 0);
+        if target == self.mapped {
+            return Ok(false);
+        }
+
+        if let Some(addr) = self.mapped.take() {
+            Self::unmap_bar(space, addr, host.bar_size)?;
+        }
+        if let Some(addr) = target {
+            match Self::map_bar(space, addr, &host) {
+                Ok(()) => self.mapped = Some(addr),
+                Err(e) => warn!(
+                    "pci: unable to map host function 0x{:x} at 0x{:x}: {:?}",
+                    host.source_id, addr, e
+                ),
+            }
+        }
+        Ok(true)
+    }
+
+    fn map_bar(
+        space: &mut GuestAddressSpace,
+        addr: u64,
+        host: &PcieHostFunction,
+    ) -> Result<()> {
+        let size = host.bar_size as u64;
+        for offset in (0..size).step_by(HostPhysFrame::SIZE) {
+            let frame = HostPhysFrame::from_start_address(HostPhysAddr::new(
+                host.bar + offset,
+            ))?;
+            if let Err(e) =
+                space.map_frame(GuestPhysAddr::new(addr + offset), frame, false)
+            {
+                // Leave nothing of a partial mapping (e.g., one that
+                // overlaps guest memory)
+                Self::unmap_bar(space, addr, offset as u32)?;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn unmap_bar(
+        space: &mut GuestAddressSpace,
+        addr: u64,
+        size: u32,
+    ) -> Result<()> {
+        for offset in (0..size as u64).step_by(HostPhysFrame::SIZE) {
+            space.unmap_frame(GuestPhysAddr::new(addr + offset))?;
+        }
+        Ok(())
+    }
+}
+
 pub struct PciRootComplex {
     current_address: u32,
-    devices: BTreeMap<u16, PciDevice>,
+    reset_control: u8,
+    pam: PamRegisters,
+    devices: BTreeMap<u16, PciFunction>,
+    emulated: BTreeMap<u16, Arc<RwLock<dyn PciDevice>>>,
+
+    // The hotplug slots, keyed by the BDF of their root port
+    slots: BTreeMap<u16, PcieSlot>,
+
+    // The base of the memory-mapped config space (if enabled)
+    ecam: Option<u64>,
 }
 
 impl PciRootComplex {
-    const PCI_CONFIG_ADDRESS: Port = 0xcf8;
+    pub const PCI_CONFIG_ADDRESS: Port = 0xcf8;
+    const PCI_RESET_CONTROL: Port = 0xcf9;
     const PCI_CONFIG_TYPE: Port = 0xcfb;
     const PCI_CONFIG_DATA: Port = 0xcfc;
     const PCI_CONFIG_DATA_MAX: Port = Self::PCI_CONFIG_DATA + 3;
 
+    const HOST_BRIDGE_BDF: u16 = 0x0000;
+
+    // The offset of PAM0 in the host bridge config space
+    const HOST_BRIDGE_PAM_OFFSET: u8 = 0x90;
+
+    // The root ports are the functions of device 0x1c (as with the ICH9)
+    const ROOT_PORT_DEVICE: u16 = 0x1c;
+    const ROOT_PORT_COUNT: u8 = 4;
+
     pub fn new() -> Arc<RwLock<Self>> {
+        Self::build(None)
+    }
+
+    /// A root complex whose config space is also memory-mapped (ECAM) at
+    /// `base`, which allows access to the extended config space
+    pub fn with_ecam(base: u64) -> Arc<RwLock<Self>> {
+        Self::build(Some(base))
+    }
+
+    fn build(ecam: Option<u64>) -> Arc<RwLock<Self>> {
         let mut devices = BTreeMap::new();
 
-        let host_bridge = PciDevice {
-            bdf: PciBdf::from(0x0000),
+        let pam = PamRegisters::new();
+        let mut host_bridge = PciFunction {
+            bdf: PciBdf::from(Self::HOST_BRIDGE_BDF),
             config_space: PciConfigSpace::Type0(PciNonBridgeSpace::new(
                 PciNonBridgeHeader {
                     vendor_id: VendorId::Intel as u16,
@@ -167,9 +767,15 @@ impl PciRootComplex {
                 },
             )),
         };
+        for index in 0..PAM_REGISTER_COUNT {
+            host_bridge.config_space.write_byte(
+                Self::HOST_BRIDGE_PAM_OFFSET + index as u8,
+                pam.read(index),
+            );
+        }
         devices.insert(host_bridge.bdf.into(), host_bridge);
 
-        let ich9 = PciDevice {
+        let ich9 = PciFunction {
             bdf: PciBdf::from(0b1000),
             config_space: PciConfigSpace::Type0(PciNonBridgeSpace::new(
                 PciNonBridgeHeader {
@@ -181,16 +787,466 @@ impl PciRootComplex {
         };
         devices.insert(ich9.bdf.into(), ich9);
 
+        let mut slots = BTreeMap::new();
+        for function in 0..Self::ROOT_PORT_COUNT {
+            let root_port = Self::new_root_port(function);
+            let bdf: u16 = root_port.bdf.into();
+            devices.insert(bdf, root_port);
+            slots.insert(
+                bdf,
+                PcieSlot {
+                    number: function + 1,
+                    device: None,
+                    host: None,
+                    mapped: None,
+                    removal_pending: false,
+                },
+            );
+        }
+
         Arc::new(RwLock::new(Self {
             current_address: 0,
+            reset_control: 0,
+            pam: pam,
             devices: devices,
+            emulated: BTreeMap::new(),
+            slots: slots,
+            ecam: ecam,
         }))
     }
+
+    /// Attach an emulated function at `bdf` on bus 0
+    pub fn add_device(
+        &mut self,
+        bdf: u16,
+        device: Arc<RwLock<dyn PciDevice>>,
+    ) -> Result<()> {
+        if bdf >> 8 != 0
+            || self.devices.contains_key(&bdf)
+            || self.emulated.contains_key(&bdf)
+        {
+            return Err(Error::InvalidValue(format!(
+                "PCI function 0x{:x} is not available",
+                bdf
+            )));
+        }
+        self.emulated.insert(bdf, device);
+        Ok(())
+    }
+
+    fn new_root_port(function: u8) -> PciFunction {
+        let mut space = PciConfigSpace::Type1(PciToPciBridgeSpace::new());
+        space.write_word(0x00, VendorId::Intel as u16);
+        space.write_word(0x02, DeviceId::Ich9PcieRootPort as u16);
+        space.write_word(PCI_STATUS, PCI_STATUS_CAP_LIST);
+        space.write_byte(PCI_SUBCLASS, PCI_SUBCLASS_PCI_BRIDGE);
+        space.write_byte(PCI_CLASS, PCI_CLASS_BRIDGE);
+        space.write_byte(
+            PCI_HEADER_TYPE,
+            PCI_HEADER_TYPE_BRIDGE | PCI_HEADER_TYPE_MULTIFUNCTION,
+        );
+        space.write_byte(PCI_CAPABILITY_LIST, PCIE_CAP_OFFSET);
+
+        // The hotplug interrupt is delivered on INTA
+        space.write_byte(PCI_INTERRUPT_PIN, 1);
+
+        space.write_byte(PCIE_CAP_OFFSET, PCIE_CAP_ID);
+        space.write_word(PCIE_CAP_FLAGS, PCIE_CAP_FLAGS_ROOT_PORT);
+        space.write_dword(PCIE_LINK_CAP, PCIE_LINK_CAP_DLL_ACTIVE_REPORTING);
+
+        let slot_number = (function + 1) as u32;
+        let slot_caps = SlotCapabilities::ATTENTION_BUTTON
+            | SlotCapabilities::POWER_CONTROLLER
+            | SlotCapabilities::ATTENTION_INDICATOR
+            | SlotCapabilities::POWER_INDICATOR
+            | SlotCapabilities::HOTPLUG_CAPABLE
+            | SlotCapabilities::NO_COMMAND_COMPLETED;
+        space.write_dword(PCIE_SLOT_CAP, slot_caps.bits() | slot_number << 19);
+
+        PciFunction {
+            bdf: PciBdf::from(Self::ROOT_PORT_DEVICE << 3 | function as u16),
+            config_space: space,
+        }
+    }
+
+    // The root port whose slot holds the function at `bdf` (each slot
+    // holds a single function, at device 0 of the secondary bus)
+    fn find_slot(&self, bdf: u16) -> Option<u16> {
+        let bus = (bdf >> 8) as u8;
+        if bus == 0 || bdf & 0xff != 0 {
+            return None;
+        }
+        self.slots.keys().copied().find(|port_bdf| {
+            self.devices.get(port_bdf).map_or(false, |port| {
+                port.config_space.read_byte(PCI_SECONDARY_BUS) == bus
+            })
+        })
+    }
+
+    // The function and config space offset of an access of `len` bytes to
+    // `addr`, if it is in the ECAM region
+    fn ecam_target(
+        &self,
+        addr: GuestPhysAddr,
+        len: usize,
+    ) -> Result<Option<(u16, u16)>> {
+        let offset = match self.ecam {
+            Some(base)
+                if (base..base + PCI_ECAM_SIZE).contains(&addr.as_u64()) =>
+            {
+                addr.as_u64() - base
+            }
+            _ => return Ok(None),
+        };
+        if (offset & 0b11) as usize + len > 4 {
+            return Err(Error::InvalidValue(format!(
+                "Invalid PCI ECAM access of {} bytes at 0x{:x}",
+                len,
+                addr.as_u64()
+            )));
+        }
+        Ok(Some(((offset >> 12) as u16, (offset & 0xfff) as u16)))
+    }
+
+    // Read the config space register (dword) at `offset` of `bdf`
+    fn read_config(&self, bdf: u16, offset: u16) -> u32 {
+        let offset = offset & !0b11;
+        if let Some(device) = self.emulated.get(&bdf) {
+            return device.read().config().read_u32(offset);
+        }
+        if let Some(port_bdf) = self.find_slot(bdf) {
+            return match self.slots[&port_bdf].device.as_ref() {
+                Some(config) => config.read_u32(offset),
+                None => 0xffffffff,
+            };
+        }
+        match self.devices.get(&bdf) {
+            Some(device) if (offset as usize) < PCI_CONFIG_SIZE => {
+                device.config_space.read_register((offset >> 2) as u8)
+            }
+            Some(_) => 0,
+            // If no device is present, just return all 0xFFs
+            None => 0xffffffff,
+        }
+    }
+
+    /// Apply a hotplug request to one of the root port slots
+    pub fn hotplug(
+        &mut self,
+        event: PcieHotplugEvent,
+        responses: &mut ResponseEventArray,
+    ) -> Result<()> {
+        let number = match event {
+            PcieHotplugEvent::Insert { slot, .. } => slot,
+            PcieHotplugEvent::Remove { slot } => slot,
+        };
+        let port_bdf = self
+            .slots
+            .iter()
+            .find(|(_, slot)| slot.number == number)
+            .map(|(bdf, _)| *bdf)
+            .ok_or_else(|| {
+                Error::InvalidValue(format!("Invalid PCIe slot: {}", number))
+            })?;
+
+        let slot = self.slots.get_mut(&port_bdf).unwrap();
+        let status_change = match event {
+            PcieHotplugEvent::Insert { function, host, .. } => {
+                if slot.device.is_some() {
+                    return Err(Error::InvalidValue(format!(
+                        "PCIe slot {} is already occupied",
+                        number
+                    )));
+                }
+                let mut config = PciConfig::new(function);
+                if let Some(host) = host {
+                    if host.bar % HostPhysFrame::SIZE as u64 != 0
+                        || (host.bar_size as usize) < HostPhysFrame::SIZE
+                    {
+                        return Err(Error::InvalidValue(format!(
+                            "Invalid BAR for host function 0x{:x}",
+                            host.source_id
+                        )));
+                    }
+                    config.set_bar(0, PciBar::Memory32(host.bar_size))?;
+                }
+                slot.device = Some(config);
+                slot.host = host;
+                Self::set_link_state(&mut self.devices, port_bdf, true)
+            }
+            PcieHotplugEvent::Remove { .. } => {
+                if slot.device.is_none() {
+                    return Err(Error::InvalidValue(format!(
+                        "PCIe slot {} is empty",
+                        number
+                    )));
+                }
+                slot.removal_pending = true;
+                SlotStatus::ATTENTION_BUTTON_PRESSED
+            }
+        };
+
+        self.raise_slot_event(port_bdf, status_change, responses);
+        Ok(())
+    }
+
+    // Update the presence and link state of a root port, returning the
+    // status bits that changed
+    fn set_link_state(
+        devices: &mut BTreeMap<u16, PciFunction>,
+        port_bdf: u16,
+        present: bool,
+    ) -> SlotStatus {
+        let space = match devices.get_mut(&port_bdf) {
+            Some(port) => &mut port.config_space,
+            None => return SlotStatus::empty(),
+        };
+
+        let mut status =
+            SlotStatus::from_bits_truncate(space.read_word(PCIE_SLOT_STATUS));
+        let mut link = space.read_word(PCIE_LINK_STATUS);
+        status.set(SlotStatus::PRESENCE_DETECT_STATE, present);
+        if present {
+            link |= PCIE_LINK_STATUS_DLL_ACTIVE;
+        } else {
+            link &= !PCIE_LINK_STATUS_DLL_ACTIVE;
+        }
+        space.write_word(PCIE_SLOT_STATUS, status.bits());
+        space.write_word(PCIE_LINK_STATUS, link);
+
+        SlotStatus::PRESENCE_DETECT_CHANGED | SlotStatus::DLL_STATE_CHANGED
+    }
+
+    // Latch new slot status events and raise the hotplug interrupt if any
+    // newly set event is enabled by the guest
+    fn raise_slot_event(
+        &mut self,
+        port_bdf: u16,
+        events: SlotStatus,
+        responses: &mut ResponseEventArray,
+    ) {
+        let space = match self.devices.get_mut(&port_bdf) {
+            Some(port) => &mut port.config_space,
+            None => return,
+        };
+
+        let status =
+            SlotStatus::from_bits_truncate(space.read_word(PCIE_SLOT_STATUS));
+        let control =
+            SlotControl::from_bits_truncate(space.read_word(PCIE_SLOT_CONTROL));
+        space.write_word(PCIE_SLOT_STATUS, (status | events).bits());
+
+        let new_events = events - status;
+        if !control.contains(SlotControl::HOTPLUG_INTERRUPT_ENABLE)
+            || !new_events.intersects(SlotStatus::enabled_events(control))
+        {
+            return;
+        }
+
+        // INTA of the root port is wired to the GSI in its interrupt line
+        let line = space.read_byte(PCI_INTERRUPT_LINE);
+        if line != PCI_INTERRUPT_LINE_UNKNOWN {
+            responses.push(DeviceEventResponse::Gsi(line as u32));
+        } else {
+            warn!(
+                "pci: dropping hotplug interrupt for unrouted root port 0x{:x}",
+                port_bdf
+            );
+        }
+    }
+
+    fn write_root_port(
+        &mut self,
+        port_bdf: u16,
+        offset: u8,
+        val: u8,
+        guest_space: &mut GuestAddressSpace,
+        responses: &mut ResponseEventArray,
+    ) -> Result<()> {
+        let space = match self.devices.get_mut(&port_bdf) {
+            Some(port) => &mut port.config_space,
+            None => return Ok(()),
+        };
+
+        match offset {
+            // The command, bus number, window, interrupt line and bridge
+            // control registers are simply stored
+            0x04..=0x05 | 0x18..=0x1a | 0x1c..=0x1d | 0x20..=0x33 => {
+                space.write_byte(offset, val)
+            }
+            PCI_INTERRUPT_LINE | 0x3e..=0x3f => space.write_byte(offset, val),
+            PCIE_SLOT_CONTROL..=PCIE_SLOT_CONTROL_HIGH => {
+                space.write_byte(offset, val);
+
+                let control = SlotControl::from_bits_truncate(
+                    space.read_word(PCIE_SLOT_CONTROL),
+                );
+                let slot = self.slots.get_mut(&port_bdf).unwrap();
+                if slot.removal_pending
+                    && control.contains(SlotControl::POWER_CONTROLLER_OFF)
+                {
+                    // The guest has released the function, so it can be
+                    // safely torn down
+                    if let Some(addr) = slot.mapped.take() {
+                        let size = slot.host.map_or(0, |host| host.bar_size);
+                        PcieSlot::unmap_bar(guest_space, addr, size)?;
+                        responses
+                            .push(DeviceEventResponse::GuestMappingsChanged);
+                    }
+                    if let Some(host) = slot.host.take() {
+                        responses.push(
+                            DeviceEventResponse::HostFunctionReleased(
+                                host.source_id,
+                            ),
+                        );
+                    }
+                    slot.device = None;
+                    slot.removal_pending = false;
+                    let events = Self::set_link_state(
+                        &mut self.devices,
+                        port_bdf,
+                        false,
+                    );
+                    self.raise_slot_event(port_bdf, events, responses);
+                }
+            }
+            PCIE_SLOT_STATUS..=PCIE_SLOT_STATUS_HIGH => {
+                let shift = (offset - PCIE_SLOT_STATUS) * 8;
+                let clear = ((val as u16) << shift)
+                    & SlotStatus::write_1_to_clear().bits();
+                let status = space.read_word(PCIE_SLOT_STATUS);
+                space.write_word(PCIE_SLOT_STATUS, status & !clear);
+            }
+            _ => {
+                debug!(
+                    "pci: Ignoring write to root port offset=0x{:x} (val=0x{:x})",
+                    offset, val
+                );
+            }
+        }
+        Ok(())
+    }
+
+    // Write the config space of the function in the slot of `port_bdf`,
+    // moving the mapping of its host function with BAR 0
+    fn write_slot_function(
+        &mut self,
+        port_bdf: u16,
+        offset: u16,
+        bytes: &[u8],
+        guest_space: &mut GuestAddressSpace,
+        responses: &mut ResponseEventArray,
+    ) -> Result<()> {
+        let slot = self.slots.get_mut(&port_bdf).unwrap();
+        match slot.device.as_mut() {
+            Some(config) => config.write(offset, bytes),
+            None => return Ok(()),
+        }
+        if slot.update_mapping(guest_space)? {
+            responses.push(DeviceEventResponse::GuestMappingsChanged);
+        }
+        Ok(())
+    }
+
+    fn on_config_write(
+        &mut self,
+        port: Port,
+        val: PortWriteRequest,
+        space: &mut GuestAddressSpaceViewMut,
+        responses: &mut ResponseEventArray,
+    ) -> Result<()> {
+        let bdf = ((self.current_address & 0xffff00) >> 8) as u16;
+        let offset = (self.current_address & 0xfc) as u16
+            + (port - Self::PCI_CONFIG_DATA);
+
+        // Config space is little endian, like the value, so its bytes are
+        // written in order
+        self.write_config(bdf, offset, val.as_slice(), space, responses)
+    }
+
+    fn write_config(
+        &mut self,
+        bdf: u16,
+        offset: u16,
+        bytes: &[u8],
+        space: &mut GuestAddressSpaceViewMut,
+        responses: &mut ResponseEventArray,
+    ) -> Result<()> {
+        if let Some(device) = self.emulated.get(&bdf) {
+            let mut device = device.write();
+            device.config_mut().write(offset, bytes);
+            return device.config_written(offset, bytes.len(), responses);
+        }
+
+        if let Some(port_bdf) = self.find_slot(bdf) {
+            return self.write_slot_function(
+                port_bdf,
+                offset,
+                bytes,
+                space.space_mut(),
+                responses,
+            );
+        }
+
+        // Only the emulated functions have an extended config space
+        if offset as usize >= PCI_CONFIG_SIZE {
+            return Ok(());
+        }
+        let offset = offset as u8;
+
+        if self.slots.contains_key(&bdf) {
+            for (i, byte) in bytes.iter().enumerate() {
+                self.write_root_port(
+                    bdf,
+                    offset + i as u8,
+                    *byte,
+                    space.space_mut(),
+                    responses,
+                )?;
+            }
+            return Ok(());
+        } else if bdf != Self::HOST_BRIDGE_BDF {
+            debug!(
+                "pci: Attempt to write to bdf=0x{:x} offset=0x{:x}. Ignoring.",
+                bdf, offset
+            );
+            return Ok(());
+        }
+
+        let mut mappings_changed = false;
+        for (i, byte) in bytes.iter().enumerate() {
+            let offset = offset + i as u8;
+            let pam_range = Self::HOST_BRIDGE_PAM_OFFSET
+                ..Self::HOST_BRIDGE_PAM_OFFSET + PAM_REGISTER_COUNT as u8;
+            if !pam_range.contains(&offset) {
+                debug!(
+                    "pci: Ignoring write to host bridge offset=0x{:x} (val=0x{:x})",
+                    offset, byte
+                );
+                continue;
+            }
+
+            let index = (offset - Self::HOST_BRIDGE_PAM_OFFSET) as usize;
+            mappings_changed |=
+                self.pam.write(index, *byte, space.space_mut())?;
+
+            if let Some(host_bridge) = self.devices.get_mut(&bdf) {
+                host_bridge
+                    .config_space
+                    .write_byte(offset, self.pam.read(index));
+            }
+        }
+
+        if mappings_changed {
+            responses.push(DeviceEventResponse::GuestMappingsChanged);
+        }
+        Ok(())
+    }
 }
 
 impl EmulatedDevice for PciRootComplex {
     fn services(&self) -> Vec<DeviceRegion> {
-        vec![
+        let mut regions = vec![
             DeviceRegion::PortIo(
                 Self::PCI_CONFIG_ADDRESS..=Self::PCI_CONFIG_ADDRESS,
             ),
@@ -198,11 +1254,28 @@ impl EmulatedDevice for PciRootComplex {
                 Self::PCI_CONFIG_DATA..=Self::PCI_CONFIG_DATA_MAX,
             ),
             DeviceRegion::PortIo(Self::PCI_CONFIG_TYPE..=Self::PCI_CONFIG_TYPE),
-        ]
+            DeviceRegion::PortIo(
+                Self::PCI_RESET_CONTROL..=Self::PCI_RESET_CONTROL,
+            ),
+            DeviceRegion::MemIo(
+                GuestPhysAddr::new(PAM_REGION_START)
+                    ..=GuestPhysAddr::new(PAM_REGION_END),
+            ),
+        ];
+        if let Some(base) = self.ecam {
+            regions.push(DeviceRegion::MemIo(
+                GuestPhysAddr::new(base)
+                    ..=GuestPhysAddr::new(base + PCI_ECAM_SIZE - 1),
+            ));
+        }
+        regions
     }
 
-    fn on_event(&mut self, event: Event) -> Result<()> {
+    fn on_event(&mut self, mut event: Event) -> Result<()> {
         match event.kind {
+            DeviceEvent::PcieHotplugRequested(hotplug) => {
+                self.hotplug(hotplug, event.responses)?;
+            }
             DeviceEvent::PortRead(port, mut val) => {
                 match port {
                     Self::PCI_CONFIG_ADDRESS => {
@@ -210,29 +1283,22 @@ impl EmulatedDevice for PciRootComplex {
                         let addr = 0x80000000 | self.current_address;
                         val.copy_from_u32(addr);
                     }
+                    Self::PCI_RESET_CONTROL => {
+                        val.copy_from_u32(self.reset_control as u32);
+                    }
                     Self::PCI_CONFIG_DATA..=Self::PCI_CONFIG_DATA_MAX => {
                         let bdf =
                             ((self.current_address & 0xffff00) >> 8) as u16;
-                        let register = (self.current_address & 0xff >> 2) as u8;
+                        let register = (self.current_address & 0xfc) as u16;
                         let offset = (port - Self::PCI_CONFIG_DATA) as u8;
 
-                        match self.devices.get(&bdf) {
-                            Some(device) => {
-                                let res =
-                                    device.config_space.read_register(register)
-                                        >> (offset * 8);
-                                val.copy_from_u32(res);
-                                debug!(
-                                    "pci: port=0x{:x}, register=0x{:x}, offset=0x{:x}, val={}",
-                                    port, register, offset, val
-                                );
-                            }
-                            None => {
-                                // If no device is present, just return all 0xFFs
-                                let res = 0xffffffffu32;
-                                val.copy_from_u32(res);
-                            }
-                        }
+                        let res =
+                            self.read_config(bdf, register) >> (offset * 8);
+                        val.copy_from_u32(res);
+                        debug!(
+                            "pci: port=0x{:x}, register=0x{:x}, offset=0x{:x}, val={}",
+                            port, register, offset, val
+                        );
                     }
                     _ => {
                         return Err(Error::InvalidValue(format!(
@@ -247,6 +1313,27 @@ impl EmulatedDevice for PciRootComplex {
                     let addr: u32 = val.try_into()?;
                     self.current_address = addr & 0x7fffffffu32;
                 }
+                Self::PCI_RESET_CONTROL => {
+                    let control: u8 = val.try_into()?;
+
+                    // Bit 2 (SYS_RST) triggers the reset, with bit 1
+                    // selecting a hard reset (both reset the VM the same
+                    // way)
+                    if control & 0b100 != 0 {
+                        event
+                            .responses
+                            .push(DeviceEventResponse::ResetRequested);
+                    }
+                    self.reset_control = control & !0b100;
+                }
+                Self::PCI_CONFIG_DATA..=Self::PCI_CONFIG_DATA_MAX => {
+                    self.on_config_write(
+                        port,
+                        val,
+                        &mut event.space,
+                        event.responses,
+                    )?;
+                }
                 _ => {
                     debug!(
                             "pci: Attempt to write to port=0x{:x} (addr=0x{:x}). Ignoring.",
@@ -254,6 +1341,36 @@ impl EmulatedDevice for PciRootComplex {
                         );
                 }
             },
+            DeviceEvent::MemRead(addr, mut req) => {
+                let len = req.as_slice().len();
+                if let Some((bdf, offset)) = self.ecam_target(addr, len)? {
+                    let register = self.read_config(bdf, offset).to_le_bytes();
+                    let start = (offset & 0b11) as usize;
+                    let data = req.as_mut_slice();
+                    data.copy_from_slice(&register[start..start + data.len()]);
+                    return Ok(());
+                }
+                self.pam.on_mem_read(
+                    addr.as_u64(),
+                    req.as_mut_slice(),
+                    &event.space,
+                )?;
+            }
+            DeviceEvent::MemWrite(addr, req) => {
+                let data = req.as_slice();
+                if let Some((bdf, offset)) =
+                    self.ecam_target(addr, data.len())?
+                {
+                    return self.write_config(
+                        bdf,
+                        offset,
+                        data,
+                        &mut event.space,
+                        event.responses,
+                    );
+                }
+                self.pam.on_mem_write(addr.as_u64(), data)?;
+            }
             _ => (),
         }
         Ok(())
@@ -278,8 +1395,8 @@ mod test {
     fn complex_ready_for_reg_read(reg: u8) -> Arc<RwLock<PciRootComplex>> {
         let view = define_test_view();
         let complex = PciRootComplex::new();
-        let addr = ((reg << 2) as u32).to_be_bytes();
-        let request = PortWriteRequest::try_from(&addr[..]).unwrap();
+        let addr = PortIoValue::from_u32((reg << 2) as u32);
+        let request = PortWriteRequest::try_from(addr.as_bytes()).unwrap();
         let mut responses = ResponseEventArray::default();
         let event = Event::new(
             DeviceEvent::PortWrite(PciRootComplex::PCI_CONFIG_ADDRESS, request),
@@ -310,7 +1427,7 @@ mod test {
         .unwrap();
         complex.on_event(event).unwrap();
 
-        assert_eq!(u32::from_be_bytes(buff), 0x29c08086);
+        assert_eq!(u32::from_le_bytes(buff), 0x29c08086);
     }
 
     #[test]
@@ -328,7 +1445,7 @@ mod test {
         )
         .unwrap();
         complex.on_event(event).unwrap();
-        assert_eq!(u16::from_be_bytes(buff), 0x8086);
+        assert_eq!(u16::from_le_bytes(buff), 0x8086);
 
         let view = define_test_view();
         let val = PortReadRequest::TwoBytes(&mut buff);
@@ -339,7 +1456,7 @@ mod test {
         )
         .unwrap();
         complex.on_event(event).unwrap();
-        assert_eq!(u16::from_be_bytes(buff), 0x29c0);
+        assert_eq!(u16::from_le_bytes(buff), 0x29c0);
     }
 
     #[test]
@@ -359,7 +1476,7 @@ mod test {
         )
         .unwrap();
         complex.on_event(event).unwrap();
-        assert_eq!(u8::from_be_bytes(buff), 0x86);
+        assert_eq!(u8::from_le_bytes(buff), 0x86);
 
         let view = define_test_view();
         let val = PortReadRequest::OneByte(&mut buff);
@@ -370,7 +1487,7 @@ mod test {
         )
         .unwrap();
         complex.on_event(event).unwrap();
-        assert_eq!(u8::from_be_bytes(buff), 0x80);
+        assert_eq!(u8::from_le_bytes(buff), 0x80);
 
         let view = define_test_view();
         let val = PortReadRequest::OneByte(&mut buff);
@@ -381,7 +1498,7 @@ mod test {
         )
         .unwrap();
         complex.on_event(event).unwrap();
-        assert_eq!(u8::from_be_bytes(buff), 0xc0);
+        assert_eq!(u8::from_le_bytes(buff), 0xc0);
 
         let view = define_test_view();
         let val = PortReadRequest::OneByte(&mut buff);
@@ -392,6 +1509,344 @@ mod test {
         )
         .unwrap();
         complex.on_event(event).unwrap();
-        assert_eq!(u8::from_be_bytes(buff), 0x29);
+        assert_eq!(u8::from_le_bytes(buff), 0x29);
+    }
+
+    #[test]
+    fn test_pam_register_write() {
+        let complex = complex_ready_for_reg_read(0x90 >> 2);
+        let mut responses = ResponseEventArray::default();
+        let mut complex = complex.write();
+
+        // Make 0xF0000-0xFFFFF read-only through PAM0
+        let view = define_test_view();
+        let data = [0x10u8];
+        let request = PortWriteRequest::try_from(&data[..]).unwrap();
+        let event = Event::new(
+            DeviceEvent::PortWrite(PciRootComplex::PCI_CONFIG_DATA, request),
+            view,
+            &mut responses,
+        )
+        .unwrap();
+        complex.on_event(event).unwrap();
+        assert_eq!(responses.len(), 1);
+
+        let view = define_test_view();
+        let mut buff = [0u8; 4];
+        let val = PortReadRequest::FourBytes(&mut buff);
+        let mut responses = ResponseEventArray::default();
+        let event = Event::new(
+            DeviceEvent::PortRead(PciRootComplex::PCI_CONFIG_DATA, val),
+            view,
+            &mut responses,
+        )
+        .unwrap();
+        complex.on_event(event).unwrap();
+
+        // The other registers still reflect the initial read/write mapping
+        assert_eq!(u32::from_le_bytes(buff), 0x33333310);
+    }
+
+    const TEST_ID: PciFunctionId = PciFunctionId {
+        vendor_id: 0x1af4,
+        device_id: 0x1041,
+        class: 0x02,
+        subclass: 0x00,
+    };
+
+    #[test]
+    fn test_pci_config() {
+        let mut config = PciConfig::new(TEST_ID);
+        config.set_bar(0, PciBar::Memory32(0x1000)).unwrap();
+        config.set_bar(1, PciBar::Io(0x20)).unwrap();
+        config.set_bar(2, PciBar::Memory64(0x1_0000_0000)).unwrap();
+        assert!(config.set_bar(5, PciBar::Memory64(0x1000)).is_err());
+        assert!(config.set_bar(4, PciBar::Memory32(0x1800)).is_err());
+
+        // The guest sizes the BARs by writing all ones
+        for bar in 0..4 {
+            config.write(0x10 + bar * 4, &[0xff; 4]);
+        }
+        assert_eq!(config.read_u32(0x10), 0xffff_f000);
+        assert_eq!(config.read_u32(0x14), 0xffff_ffe1);
+        assert_eq!(config.read_u32(0x18), 0x0000_0004);
+        assert_eq!(config.read_u32(0x1c), 0xffff_ffff);
+
+        config.write(0x14, &0xc020u32.to_le_bytes());
+        config.write(0x1c, &[0x02, 0, 0, 0]);
+        assert_eq!(config.bar_address(1), Some(0xc020));
+        assert_eq!(config.bar_address(2), Some(0x2_0000_0000));
+        assert_eq!(config.bar_address(4), None);
+
+        // The identity is read-only
+        config.write(0x00, &[0; 4]);
+        assert_eq!(config.read_u32(0x00), 0x1041_1af4);
+        config.write(0x04, &[0xff, 0xff]);
+        assert_eq!(config.command(), PCI_COMMAND_WRITABLE);
+
+        assert_eq!(config.add_capability(0x09, &[4, 1, 2]).unwrap(), 0x40);
+        assert_eq!(config.add_capability(0x05, &[0; 12]).unwrap(), 0x48);
+        assert_eq!(config.read_u16(PCI_STATUS as u16), PCI_STATUS_CAP_LIST);
+        let mut caps = [0u8; 4];
+        config.read(PCI_CAPABILITY_LIST as u16, &mut caps[..1]);
+        config.read(0x40, &mut caps[1..]);
+        assert_eq!(caps, [0x40, 0x09, 0x48, 4]);
+        assert!(config.add_capability(0x11, &[0; 0xb0]).is_err());
+    }
+
+    struct TestFunction {
+        config: PciConfig,
+        writes: Vec<(u16, usize)>,
+    }
+
+    impl PciDevice for TestFunction {
+        fn config(&self) -> &PciConfig {
+            &self.config
+        }
+
+        fn config_mut(&mut self) -> &mut PciConfig {
+            &mut self.config
+        }
+
+        fn config_written(
+            &mut self,
+            offset: u16,
+            len: usize,
+            _responses: &mut ResponseEventArray,
+        ) -> Result<()> {
+            self.writes.push((offset, len));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_emulated_function() {
+        let complex = PciRootComplex::with_ecam(PCI_ECAM_BASE);
+        let mut complex = complex.write();
+        let mut config = PciConfig::new(TEST_ID);
+        config.set_bar(0, PciBar::Memory32(0x1000)).unwrap();
+        let function = Arc::new(RwLock::new(TestFunction {
+            config,
+            writes: vec![],
+        }));
+        complex.add_device(0x18, function.clone()).unwrap();
+        assert!(complex.add_device(0x00, function.clone()).is_err());
+        assert!(complex.add_device(0x18, function.clone()).is_err());
+
+        // Through the config ports
+        complex.current_address = 0x1800;
+        let mut buff = [0u8; 2];
+        let mut responses = ResponseEventArray::default();
+        let event = Event::new(
+            DeviceEvent::PortRead(
+                PciRootComplex::PCI_CONFIG_DATA + 2,
+                PortReadRequest::TwoBytes(&mut buff),
+            ),
+            define_test_view(),
+            &mut responses,
+        )
+        .unwrap();
+        complex.on_event(event).unwrap();
+        assert_eq!(u16::from_le_bytes(buff), 0x1041);
+
+        // Through ECAM
+        let bar = GuestPhysAddr::new(PCI_ECAM_BASE + (0x18 << 12) + 0x10);
+        let data = 0xfebf_0000u32.to_le_bytes();
+        let event = Event::new(
+            DeviceEvent::MemWrite(bar, MemWriteRequest::new(&data)),
+            define_test_view(),
+            &mut responses,
+        )
+        .unwrap();
+        complex.on_event(event).unwrap();
+        assert_eq!(function.read().config.bar_address(0), Some(0xfebf_0000));
+        assert_eq!(function.read().writes, vec![(0x10, 4)]);
+
+        let mut data = [0u8; 2];
+        let event = Event::new(
+            DeviceEvent::MemRead(bar + 2, MemReadRequest::new(&mut data)),
+            define_test_view(),
+            &mut responses,
+        )
+        .unwrap();
+        complex.on_event(event).unwrap();
+        assert_eq!(data, [0xbf, 0xfe]);
+
+        // Functions that are not present read as all ones
+        let mut data = [0u8; 4];
+        let event = Event::new(
+            DeviceEvent::MemRead(
+                GuestPhysAddr::new(PCI_ECAM_BASE + (0x20 << 12)),
+                MemReadRequest::new(&mut data),
+            ),
+            define_test_view(),
+            &mut responses,
+        )
+        .unwrap();
+        complex.on_event(event).unwrap();
+        assert_eq!(data, [0xff; 4]);
+    }
+
+    // A root complex with the slot of the first root port on bus 1, with
+    // its interrupt routed to GSI 10 and hotplug events enabled
+    fn complex_with_slot(
+        space: &mut GuestAddressSpace,
+    ) -> Arc<RwLock<PciRootComplex>> {
+        let complex = PciRootComplex::new();
+        let port_bdf = PciRootComplex::ROOT_PORT_DEVICE << 3;
+        let mut responses = ResponseEventArray::default();
+        let control = SlotControl::HOTPLUG_INTERRUPT_ENABLE
+            | SlotControl::PRESENCE_DETECT_CHANGED_ENABLE
+            | SlotControl::ATTENTION_BUTTON_ENABLE;
+        {
+            let mut complex = complex.write();
+            for (offset, val) in [
+                (PCI_INTERRUPT_LINE, 10),
+                (PCI_SECONDARY_BUS, 1),
+                (PCIE_SLOT_CONTROL, control.bits() as u8),
+            ]
+            .iter()
+            {
+                complex
+                    .write_root_port(
+                        port_bdf,
+                        *offset,
+                        *val,
+                        space,
+                        &mut responses,
+                    )
+                    .unwrap();
+            }
+        }
+        assert_eq!(responses.len(), 0);
+        complex
+    }
+
+    // Power off the slot of the first root port
+    fn power_off_slot(
+        complex: &mut PciRootComplex,
+        space: &mut GuestAddressSpace,
+        responses: &mut ResponseEventArray,
+    ) {
+        complex
+            .write_root_port(
+                PciRootComplex::ROOT_PORT_DEVICE << 3,
+                PCIE_SLOT_CONTROL_HIGH,
+                (SlotControl::POWER_CONTROLLER_OFF.bits() >> 8) as u8,
+                space,
+                responses,
+            )
+            .unwrap();
+    }
+
+    const SLOT_FUNCTION: PciFunctionId = PciFunctionId {
+        vendor_id: 0x1af4,
+        device_id: 0x1001,
+        class: 0x01,
+        subclass: 0x00,
+    };
+
+    #[test]
+    fn test_pcie_hotplug() {
+        let mut space = GuestAddressSpace::new().unwrap();
+        let complex = complex_with_slot(&mut space);
+        let mut complex = complex.write();
+        let port_bdf = PciRootComplex::ROOT_PORT_DEVICE << 3;
+        let mut responses = ResponseEventArray::default();
+
+        complex
+            .hotplug(
+                PcieHotplugEvent::Insert {
+                    slot: 1,
+                    function: SLOT_FUNCTION,
+                    host: None,
+                },
+                &mut responses,
+            )
+            .unwrap();
+        assert_eq!(responses.len(), 1);
+        assert!(matches!(responses[0], DeviceEventResponse::Gsi(10)));
+        assert_eq!(complex.read_config(0x0100, 0), 0x1001_1af4);
+
+        let port = &complex.devices[&port_bdf].config_space;
+        let status =
+            SlotStatus::from_bits_truncate(port.read_word(PCIE_SLOT_STATUS));
+        assert!(status.contains(
+            SlotStatus::PRESENCE_DETECT_STATE
+                | SlotStatus::PRESENCE_DETECT_CHANGED
+        ));
+
+        // The device remains until the guest powers off the slot
+        complex
+            .hotplug(PcieHotplugEvent::Remove { slot: 1 }, &mut responses)
+            .unwrap();
+        assert_eq!(responses.len(), 2);
+        assert_eq!(complex.read_config(0x0100, 0), 0x1001_1af4);
+
+        power_off_slot(&mut complex, &mut space, &mut responses);
+        assert_eq!(complex.read_config(0x0100, 0), 0xffffffff);
+    }
+
+    #[test]
+    fn test_pcie_host_function() {
+        let mut space = GuestAddressSpace::new().unwrap();
+        let complex = complex_with_slot(&mut space);
+        let mut complex = complex.write();
+        let mut responses = ResponseEventArray::default();
+
+        let host = PcieHostFunction {
+            source_id: 0x0300,
+            bar: 0xfe00_0000,
+            bar_size: 0x2000,
+        };
+        complex
+            .hotplug(
+                PcieHotplugEvent::Insert {
+                    slot: 1,
+                    function: SLOT_FUNCTION,
+                    host: Some(host),
+                },
+                &mut responses,
+            )
+            .unwrap();
+
+        // The guest sizes and places BAR 0, then enables decoding
+        let port_bdf = PciRootComplex::ROOT_PORT_DEVICE << 3;
+        let mut write =
+            |complex: &mut PciRootComplex, offset: u16, val: u32| {
+                complex
+                    .write_slot_function(
+                        port_bdf,
+                        offset,
+                        &val.to_le_bytes(),
+                        &mut space,
+                        &mut responses,
+                    )
+                    .unwrap();
+            };
+        write(&mut complex, PCI_BAR_0, 0xffff_ffff);
+        assert_eq!(complex.read_config(0x0100, PCI_BAR_0), 0xffff_e000);
+        write(&mut complex, PCI_BAR_0, 0xc000_0000);
+        write(&mut complex, PCI_COMMAND, PCI_COMMAND_MEMORY as u32);
+        assert_eq!(complex.read_config(0x0100, PCI_BAR_0), 0xc000_0000);
+        assert_eq!(complex.slots[&port_bdf].mapped, Some(0xc000_0000));
+        let frame = space
+            .find_host_frame(GuestPhysAddr::new(0xc000_1000))
+            .unwrap();
+        assert_eq!(frame.start_address().as_u64(), 0xfe00_1000);
+
+        // Releasing the slot unmaps the BAR and detaches the function
+        responses.clear();
+        complex
+            .hotplug(PcieHotplugEvent::Remove { slot: 1 }, &mut responses)
+            .unwrap();
+        power_off_slot(&mut complex, &mut space, &mut responses);
+        assert!(space
+            .find_host_frame(GuestPhysAddr::new(0xc000_0000))
+            .is_err());
+        assert!(responses.iter().any(|response| matches!(
+            response,
+            DeviceEventResponse::HostFunctionReleased(0x0300)
+        )));
     }
 }
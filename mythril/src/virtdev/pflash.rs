@@ -0,0 +1,233 @@
+//! A CFI flash device holding the UEFI variable store
+//!
+//! UEFI firmware (e.g., a split OVMF build) expects its variable store in a
+//! flash region directly below the firmware image, which it programs with
+//! the Intel/Sharp command set (as emulated by QEMU's `pflash_cfi01`). The
+//! contents are kept in the `UefiVariables` area of the VM's `Nvram`, so
+//! the variables persist with the rest of its nvram.
+
+use crate::error::Result;
+use crate::memory::GuestPhysAddr;
+use crate::nvram::{Nvram, NvramArea};
+use crate::virtdev::{DeviceEvent, DeviceRegion, EmulatedDevice, Event};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::RwLock;
+
+/// The size of the blocks erased by a single block erase command
+pub const PFLASH_BLOCK_SIZE: usize = 0x1000;
+
+const CMD_READ_ARRAY: u8 = 0xff;
+const CMD_PROGRAM: u8 = 0x10;
+const CMD_PROGRAM_ALT: u8 = 0x40;
+const CMD_BLOCK_ERASE: u8 = 0x20;
+const CMD_ERASE_CONFIRM: u8 = 0xd0;
+const CMD_CLEAR_STATUS: u8 = 0x50;
+const CMD_READ_STATUS: u8 = 0x70;
+
+const STATUS_READY: u8 = 1 << 7;
+const STATUS_ERASE_ERROR: u8 = 1 << 5;
+const STATUS_PROGRAM_ERROR: u8 = 1 << 4;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum FlashMode {
+    ReadArray,
+    ReadStatus,
+
+    // The next write is the data to program
+    Program,
+
+    // The next write must confirm the erase of the block
+    Erase,
+}
+
+pub struct Pflash {
+    base: u64,
+    nvram: Arc<RwLock<Nvram>>,
+    mode: FlashMode,
+    status: u8,
+}
+
+impl Pflash {
+    /// Create a new flash device at `base`, backed by the UEFI variable
+    /// area of `nvram`
+    pub fn new(base: u64, nvram: Arc<RwLock<Nvram>>) -> Arc<RwLock<Self>> {
+        Arc::new(RwLock::new(Self {
+            base: base,
+            nvram: nvram,
+            mode: FlashMode::ReadArray,
+            status: 0,
+        }))
+    }
+
+    /// The size of the flash region
+    pub fn size() -> usize {
+        NvramArea::UefiVariables.size()
+    }
+
+    fn read(&self, offset: usize, data: &mut [u8]) -> Result<()> {
+        match self.mode {
+            FlashMode::ReadArray => {
+                self.nvram
+                    .read()
+                    .read(NvramArea::UefiVariables, offset, data)
+            }
+            _ => {
+                // The status is repeated across the width of the access
+                for byte in data.iter_mut() {
+                    *byte = self.status;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+        let cmd = match data.first() {
+            Some(cmd) => *cmd,
+            None => return Ok(()),
+        };
+        match self.mode {
+            FlashMode::Program => {
+                self.nvram.write().write(
+                    NvramArea::UefiVariables,
+                    offset,
+                    data,
+                )?;
+                self.status |= STATUS_READY;
+                self.mode = FlashMode::ReadStatus;
+            }
+            FlashMode::Erase => {
+                if cmd == CMD_ERASE_CONFIRM {
+                    let block = offset & !(PFLASH_BLOCK_SIZE - 1);
+                    self.nvram.write().write(
+                        NvramArea::UefiVariables,
+                        block,
+                        &[0xff; PFLASH_BLOCK_SIZE],
+                    )?;
+                    self.status |= STATUS_READY;
+                } else {
+                    self.status |= STATUS_READY
+                        | STATUS_ERASE_ERROR
+                        | STATUS_PROGRAM_ERROR;
+                }
+                self.mode = FlashMode::ReadStatus;
+            }
+            FlashMode::ReadArray | FlashMode::ReadStatus => match cmd {
+                CMD_READ_ARRAY | 0x00 | 0xf0 => {
+                    self.mode = FlashMode::ReadArray;
+                }
+                CMD_PROGRAM | CMD_PROGRAM_ALT => {
+                    self.mode = FlashMode::Program;
+                }
+                CMD_BLOCK_ERASE => {
+                    self.mode = FlashMode::Erase;
+                }
+                CMD_CLEAR_STATUS => {
+                    self.status = 0;
+                    self.mode = FlashMode::ReadArray;
+                }
+                CMD_READ_STATUS => {
+                    self.mode = FlashMode::ReadStatus;
+                }
+                cmd => {
+                    warn!("Unsupported flash command: 0x{:x}", cmd);
+                    self.mode = FlashMode::ReadArray;
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+impl EmulatedDevice for Pflash {
+    fn services(&self) -> Vec<DeviceRegion> {
+        vec![DeviceRegion::MemIo(
+            GuestPhysAddr::new(self.base)
+                ..=GuestPhysAddr::new(self.base + Self::size() as u64 - 1),
+        )]
+    }
+
+    fn on_event(&mut self, event: Event) -> Result<()> {
+        match event.kind {
+            DeviceEvent::MemRead(addr, mut req) => {
+                let offset = (addr.as_u64() - self.base) as usize;
+                self.read(offset, req.as_mut_slice())?;
+            }
+            DeviceEvent::MemWrite(addr, req) => {
+                let offset = (addr.as_u64() - self.base) as usize;
+                self.write(offset, req.as_slice())?;
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::nvram::MemoryBackend;
+    use alloc::boxed::Box;
+
+    fn flash() -> Pflash {
+        let nvram = Nvram::new(Box::new(MemoryBackend::new(None))).unwrap();
+        Pflash {
+            base: 0xff000000,
+            nvram: Arc::new(RwLock::new(nvram)),
+            mode: FlashMode::ReadArray,
+            status: 0,
+        }
+    }
+
+    fn read(flash: &Pflash, offset: usize) -> u8 {
+        let mut data = [0u8];
+        flash.read(offset, &mut data).unwrap();
+        data[0]
+    }
+
+    #[test]
+    fn test_pflash_detect() {
+        // The probe used by OVMF: a cleared status register that reads
+        // back as zero identifies a flash device (rather than RAM or ROM)
+        let mut flash = flash();
+        flash.write(0, &[CMD_CLEAR_STATUS]).unwrap();
+        assert_eq!(read(&flash, 0), 0xff);
+        flash.write(0, &[CMD_READ_STATUS]).unwrap();
+        assert_eq!(read(&flash, 0), 0);
+        flash.write(0, &[CMD_READ_ARRAY]).unwrap();
+        assert_eq!(flash.mode, FlashMode::ReadArray);
+    }
+
+    #[test]
+    fn test_pflash_program_and_erase() {
+        let mut flash = flash();
+        assert_eq!(read(&flash, 0x1234), 0xff);
+        flash.write(0x2000, &[CMD_PROGRAM]).unwrap();
+        flash.write(0x2000, &[0x5a]).unwrap();
+        flash.write(0x1234, &[CMD_PROGRAM]).unwrap();
+        flash.write(0x1234, &[0xa5]).unwrap();
+        assert_eq!(read(&flash, 0x1234), STATUS_READY);
+        flash.write(0, &[CMD_READ_ARRAY]).unwrap();
+        assert_eq!(read(&flash, 0x1234), 0xa5);
+        assert_eq!(
+            flash.nvram.read().area(NvramArea::UefiVariables)[0x1234],
+            0xa5
+        );
+
+        // Erasing sets the whole block
+        flash.write(0x1000, &[CMD_BLOCK_ERASE]).unwrap();
+        flash.write(0x1000, &[CMD_ERASE_CONFIRM]).unwrap();
+        flash.write(0, &[CMD_READ_ARRAY]).unwrap();
+        assert_eq!(read(&flash, 0x1000), 0xff);
+        assert_eq!(read(&flash, 0x1234), 0xff);
+        assert_eq!(read(&flash, 0x2000), 0x5a);
+
+        // An erase that is not confirmed fails
+        flash.write(0x2000, &[CMD_BLOCK_ERASE]).unwrap();
+        flash.write(0x2000, &[CMD_READ_ARRAY]).unwrap();
+        assert_ne!(read(&flash, 0x2000) & STATUS_ERASE_ERROR, 0);
+        flash.write(0, &[CMD_CLEAR_STATUS]).unwrap();
+        assert_eq!(read(&flash, 0x2000), 0x5a);
+    }
+}
@@ -1,3 +1,15 @@
+//! Emulation of the cascaded pair of 8259 programmable interrupt controllers
+//!
+//! Devices raise legacy (ISA) interrupts at `LEGACY_IRQ_VECTOR_BASE + irq`.
+//! Until the guest initializes the PICs, these vectors are injected as they
+//! are. Once it has, the vCPU routes them through the `Pic8259` instead,
+//! which applies the guest's masks and vector bases, and holds interrupts
+//! of equal or lower priority until the guest signals the end of the one
+//! in service.
+//!
+//! Priorities are fixed (IRQ0 highest), so the rotation commands behave as
+//! plain EOIs, and the special mask and poll modes are not supported.
+
 use crate::error::Result;
 use crate::virtdev::{DeviceEvent, DeviceRegion, EmulatedDevice, Event, Port};
 use alloc::sync::Arc;
@@ -5,15 +17,136 @@ use alloc::vec::Vec;
 use core::convert::TryInto;
 use spin::RwLock;
 
+/// The vector devices use to raise legacy IRQ 0 (the vector Linux uses)
+pub const LEGACY_IRQ_VECTOR_BASE: u8 = 48;
+
+/// The number of legacy IRQs
+pub const LEGACY_IRQS: u8 = 16;
+
+// The master IRQ the slave is cascaded on
+const CASCADE_IRQ: u8 = 2;
+
+/// The legacy IRQ raised by an interrupt at `vector`, if any
+pub fn legacy_irq(vector: u8) -> Option<u8> {
+    match vector.checked_sub(LEGACY_IRQ_VECTOR_BASE) {
+        Some(irq) if irq < LEGACY_IRQS => Some(irq),
+        _ => None,
+    }
+}
+
+// The initialization command word the PIC expects next
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum InitStep {
+    Icw2,
+    Icw3,
+    Icw4,
+}
+
 #[derive(Default, Debug)]
 pub struct PicState {
+    init: Option<InitStep>,
+    initialized: bool,
+    single: bool,
+    icw4_needed: bool,
+    auto_eoi: bool,
+    vector_base: u8,
     imr: u8,
+    irr: u8,
+    isr: u8,
+    // The command port reads the ISR (rather than the IRR)
+    read_isr: bool,
+}
+
+impl PicState {
+    fn write_command(&mut self, val: u8) {
+        if val & 0x10 != 0 {
+            // ICW1 restarts initialization and clears the mask
+            *self = PicState {
+                init: Some(InitStep::Icw2),
+                single: val & 0x02 != 0,
+                icw4_needed: val & 0x01 != 0,
+                ..PicState::default()
+            };
+        } else if val & 0x08 != 0 {
+            // OCW3
+            if val & 0x02 != 0 {
+                self.read_isr = val & 0x01 != 0;
+            }
+        } else if val & 0x20 != 0 {
+            // OCW2 (EOI)
+            if val & 0x40 != 0 {
+                self.isr &= !(1 << (val & 0x07));
+            } else {
+                // Clear the highest priority in-service interrupt
+                self.isr &= self.isr.wrapping_sub(1);
+            }
+        }
+    }
+
+    fn write_data(&mut self, val: u8) {
+        let next = match self.init {
+            None => {
+                self.imr = val;
+                return;
+            }
+            Some(InitStep::Icw2) => {
+                self.vector_base = val & 0xf8;
+                if !self.single {
+                    Some(InitStep::Icw3)
+                } else if self.icw4_needed {
+                    Some(InitStep::Icw4)
+                } else {
+                    None
+                }
+            }
+            // The cascade is fixed, so ICW3 is ignored
+            Some(InitStep::Icw3) if self.icw4_needed => Some(InitStep::Icw4),
+            Some(InitStep::Icw3) => None,
+            Some(InitStep::Icw4) => {
+                self.auto_eoi = val & 0x02 != 0;
+                None
+            }
+        };
+        self.init = next;
+        self.initialized = next.is_none();
+    }
+
+    fn read_command(&self) -> u8 {
+        if self.read_isr {
+            self.isr
+        } else {
+            self.irr
+        }
+    }
+
+    /// The highest priority interrupt that can be delivered, including the
+    /// requests in `cascade`
+    fn pending(&self, cascade: u8) -> Option<u8> {
+        let requests = (self.irr | cascade) & !self.imr;
+        if requests == 0 {
+            return None;
+        }
+        let irq = requests.trailing_zeros();
+        if self.isr != 0 && self.isr.trailing_zeros() <= irq {
+            return None;
+        }
+        Some(irq as u8)
+    }
+
+    fn acknowledge(&mut self, irq: u8) -> u8 {
+        self.irr &= !(1 << irq);
+        if !self.auto_eoi {
+            self.isr |= 1 << irq;
+        }
+        self.vector_base + irq
+    }
 }
 
 #[derive(Default, Debug)]
 pub struct Pic8259 {
     master_state: PicState,
     slave_state: PicState,
+    elcr: [u8; 2],
 }
 
 impl Pic8259 {
@@ -27,6 +160,45 @@ impl Pic8259 {
     pub fn new() -> Arc<RwLock<Self>> {
         Arc::new(RwLock::new(Pic8259::default()))
     }
+
+    /// Returns true once the guest has initialized the master PIC
+    pub fn is_initialized(&self) -> bool {
+        self.master_state.initialized
+    }
+
+    /// Raise the legacy interrupt `irq` (0 to 15)
+    pub fn request(&mut self, irq: u8) {
+        if irq < 8 {
+            self.master_state.irr |= 1 << irq;
+        } else if irq < LEGACY_IRQS {
+            self.slave_state.irr |= 1 << (irq - 8);
+        }
+    }
+
+    fn cascade(&self) -> u8 {
+        match self.slave_state.pending(0) {
+            Some(_) => 1 << CASCADE_IRQ,
+            None => 0,
+        }
+    }
+
+    /// Returns true if an interrupt can be delivered to the processor
+    pub fn has_pending(&self) -> bool {
+        self.master_state.pending(self.cascade()).is_some()
+    }
+
+    /// Acknowledge the highest priority interrupt that can be delivered,
+    /// returning its vector
+    pub fn acknowledge(&mut self) -> Option<u8> {
+        let irq = self.master_state.pending(self.cascade())?;
+        if irq == CASCADE_IRQ && self.cascade() != 0 {
+            let slave_irq = self.slave_state.pending(0)?;
+            self.master_state.acknowledge(irq);
+            Some(self.slave_state.acknowledge(slave_irq))
+        } else {
+            Some(self.master_state.acknowledge(irq))
+        }
+    }
 }
 
 impl EmulatedDevice for Pic8259 {
@@ -46,25 +218,125 @@ impl EmulatedDevice for Pic8259 {
         match event.kind {
             DeviceEvent::PortRead(port, mut val) => {
                 let data = match port {
+                    Self::PIC_MASTER_COMMAND => {
+                        self.master_state.read_command()
+                    }
                     Self::PIC_MASTER_DATA => self.master_state.imr,
-                    Self::PIC_SLAVE_DATA => self.master_state.imr,
+                    Self::PIC_SLAVE_COMMAND => self.slave_state.read_command(),
+                    Self::PIC_SLAVE_DATA => self.slave_state.imr,
+                    Self::PIC_ECLR_COMMAND => self.elcr[0],
+                    Self::PIC_ECLR_DATA => self.elcr[1],
                     _ => {
                         return Ok(());
                     }
                 };
                 val.copy_from_u32(data as u32);
             }
-            DeviceEvent::PortWrite(port, val) => match port {
-                Self::PIC_MASTER_DATA => {
-                    self.master_state.imr = val.try_into()?;
-                }
-                Self::PIC_SLAVE_DATA => {
-                    self.master_state.imr = val.try_into()?;
+            DeviceEvent::PortWrite(port, val) => {
+                let val: u8 = val.try_into()?;
+                match port {
+                    Self::PIC_MASTER_COMMAND => {
+                        self.master_state.write_command(val)
+                    }
+                    Self::PIC_MASTER_DATA => self.master_state.write_data(val),
+                    Self::PIC_SLAVE_COMMAND => {
+                        self.slave_state.write_command(val)
+                    }
+                    Self::PIC_SLAVE_DATA => self.slave_state.write_data(val),
+                    Self::PIC_ECLR_COMMAND => self.elcr[0] = val,
+                    Self::PIC_ECLR_DATA => self.elcr[1] = val,
+                    _ => (),
                 }
-                _ => (),
-            },
+            }
             _ => (),
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Initialize the pair the way Linux does
+    fn init_pics(pic: &mut Pic8259) {
+        pic.master_state.write_command(0x11);
+        pic.slave_state.write_command(0x11);
+        pic.master_state.write_data(0x30);
+        pic.slave_state.write_data(0x38);
+        pic.master_state.write_data(0x04);
+        pic.slave_state.write_data(0x02);
+        pic.master_state.write_data(0x01);
+        pic.slave_state.write_data(0x01);
+    }
+
+    #[test]
+    fn test_legacy_irq() {
+        assert_eq!(legacy_irq(48), Some(0));
+        assert_eq!(legacy_irq(63), Some(15));
+        assert_eq!(legacy_irq(47), None);
+        assert_eq!(legacy_irq(64), None);
+    }
+
+    #[test]
+    fn test_pic_initialization() {
+        let mut pic = Pic8259::default();
+        assert!(!pic.is_initialized());
+        pic.master_state.write_data(0xfe);
+        assert_eq!(pic.master_state.imr, 0xfe);
+
+        // ICW1 clears the mask
+        pic.master_state.write_command(0x11);
+        assert_eq!(pic.master_state.imr, 0);
+        assert!(!pic.is_initialized());
+        init_pics(&mut pic);
+        assert!(pic.is_initialized());
+        assert_eq!(pic.slave_state.vector_base, 0x38);
+
+        // After initialization, data writes set the mask
+        pic.slave_state.write_data(0xff);
+        assert_eq!(pic.slave_state.imr, 0xff);
+        assert_eq!(pic.master_state.imr, 0);
+    }
+
+    #[test]
+    fn test_pic_masking_and_eoi() {
+        let mut pic = Pic8259::default();
+        init_pics(&mut pic);
+        pic.master_state.write_data(0xfe);
+
+        // Masked requests are held until they are unmasked
+        pic.request(1);
+        assert!(!pic.has_pending());
+        pic.request(0);
+        assert_eq!(pic.acknowledge(), Some(0x30));
+
+        // Further requests wait for the EOI
+        pic.request(0);
+        pic.master_state.write_data(0xfc);
+        assert_eq!(pic.acknowledge(), None);
+        pic.master_state.write_command(0x0b);
+        assert_eq!(pic.master_state.read_command(), 0x01);
+        pic.master_state.write_command(0x20);
+        assert_eq!(pic.acknowledge(), Some(0x30));
+        pic.master_state.write_command(0x60);
+        assert_eq!(pic.acknowledge(), Some(0x31));
+    }
+
+    #[test]
+    fn test_pic_cascade() {
+        let mut pic = Pic8259::default();
+        init_pics(&mut pic);
+        pic.request(12);
+        pic.request(3);
+        assert_eq!(pic.acknowledge(), Some(0x3c));
+        assert_eq!(pic.master_state.isr, 1 << CASCADE_IRQ);
+        assert_eq!(pic.slave_state.isr, 1 << 4);
+
+        // IRQ3 has a lower priority than the cascade
+        assert_eq!(pic.acknowledge(), None);
+        pic.slave_state.write_command(0x20);
+        pic.master_state.write_command(0x20);
+        assert_eq!(pic.acknowledge(), Some(0x33));
+    }
+}
@@ -1,6 +1,22 @@
+//! Emulation of the 8254 programmable interval timer
+//!
+//! All three counters are emulated. Rather than ticking, each counter
+//! records when it started counting, and its count and output are derived
+//! from the time elapsed since then when they are read. Counter 0 drives
+//! IRQ0 with a timer on the per-core timer wheel.
+//!
+//! Port 0x61 (system control port B) is also emulated here, as it mostly
+//! belongs to counter 2: bit 0 is the gate of counter 2, bit 1 enables the
+//! speaker (which is silent), and the output of counter 2 is visible in
+//! bit 5. Bit 4 is the DRAM refresh request, which toggles every 15us of
+//! guest uptime (and so with the TSC), since firmware and kernels poll it
+//! for short delays. The gates of counters 0 and 1 are tied high, so their
+//! hardware triggered modes (1 and 5) never start counting.
+
 use crate::error::{Error, Result};
 use crate::physdev::pit::*;
 use crate::time;
+use crate::timekeeping;
 use crate::virtdev::{
     DeviceEvent, DeviceRegion, EmulatedDevice, Event, Port, PortReadRequest,
     PortWriteRequest,
@@ -9,54 +25,274 @@ use crate::virtdev::{
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::convert::TryFrom;
+use core::time::Duration;
 use spin::RwLock;
 
-#[derive(Debug)]
-enum OperatingModeState {
-    Mode0 {
-        start_counter: Option<u16>,
-        timer: Option<time::TimerId>,
-        start_time: Option<time::Instant>,
-    },
-    Mode2 {
-        start_counter: Option<u16>,
-        timer: Option<time::TimerId>,
-        start_time: Option<time::Instant>,
-    },
+//FIXME: this value should be determined by the virtual PIC/APIC. Currently
+// use the vector linux has for IRQ0
+const IRQ0_VECTOR: u8 = 48;
+
+// A count of zero is the largest count
+const MAX_COUNT: u64 = 0x10000;
+
+// Port 0x61 bits
+const PORT_B_GATE2: u8 = 1 << 0;
+const PORT_B_WRITABLE: u8 = 0b1111;
+const PORT_B_REFRESH_SHIFT: u8 = 4;
+const PORT_B_OUT2_SHIFT: u8 = 5;
+
+// The period of the refresh request toggle
+const REFRESH_PERIOD_NS: u128 = 15_085;
+
+fn ticks(duration: Duration) -> u64 {
+    (duration.as_nanos() / PIT_NS_PER_TICK as u128) as u64
 }
 
-#[derive(Debug)]
-enum AccessModeState {
-    LatchCount,
-    LoByte,
-    HiByte,
-    Word { lo_byte: Option<u8> },
+fn ticks_duration(ticks: u64) -> Duration {
+    Duration::from_nanos(PIT_NS_PER_TICK * ticks)
 }
 
 #[derive(Debug)]
-struct ChannelState {
-    mode: OperatingModeState,
-    access: AccessModeState,
+struct Counter {
+    mode: OperatingMode,
+    access: AccessMode,
+    // The initial count (1 to MAX_COUNT)
+    reload: Option<u64>,
+    // The time (since system start) the counter started counting
+    start: Option<Duration>,
+    // The low byte of a count being written with word access
+    write_lo: Option<u8>,
+    // The next read with word access returns the high byte
+    read_hi: bool,
+    latched_count: Option<u16>,
+    latched_status: Option<u8>,
+    // A count has been written but not yet loaded
+    null_count: bool,
+    gate: bool,
+    // The elapsed ticks of a counter stopped by its gate
+    paused: Option<u64>,
 }
 
-impl Default for ChannelState {
+impl Default for Counter {
     fn default() -> Self {
         Self {
-            mode: OperatingModeState::Mode0 {
-                start_counter: None,
-                timer: None,
-                start_time: None,
+            mode: OperatingMode::Mode0,
+            access: AccessMode::Word,
+            reload: None,
+            start: None,
+            write_lo: None,
+            read_hi: false,
+            latched_count: None,
+            latched_status: None,
+            null_count: false,
+            gate: true,
+            paused: None,
+        }
+    }
+}
+
+impl Counter {
+    /// Reprogram the counter with a control word, which stops it until a
+    /// new count is written
+    fn program(&mut self, access: AccessMode, mode: OperatingMode) {
+        *self = Self {
+            mode,
+            access,
+            null_count: true,
+            gate: self.gate,
+            ..Self::default()
+        };
+    }
+
+    fn elapsed(&self, now: Duration) -> Option<u64> {
+        if self.paused.is_some() {
+            return self.paused;
+        }
+        self.start
+            .map(|start| if now > start { ticks(now - start) } else { 0 })
+    }
+
+    /// Set the level of the counter's gate input
+    fn set_gate(&mut self, gate: bool, now: Duration) {
+        if gate == self.gate {
+            return;
+        }
+        self.gate = gate;
+        match self.mode {
+            // A low gate suspends counting
+            OperatingMode::Mode0 | OperatingMode::Mode4 => {
+                if gate {
+                    if let Some(paused) = self.paused.take() {
+                        let resumed = now
+                            .checked_sub(ticks_duration(paused))
+                            .unwrap_or_else(Duration::default);
+                        self.start = Some(resumed);
+                    }
+                } else if self.start.is_some() {
+                    self.paused = self.elapsed(now);
+                }
+            }
+            // A low gate stops the periodic modes (with their output high)
+            OperatingMode::Mode2 | OperatingMode::Mode3 if !gate => {
+                self.start = None;
+            }
+            // A rising edge (re)loads the count
+            _ => {
+                if gate && self.reload.is_some() {
+                    self.start = Some(now);
+                    self.null_count = false;
+                }
+            }
+        }
+    }
+
+    /// The current value of the counting element
+    fn count(&self, now: Duration) -> u16 {
+        let reload = match self.reload {
+            Some(reload) => reload,
+            None => return 0,
+        };
+        let elapsed = match self.elapsed(now) {
+            Some(elapsed) => elapsed,
+            None => return reload as u16,
+        };
+        match self.mode {
+            // The one-shot modes keep counting (and wrap) after the
+            // terminal count
+            OperatingMode::Mode0
+            | OperatingMode::Mode1
+            | OperatingMode::Mode4
+            | OperatingMode::Mode5 => reload.wrapping_sub(elapsed) as u16,
+            OperatingMode::Mode2 => (reload - elapsed % reload) as u16,
+            // The count decrements by two, twice per period
+            OperatingMode::Mode3 => {
+                let half = ((reload + 1) / 2).max(1);
+                (reload - 2 * (elapsed % reload % half)) as u16
+            }
+        }
+    }
+
+    /// The level of the counter's output pin
+    fn output(&self, now: Duration) -> bool {
+        let (reload, elapsed) = match (self.reload, self.elapsed(now)) {
+            (Some(reload), Some(elapsed)) => (reload, elapsed),
+            _ => return self.mode != OperatingMode::Mode0,
+        };
+        match self.mode {
+            OperatingMode::Mode0 | OperatingMode::Mode1 => elapsed >= reload,
+            OperatingMode::Mode2 => elapsed % reload != reload - 1,
+            OperatingMode::Mode3 => elapsed % reload < (reload + 1) / 2,
+            OperatingMode::Mode4 | OperatingMode::Mode5 => elapsed != reload,
+        }
+    }
+
+    fn status(&self, now: Duration) -> u8 {
+        (self.output(now) as u8) << 7
+            | (self.null_count as u8) << 6
+            | (self.access as u8) << 4
+            | (self.mode as u8) << 1
+    }
+
+    /// Latch the current count, unless a count is already latched
+    fn latch_count(&mut self, now: Duration) {
+        if self.latched_count.is_none() {
+            self.latched_count = Some(self.count(now));
+            self.read_hi = false;
+        }
+    }
+
+    /// Latch the current status, unless a status is already latched
+    fn latch_status(&mut self, now: Duration) {
+        if self.latched_status.is_none() {
+            self.latched_status = Some(self.status(now));
+        }
+    }
+
+    fn read(&mut self, now: Duration) -> u8 {
+        if let Some(status) = self.latched_status.take() {
+            return status;
+        }
+        let count = self.latched_count.unwrap_or_else(|| self.count(now));
+        let (byte, done) = match self.access {
+            AccessMode::LoByte => (count as u8, true),
+            AccessMode::HiByte => ((count >> 8) as u8, true),
+            _ if self.read_hi => ((count >> 8) as u8, true),
+            _ => (count as u8, false),
+        };
+        self.read_hi = !done;
+        if done {
+            self.latched_count = None;
+        }
+        byte
+    }
+
+    /// Write a byte of the count, returning true if the counter started
+    fn write(&mut self, val: u8, now: Duration) -> bool {
+        let count = match self.access {
+            AccessMode::LoByte => val as u64,
+            AccessMode::HiByte => (val as u64) << 8,
+            _ => match self.write_lo.take() {
+                Some(lo) => ((val as u64) << 8) | lo as u64,
+                None => {
+                    self.write_lo = Some(val);
+                    return false;
+                }
             },
-            access: AccessModeState::LoByte,
+        };
+        self.reload = Some(if count == 0 { MAX_COUNT } else { count });
+        match self.mode {
+            // These modes wait for a rising edge of the gate
+            OperatingMode::Mode1 | OperatingMode::Mode5 => {
+                self.null_count = true;
+                false
+            }
+            // The periodic modes wait for the gate to be high
+            OperatingMode::Mode2 | OperatingMode::Mode3 if !self.gate => {
+                self.null_count = true;
+                false
+            }
+            _ => {
+                self.start = Some(now);
+                self.paused = if self.gate { None } else { Some(0) };
+                self.null_count = false;
+                true
+            }
         }
     }
+
+    /// The delay until the counter's output rises, and whether it then
+    /// rises periodically
+    fn interrupt(&self) -> Option<(Duration, bool)> {
+        let reload = self.reload?;
+        self.start?;
+        let periodic = match self.mode {
+            OperatingMode::Mode2 | OperatingMode::Mode3 => true,
+            _ => false,
+        };
+        Some((ticks_duration(reload), periodic))
+    }
 }
 
-#[derive(Default, Debug)]
+#[derive(Debug)]
 pub struct Pit8254 {
-    channel0: ChannelState,
-    // channel1 is not supported
-    channel2: ChannelState,
+    counters: [Counter; 3],
+    // The timer delivering IRQ0 for counter 0
+    timer: Option<time::TimerId>,
+    // The writable bits of port 0x61
+    port_b: u8,
+}
+
+impl Default for Pit8254 {
+    fn default() -> Self {
+        // Port 0x61 resets to zero, so counter 2 is gated off
+        let mut counters = <[Counter; 3]>::default();
+        counters[2].gate = false;
+        Pit8254 {
+            counters,
+            timer: None,
+            port_b: 0,
+        }
+    }
 }
 
 impl Pit8254 {
@@ -64,196 +300,127 @@ impl Pit8254 {
         Arc::new(RwLock::new(Pit8254::default()))
     }
 
-    fn on_port_read(
-        &mut self,
-        port: Port,
-        mut val: PortReadRequest,
-    ) -> Result<()> {
+    fn read_port_b(&self, now: Duration) -> u8 {
+        let refresh = (now.as_nanos() / REFRESH_PERIOD_NS) as u8 & 1;
+        let out2 = self.counters[2].output(now) as u8;
+        self.port_b
+            | refresh << PORT_B_REFRESH_SHIFT
+            | out2 << PORT_B_OUT2_SHIFT
+    }
+
+    fn write_port_b(&mut self, val: u8, now: Duration) {
+        self.port_b = val & PORT_B_WRITABLE;
+        self.counters[2].set_gate(val & PORT_B_GATE2 != 0, now);
+    }
+
+    fn read(&mut self, port: Port, now: Duration) -> Option<u8> {
         match port {
-            //FIXME: much of this 'PS2' handling is a hack. I'm not aware of
-            // a good source for exactly what's supposed to happen here.
+            PIT_COUNTER_0..=PIT_COUNTER_2 => {
+                Some(self.counters[(port - PIT_COUNTER_0) as usize].read(now))
+            }
+            PIT_PS2_CTRL_B => Some(self.read_port_b(now)),
+            _ => None,
+        }
+    }
+
+    /// Handle a write to the PIT, returning true if counter 0 (and so the
+    /// IRQ0 timer) has changed
+    fn write(&mut self, port: Port, val: u8, now: Duration) -> Result<bool> {
+        match port {
+            PIT_MODE_CONTROL => self.write_control(val, now),
+            PIT_COUNTER_0..=PIT_COUNTER_2 => {
+                let counter = (port - PIT_COUNTER_0) as usize;
+                Ok(self.counters[counter].write(val, now) && counter == 0)
+            }
             PIT_PS2_CTRL_B => {
-                if let OperatingModeState::Mode0 {
-                    start_time,
-                    start_counter,
-                    ..
-                } = self.channel2.mode
-                {
-                    match (start_time, start_counter) {
-                        (Some(start_time), Some(start_counter)) => {
-                            let duration = time::now() - start_time;
-                            let ticks =
-                                duration.as_nanos() / PIT_NS_PER_TICK as u128;
-                            if ticks as u16 > start_counter {
-                                val.copy_from_u32(1 << 5);
-                            }
-                        }
-                        _ => (),
-                    }
-                }
+                self.write_port_b(val, now);
+                Ok(false)
             }
             _ => {
-                info!("PIT read from unsupported port");
+                info!("PIT: write to unsupported port: 0x{:x}", port);
+                Ok(false)
             }
         }
-        Ok(())
     }
 
-    fn on_port_write(
-        &mut self,
-        port: Port,
-        val: PortWriteRequest,
-    ) -> Result<()> {
-        match port {
-            PIT_MODE_CONTROL => {
-                let val = u8::try_from(val)?;
-                let channel = (0b11000000 & val) >> 6;
-                let access = (0b00110000 & val) >> 4;
-                let operating = (0b00001110 & val) >> 1;
-
-                if val & 0b00000001 != 0 {
-                    return Err(Error::InvalidValue(
-                        "PIT BCD mode is not supported".into(),
-                    ));
-                }
-
-                let operating_state = match operating {
-                    0b000 => OperatingModeState::Mode0 {
-                        start_counter: None,
-                        timer: None,
-                        start_time: None,
-                    },
-                    0b010 => OperatingModeState::Mode2 {
-                        start_counter: None,
-                        timer: None,
-                        start_time: None,
-                    },
-                    value => {
-                        return Err(Error::InvalidValue(format!(
-                            "Invalid PIT operating state '0x{:x}'",
-                            value
-                        )))
-                    }
-                };
-
-                let access_state = match access {
-                    0b00 => AccessModeState::LatchCount,
-                    0b01 => AccessModeState::LoByte,
-                    0b10 => AccessModeState::HiByte,
-                    0b11 => AccessModeState::Word { lo_byte: None },
-                    value => {
-                        return Err(Error::InvalidValue(format!(
-                            "Invalid PIT access state '0x{:x}'",
-                            value
-                        )))
-                    }
-                };
-
-                let channel_state = ChannelState {
-                    mode: operating_state,
-                    access: access_state,
-                };
-
-                let current_channel = match channel {
-                    0b00 => &mut self.channel0,
-                    0b10 => &mut self.channel2,
-                    value => {
-                        return Err(Error::InvalidValue(format!(
-                            "Invalid PIT channel '0x{:x}'",
-                            value
-                        )))
-                    }
-                };
-
-                // Stop any running timers
-                match &current_channel.mode {
-                    OperatingModeState::Mode0 { ref timer, .. } => timer,
-                    OperatingModeState::Mode2 { ref timer, .. } => timer,
-                }
-                .as_ref()
-                .map(|id| time::cancel_timer(id));
+    fn write_control(&mut self, val: u8, now: Duration) -> Result<bool> {
+        if val & 0b00000001 != 0 {
+            return Err(Error::InvalidValue(
+                "PIT BCD mode is not supported".into(),
+            ));
+        }
+        let channel = Channel::try_from((val & 0b11000000) >> 6)?;
+        let access = AccessMode::try_from((val & 0b00110000) >> 4)?;
 
-                *current_channel = channel_state;
+        let counter = match channel {
+            Channel::ReadBack => {
+                self.read_back(val, now);
+                return Ok(false);
             }
-            port @ PIT_COUNTER_0..=PIT_COUNTER_2 => {
-                let val = u8::try_from(val)?;
-                let channel_state = match port {
-                    PIT_COUNTER_0 => &mut self.channel0,
-                    PIT_COUNTER_1 => {
-                        return Err(Error::InvalidValue(format!(
-                            "Invalid PIT port '0x{:x}'",
-                            port
-                        )))
-                    }
-                    PIT_COUNTER_2 => &mut self.channel2,
-                    _ => unreachable!(),
-                };
-
-                let counter = match channel_state.access {
-                    AccessModeState::LoByte => val as u16,
-                    AccessModeState::HiByte => (val as u16) << 8,
-                    AccessModeState::Word { ref mut lo_byte } => {
-                        if let Some(lo_byte) = lo_byte {
-                            ((val as u16) << 8) | (*lo_byte as u16)
-                        } else {
-                            // We are just setting the low byte in word access mode.
-                            // There is nothing else to do, so return.
-                            *lo_byte = Some(val);
-                            return Ok(());
-                        }
-                    }
-                    _ => unreachable!(),
-                };
+            channel => channel as usize,
+        };
+        if let AccessMode::LatchCount = access {
+            self.counters[counter].latch_count(now);
+            return Ok(false);
+        }
 
-                if counter == 0 {
-                    warn!("PIT: ignoring counter set to 0");
-                    return Ok(());
-                }
+        let mode = OperatingMode::try_from((val & 0b00001110) >> 1)?;
+        self.counters[counter].program(access, mode);
+        Ok(counter == 0)
+    }
 
-                let duration = core::time::Duration::from_nanos(
-                    PIT_NS_PER_TICK * counter as u64,
-                );
-
-                match channel_state.mode {
-                    OperatingModeState::Mode0 {
-                        ref mut start_counter,
-                        ref mut timer,
-                        ref mut start_time,
-                    } => {
-                        *start_counter = Some(counter);
-                        *start_time = Some(time::now());
-
-                        // Only channel 0 produces timer interrupts
-                        if port == PIT_COUNTER_0 {
-                            //FIXME: this value should be determined by the virtual
-                            // PIC/APIC. Currently use the vector linux has for IRQ0
-                            *timer =
-                                Some(time::set_oneshot_timer(duration, 48));
-                        }
-                    }
+    fn read_back(&mut self, val: u8, now: Duration) {
+        for (i, counter) in self.counters.iter_mut().enumerate() {
+            if val & (1 << (i + 1)) == 0 {
+                continue;
+            }
+            // The latch bits are active low
+            if val & (1 << 5) == 0 {
+                counter.latch_count(now);
+            }
+            if val & (1 << 4) == 0 {
+                counter.latch_status(now);
+            }
+        }
+    }
 
-                    OperatingModeState::Mode2 {
-                        ref mut start_counter,
-                        ref mut timer,
-                        ref mut start_time,
-                    } => {
-                        *start_counter = Some(counter);
-                        *start_time = Some(time::now());
-
-                        if port == PIT_COUNTER_0 {
-                            //FIXME: this value should be determined by the virtual
-                            // PIC/APIC. Currently use the vector linux has for IRQ0
-                            *timer =
-                                Some(time::set_periodic_timer(duration, 48));
-                        }
-                    }
-                };
+    fn update_timer(&mut self) -> Result<()> {
+        if let Some(timer) = self.timer.take() {
+            time::cancel_timer(&timer)?;
+        }
+        self.timer = match self.counters[0].interrupt() {
+            Some((period, true)) => {
+                Some(time::set_periodic_timer(period, IRQ0_VECTOR))
             }
-            _ => {
-                info!("PIT: write to unsupported port: 0x{:x}", port);
+            Some((delay, false)) => {
+                Some(time::set_oneshot_timer(delay, IRQ0_VECTOR))
             }
+            None => None,
+        };
+        Ok(())
+    }
+
+    fn on_port_read(
+        &mut self,
+        port: Port,
+        mut val: PortReadRequest,
+    ) -> Result<()> {
+        match self.read(port, timekeeping::guest_uptime()) {
+            Some(byte) => val.copy_from_u32(byte as u32),
+            None => info!("PIT read from unsupported port: 0x{:x}", port),
         }
+        Ok(())
+    }
 
+    fn on_port_write(
+        &mut self,
+        port: Port,
+        val: PortWriteRequest,
+    ) -> Result<()> {
+        let val = u8::try_from(val)?;
+        if self.write(port, val, timekeeping::guest_uptime())? {
+            self.update_timer()?;
+        }
         Ok(())
     }
 }
@@ -277,3 +444,145 @@ impl EmulatedDevice for Pit8254 {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn at(ticks: u64) -> Duration {
+        ticks_duration(ticks)
+    }
+
+    fn write_count(pit: &mut Pit8254, port: Port, count: u16, now: u64) {
+        pit.write(port, count as u8, at(now)).unwrap();
+        pit.write(port, (count >> 8) as u8, at(now)).unwrap();
+    }
+
+    // The output of counter 2, as seen through port 0x61
+    fn out2(pit: &mut Pit8254, now: u64) -> u8 {
+        pit.read(PIT_PS2_CTRL_B, at(now)).unwrap() >> PORT_B_OUT2_SHIFT & 1
+    }
+
+    fn read_count(pit: &mut Pit8254, port: Port, now: u64) -> u16 {
+        let lo = pit.read(port, at(now)).unwrap() as u16;
+        lo | (pit.read(port, at(now)).unwrap() as u16) << 8
+    }
+
+    #[test]
+    fn test_pit_mode0() {
+        let mut pit = Pit8254::default();
+        // Counter 0, word access, mode 0
+        assert!(pit.write(PIT_MODE_CONTROL, 0x30, at(0)).unwrap());
+        assert!(!pit.counters[0].output(at(0)));
+        pit.write(PIT_COUNTER_0, 0x00, at(0)).unwrap();
+        assert!(pit.write(PIT_COUNTER_0, 0x10, at(10)).unwrap());
+
+        assert_eq!(read_count(&mut pit, PIT_COUNTER_0, 10), 0x1000);
+        assert_eq!(read_count(&mut pit, PIT_COUNTER_0, 0x10), 0xffa);
+        assert!(!pit.counters[0].output(at(0x1000)));
+        assert!(pit.counters[0].output(at(0x100a)));
+        assert_eq!(
+            pit.counters[0].interrupt(),
+            Some((ticks_duration(0x1000), false))
+        );
+    }
+
+    #[test]
+    fn test_pit_periodic_modes() {
+        let mut pit = Pit8254::default();
+        // Counter 0, word access, mode 2
+        pit.write(PIT_MODE_CONTROL, 0x34, at(0)).unwrap();
+        write_count(&mut pit, PIT_COUNTER_0, 100, 0);
+        assert_eq!(read_count(&mut pit, PIT_COUNTER_0, 250), 50);
+        assert!(!pit.counters[0].output(at(99)));
+        assert!(pit.counters[0].output(at(100)));
+        assert_eq!(
+            pit.counters[0].interrupt(),
+            Some((ticks_duration(100), true))
+        );
+
+        // Counter 2, word access, mode 3 (with its gate enabled)
+        pit.write(PIT_PS2_CTRL_B, PORT_B_GATE2, at(0)).unwrap();
+        assert!(!pit.write(PIT_MODE_CONTROL, 0xb6, at(0)).unwrap());
+        write_count(&mut pit, PIT_COUNTER_2, 100, 0);
+        assert_eq!(read_count(&mut pit, PIT_COUNTER_2, 10), 80);
+        assert_eq!(read_count(&mut pit, PIT_COUNTER_2, 60), 80);
+        assert_eq!(out2(&mut pit, 10), 1);
+        assert_eq!(out2(&mut pit, 60), 0);
+
+        // A count of zero is 0x10000
+        pit.write(PIT_MODE_CONTROL, 0x74, at(0)).unwrap();
+        write_count(&mut pit, PIT_COUNTER_1, 0, 0);
+        assert_eq!(read_count(&mut pit, PIT_COUNTER_1, 1), 0xffff);
+    }
+
+    #[test]
+    fn test_pit_byte_access() {
+        let mut pit = Pit8254::default();
+        pit.write(PIT_PS2_CTRL_B, PORT_B_GATE2, at(0)).unwrap();
+        // Counter 2, high byte only, mode 0
+        pit.write(PIT_MODE_CONTROL, 0xa0, at(0)).unwrap();
+        pit.write(PIT_COUNTER_2, 0x02, at(0)).unwrap();
+        assert_eq!(pit.read(PIT_COUNTER_2, at(0x10)), Some(0x01));
+        assert_eq!(pit.read(PIT_COUNTER_2, at(0x10)), Some(0x01));
+
+        // Counter 2, low byte only, mode 4
+        pit.write(PIT_MODE_CONTROL, 0x98, at(0)).unwrap();
+        pit.write(PIT_COUNTER_2, 0x20, at(0)).unwrap();
+        assert_eq!(pit.read(PIT_COUNTER_2, at(0x10)), Some(0x10));
+        assert!(!pit.counters[2].output(at(0x20)));
+        assert!(pit.counters[2].output(at(0x21)));
+    }
+
+    #[test]
+    fn test_pit_latch_and_read_back() {
+        let mut pit = Pit8254::default();
+        pit.write(PIT_MODE_CONTROL, 0x34, at(0)).unwrap();
+        write_count(&mut pit, PIT_COUNTER_0, 1000, 0);
+
+        // The latched count is returned until it is read
+        pit.write(PIT_MODE_CONTROL, 0x00, at(100)).unwrap();
+        pit.write(PIT_MODE_CONTROL, 0x00, at(200)).unwrap();
+        assert_eq!(read_count(&mut pit, PIT_COUNTER_0, 300), 900);
+        assert_eq!(read_count(&mut pit, PIT_COUNTER_0, 300), 700);
+
+        // Read back the status, then the count, of counter 0
+        pit.write(PIT_MODE_CONTROL, 0xc2, at(400)).unwrap();
+        assert_eq!(pit.read(PIT_COUNTER_0, at(500)), Some(0b10110100));
+        assert_eq!(read_count(&mut pit, PIT_COUNTER_0, 500), 600);
+
+        // A counter with no count reports a null count
+        pit.write(PIT_MODE_CONTROL, 0x72, at(0)).unwrap();
+        pit.write(PIT_MODE_CONTROL, 0xe4, at(0)).unwrap();
+        assert_eq!(pit.read(PIT_COUNTER_1, at(0)), Some(0b11110010));
+
+        assert!(pit.write(PIT_MODE_CONTROL, 0x31, at(0)).is_err());
+    }
+
+    #[test]
+    fn test_port_b() {
+        let mut pit = Pit8254::default();
+        let port_b = |pit: &mut Pit8254, ns: u64| {
+            pit.read(PIT_PS2_CTRL_B, Duration::from_nanos(ns)).unwrap()
+        };
+
+        // The refresh request toggles every 15us
+        assert_eq!(port_b(&mut pit, 0), 0);
+        assert_eq!(port_b(&mut pit, 15_100), 1 << 4);
+        assert_eq!(port_b(&mut pit, 30_200), 0);
+
+        // Counter 2 does not count (and its output stays low in mode 0)
+        // until its gate is enabled, and then pauses while it is low
+        pit.write(PIT_MODE_CONTROL, 0xb0, at(0)).unwrap();
+        write_count(&mut pit, PIT_COUNTER_2, 100, 0);
+        assert_eq!(read_count(&mut pit, PIT_COUNTER_2, 50), 100);
+        pit.write(PIT_PS2_CTRL_B, 0x03, at(50)).unwrap();
+        assert_eq!(read_count(&mut pit, PIT_COUNTER_2, 80), 70);
+        pit.write(PIT_PS2_CTRL_B, 0x02, at(80)).unwrap();
+        assert_eq!(read_count(&mut pit, PIT_COUNTER_2, 500), 70);
+        pit.write(PIT_PS2_CTRL_B, 0x01, at(500)).unwrap();
+        assert_eq!(out2(&mut pit, 569), 0);
+        assert_eq!(out2(&mut pit, 571), 1);
+        assert_eq!(pit.read(PIT_PS2_CTRL_B, at(0)), Some(0x01));
+    }
+}
@@ -9,9 +9,9 @@ pub struct ProgrammableOptionSelect;
 
 impl ProgrammableOptionSelect {
     const POS_ARBITRATION_CLOCK: Port = 0x90;
-    const _POS_CARD_SELECT_FEEDBACK: Port = 0x91;
-    const _POS_CONTROL_AND_STATUS: Port = 0x92;
-    const _POS_RESERVED_1: Port = 0x93;
+    const POS_CARD_SELECT_FEEDBACK: Port = 0x91;
+    // Port 0x92 is System Control Port A (see `sysctrl`)
+    const POS_RESERVED_1: Port = 0x93;
     const _POS_BOARD_ENABLE_SETUP: Port = 0x94;
     const _POS_RESERVED_2: Port = 0x95;
     const POS_ADAPTER_ENABLE_SETUP: Port = 0x96;
@@ -25,9 +25,14 @@ impl ProgrammableOptionSelect {
 // need to either (kvm doesn't seem to)
 impl EmulatedDevice for ProgrammableOptionSelect {
     fn services(&self) -> Vec<DeviceRegion> {
-        vec![DeviceRegion::PortIo(
-            Self::POS_ARBITRATION_CLOCK..=Self::POS_ADAPTER_ENABLE_SETUP,
-        )]
+        vec![
+            DeviceRegion::PortIo(
+                Self::POS_ARBITRATION_CLOCK..=Self::POS_CARD_SELECT_FEEDBACK,
+            ),
+            DeviceRegion::PortIo(
+                Self::POS_RESERVED_1..=Self::POS_ADAPTER_ENABLE_SETUP,
+            ),
+        ]
     }
 
     fn on_event(&mut self, event: Event) -> Result<()> {
@@ -0,0 +1,515 @@
+//! Emulation of the i8042 PS/2 controller and an attached keyboard
+//!
+//! The controller has a single output buffer, so the bytes produced by the
+//! controller, the keyboard and the (absent) auxiliary device are queued
+//! and moved into it one at a time as the guest reads port 0x60. IRQ1 is
+//! raised for each keyboard byte that reaches the output buffer while
+//! keyboard interrupts are enabled in the command byte.
+//!
+//! Pulsing the reset line (through the output port or command 0xFE)
+//! requests a reset of the guest.
+
+use crate::error::Result;
+use crate::vcpu;
+use crate::virtdev::pic::LEGACY_IRQ_VECTOR_BASE;
+use crate::virtdev::{
+    DeviceEvent, DeviceEventResponse, DeviceRegion, EmulatedDevice, Event, Port,
+};
+use alloc::collections::vec_deque::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use core::convert::TryFrom;
+use spin::RwLock;
+
+// The maximum number of bytes waiting for the output buffer. Further
+// scancodes are dropped, as the keyboard would when its buffer overflows.
+const MAX_QUEUED_BYTES: usize = 16;
+
+const KEYBOARD_IRQ: u8 = 1;
+const AUX_IRQ: u8 = 12;
+
+bitflags! {
+    /// The controller status register (port 0x64)
+    pub struct Status: u8 {
+        const OUTPUT_FULL = 1 << 0;
+        const INPUT_FULL = 1 << 1;
+        const SYSTEM = 1 << 2;
+        const COMMAND = 1 << 3;
+        const UNLOCKED = 1 << 4;
+        const AUX_OUTPUT_FULL = 1 << 5;
+        const TIMEOUT = 1 << 6;
+        const PARITY_ERROR = 1 << 7;
+    }
+}
+
+bitflags! {
+    /// The controller command byte
+    pub struct CommandByte: u8 {
+        const KEYBOARD_INTERRUPT = 1 << 0;
+        const AUX_INTERRUPT = 1 << 1;
+        const SYSTEM = 1 << 2;
+        const KEYBOARD_DISABLED = 1 << 4;
+        const AUX_DISABLED = 1 << 5;
+        const TRANSLATE = 1 << 6;
+    }
+}
+
+bitflags! {
+    /// The controller output port
+    pub struct OutputPort: u8 {
+        /// The reset line (active low)
+        const RESET = 1 << 0;
+        const A20 = 1 << 1;
+        const KEYBOARD_OUTPUT_FULL = 1 << 4;
+        const AUX_OUTPUT_FULL = 1 << 5;
+    }
+}
+
+// Controller commands
+const CMD_READ_COMMAND_BYTE: u8 = 0x20;
+const CMD_WRITE_COMMAND_BYTE: u8 = 0x60;
+const CMD_DISABLE_AUX: u8 = 0xa7;
+const CMD_ENABLE_AUX: u8 = 0xa8;
+const CMD_TEST_AUX: u8 = 0xa9;
+const CMD_SELF_TEST: u8 = 0xaa;
+const CMD_TEST_KEYBOARD: u8 = 0xab;
+const CMD_DISABLE_KEYBOARD: u8 = 0xad;
+const CMD_ENABLE_KEYBOARD: u8 = 0xae;
+const CMD_READ_INPUT_PORT: u8 = 0xc0;
+const CMD_READ_OUTPUT_PORT: u8 = 0xd0;
+const CMD_WRITE_OUTPUT_PORT: u8 = 0xd1;
+const CMD_WRITE_KEYBOARD_OUTPUT: u8 = 0xd2;
+const CMD_WRITE_AUX_OUTPUT: u8 = 0xd3;
+const CMD_WRITE_AUX: u8 = 0xd4;
+
+// Keyboard commands
+const KBD_SET_LEDS: u8 = 0xed;
+const KBD_ECHO: u8 = 0xee;
+const KBD_SCANCODE_SET: u8 = 0xf0;
+const KBD_IDENTIFY: u8 = 0xf2;
+const KBD_SET_TYPEMATIC: u8 = 0xf3;
+const KBD_ENABLE: u8 = 0xf4;
+const KBD_DISABLE: u8 = 0xf5;
+const KBD_SET_DEFAULTS: u8 = 0xf6;
+const KBD_RESEND: u8 = 0xfe;
+const KBD_RESET: u8 = 0xff;
+
+// Keyboard responses
+const KBD_ACK: u8 = 0xfa;
+const KBD_SELF_TEST_PASSED: u8 = 0xaa;
+const KBD_ID: [u8; 2] = [0xab, 0x83];
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const INTERFACE_TEST_PASSED: u8 = 0x00;
+
+// The device a byte in the output buffer came from
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Source {
+    Controller,
+    Keyboard,
+    Aux,
+}
+
+// The destination of the next byte written to the data port
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DataTarget {
+    Keyboard,
+    CommandByte,
+    OutputPort,
+    KeyboardOutput,
+    AuxOutput,
+    Aux,
+    // The argument of a keyboard command
+    KeyboardArgument(u8),
+}
+
+#[derive(Debug)]
+pub struct Ps2Controller {
+    command_byte: CommandByte,
+    output_port: OutputPort,
+    status: Status,
+    output: Option<(u8, Source)>,
+    queue: VecDeque<(u8, Source)>,
+    target: DataTarget,
+    scanning: bool,
+    scancode_set: u8,
+    // The IRQ to raise when the current access completes
+    irq: Option<u8>,
+    reset_requested: bool,
+}
+
+impl Default for Ps2Controller {
+    fn default() -> Self {
+        Self {
+            command_byte: CommandByte::KEYBOARD_INTERRUPT
+                | CommandByte::SYSTEM
+                | CommandByte::TRANSLATE,
+            output_port: OutputPort::RESET | OutputPort::A20,
+            status: Status::SYSTEM | Status::UNLOCKED,
+            output: None,
+            queue: VecDeque::new(),
+            target: DataTarget::Keyboard,
+            scanning: true,
+            scancode_set: 2,
+            irq: None,
+            reset_requested: false,
+        }
+    }
+}
+
+impl Ps2Controller {
+    const PS2_DATA: Port = 0x0060;
+    const PS2_STATUS: Port = 0x0064;
+
+    pub fn new() -> Arc<RwLock<Self>> {
+        Arc::new(RwLock::new(Self::default()))
+    }
+
+    /// Returns true if the guest enabled the A20 line through the output
+    /// port
+    pub fn a20_enabled(&self) -> bool {
+        self.output_port.contains(OutputPort::A20)
+    }
+
+    /// Queue scancodes from the keyboard, returning the number queued
+    ///
+    /// Scancodes are dropped while the guest has disabled the keyboard or
+    /// its scanning, or when the queue is full.
+    pub fn queue_scancodes(&mut self, scancodes: &[u8]) -> usize {
+        if !self.scanning
+            || self.command_byte.contains(CommandByte::KEYBOARD_DISABLED)
+        {
+            return 0;
+        }
+        let count = scancodes
+            .len()
+            .min(MAX_QUEUED_BYTES.saturating_sub(self.queue.len()));
+        for scancode in &scancodes[..count] {
+            self.queue.push_back((*scancode, Source::Keyboard));
+        }
+        self.fill_output();
+        count
+    }
+
+    fn respond(&mut self, source: Source, bytes: &[u8]) {
+        for byte in bytes {
+            if self.queue.len() < MAX_QUEUED_BYTES {
+                self.queue.push_back((*byte, source));
+            }
+        }
+        self.fill_output();
+    }
+
+    // Move the next queued byte into the empty output buffer, raising its
+    // interrupt if enabled
+    fn fill_output(&mut self) {
+        if self.output.is_some() {
+            return;
+        }
+        let (byte, source) = match self.queue.pop_front() {
+            Some(next) => next,
+            None => return,
+        };
+        self.output = Some((byte, source));
+        self.status.insert(Status::OUTPUT_FULL);
+        self.status
+            .set(Status::AUX_OUTPUT_FULL, source == Source::Aux);
+        self.irq = match source {
+            Source::Aux
+                if self.command_byte.contains(CommandByte::AUX_INTERRUPT) =>
+            {
+                Some(AUX_IRQ)
+            }
+            Source::Keyboard
+                if self
+                    .command_byte
+                    .contains(CommandByte::KEYBOARD_INTERRUPT) =>
+            {
+                Some(KEYBOARD_IRQ)
+            }
+            _ => None,
+        };
+    }
+
+    fn read_data(&mut self) -> u8 {
+        match self.output.take() {
+            Some((byte, _)) => {
+                self.status
+                    .remove(Status::OUTPUT_FULL | Status::AUX_OUTPUT_FULL);
+                self.fill_output();
+                byte
+            }
+            None => 0,
+        }
+    }
+
+    fn read_output_port(&self) -> u8 {
+        let mut port = self.output_port;
+        if let Some((_, source)) = self.output {
+            port.insert(match source {
+                Source::Aux => OutputPort::AUX_OUTPUT_FULL,
+                _ => OutputPort::KEYBOARD_OUTPUT_FULL,
+            });
+        }
+        port.bits()
+    }
+
+    fn write_output_port(&mut self, val: u8) {
+        self.output_port = OutputPort::from_bits_truncate(val);
+        if !self.output_port.contains(OutputPort::RESET) {
+            self.reset_requested = true;
+            self.output_port.insert(OutputPort::RESET);
+        }
+    }
+
+    fn write_command(&mut self, val: u8) {
+        self.status.insert(Status::COMMAND);
+        match val {
+            CMD_READ_COMMAND_BYTE => {
+                self.respond(Source::Controller, &[self.command_byte.bits()])
+            }
+            CMD_WRITE_COMMAND_BYTE => self.target = DataTarget::CommandByte,
+            CMD_DISABLE_AUX => {
+                self.command_byte.insert(CommandByte::AUX_DISABLED)
+            }
+            CMD_ENABLE_AUX => {
+                self.command_byte.remove(CommandByte::AUX_DISABLED)
+            }
+            CMD_TEST_AUX | CMD_TEST_KEYBOARD => {
+                self.respond(Source::Controller, &[INTERFACE_TEST_PASSED])
+            }
+            CMD_SELF_TEST => {
+                self.status.insert(Status::SYSTEM);
+                self.respond(Source::Controller, &[SELF_TEST_PASSED])
+            }
+            CMD_DISABLE_KEYBOARD => {
+                self.command_byte.insert(CommandByte::KEYBOARD_DISABLED)
+            }
+            CMD_ENABLE_KEYBOARD => {
+                self.command_byte.remove(CommandByte::KEYBOARD_DISABLED)
+            }
+            // The keyboard is unlocked (bit 7) and color video (bit 6) is
+            // reported
+            CMD_READ_INPUT_PORT => self.respond(Source::Controller, &[0xc0]),
+            CMD_READ_OUTPUT_PORT => {
+                let port = self.read_output_port();
+                self.respond(Source::Controller, &[port])
+            }
+            CMD_WRITE_OUTPUT_PORT => self.target = DataTarget::OutputPort,
+            CMD_WRITE_KEYBOARD_OUTPUT => {
+                self.target = DataTarget::KeyboardOutput
+            }
+            CMD_WRITE_AUX_OUTPUT => self.target = DataTarget::AuxOutput,
+            CMD_WRITE_AUX => self.target = DataTarget::Aux,
+            // Pulse the output port lines in the low nibble (active low)
+            0xf0..=0xff => {
+                if val & OutputPort::RESET.bits() == 0 {
+                    self.reset_requested = true;
+                }
+            }
+            _ => info!("PS/2: unsupported controller command 0x{:x}", val),
+        }
+    }
+
+    fn write_data(&mut self, val: u8) {
+        self.status.remove(Status::COMMAND);
+        let target = self.target;
+        self.target = DataTarget::Keyboard;
+        match target {
+            DataTarget::Keyboard => self.write_keyboard(val),
+            DataTarget::KeyboardArgument(command) => {
+                self.write_keyboard_argument(command, val)
+            }
+            DataTarget::CommandByte => {
+                self.command_byte = CommandByte::from_bits_truncate(val);
+                self.status.set(
+                    Status::SYSTEM,
+                    self.command_byte.contains(CommandByte::SYSTEM),
+                );
+            }
+            DataTarget::OutputPort => self.write_output_port(val),
+            DataTarget::KeyboardOutput => {
+                self.respond(Source::Keyboard, &[val])
+            }
+            DataTarget::AuxOutput => self.respond(Source::Aux, &[val]),
+            // There is no auxiliary device, so its commands time out
+            DataTarget::Aux => self.status.insert(Status::TIMEOUT),
+        }
+    }
+
+    fn write_keyboard(&mut self, val: u8) {
+        // Writing to the keyboard enables its interface
+        self.command_byte.remove(CommandByte::KEYBOARD_DISABLED);
+        match val {
+            KBD_SET_LEDS | KBD_SCANCODE_SET | KBD_SET_TYPEMATIC => {
+                self.target = DataTarget::KeyboardArgument(val);
+                self.respond(Source::Keyboard, &[KBD_ACK]);
+            }
+            KBD_ECHO => self.respond(Source::Keyboard, &[KBD_ECHO]),
+            KBD_IDENTIFY => {
+                self.respond(Source::Keyboard, &[KBD_ACK]);
+                self.respond(Source::Keyboard, &KBD_ID);
+            }
+            KBD_ENABLE => {
+                self.scanning = true;
+                self.respond(Source::Keyboard, &[KBD_ACK]);
+            }
+            KBD_DISABLE | KBD_SET_DEFAULTS => {
+                self.scanning = val != KBD_DISABLE;
+                self.scancode_set = 2;
+                self.respond(Source::Keyboard, &[KBD_ACK]);
+            }
+            KBD_RESET => {
+                self.scanning = true;
+                self.scancode_set = 2;
+                self.queue.retain(|(_, source)| *source != Source::Keyboard);
+                self.respond(Source::Keyboard, &[KBD_ACK]);
+                self.respond(Source::Keyboard, &[KBD_SELF_TEST_PASSED]);
+            }
+            _ => self.respond(Source::Keyboard, &[KBD_RESEND]),
+        }
+    }
+
+    fn write_keyboard_argument(&mut self, command: u8, val: u8) {
+        match command {
+            // Report the current scancode set
+            KBD_SCANCODE_SET if val == 0 => {
+                self.respond(Source::Keyboard, &[KBD_ACK, self.scancode_set])
+            }
+            KBD_SCANCODE_SET if val <= 3 => {
+                self.scancode_set = val;
+                self.respond(Source::Keyboard, &[KBD_ACK])
+            }
+            KBD_SCANCODE_SET => self.respond(Source::Keyboard, &[KBD_RESEND]),
+            _ => self.respond(Source::Keyboard, &[KBD_ACK]),
+        }
+    }
+
+    fn read(&mut self, port: Port) -> u8 {
+        match port {
+            Self::PS2_DATA => self.read_data(),
+            _ => {
+                let status = self.status.bits();
+                self.status.remove(Status::TIMEOUT);
+                status
+            }
+        }
+    }
+
+    fn write(&mut self, port: Port, val: u8) {
+        match port {
+            Self::PS2_DATA => self.write_data(val),
+            _ => self.write_command(val),
+        }
+    }
+}
+
+impl EmulatedDevice for Ps2Controller {
+    fn services(&self) -> Vec<DeviceRegion> {
+        vec![
+            DeviceRegion::PortIo(Self::PS2_DATA..=Self::PS2_DATA),
+            DeviceRegion::PortIo(Self::PS2_STATUS..=Self::PS2_STATUS),
+        ]
+    }
+
+    fn on_event(&mut self, event: Event) -> Result<()> {
+        match event.kind {
+            DeviceEvent::PortRead(port, mut val) => {
+                let data = self.read(port);
+                val.copy_from_u32(data as u32);
+            }
+            DeviceEvent::PortWrite(port, val) => {
+                self.write(port, u8::try_from(val)?)
+            }
+            _ => (),
+        }
+
+        if let Some(irq) = self.irq.take() {
+            event.responses.push(DeviceEventResponse::Interrupt((
+                LEGACY_IRQ_VECTOR_BASE + irq,
+                vcpu::InjectedInterruptType::ExternalInterrupt,
+            )));
+        }
+        if self.reset_requested {
+            self.reset_requested = false;
+            event.responses.push(DeviceEventResponse::ResetRequested);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read_all(ps2: &mut Ps2Controller) -> Vec<u8> {
+        let mut bytes = vec![];
+        while ps2.read(Ps2Controller::PS2_STATUS) & Status::OUTPUT_FULL.bits()
+            != 0
+        {
+            bytes.push(ps2.read(Ps2Controller::PS2_DATA));
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_ps2_controller_commands() {
+        let mut ps2 = Ps2Controller::default();
+        ps2.write(Ps2Controller::PS2_STATUS, CMD_SELF_TEST);
+        assert_eq!(read_all(&mut ps2), vec![SELF_TEST_PASSED]);
+
+        // Disable keyboard interrupts through the command byte
+        ps2.write(Ps2Controller::PS2_STATUS, CMD_WRITE_COMMAND_BYTE);
+        ps2.write(Ps2Controller::PS2_DATA, 0x44);
+        ps2.write(Ps2Controller::PS2_STATUS, CMD_READ_COMMAND_BYTE);
+        assert_eq!(read_all(&mut ps2), vec![0x44]);
+
+        ps2.write(Ps2Controller::PS2_DATA, KBD_RESET);
+        assert_eq!(ps2.irq, None);
+        assert_eq!(read_all(&mut ps2), vec![KBD_ACK, KBD_SELF_TEST_PASSED]);
+
+        // The keyboard raises IRQ1 once interrupts are enabled
+        ps2.write(Ps2Controller::PS2_STATUS, CMD_WRITE_COMMAND_BYTE);
+        ps2.write(Ps2Controller::PS2_DATA, 0x45);
+        ps2.write(Ps2Controller::PS2_DATA, KBD_IDENTIFY);
+        assert_eq!(ps2.irq.take(), Some(KEYBOARD_IRQ));
+        assert_eq!(read_all(&mut ps2), vec![KBD_ACK, 0xab, 0x83]);
+
+        ps2.write(Ps2Controller::PS2_DATA, KBD_SCANCODE_SET);
+        ps2.write(Ps2Controller::PS2_DATA, 0);
+        assert_eq!(read_all(&mut ps2), vec![KBD_ACK, KBD_ACK, 2]);
+    }
+
+    #[test]
+    fn test_ps2_scancodes() {
+        let mut ps2 = Ps2Controller::default();
+        assert_eq!(ps2.queue_scancodes(&[0x1e, 0x9e]), 2);
+        assert_eq!(ps2.irq, Some(KEYBOARD_IRQ));
+        assert_eq!(read_all(&mut ps2), vec![0x1e, 0x9e]);
+
+        ps2.write(Ps2Controller::PS2_DATA, KBD_DISABLE);
+        assert_eq!(read_all(&mut ps2), vec![KBD_ACK]);
+        assert_eq!(ps2.queue_scancodes(&[0x1e]), 0);
+
+        ps2.write(Ps2Controller::PS2_DATA, KBD_ENABLE);
+        assert_eq!(read_all(&mut ps2), vec![KBD_ACK]);
+        assert_eq!(ps2.queue_scancodes(&[0u8; 32]), MAX_QUEUED_BYTES);
+    }
+
+    #[test]
+    fn test_ps2_reset_line() {
+        let mut ps2 = Ps2Controller::default();
+        ps2.write(Ps2Controller::PS2_STATUS, 0xfe);
+        assert!(ps2.reset_requested);
+
+        let mut ps2 = Ps2Controller::default();
+        ps2.write(Ps2Controller::PS2_STATUS, CMD_WRITE_OUTPUT_PORT);
+        ps2.write(Ps2Controller::PS2_DATA, 0x01);
+        assert!(!ps2.reset_requested);
+        assert!(!ps2.a20_enabled());
+        ps2.write(Ps2Controller::PS2_STATUS, CMD_WRITE_OUTPUT_PORT);
+        ps2.write(Ps2Controller::PS2_DATA, 0x00);
+        assert!(ps2.reset_requested);
+    }
+}
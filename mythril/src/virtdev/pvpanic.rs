@@ -0,0 +1,56 @@
+//! The ISA pvpanic device (compatible with QEMU's `pvpanic`)
+//!
+//! The guest reads the events the device supports from its port, and
+//! writes the event that occurred. A panic stops the guest (see
+//! `lifecycle`), while the start of a crash kernel is only logged.
+
+use crate::error::Result;
+use crate::virtdev::{
+    DeviceEvent, DeviceEventResponse, DeviceRegion, EmulatedDevice, Event, Port,
+};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use spin::RwLock;
+
+/// The standard port of the ISA pvpanic device
+pub const PVPANIC_PORT: Port = 0x505;
+
+const PVPANIC_PANICKED: u8 = 1 << 0;
+const PVPANIC_CRASH_LOADED: u8 = 1 << 1;
+
+pub struct PvPanic {
+    port: Port,
+}
+
+impl PvPanic {
+    pub fn new(port: Port) -> Arc<RwLock<Self>> {
+        Arc::new(RwLock::new(Self { port }))
+    }
+}
+
+impl EmulatedDevice for PvPanic {
+    fn services(&self) -> Vec<DeviceRegion> {
+        vec![DeviceRegion::PortIo(self.port..=self.port)]
+    }
+
+    fn on_event(&mut self, event: Event) -> Result<()> {
+        match event.kind {
+            DeviceEvent::PortRead(_port, mut val) => {
+                val.copy_from_u32(
+                    (PVPANIC_PANICKED | PVPANIC_CRASH_LOADED) as u32,
+                );
+            }
+            DeviceEvent::PortWrite(_port, val) => {
+                let val: u8 = val.try_into()?;
+                if val & PVPANIC_PANICKED != 0 {
+                    event.responses.push(DeviceEventResponse::GuestPanicked);
+                } else if val & PVPANIC_CRASH_LOADED != 0 {
+                    info!("Guest panicked and is starting its crash kernel");
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
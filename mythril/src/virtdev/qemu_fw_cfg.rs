@@ -1,3 +1,12 @@
+//! A QEMU compatible fw_cfg device
+//!
+//! The device has the traditional selector (0x510) and data (0x511) ports,
+//! and the DMA interface (at 0x514), so unmodified SeaBIOS and OVMF builds
+//! can read the boot configuration (kernel, initrd and command line, ACPI
+//! and SMBIOS tables, e820 map, etc.). As in QEMU, reads past the end of an
+//! item (or of a missing item) return zeros, and writes to the data port
+//! are ignored. The items are read-only, so DMA writes fail.
+
 use crate::error::{Error, Result};
 use crate::memory::{
     GuestAccess, GuestAddressSpaceViewMut, GuestPhysAddr, GuestVirtAddr,
@@ -56,6 +65,24 @@ pub mod FwCfgSelector {
 
 const FW_CFG_MAX_FILE_NAME: usize = 55;
 
+// The type and header length of a legacy SMBIOS field entry
+const SMBIOS_FIELD_ENTRY: u8 = 0;
+const SMBIOS_FIELD_HEADER_LEN: usize = 6;
+
+/// The offset of the UUID in the SMBIOS system information (type 1) table
+pub const SMBIOS_SYSTEM_UUID_OFFSET: u16 = 8;
+
+/// The type of a region in the 'etc/e820' memory map
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum E820Type {
+    Ram = 1,
+    Reserved = 2,
+    Acpi = 3,
+    Nvs = 4,
+    Unusable = 5,
+}
+
 #[repr(C)]
 struct FWCfgFile {
     size: u32,
@@ -220,6 +247,90 @@ impl QemuFwCfgBuilder {
         Ok(())
     }
 
+    /// Add the 'etc/e820' file describing the guest memory map
+    ///
+    /// Each entry is a (start address, length, type) triple.
+    pub fn add_e820_table(
+        &mut self,
+        entries: &[(u64, u64, E820Type)],
+    ) -> Result<()> {
+        // From QEMU:
+        //
+        // struct e820_entry {
+        //     uint64_t address;
+        //     uint64_t length;
+        //     uint32_t type;
+        // } QEMU_PACKED __attribute((__aligned__(4)));
+        let mut table = Vec::with_capacity(entries.len() * 20);
+        for &(address, length, kind) in entries {
+            table.extend_from_slice(&address.to_le_bytes());
+            table.extend_from_slice(&length.to_le_bytes());
+            table.extend_from_slice(&(kind as u32).to_le_bytes());
+        }
+        self.add_file("etc/e820", &table)
+    }
+
+    /// Add ACPI tables to be installed by the firmware's table loader
+    ///
+    /// `loader` contains the linker/loader commands that allocate `rsdp` and
+    /// `tables` in guest memory and patch the pointers between them.
+    pub fn add_acpi_tables(
+        &mut self,
+        rsdp: &[u8],
+        tables: &[u8],
+        loader: &[u8],
+    ) -> Result<()> {
+        self.add_file("etc/acpi/rsdp", rsdp)?;
+        self.add_file("etc/acpi/tables", tables)?;
+        self.add_file("etc/table-loader", loader)
+    }
+
+    /// Add SMBIOS tables (and their entry point) for the firmware to install
+    pub fn add_smbios_tables(
+        &mut self,
+        anchor: &[u8],
+        tables: &[u8],
+    ) -> Result<()> {
+        self.add_file("etc/smbios/smbios-anchor", anchor)?;
+        self.add_file("etc/smbios/smbios-tables", tables)
+    }
+
+    /// Add a field of an SMBIOS table for the firmware to fill in (a legacy
+    /// SMBIOS entry, used by firmware that builds its own tables)
+    ///
+    /// `offset` is the offset of the field in the formatted area of the
+    /// table of type `table`.
+    pub fn add_smbios_field(&mut self, table: u8, offset: u16, data: &[u8]) {
+        let entries = self
+            .data
+            .entry(FwCfgSelector::X86_SMBIOS_TABLES)
+            .or_insert_with(|| vec![0, 0]);
+        let count = u16::from_le_bytes([entries[0], entries[1]]) + 1;
+        entries[..2].copy_from_slice(&count.to_le_bytes());
+
+        // struct smbios_field {
+        //     uint16_t length;     /* including this header */
+        //     uint8_t type;        /* 0 (a field entry) */
+        //     uint8_t table_type;
+        //     uint16_t offset;
+        //     uint8_t data[];
+        // };
+        let length = (SMBIOS_FIELD_HEADER_LEN + data.len()) as u16;
+        entries.extend_from_slice(&length.to_le_bytes());
+        entries.push(SMBIOS_FIELD_ENTRY);
+        entries.push(table);
+        entries.extend_from_slice(&offset.to_le_bytes());
+        entries.extend_from_slice(data);
+    }
+
+    pub fn add_u16(&mut self, selector: u16, data: u16) {
+        self.data.insert(selector, data.to_le_bytes().to_vec());
+    }
+
+    pub fn add_u64(&mut self, selector: u16, data: u64) {
+        self.data.insert(selector, data.to_le_bytes().to_vec());
+    }
+
     pub fn add_i32(&mut self, selector: u16, data: i32) {
         self.data.insert(selector, data.to_le_bytes().to_vec());
     }
@@ -254,66 +365,65 @@ impl QemuFwCfg {
         let request: RawFWCfgDmaAccess = unsafe {
             core::ptr::read(bytes.as_ptr() as *const RawFWCfgDmaAccess)
         };
-        let mut request: FWCfgDmaAccess = request.into();
+        let request: FWCfgDmaAccess = request.into();
 
         if request.control.contains(DmaControlFlags::SELECT) {
             self.selector = request.select;
             self.data_idx = 0;
-            request.control &= !DmaControlFlags::SELECT;
-        }
-
-        if request.control.contains(DmaControlFlags::SKIP) {
-            self.data_idx = request.length as usize;
-            request.control &= !DmaControlFlags::SKIP;
         }
 
+        // A transfer is a read, a write or a skip (in that order of
+        // precedence), and leaves only the error bit set when complete
+        let length = request.length as usize;
+        let mut error = false;
         if request.control.contains(DmaControlFlags::READ) {
-            match self.read_selector(request.length as usize) {
-                Some(data) => {
-                    space.write_bytes(
-                        GuestVirtAddr::NoPaging(GuestPhysAddr::new(
-                            request.address,
-                        )),
-                        data,
-                        GuestAccess::Read(PrivilegeLevel(0)),
-                    )?;
-                }
-                None => request.control = DmaControlFlags::ERROR.into(),
-            }
-            request.control &= !DmaControlFlags::READ;
-        }
-
-        // We don't support writes at all
-        if request.control.contains(DmaControlFlags::WRITE) {
-            request.control &= !DmaControlFlags::WRITE;
-            request.control |= DmaControlFlags::ERROR;
+            let data = self.read_selector(length);
+            error = space
+                .write_bytes(
+                    GuestVirtAddr::NoPaging(GuestPhysAddr::new(
+                        request.address,
+                    )),
+                    &data,
+                    GuestAccess::Read(PrivilegeLevel(0)),
+                )
+                .is_err();
+        } else if request.control.contains(DmaControlFlags::WRITE) {
+            error = true;
+        } else if request.control.contains(DmaControlFlags::SKIP) {
+            self.data_idx += length;
         }
 
-        let request: RawFWCfgDmaAccess = request.into();
-        let data = unsafe {
-            core::slice::from_raw_parts(
-                (&request as *const RawFWCfgDmaAccess) as *const u8,
-                core::mem::size_of::<RawFWCfgDmaAccess>(),
-            )
+        let control = if error {
+            DmaControlFlags::ERROR.bits() as u32
+        } else {
+            0
         };
         space.write_bytes(
             GuestVirtAddr::NoPaging(GuestPhysAddr::new(self.dma_addr)),
-            data,
+            &control.to_be_bytes(),
             GuestAccess::Read(PrivilegeLevel(0)),
         )?;
 
         Ok(())
     }
 
-    fn read_selector(&mut self, length: usize) -> Option<&[u8]> {
-        if self.data.contains_key(&self.selector) {
-            let data = &self.data[&(self.selector)];
-            let slice = &data[self.data_idx..self.data_idx + length];
-            self.data_idx += length;
-            return Some(slice);
-        } else {
-            None
+    // Read `length` bytes of the selected item at the current offset (with
+    // zeros past the end of the item, or for a missing item)
+    fn read_selector(&mut self, length: usize) -> Vec<u8> {
+        let mut res = vec![0u8; length];
+        match self.data.get(&self.selector) {
+            Some(data) => {
+                let start = self.data_idx.min(data.len());
+                let end = (self.data_idx + length).min(data.len());
+                res[..end - start].copy_from_slice(&data[start..end]);
+            }
+            None if self.data_idx == 0 => {
+                info!("Attempt to read from selector: 0x{:x}", self.selector);
+            }
+            None => (),
         }
+        self.data_idx += length;
+        res
     }
 
     fn on_port_read(
@@ -328,20 +438,7 @@ impl QemuFwCfg {
             }
             Self::FW_CFG_PORT_DATA => {
                 let data = self.read_selector(len);
-                match data {
-                    Some(data) => {
-                        val.as_mut_slice().copy_from_slice(data);
-                    }
-                    None => {
-                        info!(
-                            "Attempt to read from selector: 0x{:x}",
-                            self.selector
-                        );
-
-                        // For now, just return zeros for other fields
-                        val.copy_from_u32(0);
-                    }
-                }
+                val.as_mut_slice().copy_from_slice(&data);
             }
             Self::FW_CFG_PORT_DMA_LOW => {
                 val.copy_from_u32(0x20434647); // " CFG"
@@ -366,9 +463,7 @@ impl QemuFwCfg {
                 self.data_idx = 0;
             }
             Self::FW_CFG_PORT_DATA => {
-                return Err(Error::NotImplemented(
-                    "Write to QEMU FW CFG data port not yet supported".into(),
-                ))
+                debug!("Ignoring write to the fw_cfg data port");
             }
             Self::FW_CFG_PORT_DMA_LOW => {
                 let low = u32::from_be(val.try_into()?);
@@ -414,6 +509,158 @@ impl EmulatedDevice for QemuFwCfg {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::memory::GuestAddressSpace;
+    use crate::virtdev::virtio::{read_guest, write_guest};
+    use crate::virtdev::ResponseEventArray;
+    use core::convert::TryFrom;
+
+    fn send(
+        fw_cfg: &mut QemuFwCfg,
+        kind: DeviceEvent,
+        space: &mut GuestAddressSpace,
+    ) {
+        let mut responses = ResponseEventArray::default();
+        let view = GuestAddressSpaceViewMut::new(GuestPhysAddr::new(0), space);
+        let event = Event::new(kind, view, &mut responses).unwrap();
+        fw_cfg.on_event(event).unwrap();
+    }
+
+    fn write_port(
+        fw_cfg: &mut QemuFwCfg,
+        port: Port,
+        data: &[u8],
+        space: &mut GuestAddressSpace,
+    ) {
+        let request = PortWriteRequest::try_from(data).unwrap();
+        send(fw_cfg, DeviceEvent::PortWrite(port, request), space);
+    }
+
+    fn read_port(
+        fw_cfg: &mut QemuFwCfg,
+        port: Port,
+        len: usize,
+        space: &mut GuestAddressSpace,
+    ) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        let request = PortReadRequest::try_from(&mut data[..]).unwrap();
+        send(fw_cfg, DeviceEvent::PortRead(port, request), space);
+        data
+    }
+
+    fn setup_space() -> GuestAddressSpace {
+        let mut space = GuestAddressSpace::new().unwrap();
+        for i in 0..4 {
+            space
+                .map_new_frame(GuestPhysAddr::new(i * 0x1000), false)
+                .unwrap();
+        }
+        space
+    }
+
+    // Perform the DMA transfer described by a request at 0x1000, returning
+    // the control field after the transfer
+    fn dma(
+        fw_cfg: &mut QemuFwCfg,
+        control: u32,
+        length: u32,
+        address: u64,
+        space: &mut GuestAddressSpace,
+    ) -> u32 {
+        let mut request = vec![];
+        request.extend_from_slice(&control.to_be_bytes());
+        request.extend_from_slice(&length.to_be_bytes());
+        request.extend_from_slice(&address.to_be_bytes());
+        write_guest(space, 0x1000, &request).unwrap();
+
+        // The DMA address is written in big-endian byte order
+        write_port(fw_cfg, QemuFwCfg::FW_CFG_PORT_DMA_HIGH, &[0; 4], space);
+        write_port(
+            fw_cfg,
+            QemuFwCfg::FW_CFG_PORT_DMA_LOW,
+            &0x1000u32.to_be_bytes(),
+            space,
+        );
+
+        let mut control = [0u8; 4];
+        read_guest(space, 0x1000, &mut control).unwrap();
+        u32::from_be_bytes(control)
+    }
+
+    #[test]
+    fn test_port_interface() {
+        let mut space = setup_space();
+        let fw_cfg = QemuFwCfgBuilder::new().build();
+        let mut fw_cfg = fw_cfg.write();
+
+        let selector = FwCfgSelector::SIGNATURE.to_le_bytes();
+        write_port(
+            &mut fw_cfg,
+            QemuFwCfg::FW_CFG_PORT_SEL,
+            &selector,
+            &mut space,
+        );
+        let mut signature = vec![];
+        for _ in 0..4 {
+            signature.extend(read_port(
+                &mut fw_cfg,
+                QemuFwCfg::FW_CFG_PORT_DATA,
+                1,
+                &mut space,
+            ));
+        }
+        assert_eq!(signature, b"QEMU");
+
+        // Reads past the end of an item (or of a missing item) are zeros,
+        // and writes to the data port are ignored
+        let data =
+            read_port(&mut fw_cfg, QemuFwCfg::FW_CFG_PORT_DATA, 2, &mut space);
+        assert_eq!(data, [0, 0]);
+        write_port(&mut fw_cfg, QemuFwCfg::FW_CFG_PORT_DATA, &[1], &mut space);
+        let selector = FwCfgSelector::NUMA.to_le_bytes();
+        write_port(
+            &mut fw_cfg,
+            QemuFwCfg::FW_CFG_PORT_SEL,
+            &selector,
+            &mut space,
+        );
+        let data =
+            read_port(&mut fw_cfg, QemuFwCfg::FW_CFG_PORT_DATA, 4, &mut space);
+        assert_eq!(data, [0; 4]);
+    }
+
+    #[test]
+    fn test_dma_interface() {
+        let mut space = setup_space();
+        let mut builder = QemuFwCfgBuilder::new();
+        builder.add_file("etc/test", b"hello").unwrap();
+        let fw_cfg = builder.build();
+        let mut fw_cfg = fw_cfg.write();
+
+        let select = (FwCfgSelector::FILE_FIRST as u32) << 16
+            | DmaControlFlags::SELECT.bits() as u32;
+        let control = dma(
+            &mut fw_cfg,
+            select | DmaControlFlags::SKIP.bits() as u32,
+            1,
+            0,
+            &mut space,
+        );
+        assert_eq!(control, 0);
+
+        // The read continues after the skipped byte, with zeros past the
+        // end of the file
+        let read = DmaControlFlags::READ.bits() as u32;
+        write_guest(&space, 0x2000, &[0xff; 8]).unwrap();
+        assert_eq!(dma(&mut fw_cfg, read, 8, 0x2000, &mut space), 0);
+        let mut data = [0u8; 8];
+        read_guest(&space, 0x2000, &mut data).unwrap();
+        assert_eq!(&data, b"ello\0\0\0\0");
+
+        // Items cannot be written
+        let write = DmaControlFlags::WRITE.bits() as u32;
+        let control = dma(&mut fw_cfg, write, 1, 0x2000, &mut space);
+        assert_eq!(control, DmaControlFlags::ERROR.bits() as u32);
+    }
 
     #[test]
     fn test_next_file_selector_first() {
@@ -431,4 +678,27 @@ mod test {
         assert!(selector >= FwCfgSelector::FILE_FIRST);
         assert!(selector <= FwCfgSelector::FILE_LAST);
     }
+
+    #[test]
+    fn test_e820_table() {
+        let mut builder = QemuFwCfgBuilder::new();
+        builder
+            .add_e820_table(&[(0x0, 0x100000, E820Type::Ram)])
+            .unwrap();
+        let table = &builder.data[&FwCfgSelector::FILE_FIRST];
+        assert_eq!(table.len(), 20);
+        assert_eq!(&table[8..16], &0x100000u64.to_le_bytes());
+        assert_eq!(&table[16..20], &1u32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_smbios_fields() {
+        let mut builder = QemuFwCfgBuilder::new();
+        builder.add_smbios_field(1, SMBIOS_SYSTEM_UUID_OFFSET, &[0xaa; 16]);
+        builder.add_smbios_field(1, 4, b"x\0");
+        let entries = &builder.data[&FwCfgSelector::X86_SMBIOS_TABLES];
+        assert_eq!(&entries[..2], &2u16.to_le_bytes());
+        assert_eq!(&entries[2..8], &[22, 0, 0, 1, 8, 0]);
+        assert_eq!(entries.len(), 2 + 22 + 8);
+    }
 }
@@ -1,14 +1,125 @@
+//! Emulation of the MC146818 real-time clock and CMOS memory
+//!
+//! The clock registers are derived from the host wall clock (see
+//! `time::wall_clock_base`), in UTC or local time (see `RtcPolicy`) and
+//! offset by any time the guest sets, and are
+//! reported in the BCD or binary and 12 or 24 hour formats the guest
+//! selects in status register B. The periodic, alarm and update-ended
+//! interrupts are delivered on IRQ8 by timers on the timer wheel, while
+//! the flags in status register C are derived from the time elapsed since
+//! the guest last read it.
+//!
+//! The memory size and boot order registers are derived from the VM
+//! configuration, in the layout used by QEMU (and so expected by SeaBIOS
+//! and OVMF).
+
 use crate::error::Result;
+use crate::nvram::{Nvram, NvramArea};
+use crate::physdev::rtc::{
+    bcd_to_binary, binary_to_bcd, DateTime, RTC_A_UIP, RTC_B_24H, RTC_B_BINARY,
+    RTC_HOURS_PM,
+};
+use crate::time;
+use crate::timekeeping;
+use crate::virtdev::pic::LEGACY_IRQ_VECTOR_BASE;
 use crate::virtdev::{
     DeviceEvent, DeviceRegion, EmulatedDevice, Event, Port, PortReadRequest,
     PortWriteRequest,
 };
+use crate::vm::BootDevice;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::convert::{TryFrom, TryInto};
+use core::time::Duration;
 use num_enum::TryFromPrimitive;
 use spin::RwLock;
 
+/// The vector of the RTC interrupt (IRQ8)
+const RTC_VECTOR: u8 = LEGACY_IRQ_VECTOR_BASE + 8;
+
+// Status register B: the clock is stopped so the guest can set it
+const RTC_B_SET: u8 = 1 << 7;
+
+// The interrupt enables of status register B and the corresponding flags
+// of status register C
+const RTC_PERIODIC: u8 = 1 << 6;
+const RTC_ALARM: u8 = 1 << 5;
+const RTC_UPDATE: u8 = 1 << 4;
+const RTC_INTERRUPTS: u8 = RTC_PERIODIC | RTC_ALARM | RTC_UPDATE;
+
+// Status register C: an enabled interrupt is flagged
+const RTC_C_IRQF: u8 = 1 << 7;
+
+// An alarm register with both high bits set matches any value
+const RTC_ALARM_ANY: u8 = 0xc0;
+
+// The duration of the update cycle at the end of each second
+const RTC_UPDATE_CYCLE: Duration = Duration::from_micros(244);
+
+// The time reported if the host wall clock is unknown (2000-01-01)
+const DEFAULT_WALL_CLOCK_BASE: i64 = 946684800;
+
+// The conventional memory (in KB) below the legacy video and BIOS areas
+const BASE_MEMORY_KB: u16 = 640;
+
+// The boot device types of the boot order registers (as read by SeaBIOS)
+const BOOT_FLOPPY: u16 = 1;
+const BOOT_DISK: u16 = 2;
+const BOOT_NETWORK: u16 = 4;
+
+// The number of devices the boot order registers hold
+const BOOT_DEVICES: usize = 3;
+
+/// The time a guest's RTC is based on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RtcTimeBase {
+    /// The RTC reads UTC (as Linux guests usually expect)
+    Utc,
+    /// The RTC reads local time, the given number of minutes east of UTC
+    /// (as Windows guests usually expect)
+    LocalTime(i32),
+}
+
+impl Default for RtcTimeBase {
+    fn default() -> Self {
+        RtcTimeBase::Utc
+    }
+}
+
+/// What happens to the time a guest sets on its RTC
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RtcWritePolicy {
+    /// The time is kept (as an offset from the time base) in the VM's
+    /// nvram, if it has one, so it survives reboots
+    Persist,
+    /// The time is ignored, and the RTC always reads its time base
+    Discard,
+}
+
+impl Default for RtcWritePolicy {
+    fn default() -> Self {
+        RtcWritePolicy::Persist
+    }
+}
+
+/// How a guest's RTC keeps time
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RtcPolicy {
+    pub time_base: RtcTimeBase,
+    pub writes: RtcWritePolicy,
+}
+
+impl RtcPolicy {
+    /// The time base (in seconds since the unix epoch) at system start,
+    /// given the UTC time then
+    fn base(&self, utc: i64) -> i64 {
+        match self.time_base {
+            RtcTimeBase::Utc => utc,
+            RtcTimeBase::LocalTime(minutes) => utc + minutes as i64 * 60,
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, TryFromPrimitive)]
 #[repr(u8)]
 enum CmosRegister {
@@ -44,6 +155,12 @@ enum CmosRegister {
     BcdCenturyDate = 0x32,
     InfoFlags = 0x33,
 
+    // The boot order, as 4 bit device types (first device in the low
+    // nibble of 0x3d, second in the high nibble, third in the high nibble
+    // of 0x38)
+    BootFlag1 = 0x38,
+    BootFlag2 = 0x3d,
+
     // The follow fields appear to be qemu extensions used by OVMF
     //
     // From OvmfPkg/PlatformPei/MemDetect.c:
@@ -70,37 +187,178 @@ enum CmosRegister {
 pub struct CmosRtc {
     addr: CmosRegister,
     data: [u8; 256],
+    nvram: Option<Arc<RwLock<Nvram>>>,
+    policy: RtcPolicy,
+    // The time base (in seconds since the unix epoch) at system start
+    base: i64,
+    // The offset of the time the guest set from the time base
+    offset: i64,
+    // The uptime the guest last read status register C at
+    flags_read: Duration,
+    // The timers delivering the enabled interrupts
+    timers: Vec<time::TimerId>,
 }
 
 impl CmosRtc {
     const RTC_ADDRESS: Port = 0x0070;
     const RTC_DATA: Port = 0x0071;
 
-    pub fn new(mem: u64) -> Arc<RwLock<Self>> {
-        Arc::new(RwLock::new(Self {
+    /// Create a new CMOS/RTC device for a VM with `mem` MB of memory,
+    /// booting from the devices in `boot_order`
+    ///
+    /// If `nvram` is provided, the non-clock CMOS bytes are restored from
+    /// (and written through to) the VM's persistent storage, as is the time
+    /// set by the guest if `policy` persists it.
+    pub fn new(
+        mem: u64,
+        boot_order: &[BootDevice],
+        nvram: Option<Arc<RwLock<Nvram>>>,
+        policy: RtcPolicy,
+    ) -> Result<Arc<RwLock<Self>>> {
+        let mut data = Self::default_register_values(mem, boot_order);
+        let mut offset = 0;
+
+        if let Some(nvram) = &nvram {
+            let mut nvram = nvram.write();
+            if nvram.is_fresh() {
+                for reg in (0..data.len()).filter(|r| Self::is_persistent(*r)) {
+                    nvram.write(NvramArea::Cmos, reg, &[data[reg]])?;
+                }
+            } else {
+                let saved = nvram.area(NvramArea::Cmos);
+                for reg in (0..data.len()).filter(|r| Self::is_persistent(*r)) {
+                    data[reg] = saved[reg];
+                }
+                if policy.writes == RtcWritePolicy::Persist {
+                    let mut saved = [0u8; 8];
+                    saved.copy_from_slice(nvram.area(NvramArea::RtcOffset));
+                    offset = i64::from_le_bytes(saved);
+                }
+            }
+        }
+
+        Ok(Arc::new(RwLock::new(Self {
             addr: CmosRegister::Seconds, // For now, just set the default reg as seconds
-            data: Self::default_register_values(mem),
-        }))
+            data: data,
+            nvram: nvram,
+            policy: policy,
+            base: policy.base(
+                time::wall_clock_base().unwrap_or(DEFAULT_WALL_CLOCK_BASE),
+            ),
+            offset: offset,
+            flags_read: Duration::default(),
+            timers: vec![],
+        })))
+    }
+
+    /// Returns true if the given register should be kept in nvram
+    ///
+    /// The clock registers are derived from the current time and the memory
+    /// size and boot order registers from the VM configuration, so those are
+    /// never restored.
+    fn is_persistent(reg: usize) -> bool {
+        match CmosRegister::try_from(reg as u8) {
+            Ok(CmosRegister::Seconds)
+            | Ok(CmosRegister::SecondsAlarm)
+            | Ok(CmosRegister::Minutes)
+            | Ok(CmosRegister::MinutesAlarm)
+            | Ok(CmosRegister::Hours)
+            | Ok(CmosRegister::HoursAlarm)
+            | Ok(CmosRegister::DayOfWeek)
+            | Ok(CmosRegister::DayOfMonth)
+            | Ok(CmosRegister::Month)
+            | Ok(CmosRegister::Year)
+            | Ok(CmosRegister::StatusRegisterA)
+            | Ok(CmosRegister::StatusRegisterB)
+            | Ok(CmosRegister::StatusRegisterC)
+            | Ok(CmosRegister::StatusRegisterD)
+            | Ok(CmosRegister::BaseSystemMemoryLsb)
+            | Ok(CmosRegister::BaseSystemMemoryMsb)
+            | Ok(CmosRegister::TotalExtendedMemoryLsb)
+            | Ok(CmosRegister::TotalExtendedMemoryMsb)
+            | Ok(CmosRegister::ExtendedPostMemLsb)
+            | Ok(CmosRegister::ExtendedPostMemMsb)
+            | Ok(CmosRegister::QemuMemAbove16MbLsb)
+            | Ok(CmosRegister::QemuMemAbove16MbMsb)
+            | Ok(CmosRegister::QemuMemAbove4GbLsb)
+            | Ok(CmosRegister::QemuMemAbove4GbMmsb)
+            | Ok(CmosRegister::QemuMemAbove4GbMsb)
+            | Ok(CmosRegister::BootFlag1)
+            | Ok(CmosRegister::BootFlag2) => false,
+            _ => true,
+        }
     }
 
-    fn default_register_values(mem: u64) -> [u8; 256] {
+    /// The boot order registers for `boot_order`
+    ///
+    /// Only the first device of each type is recorded (as the registers
+    /// cannot distinguish two devices of a type), and option roms are left
+    /// to the 'bootorder' file.
+    fn boot_order_value(boot_order: &[BootDevice]) -> u16 {
+        let mut types: Vec<u16> = vec![];
+        for device in boot_order {
+            let kind = match device {
+                BootDevice::Floppy(_) => BOOT_FLOPPY,
+                BootDevice::PciDisk { .. } => BOOT_DISK,
+                BootDevice::PciNic { .. } => BOOT_NETWORK,
+                BootDevice::OptionRom(_) => continue,
+            };
+            if !types.contains(&kind) {
+                types.push(kind);
+            }
+        }
+        types
+            .iter()
+            .take(BOOT_DEVICES)
+            .enumerate()
+            .fold(0, |order, (i, kind)| order | kind << (i * 4))
+    }
+
+    fn default_register_values(
+        mem: u64,
+        boot_order: &[BootDevice],
+    ) -> [u8; 256] {
         //TODO: support memory above 4GB
 
         let mut data = [0u8; 256];
 
-        let megs_under_4gb = mem & 0xfff;
+        // The memory above 1MB, in KB (saturating at 64MB)
+        let extended_kb = ((mem.saturating_sub(1)) << 10).min(0xffff) as u16;
+
+        let megs_under_4gb = mem.min(0x1000);
         // Subtrack 16 because it's really 'blocks_under_4gb_over_16mb'
         // Shift by 4 because each 'block' is 64KiB
-        let blocks_under_4gb: u16 = ((megs_under_4gb - 16) << 4) as u16;
+        let blocks_under_4gb =
+            (megs_under_4gb.saturating_sub(16) << 4).min(0xffff) as u16;
+
+        let boot_order = Self::boot_order_value(boot_order);
 
         let defaults = [
+            // The 32.768kHz time base, with a 1024Hz periodic rate
+            (CmosRegister::StatusRegisterA, 0x26),
+            // BCD, 24 hour format
+            (CmosRegister::StatusRegisterB, RTC_B_24H),
             // The MSB of register D indicates the CMOS battery is working
             (CmosRegister::StatusRegisterD, 0b10000000),
+            (CmosRegister::BaseSystemMemoryLsb, BASE_MEMORY_KB as u8),
+            (
+                CmosRegister::BaseSystemMemoryMsb,
+                (BASE_MEMORY_KB >> 8) as u8,
+            ),
+            (CmosRegister::TotalExtendedMemoryLsb, extended_kb as u8),
+            (
+                CmosRegister::TotalExtendedMemoryMsb,
+                (extended_kb >> 8) as u8,
+            ),
+            (CmosRegister::ExtendedPostMemLsb, extended_kb as u8),
+            (CmosRegister::ExtendedPostMemMsb, (extended_kb >> 8) as u8),
             (CmosRegister::QemuMemAbove16MbLsb, blocks_under_4gb as u8),
             (
                 CmosRegister::QemuMemAbove16MbMsb,
                 (blocks_under_4gb >> 8) as u8,
             ),
+            (CmosRegister::BootFlag2, boot_order as u8),
+            (CmosRegister::BootFlag1, ((boot_order >> 4) & 0xf0) as u8),
         ];
         for &(reg, val) in &defaults {
             data[reg as usize] = val
@@ -108,6 +366,349 @@ impl CmosRtc {
         data
     }
 
+    fn is_clock_register(reg: CmosRegister) -> bool {
+        match reg {
+            CmosRegister::Seconds
+            | CmosRegister::Minutes
+            | CmosRegister::Hours
+            | CmosRegister::DayOfWeek
+            | CmosRegister::DayOfMonth
+            | CmosRegister::Month
+            | CmosRegister::Year
+            | CmosRegister::BcdCenturyDate => true,
+            _ => false,
+        }
+    }
+
+    fn status_b(&self) -> u8 {
+        self.data[CmosRegister::StatusRegisterB as usize]
+    }
+
+    fn is_set(&self) -> bool {
+        self.status_b() & RTC_B_SET != 0
+    }
+
+    fn encode(&self, val: u8) -> u8 {
+        if self.status_b() & RTC_B_BINARY != 0 {
+            val
+        } else {
+            binary_to_bcd(val)
+        }
+    }
+
+    fn decode(&self, val: u8) -> u8 {
+        if self.status_b() & RTC_B_BINARY != 0 {
+            val
+        } else {
+            bcd_to_binary(val)
+        }
+    }
+
+    fn encode_hours(&self, hour: u8) -> u8 {
+        if self.status_b() & RTC_B_24H != 0 {
+            return self.encode(hour);
+        }
+        let pm = if hour >= 12 { RTC_HOURS_PM } else { 0 };
+        match hour % 12 {
+            0 => self.encode(12) | pm,
+            hour => self.encode(hour) | pm,
+        }
+    }
+
+    fn decode_hours(&self, val: u8) -> u8 {
+        if self.status_b() & RTC_B_24H != 0 {
+            return self.decode(val);
+        }
+        let hour = self.decode(val & !RTC_HOURS_PM) % 12;
+        if val & RTC_HOURS_PM != 0 {
+            hour + 12
+        } else {
+            hour
+        }
+    }
+
+    /// The guest time (in seconds since the unix epoch) at uptime `now`
+    fn guest_time(&self, now: Duration) -> i64 {
+        self.base + self.offset + now.as_secs() as i64
+    }
+
+    /// The value of the clock register `reg` at the given guest time
+    fn clock_register(&self, reg: CmosRegister, time: i64) -> u8 {
+        let date = DateTime::from_unix(time);
+        match reg {
+            CmosRegister::Seconds => self.encode(date.second),
+            CmosRegister::Minutes => self.encode(date.minute),
+            CmosRegister::Hours => self.encode_hours(date.hour),
+            CmosRegister::DayOfWeek => self.encode(date.day_of_week()),
+            CmosRegister::DayOfMonth => self.encode(date.day),
+            CmosRegister::Month => self.encode(date.month),
+            CmosRegister::Year => self.encode((date.year % 100) as u8),
+            CmosRegister::BcdCenturyDate => {
+                self.encode((date.year / 100) as u8)
+            }
+            _ => self.data[reg as usize],
+        }
+    }
+
+    /// Copy the current time into the clock registers
+    fn freeze_clock(&mut self, now: Duration) {
+        let time = self.guest_time(now);
+        for reg in 0..=CmosRegister::BcdCenturyDate as u8 {
+            match CmosRegister::try_from(reg) {
+                Ok(reg) if Self::is_clock_register(reg) => {
+                    self.data[reg as usize] = self.clock_register(reg, time);
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Restart the clock from the time in the clock registers
+    fn start_clock(&mut self, now: Duration) -> Result<()> {
+        if self.policy.writes == RtcWritePolicy::Discard {
+            return Ok(());
+        }
+
+        let reg = |reg: CmosRegister| self.data[reg as usize];
+        let date = DateTime {
+            year: self.decode(reg(CmosRegister::BcdCenturyDate)) as u16 * 100
+                + self.decode(reg(CmosRegister::Year)) as u16,
+            month: self.decode(reg(CmosRegister::Month)),
+            day: self.decode(reg(CmosRegister::DayOfMonth)),
+            hour: self.decode_hours(reg(CmosRegister::Hours)),
+            minute: self.decode(reg(CmosRegister::Minutes)),
+            second: self.decode(reg(CmosRegister::Seconds)),
+        };
+        self.offset = date.to_unix() - now.as_secs() as i64 - self.base;
+
+        if let Some(nvram) = &self.nvram {
+            nvram.write().write(
+                NvramArea::RtcOffset,
+                0,
+                &self.offset.to_le_bytes(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The period of the periodic interrupt, if it is enabled in register A
+    fn periodic_rate(&self) -> Option<Duration> {
+        let rate =
+            match self.data[CmosRegister::StatusRegisterA as usize] & 0x0f {
+                0 => return None,
+                // Rates 1 and 2 are the same as 8 and 9
+                rate @ 1..=2 => rate + 7,
+                rate => rate,
+            };
+        Some(Duration::from_nanos(
+            (1_000_000_000u64 << (rate - 1)) / 32768,
+        ))
+    }
+
+    /// The first guest time after `after` that matches the alarm registers
+    fn next_alarm(&self, after: i64) -> i64 {
+        let alarm = |reg: CmosRegister, count: i64| {
+            let val = self.data[reg as usize];
+            if val & RTC_ALARM_ANY == RTC_ALARM_ANY {
+                0..count
+            } else {
+                let val = if let CmosRegister::HoursAlarm = reg {
+                    self.decode_hours(val)
+                } else {
+                    self.decode(val)
+                } as i64;
+                val..val + 1
+            }
+        };
+
+        let today = after - after.rem_euclid(86400);
+        for day in &[today, today + 86400] {
+            for hour in alarm(CmosRegister::HoursAlarm, 24) {
+                let hour = day + hour * 3600;
+                if hour + 3599 <= after {
+                    continue;
+                }
+                for minute in alarm(CmosRegister::MinutesAlarm, 60) {
+                    let minute = hour + minute * 60;
+                    if minute + 59 <= after {
+                        continue;
+                    }
+                    for second in alarm(CmosRegister::SecondsAlarm, 60) {
+                        if minute + second > after {
+                            return minute + second;
+                        }
+                    }
+                }
+            }
+        }
+
+        // The alarm registers can never match (e.g., a minute of 61), so the
+        // alarm never fires
+        i64::MAX
+    }
+
+    /// The flags of status register C at uptime `now`
+    fn flags(&self, now: Duration) -> u8 {
+        let mut flags = 0;
+        if let Some(period) = self.periodic_rate() {
+            let period = period.as_nanos();
+            if now.as_nanos() / period > self.flags_read.as_nanos() / period {
+                flags |= RTC_PERIODIC;
+            }
+        }
+        if !self.is_set() {
+            let (since, now) =
+                (self.guest_time(self.flags_read), self.guest_time(now));
+            if now > since {
+                flags |= RTC_UPDATE;
+            }
+            if self.next_alarm(since) <= now {
+                flags |= RTC_ALARM;
+            }
+        }
+        if flags & self.status_b() & RTC_INTERRUPTS != 0 {
+            flags |= RTC_C_IRQF;
+        }
+        flags
+    }
+
+    /// The delays (and whether each is periodic) of the timers delivering
+    /// the enabled interrupts at uptime `now`
+    fn interrupts(&self, now: Duration) -> Vec<(Duration, bool)> {
+        let mut interrupts = vec![];
+        if self.status_b() & RTC_PERIODIC != 0 {
+            if let Some(period) = self.periodic_rate() {
+                interrupts.push((period, true));
+            }
+        }
+        if self.is_set() {
+            return interrupts;
+        }
+        if self.status_b() & RTC_UPDATE != 0 {
+            interrupts.push((Duration::from_secs(1), true));
+        }
+        if self.status_b() & RTC_ALARM != 0 {
+            let time = self.guest_time(now);
+            let alarm = self.next_alarm(time);
+            if alarm != i64::MAX {
+                let delay = Duration::from_secs((alarm - time) as u64)
+                    - Duration::from_nanos(now.subsec_nanos() as u64);
+                interrupts.push((delay, false));
+            }
+        }
+        interrupts
+    }
+
+    fn update_timers(&mut self, now: Duration) -> Result<()> {
+        for timer in self.timers.drain(..) {
+            time::cancel_timer(&timer)?;
+        }
+        for (delay, periodic) in self.interrupts(now) {
+            self.timers.push(if periodic {
+                time::set_periodic_timer(delay, RTC_VECTOR)
+            } else {
+                time::set_oneshot_timer(delay, RTC_VECTOR)
+            });
+        }
+        Ok(())
+    }
+
+    /// Read the register `reg` at uptime `now`
+    fn read_register(&mut self, reg: CmosRegister, now: Duration) -> u8 {
+        match reg {
+            CmosRegister::StatusRegisterA => {
+                let val = self.data[reg as usize];
+                let subsec = Duration::from_nanos(now.subsec_nanos() as u64);
+                if !self.is_set()
+                    && subsec >= Duration::from_secs(1) - RTC_UPDATE_CYCLE
+                {
+                    val | RTC_A_UIP
+                } else {
+                    val
+                }
+            }
+            CmosRegister::StatusRegisterC => {
+                // Reading register C clears its flags
+                let flags = self.flags(now);
+                self.flags_read = now;
+                flags
+            }
+            reg if Self::is_clock_register(reg) && !self.is_set() => {
+                self.clock_register(reg, self.guest_time(now))
+            }
+            reg => self.data[reg as usize],
+        }
+    }
+
+    /// Write `val` to the register `reg` at uptime `now`, returning true if
+    /// the interrupt timers must be updated
+    fn write_register(
+        &mut self,
+        reg: CmosRegister,
+        val: u8,
+        now: Duration,
+    ) -> Result<bool> {
+        match reg {
+            CmosRegister::ShutdownStatus => {
+                // It's not clear what's supposed to happen here, just ignore
+                // it for now
+            }
+            CmosRegister::StatusRegisterD | CmosRegister::StatusRegisterC => {
+                // Status register C and D are read-only (but OVMF will attempt
+                // to write to them, so we must explicitly ignore the writes)
+            }
+            CmosRegister::StatusRegisterA => {
+                self.data[reg as usize] = val & !RTC_A_UIP;
+                return Ok(true);
+            }
+            CmosRegister::StatusRegisterB => {
+                let was_set = self.is_set();
+                if val & RTC_B_SET != 0 && !was_set {
+                    self.freeze_clock(now);
+                }
+                self.data[reg as usize] = val;
+                if val & RTC_B_SET == 0 && was_set {
+                    self.start_clock(now)?;
+                }
+                return Ok(true);
+            }
+            reg if Self::is_clock_register(reg) => {
+                // Setting a single field while the clock runs sets the time
+                // immediately
+                let running = !self.is_set();
+                if running {
+                    self.freeze_clock(now);
+                }
+                self.data[reg as usize] = val;
+                if running {
+                    self.start_clock(now)?;
+                }
+                return Ok(true);
+            }
+            CmosRegister::SecondsAlarm
+            | CmosRegister::MinutesAlarm
+            | CmosRegister::HoursAlarm => {
+                self.data[reg as usize] = val;
+                return Ok(true);
+            }
+            addr => {
+                // For now, any other register write is just directly performed
+                self.data[addr as usize] = val;
+
+                if let Some(nvram) = &self.nvram {
+                    if Self::is_persistent(addr as usize) {
+                        nvram.write().write(
+                            NvramArea::Cmos,
+                            addr as usize,
+                            &[val],
+                        )?;
+                    }
+                }
+            }
+        }
+        Ok(false)
+    }
+
     fn on_port_read(
         &mut self,
         port: Port,
@@ -115,11 +716,18 @@ impl CmosRtc {
     ) -> Result<()> {
         match port {
             Self::RTC_ADDRESS => val.copy_from_u32(self.addr as u8 as u32),
-            Self::RTC_DATA => match self.addr {
-                addr => {
-                    val.copy_from_u32(self.data[addr as usize] as u32);
+            Self::RTC_DATA => {
+                let now = timekeeping::guest_uptime();
+                let data = self.read_register(self.addr, now);
+                val.copy_from_u32(data as u32);
+
+                // A one-shot alarm is re-armed once the guest acknowledges it
+                if let CmosRegister::StatusRegisterC = self.addr {
+                    if self.status_b() & RTC_ALARM != 0 {
+                        self.update_timers(now)?;
+                    }
                 }
-            },
+            }
             _ => unreachable!(),
         }
 
@@ -131,32 +739,22 @@ impl CmosRtc {
         port: Port,
         val: PortWriteRequest,
     ) -> Result<()> {
-        // For now, just ignore the NMI masking
         let val: u8 = val.try_into()?;
-        let val = val & 0x7f;
 
         match port {
             Self::RTC_ADDRESS => {
+                // For now, just ignore the NMI masking
+                let val = val & 0x7f;
+
                 // OVMF expects to be able to read pretty much any address
                 // (and just get zeros for meaningless ones)
                 self.addr = CmosRegister::try_from(val)
                     .unwrap_or(CmosRegister::Unknown);
             }
             Self::RTC_DATA => {
-                match self.addr {
-                    CmosRegister::ShutdownStatus => {
-                        // It's not clear what's supposed to happen here, just ignore
-                        // it for now
-                    }
-                    CmosRegister::StatusRegisterD
-                    | CmosRegister::StatusRegisterC => {
-                        // Status register C and D are read-only (but OVMF will attempt
-                        // to write to them, so we must explicitly ignore the writes)
-                    }
-                    addr => {
-                        // For now, any other register write is just directly performed
-                        self.data[addr as usize] = val;
-                    }
+                let now = timekeeping::guest_uptime();
+                if self.write_register(self.addr, val, now)? {
+                    self.update_timers(now)?;
                 }
             }
             _ => unreachable!(),
@@ -182,3 +780,181 @@ impl EmulatedDevice for CmosRtc {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // 2020-02-29 23:59:58 (a Saturday)
+    const LEAP_DAY: i64 = 1583020798;
+
+    fn rtc() -> CmosRtc {
+        CmosRtc {
+            addr: CmosRegister::Seconds,
+            data: CmosRtc::default_register_values(32, &[]),
+            nvram: None,
+            policy: RtcPolicy::default(),
+            base: LEAP_DAY,
+            offset: 0,
+            flags_read: Duration::default(),
+            timers: vec![],
+        }
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_rtc_clock_formats() {
+        let mut rtc = rtc();
+        let read = |rtc: &mut CmosRtc, reg, now| rtc.read_register(reg, now);
+        assert_eq!(read(&mut rtc, CmosRegister::Seconds, secs(0)), 0x58);
+        assert_eq!(read(&mut rtc, CmosRegister::Hours, secs(0)), 0x23);
+        assert_eq!(read(&mut rtc, CmosRegister::DayOfWeek, secs(0)), 7);
+        assert_eq!(read(&mut rtc, CmosRegister::BcdCenturyDate, secs(0)), 0x20);
+
+        // The clock rolls over into March
+        assert_eq!(read(&mut rtc, CmosRegister::Month, secs(2)), 0x03);
+        assert_eq!(read(&mut rtc, CmosRegister::DayOfMonth, secs(2)), 0x01);
+
+        // Binary, 12 hour format
+        rtc.write_register(
+            CmosRegister::StatusRegisterB,
+            RTC_B_BINARY,
+            secs(0),
+        )
+        .unwrap();
+        assert_eq!(read(&mut rtc, CmosRegister::Seconds, secs(0)), 58);
+        assert_eq!(
+            read(&mut rtc, CmosRegister::Hours, secs(0)),
+            11 | RTC_HOURS_PM
+        );
+        assert_eq!(read(&mut rtc, CmosRegister::Hours, secs(2)), 12);
+    }
+
+    #[test]
+    fn test_rtc_set_time() {
+        let mut rtc = rtc();
+        rtc.write_register(
+            CmosRegister::StatusRegisterB,
+            RTC_B_SET | RTC_B_24H,
+            secs(10),
+        )
+        .unwrap();
+
+        // The clock is stopped while it is being set
+        rtc.write_register(CmosRegister::Hours, 0x08, secs(10))
+            .unwrap();
+        rtc.write_register(CmosRegister::Minutes, 0x30, secs(10))
+            .unwrap();
+        assert_eq!(rtc.read_register(CmosRegister::Hours, secs(20)), 0x08);
+        assert_eq!(rtc.read_register(CmosRegister::Seconds, secs(20)), 0x08);
+
+        rtc.write_register(CmosRegister::StatusRegisterB, RTC_B_24H, secs(20))
+            .unwrap();
+        assert_eq!(rtc.read_register(CmosRegister::Seconds, secs(25)), 0x13);
+        assert_eq!(rtc.read_register(CmosRegister::Minutes, secs(25)), 0x30);
+        assert_eq!(rtc.read_register(CmosRegister::DayOfMonth, secs(25)), 0x01);
+        assert_eq!(rtc.offset, 2 + 8 * 3600 + 30 * 60 + 8 - 20);
+    }
+
+    #[test]
+    fn test_rtc_policy() {
+        let mut rtc = rtc();
+        rtc.policy = RtcPolicy {
+            time_base: RtcTimeBase::LocalTime(-300),
+            writes: RtcWritePolicy::Discard,
+        };
+        rtc.base = rtc.policy.base(LEAP_DAY);
+        assert_eq!(rtc.read_register(CmosRegister::Hours, secs(0)), 0x18);
+
+        // The time set by the guest is ignored
+        rtc.write_register(CmosRegister::Hours, 0x08, secs(0))
+            .unwrap();
+        assert_eq!(rtc.read_register(CmosRegister::Hours, secs(0)), 0x18);
+        assert_eq!(rtc.offset, 0);
+    }
+
+    #[test]
+    fn test_rtc_interrupts() {
+        let mut rtc = rtc();
+        assert_eq!(rtc.periodic_rate(), Some(Duration::from_nanos(976562)));
+        let enables = RTC_B_24H | RTC_PERIODIC | RTC_ALARM;
+        rtc.write_register(CmosRegister::StatusRegisterB, enables, secs(0))
+            .unwrap();
+
+        // Ring at midnight, on any day
+        rtc.write_register(CmosRegister::HoursAlarm, 0, secs(0))
+            .unwrap();
+        rtc.write_register(CmosRegister::MinutesAlarm, 0, secs(0))
+            .unwrap();
+        rtc.write_register(CmosRegister::SecondsAlarm, RTC_ALARM_ANY, secs(0))
+            .unwrap();
+        assert_eq!(rtc.next_alarm(LEAP_DAY), LEAP_DAY + 2);
+        assert_eq!(
+            rtc.interrupts(Duration::from_millis(500)),
+            vec![
+                (Duration::from_nanos(976562), true),
+                (Duration::from_millis(1500), false)
+            ]
+        );
+
+        assert_eq!(
+            rtc.read_register(CmosRegister::StatusRegisterC, secs(1)),
+            { RTC_C_IRQF | RTC_PERIODIC | RTC_UPDATE }
+        );
+        assert_eq!(
+            rtc.read_register(CmosRegister::StatusRegisterC, secs(1)),
+            0
+        );
+        assert_eq!(
+            rtc.read_register(CmosRegister::StatusRegisterC, secs(2)),
+            RTC_C_IRQF | RTC_PERIODIC | RTC_ALARM | RTC_UPDATE
+        );
+    }
+
+    #[test]
+    fn test_rtc_config_registers() {
+        let data = CmosRtc::default_register_values(
+            2048,
+            &[
+                BootDevice::OptionRom("genroms/linuxboot_dma.bin".into()),
+                BootDevice::PciNic {
+                    device: 3,
+                    function: 0,
+                },
+                BootDevice::PciDisk {
+                    device: 4,
+                    function: 0,
+                },
+                BootDevice::PciNic {
+                    device: 5,
+                    function: 0,
+                },
+                BootDevice::Floppy(0),
+            ],
+        );
+        let reg = |reg: CmosRegister| data[reg as usize];
+
+        // 640KB of base memory, and the extended memory saturates at 64MB
+        assert_eq!(reg(CmosRegister::BaseSystemMemoryLsb), 0x80);
+        assert_eq!(reg(CmosRegister::BaseSystemMemoryMsb), 0x02);
+        assert_eq!(reg(CmosRegister::TotalExtendedMemoryLsb), 0xff);
+        assert_eq!(reg(CmosRegister::TotalExtendedMemoryMsb), 0xff);
+        assert_eq!(reg(CmosRegister::ExtendedPostMemMsb), 0xff);
+
+        // (2048 - 16) MB in 64KB blocks
+        assert_eq!(reg(CmosRegister::QemuMemAbove16MbLsb), 0x00);
+        assert_eq!(reg(CmosRegister::QemuMemAbove16MbMsb), 0x7f);
+
+        // Network, then disk, then floppy
+        assert_eq!(reg(CmosRegister::BootFlag2), 0x24);
+        assert_eq!(reg(CmosRegister::BootFlag1), 0x10);
+
+        let data = CmosRtc::default_register_values(32, &[]);
+        assert_eq!(data[CmosRegister::TotalExtendedMemoryLsb as usize], 0x00);
+        assert_eq!(data[CmosRegister::TotalExtendedMemoryMsb as usize], 0x7c);
+        assert_eq!(data[CmosRegister::BootFlag2 as usize], 0);
+    }
+}
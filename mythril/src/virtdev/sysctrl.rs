@@ -0,0 +1,109 @@
+//! Emulation of System Control Port A (port 0x92)
+//!
+//! Port 0x92 (introduced with the PS/2 machines) gives guests a faster way
+//! to enable the A20 line and reset the processor than going through the
+//! keyboard controller. Writing a one to bit 0 requests a reset of the VM,
+//! and bit 1 gates A20. The other bits (the security lock and the disk
+//! activity lights) read back as written.
+//!
+//! Guest memory is never wrapped at 1 MB, so the A20 gate only records what
+//! the guest asked for.
+
+use crate::error::Result;
+use crate::virtdev::{
+    DeviceEvent, DeviceEventResponse, DeviceRegion, EmulatedDevice, Event, Port,
+};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use core::convert::TryFrom;
+use spin::RwLock;
+
+bitflags! {
+    pub struct ControlA: u8 {
+        /// Writing a one resets the processor (it always reads as zero)
+        const FAST_RESET = 1 << 0;
+        const A20 = 1 << 1;
+        const SECURITY_LOCK = 1 << 3;
+        const DISK_ACTIVITY = 0b11 << 6;
+    }
+}
+
+#[derive(Debug)]
+pub struct SystemControlPortA {
+    control: ControlA,
+}
+
+impl Default for SystemControlPortA {
+    fn default() -> Self {
+        // A20 is enabled when the guest starts (as it is on modern chipsets)
+        Self {
+            control: ControlA::A20,
+        }
+    }
+}
+
+impl SystemControlPortA {
+    const SYSTEM_CONTROL_A: Port = 0x0092;
+
+    pub fn new() -> Arc<RwLock<Self>> {
+        Arc::new(RwLock::new(Self::default()))
+    }
+
+    /// Returns true if the guest enabled the A20 line through this port
+    pub fn a20_enabled(&self) -> bool {
+        self.control.contains(ControlA::A20)
+    }
+
+    // Write the control register, returning true if the guest requested
+    // a reset
+    fn write(&mut self, val: u8) -> bool {
+        let val = ControlA::from_bits_truncate(val);
+        self.control = val - ControlA::FAST_RESET;
+        val.contains(ControlA::FAST_RESET)
+    }
+}
+
+impl EmulatedDevice for SystemControlPortA {
+    fn services(&self) -> Vec<DeviceRegion> {
+        vec![DeviceRegion::PortIo(
+            Self::SYSTEM_CONTROL_A..=Self::SYSTEM_CONTROL_A,
+        )]
+    }
+
+    fn on_event(&mut self, event: Event) -> Result<()> {
+        match event.kind {
+            DeviceEvent::PortRead(_port, mut val) => {
+                val.copy_from_u32(self.control.bits() as u32);
+            }
+            DeviceEvent::PortWrite(_port, val) => {
+                if self.write(u8::try_from(val)?) {
+                    event.responses.push(DeviceEventResponse::ResetRequested);
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_system_control_a() {
+        let mut port = SystemControlPortA::default();
+        assert!(port.a20_enabled());
+
+        assert!(!port.write(0x00));
+        assert!(!port.a20_enabled());
+        assert!(!port.write(0x0a));
+        assert!(port.a20_enabled());
+        assert_eq!(port.control.bits(), 0x0a);
+
+        // The reset bit is not retained, but the A20 gate is
+        assert!(port.write(0x03));
+        assert_eq!(port.control.bits(), 0x02);
+    }
+}
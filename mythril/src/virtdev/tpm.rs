@@ -0,0 +1,831 @@
+//! A virtual TPM 2.0, with a command response buffer (CRB) interface
+//!
+//! The TPM implements the subset of TPM 2.0 commands used to discover it
+//! and to read and extend its PCRs: TPM2_Startup, TPM2_Shutdown,
+//! TPM2_SelfTest, TPM2_GetCapability, TPM2_GetRandom, TPM2_PCR_Read,
+//! TPM2_PCR_Extend and TPM2_ReadClock. It has a single (SHA-256) bank of
+//! PCRs, which TPM2_Startup sets to the values measured at launch (see
+//! `measure`), as if the hypervisor extended them right after the TPM
+//! started. The guest can then check the event log it gets through fw_cfg
+//! against the PCRs it reads from the TPM, and extend them with its own
+//! measurements.
+//!
+//! The reset and restart counts (reported in the clock information) are
+//! kept in the `TpmState` area of the VM's `Nvram`. The TPM has no keys or
+//! hierarchies, so it cannot sign a quote over its PCRs, and the only
+//! authorization it accepts is an empty password.
+//!
+//! Only locality 0 is implemented. Commands complete as soon as they are
+//! started, so the start register always reads as zero.
+
+use crate::error::Result;
+use crate::measure::{self, Digest, DIGEST_SIZE, PCR_COUNT, TPM_ALG_SHA256};
+use crate::memory::GuestPhysAddr;
+use crate::nvram::{Nvram, NvramArea};
+use crate::rng::{self, HmacDrbg, RESEED_INTERVAL};
+use crate::time;
+use crate::virtdev::{DeviceEvent, DeviceRegion, EmulatedDevice, Event};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp;
+use spin::RwLock;
+
+/// The guest physical address of the CRB registers (of locality 0)
+pub const TPM_CRB_BASE: u64 = 0xfed40000;
+
+/// The size of the CRB registers, including the data buffer
+pub const TPM_CRB_SIZE: usize = 0x1000;
+
+/// The offset of the CRB control area from the start of the registers
+pub const TPM_CRB_CONTROL_AREA: usize = CRB_CTRL_REQ;
+
+// The CRB registers (see the TCG PC Client Platform TPM Profile § 6.5.3)
+const CRB_LOC_STATE: usize = 0x00;
+const CRB_LOC_CTRL: usize = 0x08;
+const CRB_LOC_STS: usize = 0x0c;
+const CRB_INTF_ID: usize = 0x30;
+const CRB_CTRL_REQ: usize = 0x40;
+const CRB_CTRL_STS: usize = 0x44;
+const CRB_CTRL_START: usize = 0x4c;
+const CRB_CTRL_CMD_SIZE: usize = 0x58;
+const CRB_CTRL_CMD_LADDR: usize = 0x5c;
+const CRB_CTRL_RSP_SIZE: usize = 0x64;
+const CRB_CTRL_RSP_ADDR: usize = 0x68;
+const CRB_DATA_BUFFER: usize = 0x80;
+const CRB_BUFFER_SIZE: usize = TPM_CRB_SIZE - CRB_DATA_BUFFER;
+
+const LOC_STATE_ESTABLISHED: u32 = 1 << 0;
+const LOC_STATE_ASSIGNED: u32 = 1 << 1;
+const LOC_STATE_REG_VALID: u32 = 1 << 7;
+const LOC_CTRL_REQUEST_ACCESS: u32 = 1 << 0;
+const LOC_CTRL_RELINQUISH: u32 = 1 << 1;
+const LOC_STS_GRANTED: u32 = 1 << 0;
+const CTRL_REQ_CMD_READY: u32 = 1 << 0;
+const CTRL_REQ_GO_IDLE: u32 = 1 << 1;
+const CTRL_STS_IDLE: u32 = 1 << 1;
+const CTRL_START: u32 = 1 << 0;
+
+// An active CRB interface (version 1), that only supports the CRB
+const INTF_ID: u32 = 0x1 | (0x1 << 4) | (1 << 14) | (0x1 << 17);
+
+const TPM_ST_NO_SESSIONS: u16 = 0x8001;
+const TPM_ST_SESSIONS: u16 = 0x8002;
+
+const TPM_CC_SELF_TEST: u32 = 0x143;
+const TPM_CC_STARTUP: u32 = 0x144;
+const TPM_CC_SHUTDOWN: u32 = 0x145;
+const TPM_CC_GET_CAPABILITY: u32 = 0x17a;
+const TPM_CC_GET_RANDOM: u32 = 0x17b;
+const TPM_CC_PCR_READ: u32 = 0x17e;
+const TPM_CC_READ_CLOCK: u32 = 0x181;
+const TPM_CC_PCR_EXTEND: u32 = 0x182;
+
+const TPM_RC_SUCCESS: u32 = 0x000;
+const TPM_RC_BAD_TAG: u32 = 0x01e;
+const TPM_RC_HASH: u32 = 0x083;
+const TPM_RC_VALUE: u32 = 0x084;
+const TPM_RC_HANDLE: u32 = 0x08b;
+const TPM_RC_AUTH_FAIL: u32 = 0x08e;
+const TPM_RC_INSUFFICIENT: u32 = 0x09a;
+const TPM_RC_INITIALIZE: u32 = 0x100;
+const TPM_RC_FAILURE: u32 = 0x101;
+const TPM_RC_AUTH_MISSING: u32 = 0x125;
+const TPM_RC_COMMAND_SIZE: u32 = 0x142;
+const TPM_RC_COMMAND_CODE: u32 = 0x143;
+
+const TPM_SU_CLEAR: u16 = 0;
+const TPM_SU_STATE: u16 = 1;
+
+const TPM_CAP_COMMANDS: u32 = 2;
+const TPM_CAP_PCRS: u32 = 5;
+const TPM_CAP_TPM_PROPERTIES: u32 = 6;
+
+// The password session, which is the only session accepted
+const TPM_RS_PW: u32 = 0x40000009;
+
+// The digests that may be given to TPM2_PCR_Extend (SHA-1, SHA-384 and
+// SHA-512 have no bank, so those digests are ignored)
+const HASH_ALGORITHMS: [(u16, usize); 4] = [
+    (0x0004, 20),
+    (TPM_ALG_SHA256, DIGEST_SIZE),
+    (0x000c, 48),
+    (0x000d, 64),
+];
+
+// The implemented commands, with the number of handles they take
+const COMMANDS: [(u32, u32); 8] = [
+    (TPM_CC_SELF_TEST, 0),
+    (TPM_CC_STARTUP, 0),
+    (TPM_CC_SHUTDOWN, 0),
+    (TPM_CC_GET_CAPABILITY, 0),
+    (TPM_CC_GET_RANDOM, 0),
+    (TPM_CC_PCR_READ, 0),
+    (TPM_CC_READ_CLOCK, 0),
+    (TPM_CC_PCR_EXTEND, 1),
+];
+
+// The size of the PCR selection bitmaps
+const PCR_SELECT_SIZE: usize = PCR_COUNT / 8;
+
+// TPM2_PCR_Read returns at most this many digests
+const MAX_READ_DIGESTS: usize = 8;
+
+// The fixed TPM properties (TPM_PT_FAMILY_INDICATOR and onwards)
+const PROPERTIES: [(u32, u32); 10] = [
+    (0x100, 0x322e3000), // Family "2.0"
+    (0x101, 0),          // Level
+    (0x102, 138),        // Revision 1.38
+    (0x105, 0x4d595448), // Manufacturer "MYTH"
+    (0x112, PCR_COUNT as u32),
+    (0x113, PCR_SELECT_SIZE as u32),
+    (0x11e, CRB_BUFFER_SIZE as u32), // Maximum command size
+    (0x11f, CRB_BUFFER_SIZE as u32), // Maximum response size
+    (0x120, DIGEST_SIZE as u32),
+    (0x129, COMMANDS.len() as u32), // Total commands
+];
+
+// The layout of the `TpmState` area
+const STATE_MAGIC: &[u8; 4] = b"MTPM";
+const STATE_RESET_COUNT: usize = 4;
+const STATE_RESTART_COUNT: usize = 8;
+const STATE_SIZE: usize = 12;
+
+const SEED_SIZE: usize = 48;
+
+type TpmResult<T> = core::result::Result<T, u32>;
+
+// Reads the (big endian) fields of a command
+struct CommandReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> CommandReader<'a> {
+    fn bytes(&mut self, len: usize) -> TpmResult<&'a [u8]> {
+        if len > self.data.len() {
+            return Err(TPM_RC_INSUFFICIENT);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> TpmResult<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> TpmResult<u16> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> TpmResult<u32> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+/// The TPM behind the CRB interface, which executes commands
+pub struct Tpm {
+    nvram: Arc<RwLock<Nvram>>,
+    launch_pcrs: [Digest; PCR_COUNT],
+    pcrs: [Digest; PCR_COUNT],
+    pcr_update_counter: u32,
+    started: bool,
+    drbg: HmacDrbg,
+}
+
+impl Tpm {
+    /// Create a TPM whose PCRs start with `launch_pcrs`, keeping its state
+    /// in `nvram`, and with a DRBG seeded from the hypervisor's generator
+    pub fn new(
+        nvram: Arc<RwLock<Nvram>>,
+        launch_pcrs: &[Digest; PCR_COUNT],
+    ) -> Result<Self> {
+        let mut seed = [0u8; SEED_SIZE];
+        rng::fill_bytes(&mut seed)?;
+        Ok(Self::from_seed(nvram, launch_pcrs, &seed))
+    }
+
+    /// Create a TPM with a DRBG instantiated from `seed`
+    pub fn from_seed(
+        nvram: Arc<RwLock<Nvram>>,
+        launch_pcrs: &[Digest; PCR_COUNT],
+        seed: &[u8],
+    ) -> Self {
+        Self {
+            nvram: nvram,
+            launch_pcrs: *launch_pcrs,
+            pcrs: [[0; DIGEST_SIZE]; PCR_COUNT],
+            pcr_update_counter: 0,
+            started: false,
+            drbg: HmacDrbg::new(seed),
+        }
+    }
+
+    /// Return the TPM to its state at power on (before TPM2_Startup)
+    pub fn reset(&mut self) {
+        self.started = false;
+    }
+
+    /// Execute `command`, returning the response
+    pub fn execute(&mut self, command: &[u8]) -> Vec<u8> {
+        let (tag, code, params) = match self.dispatch(command) {
+            Ok((tag, params)) => (tag, TPM_RC_SUCCESS, params),
+            Err(code) => (TPM_ST_NO_SESSIONS, code, vec![]),
+        };
+        let mut response = vec![];
+        response.extend_from_slice(&tag.to_be_bytes());
+        response.extend_from_slice(&(10 + params.len() as u32).to_be_bytes());
+        response.extend_from_slice(&code.to_be_bytes());
+        response.extend_from_slice(&params);
+        response
+    }
+
+    // Execute `command`, returning the tag and parameters of the response
+    fn dispatch(&mut self, command: &[u8]) -> TpmResult<(u16, Vec<u8>)> {
+        let mut reader = CommandReader { data: command };
+        let tag = reader.u16()?;
+        let size = reader.u32()? as usize;
+        let code = reader.u32()?;
+        if size != command.len() {
+            return Err(TPM_RC_COMMAND_SIZE);
+        }
+        match (code, tag) {
+            (TPM_CC_PCR_EXTEND, TPM_ST_SESSIONS) => (),
+            (TPM_CC_PCR_EXTEND, TPM_ST_NO_SESSIONS) => {
+                return Err(TPM_RC_AUTH_MISSING)
+            }
+            (_, TPM_ST_NO_SESSIONS) => (),
+            _ => return Err(TPM_RC_BAD_TAG),
+        }
+        if !self.started && code != TPM_CC_STARTUP {
+            return Err(TPM_RC_INITIALIZE);
+        }
+
+        let params = match code {
+            TPM_CC_STARTUP => self.startup(&mut reader)?,
+            TPM_CC_SHUTDOWN => Self::shutdown(&mut reader)?,
+            TPM_CC_SELF_TEST => {
+                reader.u8()?;
+                vec![]
+            }
+            TPM_CC_GET_CAPABILITY => Self::get_capability(&mut reader)?,
+            TPM_CC_GET_RANDOM => self.get_random(&mut reader)?,
+            TPM_CC_PCR_READ => self.pcr_read(&mut reader)?,
+            TPM_CC_READ_CLOCK => self.read_clock(),
+            TPM_CC_PCR_EXTEND => {
+                return Ok((TPM_ST_SESSIONS, self.pcr_extend(&mut reader)?))
+            }
+            _ => return Err(TPM_RC_COMMAND_CODE),
+        };
+        Ok((TPM_ST_NO_SESSIONS, params))
+    }
+
+    // The reset and restart counts kept in the nvram
+    fn counts(&self) -> (u32, u32) {
+        let nvram = self.nvram.read();
+        let state = &nvram.area(NvramArea::TpmState)[..STATE_SIZE];
+        if &state[..STATE_MAGIC.len()] != STATE_MAGIC {
+            return (0, 0);
+        }
+        let count = |offset: usize| {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&state[offset..offset + 4]);
+            u32::from_le_bytes(bytes)
+        };
+        (count(STATE_RESET_COUNT), count(STATE_RESTART_COUNT))
+    }
+
+    fn startup(&mut self, reader: &mut CommandReader) -> TpmResult<Vec<u8>> {
+        if self.started {
+            return Err(TPM_RC_INITIALIZE);
+        }
+
+        // There is never a saved state to resume from
+        if reader.u16()? != TPM_SU_CLEAR {
+            return Err(TPM_RC_VALUE);
+        }
+
+        // This is a TPM Reset, so the reset count is incremented
+        let (reset_count, _) = self.counts();
+        let mut state = [0u8; STATE_SIZE];
+        state[..STATE_MAGIC.len()].copy_from_slice(STATE_MAGIC);
+        state[STATE_RESET_COUNT..STATE_RESET_COUNT + 4]
+            .copy_from_slice(&reset_count.wrapping_add(1).to_le_bytes());
+        self.nvram
+            .write()
+            .write(NvramArea::TpmState, 0, &state)
+            .map_err(|_| TPM_RC_FAILURE)?;
+
+        self.pcrs = self.launch_pcrs;
+        self.pcr_update_counter = 0;
+        self.started = true;
+        Ok(vec![])
+    }
+
+    fn shutdown(reader: &mut CommandReader) -> TpmResult<Vec<u8>> {
+        match reader.u16()? {
+            TPM_SU_CLEAR | TPM_SU_STATE => Ok(vec![]),
+            _ => Err(TPM_RC_VALUE),
+        }
+    }
+
+    fn get_capability(reader: &mut CommandReader) -> TpmResult<Vec<u8>> {
+        let capability = reader.u32()?;
+        let property = reader.u32()?;
+        let count = reader.u32()? as usize;
+
+        // The entries of a list capability from `property` onwards
+        let list = |entries: &[(u32, u32)],
+                    entry: &dyn Fn(&(u32, u32)) -> Vec<u8>| {
+            let entries = entries
+                .iter()
+                .filter(|(key, _)| *key >= property)
+                .collect::<Vec<_>>();
+            let returned = cmp::min(entries.len(), count);
+            let mut params = vec![(returned < entries.len()) as u8];
+            params.extend_from_slice(&capability.to_be_bytes());
+            params.extend_from_slice(&(returned as u32).to_be_bytes());
+            for item in entries.iter().take(returned) {
+                params.extend_from_slice(&entry(item));
+            }
+            params
+        };
+
+        match capability {
+            TPM_CAP_COMMANDS => Ok(list(&COMMANDS, &|(code, handles)| {
+                (code | handles << 25).to_be_bytes().to_vec()
+            })),
+            TPM_CAP_TPM_PROPERTIES => {
+                Ok(list(&PROPERTIES, &|(property, value)| {
+                    let mut entry = property.to_be_bytes().to_vec();
+                    entry.extend_from_slice(&value.to_be_bytes());
+                    entry
+                }))
+            }
+            TPM_CAP_PCRS => {
+                let mut params = vec![0];
+                params.extend_from_slice(&capability.to_be_bytes());
+                params.extend_from_slice(&1u32.to_be_bytes());
+                params.extend_from_slice(&TPM_ALG_SHA256.to_be_bytes());
+                params.push(PCR_SELECT_SIZE as u8);
+                params.extend_from_slice(&[0xff; PCR_SELECT_SIZE]);
+                Ok(params)
+            }
+            _ => Err(TPM_RC_VALUE),
+        }
+    }
+
+    fn get_random(&mut self, reader: &mut CommandReader) -> TpmResult<Vec<u8>> {
+        let len = cmp::min(reader.u16()? as usize, DIGEST_SIZE);
+        let mut bytes = [0u8; DIGEST_SIZE];
+        if self.drbg.requests() >= RESEED_INTERVAL {
+            let mut seed = [0u8; SEED_SIZE];
+            rng::fill_bytes(&mut seed).map_err(|_| TPM_RC_FAILURE)?;
+            self.drbg.reseed(&seed);
+        }
+        self.drbg
+            .generate(&mut bytes[..len])
+            .map_err(|_| TPM_RC_FAILURE)?;
+
+        let mut params = (len as u16).to_be_bytes().to_vec();
+        params.extend_from_slice(&bytes[..len]);
+        Ok(params)
+    }
+
+    fn pcr_read(&self, reader: &mut CommandReader) -> TpmResult<Vec<u8>> {
+        let count = reader.u32()?;
+        let mut selections = count.to_be_bytes().to_vec();
+        let mut digests = vec![];
+        for _ in 0..count {
+            let algorithm = reader.u16()?;
+            let size = reader.u8()? as usize;
+            let select = reader.bytes(size)?;
+
+            // Only the PCRs that are read are selected in the response
+            let mut selected = vec![0u8; size];
+            if algorithm == TPM_ALG_SHA256 {
+                for pcr in 0..cmp::min(size * 8, PCR_COUNT) {
+                    let bit = 1 << (pcr % 8);
+                    if select[pcr / 8] & bit != 0
+                        && digests.len() < MAX_READ_DIGESTS
+                    {
+                        selected[pcr / 8] |= bit;
+                        digests.push(pcr);
+                    }
+                }
+            }
+            selections.extend_from_slice(&algorithm.to_be_bytes());
+            selections.push(size as u8);
+            selections.extend_from_slice(&selected);
+        }
+
+        let mut params = self.pcr_update_counter.to_be_bytes().to_vec();
+        params.extend_from_slice(&selections);
+        params.extend_from_slice(&(digests.len() as u32).to_be_bytes());
+        for pcr in digests {
+            params.extend_from_slice(&(DIGEST_SIZE as u16).to_be_bytes());
+            params.extend_from_slice(&self.pcrs[pcr]);
+        }
+        Ok(params)
+    }
+
+    fn pcr_extend(&mut self, reader: &mut CommandReader) -> TpmResult<Vec<u8>> {
+        let pcr = reader.u32()? as usize;
+        if pcr >= PCR_COUNT {
+            return Err(TPM_RC_HANDLE);
+        }
+
+        // Every session must be a password session with an empty password
+        // (the authorization value of the PCRs), and is answered with an
+        // empty acknowledgement
+        let mut sessions = vec![];
+        let size = reader.u32()? as usize;
+        let mut auth = CommandReader {
+            data: reader.bytes(size)?,
+        };
+        while !auth.data.is_empty() {
+            let handle = auth.u32()?;
+            let nonce = auth.u16()? as usize;
+            auth.bytes(nonce)?;
+            let attributes = auth.u8()?;
+            let password = auth.u16()? as usize;
+            auth.bytes(password)?;
+            if handle != TPM_RS_PW {
+                return Err(TPM_RC_HANDLE);
+            }
+            if password != 0 {
+                return Err(TPM_RC_AUTH_FAIL);
+            }
+            sessions.push(attributes);
+        }
+        if sessions.is_empty() {
+            return Err(TPM_RC_AUTH_MISSING);
+        }
+
+        let count = reader.u32()?;
+        let mut extended = None;
+        for _ in 0..count {
+            let algorithm = reader.u16()?;
+            let size = HASH_ALGORITHMS
+                .iter()
+                .find(|(alg, _)| *alg == algorithm)
+                .map(|(_, size)| *size)
+                .ok_or(TPM_RC_HASH)?;
+            let digest = reader.bytes(size)?;
+            if algorithm == TPM_ALG_SHA256 {
+                extended = Some(digest);
+            }
+        }
+        if let Some(digest) = extended {
+            let mut hash = measure::Sha256::new();
+            hash.update(&self.pcrs[pcr]);
+            hash.update(digest);
+            self.pcrs[pcr] = hash.finish();
+            self.pcr_update_counter = self.pcr_update_counter.wrapping_add(1);
+        }
+
+        // There are no response parameters
+        let mut params = 0u32.to_be_bytes().to_vec();
+        for attributes in sessions {
+            params.extend_from_slice(&0u16.to_be_bytes());
+            params.push(attributes);
+            params.extend_from_slice(&0u16.to_be_bytes());
+        }
+        Ok(params)
+    }
+
+    fn read_clock(&self) -> Vec<u8> {
+        // The clock is not persisted, so it is the same as the time (both
+        // in milliseconds since the host started), and always safe
+        let (reset_count, restart_count) = self.counts();
+        let time = time::uptime().as_millis() as u64;
+        let mut params = time.to_be_bytes().to_vec();
+        params.extend_from_slice(&time.to_be_bytes());
+        params.extend_from_slice(&reset_count.to_be_bytes());
+        params.extend_from_slice(&restart_count.to_be_bytes());
+        params.push(1);
+        params
+    }
+}
+
+/// The CRB interface of a virtual TPM
+pub struct TpmCrb {
+    base: u64,
+    granted: bool,
+    idle: bool,
+    buffer: Vec<u8>,
+    tpm: Tpm,
+}
+
+impl TpmCrb {
+    /// Create a TPM with its registers at `base`, its PCRs starting with
+    /// `launch_pcrs` and its state kept in `nvram`
+    pub fn new(
+        base: u64,
+        nvram: Arc<RwLock<Nvram>>,
+        launch_pcrs: &[Digest; PCR_COUNT],
+    ) -> Result<Arc<RwLock<Self>>> {
+        Ok(Arc::new(RwLock::new(Self::with_tpm(
+            base,
+            Tpm::new(nvram, launch_pcrs)?,
+        ))))
+    }
+
+    fn with_tpm(base: u64, tpm: Tpm) -> Self {
+        Self {
+            base: base,
+            granted: false,
+            idle: true,
+            buffer: vec![0u8; CRB_BUFFER_SIZE],
+            tpm: tpm,
+        }
+    }
+
+    /// Return the TPM and its interface to their state at power on (e.g.,
+    /// when the guest is reset)
+    pub fn reset(&mut self) {
+        self.granted = false;
+        self.idle = true;
+        self.tpm.reset();
+    }
+
+    // The value of the 32-bit register at `offset`
+    fn register(&self, offset: usize) -> u32 {
+        let buffer = self.base as u32 + CRB_DATA_BUFFER as u32;
+        match offset {
+            CRB_LOC_STATE if self.granted => {
+                LOC_STATE_ESTABLISHED | LOC_STATE_ASSIGNED | LOC_STATE_REG_VALID
+            }
+            CRB_LOC_STATE => LOC_STATE_ESTABLISHED | LOC_STATE_REG_VALID,
+            CRB_LOC_STS if self.granted => LOC_STS_GRANTED,
+            CRB_INTF_ID => INTF_ID,
+            CRB_CTRL_STS if self.idle => CTRL_STS_IDLE,
+            CRB_CTRL_CMD_SIZE | CRB_CTRL_RSP_SIZE => CRB_BUFFER_SIZE as u32,
+            CRB_CTRL_CMD_LADDR | CRB_CTRL_RSP_ADDR => buffer,
+            _ => 0,
+        }
+    }
+
+    fn read(&self, offset: usize, data: &mut [u8]) {
+        for (i, byte) in data.iter_mut().enumerate() {
+            let offset = offset + i;
+            *byte = if offset >= CRB_DATA_BUFFER {
+                self.buffer
+                    .get(offset - CRB_DATA_BUFFER)
+                    .copied()
+                    .unwrap_or(0)
+            } else {
+                (self.register(offset & !0x3) >> ((offset & 0x3) * 8)) as u8
+            };
+        }
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) {
+        if offset >= CRB_DATA_BUFFER {
+            let start = cmp::min(offset - CRB_DATA_BUFFER, CRB_BUFFER_SIZE);
+            let end = cmp::min(start + data.len(), CRB_BUFFER_SIZE);
+            self.buffer[start..end].copy_from_slice(&data[..end - start]);
+            return;
+        }
+
+        let mut bytes = [0u8; 4];
+        let len = cmp::min(data.len(), bytes.len());
+        bytes[..len].copy_from_slice(&data[..len]);
+        let val = u32::from_le_bytes(bytes);
+        match offset {
+            CRB_LOC_CTRL => {
+                if val & LOC_CTRL_REQUEST_ACCESS != 0 {
+                    self.granted = true;
+                }
+                if val & LOC_CTRL_RELINQUISH != 0 {
+                    self.granted = false;
+                }
+            }
+            CRB_CTRL_REQ => {
+                if val & CTRL_REQ_CMD_READY != 0 {
+                    self.idle = false;
+                }
+                if val & CTRL_REQ_GO_IDLE != 0 {
+                    self.idle = true;
+                }
+            }
+            CRB_CTRL_START if val & CTRL_START != 0 => self.start(),
+            _ => (),
+        }
+    }
+
+    // Execute the command in the data buffer, replacing it with the
+    // response
+    fn start(&mut self) {
+        let size = u32::from_be_bytes([
+            self.buffer[2],
+            self.buffer[3],
+            self.buffer[4],
+            self.buffer[5],
+        ]) as usize;
+        let command = &self.buffer[..cmp::min(size, CRB_BUFFER_SIZE)];
+        let response = self.tpm.execute(command);
+        self.buffer[..response.len()].copy_from_slice(&response);
+    }
+}
+
+impl EmulatedDevice for TpmCrb {
+    fn services(&self) -> Vec<DeviceRegion> {
+        vec![DeviceRegion::MemIo(
+            GuestPhysAddr::new(self.base)
+                ..=GuestPhysAddr::new(self.base + TPM_CRB_SIZE as u64 - 1),
+        )]
+    }
+
+    fn on_event(&mut self, event: Event) -> Result<()> {
+        match event.kind {
+            DeviceEvent::MemRead(addr, mut req) => {
+                let offset = (addr.as_u64() - self.base) as usize;
+                self.read(offset, req.as_mut_slice());
+            }
+            DeviceEvent::MemWrite(addr, req) => {
+                let offset = (addr.as_u64() - self.base) as usize;
+                self.write(offset, req.as_slice());
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::measure::sha256;
+    use crate::nvram::MemoryBackend;
+    use alloc::boxed::Box;
+
+    fn crb() -> TpmCrb {
+        let nvram = Nvram::new(Box::new(MemoryBackend::new(None))).unwrap();
+        let mut launch_pcrs = [[0; DIGEST_SIZE]; PCR_COUNT];
+        launch_pcrs[9] = sha256(b"kernel");
+        let tpm = Tpm::from_seed(
+            Arc::new(RwLock::new(nvram)),
+            &launch_pcrs,
+            &[0u8; SEED_SIZE],
+        );
+        TpmCrb::with_tpm(TPM_CRB_BASE, tpm)
+    }
+
+    fn command(tag: u16, code: u32, params: &[u8]) -> Vec<u8> {
+        let mut command = tag.to_be_bytes().to_vec();
+        command.extend_from_slice(&(10 + params.len() as u32).to_be_bytes());
+        command.extend_from_slice(&code.to_be_bytes());
+        command.extend_from_slice(params);
+        command
+    }
+
+    fn response_code(response: &[u8]) -> u32 {
+        u32::from_be_bytes([response[6], response[7], response[8], response[9]])
+    }
+
+    // Run `command` through the CRB registers, as a driver would
+    fn send(crb: &mut TpmCrb, command: &[u8]) -> Vec<u8> {
+        crb.write(CRB_LOC_CTRL, &LOC_CTRL_REQUEST_ACCESS.to_le_bytes());
+        crb.write(CRB_CTRL_REQ, &CTRL_REQ_CMD_READY.to_le_bytes());
+        crb.write(CRB_DATA_BUFFER, command);
+        crb.write(CRB_CTRL_START, &CTRL_START.to_le_bytes());
+
+        let mut start = [0u8; 4];
+        crb.read(CRB_CTRL_START, &mut start);
+        assert_eq!(start, [0; 4]);
+
+        let mut header = [0u8; 10];
+        crb.read(CRB_DATA_BUFFER, &mut header);
+        let size =
+            u32::from_be_bytes([header[2], header[3], header[4], header[5]]);
+        let mut response = vec![0u8; size as usize];
+        crb.read(CRB_DATA_BUFFER, &mut response);
+        response
+    }
+
+    fn read_pcr(crb: &mut TpmCrb, pcr: usize) -> Vec<u8> {
+        let mut select = [0u8; PCR_SELECT_SIZE];
+        select[pcr / 8] = 1 << (pcr % 8);
+        let mut params = 1u32.to_be_bytes().to_vec();
+        params.extend_from_slice(&TPM_ALG_SHA256.to_be_bytes());
+        params.push(PCR_SELECT_SIZE as u8);
+        params.extend_from_slice(&select);
+        let response =
+            send(crb, &command(TPM_ST_NO_SESSIONS, TPM_CC_PCR_READ, &params));
+        assert_eq!(response_code(&response), TPM_RC_SUCCESS);
+        response[response.len() - DIGEST_SIZE..].to_vec()
+    }
+
+    #[test]
+    fn test_crb_registers() {
+        let mut crb = crb();
+        let mut val = [0u8; 4];
+        crb.read(CRB_LOC_STATE, &mut val);
+        assert_eq!(u32::from_le_bytes(val) & LOC_STATE_ASSIGNED, 0);
+        crb.write(CRB_LOC_CTRL, &LOC_CTRL_REQUEST_ACCESS.to_le_bytes());
+        crb.read(CRB_LOC_STATE, &mut val);
+        assert_ne!(u32::from_le_bytes(val) & LOC_STATE_ASSIGNED, 0);
+
+        crb.read(CRB_CTRL_STS, &mut val);
+        assert_eq!(u32::from_le_bytes(val), CTRL_STS_IDLE);
+        crb.write(CRB_CTRL_REQ, &CTRL_REQ_CMD_READY.to_le_bytes());
+        crb.read(CRB_CTRL_STS, &mut val);
+        assert_eq!(u32::from_le_bytes(val), 0);
+
+        // The command and response share the data buffer
+        let mut addr = [0u8; 8];
+        crb.read(CRB_CTRL_RSP_ADDR, &mut addr);
+        assert_eq!(u64::from_le_bytes(addr), TPM_CRB_BASE + 0x80);
+        crb.read(CRB_CTRL_CMD_LADDR, &mut val);
+        assert_eq!(u32::from_le_bytes(val) as u64, TPM_CRB_BASE + 0x80);
+    }
+
+    #[test]
+    fn test_startup_and_pcrs() {
+        let mut crb = crb();
+        let self_test = command(TPM_ST_NO_SESSIONS, TPM_CC_SELF_TEST, &[1]);
+        let response = send(&mut crb, &self_test);
+        assert_eq!(response_code(&response), TPM_RC_INITIALIZE);
+
+        let startup = command(
+            TPM_ST_NO_SESSIONS,
+            TPM_CC_STARTUP,
+            &TPM_SU_CLEAR.to_be_bytes(),
+        );
+        assert_eq!(response_code(&send(&mut crb, &startup)), TPM_RC_SUCCESS);
+        assert_eq!(response_code(&send(&mut crb, &startup)), TPM_RC_INITIALIZE);
+        assert_eq!(response_code(&send(&mut crb, &self_test)), TPM_RC_SUCCESS);
+        assert_eq!(crb.tpm.counts(), (1, 0));
+
+        // The PCRs start with the launch measurements
+        assert_eq!(read_pcr(&mut crb, 9), sha256(b"kernel").to_vec());
+        assert_eq!(read_pcr(&mut crb, 10), vec![0u8; DIGEST_SIZE]);
+
+        // Extend PCR 10 with a password session
+        let digest = sha256(b"abc");
+        let mut params = 10u32.to_be_bytes().to_vec();
+        params.extend_from_slice(&9u32.to_be_bytes());
+        params.extend_from_slice(&TPM_RS_PW.to_be_bytes());
+        params.extend_from_slice(&[0, 0, 1, 0, 0]);
+        params.extend_from_slice(&1u32.to_be_bytes());
+        params.extend_from_slice(&TPM_ALG_SHA256.to_be_bytes());
+        params.extend_from_slice(&digest);
+        let extend = command(TPM_ST_SESSIONS, TPM_CC_PCR_EXTEND, &params);
+        let response = send(&mut crb, &extend);
+        assert_eq!(response_code(&response), TPM_RC_SUCCESS);
+        assert_eq!(&response[10..], &[0, 0, 0, 0, 0, 0, 1, 0, 0]);
+
+        let mut expected = [0u8; 2 * DIGEST_SIZE];
+        expected[DIGEST_SIZE..].copy_from_slice(&digest);
+        assert_eq!(read_pcr(&mut crb, 10), sha256(&expected).to_vec());
+
+        // Extending requires an authorization
+        let extend = command(TPM_ST_NO_SESSIONS, TPM_CC_PCR_EXTEND, &params);
+        let response = send(&mut crb, &extend);
+        assert_eq!(response_code(&response), TPM_RC_AUTH_MISSING);
+
+        // A reset restores the launch measurements and counts the reset
+        crb.reset();
+        assert_eq!(response_code(&send(&mut crb, &startup)), TPM_RC_SUCCESS);
+        assert_eq!(read_pcr(&mut crb, 10), vec![0u8; DIGEST_SIZE]);
+        assert_eq!(crb.tpm.counts(), (2, 0));
+    }
+
+    #[test]
+    fn test_get_capability() {
+        let mut crb = crb();
+        crb.tpm.started = true;
+
+        // The total number of commands is the property probed by Linux
+        let mut params = TPM_CAP_TPM_PROPERTIES.to_be_bytes().to_vec();
+        params.extend_from_slice(&0x129u32.to_be_bytes());
+        params.extend_from_slice(&1u32.to_be_bytes());
+        let response = send(
+            &mut crb,
+            &command(TPM_ST_NO_SESSIONS, TPM_CC_GET_CAPABILITY, &params),
+        );
+        assert_eq!(response_code(&response), TPM_RC_SUCCESS);
+        assert_eq!(response[10], 0);
+        assert_eq!(&response[15..19], &1u32.to_be_bytes());
+        assert_eq!(&response[19..23], &0x129u32.to_be_bytes());
+        assert_eq!(&response[23..27], &8u32.to_be_bytes());
+
+        // The command list is paged
+        let mut params = TPM_CAP_COMMANDS.to_be_bytes().to_vec();
+        params.extend_from_slice(&TPM_CC_PCR_READ.to_be_bytes());
+        params.extend_from_slice(&2u32.to_be_bytes());
+        let response = send(
+            &mut crb,
+            &command(TPM_ST_NO_SESSIONS, TPM_CC_GET_CAPABILITY, &params),
+        );
+        assert_eq!(response[10], 1);
+        assert_eq!(&response[15..19], &2u32.to_be_bytes());
+        assert_eq!(&response[19..23], &TPM_CC_PCR_READ.to_be_bytes());
+        assert_eq!(&response[23..27], &TPM_CC_READ_CLOCK.to_be_bytes());
+
+        let response = send(&mut crb, &command(TPM_ST_NO_SESSIONS, 0x17f, &[]));
+        assert_eq!(response_code(&response), TPM_RC_COMMAND_CODE);
+    }
+}
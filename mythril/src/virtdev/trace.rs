@@ -0,0 +1,466 @@
+//! Per-device event tracing
+//!
+//! A device registered in a `DeviceMap` can be traced with
+//! `DeviceMap::trace_device`, which wraps it in a `TracingDevice`. Every
+//! `Event` delivered to a traced device is recorded (with the data returned
+//! for reads) along with the responses it produced, in a bounded
+//! `DeviceTrace`. Traces can be viewed with the `devtrace` management
+//! console command, and recorded traces can be replayed against a fresh
+//! instance of the device with `replay` (e.g., in a unit test) to
+//! reproduce device model bugs.
+
+use crate::error::{Error, Result};
+use crate::memory::{
+    GuestAddressSpace, GuestAddressSpaceViewMut, GuestPhysAddr,
+};
+use crate::percore;
+use crate::physdev::com::SerialSignal;
+use crate::virtdev::{
+    acpi, pci, DeviceEvent, DeviceEventResponse, DeviceRegion, EmulatedDevice,
+    Event, MemReadRequest, MemWriteRequest, Port, PortReadRequest,
+    PortWriteRequest, ResponseEventArray,
+};
+use crate::vm;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::fmt;
+use spin::{Mutex, RwLock};
+
+/// An `Event` delivered to a traced device, with the data it carried (for
+/// writes) or the data the device returned (for reads)
+#[derive(Clone, Debug, PartialEq)]
+pub enum TracedEvent {
+    HostUartReceived(u8),
+    HostUartSignal(SerialSignal),
+    HotplugRequested(acpi::HotplugEvent),
+    PcieHotplugRequested(pci::PcieHotplugEvent),
+    BalloonRequested(u32),
+    MemRead(GuestPhysAddr, Vec<u8>),
+    MemWrite(GuestPhysAddr, Vec<u8>),
+    PortRead(Port, Vec<u8>),
+    PortWrite(Port, Vec<u8>),
+}
+
+/// A single event delivered to a traced device and its outcome
+#[derive(Clone, Debug)]
+pub struct TraceRecord {
+    pub event: TracedEvent,
+    pub responses: Vec<DeviceEventResponse>,
+    /// Whether the device returned an error for the event
+    pub failed: bool,
+}
+
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.event {
+            TracedEvent::HostUartReceived(val) => {
+                write!(f, "uart rx 0x{:02x}", val)?
+            }
+            TracedEvent::HostUartSignal(signal) => {
+                write!(f, "uart signal {:?}", signal)?
+            }
+            TracedEvent::HotplugRequested(event) => {
+                write!(f, "hotplug {:?}", event)?
+            }
+            TracedEvent::PcieHotplugRequested(event) => {
+                write!(f, "pcie hotplug {:?}", event)?
+            }
+            TracedEvent::BalloonRequested(pages) => {
+                write!(f, "balloon {} pages", pages)?
+            }
+            TracedEvent::MemRead(addr, data) => {
+                write!(f, "read  0x{:x} -> {:02x?}", addr.as_u64(), data)?
+            }
+            TracedEvent::MemWrite(addr, data) => {
+                write!(f, "write 0x{:x} <- {:02x?}", addr.as_u64(), data)?
+            }
+            TracedEvent::PortRead(port, data) => {
+                write!(f, "in  0x{:04x} -> {:02x?}", port, data)?
+            }
+            TracedEvent::PortWrite(port, data) => {
+                write!(f, "out 0x{:04x} <- {:02x?}", port, data)?
+            }
+        }
+        if self.failed {
+            write!(f, " (failed)")?;
+        }
+        for response in self.responses.iter() {
+            write!(f, " => {:?}", response)?;
+        }
+        Ok(())
+    }
+}
+
+/// The most recent events delivered to a traced device
+pub struct DeviceTrace {
+    records: VecDeque<TraceRecord>,
+    capacity: usize,
+    /// The number of records discarded to make room for newer records
+    dropped: u64,
+}
+
+impl DeviceTrace {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            records: VecDeque::with_capacity(capacity),
+            capacity: capacity.max(1),
+            dropped: 0,
+        }
+    }
+
+    pub fn push(&mut self, record: TraceRecord) {
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+            self.dropped += 1;
+        }
+        self.records.push_back(record);
+    }
+
+    pub fn records(&self) -> impl Iterator<Item = &TraceRecord> {
+        self.records.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+        self.dropped = 0;
+    }
+}
+
+/// A device that records the events delivered to the device it wraps
+pub struct TracingDevice {
+    inner: Arc<RwLock<dyn EmulatedDevice>>,
+    trace: Arc<Mutex<DeviceTrace>>,
+}
+
+impl TracingDevice {
+    pub fn new(
+        inner: Arc<RwLock<dyn EmulatedDevice>>,
+        trace: Arc<Mutex<DeviceTrace>>,
+    ) -> Arc<RwLock<Self>> {
+        Arc::new(RwLock::new(Self { inner, trace }))
+    }
+}
+
+// Deliver `kind` to `device`, returning the data returned for reads (the
+// data written for writes) and whether the device succeeded
+fn deliver(
+    device: &mut dyn EmulatedDevice,
+    kind: DeviceEvent,
+    space: GuestAddressSpaceViewMut,
+    responses: &mut ResponseEventArray,
+) -> Result<(TracedEvent, Result<()>)> {
+    // Reads are delivered with a buffer owned by the trace, so the result
+    // can be recorded before it is copied back to the original request
+    let (traced, result) = match kind {
+        DeviceEvent::PortRead(port, mut request) => {
+            let mut data = request.as_slice().to_vec();
+            let result = device.on_event(Event::new(
+                DeviceEvent::PortRead(
+                    port,
+                    PortReadRequest::try_from(&mut data[..])?,
+                ),
+                space,
+                responses,
+            )?);
+            request.as_mut_slice().copy_from_slice(&data);
+            (TracedEvent::PortRead(port, data), result)
+        }
+        DeviceEvent::MemRead(addr, mut request) => {
+            let mut data = request.as_slice().to_vec();
+            let result = device.on_event(Event::new(
+                DeviceEvent::MemRead(addr, MemReadRequest::new(&mut data)),
+                space,
+                responses,
+            )?);
+            request.as_mut_slice().copy_from_slice(&data);
+            (TracedEvent::MemRead(addr, data), result)
+        }
+        kind => {
+            let traced = match &kind {
+                DeviceEvent::HostUartReceived(val) => {
+                    TracedEvent::HostUartReceived(*val)
+                }
+                DeviceEvent::HostUartSignal(signal) => {
+                    TracedEvent::HostUartSignal(*signal)
+                }
+                DeviceEvent::HotplugRequested(event) => {
+                    TracedEvent::HotplugRequested(*event)
+                }
+                DeviceEvent::PcieHotplugRequested(event) => {
+                    TracedEvent::PcieHotplugRequested(*event)
+                }
+                DeviceEvent::BalloonRequested(pages) => {
+                    TracedEvent::BalloonRequested(*pages)
+                }
+                DeviceEvent::PortWrite(port, request) => {
+                    TracedEvent::PortWrite(*port, request.as_slice().to_vec())
+                }
+                DeviceEvent::MemWrite(addr, request) => {
+                    TracedEvent::MemWrite(*addr, request.as_slice().to_vec())
+                }
+                DeviceEvent::PortRead(..) | DeviceEvent::MemRead(..) => {
+                    unreachable!()
+                }
+            };
+            let result = device.on_event(Event::new(kind, space, responses)?);
+            (traced, result)
+        }
+    };
+    Ok((traced, result))
+}
+
+impl EmulatedDevice for TracingDevice {
+    fn services(&self) -> Vec<DeviceRegion> {
+        self.inner.read().services()
+    }
+
+    fn on_event(&mut self, event: Event) -> Result<()> {
+        let Event {
+            kind,
+            space,
+            responses,
+        } = event;
+        let start = responses.len();
+        let (traced, result) =
+            deliver(&mut *self.inner.write(), kind, space, responses)?;
+        self.trace.lock().push(TraceRecord {
+            event: traced,
+            responses: responses[start..].to_vec(),
+            failed: result.is_err(),
+        });
+        result
+    }
+}
+
+/// Deliver the events of a recorded trace to `device`, checking that the
+/// device returns the same data and responses as when it was recorded
+///
+/// Reads are delivered with zeroed buffers. Returns an error describing
+/// the first event for which the device diverged from the trace.
+pub fn replay<'a>(
+    device: &mut dyn EmulatedDevice,
+    records: impl IntoIterator<Item = &'a TraceRecord>,
+    space: &mut GuestAddressSpace,
+) -> Result<()> {
+    for (i, record) in records.into_iter().enumerate() {
+        let mut responses = ResponseEventArray::default();
+        let view = GuestAddressSpaceViewMut::new(GuestPhysAddr::new(0), space);
+        let mut data = match &record.event {
+            TracedEvent::MemRead(_, data) | TracedEvent::PortRead(_, data) => {
+                vec![0u8; data.len()]
+            }
+            TracedEvent::MemWrite(_, data)
+            | TracedEvent::PortWrite(_, data) => data.clone(),
+            _ => vec![],
+        };
+        let kind = match &record.event {
+            TracedEvent::HostUartReceived(val) => {
+                DeviceEvent::HostUartReceived(*val)
+            }
+            TracedEvent::HostUartSignal(signal) => {
+                DeviceEvent::HostUartSignal(*signal)
+            }
+            TracedEvent::HotplugRequested(event) => {
+                DeviceEvent::HotplugRequested(*event)
+            }
+            TracedEvent::PcieHotplugRequested(event) => {
+                DeviceEvent::PcieHotplugRequested(*event)
+            }
+            TracedEvent::BalloonRequested(pages) => {
+                DeviceEvent::BalloonRequested(*pages)
+            }
+            TracedEvent::MemRead(addr, _) => {
+                DeviceEvent::MemRead(*addr, MemReadRequest::new(&mut data))
+            }
+            TracedEvent::MemWrite(addr, _) => {
+                DeviceEvent::MemWrite(*addr, MemWriteRequest::new(&data))
+            }
+            TracedEvent::PortRead(port, _) => DeviceEvent::PortRead(
+                *port,
+                PortReadRequest::try_from(&mut data[..])?,
+            ),
+            TracedEvent::PortWrite(port, _) => DeviceEvent::PortWrite(
+                *port,
+                PortWriteRequest::try_from(&data[..])?,
+            ),
+        };
+        let (traced, result) = deliver(device, kind, view, &mut responses)?;
+
+        let replayed = TraceRecord {
+            event: traced,
+            responses: responses.to_vec(),
+            failed: result.is_err(),
+        };
+        // Responses do not implement PartialEq, so compare their formatting
+        if replayed.event != record.event
+            || replayed.failed != record.failed
+            || format!("{:?}", replayed.responses)
+                != format!("{:?}", record.responses)
+        {
+            return Err(Error::InvalidValue(format!(
+                "Trace diverged at event {}: expected '{}', found '{}'",
+                i, record, replayed
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// The `devtrace` management console command
+pub fn devtrace_command(
+    out: &mut dyn fmt::Write,
+    args: &[&str],
+) -> fmt::Result {
+    let vm_id = match args.get(0).and_then(|vm| vm::find_vm(vm)) {
+        Some(vm_id) => vm_id,
+        None => return writeln!(out, "usage: devtrace <vm> [device [clear]]"),
+    };
+    let vm = match unsafe { vm::get_vm(vm_id) } {
+        Some(vm) => vm,
+        None => return writeln!(out, "No VM {}", vm_id),
+    };
+    let vm = vm.read();
+    let traces = vm.config.virtual_devices().traces();
+
+    let name = match args.get(1) {
+        Some(name) => name,
+        None => {
+            if traces.is_empty() {
+                return writeln!(out, "No traced devices");
+            }
+            for (name, trace) in traces.iter() {
+                writeln!(out, "{:<12} {} events", name, trace.lock().len())?;
+            }
+            return Ok(());
+        }
+    };
+    let trace = match traces.iter().find(|(traced, _)| traced == name) {
+        Some((_, trace)) => trace,
+        None => return writeln!(out, "Device '{}' is not traced", name),
+    };
+    let mut trace = trace.lock();
+    if args.get(2) == Some(&"clear") {
+        trace.clear();
+        return Ok(());
+    }
+    if trace.dropped() > 0 {
+        writeln!(out, "({} earlier events dropped)", trace.dropped())?;
+    }
+    for record in trace.records() {
+        writeln!(out, "{}", record)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::virtdev::{DeviceInteraction, DeviceMap};
+    use alloc::boxed::Box;
+
+    // A latch: writes store a byte, reads return it, and writing 0xff
+    // requests an interrupt
+    #[derive(Default)]
+    struct Latch {
+        value: u8,
+    }
+
+    impl EmulatedDevice for Latch {
+        fn services(&self) -> Vec<DeviceRegion> {
+            vec![DeviceRegion::PortIo(0x80..=0x80)]
+        }
+
+        fn on_event(&mut self, event: Event) -> Result<()> {
+            match event.kind {
+                DeviceEvent::PortRead(_, mut request) => {
+                    request.copy_from_u32(self.value as u32)
+                }
+                DeviceEvent::PortWrite(_, request) => {
+                    self.value = request.as_u8();
+                    if self.value == 0xff {
+                        event.responses.push(DeviceEventResponse::NextConsole);
+                    }
+                }
+                _ => return Err(Error::NotSupported),
+            }
+            Ok(())
+        }
+    }
+
+    fn send(map: &DeviceMap, kind: DeviceEvent) -> Result<()> {
+        let space = Box::leak(Box::new(GuestAddressSpace::new().unwrap()));
+        let mut responses = ResponseEventArray::default();
+        let view = GuestAddressSpaceViewMut::new(GuestPhysAddr::new(0), space);
+        let device = map.find_device(0x80u16).unwrap();
+        let event = Event::new(kind, view, &mut responses)?;
+        device.write().on_event(event)
+    }
+
+    fn trace_latch() -> (DeviceMap, Arc<Mutex<DeviceTrace>>) {
+        let mut map = DeviceMap::default();
+        map.register_device(Arc::new(RwLock::new(Latch::default())))
+            .unwrap();
+        let trace = map.trace_device(0x80u16, "latch", 2).unwrap();
+
+        for value in [0x12u8, 0xff].iter() {
+            let data = [*value];
+            let request = PortWriteRequest::try_from(&data[..]).unwrap();
+            send(&map, DeviceEvent::PortWrite(0x80, request)).unwrap();
+        }
+        let mut data = [0u8];
+        let request = PortReadRequest::try_from(&mut data[..]).unwrap();
+        send(&map, DeviceEvent::PortRead(0x80, request)).unwrap();
+        assert_eq!(data, [0xff]);
+        (map, trace)
+    }
+
+    #[test]
+    fn test_trace_device() {
+        let (map, trace) = trace_latch();
+        assert_eq!(map.traces().len(), 1);
+        assert!(0x80u16.find_device(&map).is_some());
+
+        let trace = trace.lock();
+        assert_eq!((trace.len(), trace.dropped()), (2, 1));
+        let records = trace.records().collect::<Vec<_>>();
+        assert_eq!(records[0].event, TracedEvent::PortWrite(0x80, vec![0xff]));
+        assert_eq!(records[0].responses.len(), 1);
+        assert_eq!(records[1].event, TracedEvent::PortRead(0x80, vec![0xff]));
+        assert_eq!(format!("{}", records[1]), "in  0x0080 -> [ff]");
+
+        assert!(DeviceMap::default()
+            .trace_device(0x80u16, "latch", 2)
+            .is_err());
+    }
+
+    #[test]
+    fn test_replay() {
+        let (_, trace) = trace_latch();
+        let trace = trace.lock();
+        let mut space = GuestAddressSpace::new().unwrap();
+
+        // The first recorded event (writing 0x12) was dropped, so the
+        // latch must be in the same state when the trace is replayed
+        let mut latch = Latch { value: 0x12 };
+        replay(&mut latch, trace.records(), &mut space).unwrap();
+
+        let mut diverged = trace.records().cloned().collect::<Vec<_>>();
+        diverged[1].event = TracedEvent::PortRead(0x80, vec![0x12]);
+        let mut latch = Latch::default();
+        assert!(replay(&mut latch, diverged.iter(), &mut space).is_err());
+    }
+}
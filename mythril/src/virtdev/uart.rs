@@ -0,0 +1,602 @@
+//! Emulation of a 16550A UART
+//!
+//! Bytes written by the guest are transmitted immediately, so the transmit
+//! FIFO never fills and the THR-empty interrupt is raised again after every
+//! byte. Bytes received from the host are held in the receive FIFO (or the
+//! single receive buffer, while the FIFOs are disabled) until the guest
+//! reads them, and are dropped with an overrun error when it is full.
+//!
+//! Interrupts are raised on the UART's legacy IRQ when an enabled condition
+//! becomes pending, in the priority order of the IIR. There is no receive
+//! timer, so received data below the FIFO trigger level is reported as a
+//! character timeout straight away.
+//!
+//! Each UART has a `SerialBackend` that receives the bytes the guest
+//! transmits. Only a UART backed by the host serial port receives input,
+//! which includes breaks (so a guest can be sent a sysrq over serial) and
+//! the state of the host's modem status lines.
+
+use crate::error::Result;
+use crate::physdev::com::{IerFlags, LsrFlags, SerialOffset, SerialSignal};
+use crate::vcpu;
+use crate::virtdev::pic::LEGACY_IRQ_VECTOR_BASE;
+use crate::virtdev::{
+    DeviceEvent, DeviceEventResponse, DeviceRegion, EmulatedDevice, Event,
+    Port, ResponseEventArray,
+};
+use alloc::collections::vec_deque::VecDeque;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use core::convert::TryInto;
+use spin::RwLock;
+
+const FIFO_SIZE: usize = 16;
+
+/// The standard base ports and IRQs of COM1 to COM4
+pub const COM_PORTS: [(Port, u8); 4] =
+    [(0x3f8, 4), (0x2f8, 3), (0x3e8, 4), (0x2e8, 3)];
+
+// The longest line a `SerialBackend::Log` UART buffers before logging it
+const MAX_LOG_LINE: usize = 256;
+
+// Interrupt identification values, in order of increasing priority
+const IIR_NO_INTERRUPT: u8 = 0x01;
+const IIR_MODEM_STATUS: u8 = 0x00;
+const IIR_THR_EMPTY: u8 = 0x02;
+const IIR_RX_TIMEOUT: u8 = 0x0c;
+const IIR_RX_DATA: u8 = 0x04;
+const IIR_LINE_STATUS: u8 = 0x06;
+const IIR_FIFOS_ENABLED: u8 = 0xc0;
+
+// The divisor latch access bit of the LCR
+const LCR_DLAB: u8 = 1 << 7;
+
+// The receive FIFO trigger levels selected by FCR bits 6 and 7
+const RX_TRIGGER_LEVELS: [usize; 4] = [1, 4, 8, 14];
+
+bitflags! {
+    /// The FIFO control register
+    pub struct FcrFlags: u8 {
+        const ENABLE = 1 << 0;
+        const CLEAR_RX = 1 << 1;
+        const CLEAR_TX = 1 << 2;
+        const DMA_MODE = 1 << 3;
+    }
+}
+
+bitflags! {
+    /// The modem control register
+    pub struct McrFlags: u8 {
+        const DTR = 1 << 0;
+        const RTS = 1 << 1;
+        const OUT1 = 1 << 2;
+        const OUT2 = 1 << 3;
+        const LOOPBACK = 1 << 4;
+    }
+}
+
+bitflags! {
+    /// The modem status register
+    pub struct MsrFlags: u8 {
+        const DELTA_CTS = 1 << 0;
+        const DELTA_DSR = 1 << 1;
+        const TRAILING_EDGE_RI = 1 << 2;
+        const DELTA_DCD = 1 << 3;
+        const CTS = 1 << 4;
+        const DSR = 1 << 5;
+        const RI = 1 << 6;
+        const DCD = 1 << 7;
+    }
+}
+
+/// The destination of the bytes the guest transmits on a UART
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SerialBackend {
+    /// The physical serial connection of the VM (if it has one), which is
+    /// also the source of the UART's input
+    HostSerial,
+
+    /// The hypervisor log, one line at a time
+    Log,
+
+    /// Transmitted bytes are dropped
+    Discard,
+}
+
+#[derive(Debug)]
+pub struct Uart16550 {
+    base_port: Port,
+    irq: u8,
+    backend: SerialBackend,
+    log_line: Vec<u8>,
+    divisor: u16,
+    ier: IerFlags,
+    lcr: u8,
+    mcr: McrFlags,
+    scratch: u8,
+    fifo_enabled: bool,
+    rx_trigger: usize,
+    rx: VecDeque<u8>,
+    // Overrun and break errors, cleared by reading the LSR
+    line_errors: LsrFlags,
+    msr_deltas: MsrFlags,
+    // The modem status lines of the host serial port
+    host_lines: MsrFlags,
+    // The transmit holding register emptied since the guest last saw it
+    // reported in the IIR
+    thr_interrupt: bool,
+    irq_raised: bool,
+
+    ctrl_a_count: u8,
+}
+
+impl Uart16550 {
+    pub fn new(
+        base_port: Port,
+        irq: u8,
+        backend: SerialBackend,
+    ) -> Arc<RwLock<Self>> {
+        Arc::new(RwLock::new(Self {
+            base_port,
+            irq,
+            backend,
+            log_line: vec![],
+            divisor: 12,
+            ier: IerFlags::empty(),
+            lcr: 0,
+            mcr: McrFlags::empty(),
+            scratch: 0,
+            fifo_enabled: false,
+            rx_trigger: 1,
+            rx: VecDeque::with_capacity(FIFO_SIZE),
+            line_errors: LsrFlags::empty(),
+            msr_deltas: MsrFlags::empty(),
+            host_lines: MsrFlags::CTS | MsrFlags::DSR | MsrFlags::DCD,
+            thr_interrupt: false,
+            irq_raised: false,
+            ctrl_a_count: 0,
+        }))
+    }
+
+    pub fn backend(&self) -> SerialBackend {
+        self.backend
+    }
+
+    /// Add a transmitted byte to the current log line, returning the line
+    /// once it is complete
+    fn log_byte(&mut self, data: u8) -> Option<String> {
+        if data != b'\n' {
+            self.log_line.push(data);
+            if self.log_line.len() < MAX_LOG_LINE {
+                return None;
+            }
+        }
+        let line = String::from_utf8_lossy(&self.log_line)
+            .trim_end_matches('\r')
+            .into();
+        self.log_line.clear();
+        Some(line)
+    }
+
+    fn transmit(&mut self, data: u8, responses: &mut ResponseEventArray) {
+        match self.backend {
+            SerialBackend::HostSerial => {
+                responses.push(DeviceEventResponse::GuestUartTransmitted(data))
+            }
+            SerialBackend::Log => {
+                if let Some(line) = self.log_byte(data) {
+                    info!("uart 0x{:x}: {}", self.base_port, line);
+                }
+            }
+            SerialBackend::Discard => (),
+        }
+    }
+
+    fn dlab(&self) -> bool {
+        self.lcr & LCR_DLAB != 0
+    }
+
+    fn loopback(&self) -> bool {
+        self.mcr.contains(McrFlags::LOOPBACK)
+    }
+
+    /// Insert a byte to the receive FIFO of the UART. This will be
+    /// _read_ by the VM.
+    pub fn receive(&mut self, data: u8) {
+        let capacity = if self.fifo_enabled { FIFO_SIZE } else { 1 };
+        if self.rx.len() >= capacity {
+            self.line_errors.insert(LsrFlags::OVERRUN_ERROR);
+            if !self.fifo_enabled {
+                // The new byte overwrites the receive buffer
+                self.rx.pop_front();
+                self.rx.push_back(data);
+            }
+            return;
+        }
+        self.rx.push_back(data);
+    }
+
+    /// Receive a break, which arrives as a zero byte along with a break
+    /// error
+    pub fn receive_break(&mut self) {
+        self.receive(0);
+        self.line_errors.insert(LsrFlags::BREAK_INTERRUPT);
+    }
+
+    // The state of the modem status lines. In loopback mode, these follow
+    // the modem control outputs. Otherwise, they follow the lines of the
+    // host serial port (a ready modem, until the host reports otherwise).
+    fn modem_lines(&self) -> MsrFlags {
+        if !self.loopback() {
+            return self.host_lines;
+        }
+        let mut lines = MsrFlags::empty();
+        lines.set(MsrFlags::CTS, self.mcr.contains(McrFlags::RTS));
+        lines.set(MsrFlags::DSR, self.mcr.contains(McrFlags::DTR));
+        lines.set(MsrFlags::RI, self.mcr.contains(McrFlags::OUT1));
+        lines.set(MsrFlags::DCD, self.mcr.contains(McrFlags::OUT2));
+        lines
+    }
+
+    fn write_mcr(&mut self, val: u8) {
+        let old = self.modem_lines();
+        self.mcr = McrFlags::from_bits_truncate(val);
+        self.update_msr_deltas(old);
+    }
+
+    fn set_host_lines(&mut self, lines: u8) {
+        let old = self.modem_lines();
+        self.host_lines = MsrFlags::from_bits_truncate(lines & 0xf0);
+        self.update_msr_deltas(old);
+    }
+
+    // Record the changes of the modem status lines from `old`
+    fn update_msr_deltas(&mut self, old: MsrFlags) {
+        let new = self.modem_lines();
+        let changed = old ^ new;
+        if changed.contains(MsrFlags::CTS) {
+            self.msr_deltas.insert(MsrFlags::DELTA_CTS);
+        }
+        if changed.contains(MsrFlags::DSR) {
+            self.msr_deltas.insert(MsrFlags::DELTA_DSR);
+        }
+        if changed.contains(MsrFlags::DCD) {
+            self.msr_deltas.insert(MsrFlags::DELTA_DCD);
+        }
+        if old.contains(MsrFlags::RI) && !new.contains(MsrFlags::RI) {
+            self.msr_deltas.insert(MsrFlags::TRAILING_EDGE_RI);
+        }
+    }
+
+    fn write_fcr(&mut self, val: u8) {
+        let fcr = FcrFlags::from_bits_truncate(val);
+        let enable = fcr.contains(FcrFlags::ENABLE);
+
+        // Changing the FIFO mode clears the FIFOs
+        if enable != self.fifo_enabled || fcr.contains(FcrFlags::CLEAR_RX) {
+            self.rx.clear();
+        }
+        self.fifo_enabled = enable;
+        self.rx_trigger = RX_TRIGGER_LEVELS[(val >> 6) as usize];
+    }
+
+    fn write_ier(&mut self, val: u8) {
+        let ier = IerFlags::from_bits_truncate(val & 0x0f);
+
+        // The holding register is always empty, so enabling its interrupt
+        // raises it
+        if ier.contains(IerFlags::THR_EMPTY_INTERRUPT)
+            && !self.ier.contains(IerFlags::THR_EMPTY_INTERRUPT)
+        {
+            self.thr_interrupt = true;
+        }
+        self.ier = ier;
+    }
+
+    fn line_status(&self) -> LsrFlags {
+        let mut lsr = self.line_errors
+            | LsrFlags::EMPTY_TRANSMIT_HOLDING_REGISTER
+            | LsrFlags::EMPTY_DATA_HOLDING_REGISTER;
+        if !self.rx.is_empty() {
+            lsr.insert(LsrFlags::DATA_READY);
+        }
+        lsr
+    }
+
+    /// The highest priority pending interrupt, as reported by the IIR
+    fn interrupt_identification(&self) -> u8 {
+        let ier = self.ier;
+        let id = if ier.contains(IerFlags::RECEIVER_LINE_STATUS_INTERRUPT)
+            && !self.line_errors.is_empty()
+        {
+            IIR_LINE_STATUS
+        } else if ier.contains(IerFlags::RECV_DATA_AVAIL_INTERRUPT)
+            && !self.rx.is_empty()
+        {
+            if !self.fifo_enabled || self.rx.len() >= self.rx_trigger {
+                IIR_RX_DATA
+            } else {
+                IIR_RX_TIMEOUT
+            }
+        } else if ier.contains(IerFlags::THR_EMPTY_INTERRUPT)
+            && self.thr_interrupt
+        {
+            IIR_THR_EMPTY
+        } else if ier.contains(IerFlags::MODEM_STATUS_INTERRUPT)
+            && !self.msr_deltas.is_empty()
+        {
+            IIR_MODEM_STATUS
+        } else {
+            IIR_NO_INTERRUPT
+        };
+
+        if self.fifo_enabled {
+            id | IIR_FIFOS_ENABLED
+        } else {
+            id
+        }
+    }
+
+    /// Read the register at `offset` from the base port
+    fn read_register(&mut self, offset: u16) -> u8 {
+        match offset {
+            SerialOffset::DATA if self.dlab() => self.divisor as u8,
+            SerialOffset::DATA => self.rx.pop_front().unwrap_or(0),
+            SerialOffset::IER if self.dlab() => (self.divisor >> 8) as u8,
+            SerialOffset::IER => self.ier.bits(),
+            SerialOffset::IIR => {
+                let iir = self.interrupt_identification();
+                if iir & 0x0f == IIR_THR_EMPTY {
+                    self.thr_interrupt = false;
+                }
+                iir
+            }
+            SerialOffset::LCR => self.lcr,
+            SerialOffset::MCR => self.mcr.bits(),
+            SerialOffset::LSR => {
+                let lsr = self.line_status();
+                self.line_errors = LsrFlags::empty();
+                lsr.bits()
+            }
+            SerialOffset::MSR => {
+                let msr = self.modem_lines() | self.msr_deltas;
+                self.msr_deltas = MsrFlags::empty();
+                msr.bits()
+            }
+            SerialOffset::SCR => self.scratch,
+            _ => 0xff,
+        }
+    }
+
+    /// Write the register at `offset` from the base port, returning the
+    /// byte to transmit (if any)
+    fn write_register(&mut self, offset: u16, val: u8) -> Option<u8> {
+        match offset {
+            SerialOffset::DLL if self.dlab() => {
+                self.divisor = (self.divisor & 0xff00) | val as u16;
+            }
+            SerialOffset::DATA => {
+                // The byte leaves the holding register immediately
+                self.thr_interrupt = true;
+                if self.loopback() {
+                    self.receive(val);
+                } else {
+                    return Some(val);
+                }
+            }
+            SerialOffset::DLH if self.dlab() => {
+                self.divisor = (self.divisor & 0xff) | (val as u16) << 8;
+            }
+            SerialOffset::IER => self.write_ier(val),
+            SerialOffset::FCR => self.write_fcr(val),
+            SerialOffset::LCR => self.lcr = val,
+            SerialOffset::MCR => self.write_mcr(val),
+            SerialOffset::SCR => self.scratch = val,
+            _ => (),
+        }
+        None
+    }
+
+    /// Returns true if an interrupt became pending since the last call
+    fn raise_irq(&mut self) -> bool {
+        let pending = self.interrupt_identification() & IIR_NO_INTERRUPT == 0;
+        let raise = pending && !self.irq_raised;
+        self.irq_raised = pending;
+        raise
+    }
+}
+
+impl EmulatedDevice for Uart16550 {
+    fn services(&self) -> Vec<DeviceRegion> {
+        vec![DeviceRegion::PortIo(self.base_port..=self.base_port + 7)]
+    }
+
+    fn on_event(&mut self, event: Event) -> Result<()> {
+        match event.kind {
+            DeviceEvent::HostUartReceived(key) => {
+                if key == 0x01 {
+                    // ctrl+a
+                    self.ctrl_a_count += 1;
+                }
+                if self.ctrl_a_count == 3 {
+                    event.responses.push(DeviceEventResponse::NextConsole);
+                    self.ctrl_a_count = 0;
+                }
+
+                // The receiver is disconnected in loopback mode
+                if !self.loopback() {
+                    self.receive(key);
+                }
+            }
+            DeviceEvent::HostUartSignal(signal) => match signal {
+                SerialSignal::Break if !self.loopback() => self.receive_break(),
+                SerialSignal::Break => (),
+                SerialSignal::ModemStatus(lines) => self.set_host_lines(lines),
+            },
+            DeviceEvent::PortRead(port, mut val) => {
+                let data = self.read_register(port - self.base_port);
+                val.copy_from_u32(data as u32);
+            }
+            DeviceEvent::PortWrite(port, val) => {
+                let val: u8 = val.try_into()?;
+                if let Some(data) =
+                    self.write_register(port - self.base_port, val)
+                {
+                    self.transmit(data, event.responses);
+                }
+            }
+            _ => (),
+        }
+
+        if self.raise_irq() {
+            event.responses.push(DeviceEventResponse::Interrupt((
+                LEGACY_IRQ_VECTOR_BASE + self.irq,
+                vcpu::InjectedInterruptType::ExternalInterrupt,
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn uart() -> Uart16550 {
+        Arc::try_unwrap(Uart16550::new(0x3f8, 4, SerialBackend::HostSerial))
+            .unwrap()
+            .into_inner()
+    }
+
+    #[test]
+    fn test_uart_divisor_latch() {
+        let mut uart = uart();
+        uart.write_register(SerialOffset::LCR, LCR_DLAB | 0x03);
+        assert_eq!(uart.write_register(SerialOffset::DLL, 0x01), None);
+        uart.write_register(SerialOffset::DLH, 0x02);
+        assert_eq!(uart.divisor, 0x0201);
+        assert_eq!(uart.read_register(SerialOffset::DLL), 0x01);
+        assert_eq!(uart.read_register(SerialOffset::DLH), 0x02);
+
+        uart.write_register(SerialOffset::LCR, 0x03);
+        assert_eq!(uart.read_register(SerialOffset::IER), 0);
+        assert_eq!(uart.write_register(SerialOffset::DATA, b'a'), Some(b'a'));
+    }
+
+    #[test]
+    fn test_uart_rx_fifo() {
+        let mut uart = uart();
+        uart.write_register(SerialOffset::IER, 0x05);
+        assert!(!uart.raise_irq());
+
+        // Without the FIFOs, a second byte overruns the receive buffer
+        uart.receive(b'a');
+        uart.receive(b'b');
+        assert!(uart.raise_irq());
+        assert_eq!(uart.read_register(SerialOffset::IIR), IIR_LINE_STATUS);
+        assert_eq!(uart.read_register(SerialOffset::LSR), 0x63);
+        assert_eq!(uart.read_register(SerialOffset::IIR), IIR_RX_DATA);
+        assert_eq!(uart.read_register(SerialOffset::DATA), b'b');
+        assert_eq!(uart.read_register(SerialOffset::IIR), IIR_NO_INTERRUPT);
+        assert!(!uart.raise_irq());
+
+        // Enable the FIFOs with a trigger level of 4 bytes
+        uart.write_register(SerialOffset::FCR, 0x47);
+        for byte in b"abc" {
+            uart.receive(*byte);
+        }
+        assert!(uart.raise_irq());
+        assert_eq!(uart.read_register(SerialOffset::IIR), 0xcc);
+        uart.receive(b'd');
+        assert_eq!(uart.read_register(SerialOffset::IIR), 0xc4);
+        for byte in b"abcd" {
+            assert_eq!(uart.read_register(SerialOffset::DATA), *byte);
+        }
+        assert_eq!(uart.read_register(SerialOffset::LSR), 0x60);
+
+        for byte in 0..FIFO_SIZE as u8 + 1 {
+            uart.receive(byte);
+        }
+        assert_eq!(uart.rx.len(), FIFO_SIZE);
+        assert_eq!(uart.read_register(SerialOffset::LSR), 0x63);
+    }
+
+    #[test]
+    fn test_uart_thr_empty_interrupt() {
+        let mut uart = uart();
+        uart.write_register(SerialOffset::DATA, b'a');
+        assert!(!uart.raise_irq());
+
+        uart.write_register(SerialOffset::IER, 0x02);
+        assert!(uart.raise_irq());
+        assert_eq!(uart.read_register(SerialOffset::IIR), IIR_THR_EMPTY);
+        assert_eq!(uart.read_register(SerialOffset::IIR), IIR_NO_INTERRUPT);
+        assert!(!uart.raise_irq());
+
+        // Each transmitted byte raises it again
+        uart.write_register(SerialOffset::DATA, b'b');
+        assert!(uart.raise_irq());
+        uart.write_register(SerialOffset::DATA, b'c');
+        assert!(!uart.raise_irq());
+        assert_eq!(uart.read_register(SerialOffset::IIR), IIR_THR_EMPTY);
+    }
+
+    #[test]
+    fn test_uart_loopback() {
+        let mut uart = uart();
+        uart.write_register(SerialOffset::MCR, 0x10);
+        assert_eq!(uart.read_register(SerialOffset::MSR) & 0xf0, 0);
+        uart.write_register(SerialOffset::MCR, 0x1a);
+        assert_eq!(uart.read_register(SerialOffset::MSR), 0x99);
+        assert_eq!(uart.read_register(SerialOffset::MSR), 0x90);
+
+        assert_eq!(uart.write_register(SerialOffset::DATA, b'x'), None);
+        assert_eq!(uart.read_register(SerialOffset::DATA), b'x');
+
+        // FIFO enabled 16550A
+        uart.write_register(SerialOffset::FCR, 0x01);
+        assert_eq!(uart.read_register(SerialOffset::IIR) >> 6, 3);
+    }
+
+    #[test]
+    fn test_uart_host_signals() {
+        let mut uart = uart();
+        uart.write_register(SerialOffset::IER, 0x0d);
+        uart.receive_break();
+        assert!(uart.raise_irq());
+        assert_eq!(uart.read_register(SerialOffset::IIR), IIR_LINE_STATUS);
+        assert_eq!(uart.read_register(SerialOffset::LSR), 0x71);
+        assert_eq!(uart.read_register(SerialOffset::DATA), 0);
+        assert_eq!(uart.read_register(SerialOffset::IIR), IIR_NO_INTERRUPT);
+
+        // The host dropped DCD and raised RI
+        uart.set_host_lines(0x70);
+        assert_eq!(uart.read_register(SerialOffset::IIR), IIR_MODEM_STATUS);
+        assert_eq!(uart.read_register(SerialOffset::MSR), 0x78);
+        uart.set_host_lines(0x30);
+        assert_eq!(uart.read_register(SerialOffset::MSR), 0x34);
+        assert_eq!(uart.read_register(SerialOffset::IIR), IIR_NO_INTERRUPT);
+
+        // Loopback mode disconnects the host lines
+        uart.write_register(SerialOffset::MCR, 0x10);
+        uart.set_host_lines(0xf0);
+        assert_eq!(uart.read_register(SerialOffset::MSR) & 0xf0, 0);
+    }
+
+    #[test]
+    fn test_uart_log_backend() {
+        let mut uart = uart();
+        for byte in b"hello\r" {
+            assert_eq!(uart.log_byte(*byte), None);
+        }
+        assert_eq!(uart.log_byte(b'\n'), Some("hello".into()));
+        assert_eq!(uart.log_byte(b'\n'), Some("".into()));
+
+        let long = [b'x'; MAX_LOG_LINE];
+        let lines = long.iter().filter_map(|b| uart.log_byte(*b)).count();
+        assert_eq!(lines, 1);
+        assert!(uart.log_line.is_empty());
+    }
+}
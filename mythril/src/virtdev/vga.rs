@@ -1,14 +1,87 @@
+//! Emulation of the VGA CRT controller, with text mode output mirrored to
+//! the hypervisor log
+//!
+//! The text framebuffer at 0xB8000 is ordinary guest RAM mapped through
+//! EPT, so the guest writes to it without exits. Instead, each time the
+//! guest moves the cursor (as the BIOS and Linux do after printing), the
+//! rows the cursor has left are read back through the EPT mapping and
+//! logged. This misses rows that scroll off between cursor updates, but is
+//! enough to follow the output of guests that only print to the screen.
+
 use crate::error::{Error, Result};
+use crate::memory::{GuestAddressSpace, GuestPhysAddr, HostPhysFrame};
 use crate::virtdev::{
     DeviceEvent, DeviceRegion, EmulatedDevice, Event, Port, PortReadRequest,
     PortWriteRequest,
 };
+use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::convert::{TryFrom, TryInto};
+use core::ops::Range;
 use num_enum::TryFromPrimitive;
 use spin::RwLock;
 
+/// The guest physical address of the color text framebuffer
+pub const TEXT_FRAMEBUFFER: u64 = 0xb8000;
+
+const TEXT_COLUMNS: u16 = 80;
+const TEXT_ROWS: u16 = 25;
+
+/// The rows of text completed by moving the cursor from offset `old` to
+/// offset `new` (in characters from the start of the screen)
+fn completed_rows(old: u16, new: u16) -> Range<u16> {
+    let (old_row, old_col) = (old / TEXT_COLUMNS, old % TEXT_COLUMNS);
+    let (new_row, new_col) = (new / TEXT_COLUMNS, new % TEXT_COLUMNS);
+    if new_row >= TEXT_ROWS {
+        0..0
+    } else if new_row > old_row {
+        old_row..new_row
+    } else if new_row == old_row
+        && new_row == TEXT_ROWS - 1
+        && new_col < old_col
+    {
+        // A newline on the last row scrolled the screen, moving the
+        // completed row up
+        TEXT_ROWS - 2..TEXT_ROWS - 1
+    } else {
+        0..0
+    }
+}
+
+/// The text of a row of the framebuffer (pairs of character and attribute
+/// bytes), without trailing blanks
+fn row_text(row: &[u8]) -> String {
+    let text: String = row
+        .iter()
+        .step_by(2)
+        .map(|byte| match byte {
+            0x20..=0x7e => *byte as char,
+            0 => ' ',
+            _ => '?',
+        })
+        .collect();
+    String::from(text.trim_end())
+}
+
+fn read_guest_phys(
+    space: &GuestAddressSpace,
+    mut addr: u64,
+    out: &mut [u8],
+) -> Result<()> {
+    let mut done = 0;
+    while done < out.len() {
+        let frame = space.find_host_frame(GuestPhysAddr::new(addr))?;
+        let offset = addr as usize % HostPhysFrame::SIZE;
+        let len = (HostPhysFrame::SIZE - offset).min(out.len() - done);
+        let array = unsafe { frame.as_array() };
+        out[done..done + len].copy_from_slice(&array[offset..offset + len]);
+        done += len;
+        addr += len as u64;
+    }
+    Ok(())
+}
+
 #[derive(Clone, Copy, Debug, TryFromPrimitive)]
 #[repr(u8)]
 pub enum VgaRegister {
@@ -35,6 +108,9 @@ pub struct VgaController {
     index: VgaRegister,
 
     registers: [u8; 0x10],
+
+    // The cursor offset when the screen was last mirrored
+    cursor: u16,
 }
 
 #[allow(dead_code)]
@@ -64,9 +140,42 @@ impl VgaController {
                 0x00, // CursorAddrMsb
                 0x00, // CursorAddrLsb
             ],
+            cursor: 0,
         }))
     }
 
+    fn register_word(&self, msb: VgaRegister, lsb: VgaRegister) -> u16 {
+        (self.registers[msb as usize] as u16) << 8
+            | self.registers[lsb as usize] as u16
+    }
+
+    /// Log the rows of text completed since the cursor was last moved
+    fn mirror_text(&mut self, space: &GuestAddressSpace) -> Result<()> {
+        let start = self.register_word(
+            VgaRegister::StartAddrMsb,
+            VgaRegister::StartAddrLsb,
+        );
+        let cursor = self
+            .register_word(
+                VgaRegister::CursorAddrMsb,
+                VgaRegister::CursorAddrLsb,
+            )
+            .wrapping_sub(start);
+        let rows = completed_rows(self.cursor, cursor);
+        self.cursor = cursor;
+
+        let mut row = [0u8; TEXT_COLUMNS as usize * 2];
+        for index in rows {
+            let offset = (start as u64 + (index * TEXT_COLUMNS) as u64) * 2;
+            read_guest_phys(space, TEXT_FRAMEBUFFER + offset, &mut row)?;
+            let text = row_text(&row);
+            if !text.is_empty() {
+                info!("vga: {}", text);
+            }
+        }
+        Ok(())
+    }
+
     fn on_port_read(
         &mut self,
         port: Port,
@@ -86,11 +195,14 @@ impl VgaController {
         Ok(())
     }
 
+    /// Handle a write to the controller, returning true if the cursor moved
     fn on_port_write(
         &mut self,
         port: Port,
         val: PortWriteRequest,
-    ) -> Result<()> {
+    ) -> Result<bool> {
+        let data_written = port == Self::VGA_DATA
+            || matches!(val, PortWriteRequest::TwoBytes(_));
         match port {
             Self::VGA_INDEX => match val {
                 PortWriteRequest::OneByte(b) => {
@@ -100,9 +212,10 @@ impl VgaController {
                 // The VGA controller allows a register update and data write
                 // in one operation (and linux actually does this), so handle
                 // that here
-                PortWriteRequest::TwoBytes(bytes) => {
-                    let index = bytes[1];
-                    let data = bytes[0];
+                PortWriteRequest::TwoBytes(_) => {
+                    let word = val.as_u16();
+                    let index = word as u8;
+                    let data = (word >> 8) as u8;
                     self.index = VgaRegister::try_from(index)?;
                     self.registers[self.index as usize] = data;
                 }
@@ -123,7 +236,13 @@ impl VgaController {
                 )))
             }
         }
-        Ok(())
+
+        // The cursor offset is written high byte first, so the low byte
+        // completes it
+        match self.index {
+            VgaRegister::CursorAddrLsb => Ok(data_written),
+            _ => Ok(false),
+        }
     }
 }
 
@@ -139,10 +258,43 @@ impl EmulatedDevice for VgaController {
         match event.kind {
             DeviceEvent::PortRead(port, val) => self.on_port_read(port, val)?,
             DeviceEvent::PortWrite(port, val) => {
-                self.on_port_write(port, val)?
+                if self.on_port_write(port, val)? {
+                    let mut space = event.space;
+                    self.mirror_text(space.space_mut())?;
+                }
             }
             _ => (),
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_completed_rows() {
+        // Newlines move the cursor down
+        assert_eq!(completed_rows(5, 80), 0..1);
+        assert_eq!(completed_rows(85, 240), 1..3);
+        // Printing within a row completes nothing
+        assert_eq!(completed_rows(80, 90), 0..0);
+        // A newline on the last row scrolls the screen
+        assert_eq!(completed_rows(24 * 80 + 10, 24 * 80), 23..24);
+        // Homing the cursor (e.g., clearing the screen)
+        assert_eq!(completed_rows(24 * 80 + 10, 0), 0..0);
+    }
+
+    #[test]
+    fn test_row_text() {
+        let mut row = [0x07u8; TEXT_COLUMNS as usize * 2];
+        for (i, byte) in b"Booting\xdb".iter().enumerate() {
+            row[i * 2] = *byte;
+        }
+        for i in 8..TEXT_COLUMNS as usize {
+            row[i * 2] = b' ';
+        }
+        assert_eq!(row_text(&row), "Booting?");
+    }
+}
@@ -0,0 +1,230 @@
+//! A virtio memory balloon device
+//!
+//! The hypervisor asks a cooperative guest to give up memory by setting the
+//! target size of its balloon (see the `balloon` management console
+//! command). The guest driver allocates pages to inflate the balloon, and
+//! reports them on the inflate queue. The frames behind those pages are
+//! then reclaimed from the guest address space and released, so they can be
+//! used by other VMs (see `GuestAddressSpace::reclaim_frame`).
+//!
+//! When the target shrinks, the driver deflates the balloon and may use the
+//! pages again straight away, as `VIRTIO_BALLOON_F_MUST_TELL_HOST` is not
+//! offered. A new frame is mapped when the guest first touches each page.
+
+use crate::error::Result;
+use crate::memory::{GuestAddressSpace, GuestPhysAddr, HostPhysFrame};
+use crate::virtdev::virtio::{VirtioDevice, Virtqueue};
+use crate::vm;
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, LittleEndian};
+use core::fmt;
+
+/// The virtio device ID of a memory balloon
+pub const VIRTIO_ID_BALLOON: u32 = 5;
+
+/// The size of the pages reported by the driver
+pub const BALLOON_PAGE_SIZE: u64 = 4096;
+
+const INFLATE_QUEUE: usize = 0;
+const DEFLATE_QUEUE: usize = 1;
+
+pub struct VirtioBalloon {
+    // The number of pages the guest is asked to give up
+    target: u32,
+    // The number of pages in the balloon
+    size: u32,
+    // Pages given up by the guest whose frames are not yet reclaimed
+    inflated: Vec<u64>,
+    // The number of frames reclaimed from the guest
+    reclaimed: u64,
+}
+
+impl VirtioBalloon {
+    pub fn new() -> Self {
+        Self {
+            target: 0,
+            size: 0,
+            inflated: vec![],
+            reclaimed: 0,
+        }
+    }
+
+    /// The number of pages the guest is asked to give up
+    pub fn target(&self) -> u32 {
+        self.target
+    }
+
+    /// The number of pages the guest has given up
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// The number of frames reclaimed from the guest
+    pub fn reclaimed(&self) -> u64 {
+        self.reclaimed
+    }
+
+    fn parse_pfns(data: &[u8]) -> impl Iterator<Item = u64> + '_ {
+        data.chunks_exact(4)
+            .map(|pfn| LittleEndian::read_u32(pfn) as u64)
+    }
+
+    fn inflate(&mut self, data: &[u8]) {
+        for pfn in Self::parse_pfns(data) {
+            self.inflated.push(pfn);
+            self.size = self.size.saturating_add(1);
+        }
+    }
+
+    fn deflate(&mut self, data: &[u8]) {
+        for pfn in Self::parse_pfns(data) {
+            // The page may not have been reclaimed yet
+            if let Some(pos) = self.inflated.iter().position(|p| *p == pfn) {
+                self.inflated.swap_remove(pos);
+            }
+            self.size = self.size.saturating_sub(1);
+        }
+    }
+}
+
+impl VirtioDevice for VirtioBalloon {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_BALLOON
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn queue_count(&self) -> usize {
+        2
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let mut config = [0u8; 8];
+        LittleEndian::write_u32(&mut config[0..4], self.target);
+        LittleEndian::write_u32(&mut config[4..8], self.size);
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = config.get(offset as usize + i).copied().unwrap_or(0);
+        }
+    }
+
+    fn queue_notify(
+        &mut self,
+        queue: usize,
+        vq: &mut Virtqueue,
+        space: &GuestAddressSpace,
+    ) -> Result<bool> {
+        let mut used = false;
+        while let Some(chain) = vq.pop(space)? {
+            let data = chain.read_all(space)?;
+            match queue {
+                INFLATE_QUEUE => self.inflate(&data),
+                DEFLATE_QUEUE => self.deflate(&data),
+                _ => (),
+            }
+            vq.push_used(space, chain.head, 0)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    fn update_space(&mut self, space: &mut GuestAddressSpace) -> Result<bool> {
+        let mut changed = false;
+        for pfn in self.inflated.drain(..) {
+            let addr = GuestPhysAddr::new(pfn * BALLOON_PAGE_SIZE);
+            if space.reclaim_frame(addr)? {
+                self.reclaimed += 1;
+                changed = true;
+            }
+        }
+        Ok(changed)
+    }
+
+    fn set_balloon_target(&mut self, pages: u32) -> bool {
+        let changed = pages != self.target;
+        self.target = pages;
+        changed
+    }
+}
+
+/// The `balloon` management console command
+pub fn balloon_command(out: &mut dyn fmt::Write, args: &[&str]) -> fmt::Result {
+    let vm_id = args.get(0).and_then(|vm| vm::find_vm(vm));
+    let size = args.get(1).and_then(|size| size.parse::<u64>().ok());
+    let (vm_id, size) = match (vm_id, size) {
+        (Some(vm_id), Some(size)) if args.len() == 2 => (vm_id, size),
+        _ => return writeln!(out, "usage: balloon <vm> <MB>"),
+    };
+    let pages = size * 1024 * 1024 / HostPhysFrame::SIZE as u64;
+    if pages > u32::MAX as u64 {
+        return writeln!(out, "Invalid balloon size: {}MB", size);
+    }
+    match vm::send_vm_msg(vm::VirtualMachineMsg::Balloon(pages as u32), vm_id) {
+        Ok(()) => writeln!(
+            out,
+            "Set the balloon of {} to {}MB",
+            vm::vm_name(vm_id).unwrap_or("?"),
+            size
+        ),
+        Err(e) => writeln!(out, "Failed to set the balloon size: {:?}", e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::virtdev::virtio::test::{add_chain, setup_queue, setup_space};
+    use crate::virtdev::virtio::{write_guest, Descriptor};
+
+    fn notify(
+        balloon: &mut VirtioBalloon,
+        queue: usize,
+        vq: &mut Virtqueue,
+        space: &GuestAddressSpace,
+        pfns: &[u32],
+    ) {
+        let data = pfns
+            .iter()
+            .flat_map(|pfn| pfn.to_le_bytes().to_vec())
+            .collect::<Vec<_>>();
+        write_guest(space, 0x4000, &data).unwrap();
+        add_chain(
+            space,
+            &[Descriptor {
+                addr: 0x4000,
+                len: data.len() as u32,
+                writable: false,
+            }],
+        );
+        assert!(balloon.queue_notify(queue, vq, space).unwrap());
+    }
+
+    #[test]
+    fn test_balloon() {
+        let mut space = setup_space();
+        let mut vq = setup_queue();
+        let mut balloon = VirtioBalloon::new();
+        assert!(balloon.set_balloon_target(3));
+        assert!(!balloon.set_balloon_target(3));
+
+        let mut config = [0u8; 8];
+        balloon.read_config(0, &mut config);
+        assert_eq!(config, [3, 0, 0, 0, 0, 0, 0, 0]);
+
+        notify(&mut balloon, INFLATE_QUEUE, &mut vq, &space, &[8, 9, 0x100]);
+        assert_eq!(balloon.size(), 3);
+        notify(&mut balloon, DEFLATE_QUEUE, &mut vq, &space, &[9]);
+        assert_eq!(balloon.size(), 2);
+
+        // Page 0x100 is not mapped, so only page 8 is reclaimed
+        assert!(balloon.update_space(&mut space).unwrap());
+        assert_eq!(balloon.reclaimed(), 1);
+        assert!(space.find_host_frame(GuestPhysAddr::new(0x8000)).is_err());
+        assert!(space.find_host_frame(GuestPhysAddr::new(0x9000)).is_ok());
+        assert!(!balloon.update_space(&mut space).unwrap());
+
+        balloon.read_config(4, &mut config[..4]);
+        assert_eq!(&config[..4], &[2, 0, 0, 0]);
+    }
+}
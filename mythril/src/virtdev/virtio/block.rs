@@ -0,0 +1,401 @@
+//! A virtio block device
+//!
+//! The contents of the disk are provided by a `BlockBackend`. Usually this
+//! is a RAM disk image loaded as a boot module, either copied into a
+//! `MemoryBackend` (so the guest may modify it) or served in place by a
+//! read-only `PassthroughBackend`.
+
+use crate::boot_info::BootModule;
+use crate::error::{Error, Result};
+use crate::memory::{GuestAddressSpace, HostPhysAddr};
+use crate::virtdev::virtio::{
+    DescriptorChain, VirtioDevice, Virtqueue, MAX_CHAIN_DATA,
+};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, LittleEndian};
+
+/// The virtio device ID of a block device
+pub const VIRTIO_ID_BLOCK: u32 = 2;
+
+/// The size of a sector (the unit of request offsets and the capacity)
+pub const SECTOR_SIZE: u64 = 512;
+
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+const REQUEST_HEADER_SIZE: usize = 16;
+const DEVICE_ID_SIZE: usize = 20;
+
+/// The storage behind a `VirtioBlock` device
+pub trait BlockBackend: Send + Sync {
+    /// The size of the disk in bytes
+    fn size(&self) -> u64;
+
+    /// Returns true if the guest may not write to the disk
+    fn read_only(&self) -> bool;
+
+    /// Read `data.len()` bytes at `offset` on the disk
+    fn read(&mut self, offset: u64, data: &mut [u8]) -> Result<()>;
+
+    /// Write `data` at `offset` on the disk
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<()>;
+
+    /// Make all completed writes persistent
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn check_range(offset: u64, len: usize, size: u64) -> Result<()> {
+    match offset.checked_add(len as u64) {
+        Some(end) if end <= size => Ok(()),
+        _ => Err(Error::InvalidValue(format!(
+            "Block access of {} bytes at 0x{:x} is beyond the disk size",
+            len, offset
+        ))),
+    }
+}
+
+/// A `BlockBackend` that keeps a copy of the disk in hypervisor memory
+///
+/// Writes by the guest survive guest reboots, but are lost when the host
+/// is reset.
+pub struct MemoryBackend {
+    image: Vec<u8>,
+}
+
+impl MemoryBackend {
+    /// Create a new `MemoryBackend` holding a copy of `image`
+    pub fn new(image: &[u8]) -> Self {
+        Self {
+            image: image.to_vec(),
+        }
+    }
+}
+
+impl BlockBackend for MemoryBackend {
+    fn size(&self) -> u64 {
+        self.image.len() as u64
+    }
+
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    fn read(&mut self, offset: u64, data: &mut [u8]) -> Result<()> {
+        check_range(offset, data.len(), self.size())?;
+        let offset = offset as usize;
+        data.copy_from_slice(&self.image[offset..offset + data.len()]);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        check_range(offset, data.len(), self.size())?;
+        let offset = offset as usize;
+        self.image[offset..offset + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+/// A read-only `BlockBackend` that serves a boot module in place
+///
+/// Unlike the `MemoryBackend`, this does not need a second copy of the
+/// image, which matters for large disks.
+pub struct PassthroughBackend {
+    address: HostPhysAddr,
+    size: usize,
+}
+
+impl PassthroughBackend {
+    /// Create a new `PassthroughBackend` for the contents of `module`
+    pub fn new(module: &BootModule) -> Self {
+        Self {
+            address: module.address,
+            size: module.size,
+        }
+    }
+
+    fn data(&self) -> &[u8] {
+        // Boot modules are excluded from the host allocator, so the memory
+        // remains valid for the lifetime of the hypervisor
+        unsafe {
+            core::slice::from_raw_parts(
+                self.address.as_u64() as *const u8,
+                self.size,
+            )
+        }
+    }
+}
+
+impl BlockBackend for PassthroughBackend {
+    fn size(&self) -> u64 {
+        self.size as u64
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+
+    fn read(&mut self, offset: u64, data: &mut [u8]) -> Result<()> {
+        check_range(offset, data.len(), self.size())?;
+        let offset = offset as usize;
+        data.copy_from_slice(&self.data()[offset..offset + data.len()]);
+        Ok(())
+    }
+
+    fn write(&mut self, _offset: u64, _data: &[u8]) -> Result<()> {
+        Err(Error::NotSupported)
+    }
+}
+
+/// A virtio block device with a single request queue
+pub struct VirtioBlock {
+    backend: Box<dyn BlockBackend>,
+    id: Vec<u8>,
+}
+
+impl VirtioBlock {
+    /// Create a new block device for the disk in `backend`, identified to
+    /// the guest by `id` (truncated to 20 bytes)
+    pub fn new(backend: Box<dyn BlockBackend>, id: &str) -> Self {
+        let mut id = id.as_bytes().to_vec();
+        id.truncate(DEVICE_ID_SIZE);
+        Self { backend, id }
+    }
+
+    /// The capacity of the disk in sectors
+    pub fn capacity(&self) -> u64 {
+        self.backend.size() / SECTOR_SIZE
+    }
+
+    /// Perform the request in `chain`, returning the number of bytes
+    /// written to the chain's buffers
+    fn process_request(
+        &mut self,
+        chain: &DescriptorChain,
+        space: &GuestAddressSpace,
+    ) -> Result<u32> {
+        // The last device-writable byte holds the status of the request
+        let writable = chain.writable_len();
+        if writable == 0 {
+            return Err(Error::InvalidValue(
+                "virtio-blk request without a status byte".into(),
+            ));
+        }
+
+        // The buffers are sized by the guest, so larger requests fail rather
+        // than allocate without bound
+        if writable - 1 > MAX_CHAIN_DATA
+            || chain.readable_len() > MAX_CHAIN_DATA
+        {
+            chain.write_at(space, writable - 1, &[VIRTIO_BLK_S_IOERR])?;
+            return Ok(1);
+        }
+
+        let readable = chain.read_all(space)?;
+        if readable.len() < REQUEST_HEADER_SIZE {
+            return Err(Error::InvalidValue(
+                "virtio-blk request without a header".into(),
+            ));
+        }
+        let kind = LittleEndian::read_u32(&readable[0..4]);
+        let offset = LittleEndian::read_u64(&readable[8..16])
+            .checked_mul(SECTOR_SIZE)
+            .unwrap_or(u64::MAX);
+        let payload = &readable[REQUEST_HEADER_SIZE..];
+
+        let mut reply = vec![0u8; writable - 1];
+        let (status, data_len) = match kind {
+            VIRTIO_BLK_T_IN => match self.backend.read(offset, &mut reply) {
+                Ok(()) => (VIRTIO_BLK_S_OK, writable - 1),
+                Err(_) => (VIRTIO_BLK_S_IOERR, 0),
+            },
+            VIRTIO_BLK_T_OUT if self.backend.read_only() => {
+                (VIRTIO_BLK_S_IOERR, 0)
+            }
+            VIRTIO_BLK_T_OUT => match self.backend.write(offset, payload) {
+                Ok(()) => (VIRTIO_BLK_S_OK, 0),
+                Err(_) => (VIRTIO_BLK_S_IOERR, 0),
+            },
+            VIRTIO_BLK_T_FLUSH => match self.backend.flush() {
+                Ok(()) => (VIRTIO_BLK_S_OK, 0),
+                Err(_) => (VIRTIO_BLK_S_IOERR, 0),
+            },
+            VIRTIO_BLK_T_GET_ID => {
+                let len = self.id.len().min(writable - 1);
+                reply[..len].copy_from_slice(&self.id[..len]);
+                (VIRTIO_BLK_S_OK, writable - 1)
+            }
+            _ => (VIRTIO_BLK_S_UNSUPP, 0),
+        };
+
+        // Only the data and the status are written back
+        chain.write_at(space, 0, &reply[..data_len])?;
+        chain.write_at(space, writable - 1, &[status])?;
+        Ok(data_len as u32 + 1)
+    }
+}
+
+impl VirtioDevice for VirtioBlock {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_BLOCK
+    }
+
+    fn features(&self) -> u64 {
+        if self.backend.read_only() {
+            VIRTIO_BLK_F_FLUSH | VIRTIO_BLK_F_RO
+        } else {
+            VIRTIO_BLK_F_FLUSH
+        }
+    }
+
+    fn queue_count(&self) -> usize {
+        1
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        // Only the capacity is reported (the optional fields are guarded by
+        // features that are not offered)
+        let mut config = [0u8; 8];
+        LittleEndian::write_u64(&mut config, self.capacity());
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = config.get(offset as usize + i).copied().unwrap_or(0);
+        }
+    }
+
+    fn queue_notify(
+        &mut self,
+        _queue: usize,
+        vq: &mut Virtqueue,
+        space: &GuestAddressSpace,
+    ) -> Result<bool> {
+        let mut used = false;
+        while let Some(chain) = vq.pop(space)? {
+            let len = self.process_request(&chain, space)?;
+            vq.push_used(space, chain.head, len)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::virtdev::virtio::test::{add_chain, setup_queue, setup_space};
+    use crate::virtdev::virtio::{read_guest, write_guest, Descriptor};
+
+    const HEADER: u64 = 0x5000;
+    const DATA: u64 = 0x6000;
+    const STATUS: u64 = 0x7000;
+
+    fn request(
+        space: &GuestAddressSpace,
+        kind: u32,
+        sector: u64,
+        len: u32,
+        device_writes: bool,
+    ) {
+        let mut header = [0u8; REQUEST_HEADER_SIZE];
+        LittleEndian::write_u32(&mut header[0..4], kind);
+        LittleEndian::write_u64(&mut header[8..16], sector);
+        write_guest(space, HEADER, &header).unwrap();
+        write_guest(space, STATUS, &[0xff]).unwrap();
+        add_chain(
+            space,
+            &[
+                Descriptor {
+                    addr: HEADER,
+                    len: REQUEST_HEADER_SIZE as u32,
+                    writable: false,
+                },
+                Descriptor {
+                    addr: DATA,
+                    len,
+                    writable: device_writes,
+                },
+                Descriptor {
+                    addr: STATUS,
+                    len: 1,
+                    writable: true,
+                },
+            ],
+        );
+    }
+
+    fn status(space: &GuestAddressSpace) -> u8 {
+        let mut status = [0u8];
+        read_guest(space, STATUS, &mut status).unwrap();
+        status[0]
+    }
+
+    #[test]
+    fn test_block_read_write() {
+        let space = setup_space();
+        let mut queue = setup_queue();
+        let mut image = vec![0u8; 4 * SECTOR_SIZE as usize];
+        image[SECTOR_SIZE as usize] = 0xaa;
+        let mut block =
+            VirtioBlock::new(Box::new(MemoryBackend::new(&image)), "test");
+        assert_eq!(block.capacity(), 4);
+
+        request(&space, VIRTIO_BLK_T_IN, 1, 512, true);
+        assert!(block.queue_notify(0, &mut queue, &space).unwrap());
+        assert_eq!(status(&space), VIRTIO_BLK_S_OK);
+        let mut data = [0u8; 2];
+        read_guest(&space, DATA, &mut data).unwrap();
+        assert_eq!(data, [0xaa, 0]);
+
+        write_guest(&space, DATA, &[0x55]).unwrap();
+        request(&space, VIRTIO_BLK_T_OUT, 3, 512, false);
+        assert!(block.queue_notify(0, &mut queue, &space).unwrap());
+        assert_eq!(status(&space), VIRTIO_BLK_S_OK);
+        request(&space, VIRTIO_BLK_T_IN, 3, 512, true);
+        block.queue_notify(0, &mut queue, &space).unwrap();
+        read_guest(&space, DATA, &mut data).unwrap();
+        assert_eq!(data[0], 0x55);
+
+        // Accesses beyond the end of the disk fail
+        request(&space, VIRTIO_BLK_T_IN, 4, 512, true);
+        block.queue_notify(0, &mut queue, &space).unwrap();
+        assert_eq!(status(&space), VIRTIO_BLK_S_IOERR);
+
+        // Requests larger than the host will buffer fail without being
+        // performed
+        request(&space, VIRTIO_BLK_T_IN, 0, 0xffff_0000, true);
+        block.queue_notify(0, &mut queue, &space).unwrap();
+        assert_eq!(status(&space), VIRTIO_BLK_S_IOERR);
+        request(&space, VIRTIO_BLK_T_OUT, 0, 0xffff_0000, false);
+        block.queue_notify(0, &mut queue, &space).unwrap();
+        assert_eq!(status(&space), VIRTIO_BLK_S_IOERR);
+
+        request(&space, 0xff, 0, 512, true);
+        block.queue_notify(0, &mut queue, &space).unwrap();
+        assert_eq!(status(&space), VIRTIO_BLK_S_UNSUPP);
+        assert!(!block.queue_notify(0, &mut queue, &space).unwrap());
+    }
+
+    #[test]
+    fn test_block_get_id() {
+        let space = setup_space();
+        let mut queue = setup_queue();
+        let mut block =
+            VirtioBlock::new(Box::new(MemoryBackend::new(&[])), "disk0");
+        request(&space, VIRTIO_BLK_T_GET_ID, 0, 20, true);
+        block.queue_notify(0, &mut queue, &space).unwrap();
+        assert_eq!(status(&space), VIRTIO_BLK_S_OK);
+        let mut id = [0u8; 6];
+        read_guest(&space, DATA, &mut id).unwrap();
+        assert_eq!(&id, b"disk0\0");
+    }
+}
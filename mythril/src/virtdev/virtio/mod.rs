@@ -0,0 +1,751 @@
+//! Paravirtual devices using the virtio protocol
+//!
+//! Each virtio device is exposed to the guest through the virtio-mmio
+//! transport (version 2, see section 4.2 of the virtio 1.1 specification):
+//! a page of registers at a fixed guest physical address and a legacy IRQ.
+//! Guests without firmware support for discovering the devices must be
+//! told about them, e.g., with a `virtio_mmio.device=4K@<base>:<irq>`
+//! Linux command line argument (see `VirtioMmio::linux_cmdline`).
+//!
+//! Requests are passed to the device through split virtqueues in guest
+//! memory. The transport owns the queues, and hands the device each
+//! descriptor chain the driver makes available when the queue is notified.
+//! Devices may also change the guest address space after a notification
+//! (see `VirtioDevice::update_space`).
+
+use crate::error::{Error, Result};
+use crate::memory::{GuestAddressSpace, GuestPhysAddr, HostPhysFrame};
+use crate::vcpu;
+use crate::virtdev::pic::LEGACY_IRQ_VECTOR_BASE;
+use crate::virtdev::{
+    DeviceEvent, DeviceEventResponse, DeviceRegion, EmulatedDevice, Event,
+};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, LittleEndian};
+use core::sync::atomic::{fence, Ordering};
+use spin::RwLock;
+
+pub mod balloon;
+pub mod block;
+pub mod p9;
+pub mod rng;
+pub mod vsock;
+
+/// The guest physical address of the first virtio-mmio register block
+pub const VIRTIO_MMIO_BASE: u64 = 0xd000_0000;
+
+/// The size of the register block of each device
+pub const VIRTIO_MMIO_SIZE: u64 = 0x1000;
+
+/// The largest number of descriptors in a virtqueue
+pub const MAX_QUEUE_SIZE: u16 = 256;
+
+/// The device complies with version 1 of the virtio specification
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+const MAGIC_VALUE: u32 = 0x7472_6976; // 'virt'
+const VERSION: u32 = 2;
+const VENDOR_ID: u32 = 0x6874_796d; // 'myth'
+
+const CONFIG_OFFSET: u64 = 0x100;
+
+const INTERRUPT_USED_BUFFER: u32 = 1 << 0;
+const INTERRUPT_CONFIG_CHANGE: u32 = 1 << 1;
+
+const STATUS_FEATURES_OK: u32 = 1 << 3;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1 << 0;
+const VIRTQ_DESC_F_WRITE: u16 = 1 << 1;
+const VIRTQ_DESC_F_INDIRECT: u16 = 1 << 2;
+
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1 << 0;
+
+mod reg {
+    pub const MAGIC_VALUE: u64 = 0x000;
+    pub const VERSION: u64 = 0x004;
+    pub const DEVICE_ID: u64 = 0x008;
+    pub const VENDOR_ID: u64 = 0x00c;
+    pub const DEVICE_FEATURES: u64 = 0x010;
+    pub const DEVICE_FEATURES_SEL: u64 = 0x014;
+    pub const DRIVER_FEATURES: u64 = 0x020;
+    pub const DRIVER_FEATURES_SEL: u64 = 0x024;
+    pub const QUEUE_SEL: u64 = 0x030;
+    pub const QUEUE_NUM_MAX: u64 = 0x034;
+    pub const QUEUE_NUM: u64 = 0x038;
+    pub const QUEUE_READY: u64 = 0x044;
+    pub const QUEUE_NOTIFY: u64 = 0x050;
+    pub const INTERRUPT_STATUS: u64 = 0x060;
+    pub const INTERRUPT_ACK: u64 = 0x064;
+    pub const STATUS: u64 = 0x070;
+    pub const QUEUE_DESC_LOW: u64 = 0x080;
+    pub const QUEUE_DESC_HIGH: u64 = 0x084;
+    pub const QUEUE_DRIVER_LOW: u64 = 0x090;
+    pub const QUEUE_DRIVER_HIGH: u64 = 0x094;
+    pub const QUEUE_DEVICE_LOW: u64 = 0x0a0;
+    pub const QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+    pub const CONFIG_GENERATION: u64 = 0x0fc;
+}
+
+/// Read `out.len()` bytes of guest physical memory at `addr`
+pub fn read_guest(
+    space: &GuestAddressSpace,
+    mut addr: u64,
+    out: &mut [u8],
+) -> Result<()> {
+    let mut done = 0;
+    while done < out.len() {
+        let frame = space.find_host_frame(GuestPhysAddr::new(addr))?;
+        let offset = addr as usize % HostPhysFrame::SIZE;
+        let len = (HostPhysFrame::SIZE - offset).min(out.len() - done);
+        let array = unsafe { frame.as_array() };
+        out[done..done + len].copy_from_slice(&array[offset..offset + len]);
+        done += len;
+        addr += len as u64;
+    }
+    Ok(())
+}
+
+/// Write `data` to guest physical memory at `addr`
+pub fn write_guest(
+    space: &GuestAddressSpace,
+    mut addr: u64,
+    data: &[u8],
+) -> Result<()> {
+    let mut done = 0;
+    while done < data.len() {
+        let mut frame = space.find_host_frame(GuestPhysAddr::new(addr))?;
+        let offset = addr as usize % HostPhysFrame::SIZE;
+        let len = (HostPhysFrame::SIZE - offset).min(data.len() - done);
+        let array = unsafe { frame.as_mut_array() };
+        array[offset..offset + len].copy_from_slice(&data[done..done + len]);
+        done += len;
+        addr += len as u64;
+    }
+    Ok(())
+}
+
+fn read_guest_u16(space: &GuestAddressSpace, addr: u64) -> Result<u16> {
+    let mut bytes = [0u8; 2];
+    read_guest(space, addr, &mut bytes)?;
+    Ok(LittleEndian::read_u16(&bytes))
+}
+
+/// The most data a device reads from (or prepares for) a single chain
+///
+/// The lengths of the descriptors are chosen by the guest, so a chain can
+/// describe far more memory than the host should allocate for one request.
+pub const MAX_CHAIN_DATA: usize = 4 * 1024 * 1024;
+
+/// A buffer in guest memory described by a virtqueue descriptor
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Descriptor {
+    pub addr: u64,
+    pub len: u32,
+
+    /// The buffer is written by the device (rather than read)
+    pub writable: bool,
+}
+
+/// A chain of descriptors made available by the driver
+#[derive(Clone, Debug)]
+pub struct DescriptorChain {
+    /// The index of the first descriptor, which identifies the chain when
+    /// it is returned to the driver
+    pub head: u16,
+    pub descriptors: Vec<Descriptor>,
+}
+
+impl DescriptorChain {
+    /// The total length of the device-writable buffers of the chain
+    pub fn writable_len(&self) -> usize {
+        self.descriptors
+            .iter()
+            .filter(|desc| desc.writable)
+            .map(|desc| desc.len as usize)
+            .sum()
+    }
+
+    /// The total length of the device-readable buffers of the chain
+    pub fn readable_len(&self) -> usize {
+        self.descriptors
+            .iter()
+            .filter(|desc| !desc.writable)
+            .map(|desc| desc.len as usize)
+            .sum()
+    }
+
+    /// Read the contents of all device-readable buffers of the chain, which
+    /// must hold at most `MAX_CHAIN_DATA` bytes
+    pub fn read_all(&self, space: &GuestAddressSpace) -> Result<Vec<u8>> {
+        let len = self.readable_len();
+        if len > MAX_CHAIN_DATA {
+            return Err(Error::InvalidValue(format!(
+                "Virtqueue descriptor chain holds {} bytes (at most {} are \
+                 read)",
+                len, MAX_CHAIN_DATA
+            )));
+        }
+        let mut data = Vec::with_capacity(len);
+        for desc in self.descriptors.iter().filter(|desc| !desc.writable) {
+            let start = data.len();
+            data.resize(start + desc.len as usize, 0);
+            read_guest(space, desc.addr, &mut data[start..])?;
+        }
+        Ok(data)
+    }
+
+    /// Write `data` across the device-writable buffers of the chain,
+    /// starting `offset` bytes into them, and return the number of bytes
+    /// written
+    pub fn write_at(
+        &self,
+        space: &GuestAddressSpace,
+        mut offset: usize,
+        mut data: &[u8],
+    ) -> Result<usize> {
+        let mut written = 0;
+        for desc in self.descriptors.iter().filter(|desc| desc.writable) {
+            if data.is_empty() {
+                break;
+            }
+            let desc_len = desc.len as usize;
+            if offset >= desc_len {
+                offset -= desc_len;
+                continue;
+            }
+            let len = data.len().min(desc_len - offset);
+            write_guest(space, desc.addr + offset as u64, &data[..len])?;
+            data = &data[len..];
+            written += len;
+            offset = 0;
+        }
+        Ok(written)
+    }
+}
+
+/// A split virtqueue
+#[derive(Clone, Debug, Default)]
+pub struct Virtqueue {
+    size: u16,
+    ready: bool,
+    desc: u64,
+    avail: u64,
+    used: u64,
+    last_avail: u16,
+    used_idx: u16,
+}
+
+impl Virtqueue {
+    fn is_ready(&self) -> bool {
+        self.ready && self.size > 0
+    }
+
+    fn read_descriptor(
+        &self,
+        space: &GuestAddressSpace,
+        index: u16,
+    ) -> Result<(Descriptor, u16, Option<u16>)> {
+        if index >= self.size {
+            return Err(Error::InvalidValue(format!(
+                "Invalid virtqueue descriptor index: {}",
+                index
+            )));
+        }
+        let mut bytes = [0u8; 16];
+        read_guest(space, self.desc + index as u64 * 16, &mut bytes)?;
+        let flags = LittleEndian::read_u16(&bytes[12..14]);
+        let next = if flags & VIRTQ_DESC_F_NEXT != 0 {
+            Some(LittleEndian::read_u16(&bytes[14..16]))
+        } else {
+            None
+        };
+        let desc = Descriptor {
+            addr: LittleEndian::read_u64(&bytes[0..8]),
+            len: LittleEndian::read_u32(&bytes[8..12]),
+            writable: flags & VIRTQ_DESC_F_WRITE != 0,
+        };
+        Ok((desc, flags, next))
+    }
+
+    /// Take the next descriptor chain made available by the driver
+    pub fn pop(
+        &mut self,
+        space: &GuestAddressSpace,
+    ) -> Result<Option<DescriptorChain>> {
+        if !self.is_ready() {
+            return Ok(None);
+        }
+        let avail_idx = read_guest_u16(space, self.avail + 2)?;
+        if avail_idx == self.last_avail {
+            return Ok(None);
+        }
+        fence(Ordering::Acquire);
+
+        let slot = (self.last_avail % self.size) as u64;
+        let head = read_guest_u16(space, self.avail + 4 + slot * 2)?;
+        self.last_avail = self.last_avail.wrapping_add(1);
+
+        let mut descriptors = vec![];
+        let mut next = Some(head);
+        while let Some(index) = next {
+            // A chain cannot be longer than the queue, so a longer one
+            // must contain a loop
+            if descriptors.len() >= self.size as usize {
+                return Err(Error::InvalidValue(
+                    "Virtqueue descriptor chain is too long".into(),
+                ));
+            }
+            let (desc, flags, following) =
+                self.read_descriptor(space, index)?;
+            if flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                return Err(Error::NotSupported);
+            }
+            descriptors.push(desc);
+            next = following;
+        }
+        Ok(Some(DescriptorChain { head, descriptors }))
+    }
+
+    /// Return the chain starting at `head` to the driver, after `len` bytes
+    /// were written to its buffers
+    pub fn push_used(
+        &mut self,
+        space: &GuestAddressSpace,
+        head: u16,
+        len: u32,
+    ) -> Result<()> {
+        let slot = (self.used_idx % self.size) as u64;
+        let mut elem = [0u8; 8];
+        LittleEndian::write_u32(&mut elem[0..4], head as u32);
+        LittleEndian::write_u32(&mut elem[4..8], len);
+        write_guest(space, self.used + 4 + slot * 8, &elem)?;
+
+        // The element must be visible before the driver sees the new index
+        fence(Ordering::Release);
+        self.used_idx = self.used_idx.wrapping_add(1);
+        let mut idx = [0u8; 2];
+        LittleEndian::write_u16(&mut idx, self.used_idx);
+        write_guest(space, self.used + 2, &idx)
+    }
+
+    /// Returns true if the driver asked not to be interrupted when buffers
+    /// are used
+    pub fn interrupt_suppressed(
+        &self,
+        space: &GuestAddressSpace,
+    ) -> Result<bool> {
+        Ok(
+            read_guest_u16(space, self.avail)? & VIRTQ_AVAIL_F_NO_INTERRUPT
+                != 0,
+        )
+    }
+}
+
+/// A device behind a virtio transport
+pub trait VirtioDevice: Send + Sync {
+    /// The virtio device ID (e.g., 2 for a block device)
+    fn device_id(&self) -> u32;
+
+    /// The device-specific feature bits offered to the driver
+    fn features(&self) -> u64;
+
+    /// The number of virtqueues used by the device
+    fn queue_count(&self) -> usize;
+
+    /// Read the device configuration space at `offset`
+    fn read_config(&self, offset: u64, data: &mut [u8]);
+
+    /// Process the chains made available on `queue`, returning true if any
+    /// were used
+    fn queue_notify(
+        &mut self,
+        queue: usize,
+        vq: &mut Virtqueue,
+        space: &GuestAddressSpace,
+    ) -> Result<bool>;
+
+    /// Use the chains available on any of the `queues` for work the device
+    /// has pending (e.g., data for the driver that was produced while
+    /// handling a notification on another queue), returning true if any
+    /// were used. This is called after every notification.
+    fn process_pending(
+        &mut self,
+        _queues: &mut [Virtqueue],
+        _space: &GuestAddressSpace,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Take the responses the device has for the vCPU (e.g., to stop the
+    /// guest)
+    fn take_responses(&mut self) -> Vec<DeviceEventResponse> {
+        vec![]
+    }
+
+    /// Apply the changes to the guest address space that the device has
+    /// pending (e.g., pages given up to a balloon), returning true if any
+    /// mappings changed. This is called after every notification.
+    fn update_space(&mut self, _space: &mut GuestAddressSpace) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Set the number of pages the guest is asked to give up, returning
+    /// true if the configuration changed. Only a balloon device has a
+    /// target size.
+    fn set_balloon_target(&mut self, _pages: u32) -> bool {
+        false
+    }
+}
+
+/// The virtio-mmio transport for a `VirtioDevice`
+pub struct VirtioMmio<D: VirtioDevice> {
+    base: u64,
+    irq: u8,
+    device: D,
+    queues: Vec<Virtqueue>,
+    status: u32,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    driver_features: u64,
+    queue_sel: u32,
+    interrupt_status: u32,
+}
+
+impl<D: VirtioDevice> VirtioMmio<D> {
+    /// Expose `device` to the guest with a register block at `base`,
+    /// raising the legacy `irq`
+    pub fn new(base: u64, irq: u8, device: D) -> Arc<RwLock<Self>> {
+        let queues = vec![Virtqueue::default(); device.queue_count()];
+        Arc::new(RwLock::new(Self {
+            base,
+            irq,
+            device,
+            queues,
+            status: 0,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            queue_sel: 0,
+            interrupt_status: 0,
+        }))
+    }
+
+    /// The Linux command line argument that describes this device
+    pub fn linux_cmdline(&self) -> String {
+        format!(
+            "virtio_mmio.device={}K@0x{:x}:{}",
+            VIRTIO_MMIO_SIZE / 1024,
+            self.base,
+            self.irq
+        )
+    }
+
+    /// The device behind this transport
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    fn device_features(&self) -> u64 {
+        self.device.features() | VIRTIO_F_VERSION_1
+    }
+
+    fn reset(&mut self) {
+        for queue in self.queues.iter_mut() {
+            *queue = Virtqueue::default();
+        }
+        self.status = 0;
+        self.driver_features = 0;
+        self.queue_sel = 0;
+        self.interrupt_status = 0;
+    }
+
+    fn interrupt(&self) -> DeviceEventResponse {
+        DeviceEventResponse::Interrupt((
+            LEGACY_IRQ_VECTOR_BASE + self.irq,
+            vcpu::InjectedInterruptType::ExternalInterrupt,
+        ))
+    }
+
+    fn selected_queue(&mut self) -> Option<&mut Virtqueue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    fn read_register(&self, offset: u64) -> u32 {
+        let queue = self.queues.get(self.queue_sel as usize);
+        match offset {
+            reg::MAGIC_VALUE => MAGIC_VALUE,
+            reg::VERSION => VERSION,
+            reg::DEVICE_ID => self.device.device_id(),
+            reg::VENDOR_ID => VENDOR_ID,
+            reg::DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.device_features() as u32,
+                1 => (self.device_features() >> 32) as u32,
+                _ => 0,
+            },
+            reg::QUEUE_NUM_MAX => queue.map_or(0, |_| MAX_QUEUE_SIZE as u32),
+            reg::QUEUE_READY => queue.map_or(0, |queue| queue.ready as u32),
+            reg::INTERRUPT_STATUS => self.interrupt_status,
+            reg::STATUS => self.status,
+            reg::CONFIG_GENERATION => 0,
+            offset => {
+                warn!("Read of unknown virtio-mmio register 0x{:x}", offset);
+                0
+            }
+        }
+    }
+
+    fn write_register(
+        &mut self,
+        offset: u64,
+        val: u32,
+        space: &GuestAddressSpace,
+    ) -> Result<bool> {
+        match offset {
+            reg::DEVICE_FEATURES_SEL => self.device_features_sel = val,
+            reg::DRIVER_FEATURES_SEL => self.driver_features_sel = val,
+            reg::DRIVER_FEATURES => {
+                let shift = match self.driver_features_sel {
+                    0 => 0,
+                    1 => 32,
+                    _ => return Ok(false),
+                };
+                self.driver_features = (self.driver_features
+                    & !(0xffff_ffff << shift))
+                    | (val as u64) << shift;
+            }
+            reg::QUEUE_SEL => self.queue_sel = val,
+            reg::QUEUE_NUM => {
+                if val == 0 || val > MAX_QUEUE_SIZE as u32 {
+                    return Err(Error::InvalidValue(format!(
+                        "Invalid virtqueue size: {}",
+                        val
+                    )));
+                }
+                if let Some(queue) = self.selected_queue() {
+                    queue.size = val as u16;
+                }
+            }
+            reg::QUEUE_READY => {
+                if let Some(queue) = self.selected_queue() {
+                    queue.ready = val & 1 != 0;
+                }
+            }
+            reg::QUEUE_DESC_LOW
+            | reg::QUEUE_DESC_HIGH
+            | reg::QUEUE_DRIVER_LOW
+            | reg::QUEUE_DRIVER_HIGH
+            | reg::QUEUE_DEVICE_LOW
+            | reg::QUEUE_DEVICE_HIGH => {
+                if let Some(queue) = self.selected_queue() {
+                    let addr = match offset & !0b1111 {
+                        reg::QUEUE_DESC_LOW => &mut queue.desc,
+                        reg::QUEUE_DRIVER_LOW => &mut queue.avail,
+                        _ => &mut queue.used,
+                    };
+                    let shift = (offset & 0b100) * 8;
+                    *addr = (*addr & !(0xffff_ffff << shift))
+                        | (val as u64) << shift;
+                }
+            }
+            reg::QUEUE_NOTIFY => {
+                let index = val as usize;
+                let queue = match self.queues.get_mut(index) {
+                    Some(queue) if queue.is_ready() => queue,
+                    _ => return Ok(false),
+                };
+                let used = self.device.queue_notify(index, queue, space)?
+                    && !queue.interrupt_suppressed(space)?;
+                let pending =
+                    self.device.process_pending(&mut self.queues, space)?;
+                if used || pending {
+                    self.interrupt_status |= INTERRUPT_USED_BUFFER;
+                    return Ok(true);
+                }
+            }
+            reg::INTERRUPT_ACK => self.interrupt_status &= !val,
+            reg::STATUS => {
+                if val == 0 {
+                    self.reset();
+                } else if val & STATUS_FEATURES_OK != 0
+                    && self.driver_features & !self.device_features() != 0
+                {
+                    // Refuse features that were not offered by leaving
+                    // FEATURES_OK clear
+                    self.status = val & !STATUS_FEATURES_OK;
+                } else {
+                    self.status = val;
+                }
+            }
+            offset => {
+                warn!(
+                    "Write of unknown virtio-mmio register 0x{:x} (0x{:x})",
+                    offset, val
+                );
+            }
+        }
+        Ok(false)
+    }
+}
+
+impl<D: VirtioDevice> EmulatedDevice for VirtioMmio<D> {
+    fn services(&self) -> Vec<DeviceRegion> {
+        vec![DeviceRegion::MemIo(
+            GuestPhysAddr::new(self.base)
+                ..=GuestPhysAddr::new(self.base + VIRTIO_MMIO_SIZE - 1),
+        )]
+    }
+
+    fn on_event(&mut self, event: Event) -> Result<()> {
+        match event.kind {
+            DeviceEvent::MemRead(addr, mut req) => {
+                let offset = addr.as_u64() - self.base;
+                let data = req.as_mut_slice();
+                if offset >= CONFIG_OFFSET {
+                    self.device.read_config(offset - CONFIG_OFFSET, data);
+                } else if data.len() == 4 {
+                    LittleEndian::write_u32(data, self.read_register(offset));
+                } else {
+                    return Err(Error::InvalidValue(format!(
+                        "Invalid virtio-mmio register read size: {}",
+                        data.len()
+                    )));
+                }
+            }
+            DeviceEvent::MemWrite(addr, req) => {
+                let offset = addr.as_u64() - self.base;
+                let data = req.as_slice();
+                if offset >= CONFIG_OFFSET {
+                    // None of the devices have writable configuration (the
+                    // balloon tracks its own size)
+                    return Ok(());
+                } else if data.len() != 4 {
+                    return Err(Error::InvalidValue(format!(
+                        "Invalid virtio-mmio register write size: {}",
+                        data.len()
+                    )));
+                }
+                let mut space = event.space;
+                let val = LittleEndian::read_u32(data);
+                if self.write_register(offset, val, space.space_mut())? {
+                    event.responses.push(self.interrupt());
+                }
+                for response in self.device.take_responses() {
+                    event.responses.push(response);
+                }
+                if self.device.update_space(space.space_mut())? {
+                    event
+                        .responses
+                        .push(DeviceEventResponse::GuestMappingsChanged);
+                }
+            }
+            DeviceEvent::BalloonRequested(pages) => {
+                if self.device.set_balloon_target(pages) {
+                    self.interrupt_status |= INTERRUPT_CONFIG_CHANGE;
+                    event.responses.push(self.interrupt());
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    // The layout of the test queue in guest memory
+    pub const DESC: u64 = 0x1000;
+    pub const AVAIL: u64 = 0x2000;
+    pub const USED: u64 = 0x3000;
+
+    pub fn setup_space() -> GuestAddressSpace {
+        let mut space = GuestAddressSpace::new().unwrap();
+        for i in 0..16 {
+            space
+                .map_new_frame(GuestPhysAddr::new(i * 0x1000), false)
+                .unwrap();
+        }
+        space
+    }
+
+    pub fn setup_queue() -> Virtqueue {
+        Virtqueue {
+            size: 16,
+            ready: true,
+            desc: DESC,
+            avail: AVAIL,
+            used: USED,
+            ..Virtqueue::default()
+        }
+    }
+
+    /// Make the chain of `descriptors` (at the start of the descriptor
+    /// table) available to the device
+    pub fn add_chain(space: &GuestAddressSpace, descriptors: &[Descriptor]) {
+        for (i, desc) in descriptors.iter().enumerate() {
+            let mut bytes = [0u8; 16];
+            let mut flags = 0;
+            if desc.writable {
+                flags |= VIRTQ_DESC_F_WRITE;
+            }
+            if i + 1 < descriptors.len() {
+                flags |= VIRTQ_DESC_F_NEXT;
+            }
+            LittleEndian::write_u64(&mut bytes[0..8], desc.addr);
+            LittleEndian::write_u32(&mut bytes[8..12], desc.len);
+            LittleEndian::write_u16(&mut bytes[12..14], flags);
+            LittleEndian::write_u16(&mut bytes[14..16], i as u16 + 1);
+            write_guest(space, DESC + i as u64 * 16, &bytes).unwrap();
+        }
+
+        let idx = read_guest_u16(space, AVAIL + 2).unwrap();
+        let mut bytes = [0u8; 2];
+        write_guest(space, AVAIL + 4 + (idx % 16) as u64 * 2, &bytes).unwrap();
+        LittleEndian::write_u16(&mut bytes, idx + 1);
+        write_guest(space, AVAIL + 2, &bytes).unwrap();
+    }
+
+    #[test]
+    fn test_virtqueue() {
+        let space = setup_space();
+        let mut queue = setup_queue();
+        assert!(queue.pop(&space).unwrap().is_none());
+
+        let descriptors = [
+            Descriptor {
+                addr: 0x4000,
+                len: 3,
+                writable: false,
+            },
+            Descriptor {
+                addr: 0x4ffe,
+                len: 4,
+                writable: true,
+            },
+        ];
+        write_guest(&space, 0x4000, b"abc").unwrap();
+        add_chain(&space, &descriptors);
+
+        let chain = queue.pop(&space).unwrap().unwrap();
+        assert_eq!(chain.head, 0);
+        assert_eq!(chain.descriptors, descriptors);
+        assert_eq!(chain.read_all(&space).unwrap(), b"abc");
+        assert_eq!(chain.writable_len(), 4);
+        assert_eq!(chain.write_at(&space, 0, b"wxyz!").unwrap(), 4);
+        assert_eq!(chain.write_at(&space, 3, b"?").unwrap(), 1);
+
+        let mut data = [0u8; 4];
+        read_guest(&space, 0x4ffe, &mut data).unwrap();
+        assert_eq!(&data, b"wxy?");
+        assert!(queue.pop(&space).unwrap().is_none());
+
+        queue.push_used(&space, chain.head, 4).unwrap();
+        assert_eq!(read_guest_u16(&space, USED + 2).unwrap(), 1);
+        let mut elem = [0u8; 8];
+        read_guest(&space, USED + 4, &mut elem).unwrap();
+        assert_eq!(LittleEndian::read_u32(&elem[4..8]), 4);
+    }
+}
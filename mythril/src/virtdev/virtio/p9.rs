@@ -0,0 +1,955 @@
+//! A virtio 9P device sharing a read-only filesystem with the guest
+//!
+//! The filesystem is built from a cpio archive (in the 'newc' format used
+//! for Linux initramfs images), usually loaded as a boot module, and is
+//! served using the 9P2000.L protocol. A Linux guest can mount it with:
+//!
+//! ```text
+//! mount -t 9p -o trans=virtio,version=9p2000.L <tag> /mnt
+//! ```
+//!
+//! Only the messages needed to read the filesystem are implemented. All
+//! modifications fail with EROFS.
+
+use crate::error::{Error, Result};
+use crate::memory::GuestAddressSpace;
+use crate::virtdev::virtio::{VirtioDevice, Virtqueue};
+use alloc::collections::btree_map::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, LittleEndian};
+
+/// The virtio device ID of a 9P transport
+pub const VIRTIO_ID_9P: u32 = 9;
+
+/// The largest message size negotiated with the guest
+pub const MAX_MSIZE: u32 = 128 * 1024;
+
+/// The protocol version implemented by the server
+pub const VERSION: &str = "9P2000.L";
+
+const VIRTIO_9P_MOUNT_TAG: u64 = 1 << 0;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+const CPIO_MAGIC: &[u8] = b"070701";
+const CPIO_HEADER_SIZE: usize = 110;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+
+// The size of a message header (size[4] type[1] tag[2])
+const HEADER_SIZE: usize = 7;
+
+// The size of the fixed part of an Rread or Rreaddir (the header and count)
+const IO_HEADER_SIZE: usize = HEADER_SIZE + 4;
+
+const NO_TAG: u16 = 0xffff;
+
+// The most fids a guest may hold on a channel at once
+const MAX_FIDS: usize = 1024;
+
+const V9FS_MAGIC: u32 = 0x0102_1997;
+
+const QTDIR: u8 = 0x80;
+const QTSYMLINK: u8 = 0x02;
+const QTFILE: u8 = 0x00;
+
+const DT_DIR: u8 = 4;
+const DT_REG: u8 = 8;
+const DT_LNK: u8 = 10;
+
+// The fields of an Rgetattr that are valid (P9_GETATTR_BASIC)
+const GETATTR_BASIC: u64 = 0x7ff;
+
+// The open flags that would modify a file
+const O_ACCMODE: u32 = 0o3;
+const O_TRUNC: u32 = 0o1000;
+
+// Linux error numbers, as returned by Rlerror
+const ENOENT: u32 = 2;
+const EBADF: u32 = 9;
+const EEXIST: u32 = 17;
+const ENOTDIR: u32 = 20;
+const EISDIR: u32 = 21;
+const EINVAL: u32 = 22;
+const EMFILE: u32 = 24;
+const EROFS: u32 = 30;
+const EPROTO: u32 = 71;
+const EOPNOTSUPP: u32 = 95;
+
+mod msg {
+    pub const RLERROR: u8 = 7;
+    pub const TSTATFS: u8 = 8;
+    pub const TLOPEN: u8 = 12;
+    pub const TLCREATE: u8 = 14;
+    pub const TSYMLINK: u8 = 16;
+    pub const TMKNOD: u8 = 18;
+    pub const TRENAME: u8 = 20;
+    pub const TREADLINK: u8 = 22;
+    pub const TGETATTR: u8 = 24;
+    pub const TSETATTR: u8 = 26;
+    pub const TXATTRCREATE: u8 = 32;
+    pub const TREADDIR: u8 = 40;
+    pub const TFSYNC: u8 = 50;
+    pub const TLINK: u8 = 70;
+    pub const TMKDIR: u8 = 72;
+    pub const TRENAMEAT: u8 = 74;
+    pub const TUNLINKAT: u8 = 76;
+    pub const TVERSION: u8 = 100;
+    pub const TATTACH: u8 = 104;
+    pub const TFLUSH: u8 = 108;
+    pub const TWALK: u8 = 110;
+    pub const TREAD: u8 = 116;
+    pub const TWRITE: u8 = 118;
+    pub const TCLUNK: u8 = 120;
+    pub const TREMOVE: u8 = 122;
+}
+
+// The result of handling a message, where errors are Linux error numbers
+type P9Result<T> = core::result::Result<T, u32>;
+
+/// A file, directory or symbolic link in a `Filesystem`
+#[derive(Clone, Debug)]
+struct Node {
+    name: String,
+    parent: usize,
+    mode: u32,
+    mtime: u64,
+    /// The contents of a file, or the target of a symbolic link
+    data: Vec<u8>,
+    children: Vec<usize>,
+}
+
+impl Node {
+    fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    fn qid_type(&self) -> u8 {
+        match self.mode & S_IFMT {
+            S_IFDIR => QTDIR,
+            S_IFLNK => QTSYMLINK,
+            _ => QTFILE,
+        }
+    }
+
+    fn dirent_type(&self) -> u8 {
+        match self.mode & S_IFMT {
+            S_IFDIR => DT_DIR,
+            S_IFLNK => DT_LNK,
+            _ => DT_REG,
+        }
+    }
+}
+
+/// A read-only, in-memory filesystem
+///
+/// Nodes are identified by their index, where the root directory is 0.
+#[derive(Clone, Debug)]
+pub struct Filesystem {
+    nodes: Vec<Node>,
+}
+
+fn parse_hex(field: &[u8]) -> Result<u32> {
+    core::str::from_utf8(field)
+        .ok()
+        .and_then(|field| u32::from_str_radix(field, 16).ok())
+        .ok_or_else(|| Error::InvalidValue("Invalid cpio header".into()))
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+impl Filesystem {
+    /// Create a filesystem holding only an empty root directory
+    pub fn new() -> Self {
+        Self {
+            nodes: vec![Node {
+                name: String::new(),
+                parent: 0,
+                mode: S_IFDIR | 0o755,
+                mtime: 0,
+                data: vec![],
+                children: vec![],
+            }],
+        }
+    }
+
+    /// Build a filesystem from a cpio archive in the 'newc' format
+    ///
+    /// Only regular files, directories and symbolic links are included.
+    /// Directories that are not in the archive are created as needed.
+    pub fn from_cpio(archive: &[u8]) -> Result<Self> {
+        let invalid = || Error::InvalidValue("Invalid cpio archive".into());
+        let mut fs = Self::new();
+        let mut offset = 0;
+        loop {
+            let header = archive
+                .get(offset..offset + CPIO_HEADER_SIZE)
+                .ok_or_else(invalid)?;
+            if &header[..6] != CPIO_MAGIC {
+                return Err(invalid());
+            }
+            let field = |index: usize| {
+                let start = 6 + index * 8;
+                parse_hex(&header[start..start + 8])
+            };
+            let mode = field(1)?;
+            let mtime = field(5)? as u64;
+            let file_size = field(6)? as usize;
+            let name_size = field(11)? as usize;
+
+            let name_start = offset + CPIO_HEADER_SIZE;
+            let name = archive
+                .get(name_start..name_start + name_size)
+                .and_then(|name| name.split_last())
+                .and_then(|(_nul, name)| core::str::from_utf8(name).ok())
+                .ok_or_else(invalid)?;
+            if name == CPIO_TRAILER {
+                break;
+            }
+
+            let data_start = align4(name_start + name_size);
+            let data = archive
+                .get(data_start..data_start + file_size)
+                .ok_or_else(invalid)?;
+            match mode & S_IFMT {
+                S_IFDIR | S_IFREG | S_IFLNK => {
+                    fs.add_path(name, mode, mtime, data)?
+                }
+                _ => (),
+            }
+            offset = align4(data_start + file_size);
+        }
+        Ok(fs)
+    }
+
+    fn add_path(
+        &mut self,
+        path: &str,
+        mode: u32,
+        mtime: u64,
+        data: &[u8],
+    ) -> Result<()> {
+        let components = path
+            .split('/')
+            .filter(|name| !name.is_empty() && *name != ".")
+            .collect::<Vec<_>>();
+        if components.iter().any(|name| *name == "..") {
+            return Err(Error::InvalidValue(format!(
+                "Invalid path in archive: '{}'",
+                path
+            )));
+        }
+
+        let (name, dirs) = match components.split_last() {
+            Some(split) => split,
+            None => {
+                // The archive entry for the root directory itself
+                self.nodes[0].mode = mode;
+                self.nodes[0].mtime = mtime;
+                return Ok(());
+            }
+        };
+        let mut parent = 0;
+        for dir in dirs {
+            parent = match self.lookup(parent, dir) {
+                Some(node) if self.nodes[node].is_dir() => node,
+                Some(_) => {
+                    return Err(Error::InvalidValue(format!(
+                        "'{}' is not a directory in archive",
+                        dir
+                    )))
+                }
+                None => self.add_node(parent, dir, S_IFDIR | 0o755, 0, &[]),
+            };
+        }
+        match self.lookup(parent, name) {
+            // Directories may be listed after their contents
+            Some(node) if self.nodes[node].is_dir() && mode & S_IFDIR != 0 => {
+                self.nodes[node].mode = mode;
+                self.nodes[node].mtime = mtime;
+            }
+            Some(node) => {
+                self.nodes[node].mode = mode;
+                self.nodes[node].mtime = mtime;
+                self.nodes[node].data = data.to_vec();
+            }
+            None => {
+                self.add_node(parent, name, mode, mtime, data);
+            }
+        }
+        Ok(())
+    }
+
+    fn add_node(
+        &mut self,
+        parent: usize,
+        name: &str,
+        mode: u32,
+        mtime: u64,
+        data: &[u8],
+    ) -> usize {
+        let index = self.nodes.len();
+        self.nodes.push(Node {
+            name: name.into(),
+            parent,
+            mode,
+            mtime,
+            data: data.to_vec(),
+            children: vec![],
+        });
+        self.nodes[parent].children.push(index);
+        index
+    }
+
+    /// The node named `name` in the directory `dir`
+    pub fn lookup(&self, dir: usize, name: &str) -> Option<usize> {
+        if !self.nodes[dir].is_dir() {
+            return None;
+        }
+        match name {
+            "." => Some(dir),
+            ".." => Some(self.nodes[dir].parent),
+            name => self.nodes[dir]
+                .children
+                .iter()
+                .copied()
+                .find(|child| self.nodes[*child].name == name),
+        }
+    }
+
+    /// The node at `path` (relative to the root directory)
+    pub fn lookup_path(&self, path: &str) -> Option<usize> {
+        path.split('/')
+            .filter(|name| !name.is_empty())
+            .try_fold(0, |dir, name| self.lookup(dir, name))
+    }
+
+    /// The contents of the file (or target of the symbolic link) `node`
+    pub fn data(&self, node: usize) -> &[u8] {
+        &self.nodes[node].data
+    }
+}
+
+// Parses the fields of a T-message
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> P9Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(EPROTO);
+        }
+        let (field, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(field)
+    }
+
+    fn u8(&mut self) -> P9Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> P9Result<u16> {
+        Ok(LittleEndian::read_u16(self.take(2)?))
+    }
+
+    fn u32(&mut self) -> P9Result<u32> {
+        Ok(LittleEndian::read_u32(self.take(4)?))
+    }
+
+    fn u64(&mut self) -> P9Result<u64> {
+        Ok(LittleEndian::read_u64(self.take(8)?))
+    }
+
+    fn string(&mut self) -> P9Result<&'a str> {
+        let len = self.u16()? as usize;
+        core::str::from_utf8(self.take(len)?).map_err(|_| EINVAL)
+    }
+}
+
+// Builds an R-message
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn new(kind: u8, tag: u16) -> Self {
+        let mut writer = Self { buf: vec![] };
+        writer.u32(0);
+        writer.u8(kind);
+        writer.u16(tag);
+        writer
+    }
+
+    fn u8(&mut self, val: u8) {
+        self.buf.push(val);
+    }
+
+    fn u16(&mut self, val: u16) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    fn u32(&mut self, val: u32) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    fn u64(&mut self, val: u64) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    fn string(&mut self, val: &[u8]) {
+        self.u16(val.len() as u16);
+        self.buf.extend_from_slice(val);
+    }
+
+    fn qid(&mut self, fs: &Filesystem, node: usize) {
+        self.u8(fs.nodes[node].qid_type());
+        self.u32(0);
+        self.u64(node as u64);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        LittleEndian::write_u32(&mut self.buf[0..4], len);
+        self.buf
+    }
+}
+
+// A file the guest has walked to
+#[derive(Clone, Copy, Debug)]
+struct Fid {
+    node: usize,
+    opened: bool,
+}
+
+/// A 9P2000.L server for a read-only `Filesystem`
+pub struct P9Server {
+    fs: Filesystem,
+    msize: u32,
+    fids: BTreeMap<u32, Fid>,
+}
+
+impl P9Server {
+    pub fn new(fs: Filesystem) -> Self {
+        Self {
+            fs,
+            msize: MAX_MSIZE,
+            fids: BTreeMap::new(),
+        }
+    }
+
+    /// Handle the T-message `request`, returning the R-message, which is
+    /// at most `max_reply` bytes long
+    pub fn handle(&mut self, request: &[u8], max_reply: usize) -> Vec<u8> {
+        let mut reader = Reader { data: request };
+        let header = (|| -> P9Result<(u32, u8, u16)> {
+            Ok((reader.u32()?, reader.u8()?, reader.u16()?))
+        })();
+        let (size, kind, tag) = match header {
+            Ok(header) => header,
+            Err(err) => return Self::error(NO_TAG, err),
+        };
+        if (size as usize) < HEADER_SIZE || size as usize > request.len() {
+            return Self::error(tag, EPROTO);
+        }
+        reader.data = &request[HEADER_SIZE..size as usize];
+
+        let max_reply = max_reply.min(self.msize as usize);
+        match self.dispatch(kind, tag, &mut reader, max_reply) {
+            Ok(reply) => {
+                let reply = reply.finish();
+                if reply.len() > max_reply {
+                    Self::error(tag, EINVAL)
+                } else {
+                    reply
+                }
+            }
+            Err(err) => Self::error(tag, err),
+        }
+    }
+
+    fn error(tag: u16, err: u32) -> Vec<u8> {
+        let mut reply = Writer::new(msg::RLERROR, tag);
+        reply.u32(err);
+        reply.finish()
+    }
+
+    fn fid(&self, fid: u32) -> P9Result<Fid> {
+        self.fids.get(&fid).copied().ok_or(EBADF)
+    }
+
+    // Bind (or rebind) `number` to `fid`, if the guest holds fewer than
+    // `MAX_FIDS` fids
+    fn set_fid(&mut self, number: u32, fid: Fid) -> P9Result<()> {
+        if !self.fids.contains_key(&number) && self.fids.len() >= MAX_FIDS {
+            return Err(EMFILE);
+        }
+        self.fids.insert(number, fid);
+        Ok(())
+    }
+
+    fn dispatch(
+        &mut self,
+        kind: u8,
+        tag: u16,
+        req: &mut Reader,
+        max_reply: usize,
+    ) -> P9Result<Writer> {
+        // Every T-message has an even type, answered by the next (odd) type
+        if kind % 2 != 0 {
+            return Err(EOPNOTSUPP);
+        }
+        let mut reply = Writer::new(kind + 1, tag);
+        match kind {
+            msg::TVERSION => {
+                let msize = req.u32()?;
+                let version = req.string()?;
+                self.fids.clear();
+                self.msize = msize.min(MAX_MSIZE);
+                reply.u32(self.msize);
+                if version == VERSION {
+                    reply.string(VERSION.as_bytes());
+                } else {
+                    reply.string(b"unknown");
+                }
+            }
+            msg::TATTACH => {
+                let fid = req.u32()?;
+                if self.fids.contains_key(&fid) {
+                    return Err(EEXIST);
+                }
+                self.set_fid(
+                    fid,
+                    Fid {
+                        node: 0,
+                        opened: false,
+                    },
+                )?;
+                reply.qid(&self.fs, 0);
+            }
+            msg::TFLUSH => (),
+            msg::TWALK => {
+                let fid = self.fid(req.u32()?)?;
+                let newfid = req.u32()?;
+                let count = req.u16()?;
+                if fid.opened
+                    || (count > 0 && !self.fs.nodes[fid.node].is_dir())
+                {
+                    return Err(ENOTDIR);
+                }
+
+                let mut node = fid.node;
+                let mut qids = vec![];
+                for _ in 0..count {
+                    let name = req.string()?;
+                    match self.fs.lookup(node, name) {
+                        Some(next) => {
+                            node = next;
+                            qids.push(next);
+                        }
+                        None if qids.is_empty() => return Err(ENOENT),
+                        None => break,
+                    }
+                }
+
+                // The new fid is only created if every name was found
+                if qids.len() == count as usize {
+                    self.set_fid(
+                        newfid,
+                        Fid {
+                            node,
+                            opened: false,
+                        },
+                    )?;
+                }
+                reply.u16(qids.len() as u16);
+                for node in qids {
+                    reply.qid(&self.fs, node);
+                }
+            }
+            msg::TLOPEN => {
+                let fid_number = req.u32()?;
+                let fid = self.fid(fid_number)?;
+                let flags = req.u32()?;
+                if flags & (O_ACCMODE | O_TRUNC) != 0 {
+                    return Err(EROFS);
+                }
+                self.set_fid(
+                    fid_number,
+                    Fid {
+                        node: fid.node,
+                        opened: true,
+                    },
+                )?;
+                reply.qid(&self.fs, fid.node);
+                reply.u32(0);
+            }
+            msg::TREAD => {
+                let fid = self.fid(req.u32()?)?;
+                let offset = req.u64()?;
+                let count = req.u32()? as usize;
+                let node = &self.fs.nodes[fid.node];
+                if node.is_dir() {
+                    return Err(EISDIR);
+                }
+                let count = count.min(max_reply.saturating_sub(IO_HEADER_SIZE));
+                let start = core::cmp::min(offset, node.data.len() as u64);
+                let start = start as usize;
+                let end = core::cmp::min(start + count, node.data.len());
+                reply.u32((end - start) as u32);
+                reply.buf.extend_from_slice(&node.data[start..end]);
+            }
+            msg::TREADDIR => {
+                let fid = self.fid(req.u32()?)?;
+                let offset = req.u64()?;
+                let count = req.u32()? as usize;
+                let node = &self.fs.nodes[fid.node];
+                if !node.is_dir() {
+                    return Err(ENOTDIR);
+                }
+                let count = count.min(max_reply.saturating_sub(IO_HEADER_SIZE));
+
+                // Entries are numbered from '.' and '..', and the offset of
+                // each entry is the number of the entry after it
+                let dots = [(".", fid.node), ("..", node.parent)];
+                let entries =
+                    dots.iter()
+                        .copied()
+                        .chain(node.children.iter().map(|child| {
+                            (&*self.fs.nodes[*child].name, *child)
+                        }))
+                        .enumerate()
+                        .skip(offset as usize);
+                let mut data = Writer { buf: vec![] };
+                for (index, (name, child)) in entries {
+                    let len = 13 + 8 + 1 + 2 + name.len();
+                    if data.buf.len() + len > count {
+                        break;
+                    }
+                    data.qid(&self.fs, child);
+                    data.u64(index as u64 + 1);
+                    data.u8(self.fs.nodes[child].dirent_type());
+                    data.string(name.as_bytes());
+                }
+                reply.u32(data.buf.len() as u32);
+                reply.buf.extend_from_slice(&data.buf);
+            }
+            msg::TREADLINK => {
+                let fid = self.fid(req.u32()?)?;
+                let node = &self.fs.nodes[fid.node];
+                if node.mode & S_IFMT != S_IFLNK {
+                    return Err(EINVAL);
+                }
+                reply.string(&node.data);
+            }
+            msg::TGETATTR => {
+                let fid = self.fid(req.u32()?)?;
+                let node = &self.fs.nodes[fid.node];
+                let size = node.data.len() as u64;
+                let nlink = if node.is_dir() { 2 } else { 1 };
+                reply.u64(GETATTR_BASIC);
+                reply.qid(&self.fs, fid.node);
+                reply.u32(node.mode);
+                reply.u32(0); // uid
+                reply.u32(0); // gid
+                reply.u64(nlink);
+                reply.u64(0); // rdev
+                reply.u64(size);
+                reply.u64(4096); // blksize
+                reply.u64((size + 511) / 512);
+                for _ in 0..3 {
+                    reply.u64(node.mtime); // atime, mtime and ctime
+                    reply.u64(0);
+                }
+                for _ in 0..4 {
+                    reply.u64(0); // btime, gen and data_version
+                }
+            }
+            msg::TSTATFS => {
+                self.fid(req.u32()?)?;
+                let size = self
+                    .fs
+                    .nodes
+                    .iter()
+                    .map(|node| (node.data.len() as u64 + 4095) / 4096)
+                    .sum::<u64>();
+                reply.u32(V9FS_MAGIC);
+                reply.u32(4096);
+                reply.u64(size);
+                reply.u64(0); // bfree
+                reply.u64(0); // bavail
+                reply.u64(self.fs.nodes.len() as u64);
+                reply.u64(0); // ffree
+                reply.u64(0); // fsid
+                reply.u32(255);
+            }
+            msg::TFSYNC => {
+                self.fid(req.u32()?)?;
+            }
+            msg::TCLUNK => {
+                self.fids.remove(&req.u32()?).ok_or(EBADF)?;
+            }
+            msg::TREMOVE => {
+                // The fid is clunked even though the remove fails
+                self.fids.remove(&req.u32()?);
+                return Err(EROFS);
+            }
+            msg::TLCREATE
+            | msg::TSYMLINK
+            | msg::TMKNOD
+            | msg::TRENAME
+            | msg::TSETATTR
+            | msg::TXATTRCREATE
+            | msg::TLINK
+            | msg::TMKDIR
+            | msg::TRENAMEAT
+            | msg::TUNLINKAT
+            | msg::TWRITE => return Err(EROFS),
+            _ => return Err(EOPNOTSUPP),
+        }
+        Ok(reply)
+    }
+}
+
+/// A virtio 9P device serving a read-only `Filesystem`
+pub struct Virtio9p {
+    server: P9Server,
+    tag: Vec<u8>,
+}
+
+impl Virtio9p {
+    /// Create a new device serving `fs`, which the guest mounts by `tag`
+    pub fn new(fs: Filesystem, tag: &str) -> Self {
+        Self {
+            server: P9Server::new(fs),
+            tag: tag.as_bytes().to_vec(),
+        }
+    }
+}
+
+impl VirtioDevice for Virtio9p {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_9P
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_9P_MOUNT_TAG
+    }
+
+    fn queue_count(&self) -> usize {
+        1
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let mut config = (self.tag.len() as u16).to_le_bytes().to_vec();
+        config.extend_from_slice(&self.tag);
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = config.get(offset as usize + i).copied().unwrap_or(0);
+        }
+    }
+
+    fn queue_notify(
+        &mut self,
+        _queue: usize,
+        vq: &mut Virtqueue,
+        space: &GuestAddressSpace,
+    ) -> Result<bool> {
+        let mut used = false;
+        while let Some(chain) = vq.pop(space)? {
+            let request = chain.read_all(space)?;
+            let reply = self.server.handle(&request, chain.writable_len());
+            let len = chain.write_at(space, 0, &reply)?;
+            vq.push_used(space, chain.head, len as u32)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cpio_entry(archive: &mut Vec<u8>, name: &str, mode: u32, data: &[u8]) {
+        let fields = [
+            0,
+            mode,
+            0,
+            0,
+            1,
+            0x5f00_0000,
+            data.len() as u32,
+            0,
+            0,
+            0,
+            0,
+            name.len() as u32 + 1,
+            0,
+        ];
+        archive.extend_from_slice(CPIO_MAGIC);
+        for field in fields.iter() {
+            archive.extend_from_slice(format!("{:08x}", field).as_bytes());
+        }
+        archive.extend_from_slice(name.as_bytes());
+        archive.push(0);
+        archive.resize(align4(archive.len()), 0);
+        archive.extend_from_slice(data);
+        archive.resize(align4(archive.len()), 0);
+    }
+
+    fn test_fs() -> Filesystem {
+        let mut archive = vec![];
+        cpio_entry(&mut archive, ".", S_IFDIR | 0o755, &[]);
+        cpio_entry(&mut archive, "etc/app.conf", S_IFREG | 0o644, b"a=1\n");
+        cpio_entry(&mut archive, "bin", S_IFDIR | 0o700, &[]);
+        cpio_entry(&mut archive, "bin/tool", S_IFLNK | 0o777, b"../tool");
+        cpio_entry(&mut archive, CPIO_TRAILER, 0, &[]);
+        Filesystem::from_cpio(&archive).unwrap()
+    }
+
+    fn message(kind: u8, tag: u16, body: &[u8]) -> Vec<u8> {
+        let mut msg = Writer::new(kind, tag);
+        msg.buf.extend_from_slice(body);
+        msg.finish()
+    }
+
+    fn walk(server: &mut P9Server, fid: u32, newfid: u32, path: &[&str]) {
+        let mut body = Writer { buf: vec![] };
+        body.u32(fid);
+        body.u32(newfid);
+        body.u16(path.len() as u16);
+        for name in path {
+            body.string(name.as_bytes());
+        }
+        let reply = server.handle(&message(msg::TWALK, 1, &body.buf), 8192);
+        assert_eq!(reply[4], msg::TWALK + 1);
+    }
+
+    #[test]
+    fn test_cpio() {
+        let fs = test_fs();
+        let conf = fs.lookup_path("etc/app.conf").unwrap();
+        assert_eq!(fs.data(conf), b"a=1\n");
+        let bin = fs.lookup_path("/bin").unwrap();
+        assert_eq!(fs.nodes[bin].mode, S_IFDIR | 0o700);
+        assert_eq!(fs.lookup_path("bin/tool/.."), None);
+        assert_eq!(fs.lookup_path("bin/.."), Some(0));
+        assert!(Filesystem::from_cpio(b"070702").is_err());
+    }
+
+    #[test]
+    fn test_p9_read() {
+        let mut server = P9Server::new(test_fs());
+        let mut body = Writer { buf: vec![] };
+        body.u32(8192);
+        body.string(VERSION.as_bytes());
+        let reply =
+            server.handle(&message(msg::TVERSION, NO_TAG, &body.buf), 8192);
+        assert_eq!(&reply[11..], &b"\x08\x009P2000.L"[..]);
+
+        let mut body = Writer { buf: vec![] };
+        body.u32(1);
+        body.u32(!0);
+        body.string(b"root");
+        body.string(b"");
+        body.u32(0);
+        let reply = server.handle(&message(msg::TATTACH, 1, &body.buf), 8192);
+        assert_eq!(reply[4], msg::TATTACH + 1);
+        assert_eq!(reply[7], QTDIR);
+
+        walk(&mut server, 1, 2, &["etc", "app.conf"]);
+        let reply = server
+            .handle(&message(msg::TLOPEN, 1, &[2, 0, 0, 0, 1, 0, 0, 0]), 8192);
+        assert_eq!(LittleEndian::read_u32(&reply[7..]), EROFS);
+        let reply = server
+            .handle(&message(msg::TLOPEN, 1, &[2, 0, 0, 0, 0, 0, 0, 0]), 8192);
+        assert_eq!(reply[4], msg::TLOPEN + 1);
+
+        let mut body = Writer { buf: vec![] };
+        body.u32(2);
+        body.u64(2);
+        body.u32(100);
+        let reply = server.handle(&message(msg::TREAD, 1, &body.buf), 8192);
+        assert_eq!(LittleEndian::read_u32(&reply[7..]), 2);
+        assert_eq!(&reply[11..], b"1\n");
+
+        // A read is limited by the size of the reply buffer
+        let mut body = Writer { buf: vec![] };
+        body.u32(2);
+        body.u64(0);
+        body.u32(100);
+        let reply = server.handle(&message(msg::TREAD, 1, &body.buf), 12);
+        assert_eq!(&reply[11..], b"a");
+
+        let reply =
+            server.handle(&message(msg::TWRITE, 1, &[2, 0, 0, 0]), 8192);
+        assert_eq!(reply[4], msg::RLERROR);
+    }
+
+    #[test]
+    fn test_p9_malformed() {
+        let mut server = P9Server::new(test_fs());
+
+        // A size shorter than the header, and a message type that has no
+        // reply type
+        let reply = server.handle(&[6, 0, 0, 0, msg::TVERSION, 1, 0], 8192);
+        assert_eq!(reply[4], msg::RLERROR);
+        assert_eq!(LittleEndian::read_u32(&reply[7..]), EPROTO);
+        let reply = server.handle(&message(0xff, 1, &[]), 8192);
+        assert_eq!(LittleEndian::read_u32(&reply[7..]), EOPNOTSUPP);
+
+        // The number of fids is limited
+        let mut body = Writer { buf: vec![] };
+        body.u32(0);
+        body.u32(!0);
+        body.string(b"root");
+        body.string(b"");
+        body.u32(0);
+        server.handle(&message(msg::TATTACH, 1, &body.buf), 8192);
+        for fid in 1..MAX_FIDS as u32 {
+            walk(&mut server, 0, fid, &[]);
+        }
+        let mut body = Writer { buf: vec![] };
+        body.u32(0);
+        body.u32(MAX_FIDS as u32);
+        body.u16(0);
+        let reply = server.handle(&message(msg::TWALK, 1, &body.buf), 8192);
+        assert_eq!(LittleEndian::read_u32(&reply[7..]), EMFILE);
+
+        // An existing fid can still be reused
+        walk(&mut server, 0, 1, &["etc"]);
+    }
+
+    #[test]
+    fn test_p9_readdir() {
+        let mut server = P9Server::new(test_fs());
+        let mut body = Writer { buf: vec![] };
+        body.u32(1);
+        body.u32(!0);
+        body.string(b"");
+        body.string(b"");
+        body.u32(0);
+        server.handle(&message(msg::TATTACH, 1, &body.buf), 8192);
+
+        let mut body = Writer { buf: vec![] };
+        body.u32(1);
+        body.u64(2);
+        body.u32(4096);
+        let reply = server.handle(&message(msg::TREADDIR, 1, &body.buf), 8192);
+        let count = LittleEndian::read_u32(&reply[7..]) as usize;
+        assert_eq!(count, reply.len() - IO_HEADER_SIZE);
+
+        // The entries after '.' and '..' are 'etc' and 'bin'
+        let entry = &reply[IO_HEADER_SIZE..];
+        assert_eq!(entry[0], QTDIR);
+        assert_eq!(LittleEndian::read_u64(&entry[13..]), 3);
+        assert_eq!(entry[21], DT_DIR);
+        assert_eq!(&entry[24..27], b"etc");
+        assert_eq!(&entry[27 + 24..27 + 27], b"bin");
+    }
+}
@@ -0,0 +1,129 @@
+//! A virtio entropy device
+//!
+//! The driver makes device-writable buffers available on the request
+//! queue, and the device fills each of them with random bytes. The bytes
+//! come from a DRBG of the device, which is seeded (and periodically
+//! reseeded) from the hypervisor's generator (see `rng`), so a guest
+//! draining the device does not hold the global generator.
+
+use crate::error::Result;
+use crate::memory::GuestAddressSpace;
+use crate::rng::{self, HmacDrbg, MAX_REQUEST_SIZE, RESEED_INTERVAL};
+use crate::virtdev::virtio::{VirtioDevice, Virtqueue};
+
+/// The virtio device ID of an entropy source
+pub const VIRTIO_ID_RNG: u32 = 4;
+
+const REQUEST_QUEUE: usize = 0;
+const SEED_SIZE: usize = 48;
+
+pub struct VirtioRng {
+    drbg: HmacDrbg,
+}
+
+impl VirtioRng {
+    /// Create a device seeded from the hypervisor's generator
+    pub fn new() -> Result<Self> {
+        let mut seed = [0u8; SEED_SIZE];
+        rng::fill_bytes(&mut seed)?;
+        Ok(Self::from_seed(&seed))
+    }
+
+    /// Create a device with a DRBG instantiated from `seed`
+    pub fn from_seed(seed: &[u8]) -> Self {
+        Self {
+            drbg: HmacDrbg::new(seed),
+        }
+    }
+
+    fn generate(&mut self, out: &mut [u8]) -> Result<()> {
+        if self.drbg.requests() >= RESEED_INTERVAL {
+            let mut seed = [0u8; SEED_SIZE];
+            rng::fill_bytes(&mut seed)?;
+            self.drbg.reseed(&seed);
+        }
+        self.drbg.generate(out)
+    }
+}
+
+impl VirtioDevice for VirtioRng {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_RNG
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn queue_count(&self) -> usize {
+        1
+    }
+
+    fn read_config(&self, _offset: u64, data: &mut [u8]) {
+        for byte in data.iter_mut() {
+            *byte = 0;
+        }
+    }
+
+    fn queue_notify(
+        &mut self,
+        queue: usize,
+        vq: &mut Virtqueue,
+        space: &GuestAddressSpace,
+    ) -> Result<bool> {
+        let mut used = false;
+        while let Some(chain) = vq.pop(space)? {
+            let len = if queue == REQUEST_QUEUE {
+                let mut data =
+                    vec![0u8; chain.writable_len().min(MAX_REQUEST_SIZE)];
+                self.generate(&mut data)?;
+                chain.write_at(space, 0, &data)?
+            } else {
+                0
+            };
+            vq.push_used(space, chain.head, len as u32)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::virtdev::virtio::test::{add_chain, setup_queue, setup_space};
+    use crate::virtdev::virtio::{read_guest, Descriptor};
+
+    #[test]
+    fn test_rng() {
+        let space = setup_space();
+        let mut vq = setup_queue();
+        let mut device = VirtioRng::from_seed(b"test seed");
+        add_chain(
+            &space,
+            &[
+                Descriptor {
+                    addr: 0x4000,
+                    len: 16,
+                    writable: true,
+                },
+                Descriptor {
+                    addr: 0x5000,
+                    len: 24,
+                    writable: true,
+                },
+            ],
+        );
+        assert!(device.queue_notify(REQUEST_QUEUE, &mut vq, &space).unwrap());
+
+        // The chain is filled with the output of the DRBG
+        let mut expected = [0u8; 40];
+        HmacDrbg::new(b"test seed").generate(&mut expected).unwrap();
+        let mut data = [0u8; 24];
+        read_guest(&space, 0x4000, &mut data[..16]).unwrap();
+        assert_eq!(&data[..16], &expected[..16]);
+        read_guest(&space, 0x5000, &mut data).unwrap();
+        assert_eq!(&data[..], &expected[16..]);
+        assert!(!device.queue_notify(REQUEST_QUEUE, &mut vq, &space).unwrap());
+    }
+}
@@ -0,0 +1,688 @@
+//! A virtio socket device for communication between guests and the
+//! hypervisor
+//!
+//! Guest programs (e.g., management agents) connect stream sockets to
+//! services of the hypervisor at `HOST_CID`, by port, without any emulated
+//! networking. The connection carries messages, each a line of text, and
+//! the service replies to each message with a line of text (see
+//! `VsockService`). Connections from the hypervisor to the guest are not
+//! supported.
+//!
+//! The `AgentService` is the service for guest management agents, which
+//! report boot progress and request shutdown.
+//!
+//! Like TCP, a connection may number its messages from a random initial
+//! sequence number, so an agent cannot mistake the replies to an earlier
+//! connection (e.g., before the agent restarted) for its own. The message
+//! `hello` is answered with `hello <isn>`, and each following message must
+//! be prefixed with the next number (`<isn + 1> ping`, and so on). Its
+//! reply is prefixed with the same number. A message with any other number
+//! is not passed to the service, and is answered with `<expected> error
+//! bad sequence`.
+
+use crate::error::{Error, Result};
+use crate::memory::GuestAddressSpace;
+use crate::rng::{self, HmacDrbg, RESEED_INTERVAL};
+use crate::virtdev::virtio::{VirtioDevice, Virtqueue};
+use crate::virtdev::DeviceEventResponse;
+use alloc::boxed::Box;
+use alloc::collections::btree_map::BTreeMap;
+use alloc::collections::vec_deque::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, LittleEndian};
+
+/// The virtio device ID of a socket device
+pub const VIRTIO_ID_VSOCK: u32 = 19;
+
+/// The context ID (i.e., the address) of the hypervisor
+pub const HOST_CID: u64 = 2;
+
+/// The port of the `AgentService`
+pub const AGENT_PORT: u32 = 1024;
+
+/// The longest message (in bytes, excluding the newline)
+pub const MAX_MESSAGE_LEN: usize = 4096;
+
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+
+const HEADER_SIZE: usize = 44;
+const MAX_PAYLOAD: usize = 4096;
+
+// The receive buffer advertised for each connection
+const BUF_ALLOC: u32 = 64 * 1024;
+
+const SEED_SIZE: usize = 48;
+
+const TYPE_STREAM: u16 = 1;
+const SHUTDOWN_BOTH: u32 = 3;
+
+mod op {
+    pub const REQUEST: u16 = 1;
+    pub const RESPONSE: u16 = 2;
+    pub const RST: u16 = 3;
+    pub const SHUTDOWN: u16 = 4;
+    pub const RW: u16 = 5;
+    pub const CREDIT_UPDATE: u16 = 6;
+    pub const CREDIT_REQUEST: u16 = 7;
+}
+
+/// A service of the hypervisor that guests can connect to
+pub trait VsockService: Send + Sync {
+    /// Handle a message (without its newline) from the guest, returning the
+    /// reply. Any actions for the vCPU (e.g., to stop the guest) are added
+    /// to `responses`.
+    fn on_message(
+        &mut self,
+        message: &str,
+        responses: &mut Vec<DeviceEventResponse>,
+    ) -> String;
+}
+
+/// The service for guest management agents
+///
+/// Each message is a command, optionally followed by a space and an
+/// argument:
+///
+/// - `ping`: replies `pong`
+/// - `progress <stage>`: logs the boot progress of the guest
+/// - `shutdown`: stops the guest (see `lifecycle::StopReason::Shutdown`)
+///
+/// The other commands reply `ok`, or `error <reason>` if they fail.
+pub struct AgentService {
+    name: String,
+}
+
+impl AgentService {
+    /// Create an agent service for the VM with the given name
+    pub fn new(name: &str) -> Self {
+        Self { name: name.into() }
+    }
+}
+
+impl VsockService for AgentService {
+    fn on_message(
+        &mut self,
+        message: &str,
+        responses: &mut Vec<DeviceEventResponse>,
+    ) -> String {
+        let mut parts = message.splitn(2, ' ');
+        match (parts.next(), parts.next()) {
+            (Some("ping"), None) => "pong".into(),
+            (Some("progress"), Some(stage)) => {
+                info!("VM {} boot progress: {}", self.name, stage);
+                "ok".into()
+            }
+            (Some("shutdown"), None) => {
+                info!("VM {} agent requested shutdown", self.name);
+                responses.push(DeviceEventResponse::ShutdownRequested);
+                "ok".into()
+            }
+            _ => "error unknown command".into(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Header {
+    src_cid: u64,
+    dst_cid: u64,
+    src_port: u32,
+    dst_port: u32,
+    len: u32,
+    kind: u16,
+    op: u16,
+    flags: u32,
+    buf_alloc: u32,
+    fwd_cnt: u32,
+}
+
+impl Header {
+    fn parse(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(Error::InvalidValue(format!(
+                "Invalid vsock packet length: {}",
+                bytes.len()
+            )));
+        }
+        Ok(Self {
+            src_cid: LittleEndian::read_u64(&bytes[0..8]),
+            dst_cid: LittleEndian::read_u64(&bytes[8..16]),
+            src_port: LittleEndian::read_u32(&bytes[16..20]),
+            dst_port: LittleEndian::read_u32(&bytes[20..24]),
+            len: LittleEndian::read_u32(&bytes[24..28]),
+            kind: LittleEndian::read_u16(&bytes[28..30]),
+            op: LittleEndian::read_u16(&bytes[30..32]),
+            flags: LittleEndian::read_u32(&bytes[32..36]),
+            buf_alloc: LittleEndian::read_u32(&bytes[36..40]),
+            fwd_cnt: LittleEndian::read_u32(&bytes[40..44]),
+        })
+    }
+
+    fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0u8; HEADER_SIZE];
+        LittleEndian::write_u64(&mut bytes[0..8], self.src_cid);
+        LittleEndian::write_u64(&mut bytes[8..16], self.dst_cid);
+        LittleEndian::write_u32(&mut bytes[16..20], self.src_port);
+        LittleEndian::write_u32(&mut bytes[20..24], self.dst_port);
+        LittleEndian::write_u32(&mut bytes[24..28], self.len);
+        LittleEndian::write_u16(&mut bytes[28..30], self.kind);
+        LittleEndian::write_u16(&mut bytes[30..32], self.op);
+        LittleEndian::write_u32(&mut bytes[32..36], self.flags);
+        LittleEndian::write_u32(&mut bytes[36..40], self.buf_alloc);
+        LittleEndian::write_u32(&mut bytes[40..44], self.fwd_cnt);
+        bytes
+    }
+}
+
+#[derive(Debug)]
+struct Packet {
+    header: Header,
+    data: Vec<u8>,
+}
+
+// A connection, by its guest port and host port
+type ConnectionKey = (u32, u32);
+
+#[derive(Debug, Default)]
+struct Connection {
+    // The start of a message that has not been completed
+    input: Vec<u8>,
+    // Replies waiting for the guest to have space to receive them
+    output: VecDeque<u8>,
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+    // The bytes sent to and received from the guest
+    tx_cnt: u32,
+    fwd_cnt: u32,
+    // The received count last reported to the guest
+    reported_fwd_cnt: u32,
+    // The number of the next message, if the messages are numbered
+    seq: Option<u32>,
+}
+
+impl Connection {
+    // The bytes the guest has space to receive
+    fn peer_credit(&self) -> u32 {
+        let in_flight = self.tx_cnt.wrapping_sub(self.peer_fwd_cnt);
+        self.peer_buf_alloc.saturating_sub(in_flight)
+    }
+}
+
+/// A virtio socket device, connecting a guest to the services of the
+/// hypervisor
+pub struct VirtioVsock {
+    guest_cid: u64,
+    services: BTreeMap<u32, Box<dyn VsockService>>,
+    connections: BTreeMap<ConnectionKey, Connection>,
+    // The packets waiting for buffers on the receive queue
+    rx_pending: VecDeque<Packet>,
+    responses: Vec<DeviceEventResponse>,
+    // The source of initial sequence numbers
+    drbg: HmacDrbg,
+}
+
+impl VirtioVsock {
+    /// Create a new device, where the guest has the context ID `guest_cid`,
+    /// seeded from the hypervisor's generator
+    pub fn new(guest_cid: u64) -> Result<Self> {
+        let mut seed = [0u8; SEED_SIZE];
+        rng::fill_bytes(&mut seed)?;
+        Ok(Self::from_seed(guest_cid, &seed))
+    }
+
+    /// Create a device with a DRBG instantiated from `seed`
+    pub fn from_seed(guest_cid: u64, seed: &[u8]) -> Self {
+        Self {
+            guest_cid,
+            services: BTreeMap::new(),
+            connections: BTreeMap::new(),
+            rx_pending: VecDeque::new(),
+            responses: vec![],
+            drbg: HmacDrbg::new(seed),
+        }
+    }
+
+    fn initial_sequence(drbg: &mut HmacDrbg) -> Result<u32> {
+        if drbg.requests() >= RESEED_INTERVAL {
+            let mut seed = [0u8; SEED_SIZE];
+            rng::fill_bytes(&mut seed)?;
+            drbg.reseed(&seed);
+        }
+        let mut isn = [0u8; 4];
+        drbg.generate(&mut isn)?;
+        Ok(u32::from_le_bytes(isn))
+    }
+
+    // The reply to a message, checking its number if the messages of the
+    // connection are numbered
+    fn reply(
+        conn: &mut Connection,
+        service: &mut Box<dyn VsockService>,
+        drbg: &mut HmacDrbg,
+        message: &str,
+        responses: &mut Vec<DeviceEventResponse>,
+    ) -> String {
+        if message == "hello" {
+            return match Self::initial_sequence(drbg) {
+                Ok(isn) => {
+                    conn.seq = Some(isn.wrapping_add(1));
+                    format!("hello {}", isn)
+                }
+                Err(_) => "error no sequence number".into(),
+            };
+        }
+        let expected = match conn.seq {
+            Some(seq) => seq,
+            None => return service.on_message(message, responses),
+        };
+        let mut parts = message.splitn(2, ' ');
+        match (parts.next().map(str::parse::<u32>), parts.next()) {
+            (Some(Ok(seq)), Some(message)) if seq == expected => {
+                conn.seq = Some(seq.wrapping_add(1));
+                format!("{} {}", seq, service.on_message(message, responses))
+            }
+            _ => format!("{} error bad sequence", expected),
+        }
+    }
+
+    /// Accept guest connections to `port` with `service`
+    pub fn add_service(
+        &mut self,
+        port: u32,
+        service: Box<dyn VsockService>,
+    ) -> Result<()> {
+        if self.services.contains_key(&port) {
+            return Err(Error::InvalidValue(format!(
+                "Vsock port {} is already in use",
+                port
+            )));
+        }
+        self.services.insert(port, service);
+        Ok(())
+    }
+
+    fn queue_packet(&mut self, key: ConnectionKey, op: u16, data: Vec<u8>) {
+        let fwd_cnt = match self.connections.get_mut(&key) {
+            Some(conn) => {
+                conn.reported_fwd_cnt = conn.fwd_cnt;
+                conn.fwd_cnt
+            }
+            None => 0,
+        };
+        let flags = if op == op::SHUTDOWN { SHUTDOWN_BOTH } else { 0 };
+        self.rx_pending.push_back(Packet {
+            header: Header {
+                src_cid: HOST_CID,
+                dst_cid: self.guest_cid,
+                src_port: key.1,
+                dst_port: key.0,
+                len: data.len() as u32,
+                kind: TYPE_STREAM,
+                op,
+                flags,
+                buf_alloc: BUF_ALLOC,
+                fwd_cnt,
+            },
+            data,
+        });
+    }
+
+    fn reset(&mut self, key: ConnectionKey) {
+        self.connections.remove(&key);
+        self.queue_packet(key, op::RST, vec![]);
+    }
+
+    // Pass each complete message received on a connection to its service
+    fn receive(&mut self, key: ConnectionKey, data: &[u8]) {
+        let (conn, service) = match (
+            self.connections.get_mut(&key),
+            self.services.get_mut(&key.1),
+        ) {
+            (Some(conn), Some(service)) => (conn, service),
+            _ => return,
+        };
+        conn.fwd_cnt = conn.fwd_cnt.wrapping_add(data.len() as u32);
+        conn.input.extend_from_slice(data);
+
+        while let Some(end) = conn.input.iter().position(|b| *b == b'\n') {
+            let line = conn.input.drain(..=end).collect::<Vec<_>>();
+            let reply = match core::str::from_utf8(&line[..end]) {
+                Ok(message) => Self::reply(
+                    conn,
+                    service,
+                    &mut self.drbg,
+                    message.trim_end_matches('\r'),
+                    &mut self.responses,
+                ),
+                Err(_) => "error invalid message".into(),
+            };
+            conn.output.extend(reply.as_bytes());
+            conn.output.push_back(b'\n');
+        }
+        if conn.input.len() > MAX_MESSAGE_LEN {
+            conn.input.clear();
+            conn.output.extend(b"error message too long\n");
+        }
+    }
+
+    // Send as much of the output of a connection as the guest has space for
+    fn flush(&mut self, key: ConnectionKey) {
+        loop {
+            let conn = match self.connections.get_mut(&key) {
+                Some(conn) => conn,
+                None => return,
+            };
+            let len = conn
+                .output
+                .len()
+                .min(conn.peer_credit() as usize)
+                .min(MAX_PAYLOAD);
+            if len == 0 {
+                // The guest must be told the space it has to send in, even
+                // if there is nothing to reply
+                if conn.fwd_cnt.wrapping_sub(conn.reported_fwd_cnt)
+                    >= BUF_ALLOC / 2
+                {
+                    self.queue_packet(key, op::CREDIT_UPDATE, vec![]);
+                }
+                return;
+            }
+            let data = conn.output.drain(..len).collect::<Vec<_>>();
+            conn.tx_cnt = conn.tx_cnt.wrapping_add(len as u32);
+            self.queue_packet(key, op::RW, data);
+        }
+    }
+
+    fn handle_packet(&mut self, header: &Header, data: &[u8]) {
+        if header.src_cid != self.guest_cid || header.dst_cid != HOST_CID {
+            warn!(
+                "Dropping vsock packet from cid {} to cid {}",
+                header.src_cid, header.dst_cid
+            );
+            return;
+        }
+        let key = (header.src_port, header.dst_port);
+        if header.kind != TYPE_STREAM {
+            if header.op != op::RST {
+                self.reset(key);
+            }
+            return;
+        }
+
+        if header.op == op::REQUEST {
+            if self.services.contains_key(&key.1)
+                && !self.connections.contains_key(&key)
+            {
+                self.connections.insert(key, Connection::default());
+                self.update_credit(key, header);
+                self.queue_packet(key, op::RESPONSE, vec![]);
+            } else {
+                self.reset(key);
+            }
+            return;
+        }
+        if !self.connections.contains_key(&key) {
+            if header.op != op::RST {
+                self.reset(key);
+            }
+            return;
+        }
+
+        self.update_credit(key, header);
+        match header.op {
+            op::RW => self.receive(key, data),
+            op::CREDIT_UPDATE => (),
+            op::CREDIT_REQUEST => {
+                self.queue_packet(key, op::CREDIT_UPDATE, vec![])
+            }
+            op::RST => {
+                self.connections.remove(&key);
+                return;
+            }
+            // The guest will not send any more, and any replies would not be
+            // read, so the connection is closed
+            op::SHUTDOWN => {
+                self.reset(key);
+                return;
+            }
+            _ => {
+                self.reset(key);
+                return;
+            }
+        }
+        self.flush(key);
+    }
+
+    fn update_credit(&mut self, key: ConnectionKey, header: &Header) {
+        if let Some(conn) = self.connections.get_mut(&key) {
+            conn.peer_buf_alloc = header.buf_alloc;
+            conn.peer_fwd_cnt = header.fwd_cnt;
+        }
+    }
+}
+
+impl VirtioDevice for VirtioVsock {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_VSOCK
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn queue_count(&self) -> usize {
+        // The receive, transmit and event queues (no events are sent)
+        3
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let config = self.guest_cid.to_le_bytes();
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = config.get(offset as usize + i).copied().unwrap_or(0);
+        }
+    }
+
+    fn queue_notify(
+        &mut self,
+        queue: usize,
+        vq: &mut Virtqueue,
+        space: &GuestAddressSpace,
+    ) -> Result<bool> {
+        if queue != TX_QUEUE {
+            return Ok(false);
+        }
+        let mut used = false;
+        while let Some(chain) = vq.pop(space)? {
+            let packet = chain.read_all(space)?;
+            vq.push_used(space, chain.head, 0)?;
+            used = true;
+
+            let header = match Header::parse(&packet) {
+                Ok(header) => header,
+                Err(e) => {
+                    warn!("{:?}", e);
+                    continue;
+                }
+            };
+            match packet[HEADER_SIZE..].get(..header.len as usize) {
+                Some(data) => self.handle_packet(&header, data),
+                None => warn!("Truncated vsock packet"),
+            }
+        }
+        Ok(used)
+    }
+
+    fn process_pending(
+        &mut self,
+        queues: &mut [Virtqueue],
+        space: &GuestAddressSpace,
+    ) -> Result<bool> {
+        let rx = &mut queues[RX_QUEUE];
+        let mut used = false;
+        while !self.rx_pending.is_empty() {
+            let chain = match rx.pop(space)? {
+                Some(chain) => chain,
+                None => break,
+            };
+            let packet = self.rx_pending.pop_front().unwrap();
+            let len = HEADER_SIZE + packet.data.len();
+            if chain.writable_len() < len {
+                return Err(Error::InvalidValue(format!(
+                    "Vsock receive buffer is too small ({} bytes)",
+                    chain.writable_len()
+                )));
+            }
+            chain.write_at(space, 0, &packet.header.to_bytes())?;
+            chain.write_at(space, HEADER_SIZE, &packet.data)?;
+            rx.push_used(space, chain.head, len as u32)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    fn take_responses(&mut self) -> Vec<DeviceEventResponse> {
+        core::mem::replace(&mut self.responses, vec![])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const GUEST_CID: u64 = 3;
+
+    fn device() -> VirtioVsock {
+        let mut vsock = VirtioVsock::from_seed(GUEST_CID, &[0; SEED_SIZE]);
+        vsock
+            .add_service(AGENT_PORT, Box::new(AgentService::new("test")))
+            .unwrap();
+        vsock
+    }
+
+    fn guest_packet(op: u16, buf_alloc: u32, data: &[u8]) -> Header {
+        Header {
+            src_cid: GUEST_CID,
+            dst_cid: HOST_CID,
+            src_port: 5000,
+            dst_port: AGENT_PORT,
+            len: data.len() as u32,
+            kind: TYPE_STREAM,
+            op,
+            flags: 0,
+            buf_alloc,
+            fwd_cnt: 0,
+        }
+    }
+
+    fn connect(vsock: &mut VirtioVsock, buf_alloc: u32) {
+        vsock.handle_packet(&guest_packet(op::REQUEST, buf_alloc, &[]), &[]);
+        let packet = vsock.rx_pending.pop_front().unwrap();
+        assert_eq!(packet.header.op, op::RESPONSE);
+        assert_eq!(packet.header.src_port, AGENT_PORT);
+        assert_eq!(packet.header.dst_port, 5000);
+    }
+
+    fn send(vsock: &mut VirtioVsock, data: &[u8]) {
+        vsock.handle_packet(&guest_packet(op::RW, 4096, data), data);
+    }
+
+    #[test]
+    fn test_header() {
+        let header = guest_packet(op::RW, 4096, b"ping\n");
+        assert_eq!(Header::parse(&header.to_bytes()).unwrap(), header);
+        assert!(Header::parse(&[0; 43]).is_err());
+    }
+
+    #[test]
+    fn test_agent_messages() {
+        let mut vsock = device();
+        assert!(vsock
+            .add_service(AGENT_PORT, Box::new(AgentService::new("test")))
+            .is_err());
+
+        // There is no service on other ports
+        let mut header = guest_packet(op::REQUEST, 4096, &[]);
+        header.dst_port = 1;
+        vsock.handle_packet(&header, &[]);
+        assert_eq!(vsock.rx_pending.pop_front().unwrap().header.op, op::RST);
+
+        connect(&mut vsock, 4096);
+        send(&mut vsock, b"pi");
+        assert!(vsock.rx_pending.is_empty());
+        send(&mut vsock, b"ng\nprogress init\nbogus\n");
+        let packet = vsock.rx_pending.pop_front().unwrap();
+        assert_eq!(packet.header.op, op::RW);
+        assert_eq!(packet.header.fwd_cnt, 25);
+        assert_eq!(packet.data, b"pong\nok\nerror unknown command\n");
+
+        send(&mut vsock, b"shutdown\n");
+        assert_eq!(vsock.rx_pending.pop_front().unwrap().data, b"ok\n");
+        match vsock.take_responses().as_slice() {
+            [DeviceEventResponse::ShutdownRequested] => (),
+            responses => panic!("Unexpected responses: {:?}", responses),
+        }
+    }
+
+    #[test]
+    fn test_credit() {
+        let mut vsock = device();
+        connect(&mut vsock, 7);
+
+        // Replies wait for the guest to have space to receive them
+        let data = b"ping\nping\n";
+        vsock.handle_packet(&guest_packet(op::RW, 7, data), data);
+        assert_eq!(vsock.rx_pending.pop_front().unwrap().data, b"pong\npo");
+        assert!(vsock.rx_pending.is_empty());
+
+        let mut update = guest_packet(op::CREDIT_UPDATE, 7, &[]);
+        update.fwd_cnt = 7;
+        vsock.handle_packet(&update, &[]);
+        assert_eq!(vsock.rx_pending.pop_front().unwrap().data, b"ng\n");
+
+        vsock.handle_packet(&guest_packet(op::SHUTDOWN, 7, &[]), &[]);
+        assert_eq!(vsock.rx_pending.pop_front().unwrap().header.op, op::RST);
+        assert!(vsock.connections.is_empty());
+    }
+
+    #[test]
+    fn test_sequence_numbers() {
+        let mut vsock = device();
+        connect(&mut vsock, 4096);
+        send(&mut vsock, b"hello\n");
+        let reply = vsock.rx_pending.pop_front().unwrap().data;
+        let reply = core::str::from_utf8(&reply).unwrap();
+        let isn = reply
+            .trim_start_matches("hello ")
+            .trim_end()
+            .parse::<u32>()
+            .unwrap();
+
+        // The replies carry the number of their message
+        let next = isn.wrapping_add(1);
+        send(&mut vsock, format!("{} ping\n", next).as_bytes());
+        assert_eq!(
+            vsock.rx_pending.pop_front().unwrap().data,
+            format!("{} pong\n", next).as_bytes()
+        );
+
+        // A repeated (or unnumbered) message is rejected
+        let expected = next.wrapping_add(1);
+        send(&mut vsock, format!("{} shutdown\nping\n", next).as_bytes());
+        assert_eq!(
+            vsock.rx_pending.pop_front().unwrap().data,
+            format!(
+                "{0} error bad sequence\n{0} error bad sequence\n",
+                expected
+            )
+            .as_bytes()
+        );
+        assert!(vsock.take_responses().is_empty());
+
+        // Saying hello again starts from a new number
+        send(&mut vsock, b"hello\n");
+        let reply = vsock.rx_pending.pop_front().unwrap().data;
+        assert_ne!(reply, format!("hello {}\n", isn).as_bytes());
+    }
+}
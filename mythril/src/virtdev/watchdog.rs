@@ -0,0 +1,172 @@
+//! Emulation of the IB700 ISA watchdog timer
+//!
+//! The IB700 (as emulated by QEMU, and driven by Linux's `ib700wdt`) has
+//! two write-only ports. Writing to `WDT_START` arms the watchdog (or pats
+//! it if it is already armed), with a timeout selected by the low four
+//! bits of the value: 30 seconds for 0, and two seconds less for each
+//! step up to 0 seconds for 15. Writing to `WDT_STOP` disarms it.
+//!
+//! The watchdog is polled by the vCPUs of its VM as they handle exits (see
+//! `Ib700::poll`), so it expires even when the guest is hung in a loop
+//! that never exits on its own (the VMX-preemption timer still forces
+//! exits). When it expires, its `WatchdogAction` is taken and it is
+//! disarmed until the guest arms it again.
+
+use crate::error::Result;
+use crate::virtdev::{DeviceEvent, DeviceRegion, EmulatedDevice, Event, Port};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::time::Duration;
+use spin::RwLock;
+
+/// What happens when the guest fails to pat its watchdog in time
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatchdogAction {
+    /// Only log the expiry
+    Log,
+
+    /// Inject an NMI, so the guest can report where it is stuck
+    Nmi,
+
+    /// Reset the VM (as if the guest had requested it)
+    Reset,
+}
+
+impl Default for WatchdogAction {
+    fn default() -> Self {
+        WatchdogAction::Reset
+    }
+}
+
+impl fmt::Display for WatchdogAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WatchdogAction::Log => write!(f, "log"),
+            WatchdogAction::Nmi => write!(f, "nmi"),
+            WatchdogAction::Reset => write!(f, "reset"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Ib700 {
+    action: WatchdogAction,
+    timeout: Duration,
+
+    // The uptime at which the watchdog expires (or None if it is disarmed)
+    deadline: Option<Duration>,
+}
+
+impl Ib700 {
+    const WDT_STOP: Port = 0x0441;
+    const WDT_START: Port = 0x0443;
+
+    pub fn new(action: WatchdogAction) -> Arc<RwLock<Self>> {
+        Arc::new(RwLock::new(Self {
+            action,
+            timeout: Duration::from_secs(0),
+            deadline: None,
+        }))
+    }
+
+    /// The timeout selected by a write of `val` to `WDT_START`
+    pub fn timeout_for(val: u8) -> Duration {
+        Duration::from_secs(30 - 2 * (val & 0xf) as u64)
+    }
+
+    /// The action taken when the watchdog expires
+    pub fn action(&self) -> WatchdogAction {
+        self.action
+    }
+
+    /// Returns true if the guest has armed the watchdog
+    pub fn is_armed(&self) -> bool {
+        self.deadline.is_some()
+    }
+
+    /// Arm (or pat) the watchdog at `now` (an uptime), with the timeout
+    /// selected by `val`
+    pub fn start(&mut self, val: u8, now: Duration) {
+        self.timeout = Self::timeout_for(val);
+        self.deadline = Some(now + self.timeout);
+    }
+
+    /// Disarm the watchdog (e.g., when the VM is reset)
+    pub fn stop(&mut self) {
+        self.deadline = None;
+    }
+
+    /// Returns the action to take if the watchdog has expired by `now`, in
+    /// which case it is disarmed
+    pub fn poll(&mut self, now: Duration) -> Option<WatchdogAction> {
+        match self.deadline {
+            Some(deadline) if now >= deadline => {
+                self.deadline = None;
+                warn!(
+                    "Watchdog expired ({}s timeout), taking action '{}'",
+                    self.timeout.as_secs(),
+                    self.action
+                );
+                Some(self.action)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl EmulatedDevice for Ib700 {
+    fn services(&self) -> Vec<DeviceRegion> {
+        vec![
+            DeviceRegion::PortIo(Self::WDT_STOP..=Self::WDT_STOP),
+            DeviceRegion::PortIo(Self::WDT_START..=Self::WDT_START),
+        ]
+    }
+
+    fn on_event(&mut self, event: Event) -> Result<()> {
+        match event.kind {
+            DeviceEvent::PortWrite(Self::WDT_START, val) => {
+                self.start(val.as_u8(), crate::time::uptime());
+            }
+            DeviceEvent::PortWrite(Self::WDT_STOP, _) => self.stop(),
+            DeviceEvent::PortRead(_port, mut val) => {
+                // The ports are write-only
+                val.copy_from_u32(0xffffffff);
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_timeouts() {
+        assert_eq!(Ib700::timeout_for(0), Duration::from_secs(30));
+        assert_eq!(Ib700::timeout_for(0x11), Duration::from_secs(28));
+        assert_eq!(Ib700::timeout_for(15), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_expiry() {
+        let watchdog = Ib700::new(WatchdogAction::Nmi);
+        let mut watchdog = watchdog.write();
+        let secs = Duration::from_secs;
+        assert_eq!(watchdog.poll(secs(u64::MAX)), None);
+
+        // Disarming a watchdog with a zero timeout prevents its expiry
+        watchdog.start(15, secs(100));
+        watchdog.stop();
+        assert_eq!(watchdog.poll(secs(100)), None);
+
+        watchdog.start(15, secs(100));
+        assert!(watchdog.is_armed());
+        assert_eq!(watchdog.poll(secs(99)), None);
+        assert_eq!(watchdog.poll(secs(100)), Some(WatchdogAction::Nmi));
+        assert!(!watchdog.is_armed());
+        assert_eq!(watchdog.poll(secs(200)), None);
+    }
+}
@@ -1,25 +1,58 @@
+use crate::acpi::tables::AcpiTables;
 use crate::apic;
+use crate::biosdata::BiosData;
 use crate::boot_info::BootInfo;
+use crate::chaos;
+use crate::consoleroute::{
+    self, ConsoleDestination, ConsoleFilter, ConsoleRoute,
+};
+use crate::emulate::cpuid::{CpuidPolicy, CpuidTable, GuestClocks};
+use crate::emulate::msr::DebugCtlPolicy;
 use crate::error::{Error, Result};
+use crate::evtchn::{self, EventChannels};
+use crate::exitpolicy::ExitPolicy;
+use crate::identity::{self, Uuid};
 use crate::interrupt;
+use crate::iommu;
+use crate::lifecycle::{RestartPolicy, RestartTracker};
 use crate::lock::ro_after_init::RoAfterInit;
+use crate::measure::{self, MeasuredBoot};
 use crate::memory::{
     self, GuestAddressSpace, GuestPhysAddr, HostPhysAddr, HostPhysFrame,
     Raw4kPage,
 };
+use crate::migrate;
+use crate::nvram::Nvram;
 use crate::percore;
 use crate::physdev;
+use crate::pvh;
+use crate::ratelimit::{
+    ConsoleLimiter, ConsoleRateLimit, EmulationLimiter, EmulationRateLimits,
+    GuestLogLimiter, GuestLogRateLimit,
+};
+use crate::sched::{self, SchedParams, SmtPolicy};
+use crate::smbios::{SmbiosStrings, SmbiosTables};
+use crate::symbols::SymbolMap;
 use crate::time;
+use crate::timekeeping::TimePolicy;
 use crate::virtdev::{
-    DeviceEvent, DeviceInteraction, DeviceMap, Event, ResponseEventArray,
+    acpi, e1000, ioapic, lapic, pci, pic,
+    rtc::RtcPolicy,
+    tpm,
+    uart::{self, SerialBackend},
+    watchdog, DeviceEvent, DeviceInteraction, DeviceMap, Event, Port,
+    ResponseEventArray,
 };
+use crate::vmcs;
 use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
 use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use arraydeque::ArrayDeque;
-use spin::RwLock;
+use core::fmt;
+use core::time::Duration;
+use spin::{Mutex, RwLock};
 
 static BIOS_BLOB: &'static [u8] = include_bytes!("blob/bios.bin");
 
@@ -40,7 +73,25 @@ pub unsafe fn get_vm_for_core_id(
     VIRTUAL_MACHINES.get_by_core_id(core_id)
 }
 
-//FIXME(alschwalm): this breaks if the current VM is already locked
+/// Get the virtual machine with the given id
+///
+/// This method is unsafe for the same reasons as `get_vm_for_core_id`.
+pub unsafe fn get_vm(id: u32) -> Option<Arc<RwLock<VirtualMachine>>> {
+    VIRTUAL_MACHINES.get_by_vm_id(id)
+}
+
+/// The id of the VM with the given name, UUID or (numeric) id
+pub fn find_vm(spec: &str) -> Option<u32> {
+    VIRTUAL_MACHINES.find(spec)
+}
+
+/// The name of the VM with the given id
+pub fn vm_name(id: u32) -> Option<&'static str> {
+    VIRTUAL_MACHINES
+        .context_by_vm_id(id)
+        .map(|context| context.name.as_str())
+}
+
 pub fn send_vm_msg(msg: VirtualMachineMsg, vmid: u32) -> Result<()> {
     VIRTUAL_MACHINES.send_msg(msg, vmid)
 }
@@ -56,18 +107,87 @@ pub fn recv_vm_msg() -> Option<VirtualMachineMsg> {
     VIRTUAL_MACHINES.resv_msg()
 }
 
+/// The number of messages waiting to be received by the current core
+pub fn pending_msg_count() -> usize {
+    VIRTUAL_MACHINES.pending_msg_count()
+}
+
 pub fn max_vm_id() -> u32 {
     VIRTUAL_MACHINES.len() as u32
 }
 
+/// The `vms` management console command
+pub fn vms_command(out: &mut dyn fmt::Write, _args: &[&str]) -> fmt::Result {
+    let mut vms = BTreeMap::new();
+    for (core_id, context) in VIRTUAL_MACHINES.map.iter() {
+        vms.entry(context.id)
+            .or_insert_with(|| (context, vec![]))
+            .1
+            .push(core_id.raw);
+    }
+    for (id, (context, cores)) in vms {
+        writeln!(
+            out,
+            "{:>3} {:<16} {} cores {:?}",
+            id, context.name, context.uuid, cores
+        )?;
+    }
+    Ok(())
+}
+
 const MAX_PENDING_MSG: usize = 100;
 
 pub enum VirtualMachineMsg {
-    GrantConsole(physdev::com::Uart8250),
+    /// Hand the physical serial port to this VM, along with the input it
+    /// received during the handoff. The output of other VMs can still be
+    /// mirrored to the port (see `consoleroute`).
+    GrantConsole(physdev::com::Uart8250, Vec<physdev::com::SerialInput>),
     CancelTimer(time::TimerId),
+
+    /// Deliver a hotplug notification to the guest's ACPI interpreter
+    Hotplug(acpi::HotplugEvent),
+
+    /// Add or remove a function in one of the guest's PCI Express slots
+    PcieHotplug(pci::PcieHotplugEvent),
+
+    /// Wake the vCPU of the receiving core if it is halted (sent by the
+    /// `HC_KICK_CPU` hypercall)
+    Kick,
+
+    /// Disturb the VM for chaos testing (sent by the management console)
+    Inject(chaos::Injection),
+
+    /// Signal the event channel bound to the given source in the receiving
+    /// VM (sent by `evtchn::signal`)
+    EventChannel(evtchn::EventSource),
+
+    /// Set the target size of the VM's memory balloon, in pages (sent by
+    /// the management console)
+    Balloon(u32),
+
+    /// Inject an interrupt routed to the receiving vCPU (sent by the vCPU
+    /// that raised it, through the I/O APIC)
+    Interrupt(u8, crate::vcpu::InjectedInterruptType),
+
+    /// Return the receiving vCPU to its power-on state (sent by the vCPU
+    /// whose guest requested a reset of the VM)
+    Reset,
+
+    /// Start the receiving vCPU at the page given by the vector, if it is
+    /// waiting for a startup IPI (sent by the vCPU whose guest wrote the
+    /// IPI to its ICR)
+    StartupIpi(u8),
+
+    /// Move the receiving vCPU to the given (idle) core (see `migrate`)
+    Migrate(percore::CoreId),
 }
 
 struct VirtualMachineContext {
+    // The identity of the VM is copied here, so it can be found without
+    // taking its lock
+    id: u32,
+    name: String,
+    uuid: Uuid,
     vm: Arc<RwLock<VirtualMachine>>,
     msgqueue: RwLock<ArrayDeque<[VirtualMachineMsg; MAX_PENDING_MSG]>>,
 }
@@ -85,16 +205,20 @@ impl VirtualMachines {
     }
 
     fn context_by_vm_id(&self, id: u32) -> Option<&VirtualMachineContext> {
+        self.map.values().find(|context| context.id == id)
+    }
+
+    fn find(&self, spec: &str) -> Option<u32> {
+        let uuid = Uuid::parse(spec).ok();
+        let id = spec.parse::<u32>().ok();
         self.map
-            .iter()
-            .filter_map(|(_core_id, context)| {
-                if context.vm.read().id == id {
-                    Some(context)
-                } else {
-                    None
-                }
+            .values()
+            .find(|context| {
+                context.name == spec
+                    || Some(context.uuid) == uuid
+                    || Some(context.id) == id
             })
-            .next()
+            .map(|context| context.id)
     }
 
     pub fn len(&self) -> usize {
@@ -128,12 +252,19 @@ impl VirtualMachines {
             ))
         })?;
 
+        // The vCPU may have moved to another core. If it is not running
+        // at all, the message waits in its queue.
+        let host = match migrate::host_core(core_id) {
+            Some(host) => host,
+            None => return Ok(()),
+        };
+
         // Transmit the IPC external interrupt vector to the other vm, so it will
         // process the message.
         unsafe {
             let localapic = apic::get_local_apic_mut();
             localapic.send_ipi(
-                core_id.raw.into(), //TODO(alschwalm): convert core_id to APIC ID
+                host.raw.into(), //TODO(alschwalm): convert core_id to APIC ID
                 apic::DstShorthand::NoShorthand,
                 apic::TriggerMode::Edge,
                 apic::Level::Assert,
@@ -147,13 +278,25 @@ impl VirtualMachines {
     }
 
     pub fn send_msg(&self, msg: VirtualMachineMsg, vm_id: u32) -> Result<()> {
-        //TODO(alschwalm): this should actually be the BSP core_id of the other vm
-        self.send_msg_core(msg, vm_id.into())
+        // Messages for a VM are handled by its first core
+        let core_id = self
+            .map
+            .iter()
+            .find(|(_core_id, context)| context.id == vm_id)
+            .map(|(core_id, _context)| *core_id)
+            .ok_or_else(|| Error::NotFound)?;
+        self.send_msg_core(msg, core_id)
+    }
+
+    pub fn pending_msg_count(&self) -> usize {
+        self.context_by_core_id(migrate::current_vcpu())
+            .map(|context| context.msgqueue.read().len())
+            .unwrap_or(0)
     }
 
     pub fn resv_msg(&self) -> Option<VirtualMachineMsg> {
         let context = self
-            .context_by_core_id(percore::read_core_id())
+            .context_by_core_id(migrate::current_vcpu())
             .expect("No VirtualMachineContext for apic id");
         context.msgqueue.write().pop_front()
     }
@@ -171,10 +314,36 @@ impl VirtualMachineBuilder {
         }
     }
 
+    /// Add a VM, which must not conflict with the `SmtPolicy` of the VMs
+    /// already added
     pub fn insert_machine(
         &mut self,
         vm: Arc<RwLock<VirtualMachine>>,
     ) -> Result<()> {
+        {
+            let machines =
+                self.map.values().map(|vm| vm.read()).collect::<Vec<_>>();
+            let mut placed: Vec<sched::Placement> = vec![];
+            for other in machines.iter() {
+                if placed.iter().all(|p| p.vm_id != other.id) {
+                    placed.push(sched::Placement {
+                        vm_id: other.id,
+                        cores: other.config.cpus(),
+                        smt_policy: other.config.smt_policy(),
+                    });
+                }
+            }
+            let new = vm.read();
+            sched::check_smt_placement(
+                &sched::Placement {
+                    vm_id: new.id,
+                    cores: new.config.cpus(),
+                    smt_policy: new.config.smt_policy(),
+                },
+                &placed,
+                sched::smt_shift(),
+            )?;
+        }
         for cpu in vm.read().config.cpus() {
             self.map.insert(percore::CoreId::from(*cpu), vm.clone());
         }
@@ -194,9 +363,16 @@ impl VirtualMachineBuilder {
                 .map
                 .into_iter()
                 .map(|(core_id, vm)| {
+                    let (id, name, uuid) = {
+                        let vm = vm.read();
+                        (vm.id, vm.name.clone(), vm.uuid)
+                    };
                     (
                         core_id,
                         VirtualMachineContext {
+                            id: id,
+                            name: name,
+                            uuid: uuid,
                             vm: vm,
                             msgqueue: RwLock::new(ArrayDeque::new()),
                         },
@@ -216,13 +392,119 @@ pub struct PhysicalDeviceConfig {
     pub ps2_keyboard: Option<physdev::keyboard::Ps2Controller>,
 }
 
+/// A kernel (and optional initramfs) provided as boot modules
+pub struct KernelImage {
+    /// The boot module containing the kernel
+    pub kernel: String,
+
+    /// The boot module containing the initramfs (if any)
+    pub initramfs: Option<String>,
+
+    /// The NULL terminated kernel command line
+    pub cmdline: Vec<u8>,
+}
+
+/// The method used to boot a `VirtualMachine`
+pub enum BootMethod {
+    /// Start the VM firmware, which boots from the configured boot order
+    Firmware,
+
+    /// Load a linux kernel through fw_cfg, which is then started by the
+    /// firmware using the linuxboot option rom
+    DirectKernel(KernelImage),
+
+    /// Load an ELF kernel and enter it at its PVH entry point (the firmware
+    /// is still mapped, but never executed, so the hypervisor writes the
+    /// IVT, BIOS data area and SMBIOS tables instead)
+    Pvh(KernelImage),
+}
+
+/// A device that the firmware may boot from
+#[derive(Clone, Debug)]
+pub enum BootDevice {
+    /// An option rom provided through fw_cfg (e.g., 'genroms/linuxboot_dma.bin')
+    OptionRom(String),
+
+    /// A disk attached to the PCI device at the given device/function
+    PciDisk { device: u8, function: u8 },
+
+    /// A network card at the given PCI device/function
+    PciNic { device: u8, function: u8 },
+
+    /// A legacy floppy drive
+    Floppy(u8),
+}
+
+impl BootDevice {
+    /// The open firmware path for this device, as used in a 'bootorder' file
+    pub fn firmware_path(&self) -> String {
+        fn pci_path(kind: &str, device: u8, function: u8) -> String {
+            if function == 0 {
+                format!("/pci@i0cf8/{}@{:x}", kind, device)
+            } else {
+                format!("/pci@i0cf8/{}@{:x},{:x}", kind, device, function)
+            }
+        }
+
+        match self {
+            BootDevice::OptionRom(name) => format!("/rom@{}", name),
+            BootDevice::PciDisk { device, function } => {
+                pci_path("disk", *device, *function)
+            }
+            BootDevice::PciNic { device, function } => {
+                pci_path("ethernet", *device, *function)
+            }
+            BootDevice::Floppy(drive) => {
+                format!("/isa/fdc@03f0/floppy@{:x}", drive)
+            }
+        }
+    }
+}
+
 /// A configuration for a `VirtualMachine`
 pub struct VirtualMachineConfig {
+    name: String,
+    uuid: Option<Uuid>,
     cpus: Vec<percore::CoreId>,
     images: Vec<(String, GuestPhysAddr)>,
     virtual_devices: DeviceMap,
     physical_devices: PhysicalDeviceConfig,
     memory: u64, // in MB
+    nvram: Option<Arc<RwLock<Nvram>>>,
+    pic: Option<Arc<RwLock<pic::Pic8259>>>,
+    ioapic: Option<Arc<RwLock<ioapic::IoApic>>>,
+    watchdog: Option<Arc<RwLock<watchdog::Ib700>>>,
+    tpm: Option<Arc<RwLock<tpm::TpmCrb>>>,
+    nic: Option<Arc<RwLock<e1000::E1000>>>,
+    boot_method: BootMethod,
+    boot_order: Vec<BootDevice>,
+    firmware: Option<String>,
+    builtin_firmware: &'static [u8],
+    symbol_map: Option<String>,
+    cpuid_policy: CpuidPolicy,
+    idle_poll: Option<Duration>,
+    start_paused: bool,
+    sched_params: SchedParams,
+    smt_policy: Option<SmtPolicy>,
+    apic_timer_frequency: u64,
+    invlpg_exiting: bool,
+    debugctl_policy: DebugCtlPolicy,
+    measurements: Option<MeasuredBoot>,
+    overhead_msrs: bool,
+    lazy_memory: bool,
+    console_rate_limit: Option<ConsoleRateLimit>,
+    console_routes: Vec<(ConsoleDestination, ConsoleFilter)>,
+    guest_log_rate_limit: GuestLogRateLimit,
+    emulation_rate_limits: EmulationRateLimits,
+    shadow_fields: Vec<vmcs::VmcsField>,
+    rtc_policy: RtcPolicy,
+    serial_backends: [SerialBackend; 4],
+    restart_policy: RestartPolicy,
+    exit_policy: ExitPolicy,
+    time_policy: TimePolicy,
+    balloon: Option<GuestPhysAddr>,
+    acpi_tables: Option<AcpiTables>,
+    smbios_strings: SmbiosStrings,
 }
 
 impl VirtualMachineConfig {
@@ -237,15 +519,82 @@ impl VirtualMachineConfig {
         memory: u64,
         physical_devices: PhysicalDeviceConfig,
     ) -> VirtualMachineConfig {
+        // VMs are numbered by their first core
+        let name = match cpus.first() {
+            Some(core) => format!("vm{}", core.raw),
+            None => "vm".into(),
+        };
         VirtualMachineConfig {
+            name: name,
+            uuid: None,
             cpus: cpus,
             images: vec![],
             virtual_devices: DeviceMap::default(),
             physical_devices: physical_devices,
             memory: memory,
+            nvram: None,
+            pic: None,
+            ioapic: None,
+            watchdog: None,
+            tpm: None,
+            nic: None,
+            boot_method: BootMethod::Firmware,
+            boot_order: vec![],
+            firmware: None,
+            builtin_firmware: BIOS_BLOB,
+            symbol_map: None,
+            cpuid_policy: CpuidPolicy::default(),
+            idle_poll: None,
+            start_paused: false,
+            sched_params: SchedParams::default(),
+            smt_policy: None,
+            apic_timer_frequency: lapic::DEFAULT_APIC_TIMER_FREQUENCY,
+            invlpg_exiting: false,
+            debugctl_policy: DebugCtlPolicy::default(),
+            measurements: None,
+            overhead_msrs: false,
+            lazy_memory: false,
+            console_rate_limit: None,
+            console_routes: vec![],
+            guest_log_rate_limit: GuestLogRateLimit::default(),
+            emulation_rate_limits: EmulationRateLimits::default(),
+            shadow_fields: vec![],
+            rtc_policy: RtcPolicy::default(),
+            serial_backends: [
+                SerialBackend::HostSerial,
+                SerialBackend::Discard,
+                SerialBackend::Discard,
+                SerialBackend::Discard,
+            ],
+            restart_policy: RestartPolicy::default(),
+            exit_policy: ExitPolicy::default(),
+            time_policy: TimePolicy::default(),
+            balloon: None,
+            acpi_tables: None,
+            smbios_strings: SmbiosStrings::default(),
         }
     }
 
+    /// Set the name of the VM (see `identity::validate_name`)
+    pub fn set_name(&mut self, name: String) -> Result<()> {
+        identity::validate_name(&name)?;
+        self.name = name;
+        Ok(())
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Set the UUID of the VM. By default, it is derived from the name.
+    pub fn set_uuid(&mut self, uuid: Uuid) {
+        self.uuid = Some(uuid);
+    }
+
+    pub fn uuid(&self) -> Uuid {
+        self.uuid.unwrap_or_else(|| Uuid::from_name(&self.name))
+    }
+
     /// Specify that the given image 'path' should be mapped to the given address
     ///
     /// The precise meaning of `image` will vary by platform. This will be a
@@ -280,6 +629,468 @@ impl VirtualMachineConfig {
     pub fn cpus(&self) -> &Vec<percore::CoreId> {
         &self.cpus
     }
+
+    /// The memory of this VM, in MB
+    pub fn memory(&self) -> u64 {
+        self.memory
+    }
+
+    /// Attach a persistent `Nvram` store to this VM
+    ///
+    /// The store is owned by the configuration rather than by any device,
+    /// so its contents survive the guest being restarted.
+    pub fn set_nvram(&mut self, nvram: Nvram) -> Arc<RwLock<Nvram>> {
+        let nvram = Arc::new(RwLock::new(nvram));
+        self.nvram = Some(nvram.clone());
+        nvram
+    }
+
+    /// Access the persistent `Nvram` store for this VM (if any)
+    pub fn nvram(&self) -> Option<&Arc<RwLock<Nvram>>> {
+        self.nvram.as_ref()
+    }
+
+    /// Route the legacy interrupts of this VM through `pic` once the guest
+    /// initializes it. The PIC must also be registered as a device.
+    pub fn set_pic(&mut self, pic: Arc<RwLock<pic::Pic8259>>) {
+        self.pic = Some(pic);
+    }
+
+    /// The virtual PIC legacy interrupts are routed through (if any)
+    pub fn pic(&self) -> Option<&Arc<RwLock<pic::Pic8259>>> {
+        self.pic.as_ref()
+    }
+
+    /// Route the legacy interrupts of this VM through `ioapic` once the
+    /// guest unmasks their redirection entries (in preference to the PIC).
+    /// The I/O APIC must also be registered as a device.
+    pub fn set_ioapic(&mut self, ioapic: Arc<RwLock<ioapic::IoApic>>) {
+        self.ioapic = Some(ioapic);
+    }
+
+    pub fn ioapic(&self) -> Option<&Arc<RwLock<ioapic::IoApic>>> {
+        self.ioapic.as_ref()
+    }
+
+    /// Poll `watchdog` as the vCPUs of this VM handle exits, taking its
+    /// action when it expires. The watchdog must also be registered as a
+    /// device.
+    pub fn set_watchdog(&mut self, watchdog: Arc<RwLock<watchdog::Ib700>>) {
+        self.watchdog = Some(watchdog);
+    }
+
+    /// The watchdog of this VM (if it has one)
+    pub fn watchdog(&self) -> Option<&Arc<RwLock<watchdog::Ib700>>> {
+        self.watchdog.as_ref()
+    }
+
+    /// Reset `tpm` with the guest. The TPM must also be registered as a
+    /// device.
+    pub fn set_tpm(&mut self, tpm: Arc<RwLock<tpm::TpmCrb>>) {
+        self.tpm = Some(tpm);
+    }
+
+    /// The virtual TPM of this VM (if it has one)
+    pub fn tpm(&self) -> Option<&Arc<RwLock<tpm::TpmCrb>>> {
+        self.tpm.as_ref()
+    }
+
+    /// Poll `nic` as the vCPUs of this VM handle exits, so frames reach the
+    /// guest even while it is not touching the controller. The controller
+    /// must also be registered as a device and attached to the root complex.
+    pub fn set_nic(&mut self, nic: Arc<RwLock<e1000::E1000>>) {
+        self.nic = Some(nic);
+    }
+
+    /// The network controller of this VM (if it has one)
+    pub fn nic(&self) -> Option<&Arc<RwLock<e1000::E1000>>> {
+        self.nic.as_ref()
+    }
+
+    /// Set the method used to boot this VM (defaults to `BootMethod::Firmware`)
+    pub fn set_boot_method(&mut self, method: BootMethod) {
+        self.boot_method = method;
+    }
+
+    pub fn boot_method(&self) -> &BootMethod {
+        &self.boot_method
+    }
+
+    /// Set the order in which the firmware should attempt to boot devices
+    pub fn set_boot_order(&mut self, order: Vec<BootDevice>) {
+        self.boot_order = order;
+    }
+
+    pub fn boot_order(&self) -> &[BootDevice] {
+        &self.boot_order
+    }
+
+    /// Build the contents of the fw_cfg 'bootorder' file for this VM
+    ///
+    /// When booting a kernel directly, the linuxboot option rom is always
+    /// placed first. Returns `None` if there is no boot order to enforce.
+    pub fn bootorder_file(&self) -> Option<Vec<u8>> {
+        let mut devices = vec![];
+        if let BootMethod::DirectKernel(_) = self.boot_method {
+            devices.push(BootDevice::OptionRom(
+                "genroms/linuxboot_dma.bin".into(),
+            ));
+        }
+        devices.extend(self.boot_order.iter().cloned());
+
+        if devices.is_empty() {
+            return None;
+        }
+
+        // The trailing 'HALT' prevents the firmware from trying any
+        // devices that are not listed
+        let mut file = String::new();
+        for device in devices {
+            file.push_str(&device.firmware_path());
+            file.push('\n');
+        }
+        file.push_str("HALT");
+        Some(file.into_bytes())
+    }
+
+    /// Use the given boot module as the firmware for this VM
+    ///
+    /// By default, the builtin SeaBIOS image is used.
+    pub fn set_firmware(&mut self, module: Option<String>) {
+        self.firmware = module;
+    }
+
+    pub fn firmware(&self) -> Option<&str> {
+        self.firmware.as_ref().map(|name| name.as_str())
+    }
+
+    /// Use an image built into the hypervisor as the firmware for this VM,
+    /// when no firmware module is set (e.g., the `selftest` guest)
+    pub fn set_builtin_firmware(&mut self, image: &'static [u8]) {
+        self.builtin_firmware = image;
+    }
+
+    /// The contents of the firmware used by this VM
+    pub fn firmware_image<'a>(&self, info: &'a BootInfo) -> Result<&'a [u8]> {
+        match self.firmware() {
+            Some(name) => Ok(info
+                .find_module(name)
+                .ok_or_else(|| {
+                    Error::InvalidValue(format!("No such firmware '{}'", name))
+                })?
+                .data()),
+            None => Ok(self.builtin_firmware),
+        }
+    }
+
+    /// Use the given module as the symbol map of the guest kernel (see
+    /// `symbols::SymbolMap`), so guest addresses in logs are symbolized
+    pub fn set_symbol_map(&mut self, module: Option<String>) {
+        self.symbol_map = module;
+    }
+
+    pub fn symbol_map(&self) -> Option<&str> {
+        self.symbol_map.as_ref().map(|name| name.as_str())
+    }
+
+    /// Set the CPUID features exposed to this VM
+    pub fn set_cpuid_policy(&mut self, policy: CpuidPolicy) {
+        self.cpuid_policy = policy;
+    }
+
+    pub fn cpuid_policy(&self) -> &CpuidPolicy {
+        &self.cpuid_policy
+    }
+
+    /// Busy-poll for wakeup events for up to `window` after the guest
+    /// executes HLT, before halting the core
+    ///
+    /// This reduces the wakeup latency of the guest at the cost of power.
+    /// By default (`None`), a HLT halts the core immediately.
+    pub fn set_idle_poll(&mut self, window: Option<Duration>) {
+        self.idle_poll = window;
+    }
+
+    pub fn idle_poll(&self) -> Option<Duration> {
+        self.idle_poll
+    }
+
+    /// Pause each vCPU before its first instruction (at the reset vector,
+    /// or the kernel entry point of a PVH boot) until the `continue`
+    /// management console command is issued
+    ///
+    /// While paused, the guest can be inspected with `coredump` and
+    /// `memscan`, and watches can be added before it executes anything.
+    pub fn set_start_paused(&mut self, paused: bool) {
+        self.start_paused = paused;
+    }
+
+    pub fn start_paused(&self) -> bool {
+        self.start_paused
+    }
+
+    /// Set the weight or deadline reservation of the vCPUs of this VM,
+    /// which must suit the policy of the pool their cores are in (see
+    /// `sched::add_pool`)
+    pub fn set_sched_params(&mut self, params: SchedParams) {
+        self.sched_params = params;
+    }
+
+    pub fn sched_params(&self) -> SchedParams {
+        self.sched_params
+    }
+
+    /// Require the SMT siblings of this VM's cores to run only this VM (or
+    /// allow them to run any VM), instead of following the hypervisor
+    /// default (see `sched::set_default_smt_policy`)
+    pub fn set_smt_policy(&mut self, policy: Option<SmtPolicy>) {
+        self.smt_policy = policy;
+    }
+
+    pub fn smt_policy(&self) -> SmtPolicy {
+        self.smt_policy.unwrap_or_else(sched::default_smt_policy)
+    }
+
+    /// Set the frequency (in Hz) of the local APIC timers of this VM
+    ///
+    /// This is also reported to the guest as the crystal clock frequency,
+    /// so the guest does not need to calibrate the timer.
+    pub fn set_apic_timer_frequency(&mut self, frequency: u64) {
+        self.apic_timer_frequency = frequency;
+    }
+
+    pub fn apic_timer_frequency(&self) -> u64 {
+        self.apic_timer_frequency
+    }
+
+    /// Cause a VMEXIT when the guest executes INVLPG or INVPCID
+    ///
+    /// This allows the guest's TLB invalidations to be observed (e.g., for
+    /// introspection), at the cost of an exit for each of them. By
+    /// default, the guest invalidates its translations directly.
+    pub fn set_invlpg_exiting(&mut self, enabled: bool) {
+        self.invlpg_exiting = enabled;
+    }
+
+    pub fn invlpg_exiting(&self) -> bool {
+        self.invlpg_exiting
+    }
+
+    /// Set how the guest's branch tracing (IA32_DEBUGCTL and the last
+    /// branch records) is virtualized. By default it is masked.
+    pub fn set_debugctl_policy(&mut self, policy: DebugCtlPolicy) {
+        self.debugctl_policy = policy;
+    }
+
+    pub fn debugctl_policy(&self) -> DebugCtlPolicy {
+        self.debugctl_policy
+    }
+
+    /// Measure the firmware and boot images of this VM into a bank of
+    /// PCRs and an event log (see `measure`)
+    ///
+    /// The images are measured as they are currently configured, so this
+    /// must be called after the boot method and firmware are set.
+    pub fn enable_measured_boot(&mut self, info: &BootInfo) -> Result<()> {
+        self.measurements = Some(measure::measure_boot(self, info)?);
+        Ok(())
+    }
+
+    pub fn measurements(&self) -> Option<&MeasuredBoot> {
+        self.measurements.as_ref()
+    }
+
+    /// Expose the time each vCPU spends handling exits (its steal time)
+    /// and running the guest to the guest, through the synthetic MSRs
+    /// `emulate::msr::MSR_MYTHRIL_EXIT_TIME` and `MSR_MYTHRIL_GUEST_TIME`
+    ///
+    /// By default, reading these MSRs raises #GP.
+    pub fn set_overhead_msrs(&mut self, enabled: bool) {
+        self.overhead_msrs = enabled;
+    }
+
+    pub fn overhead_msrs(&self) -> bool {
+        self.overhead_msrs
+    }
+
+    /// Allocate guest RAM when the guest first touches each page, rather
+    /// than when the VM is created
+    ///
+    /// If idle polling is enabled, the remaining pages are allocated in
+    /// the background while the vCPUs are idle.
+    pub fn set_lazy_memory(&mut self, enabled: bool) {
+        self.lazy_memory = enabled;
+    }
+
+    pub fn lazy_memory(&self) -> bool {
+        self.lazy_memory
+    }
+
+    /// Limit the rate of the UART output of this VM (see `ratelimit`)
+    ///
+    /// By default, the output is not limited.
+    pub fn set_console_rate_limit(&mut self, limit: Option<ConsoleRateLimit>) {
+        self.console_rate_limit = limit;
+    }
+
+    pub fn console_rate_limit(&self) -> Option<ConsoleRateLimit> {
+        self.console_rate_limit
+    }
+
+    /// Mirror the lines of console output of this VM accepted by `filter`
+    /// to `destination` (see `consoleroute`)
+    ///
+    /// This is in addition to the output written to the physical serial
+    /// port while this VM owns it.
+    pub fn add_console_route(
+        &mut self,
+        destination: ConsoleDestination,
+        filter: ConsoleFilter,
+    ) {
+        self.console_routes.push((destination, filter));
+    }
+
+    pub fn console_routes(&self) -> &[(ConsoleDestination, ConsoleFilter)] {
+        &self.console_routes
+    }
+
+    /// Limit the rate of the messages the guest logs by hypercall
+    pub fn set_guest_log_rate_limit(&mut self, limit: GuestLogRateLimit) {
+        self.guest_log_rate_limit = limit;
+    }
+
+    pub fn guest_log_rate_limit(&self) -> GuestLogRateLimit {
+        self.guest_log_rate_limit
+    }
+
+    /// Limit the rate of the port and MMIO accesses and the interrupts
+    /// handled for this VM (see `ratelimit`)
+    ///
+    /// By default, neither is limited.
+    pub fn set_emulation_rate_limits(&mut self, limits: EmulationRateLimits) {
+        self.emulation_rate_limits = limits;
+    }
+
+    pub fn emulation_rate_limits(&self) -> EmulationRateLimits {
+        self.emulation_rate_limits
+    }
+
+    /// Allow the guest to VMREAD `fields` without an exit, through a shadow
+    /// VMCS updated on every exit. This is ignored if the processor does
+    /// not support VMCS shadowing.
+    pub fn set_shadow_fields(&mut self, fields: Vec<vmcs::VmcsField>) {
+        self.shadow_fields = fields;
+    }
+
+    pub fn shadow_fields(&self) -> &[vmcs::VmcsField] {
+        &self.shadow_fields
+    }
+
+    /// Set whether the guest RTC reads UTC or local time, and whether the
+    /// time the guest sets on it is kept. By default it reads UTC and
+    /// keeps the guest's time in the VM's nvram.
+    pub fn set_rtc_policy(&mut self, policy: RtcPolicy) {
+        self.rtc_policy = policy;
+    }
+
+    pub fn rtc_policy(&self) -> RtcPolicy {
+        self.rtc_policy
+    }
+
+    /// Set the backends of the guest's COM1 to COM4. By default, COM1 is
+    /// connected to the host serial port and the others discard their
+    /// output.
+    pub fn set_serial_backends(&mut self, backends: [SerialBackend; 4]) {
+        self.serial_backends = backends;
+    }
+
+    pub fn serial_backends(&self) -> [SerialBackend; 4] {
+        self.serial_backends
+    }
+
+    /// Set whether the guest is restarted after it stops (see
+    /// `lifecycle`). By default, it is never restarted.
+    pub fn set_restart_policy(&mut self, policy: RestartPolicy) {
+        self.restart_policy = policy;
+    }
+
+    pub fn restart_policy(&self) -> RestartPolicy {
+        self.restart_policy
+    }
+
+    /// Set the actions taken for selected VM exits, before the regular
+    /// handlers (see `exitpolicy`). By default, every exit is handled.
+    pub fn set_exit_policy(&mut self, policy: ExitPolicy) {
+        self.exit_policy = policy;
+    }
+
+    pub fn exit_policy(&self) -> &ExitPolicy {
+        &self.exit_policy
+    }
+
+    /// Set how the guest sees the time its vCPUs are paused or stopped for
+    /// a snapshot (see `timekeeping`). By default, guest time jumps forward
+    /// when they run again.
+    pub fn set_time_policy(&mut self, policy: TimePolicy) {
+        self.time_policy = policy;
+    }
+
+    pub fn time_policy(&self) -> TimePolicy {
+        self.time_policy
+    }
+
+    /// Set the address of the registers of the VM's virtio balloon device,
+    /// which receives the target sizes set by the `balloon` console command
+    pub fn set_balloon(&mut self, base: GuestPhysAddr) {
+        self.balloon = Some(base);
+    }
+
+    pub fn balloon(&self) -> Option<GuestPhysAddr> {
+        self.balloon
+    }
+
+    /// Provide the guest with the given ACPI tables (see `acpi::tables`)
+    ///
+    /// The tables are installed by the firmware (through fw_cfg), or
+    /// placed in guest memory directly for a PVH boot. Without them, the
+    /// guest only has whatever tables its firmware builds itself.
+    pub fn set_acpi_tables(&mut self, tables: AcpiTables) {
+        self.acpi_tables = Some(tables);
+    }
+
+    pub fn acpi_tables(&self) -> Option<&AcpiTables> {
+        self.acpi_tables.as_ref()
+    }
+
+    /// Set the vendor, product (and other) strings reported in the SMBIOS
+    /// tables of the VM
+    pub fn set_smbios_strings(&mut self, strings: SmbiosStrings) {
+        self.smbios_strings = strings;
+    }
+
+    pub fn smbios_strings(&self) -> &SmbiosStrings {
+        &self.smbios_strings
+    }
+
+    /// The SMBIOS tables of the VM, describing its cores, memory and UUID
+    pub fn smbios_tables(&self) -> Result<SmbiosTables> {
+        SmbiosTables::new(
+            &self.smbios_strings,
+            &self.uuid(),
+            self.cpus.len(),
+            self.memory,
+        )
+    }
+
+    /// The base port of the guest UART connected to the host serial port
+    /// (if any)
+    pub fn host_serial_port(&self) -> Option<Port> {
+        uart::COM_PORTS
+            .iter()
+            .zip(self.serial_backends.iter())
+            .find(|(_, backend)| **backend == SerialBackend::HostSerial)
+            .map(|((port, _), _)| *port)
+    }
 }
 
 /// A virtual machine
@@ -287,6 +1098,12 @@ pub struct VirtualMachine {
     /// The numeric ID of this virtual machine
     pub id: u32,
 
+    /// The name of this virtual machine (from the `config`)
+    pub name: String,
+
+    /// The UUID of this virtual machine (from the `config`)
+    pub uuid: Uuid,
+
     /// The configuration for this virtual machine (including the `DeviceMap`)
     pub config: VirtualMachineConfig,
 
@@ -294,6 +1111,32 @@ pub struct VirtualMachine {
     ///
     /// This will be shared by all `VCpu`s associated with this VM.
     pub guest_space: GuestAddressSpace,
+
+    /// The initial processor state for a VM using `BootMethod::Pvh`
+    pub pvh_entry: Option<pvh::PvhEntry>,
+
+    /// The CPUID leaves reported to the guest (built from the policy in
+    /// the `config`)
+    pub cpuid: Arc<CpuidTable>,
+
+    /// The symbols of the guest kernel (empty if no map was configured)
+    pub symbols: SymbolMap,
+
+    /// The rate limiting state and counters of the UART output
+    pub console: Mutex<ConsoleLimiter>,
+
+    /// The rate limiting state of the messages the guest logs by hypercall
+    pub guest_log: Mutex<GuestLogLimiter>,
+
+    /// The rate limiting state and counters of the emulation work done
+    /// for the guest
+    pub emulation: Mutex<EmulationLimiter>,
+
+    /// The restart history of the guest
+    pub restarts: Mutex<RestartTracker>,
+
+    /// The event channels the guest has bound (see `evtchn`)
+    pub event_channels: Mutex<EventChannels>,
 }
 
 impl VirtualMachine {
@@ -306,15 +1149,127 @@ impl VirtualMachine {
         config: VirtualMachineConfig,
         info: &BootInfo,
     ) -> Result<Arc<RwLock<Self>>> {
-        let guest_space = Self::setup_ept(&config, info)?;
+        let mut guest_space = Self::setup_ept(&config, info)?;
+
+        let pvh_entry = match config.boot_method() {
+            BootMethod::Pvh(image) => {
+                // There is no firmware to set up the BIOS data or install
+                // the SMBIOS tables
+                let com_ports = uart::COM_PORTS
+                    .iter()
+                    .map(|(port, _)| *port)
+                    .filter(|port| {
+                        config.virtual_devices().find_device(*port).is_some()
+                    })
+                    .collect::<Vec<_>>();
+                pvh::load_bios_data(
+                    &BiosData::new(&com_ports),
+                    &mut guest_space,
+                )?;
+                pvh::load_smbios(&config.smbios_tables()?, &mut guest_space)?;
+                Some(pvh::load_pvh(
+                    &image.kernel,
+                    image.initramfs.as_ref(),
+                    &image.cmdline,
+                    config.memory,
+                    config.acpi_tables(),
+                    &mut guest_space,
+                    info,
+                )?)
+            }
+            _ => None,
+        };
+
+        // A TSC frequency of zero is reported as not enumerated
+        let clocks = GuestClocks {
+            tsc: if time::is_global_time_ready() {
+                time::frequency()
+            } else {
+                0
+            },
+            apic_timer: config.apic_timer_frequency(),
+        };
+        let cpuid = CpuidTable::new(config.cpuid_policy().clone(), clocks);
+
+        let symbols = match config.symbol_map() {
+            Some(name) => SymbolMap::parse(
+                info.find_module(name)
+                    .ok_or_else(|| {
+                        Error::InvalidValue(format!(
+                            "No such symbol map '{}'",
+                            name
+                        ))
+                    })?
+                    .data(),
+            )?,
+            None => SymbolMap::default(),
+        };
+
+        let console =
+            Mutex::new(ConsoleLimiter::new(config.console_rate_limit()));
+        let guest_log =
+            Mutex::new(GuestLogLimiter::new(config.guest_log_rate_limit()));
+        let emulation =
+            Mutex::new(EmulationLimiter::new(config.emulation_rate_limits()));
+        for (destination, filter) in config.console_routes() {
+            consoleroute::add_route(ConsoleRoute {
+                source: id,
+                destination: *destination,
+                filter: filter.clone(),
+            });
+        }
+
+        let name = config.name().into();
+        let uuid = config.uuid();
 
         Ok(Arc::new(RwLock::new(Self {
             id: id,
+            name: name,
+            uuid: uuid,
             config: config,
             guest_space: guest_space,
+            pvh_entry: pvh_entry,
+            cpuid: Arc::new(cpuid),
+            symbols: symbols,
+            console: console,
+            guest_log: guest_log,
+            emulation: emulation,
+            restarts: Mutex::new(RestartTracker::default()),
+            event_channels: Mutex::new(EventChannels::default()),
         })))
     }
 
+    /// Change the CPUID features exposed to this VM
+    ///
+    /// The cached CPUID table is rebuilt, and each vCPU takes the new table
+    /// at its next exit that is not completed by the fast path.
+    pub fn set_cpuid_policy(&mut self, policy: CpuidPolicy) {
+        if *self.cpuid.policy() != policy {
+            self.cpuid =
+                Arc::new(CpuidTable::new(policy.clone(), self.cpuid.clocks()));
+        }
+        self.config.set_cpuid_policy(policy);
+    }
+
+    /// Give this VM ownership of the host device `source_id` in `segment`
+    ///
+    /// The device will perform DMA using the guest physical address space
+    /// of this VM.
+    pub fn attach_host_device(
+        &mut self,
+        segment: u16,
+        source_id: u16,
+        policy: iommu::FaultPolicy,
+    ) -> Result<()> {
+        iommu::attach_device(
+            segment,
+            source_id,
+            self.id,
+            policy,
+            &mut self.guest_space,
+        )
+    }
+
     pub fn dispatch_event(
         &mut self,
         ident: impl DeviceInteraction + core::fmt::Debug,
@@ -340,10 +1295,35 @@ impl VirtualMachine {
         dev.write().on_event(event)
     }
 
+    /// Deliver a string write of `data` to the device at `port`, as
+    /// consecutive writes of `stride` bytes (see
+    /// `EmulatedDevice::on_port_write_bulk`)
+    pub fn dispatch_port_write_bulk(
+        &mut self,
+        port: Port,
+        data: &[u8],
+        stride: usize,
+        vcpu: &crate::vcpu::VCpu,
+        responses: &mut ResponseEventArray,
+    ) -> Result<()> {
+        let dev = self.config.virtual_devices().find_device(port).ok_or_else(
+            || Error::MissingDevice("Unable to dispatch event".into()),
+        )?;
+
+        let space = crate::memory::GuestAddressSpaceViewMut::from_vmcs(
+            &vcpu.vmcs,
+            &mut self.guest_space,
+        )?;
+
+        dev.write()
+            .on_port_write_bulk(port, data, stride, space, responses)
+    }
+
     fn map_data(
         image: &[u8],
         addr: &GuestPhysAddr,
         space: &mut GuestAddressSpace,
+        readonly: bool,
     ) -> Result<()> {
         for (i, chunk) in image.chunks(4096 as usize).enumerate() {
             let frame_ptr =
@@ -365,7 +1345,7 @@ impl VirtualMachine {
                     addr.as_u64() + (i as u64 * 4096) as u64,
                 ),
                 frame,
-                false,
+                readonly,
             )?;
         }
         Ok(())
@@ -383,20 +1363,41 @@ impl VirtualMachine {
                 Error::InvalidValue(format!("No such module '{}'", image))
             })?
             .data();
-        Self::map_data(data, addr, space)
+        Self::map_data(data, addr, space, false)
     }
 
-    fn map_bios(space: &mut GuestAddressSpace) -> Result<()> {
-        let bios_size = BIOS_BLOB.len() as u64;
+    fn map_bios(
+        config: &VirtualMachineConfig,
+        space: &mut GuestAddressSpace,
+        info: &BootInfo,
+    ) -> Result<()> {
+        let bios = config.firmware_image(info)?;
+
+        // The copy below 1MB is the 'shadow' of the firmware, which is
+        // writable RAM (firmware will unlock it via the PAM registers and
+        // relocate itself there during POST). Large firmware images (e.g.,
+        // OVMF) cannot be mapped entirely below 1MB, so only the last 128KB
+        // of them is aliased there.
+        let legacy_bios = if bios.len() > 256 * 1024 {
+            &bios[bios.len() - 128 * 1024..]
+        } else {
+            bios
+        };
         Self::map_data(
-            BIOS_BLOB,
-            &memory::GuestPhysAddr::new((1024 * 1024) - bios_size),
+            legacy_bios,
+            &memory::GuestPhysAddr::new(
+                (1024 * 1024) - legacy_bios.len() as u64,
+            ),
             space,
+            false,
         )?;
+
+        let bios_size = bios.len() as u64;
         Self::map_data(
-            BIOS_BLOB,
+            bios,
             &memory::GuestPhysAddr::new((4 * 1024 * 1024 * 1024) - bios_size),
             space,
+            true, // The flash at the top of 4GB is always read-only
         )
     }
 
@@ -405,15 +1406,22 @@ impl VirtualMachine {
         info: &BootInfo,
     ) -> Result<GuestAddressSpace> {
         let mut guest_space = GuestAddressSpace::new()?;
+        if config.lazy_memory {
+            guest_space.set_lazy_memory(config.memory << 20);
+        }
 
         // First map the bios
-        Self::map_bios(&mut guest_space)?;
+        Self::map_bios(config, &mut guest_space, info)?;
 
         // Now map any guest iamges
         for image in config.images.iter() {
             Self::map_image(&image.0, &image.1, &mut guest_space, info)?;
         }
 
+        if config.lazy_memory {
+            return Ok(guest_space);
+        }
+
         // Iterate over each page
         for i in 0..(config.memory << 8) {
             match guest_space.map_new_frame(
@@ -445,4 +1453,38 @@ mod test {
         );
         VirtualMachine::new(0, config, &info).unwrap();
     }
+
+    #[test]
+    fn test_bootorder_file() {
+        let mut config = VirtualMachineConfig::new(
+            vec![percore::CoreId::from(1)],
+            0,
+            PhysicalDeviceConfig::default(),
+        );
+        assert_eq!(config.bootorder_file(), None);
+
+        config.set_boot_order(vec![
+            BootDevice::PciDisk {
+                device: 4,
+                function: 0,
+            },
+            BootDevice::PciNic {
+                device: 3,
+                function: 1,
+            },
+        ]);
+        config.set_boot_method(BootMethod::DirectKernel(KernelImage {
+            kernel: "kernel".into(),
+            initramfs: None,
+            cmdline: vec![0],
+        }));
+        assert_eq!(
+            config.bootorder_file().unwrap(),
+            "/rom@genroms/linuxboot_dma.bin\n\
+             /pci@i0cf8/disk@4\n\
+             /pci@i0cf8/ethernet@3,1\n\
+             HALT"
+                .as_bytes()
+        );
+    }
 }
@@ -0,0 +1,508 @@
+//! Pre-entry checks of the VMCS guest state
+//!
+//! When VM entry fails because of invalid guest state, the processor only
+//! reports a generic failure (or a VM-instruction error). In debug builds,
+//! the checks in this module are run before each VM entry so the specific
+//! check that would fail is reported instead. Only a subset of the checks
+//! in the SDM (Vol. 3C § 26.3.1) is implemented, focusing on the ones that
+//! mythril's own emulation could violate.
+
+use crate::error::{Error, Result};
+use crate::vmcs::{
+    self, CpuBasedCtrlFlags, SecondaryExecFlags, VmEntryCtrlFlags,
+    VmExitCtrlFlags, VmcsField,
+};
+use alloc::string::String;
+use alloc::vec::Vec;
+use x86::msr;
+
+const CR0_PE: u64 = 1 << 0;
+const CR0_PG: u64 = 1 << 31;
+const CR4_PAE: u64 = 1 << 5;
+const CR4_PCIDE: u64 = 1 << 17;
+const EFER_LME: u64 = 1 << 8;
+const EFER_LMA: u64 = 1 << 10;
+const RFLAGS_RESERVED_ONE: u64 = 1 << 1;
+const RFLAGS_RESERVED_ZERO: u64 = 0xffffffffffc08028;
+const RFLAGS_VM: u64 = 1 << 17;
+
+// Segment access rights fields
+const AR_TYPE_MASK: u64 = 0xf;
+const AR_S: u64 = 1 << 4;
+const AR_DPL_SHIFT: u64 = 5;
+const AR_P: u64 = 1 << 7;
+const AR_RESERVED: u64 = 0xfffe0f00;
+const AR_L: u64 = 1 << 13;
+const AR_DB: u64 = 1 << 14;
+const AR_G: u64 = 1 << 15;
+const AR_UNUSABLE: u64 = 1 << 16;
+
+/// The guest data and code segment registers
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Segment {
+    Cs,
+    Ss,
+    Ds,
+    Es,
+    Fs,
+    Gs,
+}
+
+/// The state of a single guest segment register
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SegmentState {
+    pub selector: u64,
+    pub limit: u64,
+    pub access_rights: u64,
+}
+
+impl SegmentState {
+    fn usable(&self) -> bool {
+        self.access_rights & AR_UNUSABLE == 0
+    }
+
+    fn segment_type(&self) -> u64 {
+        self.access_rights & AR_TYPE_MASK
+    }
+
+    fn dpl(&self) -> u64 {
+        (self.access_rights >> AR_DPL_SHIFT) & 0b11
+    }
+}
+
+/// The parts of the VMCS (and VMX capability MSRs) that are checked before
+/// VM entry
+#[derive(Clone, Debug, Default)]
+pub struct EntryState {
+    pub cr0: u64,
+    pub cr4: u64,
+    pub efer: u64,
+    pub rflags: u64,
+    pub entry_controls: u64,
+    pub exit_controls: u64,
+    pub proc_controls: u64,
+    pub secondary_controls: u64,
+    pub activity_state: u64,
+    pub cr0_fixed0: u64,
+    pub cr0_fixed1: u64,
+    pub cr4_fixed0: u64,
+    pub cr4_fixed1: u64,
+    pub cs: SegmentState,
+    pub ss: SegmentState,
+    pub ds: SegmentState,
+    pub es: SegmentState,
+    pub fs: SegmentState,
+    pub gs: SegmentState,
+    pub tr: SegmentState,
+    pub ldtr: SegmentState,
+}
+
+impl EntryState {
+    /// Read the state to check from the active VMCS
+    pub fn from_active_vmcs(vmcs: &vmcs::ActiveVmcs) -> Result<Self> {
+        let segment =
+            |selector, limit, access_rights| -> Result<SegmentState> {
+                Ok(SegmentState {
+                    selector: vmcs.read_field(selector)?,
+                    limit: vmcs.read_field(limit)?,
+                    access_rights: vmcs.read_field(access_rights)?,
+                })
+            };
+        let (cr0_fixed0, cr0_fixed1, cr4_fixed0, cr4_fixed1) = unsafe {
+            (
+                msr::rdmsr(msr::IA32_VMX_CR0_FIXED0),
+                msr::rdmsr(msr::IA32_VMX_CR0_FIXED1),
+                msr::rdmsr(msr::IA32_VMX_CR4_FIXED0),
+                msr::rdmsr(msr::IA32_VMX_CR4_FIXED1),
+            )
+        };
+        Ok(Self {
+            cr0: vmcs.read_field(VmcsField::GuestCr0)?,
+            cr4: vmcs.read_field(VmcsField::GuestCr4)?,
+            efer: vmcs.read_field(VmcsField::GuestIa32Efer)?,
+            rflags: vmcs.read_field(VmcsField::GuestRflags)?,
+            entry_controls: vmcs.read_field(VmcsField::VmEntryControls)?,
+            exit_controls: vmcs.read_field(VmcsField::VmExitControls)?,
+            proc_controls: vmcs.read_field(VmcsField::CpuBasedVmExecControl)?,
+            secondary_controls: vmcs
+                .read_field(VmcsField::SecondaryVmExecControl)?,
+            activity_state: vmcs.read_field(VmcsField::GuestActivityState)?,
+            cr0_fixed0,
+            cr0_fixed1,
+            cr4_fixed0,
+            cr4_fixed1,
+            cs: segment(
+                VmcsField::GuestCsSelector,
+                VmcsField::GuestCsLimit,
+                VmcsField::GuestCsArBytes,
+            )?,
+            ss: segment(
+                VmcsField::GuestSsSelector,
+                VmcsField::GuestSsLimit,
+                VmcsField::GuestSsArBytes,
+            )?,
+            ds: segment(
+                VmcsField::GuestDsSelector,
+                VmcsField::GuestDsLimit,
+                VmcsField::GuestDsArBytes,
+            )?,
+            es: segment(
+                VmcsField::GuestEsSelector,
+                VmcsField::GuestEsLimit,
+                VmcsField::GuestEsArBytes,
+            )?,
+            fs: segment(
+                VmcsField::GuestFsSelector,
+                VmcsField::GuestFsLimit,
+                VmcsField::GuestFsArBytes,
+            )?,
+            gs: segment(
+                VmcsField::GuestGsSelector,
+                VmcsField::GuestGsLimit,
+                VmcsField::GuestGsArBytes,
+            )?,
+            tr: segment(
+                VmcsField::GuestTrSelector,
+                VmcsField::GuestTrLimit,
+                VmcsField::GuestTrArBytes,
+            )?,
+            ldtr: segment(
+                VmcsField::GuestLdtrSelector,
+                VmcsField::GuestLdtrLimit,
+                VmcsField::GuestLdtrArBytes,
+            )?,
+        })
+    }
+
+    fn unrestricted_guest(&self) -> bool {
+        self.proc_controls
+            & CpuBasedCtrlFlags::ACTIVATE_SECONDARY_CONTROLS.bits()
+            != 0
+            && self.secondary_controls
+                & SecondaryExecFlags::UNRESTRICTED_GUEST.bits()
+                != 0
+    }
+
+    fn ia32e_guest(&self) -> bool {
+        self.entry_controls & VmEntryCtrlFlags::IA32E_MODE.bits() != 0
+    }
+
+    /// Run the checks, returning a description of each one that fails
+    pub fn violations(&self) -> Vec<String> {
+        let mut violations = vec![];
+        self.check_control_registers(&mut violations);
+        self.check_rflags(&mut violations);
+        self.check_segments(&mut violations);
+        if self.activity_state > 3 {
+            violations.push(format!(
+                "26.3.1.5: invalid activity state {}",
+                self.activity_state
+            ));
+        }
+        if self.exit_controls & VmExitCtrlFlags::IA32E_MODE.bits() == 0 {
+            violations.push(
+                "26.2.4: 'host address-space size' exit control is clear \
+                 for a 64-bit host"
+                    .into(),
+            );
+        }
+        violations
+    }
+
+    fn check_control_registers(&self, violations: &mut Vec<String>) {
+        // With unrestricted guest, PE and PG are not required to be set
+        let mut cr0_fixed0 = self.cr0_fixed0;
+        if self.unrestricted_guest() {
+            cr0_fixed0 &= !(CR0_PE | CR0_PG);
+        }
+        if self.cr0 & cr0_fixed0 != cr0_fixed0 {
+            violations.push(format!(
+                "26.3.1.1: CR0=0x{:x} is missing required bits 0x{:x}",
+                self.cr0,
+                cr0_fixed0 & !self.cr0
+            ));
+        }
+        if self.cr0 & !self.cr0_fixed1 & 0xffffffff != 0 {
+            violations.push(format!(
+                "26.3.1.1: CR0=0x{:x} sets disallowed bits 0x{:x}",
+                self.cr0,
+                self.cr0 & !self.cr0_fixed1
+            ));
+        }
+        if self.cr0 & CR0_PG != 0 && self.cr0 & CR0_PE == 0 {
+            violations.push("26.3.1.1: CR0.PG is set without CR0.PE".into());
+        }
+        if self.cr4 & self.cr4_fixed0 != self.cr4_fixed0 {
+            violations.push(format!(
+                "26.3.1.1: CR4=0x{:x} is missing required bits 0x{:x}",
+                self.cr4,
+                self.cr4_fixed0 & !self.cr4
+            ));
+        }
+        if self.cr4 & !self.cr4_fixed1 != 0 {
+            violations.push(format!(
+                "26.3.1.1: CR4=0x{:x} sets disallowed bits 0x{:x}",
+                self.cr4,
+                self.cr4 & !self.cr4_fixed1
+            ));
+        }
+
+        if self.ia32e_guest() {
+            if self.cr0 & CR0_PG == 0 || self.cr4 & CR4_PAE == 0 {
+                violations.push(
+                    "26.3.1.1: 'IA-32e mode guest' entry control requires \
+                     CR0.PG and CR4.PAE"
+                        .into(),
+                );
+            }
+        } else if self.cr4 & CR4_PCIDE != 0 {
+            violations.push(
+                "26.3.1.1: CR4.PCIDE is set outside of IA-32e mode".into(),
+            );
+        }
+
+        if self.entry_controls & VmEntryCtrlFlags::LOAD_GUEST_EFER.bits() != 0 {
+            let lma = self.efer & EFER_LMA != 0;
+            if lma != self.ia32e_guest() {
+                violations.push(format!(
+                    "26.3.1.1: EFER.LMA={} does not match the 'IA-32e mode \
+                     guest' entry control",
+                    lma as u8
+                ));
+            }
+            let lme = self.efer & EFER_LME != 0;
+            if self.cr0 & CR0_PG != 0 && lma != lme {
+                violations.push(format!(
+                    "26.3.1.1: EFER.LME={} does not match EFER.LMA={} with \
+                     paging enabled",
+                    lme as u8, lma as u8
+                ));
+            }
+        }
+    }
+
+    fn check_rflags(&self, violations: &mut Vec<String>) {
+        if self.rflags & RFLAGS_RESERVED_ONE == 0
+            || self.rflags & RFLAGS_RESERVED_ZERO != 0
+        {
+            violations.push(format!(
+                "26.3.1.4: RFLAGS=0x{:x} has invalid reserved bits",
+                self.rflags
+            ));
+        }
+        if (self.ia32e_guest() || self.cr0 & CR0_PE == 0)
+            && self.rflags & RFLAGS_VM != 0
+        {
+            violations.push(
+                "26.3.1.4: RFLAGS.VM is set in IA-32e mode or real mode".into(),
+            );
+        }
+    }
+
+    fn check_segments(&self, violations: &mut Vec<String>) {
+        // Virtual-8086 segments are checked differently (and not here)
+        if self.rflags & RFLAGS_VM != 0 {
+            return;
+        }
+
+        let unrestricted = self.unrestricted_guest();
+        let segments = [
+            (Segment::Cs, &self.cs),
+            (Segment::Ss, &self.ss),
+            (Segment::Ds, &self.ds),
+            (Segment::Es, &self.es),
+            (Segment::Fs, &self.fs),
+            (Segment::Gs, &self.gs),
+        ];
+        for (name, seg) in segments.iter() {
+            // CS is always usable
+            if !seg.usable() && *name != Segment::Cs {
+                continue;
+            }
+            let mut fail = |msg: &str| {
+                violations.push(format!(
+                    "26.3.1.2: {:?} (selector=0x{:x} ar=0x{:x}): {}",
+                    name, seg.selector, seg.access_rights, msg
+                ))
+            };
+            let ty = seg.segment_type();
+            match name {
+                Segment::Cs => {
+                    let valid = [9, 11, 13, 15].contains(&ty)
+                        || (unrestricted && ty == 3);
+                    if !valid {
+                        fail("invalid type");
+                    }
+                }
+                Segment::Ss => {
+                    if ty != 3 && ty != 7 {
+                        fail("invalid type");
+                    }
+                }
+                _ => {
+                    // Must be accessed, and readable if it is a code segment
+                    if ty & 1 == 0 || (ty & 0b1000 != 0 && ty & 0b10 == 0) {
+                        fail("invalid type");
+                    }
+                }
+            }
+            if seg.access_rights & AR_S == 0 {
+                fail("S (descriptor type) must be set");
+            }
+            if seg.access_rights & AR_P == 0 {
+                fail("P (present) must be set");
+            }
+            if seg.access_rights & AR_RESERVED != 0 {
+                fail("reserved bits must be clear");
+            }
+            if seg.limit & 0xfff != 0xfff && seg.access_rights & AR_G != 0 {
+                fail("G must be clear when limit[11:0] is not all ones");
+            }
+            if seg.limit & 0xfff00000 != 0 && seg.access_rights & AR_G == 0 {
+                fail("G must be set when limit[31:20] is not zero");
+            }
+        }
+
+        if self.ia32e_guest()
+            && self.cs.access_rights & AR_L != 0
+            && self.cs.access_rights & AR_DB != 0
+        {
+            violations.push(
+                "26.3.1.2: CS.L and CS.D/B are both set in IA-32e mode".into(),
+            );
+        }
+        if !(unrestricted && self.cs.segment_type() == 3)
+            && self.ss.dpl() != self.cs.dpl()
+        {
+            violations.push(format!(
+                "26.3.1.2: SS.DPL={} does not match CS.DPL={}",
+                self.ss.dpl(),
+                self.cs.dpl()
+            ));
+        }
+
+        let tr_type = self.tr.segment_type();
+        let tr_valid = if self.ia32e_guest() {
+            tr_type == 11
+        } else {
+            tr_type == 3 || tr_type == 11
+        };
+        if !tr_valid {
+            violations.push(format!(
+                "26.3.1.2: TR has invalid type {} (ar=0x{:x})",
+                tr_type, self.tr.access_rights
+            ));
+        }
+        if !self.tr.usable()
+            || self.tr.access_rights & AR_S != 0
+            || self.tr.access_rights & AR_P == 0
+        {
+            violations.push(format!(
+                "26.3.1.2: TR must be usable, present and a system segment \
+                 (ar=0x{:x})",
+                self.tr.access_rights
+            ));
+        }
+        if self.ldtr.usable()
+            && (self.ldtr.segment_type() != 2
+                || self.ldtr.access_rights & AR_S != 0
+                || self.ldtr.access_rights & AR_P == 0)
+        {
+            violations.push(format!(
+                "26.3.1.2: usable LDTR has invalid access rights 0x{:x}",
+                self.ldtr.access_rights
+            ));
+        }
+    }
+}
+
+/// Check the guest state of the active VMCS before VM entry
+///
+/// Returns an error that describes every failed check.
+pub fn check_vm_entry(vmcs: &vmcs::ActiveVmcs) -> Result<()> {
+    let violations = EntryState::from_active_vmcs(vmcs)?.violations();
+    if violations.is_empty() {
+        return Ok(());
+    }
+    for violation in violations.iter() {
+        error!("VM entry check failed: {}", violation);
+    }
+    Err(Error::Vmcs(violations.join("; ")))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn long_mode_state() -> EntryState {
+        let data = SegmentState {
+            selector: 0x10,
+            limit: 0xffffffff,
+            access_rights: 0xc093,
+        };
+        EntryState {
+            cr0: 0x80000031,
+            cr4: 0x2020,
+            efer: EFER_LME | EFER_LMA,
+            rflags: 0x2,
+            entry_controls: (VmEntryCtrlFlags::IA32E_MODE
+                | VmEntryCtrlFlags::LOAD_GUEST_EFER)
+                .bits(),
+            exit_controls: VmExitCtrlFlags::IA32E_MODE.bits(),
+            cr0_fixed0: 0x80000021,
+            cr0_fixed1: 0xffffffff,
+            cr4_fixed0: 0x2000,
+            cr4_fixed1: 0x3767ff,
+            cs: SegmentState {
+                selector: 0x8,
+                limit: 0xffffffff,
+                access_rights: 0xa09b,
+            },
+            ss: data,
+            ds: data,
+            es: data,
+            fs: data,
+            gs: data,
+            tr: SegmentState {
+                selector: 0x18,
+                limit: 0x67,
+                access_rights: 0x8b,
+            },
+            ldtr: SegmentState {
+                selector: 0,
+                limit: 0,
+                access_rights: AR_UNUSABLE,
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_valid_state() {
+        assert_eq!(long_mode_state().violations(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_ia32e_mismatch() {
+        let mut state = long_mode_state();
+        state.entry_controls &= !VmEntryCtrlFlags::IA32E_MODE.bits();
+        let violations = state.violations();
+        assert!(violations.iter().any(|v| v.contains("EFER.LMA=1")));
+        // TR may also be a 32-bit TSS, but CR4.PCIDE is not set, so the
+        // mismatch is the only failure
+        assert_eq!(violations.len(), 1);
+    }
+
+    #[test]
+    fn test_segment_checks() {
+        let mut state = long_mode_state();
+        state.ss.access_rights = 0xc0f3; // DPL 3
+        state.cs.access_rights |= AR_DB;
+        state.ds.limit = 0xffff0;
+        let violations = state.violations();
+        assert_eq!(violations.len(), 3);
+        assert!(violations.iter().any(|v| v.contains("CS.L and CS.D/B")));
+        assert!(violations.iter().any(|v| v.contains("SS.DPL=3")));
+        assert!(violations.iter().any(|v| v.contains("Ds")));
+    }
+}
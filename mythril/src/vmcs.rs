@@ -1,10 +1,12 @@
 use crate::error::{self, Error, Result};
+use crate::hostmem::{HostPage, HostRegionKind};
 use crate::memory::Raw4kPage;
 use crate::vmx;
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 use bitflags::bitflags;
 use core::fmt;
-use x86::msr::rdmsr;
+use x86::msr::{self, rdmsr};
 
 #[allow(dead_code)]
 #[derive(Clone, Copy, Debug)]
@@ -213,7 +215,7 @@ bitflags! {
         const CR8_LOAD_EXITING =            0x00080000;
         const CR8_STORE_EXITING =           0x00100000;
         const TPR_SHADOW =                  0x00200000;
-        const VIRTUAL_NMI_PENDING =         0x00400000;
+        const NMI_WINDOW_EXITING =          0x00400000;
         const MOV_DR_EXITING =              0x00800000;
         const UNCOND_IO_EXITING =           0x01000000;
         const ACTIVATE_IO_BITMAP =          0x02000000;
@@ -242,6 +244,7 @@ bitflags! {
 
 bitflags! {
     pub struct VmEntryCtrlFlags: u64 {
+        const LOAD_DEBUG_CNTRLS =     0x00000004;
         const IA32E_MODE =            0x00000200;
         const SMM =                   0x00000400;
         const DEACT_DUAL_MONITOR =    0x00000800;
@@ -265,9 +268,11 @@ bitflags! {
         const APIC_REGISTER_VIRT =       0x00000100;
         const VIRTUAL_INTR_DELIVERY =    0x00000200;
         const PAUSE_LOOP_EXITING =       0x00000400;
+        const RDRAND_EXITING =           0x00000800;
         const ENABLE_INVPCID =           0x00001000;
         const ENABLE_VM_FUNCTIONS =      0x00002000;
         const ENABLE_VMCS_SHADOWING =    0x00004000;
+        const RDSEED_EXITING =           0x00010000;
         const ENABLE_PML =               0x00020000;
         const ENABLE_VIRT_EXCEPTIONS =   0x00040000;
         const XSAVES =                   0x00100000;
@@ -285,6 +290,286 @@ bitflags! {
     }
 }
 
+/// The processor feature a VMCS field depends on
+///
+/// Fields that are only present when a VM-execution, VM-exit or VM-entry
+/// control may be set to 1 are listed with that control (see Appendix B
+/// of the SDM).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FieldRequirement {
+    Always,
+    PinBased(PinBasedCtrlFlags),
+    CpuBased(CpuBasedCtrlFlags),
+    Secondary(SecondaryExecFlags),
+    Exit(VmExitCtrlFlags),
+    Entry(VmEntryCtrlFlags),
+}
+
+impl VmcsField {
+    /// The index of the field, as reported by IA32_VMX_VMCS_ENUM
+    pub fn index(self) -> u64 {
+        (self as u64 >> 1) & 0x1ff
+    }
+
+    /// Returns true if this is a (read-only) VM-exit information field
+    pub fn is_read_only(self) -> bool {
+        (self as u64 >> 10) & 0b11 == 1
+    }
+
+    /// The processor feature this field depends on
+    pub fn requirement(self) -> FieldRequirement {
+        use VmcsField::*;
+        match self {
+            PostedIntrNv | PostedIntrDescAddr | PostedIntrDescAddrHigh => {
+                FieldRequirement::PinBased(PinBasedCtrlFlags::POSTED_INTERRUPT)
+            }
+            VmxPreemptionTimerValue => {
+                FieldRequirement::PinBased(PinBasedCtrlFlags::PREEMPT_TIMER)
+            }
+            VirtualApicPageAddr | VirtualApicPageAddrHigh | TprThreshold => {
+                FieldRequirement::CpuBased(CpuBasedCtrlFlags::TPR_SHADOW)
+            }
+            SecondaryVmExecControl => FieldRequirement::CpuBased(
+                CpuBasedCtrlFlags::ACTIVATE_SECONDARY_CONTROLS,
+            ),
+            VirtualProcessorId => {
+                FieldRequirement::Secondary(SecondaryExecFlags::ENABLE_VPID)
+            }
+            ApicAccessAddr | ApicAccessAddrHigh => FieldRequirement::Secondary(
+                SecondaryExecFlags::VIRTUALIZE_APIC_ACCESSES,
+            ),
+            EptPointer
+            | EptPointerHigh
+            | GuestPhysicalAddress
+            | GuestPhysicalAddressHigh
+            | GuestPdptr0
+            | GuestPdptr0High
+            | GuestPdptr1
+            | GuestPdptr1High
+            | GuestPdptr2
+            | GuestPdptr2High
+            | GuestPdptr3
+            | GuestPdptr3High => {
+                FieldRequirement::Secondary(SecondaryExecFlags::ENABLE_EPT)
+            }
+            GuestIntrStatus | EoiExitBitmap0 | EoiExitBitmap0High
+            | EoiExitBitmap1 | EoiExitBitmap1High | EoiExitBitmap2
+            | EoiExitBitmap2High | EoiExitBitmap3 | EoiExitBitmap3High => {
+                FieldRequirement::Secondary(
+                    SecondaryExecFlags::VIRTUAL_INTR_DELIVERY,
+                )
+            }
+            PleGap | PleWindow => FieldRequirement::Secondary(
+                SecondaryExecFlags::PAUSE_LOOP_EXITING,
+            ),
+            VmreadBitmap | VmreadBitmapHigh | VmwriteBitmap
+            | VmwriteBitmapHigh => FieldRequirement::Secondary(
+                SecondaryExecFlags::ENABLE_VMCS_SHADOWING,
+            ),
+            GuestPmlIndex | PmlAddress | PmlAddressHigh => {
+                FieldRequirement::Secondary(SecondaryExecFlags::ENABLE_PML)
+            }
+            XssExitBitmap | XssExitBitmapHigh => {
+                FieldRequirement::Secondary(SecondaryExecFlags::XSAVES)
+            }
+            TscMultiplier | TscMultiplierHigh => {
+                FieldRequirement::Secondary(SecondaryExecFlags::TSC_SCALING)
+            }
+            // The guest PAT field also exists if 'save IA32_PAT' may be set,
+            // but processors support both controls or neither
+            GuestIa32Pat | GuestIa32PatHigh => {
+                FieldRequirement::Entry(VmEntryCtrlFlags::LOAD_GUEST_PAT)
+            }
+            GuestIa32Efer | GuestIa32EferHigh => {
+                FieldRequirement::Entry(VmEntryCtrlFlags::LOAD_GUEST_EFER)
+            }
+            GuestIa32PerfGlobalCtrl | GuestIa32PerfGlobalCtrlHigh => {
+                FieldRequirement::Entry(VmEntryCtrlFlags::LOAD_PERF_GLOBAL_CTRL)
+            }
+            GuestBndcfgs | GuestBndcfgsHigh => {
+                FieldRequirement::Entry(VmEntryCtrlFlags::LOAD_BNDCFGS)
+            }
+            HostIa32Pat | HostIa32PatHigh => {
+                FieldRequirement::Exit(VmExitCtrlFlags::LOAD_HOST_PAT)
+            }
+            HostIa32Efer | HostIa32EferHigh => {
+                FieldRequirement::Exit(VmExitCtrlFlags::LOAD_HOST_EFER)
+            }
+            HostIa32PerfGlobalCtrl | HostIa32PerfGlobalCtrlHigh => {
+                FieldRequirement::Exit(VmExitCtrlFlags::LOAD_PERF_GLOBAL_CTRL)
+            }
+            _ => FieldRequirement::Always,
+        }
+    }
+
+    /// The value of this field when its feature is not supported, if the
+    /// field can be emulated. Reading an unsupported field returns this
+    /// value, and writing this value to it is ignored.
+    pub fn emulated_value(self) -> Option<u64> {
+        match self {
+            // No secondary controls are enabled
+            VmcsField::SecondaryVmExecControl => Some(0),
+            VmcsField::TprThreshold
+            | VmcsField::PleGap
+            | VmcsField::PleWindow
+            | VmcsField::XssExitBitmap
+            | VmcsField::XssExitBitmapHigh
+            | VmcsField::TscMultiplierHigh => Some(0),
+            // A multiplier of 1.0 (in 16.48 fixed point)
+            VmcsField::TscMultiplier => Some(1 << 48),
+            _ => None,
+        }
+    }
+}
+
+/// The VMCS fields supported by the current processor
+///
+/// This is derived from the VMX capability MSRs: a field exists if the
+/// control it depends on may be set to 1, and its index is no larger than
+/// the highest index reported by IA32_VMX_VMCS_ENUM.
+#[derive(Clone, Copy, Debug)]
+pub struct VmcsCapabilities {
+    pin: PinBasedCtrlFlags,
+    cpu: CpuBasedCtrlFlags,
+    secondary: SecondaryExecFlags,
+    exit: VmExitCtrlFlags,
+    entry: VmEntryCtrlFlags,
+    max_index: u64,
+    write_any_field: bool,
+}
+
+impl VmcsCapabilities {
+    /// Build the capabilities from the values of the IA32_VMX_PINBASED_CTLS,
+    /// IA32_VMX_PROCBASED_CTLS, IA32_VMX_PROCBASED_CTLS2, IA32_VMX_EXIT_CTLS,
+    /// IA32_VMX_ENTRY_CTLS, IA32_VMX_VMCS_ENUM and IA32_VMX_MISC MSRs
+    pub fn from_msrs(
+        pin: u64,
+        cpu: u64,
+        secondary: u64,
+        exit: u64,
+        entry: u64,
+        vmcs_enum: u64,
+        misc: u64,
+    ) -> Self {
+        // The allowed 1-settings are in the high word of each MSR
+        let cpu = CpuBasedCtrlFlags::from_bits_truncate(cpu >> 32);
+        let secondary =
+            if cpu.contains(CpuBasedCtrlFlags::ACTIVATE_SECONDARY_CONTROLS) {
+                SecondaryExecFlags::from_bits_truncate(secondary >> 32)
+            } else {
+                SecondaryExecFlags::empty()
+            };
+        Self {
+            pin: PinBasedCtrlFlags::from_bits_truncate(pin >> 32),
+            cpu,
+            secondary,
+            exit: VmExitCtrlFlags::from_bits_truncate(exit >> 32),
+            entry: VmEntryCtrlFlags::from_bits_truncate(entry >> 32),
+            max_index: (vmcs_enum >> 1) & 0x1ff,
+            write_any_field: misc & (1 << 29) != 0,
+        }
+    }
+
+    /// Read the capabilities of the current processor
+    pub fn read() -> Self {
+        unsafe {
+            let cpu = rdmsr(msr::IA32_VMX_PROCBASED_CTLS);
+            // IA32_VMX_PROCBASED_CTLS2 only exists if the secondary
+            // controls may be activated
+            let secondary = if CpuBasedCtrlFlags::from_bits_truncate(cpu >> 32)
+                .contains(CpuBasedCtrlFlags::ACTIVATE_SECONDARY_CONTROLS)
+            {
+                rdmsr(msr::IA32_VMX_PROCBASED_CTLS2)
+            } else {
+                0
+            };
+            Self::from_msrs(
+                rdmsr(msr::IA32_VMX_PINBASED_CTLS),
+                cpu,
+                secondary,
+                rdmsr(msr::IA32_VMX_EXIT_CTLS),
+                rdmsr(msr::IA32_VMX_ENTRY_CTLS),
+                rdmsr(msr::IA32_VMX_VMCS_ENUM),
+                rdmsr(msr::IA32_VMX_MISC),
+            )
+        }
+    }
+
+    /// Returns true if `field` exists on this processor
+    pub fn supports(&self, field: VmcsField) -> bool {
+        if field.index() > self.max_index {
+            return false;
+        }
+        match field.requirement() {
+            FieldRequirement::Always => true,
+            FieldRequirement::PinBased(flags) => self.pin.contains(flags),
+            FieldRequirement::CpuBased(flags) => self.cpu.contains(flags),
+            FieldRequirement::Secondary(flags) => {
+                self.secondary.contains(flags)
+            }
+            FieldRequirement::Exit(flags) => self.exit.contains(flags),
+            FieldRequirement::Entry(flags) => self.entry.contains(flags),
+        }
+    }
+
+    /// Returns true if the guest may be given a shadow VMCS
+    pub fn supports_shadowing(&self) -> bool {
+        self.secondary
+            .contains(SecondaryExecFlags::ENABLE_VMCS_SHADOWING)
+    }
+
+    fn unsupported(&self, field: VmcsField) -> Error {
+        if field.index() > self.max_index {
+            Error::Vmcs(format!(
+                "VMCS field {:?} is not supported by this processor (index {} > maximum {})",
+                field,
+                field.index(),
+                self.max_index
+            ))
+        } else {
+            Error::Vmcs(format!(
+                "VMCS field {:?} is not supported by this processor (requires {:?})",
+                field,
+                field.requirement()
+            ))
+        }
+    }
+
+    /// Read `field`, or its emulated value if it is not supported
+    fn read_field(&self, field: VmcsField) -> Result<u64> {
+        if self.supports(field) {
+            return vmcs_read(field);
+        }
+        field
+            .emulated_value()
+            .ok_or_else(|| self.unsupported(field))
+    }
+
+    /// Write `field`. Writing the emulated value of an unsupported field
+    /// has no effect.
+    fn write_field(&self, field: VmcsField, value: u64) -> Result<()> {
+        if self.supports(field) {
+            return vmcs_write(field, value);
+        }
+        match field.emulated_value() {
+            Some(emulated) if emulated == value => Ok(()),
+            _ => Err(self.unsupported(field)),
+        }
+    }
+
+    fn write_with_fixed(
+        &self,
+        field: VmcsField,
+        value: u64,
+        msr: u32,
+    ) -> Result<u64> {
+        if self.supports(field) {
+            return vmcs_write_with_fixed(field, value, msr);
+        }
+        self.write_field(field, value).map(|_| value)
+    }
+}
+
 fn vmcs_write_with_fixed(
     field: VmcsField,
     value: u64,
@@ -331,26 +616,39 @@ fn vmcs_write(field: VmcsField, value: u64) -> Result<()> {
 }
 
 fn vmcs_read(field: VmcsField) -> Result<u64> {
-    let value = unsafe {
+    let (value, rflags) = unsafe {
         let value: u64;
-        llvm_asm!("vmreadq %rdx, %rax"
-                  : "={rax}"(value)
+        let rflags: u64;
+        llvm_asm!("vmreadq %rdx, %rax; pushfq; popq $1"
+                  : "={rax}"(value), "=r"(rflags)
                   : "{rdx}"(field as u64)
                   : "rflags"
                   : "volatile");
-        value
+        (value, rflags)
     };
 
+    error::check_vm_insruction(
+        rflags,
+        format!("Failed to read field {:?}", field),
+    )?;
     Ok(value)
 }
 
 fn vmcs_activate(vmcs: &mut Vmcs, _vmx: &vmx::Vmx) -> Result<()> {
-    let revision_id = vmx::Vmx::revision();
-    let vmcs_region_addr = &mut *vmcs.frame as *mut Raw4kPage;
-    let region_revision = vmcs_region_addr as *mut u32;
+    let mut revision_id = vmx::Vmx::revision();
+    if vmcs.shadow {
+        revision_id |= SHADOW_VMCS_INDICATOR;
+    }
+    let region_revision = &mut *vmcs.frame as *mut Raw4kPage as *mut u32;
     unsafe {
         *region_revision = revision_id;
     }
+    vmcs_load(vmcs)
+}
+
+// Make `vmcs` the current VMCS, without reinitializing its region
+fn vmcs_load(vmcs: &mut Vmcs) -> Result<()> {
+    let vmcs_region_addr = &mut *vmcs.frame as *mut Raw4kPage;
     let rflags = unsafe {
         let rflags: u64;
         llvm_asm!("vmptrld $1; pushfq; popq $0"
@@ -376,17 +674,35 @@ fn vmcs_clear(vmcs_page: &mut Raw4kPage) -> Result<()> {
     error::check_vm_insruction(rflags, "Failed to clear VMCS".into())
 }
 
+// The bit set in the revision identifier of a shadow VMCS region
+const SHADOW_VMCS_INDICATOR: u32 = 1 << 31;
+
 pub struct Vmcs {
-    frame: Box<Raw4kPage>,
+    frame: HostPage,
+    shadow: bool,
 }
 
 impl Vmcs {
     pub fn new() -> Result<Self> {
         Ok(Vmcs {
-            frame: Box::new(Raw4kPage::default()),
+            frame: HostPage::new(HostRegionKind::Vmcs)?,
+            shadow: false,
         })
     }
 
+    /// Create a VMCS that may only be used as a shadow VMCS
+    pub fn new_shadow() -> Result<Self> {
+        Ok(Vmcs {
+            frame: HostPage::new(HostRegionKind::Vmcs)?,
+            shadow: true,
+        })
+    }
+
+    /// The physical address of the VMCS region
+    pub fn address(&self) -> u64 {
+        self.frame.address()
+    }
+
     pub fn activate(self, vmx: vmx::Vmx) -> Result<ActiveVmcs> {
         ActiveVmcs::new(self, vmx)
     }
@@ -412,11 +728,11 @@ impl ActiveVmcs {
     }
 
     pub fn read_field(&self, field: VmcsField) -> Result<u64> {
-        vmcs_read(field)
+        self.vmx.capabilities().read_field(field)
     }
 
     pub fn write_field(&mut self, field: VmcsField, value: u64) -> Result<()> {
-        vmcs_write(field, value)
+        self.vmx.capabilities().write_field(field, value)
     }
 
     pub fn write_with_fixed(
@@ -425,7 +741,49 @@ impl ActiveVmcs {
         value: u64,
         msr: u32,
     ) -> Result<u64> {
-        vmcs_write_with_fixed(field, value, msr)
+        self.vmx.capabilities().write_with_fixed(field, value, msr)
+    }
+
+    /// Link `shadow` to this VMCS and enable VMCS shadowing, so the guest
+    /// may VMREAD the shadowed fields without an exit
+    pub fn enable_shadowing(&mut self, shadow: &mut VmcsShadow) -> Result<()> {
+        // Initialize the shadow region, then make this VMCS current again
+        vmcs_activate(&mut shadow.vmcs, &self.vmx)?;
+        vmcs_clear(&mut shadow.vmcs.frame)?;
+        vmcs_load(&mut self.vmcs)?;
+
+        self.write_field(VmcsField::VmcsLinkPointer, shadow.vmcs.address())?;
+        self.write_field(
+            VmcsField::VmreadBitmap,
+            shadow.bitmaps.read_address(),
+        )?;
+        self.write_field(
+            VmcsField::VmwriteBitmap,
+            shadow.bitmaps.write_address(),
+        )?;
+        let secondary = self.read_field(VmcsField::SecondaryVmExecControl)?;
+        self.write_with_fixed(
+            VmcsField::SecondaryVmExecControl,
+            secondary | SecondaryExecFlags::ENABLE_VMCS_SHADOWING.bits(),
+            msr::IA32_VMX_PROCBASED_CTLS2,
+        )?;
+        Ok(())
+    }
+
+    /// Copy the current values of the shadowed fields to `shadow`
+    pub fn sync_shadow(&mut self, shadow: &mut VmcsShadow) -> Result<()> {
+        let mut values = Vec::with_capacity(shadow.fields.len());
+        for field in shadow.fields.iter() {
+            values.push((*field, self.read_field(*field)?));
+        }
+
+        vmcs_load(&mut shadow.vmcs)?;
+        let res = values
+            .iter()
+            .try_for_each(|(field, value)| vmcs_write(*field, *value));
+        vmcs_clear(&mut shadow.vmcs.frame)?;
+        vmcs_load(&mut self.vmcs)?;
+        res
     }
 
     pub fn deactivate(mut self) -> Result<(Vmcs, vmx::Vmx)> {
@@ -434,6 +792,222 @@ impl ActiveVmcs {
     }
 }
 
+/// The VMREAD and VMWRITE bitmaps, which select the fields the guest may
+/// access in its shadow VMCS without an exit (see Section 24.6.15 of the
+/// SDM)
+pub struct VmcsAccessBitmaps {
+    read: Box<Raw4kPage>,
+    write: Box<Raw4kPage>,
+}
+
+impl VmcsAccessBitmaps {
+    /// Create bitmaps that cause every access to exit
+    pub fn new() -> Self {
+        Self {
+            read: Box::new(Raw4kPage([0xff; 4096])),
+            write: Box::new(Raw4kPage([0xff; 4096])),
+        }
+    }
+
+    // The bitmaps are indexed by bits 14:0 of the field encoding
+    fn position(field: VmcsField) -> (usize, u8) {
+        let bit = field as usize & 0x7fff;
+        (bit / 8, 1 << (bit % 8))
+    }
+
+    /// Allow the guest to VMREAD `field`
+    pub fn allow_read(&mut self, field: VmcsField) {
+        let (byte, mask) = Self::position(field);
+        self.read.0[byte] &= !mask;
+    }
+
+    /// Allow the guest to VMWRITE `field`
+    pub fn allow_write(&mut self, field: VmcsField) {
+        let (byte, mask) = Self::position(field);
+        self.write.0[byte] &= !mask;
+    }
+
+    pub fn read_allowed(&self, field: VmcsField) -> bool {
+        let (byte, mask) = Self::position(field);
+        self.read.0[byte] & mask == 0
+    }
+
+    pub fn write_allowed(&self, field: VmcsField) -> bool {
+        let (byte, mask) = Self::position(field);
+        self.write.0[byte] & mask == 0
+    }
+
+    /// The physical address of the VMREAD bitmap
+    pub fn read_address(&self) -> u64 {
+        &*self.read as *const Raw4kPage as u64
+    }
+
+    /// The physical address of the VMWRITE bitmap
+    pub fn write_address(&self) -> u64 {
+        &*self.write as *const Raw4kPage as u64
+    }
+}
+
+/// A shadow VMCS holding copies of selected fields of the guest's VMCS
+///
+/// The guest may VMREAD the shadowed fields without an exit. The copies are
+/// only updated by `ActiveVmcs::sync_shadow`, so they reflect the state of
+/// the guest at its last exit.
+pub struct VmcsShadow {
+    vmcs: Vmcs,
+    bitmaps: VmcsAccessBitmaps,
+    fields: Vec<VmcsField>,
+}
+
+impl VmcsShadow {
+    /// Create a shadow of `fields`, which must all be supported (and, if
+    /// they are read-only, writable) on this processor
+    pub fn new(
+        fields: &[VmcsField],
+        capabilities: &VmcsCapabilities,
+    ) -> Result<Self> {
+        if !capabilities.supports_shadowing() {
+            return Err(Error::NotSupported);
+        }
+        let mut bitmaps = VmcsAccessBitmaps::new();
+        for field in fields {
+            if !capabilities.supports(*field) {
+                return Err(capabilities.unsupported(*field));
+            }
+            // Filling in the shadow of a read-only field requires VMWRITE to
+            // any field
+            if field.is_read_only() && !capabilities.write_any_field {
+                return Err(Error::Vmcs(format!(
+                    "VMCS field {:?} is read-only and cannot be shadowed by this processor",
+                    field
+                )));
+            }
+            bitmaps.allow_read(*field);
+        }
+        Ok(Self {
+            vmcs: Vmcs::new_shadow()?,
+            bitmaps,
+            fields: fields.to_vec(),
+        })
+    }
+
+    /// The fields copied to the shadow VMCS
+    pub fn fields(&self) -> &[VmcsField] {
+        &self.fields
+    }
+
+    pub fn bitmaps(&self) -> &VmcsAccessBitmaps {
+        &self.bitmaps
+    }
+}
+
+/// The interrupt-window and NMI-window exiting controls of the active VMCS
+///
+/// The exits the guest should take once it can accept an interrupt (or an
+/// NMI) are requested while an exit is handled, and the primary
+/// processor-based controls are then written once by `commit` (and not at
+/// all if they did not change).
+///
+/// NMI-window exiting requires virtual NMIs. Without them, a requested NMI
+/// window is replaced by an interrupt window, which opens no earlier.
+#[derive(Clone, Copy, Debug)]
+pub struct InterruptWindowControl {
+    controls: u64,
+    virtual_nmis: bool,
+    interrupt: bool,
+    nmi: bool,
+}
+
+impl InterruptWindowControl {
+    /// Start from the current controls of `vmcs`, with the windows that are
+    /// open in them still requested
+    pub fn read(vmcs: &ActiveVmcs) -> Result<Self> {
+        let pin = vmcs.read_field(VmcsField::PinBasedVmExecControl)?;
+        let controls = vmcs.read_field(VmcsField::CpuBasedVmExecControl)?;
+        Ok(Self::new(
+            controls,
+            pin & PinBasedCtrlFlags::VIRTUAL_NMIS.bits() != 0,
+        ))
+    }
+
+    fn new(controls: u64, virtual_nmis: bool) -> Self {
+        Self {
+            controls,
+            virtual_nmis,
+            interrupt: controls
+                & CpuBasedCtrlFlags::INTERRUPT_WINDOW_EXITING.bits()
+                != 0,
+            nmi: controls & CpuBasedCtrlFlags::NMI_WINDOW_EXITING.bits() != 0,
+        }
+    }
+
+    /// Request (or cancel) an exit when the guest can accept interrupts
+    pub fn set_interrupt_window(&mut self, open: bool) {
+        self.interrupt = open;
+    }
+
+    /// Request (or cancel) an exit when the guest can accept an NMI
+    pub fn set_nmi_window(&mut self, open: bool) {
+        self.nmi = open;
+    }
+
+    /// The processor-based controls with the requested windows
+    pub fn controls(&self) -> u64 {
+        let mut controls = self.controls
+            & !(CpuBasedCtrlFlags::INTERRUPT_WINDOW_EXITING
+                | CpuBasedCtrlFlags::NMI_WINDOW_EXITING)
+                .bits();
+        if self.interrupt || (self.nmi && !self.virtual_nmis) {
+            controls |= CpuBasedCtrlFlags::INTERRUPT_WINDOW_EXITING.bits();
+        }
+        if self.nmi && self.virtual_nmis {
+            controls |= CpuBasedCtrlFlags::NMI_WINDOW_EXITING.bits();
+        }
+        controls
+    }
+
+    /// Write the requested windows to `vmcs`, if they changed
+    pub fn commit(self, vmcs: &mut ActiveVmcs) -> Result<()> {
+        let controls = self.controls();
+        if controls == self.controls {
+            return Ok(());
+        }
+        vmcs.write_field(VmcsField::CpuBasedVmExecControl, controls)
+    }
+}
+
+/// The decoded access rights of a segment, in the VMCS format
+pub struct SegmentAccessRights(pub u64);
+
+impl fmt::Display for SegmentAccessRights {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ar = self.0;
+        if ar & (1 << 16) != 0 {
+            return write!(f, "0x{:x}(unusable)", ar);
+        }
+        write!(
+            f,
+            "0x{:x}(type={:x} {} dpl={}",
+            ar,
+            ar & 0xf,
+            if ar & (1 << 4) != 0 {
+                "code/data"
+            } else {
+                "system"
+            },
+            (ar >> 5) & 0b11
+        )?;
+        for (bit, name) in
+            &[(7, "P"), (12, "AVL"), (13, "L"), (14, "D/B"), (15, "G")]
+        {
+            if ar & (1 << bit) != 0 {
+                write!(f, " {}", name)?;
+            }
+        }
+        write!(f, ")")
+    }
+}
+
 impl fmt::Display for ActiveVmcs {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let read_field =
@@ -441,23 +1015,90 @@ impl fmt::Display for ActiveVmcs {
                 self.read_field(field).map_err(|_| fmt::Error)
             };
 
+        let segments = [
+            (
+                "CS",
+                VmcsField::GuestCsSelector,
+                VmcsField::GuestCsBase,
+                VmcsField::GuestCsLimit,
+                VmcsField::GuestCsArBytes,
+            ),
+            (
+                "DS",
+                VmcsField::GuestDsSelector,
+                VmcsField::GuestDsBase,
+                VmcsField::GuestDsLimit,
+                VmcsField::GuestDsArBytes,
+            ),
+            (
+                "SS",
+                VmcsField::GuestSsSelector,
+                VmcsField::GuestSsBase,
+                VmcsField::GuestSsLimit,
+                VmcsField::GuestSsArBytes,
+            ),
+            (
+                "ES",
+                VmcsField::GuestEsSelector,
+                VmcsField::GuestEsBase,
+                VmcsField::GuestEsLimit,
+                VmcsField::GuestEsArBytes,
+            ),
+            (
+                "FS",
+                VmcsField::GuestFsSelector,
+                VmcsField::GuestFsBase,
+                VmcsField::GuestFsLimit,
+                VmcsField::GuestFsArBytes,
+            ),
+            (
+                "GS",
+                VmcsField::GuestGsSelector,
+                VmcsField::GuestGsBase,
+                VmcsField::GuestGsLimit,
+                VmcsField::GuestGsArBytes,
+            ),
+            (
+                "LDTR",
+                VmcsField::GuestLdtrSelector,
+                VmcsField::GuestLdtrBase,
+                VmcsField::GuestLdtrLimit,
+                VmcsField::GuestLdtrArBytes,
+            ),
+            (
+                "TR",
+                VmcsField::GuestTrSelector,
+                VmcsField::GuestTrBase,
+                VmcsField::GuestTrLimit,
+                VmcsField::GuestTrArBytes,
+            ),
+        ];
+
         write!(f, "VMCS:\n")?;
         write!(f, " Guest State:\n")?;
         write!(
             f,
-            "  CR0=0x{:x}(shadow=0x{:x}) ",
+            "  CR0=0x{:x}(shadow=0x{:x} mask=0x{:x}) ",
             read_field(VmcsField::GuestCr0)?,
-            read_field(VmcsField::Cr0ReadShadow)?
+            read_field(VmcsField::Cr0ReadShadow)?,
+            read_field(VmcsField::Cr0GuestHostMask)?
         )?;
         write!(f, "CR3=0x{:x} ", read_field(VmcsField::GuestCr3)?)?;
         write!(
             f,
-            "CR4=0x{:x}(shadow=0x{:x})\n",
+            "CR4=0x{:x}(shadow=0x{:x} mask=0x{:x})\n",
             read_field(VmcsField::GuestCr4)?,
-            read_field(VmcsField::Cr4ReadShadow)?
+            read_field(VmcsField::Cr4ReadShadow)?,
+            read_field(VmcsField::Cr4GuestHostMask)?
         )?;
 
-        write!(f, "  EFER=0x{:x}\n", read_field(VmcsField::GuestIa32Efer)?)?;
+        write!(f, "  EFER=0x{:x} ", read_field(VmcsField::GuestIa32Efer)?)?;
+        write!(f, "PAT=0x{:x} ", read_field(VmcsField::GuestIa32Pat)?)?;
+        write!(
+            f,
+            "DEBUGCTL=0x{:x}\n",
+            read_field(VmcsField::GuestIa32Debugctl)?
+        )?;
 
         write!(f, "  RSP=0x{:x} ", read_field(VmcsField::GuestRsp)?)?;
         write!(f, "RIP=0x{:x}\n", read_field(VmcsField::GuestRip)?)?;
@@ -465,83 +1106,154 @@ impl fmt::Display for ActiveVmcs {
         write!(f, "  RFLAGS=0x{:x} ", read_field(VmcsField::GuestRflags)?)?;
         write!(f, "DR7=0x{:x}\n", read_field(VmcsField::GuestDr7)?)?;
 
-        // Guest selectors
-        write!(f, "  CS: ")?;
+        for (name, selector, base, limit, ar) in segments.iter() {
+            write!(
+                f,
+                "  {}: selector=0x{:x} base=0x{:x} limit=0x{:x} ar={}\n",
+                name,
+                read_field(*selector)?,
+                read_field(*base)?,
+                read_field(*limit)?,
+                SegmentAccessRights(read_field(*ar)?)
+            )?;
+        }
         write!(
             f,
-            "selector=0x{:x} base=0x{:x} limit=0x{:x} ar=0x{:x}\n",
-            read_field(VmcsField::GuestCsSelector)?,
-            read_field(VmcsField::GuestCsBase)?,
-            read_field(VmcsField::GuestCsLimit)?,
-            read_field(VmcsField::GuestCsArBytes)?
+            "  GDTR: base=0x{:x} limit=0x{:x}\n",
+            read_field(VmcsField::GuestGdtrBase)?,
+            read_field(VmcsField::GuestGdtrLimit)?
         )?;
-        write!(f, "  DS: ")?;
         write!(
             f,
-            "selector=0x{:x} base=0x{:x} limit=0x{:x} ar=0x{:x}\n",
-            read_field(VmcsField::GuestDsSelector)?,
-            read_field(VmcsField::GuestDsBase)?,
-            read_field(VmcsField::GuestDsLimit)?,
-            read_field(VmcsField::GuestDsArBytes)?
+            "  IDTR: base=0x{:x} limit=0x{:x}\n",
+            read_field(VmcsField::GuestIdtrBase)?,
+            read_field(VmcsField::GuestIdtrLimit)?
         )?;
-        write!(f, "  SS: ")?;
         write!(
             f,
-            "selector=0x{:x} base=0x{:x} limit=0x{:x} ar=0x{:x}\n",
-            read_field(VmcsField::GuestSsSelector)?,
-            read_field(VmcsField::GuestSsBase)?,
-            read_field(VmcsField::GuestSsLimit)?,
-            read_field(VmcsField::GuestSsArBytes)?
+            "  SYSENTER: cs=0x{:x} esp=0x{:x} eip=0x{:x}\n",
+            read_field(VmcsField::GuestSysenterCs)?,
+            read_field(VmcsField::GuestSysenterEsp)?,
+            read_field(VmcsField::GuestSysenterEip)?
         )?;
-        write!(f, "  ES: ")?;
         write!(
             f,
-            "selector=0x{:x} base=0x{:x} limit=0x{:x} ar=0x{:x}\n",
-            read_field(VmcsField::GuestEsSelector)?,
-            read_field(VmcsField::GuestEsBase)?,
-            read_field(VmcsField::GuestEsLimit)?,
-            read_field(VmcsField::GuestEsArBytes)?
+            "  Activity={} Interruptibility={:?} PendingDbg=0x{:x}\n",
+            read_field(VmcsField::GuestActivityState)?,
+            InterruptibilityState::from_bits_truncate(read_field(
+                VmcsField::GuestInterruptibilityInfo
+            )?),
+            read_field(VmcsField::GuestPendingDbgExceptions)?
         )?;
-        write!(f, "  FS: ")?;
         write!(
             f,
-            "selector=0x{:x} base=0x{:x} limit=0x{:x} ar=0x{:x}\n",
-            read_field(VmcsField::GuestFsSelector)?,
-            read_field(VmcsField::GuestFsBase)?,
-            read_field(VmcsField::GuestFsLimit)?,
-            read_field(VmcsField::GuestFsArBytes)?
+            "  VmcsLink=0x{:x}\n",
+            read_field(VmcsField::VmcsLinkPointer)?
         )?;
-        write!(f, "  GS: ")?;
+
+        write!(f, " Host State:\n")?;
         write!(
             f,
-            "selector=0x{:x} base=0x{:x} limit=0x{:x} ar=0x{:x}\n",
-            read_field(VmcsField::GuestGsSelector)?,
-            read_field(VmcsField::GuestGsBase)?,
-            read_field(VmcsField::GuestGsLimit)?,
-            read_field(VmcsField::GuestGsArBytes)?
+            "  CR0=0x{:x} CR3=0x{:x} CR4=0x{:x}\n",
+            read_field(VmcsField::HostCr0)?,
+            read_field(VmcsField::HostCr3)?,
+            read_field(VmcsField::HostCr4)?
         )?;
-        write!(f, "  LDTR: ")?;
         write!(
             f,
-            "selector=0x{:x} base=0x{:x} limit=0x{:x} ar=0x{:x}\n",
-            read_field(VmcsField::GuestLdtrSelector)?,
-            read_field(VmcsField::GuestLdtrBase)?,
-            read_field(VmcsField::GuestLdtrLimit)?,
-            read_field(VmcsField::GuestLdtrArBytes)?
+            "  EFER=0x{:x} PAT=0x{:x}\n",
+            read_field(VmcsField::HostIa32Efer)?,
+            read_field(VmcsField::HostIa32Pat)?
         )?;
-        write!(f, "  GDTR: ")?;
         write!(
             f,
-            "base=0x{:x} limit=0x{:x}\n",
-            read_field(VmcsField::GuestGdtrBase)?,
-            read_field(VmcsField::GuestGdtrLimit)?
+            "  RSP=0x{:x} RIP=0x{:x}\n",
+            read_field(VmcsField::HostRsp)?,
+            read_field(VmcsField::HostRip)?
         )?;
-        write!(f, "  IDTR: ")?;
         write!(
             f,
-            "base=0x{:x} limit=0x{:x}\n",
-            read_field(VmcsField::GuestIdtrBase)?,
-            read_field(VmcsField::GuestIdtrLimit)?
+            "  CS=0x{:x} SS=0x{:x} DS=0x{:x} ES=0x{:x} FS=0x{:x} GS=0x{:x} TR=0x{:x}\n",
+            read_field(VmcsField::HostCsSelector)?,
+            read_field(VmcsField::HostSsSelector)?,
+            read_field(VmcsField::HostDsSelector)?,
+            read_field(VmcsField::HostEsSelector)?,
+            read_field(VmcsField::HostFsSelector)?,
+            read_field(VmcsField::HostGsSelector)?,
+            read_field(VmcsField::HostTrSelector)?
+        )?;
+        write!(
+            f,
+            "  FSBase=0x{:x} GSBase=0x{:x} TRBase=0x{:x}\n",
+            read_field(VmcsField::HostFsBase)?,
+            read_field(VmcsField::HostGsBase)?,
+            read_field(VmcsField::HostTrBase)?
+        )?;
+        write!(
+            f,
+            "  GDTRBase=0x{:x} IDTRBase=0x{:x}\n",
+            read_field(VmcsField::HostGdtrBase)?,
+            read_field(VmcsField::HostIdtrBase)?
+        )?;
+
+        let pin = read_field(VmcsField::PinBasedVmExecControl)?;
+        let cpu = read_field(VmcsField::CpuBasedVmExecControl)?;
+        let secondary = read_field(VmcsField::SecondaryVmExecControl)?;
+        let exit = read_field(VmcsField::VmExitControls)?;
+        let entry = read_field(VmcsField::VmEntryControls)?;
+
+        write!(f, " Control State:\n")?;
+        write!(
+            f,
+            "  PinBased=0x{:x} {:?}\n",
+            pin,
+            PinBasedCtrlFlags::from_bits_truncate(pin)
+        )?;
+        write!(
+            f,
+            "  CpuBased=0x{:x} {:?}\n",
+            cpu,
+            CpuBasedCtrlFlags::from_bits_truncate(cpu)
+        )?;
+        write!(
+            f,
+            "  Secondary=0x{:x} {:?}\n",
+            secondary,
+            SecondaryExecFlags::from_bits_truncate(secondary)
+        )?;
+        write!(
+            f,
+            "  Exit=0x{:x} {:?}\n",
+            exit,
+            VmExitCtrlFlags::from_bits_truncate(exit)
+        )?;
+        write!(
+            f,
+            "  Entry=0x{:x} {:?}\n",
+            entry,
+            VmEntryCtrlFlags::from_bits_truncate(entry)
+        )?;
+        write!(
+            f,
+            "  ExceptionBitmap=0x{:x} EntryIntrInfo=0x{:x} EntryErrorCode=0x{:x}\n",
+            read_field(VmcsField::ExceptionBitmap)?,
+            read_field(VmcsField::VmEntryIntrInfoField)?,
+            read_field(VmcsField::VmEntryExceptionErrorCode)?
+        )?;
+        write!(
+            f,
+            "  EPTP=0x{:x} TscOffset=0x{:x} TprThreshold=0x{:x}\n",
+            read_field(VmcsField::EptPointer)?,
+            read_field(VmcsField::TscOffset)?,
+            read_field(VmcsField::TprThreshold)?
+        )?;
+        write!(
+            f,
+            "  ExitReason=0x{:x} ExitQualification=0x{:x} ExitIntrInfo=0x{:x} IdtVectoring=0x{:x}\n",
+            read_field(VmcsField::VmExitReason)?,
+            read_field(VmcsField::ExitQualification)?,
+            read_field(VmcsField::VmExitIntrInfo)?,
+            read_field(VmcsField::IdtVectoringInfoField)?
         )?;
         Ok(())
     }
@@ -559,11 +1271,11 @@ impl<'a> TemporaryActiveVmcs<'a> {
     }
 
     pub fn read_field(&mut self, field: VmcsField) -> Result<u64> {
-        vmcs_read(field)
+        self.vmx.capabilities().read_field(field)
     }
 
     pub fn write_field(&mut self, field: VmcsField, value: u64) -> Result<()> {
-        vmcs_write(field, value)
+        self.vmx.capabilities().write_field(field, value)
     }
 
     pub fn write_with_fixed(
@@ -572,7 +1284,7 @@ impl<'a> TemporaryActiveVmcs<'a> {
         value: u64,
         msr: u32,
     ) -> Result<u64> {
-        vmcs_write_with_fixed(field, value, msr)
+        self.vmx.capabilities().write_with_fixed(field, value, msr)
     }
 }
 
@@ -582,3 +1294,255 @@ impl<'a> Drop for TemporaryActiveVmcs<'a> {
             .expect("Failed to clear TemporaryActiveVmcs");
     }
 }
+
+/// The maximum number of MSRs in an `MsrArea`
+pub const MAX_AREA_MSRS: usize = 16;
+
+/// An entry in a VM-entry or VM-exit MSR area
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MsrEntry {
+    pub index: u32,
+    reserved: u32,
+    pub data: u64,
+}
+
+/// A list of MSRs loaded or stored by the processor on VM entry or exit
+/// (see Sections 24.7.2 and 24.8.2 of the SDM)
+///
+/// The processor accesses the area by its physical address, so it must not
+/// move while it is referenced by the VMCS.
+#[repr(C, align(16))]
+pub struct MsrArea {
+    entries: [MsrEntry; MAX_AREA_MSRS],
+    count: usize,
+}
+
+impl MsrArea {
+    pub fn new() -> Self {
+        Self {
+            entries: [MsrEntry::default(); MAX_AREA_MSRS],
+            count: 0,
+        }
+    }
+
+    /// Add `msr` to the area with the initial value `data`
+    pub fn add(&mut self, msr: u32, data: u64) -> Result<()> {
+        if self.get(msr).is_some() {
+            return Err(Error::InvalidValue(format!(
+                "MSR 0x{:x} is already in the MSR area",
+                msr
+            )));
+        }
+        if self.count == MAX_AREA_MSRS {
+            return Err(Error::AllocError(format!(
+                "No space for MSR 0x{:x} in the MSR area",
+                msr
+            )));
+        }
+        self.entries[self.count] = MsrEntry {
+            index: msr,
+            reserved: 0,
+            data: data,
+        };
+        self.count += 1;
+        Ok(())
+    }
+
+    /// The value of `msr`, or None if it is not in the area
+    pub fn get(&self, msr: u32) -> Option<u64> {
+        self.entries()
+            .iter()
+            .find(|e| e.index == msr)
+            .map(|e| e.data)
+    }
+
+    /// Set the value of `msr`, which must already be in the area
+    pub fn set(&mut self, msr: u32, data: u64) -> Result<()> {
+        let count = self.count;
+        match self.entries[..count].iter_mut().find(|e| e.index == msr) {
+            Some(entry) => {
+                entry.data = data;
+                Ok(())
+            }
+            None => Err(Error::NotFound),
+        }
+    }
+
+    pub fn entries(&self) -> &[MsrEntry] {
+        &self.entries[..self.count]
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// The physical address of the area (for the VMCS)
+    pub fn address(&self) -> u64 {
+        self.entries.as_ptr() as u64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_segment_access_rights() {
+        assert_eq!(
+            format!("{}", SegmentAccessRights(0xa09b)),
+            "0xa09b(type=b code/data dpl=0 P L G)"
+        );
+        assert_eq!(
+            format!("{}", SegmentAccessRights(0x8b)),
+            "0x8b(type=b system dpl=0 P)"
+        );
+        assert_eq!(
+            format!("{}", SegmentAccessRights(0x10000)),
+            "0x10000(unusable)"
+        );
+    }
+
+    #[test]
+    fn test_interrupt_window_control() {
+        let hlt = CpuBasedCtrlFlags::HLT_EXITING.bits();
+        let interrupt = CpuBasedCtrlFlags::INTERRUPT_WINDOW_EXITING.bits();
+        let nmi = CpuBasedCtrlFlags::NMI_WINDOW_EXITING.bits();
+
+        let mut window = InterruptWindowControl::new(hlt | interrupt, true);
+        assert_eq!(window.controls(), hlt | interrupt);
+        window.set_interrupt_window(false);
+        window.set_nmi_window(true);
+        assert_eq!(window.controls(), hlt | nmi);
+
+        // Without virtual NMIs, the NMI waits for an interrupt window
+        let mut window = InterruptWindowControl::new(hlt, false);
+        window.set_nmi_window(true);
+        window.set_interrupt_window(false);
+        assert_eq!(window.controls(), hlt | interrupt);
+        window.set_nmi_window(false);
+        assert_eq!(window.controls(), hlt);
+    }
+
+    #[test]
+    fn test_vmcs_capabilities() {
+        let cpu = (CpuBasedCtrlFlags::ACTIVATE_SECONDARY_CONTROLS.bits()
+            | CpuBasedCtrlFlags::TPR_SHADOW.bits())
+            << 32;
+        let secondary = SecondaryExecFlags::ENABLE_EPT.bits() << 32;
+        let exit = VmExitCtrlFlags::LOAD_HOST_EFER.bits() << 32;
+        let caps =
+            VmcsCapabilities::from_msrs(0, cpu, secondary, exit, 0, 0x2e, 0);
+
+        assert!(caps.supports(VmcsField::GuestRip));
+        assert!(caps.supports(VmcsField::EptPointer));
+        assert!(caps.supports(VmcsField::TprThreshold));
+        assert!(caps.supports(VmcsField::HostIa32Efer));
+        assert!(!caps.supports(VmcsField::GuestIa32Efer));
+        assert!(!caps.supports(VmcsField::VirtualProcessorId));
+        assert!(!caps.supports(VmcsField::PostedIntrNv));
+
+        // Fields beyond the highest index are not supported
+        assert_eq!(VmcsField::TscMultiplier.index(), 0x19);
+        let mut caps = caps;
+        caps.secondary |= SecondaryExecFlags::TSC_SCALING;
+        assert!(!caps.supports(VmcsField::TscMultiplier));
+
+        // Secondary controls are ignored if they cannot be activated
+        let caps = VmcsCapabilities::from_msrs(0, 0, secondary, 0, 0, 0x3fe, 0);
+        assert!(!caps.supports(VmcsField::SecondaryVmExecControl));
+        assert!(!caps.supports(VmcsField::EptPointer));
+    }
+
+    #[test]
+    fn test_unsupported_fields() {
+        let caps = VmcsCapabilities::from_msrs(0, 0, 0, 0, 0, 0x3fe, 0);
+        assert_eq!(caps.read_field(VmcsField::SecondaryVmExecControl), Ok(0));
+        assert_eq!(caps.read_field(VmcsField::TscMultiplier), Ok(1 << 48));
+        assert_eq!(caps.write_field(VmcsField::TprThreshold, 0), Ok(()));
+        assert_eq!(
+            caps.write_with_fixed(
+                VmcsField::SecondaryVmExecControl,
+                0,
+                msr::IA32_VMX_PROCBASED_CTLS2
+            ),
+            Ok(0)
+        );
+        assert_eq!(
+            caps.write_field(VmcsField::TprThreshold, 4),
+            Err(Error::Vmcs(
+                "VMCS field TprThreshold is not supported by this processor (requires CpuBased(TPR_SHADOW))".into()
+            ))
+        );
+        assert!(caps.read_field(VmcsField::EptPointer).is_err());
+    }
+
+    #[test]
+    fn test_vmcs_access_bitmaps() {
+        let mut bitmaps = VmcsAccessBitmaps::new();
+        assert!(!bitmaps.read_allowed(VmcsField::GuestRip));
+        bitmaps.allow_read(VmcsField::GuestRip);
+        bitmaps.allow_write(VmcsField::GuestRsp);
+        assert!(bitmaps.read_allowed(VmcsField::GuestRip));
+        assert!(!bitmaps.write_allowed(VmcsField::GuestRip));
+        assert!(!bitmaps.read_allowed(VmcsField::GuestRsp));
+        assert!(bitmaps.write_allowed(VmcsField::GuestRsp));
+        assert_eq!(bitmaps.read.0[0x681e / 8], !(1 << 6));
+    }
+
+    #[test]
+    fn test_vmcs_shadow() {
+        let cpu = CpuBasedCtrlFlags::ACTIVATE_SECONDARY_CONTROLS.bits() << 32;
+        let secondary = SecondaryExecFlags::ENABLE_VMCS_SHADOWING.bits() << 32;
+        let fields = [VmcsField::GuestRip, VmcsField::VmExitReason];
+
+        let caps = VmcsCapabilities::from_msrs(0, 0, 0, 0, 0, 0x3fe, 0);
+        assert!(VmcsShadow::new(&fields, &caps).is_err());
+
+        // Read-only fields can only be shadowed with VMWRITE to any field
+        let caps =
+            VmcsCapabilities::from_msrs(0, cpu, secondary, 0, 0, 0x3fe, 0);
+        assert!(VmcsShadow::new(&fields[..1], &caps).is_ok());
+        assert!(VmcsShadow::new(&fields, &caps).is_err());
+
+        let caps = VmcsCapabilities::from_msrs(
+            0,
+            cpu,
+            secondary,
+            0,
+            0,
+            0x3fe,
+            1 << 29,
+        );
+        let shadow = VmcsShadow::new(&fields, &caps).unwrap();
+        assert!(shadow.bitmaps().read_allowed(VmcsField::VmExitReason));
+        assert!(!shadow.bitmaps().read_allowed(VmcsField::GuestRsp));
+        assert_eq!(shadow.fields().len(), 2);
+    }
+
+    #[test]
+    fn test_msr_area() {
+        let mut area = MsrArea::new();
+        assert!(area.is_empty());
+        assert_eq!(area.address() % 16, 0);
+
+        area.add(0xc0000102, 5).unwrap();
+        assert!(area.add(0xc0000102, 6).is_err());
+        assert_eq!(area.get(0xc0000102), Some(5));
+        assert_eq!(area.get(0xc0000100), None);
+
+        area.set(0xc0000102, 7).unwrap();
+        assert_eq!(area.get(0xc0000102), Some(7));
+        assert!(area.set(0xc0000100, 7).is_err());
+
+        for i in 1..MAX_AREA_MSRS {
+            area.add(i as u32, 0).unwrap();
+        }
+        assert_eq!(area.len(), MAX_AREA_MSRS);
+        assert!(area.add(0xc0000100, 0).is_err());
+    }
+}
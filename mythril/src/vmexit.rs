@@ -1,6 +1,6 @@
 use crate::error::{self, Error, Result};
 use crate::memory::GuestPhysAddr;
-use crate::{vcpu, vmcs};
+use crate::{chaos, health, migrate, vcpu, vm, vmcheck, vmcs, watch};
 use alloc::fmt::Debug;
 use bitflags::bitflags;
 use core::convert::TryFrom;
@@ -35,11 +35,192 @@ pub struct GuestCpuState {
     pub vcpu: *mut vcpu::VCpu,
 }
 
+impl GuestCpuState {
+    /// The general purpose registers (and CR2) at power on, for `vcpu`
+    pub fn new(vcpu: *mut vcpu::VCpu) -> Self {
+        GuestCpuState {
+            cr2: 0,
+            r15: 0,
+            r14: 0,
+            r13: 0,
+            r12: 0,
+            r11: 0,
+            r10: 0,
+            r9: 0,
+            r8: 0,
+            rbp: 0,
+            rdi: 0,
+            rsi: 0,
+            rdx: 0,
+            rcx: 0,
+            rbx: 0,
+            rax: 0,
+            vcpu,
+        }
+    }
+
+    /// Clear the general purpose registers (and CR2), as at power on
+    pub fn clear_registers(&mut self) {
+        *self = Self::new(self.vcpu);
+    }
+
+    /// Read the low `width` bytes of `reg`
+    pub fn read_gpr(&self, reg: Gpr, width: OperandWidth) -> u64 {
+        self.gpr(reg) & width.mask()
+    }
+
+    /// Write `value` to the low `width` bytes of `reg`, as an instruction
+    /// with a register operand of that width would
+    ///
+    /// Byte and word writes leave the rest of the register unchanged, while
+    /// doubleword writes clear the upper half (as in 64-bit mode).
+    pub fn write_gpr(&mut self, reg: Gpr, value: u64, width: OperandWidth) {
+        let value = match width {
+            OperandWidth::Byte | OperandWidth::Word => {
+                (self.gpr(reg) & !width.mask()) | (value & width.mask())
+            }
+            OperandWidth::Dword | OperandWidth::Qword => value & width.mask(),
+        };
+        self.set_gpr(reg, value);
+    }
+
+    /// Return `value` from RDMSR, in EDX:EAX
+    pub fn write_msr_result(&mut self, value: u64) {
+        self.write_gpr(Gpr::Rdx, value >> 32, OperandWidth::Dword);
+        self.write_gpr(Gpr::Rax, value, OperandWidth::Dword);
+    }
+
+    fn gpr(&self, reg: Gpr) -> u64 {
+        match reg {
+            Gpr::Rax => self.rax,
+            Gpr::Rcx => self.rcx,
+            Gpr::Rdx => self.rdx,
+            Gpr::Rbx => self.rbx,
+            Gpr::Rbp => self.rbp,
+            Gpr::Rsi => self.rsi,
+            Gpr::Rdi => self.rdi,
+            Gpr::R8 => self.r8,
+            Gpr::R9 => self.r9,
+            Gpr::R10 => self.r10,
+            Gpr::R11 => self.r11,
+            Gpr::R12 => self.r12,
+            Gpr::R13 => self.r13,
+            Gpr::R14 => self.r14,
+            Gpr::R15 => self.r15,
+        }
+    }
+
+    fn set_gpr(&mut self, reg: Gpr, value: u64) {
+        match reg {
+            Gpr::Rax => self.rax = value,
+            Gpr::Rcx => self.rcx = value,
+            Gpr::Rdx => self.rdx = value,
+            Gpr::Rbx => self.rbx = value,
+            Gpr::Rbp => self.rbp = value,
+            Gpr::Rsi => self.rsi = value,
+            Gpr::Rdi => self.rdi = value,
+            Gpr::R8 => self.r8 = value,
+            Gpr::R9 => self.r9 = value,
+            Gpr::R10 => self.r10 = value,
+            Gpr::R11 => self.r11 = value,
+            Gpr::R12 => self.r12 = value,
+            Gpr::R13 => self.r13 = value,
+            Gpr::R14 => self.r14 = value,
+            Gpr::R15 => self.r15 = value,
+        }
+    }
+}
+
+/// A general purpose register saved in `GuestCpuState`
+///
+/// RSP is not included, as it is held in the guest-state area of the VMCS.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Gpr {
+    Rax,
+    Rcx,
+    Rdx,
+    Rbx,
+    Rbp,
+    Rsi,
+    Rdi,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
+}
+
+/// The width of a register operand
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OperandWidth {
+    Byte,
+    Word,
+    Dword,
+    Qword,
+}
+
+impl OperandWidth {
+    /// The width of an operand of `bytes` bytes
+    pub fn from_bytes(bytes: usize) -> Result<Self> {
+        match bytes {
+            1 => Ok(OperandWidth::Byte),
+            2 => Ok(OperandWidth::Word),
+            4 => Ok(OperandWidth::Dword),
+            8 => Ok(OperandWidth::Qword),
+            _ => Err(Error::InvalidValue(format!(
+                "Invalid operand size: {}",
+                bytes
+            ))),
+        }
+    }
+
+    /// The number of bytes in an operand of this width
+    pub fn bytes(self) -> usize {
+        match self {
+            OperandWidth::Byte => 1,
+            OperandWidth::Word => 2,
+            OperandWidth::Dword => 4,
+            OperandWidth::Qword => 8,
+        }
+    }
+
+    fn mask(self) -> u64 {
+        match self {
+            OperandWidth::Qword => !0,
+            width => (1 << (width.bytes() * 8)) - 1,
+        }
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn vmexit_handler(state: *mut GuestCpuState) {
     let state = unsafe { state.as_mut() }.expect("Guest cpu sate is NULL");
     let vcpu = unsafe { state.vcpu.as_mut() }.expect("VCpu state is NULL");
 
+    health::exit_started(
+        vcpu.vmcs
+            .read_field(vmcs::VmcsField::VmExitReason)
+            .map(|reason| reason & 0xffff)
+            .unwrap_or(0),
+    );
+
+    // The most frequent exits are handled before decoding the full reason
+    match vcpu.handle_fast_vmexit(state) {
+        Ok(true) => {
+            chaos::delay_exit();
+            health::fast_exit_finished(vcpu.pending_interrupt_count());
+            if let Err(e) = watch::check(vcpu, state) {
+                panic!("Failed to evaluate watches: {:?}", e);
+            }
+            return;
+        }
+        Ok(false) => (),
+        Err(e) => panic!("Failed to handle fast vmexit: {:?}", e),
+    }
+
     let reason = ExitReason::from_active_vmcs(&mut vcpu.vmcs)
         .expect("Failed to get vm reason");
 
@@ -51,6 +232,28 @@ pub extern "C" fn vmexit_handler(state: *mut GuestCpuState) {
         info!("exit reason = {:?}", reason);
         panic!("Failed to handle vmexit: {:?}", e);
     }
+
+    chaos::delay_exit();
+    health::exit_finished(
+        vcpu.pending_interrupt_count(),
+        vm::pending_msg_count(),
+    );
+
+    if let Err(e) = watch::check(vcpu, state) {
+        panic!("Failed to evaluate watches: {:?}", e);
+    }
+
+    if cfg!(debug_assertions) {
+        if let Err(e) = vmcheck::check_vm_entry(&vcpu.vmcs) {
+            panic!("VM entry would fail: {:?}", e);
+        }
+    }
+
+    // Nothing is borrowed from the host stack any more, so the vCPU can
+    // leave it (and this core) for good
+    if let Some(target) = vcpu.take_migration() {
+        migrate::depart(vcpu, state, target);
+    }
 }
 
 #[no_mangle]
@@ -59,6 +262,13 @@ pub extern "C" fn vmresume_failure_handler(rflags: u64) {
         .expect("vmresume failed");
 }
 
+/// Basic exit reasons (see Table C-1 in Appendix C)
+pub mod basic_reason {
+    pub const CPUID: u64 = 10;
+    pub const EPT_VIOLATION: u64 = 48;
+    pub const APIC_WRITE: u64 = 56;
+}
+
 pub trait ExtendedExitInformation
 where
     Self: core::marker::Sized,
@@ -129,11 +339,11 @@ pub enum ExitInformation {
     Wbinvd,
     Xsetbv,
     ApicWrite,
-    RdRand,
-    Invpcid,
+    RdRand(RandomInformation),
+    Invpcid(InvpcidInformation),
     VmFunc,
     Encls,
-    RdSeed,
+    RdSeed(RandomInformation),
     PageModificationLogFull,
     Xsaves,
     Xrstors,
@@ -211,11 +421,17 @@ impl ExitReason {
             54 => ExitInformation::Wbinvd,
             55 => ExitInformation::Xsetbv,
             56 => ExitInformation::ApicWrite,
-            57 => ExitInformation::RdRand,
-            58 => ExitInformation::Invpcid,
+            57 => ExitInformation::RdRand(RandomInformation::from_active_vmcs(
+                vmcs,
+            )?),
+            58 => ExitInformation::Invpcid(
+                InvpcidInformation::from_active_vmcs(vmcs)?,
+            ),
             59 => ExitInformation::VmFunc,
             60 => ExitInformation::Encls,
-            61 => ExitInformation::RdSeed,
+            61 => ExitInformation::RdSeed(RandomInformation::from_active_vmcs(
+                vmcs,
+            )?),
             62 => ExitInformation::PageModificationLogFull,
             63 => ExitInformation::Xsaves,
             64 => ExitInformation::Xrstors,
@@ -381,29 +597,37 @@ pub enum MovCrRegister {
 }
 
 impl MovCrRegister {
+    /// The register, unless it is RSP
+    pub fn gpr(&self) -> Option<Gpr> {
+        Some(match self {
+            MovCrRegister::Rax => Gpr::Rax,
+            MovCrRegister::Rcx => Gpr::Rcx,
+            MovCrRegister::Rdx => Gpr::Rdx,
+            MovCrRegister::Rbx => Gpr::Rbx,
+            MovCrRegister::Rbp => Gpr::Rbp,
+            MovCrRegister::Rsi => Gpr::Rsi,
+            MovCrRegister::Rdi => Gpr::Rdi,
+            MovCrRegister::R8 => Gpr::R8,
+            MovCrRegister::R9 => Gpr::R9,
+            MovCrRegister::R10 => Gpr::R10,
+            MovCrRegister::R11 => Gpr::R11,
+            MovCrRegister::R12 => Gpr::R12,
+            MovCrRegister::R13 => Gpr::R13,
+            MovCrRegister::R14 => Gpr::R14,
+            MovCrRegister::R15 => Gpr::R15,
+            MovCrRegister::Rsp => return None,
+        })
+    }
+
     pub fn read(
         &self,
         vmcs: &vmcs::ActiveVmcs,
         guest_cpu: &GuestCpuState,
     ) -> Result<u64> {
-        Ok(match self {
-            MovCrRegister::Rax => guest_cpu.rax,
-            MovCrRegister::Rcx => guest_cpu.rcx,
-            MovCrRegister::Rdx => guest_cpu.rdx,
-            MovCrRegister::Rbx => guest_cpu.rbx,
-            MovCrRegister::Rbp => guest_cpu.rbp,
-            MovCrRegister::Rsi => guest_cpu.rsi,
-            MovCrRegister::Rdi => guest_cpu.rdi,
-            MovCrRegister::R8 => guest_cpu.r8,
-            MovCrRegister::R9 => guest_cpu.r9,
-            MovCrRegister::R10 => guest_cpu.r10,
-            MovCrRegister::R11 => guest_cpu.r11,
-            MovCrRegister::R12 => guest_cpu.r12,
-            MovCrRegister::R13 => guest_cpu.r13,
-            MovCrRegister::R14 => guest_cpu.r14,
-            MovCrRegister::R15 => guest_cpu.r15,
-            MovCrRegister::Rsp => vmcs.read_field(vmcs::VmcsField::GuestRsp)?,
-        })
+        match self.gpr() {
+            Some(gpr) => Ok(guest_cpu.read_gpr(gpr, OperandWidth::Qword)),
+            None => vmcs.read_field(vmcs::VmcsField::GuestRsp),
+        }
     }
 
     pub fn write(
@@ -412,27 +636,13 @@ impl MovCrRegister {
         vmcs: &mut vmcs::ActiveVmcs,
         guest_cpu: &mut GuestCpuState,
     ) -> Result<()> {
-        match self {
-            MovCrRegister::Rax => guest_cpu.rax = value,
-            MovCrRegister::Rcx => guest_cpu.rcx = value,
-            MovCrRegister::Rdx => guest_cpu.rdx = value,
-            MovCrRegister::Rbx => guest_cpu.rbx = value,
-            MovCrRegister::Rbp => guest_cpu.rbp = value,
-            MovCrRegister::Rsi => guest_cpu.rsi = value,
-            MovCrRegister::Rdi => guest_cpu.rdi = value,
-            MovCrRegister::R8 => guest_cpu.r8 = value,
-            MovCrRegister::R9 => guest_cpu.r9 = value,
-            MovCrRegister::R10 => guest_cpu.r10 = value,
-            MovCrRegister::R11 => guest_cpu.r11 = value,
-            MovCrRegister::R12 => guest_cpu.r12 = value,
-            MovCrRegister::R13 => guest_cpu.r13 = value,
-            MovCrRegister::R14 => guest_cpu.r14 = value,
-            MovCrRegister::R15 => guest_cpu.r15 = value,
-            MovCrRegister::Rsp => {
-                vmcs.write_field(vmcs::VmcsField::GuestRsp, value)?
+        match self.gpr() {
+            Some(gpr) => {
+                guest_cpu.write_gpr(gpr, value, OperandWidth::Qword);
+                Ok(())
             }
+            None => vmcs.write_field(vmcs::VmcsField::GuestRsp, value),
         }
-        Ok(())
     }
 }
 
@@ -468,6 +678,127 @@ impl ExtendedExitInformation for CrInformation {
     }
 }
 
+/// The destination of an RDRAND or RDSEED instruction (see Table 27-14 in
+/// the SDM)
+#[derive(Clone, Debug)]
+pub struct RandomInformation {
+    pub register: MovCrRegister,
+    pub width: OperandWidth,
+}
+
+impl ExtendedExitInformation for RandomInformation {
+    fn from_active_vmcs(vmcs: &vmcs::ActiveVmcs) -> Result<Self> {
+        let info = vmcs.read_field(vmcs::VmcsField::VmxInstructionInfo)?;
+        let width = match (info >> 11) & 0b11 {
+            0 => OperandWidth::Word,
+            1 => OperandWidth::Dword,
+            2 => OperandWidth::Qword,
+            size => {
+                return Err(Error::InvalidValue(format!(
+                    "Invalid RDRAND/RDSEED operand size: {}",
+                    size
+                )))
+            }
+        };
+        Ok(RandomInformation {
+            register: MovCrRegister::try_from(((info >> 3) & 0xf) as u8)?,
+            width: width,
+        })
+    }
+}
+
+/// The operands of an INVPCID instruction (see Table 27-13 in the SDM)
+#[derive(Clone, Debug)]
+pub struct InvpcidInformation {
+    /// The register holding the invalidation type
+    pub type_register: MovCrRegister,
+    /// The components of the address of the INVPCID descriptor
+    pub base: Option<MovCrRegister>,
+    pub index: Option<MovCrRegister>,
+    pub scaling: u8,
+    pub displacement: u64,
+    /// The address size (in bits) of the instruction
+    pub address_size: u8,
+    /// The VMCS field with the base of the segment of the descriptor
+    pub segment_base: vmcs::VmcsField,
+}
+
+impl ExtendedExitInformation for InvpcidInformation {
+    fn from_active_vmcs(vmcs: &vmcs::ActiveVmcs) -> Result<Self> {
+        let info = vmcs.read_field(vmcs::VmcsField::VmxInstructionInfo)?;
+        let displacement =
+            vmcs.read_field(vmcs::VmcsField::ExitQualification)?;
+
+        let register = |shift: u64, invalid_bit: u64| -> Result<_> {
+            if info & (1 << invalid_bit) != 0 {
+                Ok(None)
+            } else {
+                Ok(Some(MovCrRegister::try_from(
+                    ((info >> shift) & 0xf) as u8,
+                )?))
+            }
+        };
+        let address_size = match (info >> 7) & 0b111 {
+            0 => 16,
+            1 => 32,
+            2 => 64,
+            size => {
+                return Err(Error::InvalidValue(format!(
+                    "Invalid INVPCID address size: {}",
+                    size
+                )))
+            }
+        };
+        let segment_base = match (info >> 15) & 0b111 {
+            0 => vmcs::VmcsField::GuestEsBase,
+            1 => vmcs::VmcsField::GuestCsBase,
+            2 => vmcs::VmcsField::GuestSsBase,
+            3 => vmcs::VmcsField::GuestDsBase,
+            4 => vmcs::VmcsField::GuestFsBase,
+            5 => vmcs::VmcsField::GuestGsBase,
+            segment => {
+                return Err(Error::InvalidValue(format!(
+                    "Invalid INVPCID segment: {}",
+                    segment
+                )))
+            }
+        };
+
+        Ok(InvpcidInformation {
+            type_register: MovCrRegister::try_from(((info >> 28) & 0xf) as u8)?,
+            base: register(23, 27)?,
+            index: register(18, 22)?,
+            scaling: (info & 0b11) as u8,
+            displacement,
+            address_size,
+            segment_base,
+        })
+    }
+}
+
+impl InvpcidInformation {
+    /// The linear address of the INVPCID descriptor
+    pub fn descriptor_address(
+        &self,
+        vmcs: &vmcs::ActiveVmcs,
+        guest_cpu: &GuestCpuState,
+    ) -> Result<u64> {
+        let mut offset = self.displacement;
+        if let Some(base) = self.base {
+            offset = offset.wrapping_add(base.read(vmcs, guest_cpu)?);
+        }
+        if let Some(index) = self.index {
+            offset = offset
+                .wrapping_add(index.read(vmcs, guest_cpu)? << self.scaling);
+        }
+        if self.address_size < 64 {
+            offset &= (1 << self.address_size) - 1;
+        }
+        let segment_base = vmcs.read_field(self.segment_base)?;
+        Ok(segment_base.wrapping_add(offset))
+    }
+}
+
 bitflags! {
     pub struct ExitReasonFlags: u64 {
         const ENCLAVE_MODE =        1 << 27;
@@ -476,3 +807,41 @@ bitflags! {
         const VM_ENTRY_FAIL =       1 << 31;
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_partial_register_writes() {
+        let mut cpu = GuestCpuState::new(core::ptr::null_mut());
+        cpu.write_gpr(Gpr::Rax, 0x1122334455667788, OperandWidth::Qword);
+        cpu.write_gpr(Gpr::Rax, 0xaabb, OperandWidth::Byte);
+        assert_eq!(
+            cpu.read_gpr(Gpr::Rax, OperandWidth::Qword),
+            0x11223344556677bb
+        );
+        cpu.write_gpr(Gpr::Rax, 0xccdd, OperandWidth::Word);
+        assert_eq!(
+            cpu.read_gpr(Gpr::Rax, OperandWidth::Qword),
+            0x112233445566ccdd
+        );
+        assert_eq!(cpu.read_gpr(Gpr::Rax, OperandWidth::Word), 0xccdd);
+
+        // Doubleword writes zero-extend
+        cpu.write_gpr(Gpr::Rax, 0xffff_0000_8000_0000, OperandWidth::Dword);
+        assert_eq!(cpu.read_gpr(Gpr::Rax, OperandWidth::Qword), 0x80000000);
+
+        cpu.write_gpr(Gpr::R15, 0x42, OperandWidth::Byte);
+        assert_eq!(cpu.read_gpr(Gpr::R15, OperandWidth::Qword), 0x42);
+        assert_eq!(cpu.read_gpr(Gpr::Rax, OperandWidth::Qword), 0x80000000);
+    }
+
+    #[test]
+    fn test_operand_width() {
+        assert_eq!(OperandWidth::from_bytes(4).unwrap(), OperandWidth::Dword);
+        assert!(OperandWidth::from_bytes(3).is_err());
+        assert_eq!(OperandWidth::Qword.mask(), u64::MAX);
+        assert_eq!(OperandWidth::Word.mask(), 0xffff);
+    }
+}
@@ -1,11 +1,13 @@
 use crate::error::{self, Error, Result};
 use crate::memory::{GuestVirtAddr, Raw4kPage};
+use crate::vmcs::VmcsCapabilities;
 use alloc::boxed::Box;
 use raw_cpuid::CpuId;
 use x86::msr;
 
 pub struct Vmx {
     _vmxon_region: *mut Raw4kPage,
+    capabilities: VmcsCapabilities,
 }
 
 impl Vmx {
@@ -55,6 +57,7 @@ impl Vmx {
         error::check_vm_insruction(rflags, "Failed to enable vmx".into())?;
         Ok(Vmx {
             _vmxon_region: vmxon_region,
+            capabilities: VmcsCapabilities::read(),
         })
     }
 
@@ -73,6 +76,11 @@ impl Vmx {
         error::check_vm_insruction(rflags, "Failed to disable vmx".into())
     }
 
+    /// The VMCS fields supported by this processor
+    pub fn capabilities(&self) -> &VmcsCapabilities {
+        &self.capabilities
+    }
+
     pub fn revision() -> u32 {
         unsafe { msr::rdmsr(msr::IA32_VMX_BASIC) as u32 }
     }
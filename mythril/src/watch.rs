@@ -0,0 +1,413 @@
+//! Watch expressions for debugging guests
+//!
+//! A watch observes a VMCS field or a byte of guest physical memory in a
+//! single VM, and triggers when the value changes (or becomes non-zero).
+//! Watches are registered from the management console and evaluated after
+//! each exit of the VM's vCPUs. A vCPU that triggers a watch reports the
+//! change and then stays paused until the `continue` command is issued.
+//! VMs can also be configured to pause before their first instruction
+//! (see `pause_at_entry`), so watches can be added before the guest runs.
+
+use crate::console;
+use crate::coredump::{self, VCpuRegisters};
+use crate::error::Result;
+use crate::memory::GuestPhysAddr;
+use crate::migrate;
+use crate::percore::{self, CoreId};
+use crate::time;
+use crate::timekeeping;
+use crate::vcpu::VCpu;
+use crate::vm;
+use crate::vmcs::VmcsField;
+use crate::vmexit::GuestCpuState;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+
+/// The VMCS fields that can be watched (by the name of the variant)
+const WATCHABLE_FIELDS: &[VmcsField] = &[
+    VmcsField::GuestCr0,
+    VmcsField::GuestCr3,
+    VmcsField::GuestCr4,
+    VmcsField::GuestIa32Efer,
+    VmcsField::GuestRip,
+    VmcsField::GuestRsp,
+    VmcsField::GuestRflags,
+    VmcsField::GuestDr7,
+    VmcsField::GuestCsSelector,
+    VmcsField::GuestSsSelector,
+    VmcsField::GuestFsBase,
+    VmcsField::GuestGsBase,
+    VmcsField::GuestGdtrBase,
+    VmcsField::GuestIdtrBase,
+    VmcsField::GuestActivityState,
+    VmcsField::GuestInterruptibilityInfo,
+    VmcsField::VmExitReason,
+    VmcsField::ExitQualification,
+];
+
+/// The value observed by a watch
+#[derive(Clone, Copy, Debug)]
+pub enum WatchTarget {
+    /// A field of the VMCS of each vCPU
+    Field(VmcsField),
+    /// A byte of guest physical memory
+    Memory(GuestPhysAddr),
+}
+
+impl fmt::Display for WatchTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WatchTarget::Field(field) => write!(f, "{:?}", field),
+            WatchTarget::Memory(addr) => {
+                write!(f, "byte at GPA 0x{:x}", addr.as_u64())
+            }
+        }
+    }
+}
+
+/// When a watch triggers
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatchCondition {
+    /// The value differs from the last value observed on the same core
+    Changed,
+    /// The value became non-zero
+    NonZero,
+}
+
+impl fmt::Display for WatchCondition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WatchCondition::Changed => write!(f, "changed"),
+            WatchCondition::NonZero => write!(f, "nonzero"),
+        }
+    }
+}
+
+/// A registered watch
+pub struct Watch {
+    /// The number used to remove this watch
+    pub id: u32,
+    /// The VM whose exits evaluate this watch
+    pub vm_id: u32,
+    pub target: WatchTarget,
+    pub condition: WatchCondition,
+    /// The last value observed by each vCPU
+    last: BTreeMap<CoreId, u64>,
+}
+
+impl Watch {
+    pub fn new(
+        id: u32,
+        vm_id: u32,
+        target: WatchTarget,
+        condition: WatchCondition,
+    ) -> Self {
+        Self {
+            id,
+            vm_id,
+            target,
+            condition,
+            last: BTreeMap::new(),
+        }
+    }
+
+    /// Record the value observed on `core`, returning true if the watch
+    /// triggered
+    ///
+    /// The first observation on a core only establishes the initial value
+    /// for `Changed` watches. `NonZero` watches trigger on the transition
+    /// to non-zero (or if the value is non-zero when first observed).
+    pub fn observe(&mut self, core: CoreId, value: u64) -> bool {
+        let previous = self.last.insert(core, value);
+        match self.condition {
+            WatchCondition::Changed => {
+                previous.map(|prev| prev != value).unwrap_or(false)
+            }
+            WatchCondition::NonZero => {
+                value != 0 && previous.map(|prev| prev == 0).unwrap_or(true)
+            }
+        }
+    }
+}
+
+struct WatchList {
+    next_id: u32,
+    watches: Vec<Watch>,
+}
+
+static WATCHES: Mutex<WatchList> = Mutex::new(WatchList {
+    next_id: 1,
+    watches: Vec::new(),
+});
+
+// The number of registered watches, so exits need not take the lock when
+// nothing is being watched.
+static WATCH_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// Incremented by `continue` to release all paused vCPUs
+static RESUME_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Register a new watch, returning its id
+pub fn add(vm_id: u32, target: WatchTarget, condition: WatchCondition) -> u32 {
+    let mut list = WATCHES.lock();
+    let id = list.next_id;
+    list.next_id += 1;
+    list.watches.push(Watch::new(id, vm_id, target, condition));
+    WATCH_COUNT.store(list.watches.len(), Ordering::Relaxed);
+    id
+}
+
+/// Remove the watch with the given id, returning false if there is none
+pub fn remove(id: u32) -> bool {
+    let mut list = WATCHES.lock();
+    let count = list.watches.len();
+    list.watches.retain(|watch| watch.id != id);
+    WATCH_COUNT.store(list.watches.len(), Ordering::Relaxed);
+    list.watches.len() != count
+}
+
+// Read the current value of a watch target (or None if the target is guest
+// memory that is not mapped)
+fn read_target(vcpu: &VCpu, target: &WatchTarget) -> Result<Option<u64>> {
+    match target {
+        WatchTarget::Field(field) => Ok(Some(vcpu.vmcs.read_field(*field)?)),
+        WatchTarget::Memory(addr) => {
+            let vm = vcpu.vm.read();
+            let page = GuestPhysAddr::new(addr.as_u64() & !0xfff);
+            let frame = match vm.guest_space.find_host_frame(page) {
+                Ok(frame) => frame,
+                Err(_) => return Ok(None),
+            };
+            let array = unsafe { frame.as_array() };
+            Ok(Some(array[(addr.as_u64() & 0xfff) as usize] as u64))
+        }
+    }
+}
+
+/// Evaluate the watches of the current vCPU's VM, pausing the vCPU if any
+/// of them trigger
+pub fn check(vcpu: &mut VCpu, guest_cpu: &GuestCpuState) -> Result<()> {
+    if WATCH_COUNT.load(Ordering::Relaxed) == 0 {
+        return Ok(());
+    }
+
+    let vm_id = vcpu.vm.read().id;
+    let core = migrate::current_vcpu();
+    let mut triggered = vec![];
+    {
+        let mut list = WATCHES.lock();
+        for watch in list.watches.iter_mut() {
+            if watch.vm_id != vm_id {
+                continue;
+            }
+            let value = match read_target(vcpu, &watch.target)? {
+                Some(value) => value,
+                None => continue,
+            };
+            let previous = watch.last.get(&core).copied();
+            if watch.observe(core, value) {
+                triggered.push(format!(
+                    "Watch {} ({} {}) triggered on vCPU {}: 0x{:x} -> 0x{:x}",
+                    watch.id,
+                    watch.target,
+                    watch.condition,
+                    core.raw,
+                    previous.unwrap_or(0),
+                    value
+                ));
+            }
+        }
+    }
+
+    if triggered.is_empty() {
+        return Ok(());
+    }
+    for report in triggered {
+        info!("{}", report);
+    }
+    let rip = vcpu.vmcs.read_field(VmcsField::GuestRip)?;
+    info!("Guest rip: {}", vcpu.vm.read().symbols.describe(rip));
+    info!("{}", vcpu.vmcs);
+
+    // The registers are recorded for `coredump`
+    let stopped = timekeeping::TimeState::capture(vcpu)?;
+    let paused_at = time::now();
+    coredump::vcpu_paused(vm_id, VCpuRegisters::capture(vcpu, guest_cpu));
+    pause(vcpu);
+    coredump::vcpu_resumed(vm_id);
+
+    let policy = vcpu.vm.read().config.time_policy();
+    let fixup = stopped.fixup(
+        policy,
+        unsafe { x86::time::rdtsc() },
+        time::now() - paused_at,
+    );
+    timekeeping::apply(vcpu, &fixup)
+}
+
+/// Pause the current vCPU before its first instruction, with the registers
+/// it will start with in `guest_cpu` (see
+/// `VirtualMachineConfig::set_start_paused`)
+pub fn pause_at_entry(vcpu: &VCpu, guest_cpu: &GuestCpuState) -> Result<()> {
+    let vm_id = vcpu.vm.read().id;
+    let rip = vcpu.vmcs.read_field(VmcsField::GuestRip)?;
+    info!(
+        "VM {} is paused at its first instruction: {}",
+        vm::vm_name(vm_id).unwrap_or("?"),
+        vcpu.vm.read().symbols.describe(rip)
+    );
+    coredump::vcpu_paused(vm_id, VCpuRegisters::capture(vcpu, guest_cpu));
+    pause(vcpu);
+    coredump::vcpu_resumed(vm_id);
+    Ok(())
+}
+
+fn pause(vcpu: &VCpu) {
+    let generation = RESUME_GENERATION.load(Ordering::Acquire);
+    info!(
+        "Core {} paused (use 'continue' in the management console to resume)",
+        percore::read_core_id().raw
+    );
+    while RESUME_GENERATION.load(Ordering::Acquire) == generation {
+        // Interrupts are not delivered while the vCPU is paused in its exit
+        // handler, and the serial interrupt may be routed to this core, so
+        // poll for console input directly. Input that is not for the
+        // console is dropped.
+        let vm = vcpu.vm.read();
+        let key = match vm.config.physical_devices().serial.as_ref() {
+            Some(serial) if serial.data_ready() => Some(serial.read()),
+            _ => None,
+        };
+        drop(vm);
+        if let Some(key) = key {
+            console::handle_key(key);
+        }
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+fn parse_u64(value: &str) -> Option<u64> {
+    if value.starts_with("0x") {
+        u64::from_str_radix(&value[2..], 16).ok()
+    } else {
+        value.parse().ok()
+    }
+}
+
+fn parse_target(target: &str) -> Option<WatchTarget> {
+    if let Some(addr) = parse_u64(target) {
+        return Some(WatchTarget::Memory(GuestPhysAddr::new(addr)));
+    }
+    WATCHABLE_FIELDS
+        .iter()
+        .find(|field| format!("{:?}", field).eq_ignore_ascii_case(target))
+        .map(|field| WatchTarget::Field(*field))
+}
+
+fn parse_condition(condition: Option<&&str>) -> Option<WatchCondition> {
+    match condition.copied() {
+        None | Some("changed") => Some(WatchCondition::Changed),
+        Some("nonzero") => Some(WatchCondition::NonZero),
+        _ => None,
+    }
+}
+
+/// The `watch` management console command
+pub fn watch_command(out: &mut dyn fmt::Write, args: &[&str]) -> fmt::Result {
+    if args.is_empty() {
+        let list = WATCHES.lock();
+        if list.watches.is_empty() {
+            return writeln!(out, "No watches");
+        }
+        for watch in list.watches.iter() {
+            writeln!(
+                out,
+                "  {:>3}: vm {} {} {}",
+                watch.id,
+                vm::vm_name(watch.vm_id).unwrap_or("?"),
+                watch.target,
+                watch.condition
+            )?;
+        }
+        return Ok(());
+    }
+
+    let vm_id = args.get(0).and_then(|vm| vm::find_vm(vm));
+    let target = args.get(1).and_then(|target| parse_target(target));
+    let condition = parse_condition(args.get(2));
+    match (vm_id, target, condition) {
+        (Some(vm_id), Some(target), Some(condition)) => {
+            let id = add(vm_id, target, condition);
+            writeln!(out, "Added watch {}", id)
+        }
+        _ => {
+            writeln!(out, "usage: watch [<vm> <field|gpa> [changed|nonzero]]")?;
+            let names = WATCHABLE_FIELDS
+                .iter()
+                .map(|field| format!("{:?}", field))
+                .collect::<Vec<String>>();
+            writeln!(out, "fields: {}", names.join(" "))
+        }
+    }
+}
+
+/// The `unwatch` management console command
+pub fn unwatch_command(out: &mut dyn fmt::Write, args: &[&str]) -> fmt::Result {
+    match args.get(0).and_then(|id| id.parse::<u32>().ok()) {
+        Some(id) if remove(id) => writeln!(out, "Removed watch {}", id),
+        Some(id) => writeln!(out, "No watch {}", id),
+        None => writeln!(out, "usage: unwatch <id>"),
+    }
+}
+
+/// The `continue` management console command
+pub fn continue_command(
+    _out: &mut dyn fmt::Write,
+    _args: &[&str],
+) -> fmt::Result {
+    RESUME_GENERATION.fetch_add(1, Ordering::Release);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_observe() {
+        let core = CoreId::from(0);
+        let target = WatchTarget::Field(VmcsField::GuestCr3);
+        let mut watch = Watch::new(1, 0, target, WatchCondition::Changed);
+        assert!(!watch.observe(core, 0x1000));
+        assert!(!watch.observe(core, 0x1000));
+        assert!(watch.observe(core, 0x2000));
+        assert!(!watch.observe(CoreId::from(1), 0x3000));
+
+        let target = WatchTarget::Memory(GuestPhysAddr::new(0x1234));
+        let mut watch = Watch::new(2, 0, target, WatchCondition::NonZero);
+        assert!(!watch.observe(core, 0));
+        assert!(watch.observe(core, 1));
+        assert!(!watch.observe(core, 2));
+        assert!(!watch.observe(core, 0));
+        assert!(watch.observe(core, 3));
+    }
+
+    #[test]
+    fn test_parse_target() {
+        match parse_target("guestcr3") {
+            Some(WatchTarget::Field(VmcsField::GuestCr3)) => (),
+            target => panic!("Unexpected target: {:?}", target),
+        }
+        match parse_target("0x1000") {
+            Some(WatchTarget::Memory(addr)) => {
+                assert_eq!(addr.as_u64(), 0x1000)
+            }
+            target => panic!("Unexpected target: {:?}", target),
+        }
+        assert!(parse_target("GuestCr7").is_none());
+    }
+}